@@ -0,0 +1,76 @@
+//! Loading pairs over TCP one request at a time, pipelined, and as `BulkSet` batches.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use kvs::protocol::{read_frame, write_frame, Request, Response};
+use kvs::{KvStore, KvsClient, KvsServer};
+use std::io::{BufReader, BufWriter};
+use std::net::{SocketAddr, TcpStream};
+use std::thread;
+use tempfile::TempDir;
+
+const PAIRS: usize = 1_000_000;
+/// Requests in flight when pipelining, and pairs per batch when bulk loading.
+const WINDOW: usize = 1_000;
+
+fn start_server(dir: &std::path::Path) -> SocketAddr {
+    let log = slog::Logger::root(slog::Discard, slog::o!());
+    let server = KvsServer::new("127.0.0.1:0", KvStore::open(dir).unwrap(), log).unwrap();
+    let addr = server.local_addr().unwrap();
+    thread::spawn(move || server.listen_forever());
+    addr
+}
+
+fn pairs(window: usize, offset: usize) -> Vec<(String, String)> {
+    (offset..offset + window)
+        .map(|i| (format!("key{:08}", i), format!("value{}", i)))
+        .collect()
+}
+
+fn sequential(addr: SocketAddr) {
+    let mut client = KvsClient::connect(addr).unwrap();
+    for offset in (0..PAIRS).step_by(WINDOW) {
+        for (key, value) in pairs(WINDOW, offset) {
+            client.set(key, value).unwrap();
+        }
+    }
+}
+
+fn pipelined(addr: SocketAddr) {
+    let stream = TcpStream::connect(addr).unwrap();
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    let mut writer = BufWriter::new(stream);
+    for offset in (0..PAIRS).step_by(WINDOW) {
+        for (key, value) in pairs(WINDOW, offset) {
+            write_frame(&mut writer, &Request::Set { key, value }).unwrap();
+        }
+        for _ in 0..WINDOW {
+            let response: Response = read_frame(&mut reader).unwrap().unwrap();
+            assert_eq!(response, Response::Ok(None));
+        }
+    }
+}
+
+fn bulk(addr: SocketAddr) {
+    let mut client = KvsClient::connect(addr).unwrap();
+    for offset in (0..PAIRS).step_by(WINDOW) {
+        client.set_bulk(pairs(WINDOW, offset)).unwrap();
+    }
+}
+
+fn load(c: &mut Criterion) {
+    let mut group = c.benchmark_group("load_1m_pairs");
+    group.sample_size(10);
+    for (name, run) in [
+        ("sequential", sequential as fn(SocketAddr)),
+        ("pipelined", pipelined),
+        ("bulk", bulk),
+    ] {
+        let temp_dir = TempDir::new().unwrap();
+        let addr = start_server(temp_dir.path());
+        group.bench_function(BenchmarkId::from_parameter(name), |b| b.iter(|| run(addr)));
+    }
+    group.finish();
+}
+
+criterion_group!(benches, load);
+criterion_main!(benches);
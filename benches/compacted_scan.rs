@@ -0,0 +1,56 @@
+//! A prefix scan of 100k contiguous keys over a log in write order compared to one
+//! compaction rewrote in key order, where the values are read front to back.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use kvs::testing::FixtureRng;
+use kvs::{KvStore, KvStoreOptions};
+use tempfile::TempDir;
+
+const KEY_COUNT: usize = 100_000;
+
+/// Nothing compacts on its own, so the log stays as written until `populate` compacts it.
+fn options() -> KvStoreOptions {
+    KvStoreOptions::new()
+        .auto_compaction(false)
+        .compact_on_open(false)
+}
+
+/// `KEY_COUNT` keys under `scan:` and as many under `other:`, set in random order.
+fn populate(dir: &std::path::Path, compact: bool) {
+    let mut keys: Vec<String> = (0..KEY_COUNT)
+        .flat_map(|i| [format!("scan:{:08}", i), format!("other:{:08}", i)])
+        .collect();
+    let mut rng = FixtureRng::new(7);
+    for i in (1..keys.len()).rev() {
+        keys.swap(i, rng.range(0, i));
+    }
+
+    let mut store = KvStore::open_with_options(dir, options()).unwrap();
+    for (i, key) in keys.into_iter().enumerate() {
+        store.set(key, format!("value{:064}", i)).unwrap();
+    }
+    if compact {
+        // An overwrite leaves something to reclaim, so the whole log is rewritten.
+        store
+            .set("other:00000000".to_owned(), "again".to_owned())
+            .unwrap();
+        store.compact().unwrap();
+    }
+}
+
+fn scan(c: &mut Criterion) {
+    let mut group = c.benchmark_group("compacted_scan");
+    group.sample_size(10);
+    for (name, compact) in [("write_order", false), ("key_order", true)] {
+        let temp_dir = TempDir::new().unwrap();
+        populate(temp_dir.path(), compact);
+        let store = KvStore::open_with_options(temp_dir.path(), options()).unwrap();
+        group.bench_function(BenchmarkId::from_parameter(name), |b| {
+            b.iter(|| assert_eq!(store.scan_prefix("scan:").unwrap().len(), KEY_COUNT))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, scan);
+criterion_main!(benches);
@@ -0,0 +1,60 @@
+//! Loading an NDJSON export with `import` compared to a `set` per entry.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use kvs::{ExportFormat, ImportMode, KvStore};
+use std::io::Write;
+use tempfile::TempDir;
+
+const ENTRIES: usize = 1_000_000;
+
+fn ndjson() -> Vec<u8> {
+    let mut lines = Vec::new();
+    for i in 0..ENTRIES {
+        writeln!(
+            lines,
+            "{{\"key\":\"key{:08}\",\"value\":\"value{}\"}}",
+            i, i
+        )
+        .unwrap();
+    }
+    lines
+}
+
+fn set_each(store: &mut KvStore, lines: &[u8]) {
+    for line in lines.split(|&byte| byte == b'\n') {
+        if line.is_empty() {
+            continue;
+        }
+        let entry: serde_json::Value = serde_json::from_slice(line).unwrap();
+        let key = entry["key"].as_str().unwrap().to_owned();
+        let value = entry["value"].as_str().unwrap().to_owned();
+        store.set(key, value).unwrap();
+    }
+}
+
+fn import(store: &mut KvStore, lines: &[u8]) {
+    let stats = store
+        .import(lines, ExportFormat::JsonLines, ImportMode::Overwrite)
+        .unwrap();
+    assert_eq!(stats.imported, ENTRIES as u64);
+}
+
+fn load(c: &mut Criterion) {
+    let lines = ndjson();
+    let mut group = c.benchmark_group("load_1m_ndjson");
+    group.sample_size(10);
+    for (name, run) in [
+        ("set_each", set_each as fn(&mut KvStore, &[u8])),
+        ("import", import),
+    ] {
+        let temp_dir = TempDir::new().unwrap();
+        let mut store = KvStore::open(temp_dir.path()).unwrap();
+        group.bench_function(BenchmarkId::from_parameter(name), |b| {
+            b.iter(|| run(&mut store, &lines))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, load);
+criterion_main!(benches);
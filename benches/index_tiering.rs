@@ -0,0 +1,43 @@
+//! Cost of a get answered from the on-disk index tier compared to the in-memory one.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use kvs::testing::FixtureRng;
+use kvs::{KvStore, KvStoreOptions};
+use tempfile::TempDir;
+
+const KEY_COUNT: usize = 20_000;
+
+fn populate(dir: &std::path::Path) {
+    let mut store = KvStore::open(dir).unwrap();
+    for i in 0..KEY_COUNT {
+        store
+            .set(format!("key{:08}", i), format!("value{}", i))
+            .unwrap();
+    }
+}
+
+fn get(c: &mut Criterion) {
+    let temp_dir = TempDir::new().unwrap();
+    populate(temp_dir.path());
+
+    let mut group = c.benchmark_group("get");
+    let tiers = [
+        ("in_memory", KvStoreOptions::new()),
+        // Holds only a handful of entries, so nearly every random get goes to disk.
+        ("cold", KvStoreOptions::new().max_index_bytes(4096)),
+    ];
+    for (name, options) in tiers {
+        let store = KvStore::open_with_options(temp_dir.path(), options).unwrap();
+        let mut rng = FixtureRng::new(7);
+        group.bench_function(BenchmarkId::from_parameter(name), |b| {
+            b.iter(|| {
+                let key = format!("key{:08}", rng.range(0, KEY_COUNT - 1));
+                store.get(&key).unwrap().unwrap()
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, get);
+criterion_main!(benches);
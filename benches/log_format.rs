@@ -0,0 +1,64 @@
+//! Sets and gets against a JSON log compared to bincode and MessagePack ones.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use kvs::testing::FixtureRng;
+use kvs::{KvStore, KvStoreOptions, LogFormat};
+use std::path::Path;
+use tempfile::TempDir;
+
+const KEY_COUNT: usize = 20_000;
+/// Sets per iteration of the set benchmark.
+const SETS: usize = 1_000;
+
+const FORMATS: [LogFormat; 3] = [LogFormat::Json, LogFormat::Bincode, LogFormat::MessagePack];
+
+fn open(dir: &Path, format: LogFormat) -> KvStore {
+    KvStore::open_with_options(dir, KvStoreOptions::new().format(format)).unwrap()
+}
+
+/// A value with quotes and a newline, which the JSON log escapes and the framed ones do not.
+fn value(i: usize) -> String {
+    format!("value \"{}\"\n{}", i, "x".repeat(100))
+}
+
+fn set(c: &mut Criterion) {
+    let mut group = c.benchmark_group("log_format_set");
+    for format in FORMATS {
+        let temp_dir = TempDir::new().unwrap();
+        let mut store = open(temp_dir.path(), format);
+        let mut i = 0;
+        group.bench_function(BenchmarkId::from_parameter(format), |b| {
+            b.iter(|| {
+                for _ in 0..SETS {
+                    store
+                        .set(format!("key{:08}", i % KEY_COUNT), value(i))
+                        .unwrap();
+                    i += 1;
+                }
+            })
+        });
+    }
+    group.finish();
+}
+
+fn get(c: &mut Criterion) {
+    let mut group = c.benchmark_group("log_format_get");
+    for format in FORMATS {
+        let temp_dir = TempDir::new().unwrap();
+        let mut store = open(temp_dir.path(), format);
+        for i in 0..KEY_COUNT {
+            store.set(format!("key{:08}", i), value(i)).unwrap();
+        }
+        let mut rng = FixtureRng::new(7);
+        group.bench_function(BenchmarkId::from_parameter(format), |b| {
+            b.iter(|| {
+                let key = format!("key{:08}", rng.next_u64() as usize % KEY_COUNT);
+                store.get(&key).unwrap().unwrap()
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, set, get);
+criterion_main!(benches);
@@ -0,0 +1,48 @@
+//! Fetching 64 random keys with one `multi_get` compared to a loop of `get`s.
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use kvs::testing::FixtureRng;
+use kvs::KvStore;
+use tempfile::TempDir;
+
+const KEY_COUNT: usize = 20_000;
+const BATCH: usize = 64;
+
+fn populate(dir: &std::path::Path) {
+    let mut store = KvStore::open(dir).unwrap();
+    for i in 0..KEY_COUNT {
+        store
+            .set(format!("key{:08}", i), format!("value{}", i))
+            .unwrap();
+    }
+}
+
+fn batch(rng: &mut FixtureRng) -> Vec<String> {
+    (0..BATCH)
+        .map(|_| format!("key{:08}", rng.next_u64() as usize % KEY_COUNT))
+        .collect()
+}
+
+fn multi_get(c: &mut Criterion) {
+    let temp_dir = TempDir::new().unwrap();
+    populate(temp_dir.path());
+    let store = KvStore::open(temp_dir.path()).unwrap();
+
+    let mut group = c.benchmark_group("multi_get");
+    let mut rng = FixtureRng::new(7);
+    group.bench_function("get_loop", |b| {
+        b.iter(|| {
+            for key in batch(&mut rng) {
+                store.get(&key).unwrap().unwrap();
+            }
+        })
+    });
+    let mut rng = FixtureRng::new(7);
+    group.bench_function("multi_get", |b| {
+        b.iter(|| store.multi_get(&batch(&mut rng)).unwrap())
+    });
+    group.finish();
+}
+
+criterion_group!(benches, multi_get);
+criterion_main!(benches);
@@ -0,0 +1,64 @@
+//! Point gets and narrow range scans with and without `KvStoreOptions::ordered_index`.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use kvs::testing::FixtureRng;
+use kvs::{KvStore, KvStoreOptions};
+use tempfile::TempDir;
+
+const KEY_COUNT: usize = 20_000;
+
+fn populate(dir: &std::path::Path) {
+    let mut store = KvStore::open(dir).unwrap();
+    for i in 0..KEY_COUNT {
+        store
+            .set(format!("key{:08}", i), format!("value{}", i))
+            .unwrap();
+    }
+}
+
+fn indexes() -> [(&'static str, KvStoreOptions); 2] {
+    [
+        ("hash", KvStoreOptions::new()),
+        ("ordered", KvStoreOptions::new().ordered_index(true)),
+    ]
+}
+
+fn get(c: &mut Criterion) {
+    let temp_dir = TempDir::new().unwrap();
+    populate(temp_dir.path());
+
+    let mut group = c.benchmark_group("ordered_index_get");
+    for (name, options) in indexes() {
+        let store = KvStore::open_with_options(temp_dir.path(), options).unwrap();
+        let mut rng = FixtureRng::new(7);
+        group.bench_function(BenchmarkId::from_parameter(name), |b| {
+            b.iter(|| {
+                let key = format!("key{:08}", rng.next_u64() as usize % KEY_COUNT);
+                store.get(&key).unwrap().unwrap()
+            })
+        });
+    }
+    group.finish();
+}
+
+fn range(c: &mut Criterion) {
+    let temp_dir = TempDir::new().unwrap();
+    populate(temp_dir.path());
+
+    let mut group = c.benchmark_group("ordered_index_range");
+    for (name, options) in indexes() {
+        let store = KvStore::open_with_options(temp_dir.path(), options).unwrap();
+        let mut rng = FixtureRng::new(7);
+        group.bench_function(BenchmarkId::from_parameter(name), |b| {
+            b.iter(|| {
+                let start = rng.next_u64() as usize % (KEY_COUNT - 50);
+                let range = format!("key{:08}", start)..format!("key{:08}", start + 50);
+                assert_eq!(store.range(range).unwrap().len(), 50);
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, get, range);
+criterion_main!(benches);
@@ -1,68 +1,213 @@
 use clap::{Parser, Subcommand};
-use kvs::kv_store::KvStore;
-use std::{env, process};
+use kvs::cli::args::parse_duration;
+use kvs::cli::style::{ColorChoice, Stream, Style};
+use kvs::sharded_client::DEFAULT_REPLICAS_PER_NODE;
+use kvs::{KvError, KvsClient, KvsClientOptions, Result, ShardedKvsClient};
+use serde::Deserialize;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::time::Duration;
 
 #[derive(Parser)]
 #[command(version, about, long_about = None)]
 struct Args {
     #[command(subcommand)]
     cmd: Commands,
-    #[arg(short, long)]
-    addr: String,
+    #[arg(short, long, required_unless_present = "nodes")]
+    addr: Option<String>,
+    /// Shard keys over these servers instead of talking to one `--addr`.
+    #[arg(long, value_delimiter = ',', conflicts_with = "addr")]
+    nodes: Vec<SocketAddr>,
+    #[arg(long, value_enum, default_value_t = ColorChoice::Auto)]
+    color: ColorChoice,
+    /// How long to wait for the connection, e.g. 500ms or 5s
+    #[arg(long, value_parser = parse_duration, default_value = "5s")]
+    connect_timeout: Duration,
+    /// How long each request may wait for the server's answer
+    #[arg(long, value_parser = parse_duration, default_value = "30s")]
+    timeout: Duration,
 }
 
 #[derive(Subcommand, Debug, Clone)]
 enum Commands {
-    Get { key: String },
-    Set { key: String, value: String },
-    Rm { key: String },
+    Get {
+        key: String,
+    },
+    Set {
+        key: String,
+        value: String,
+    },
+    Rm {
+        key: String,
+    },
+    /// Make an existing key expire after `--ttl`, e.g. 30m, without sending its value
+    Touch {
+        key: String,
+        #[arg(long, value_parser = parse_duration)]
+        ttl: Duration,
+    },
+    /// Set the pairs in a file of `{"key": ..., "value": ...}` lines, in batches
+    Import {
+        #[arg(long = "in")]
+        input: PathBuf,
+        #[arg(long, default_value_t = 1000)]
+        bulk_size: usize,
+    },
 }
 
-fn main() {
-    let args = Args::parse();
-    let cwd = env::current_dir().unwrap();
+#[derive(Deserialize)]
+struct ImportRecord {
+    key: String,
+    value: String,
+}
+
+enum Client {
+    Single(KvsClient),
+    Sharded(ShardedKvsClient),
+}
+
+impl Client {
+    fn get(&mut self, key: String) -> Result<Option<String>> {
+        match self {
+            Client::Single(client) => client.get(key),
+            Client::Sharded(client) => client.get(key),
+        }
+    }
 
-    if args.addr.len() == 0 {
-        process::exit(1);
+    fn set(&mut self, key: String, value: String) -> Result<()> {
+        match self {
+            Client::Single(client) => client.set(key, value),
+            Client::Sharded(client) => client.set(key, value),
+        }
     }
 
-    let mut kv_store = match KvStore::open(&cwd) {
-        Ok(kv_store) => kv_store,
-        Err(e) => {
-            println!("Failed to create key-value store: {}", e);
-            process::exit(0);
+    fn remove(&mut self, key: String) -> Result<()> {
+        match self {
+            Client::Single(client) => client.remove(key),
+            Client::Sharded(client) => client.remove(key),
         }
-    };
+    }
 
-    match args.cmd {
-        Commands::Get { key } => {
-            let value = kv_store.get(key);
-            match value {
-                Ok(value) => match value {
-                    Some(value) => println!("{value}"),
-                    _ => println!("Key not found"),
-                },
+    fn touch(&mut self, key: String, ttl: Duration) -> Result<bool> {
+        match self {
+            Client::Single(client) => client.touch(key, ttl),
+            Client::Sharded(client) => client.touch(key, ttl),
+        }
+    }
+
+    fn set_bulk(&mut self, pairs: Vec<(String, String)>) -> Result<u64> {
+        match self {
+            Client::Single(client) => client.set_bulk(pairs).map(|applied| applied.count),
+            Client::Sharded(client) => client.set_bulk(pairs),
+        }
+    }
+}
+
+fn main() {
+    let args = Args::parse();
+    let out = Style::resolve(args.color, Stream::Stdout);
+    let err = Style::resolve(args.color, Stream::Stderr);
+
+    let options = KvsClientOptions::new()
+        .connect_timeout(args.connect_timeout)
+        .operation_timeout(args.timeout);
+    let mut client = match args.addr {
+        Some(ref addr) if addr.is_empty() => process::exit(1),
+        Some(ref addr) => match KvsClient::connect_with_options(addr, options) {
+            Ok(client) => Client::Single(client),
+            Err(e) => {
+                let message = format!("Failed to connect to {}:", addr);
+                eprintln!("{} {}", err.error(&message), e);
+                process::exit(1);
+            }
+        },
+        None => {
+            match ShardedKvsClient::with_options(args.nodes, DEFAULT_REPLICAS_PER_NODE, options) {
+                Ok(client) => Client::Sharded(client),
                 Err(e) => {
-                    eprint!("Error getting value: {}", e);
+                    eprintln!("{} {}", err.error("Failed to build the node ring:"), e);
                     process::exit(1);
                 }
             }
         }
-        Commands::Set { key, value } => match kv_store.set(key, value) {
-            Ok(_) => (),
-            Err(_) => {
-                println!("Failed to set key");
+    };
+
+    match args.cmd {
+        Commands::Get { key } => match client.get(key) {
+            Ok(Some(value)) => println!("{value}"),
+            Ok(None) => println!("{}", out.warning("Key not found")),
+            Err(e) => {
+                eprintln!("{} {}", err.error("Error getting value:"), e);
+                process::exit(1);
+            }
+        },
+        Commands::Set { key, value } => {
+            if let Err(e) = client.set(key, value) {
+                eprintln!("{} {}", err.error("Failed to set key:"), e);
+                process::exit(1);
+            }
+        }
+        Commands::Rm { key } => match client.remove(key) {
+            Ok(()) => (),
+            Err(KvError::RemoveError(_)) => {
+                println!("{}", out.warning("Key not found"));
+                process::exit(1);
+            }
+            Err(e) => {
+                eprintln!("{}", err.error(&e.to_string()));
                 process::exit(1);
             }
         },
-        Commands::Rm { key } => match kv_store.remove(key) {
-            Ok(_) => (),
-            Err(_) => {
-                println!("Key not found");
+        Commands::Touch { key, ttl } => match client.touch(key, ttl) {
+            Ok(true) => (),
+            Ok(false) => {
+                println!("{}", out.warning("Key not found"));
+                process::exit(1);
+            }
+            Err(e) => {
+                eprintln!("{} {}", err.error("Failed to touch key:"), e);
                 process::exit(1);
             }
         },
+        Commands::Import { input, bulk_size } => {
+            match import(&mut client, &input, bulk_size.max(1)) {
+                Ok(count) => println!("Imported {} pairs", count),
+                Err(message) => {
+                    eprintln!("{} {}", err.error("Failed to import:"), message);
+                    process::exit(1);
+                }
+            }
+        }
     }
 
     process::exit(0);
 }
+
+/// Sends the pairs in `input` as `bulk_size` batches. Batches already sent stay applied
+/// when a later line or batch fails.
+fn import(client: &mut Client, input: &Path, bulk_size: usize) -> std::result::Result<u64, String> {
+    let file = File::open(input).map_err(|e| format!("{}: {}", input.display(), e))?;
+    let mut imported = 0;
+    let mut batch = Vec::with_capacity(bulk_size);
+    for (number, line) in BufReader::new(file).lines().enumerate() {
+        let line = line.map_err(|e| format!("{}: {}", input.display(), e))?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let record: ImportRecord = serde_json::from_str(&line)
+            .map_err(|e| format!("{} line {}: {}", input.display(), number + 1, e))?;
+        batch.push((record.key, record.value));
+        if batch.len() == bulk_size {
+            imported += client
+                .set_bulk(std::mem::take(&mut batch))
+                .map_err(|e| e.to_string())?;
+        }
+    }
+    if !batch.is_empty() {
+        imported += client.set_bulk(batch).map_err(|e| e.to_string())?;
+    }
+    Ok(imported)
+}
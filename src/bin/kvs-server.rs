@@ -5,9 +5,12 @@ extern crate slog_term;
 
 use slog::Drain;
 
-use clap::{Parser, Subcommand};
-use kvs::{kvs_server, KvStore};
-use slog_term::Decorator;
+use clap::Parser;
+use kvs::cli::style::{ColorChoice, Stream, Style};
+use kvs::display::{set_display_cap, DEFAULT_DISPLAY_CAP};
+use kvs::shedding::{LoadShedding, Watermarks};
+use kvs::{KvStore, KvStoreOptions, KvsServer};
+use std::path::PathBuf;
 use std::{env, process};
 
 #[derive(Parser)]
@@ -17,63 +20,149 @@ struct Args {
     addr: String,
     #[arg(short, long)]
     engine: String,
+    /// Directory holding the store, created if it does not exist yet
+    #[arg(long)]
+    data_dir: Option<PathBuf>,
+    /// Log values as `<len=N>` instead of their contents
+    #[arg(long)]
+    redact_values: bool,
+    /// Maximum number of bytes of a key or value written to logs and error messages
+    #[arg(long, default_value_t = DEFAULT_DISPLAY_CAP)]
+    log_value_cap: usize,
+    /// Color log lines and errors: auto colors only a terminal, and honors NO_COLOR
+    #[arg(long, value_enum, default_value_t = ColorChoice::Auto)]
+    color: ColorChoice,
+    /// Account usage per key prefix of this many `/`-separated segments; 0 turns it off
+    #[arg(long, default_value_t = 0)]
+    accounting_depth: usize,
+    /// Most key prefixes accounted separately, the rest share one bucket
+    #[arg(long, default_value_t = kvs::accounting::DEFAULT_MAX_PREFIXES)]
+    accounting_max_prefixes: usize,
+    /// Shed reads while this many requests are queued for the store; omit to never shed
+    #[arg(long)]
+    read_high_watermark: Option<usize>,
+    /// Serve reads again once the queue is down to this many, half the high one by default
+    #[arg(long, requires = "read_high_watermark")]
+    read_low_watermark: Option<usize>,
+    /// Shed writes while this many requests are queued for the store; omit to never shed
+    #[arg(long)]
+    write_high_watermark: Option<usize>,
+    /// Serve writes again once the queue is down to this many, half the high one by default
+    #[arg(long, requires = "write_high_watermark")]
+    write_low_watermark: Option<usize>,
+    /// Finish warming up the store, compaction included, before accepting connections,
+    /// instead of serving at once and reporting warming_up in health checks
+    #[arg(long)]
+    wait_ready_before_listen: bool,
+    /// Refuse sets of keys longer than this many bytes; omit for no limit
+    #[arg(long)]
+    max_key_size: Option<u64>,
+    /// Refuse sets of values longer than this many bytes, chunked uploads included; omit for
+    /// no limit
+    #[arg(long)]
+    max_value_size: Option<u64>,
 }
 
 fn main() {
-    let decorator = slog_term::TermDecorator::new().build();
-    let drain = slog_term::FullFormat::new(decorator).build().fuse();
-    let drain = slog_async::Async::new(drain).build().fuse();
-
-    let _log = slog::Logger::root(drain, o!());
-
     let args = Args::parse();
+    let err = Style::resolve(args.color, Stream::Stderr);
+    let log = setup_logger(err);
 
-    if args.addr.len() == 0 || args.engine.len() == 0 {
+    if args.addr.is_empty() || args.engine.is_empty() {
         process::exit(1);
     }
-
-    let kvs_server = KvsServer::new(args.addr);
-    kvs_server.listen_forever();
-    process::exit(0);
-}
-
-fn setup_logger() {}
-
-use std::{
-    io::Read,
-    net::{TcpListener, TcpStream},
-};
-
-pub struct KvsServer {
-    tcp_listener: TcpListener,
-}
-
-impl KvsServer {
-    pub fn new(ip_addr: String) -> KvsServer {
-        let tcp_listener = TcpListener::bind(ip_addr).unwrap();
-
-        KvsServer { tcp_listener }
+    set_display_cap(args.log_value_cap);
+    let shedding = LoadShedding {
+        reads: watermarks(
+            "read",
+            args.read_high_watermark,
+            args.read_low_watermark,
+            err,
+        ),
+        writes: watermarks(
+            "write",
+            args.write_high_watermark,
+            args.write_low_watermark,
+            err,
+        ),
+    };
+
+    let data_dir = match args.data_dir {
+        Some(dir) => dir,
+        None => env::current_dir().unwrap(),
+    };
+    let mut options = KvStoreOptions::new()
+        .event_sink(kvs::events::stderr_sink())
+        .create_if_missing(true)
+        .accounting_prefix_depth(args.accounting_depth)
+        .accounting_max_prefixes(args.accounting_max_prefixes)
+        .defer_warm_up(!args.wait_ready_before_listen);
+    if let Some(max_key_size) = args.max_key_size {
+        options = options.max_key_size(max_key_size);
     }
-
-    pub fn listen_forever(&self) -> Result<(), std::io::Error> {
-        for stream in self.tcp_listener.incoming() {
-            match stream {
-                Ok(mut stream) => {
-                    handle_connection(&mut stream)?;
-                }
-                Err(_) => {}
-            }
+    if let Some(max_value_size) = args.max_value_size {
+        options = options.max_value_size(max_value_size);
+    }
+    let kv_store = match KvStore::open_with_options(&data_dir, options) {
+        Ok(kv_store) => kv_store,
+        Err(e) => {
+            eprintln!("{} {}", err.error("Failed to open data directory:"), e);
+            process::exit(1);
         }
-
-        Ok(())
+    };
+
+    let kvs_server = match KvsServer::new(&args.addr, kv_store, log.clone()) {
+        Ok(kvs_server) => kvs_server
+            .redact_values(args.redact_values)
+            .load_shedding(shedding),
+        Err(e) => {
+            let message = format!("Failed to bind {}:", args.addr);
+            eprintln!("{} {}", err.error(&message), e);
+            process::exit(1);
+        }
+    };
+    info!(log, "listening"; "addr" => &args.addr, "engine" => &args.engine);
+    if let Err(e) = kvs_server.listen_forever() {
+        error!(log, "server stopped"; "error" => %e);
+        process::exit(1);
     }
+    process::exit(0);
 }
 
-fn handle_connection(stream: &mut TcpStream) -> Result<(), std::io::Error> {
-    let mut buffer: Vec<u8> = Vec::new();
+fn watermarks(
+    class: &str,
+    high: Option<usize>,
+    low: Option<usize>,
+    err: Style,
+) -> Option<Watermarks> {
+    let high = high?;
+    let low = low.unwrap_or(high / 2);
+    if low >= high {
+        let message = format!("Invalid {} watermarks:", class);
+        eprintln!(
+            "{} the low watermark {} must be below the high one, {}",
+            err.error(&message),
+            low,
+            high
+        );
+        process::exit(1);
+    }
+    Some(Watermarks::new(high, low))
+}
 
-    stream.read_to_end(&mut buffer)?;
-    // Deserialize this to a command, we will need to implement a shared library for the commands
-    // as well as a method that can Deserialize from bytes.
-    Ok(())
+fn setup_logger(style: Style) -> slog::Logger {
+    // The plain decorator never writes escapes, whatever the terminal claims to support.
+    let drain = if style.is_colored() {
+        let decorator = slog_term::TermDecorator::new().force_color().build();
+        slog_async::Async::new(slog_term::FullFormat::new(decorator).build().fuse())
+            .build()
+            .fuse()
+    } else {
+        let decorator = slog_term::PlainDecorator::new(std::io::stderr());
+        slog_async::Async::new(slog_term::FullFormat::new(decorator).build().fuse())
+            .build()
+            .fuse()
+    };
+
+    slog::Logger::root(drain, o!())
 }
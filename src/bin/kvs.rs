@@ -1,62 +1,452 @@
-use clap::{Parser, Subcommand};
-use kvs::KvStore;
-use std::{env, process};
+use clap::{Parser, Subcommand, ValueEnum};
+use kvs::canonical::{self, Change};
+use kvs::cli::args::parse_duration;
+use kvs::cli::report;
+use kvs::cli::style::{ColorChoice, Stream, Style};
+use kvs::cli::table::OutputFormat;
+use kvs::events;
+use kvs::{ExportFormat, ImportMode, KvError, KvStore, KvStoreOptions};
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use std::{env, process, thread};
 
 #[derive(Parser)]
 #[command(version, about, long_about = None)]
 struct Args {
     #[command(subcommand)]
     cmd: Commands,
+    /// Directory holding the store, defaults to the current directory
+    #[arg(long, global = true)]
+    dir: Option<PathBuf>,
+    #[arg(long, global = true, value_enum, default_value_t = ColorChoice::Auto)]
+    color: ColorChoice,
 }
 
 #[derive(Subcommand, Debug, Clone)]
 enum Commands {
-    Get { key: String },
-    Set { key: String, value: String },
-    Rm { key: String },
+    Get {
+        key: String,
+    },
+    Set {
+        key: String,
+        value: String,
+    },
+    /// Remove KEY, or with --prefix every key under PREFIX, which needs --yes
+    Rm {
+        #[arg(required_unless_present = "prefix", conflicts_with = "prefix")]
+        key: Option<String>,
+        #[arg(long)]
+        prefix: Option<String>,
+        /// Confirm removing every key under --prefix
+        #[arg(long, requires = "prefix")]
+        yes: bool,
+    },
+    /// Add DELTA, 1 by default, to the integer at KEY, a missing key counting as 0, and
+    /// print the result
+    Incr {
+        key: String,
+        #[arg(default_value_t = 1, allow_negative_numbers = true)]
+        delta: i64,
+    },
+    /// Compact the log and report what that reclaimed, or with --dry-run only report what
+    /// compacting would reclaim
+    ///
+    /// The report gives the log's size before and after, headers included, and the bytes
+    /// reclaimed. It also gives the records dropped, which are sets overwritten or removed
+    /// since the last compaction and removes no longer needed, and how long it took.
+    /// With --output json these are `log_bytes_before`, `log_bytes_after`,
+    /// `records_dropped` and `duration`.
+    Compact {
+        #[arg(long)]
+        dry_run: bool,
+        #[arg(long, value_enum, default_value_t = OutputFormat::Table)]
+        output: OutputFormat,
+    },
+    /// Exchange the keys under prefix A with those under prefix B in one batch
+    SwapPrefix {
+        a: String,
+        b: String,
+    },
+    /// Load the entries of FILE, JSON lines as `KvStore::export` writes them or a CSV file
+    /// taking keys and values from the given columns
+    Import {
+        #[arg(long, value_enum, default_value_t = ImportFormat::Jsonl)]
+        format: ImportFormat,
+        /// CSV column of the keys, counting from 0
+        #[arg(long, default_value_t = 0)]
+        key_col: usize,
+        /// CSV column of the values, counting from 0
+        #[arg(long, default_value_t = 1)]
+        value_col: usize,
+        /// Skip the first row of the CSV file
+        #[arg(long)]
+        has_header: bool,
+        file: PathBuf,
+    },
+    /// Work on the raw log offline, without opening the store
+    Log {
+        #[command(subcommand)]
+        cmd: LogCommands,
+    },
+    /// Print keys under PREFIX as another process changes them, polling the store read-only
+    WatchDir {
+        #[arg(long, default_value = "1s", value_parser = parse_duration)]
+        interval: Duration,
+        prefix: String,
+    },
+    /// Generate a deterministic fixture directory (see `kvs::testing::FixtureBuilder`)
+    #[cfg(feature = "test-util")]
+    #[command(hide = true)]
+    GenFixture {
+        #[arg(long)]
+        out: PathBuf,
+        #[arg(long, default_value_t = 0)]
+        seed: u64,
+        #[arg(long, default_value_t = 1_000)]
+        keys: usize,
+        /// N, MIN-MAX or SMALL/LARGE@RATIO
+        #[arg(long, default_value = "8-24")]
+        key_len: kvs::testing::SizeDistribution,
+        /// N, MIN-MAX or SMALL/LARGE@RATIO
+        #[arg(long, default_value = "16-128")]
+        value_len: kvs::testing::SizeDistribution,
+        #[arg(long, default_value_t = 1.0)]
+        overwrite_factor: f64,
+        #[arg(long, default_value_t = 0.0)]
+        delete_ratio: f64,
+        #[arg(long)]
+        no_compact: bool,
+    },
+}
+
+#[derive(Subcommand, Debug, Clone)]
+enum LogCommands {
+    /// Write every record of the log as editable text (see `kvs::canonical`)
+    Export {
+        #[arg(long, value_enum, default_value_t = LogFormat::Canonical)]
+        format: LogFormat,
+        /// File to write, defaults to stdout
+        #[arg(long)]
+        out: Option<PathBuf>,
+    },
+    /// Build a new store directory from an exported, possibly edited, file
+    Build {
+        #[arg(long)]
+        from: PathBuf,
+        #[arg(long)]
+        out: PathBuf,
+        /// Report which keys differ from the store in this directory
+        #[arg(long)]
+        diff_against: Option<PathBuf>,
+    },
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy)]
+enum LogFormat {
+    Canonical,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy)]
+enum ImportFormat {
+    Jsonl,
+    Csv,
 }
 
 fn main() {
     let args = Args::parse();
-    let cwd = env::current_dir().unwrap();
+    let out = Style::resolve(args.color, Stream::Stdout);
+    let err = Style::resolve(args.color, Stream::Stderr);
+
+    #[cfg(feature = "test-util")]
+    if let Commands::GenFixture { .. } = args.cmd {
+        gen_fixture(args.cmd);
+    }
+    let dir = match args.dir {
+        Some(dir) => dir,
+        None => env::current_dir().unwrap(),
+    };
+    if let Commands::Log { cmd } = args.cmd {
+        if let Err(e) = log_command(cmd, &dir) {
+            eprintln!("{} {}", err.error("Failed:"), e);
+            process::exit(1);
+        }
+        process::exit(0);
+    }
+    if let Commands::WatchDir { interval, prefix } = args.cmd {
+        if let Err(e) = watch_dir(&dir, interval, &prefix) {
+            eprintln!("{} {}", err.error("Failed:"), e);
+            process::exit(1);
+        }
+        process::exit(0);
+    }
 
-    let mut kv_store = match KvStore::open(&cwd) {
+    // `get` opens the store read-only and under the shared lock, so it works on the
+    // directory of a running server. A directory without a log yet is opened for writing
+    // as before, which creates the store. `compact --dry-run` still opens for writing, as
+    // its estimate is of the log after the compaction open runs; `compact` skips that
+    // compaction, so that the one it reports on is what reclaims the log.
+    let read_only = matches!(args.cmd, Commands::Get { .. }) && KvStore::log_exists(&dir);
+    let compacting = matches!(args.cmd, Commands::Compact { dry_run: false, .. });
+    let options = KvStoreOptions::new()
+        .compact_on_open(!compacting)
+        .read_only(read_only)
+        .shared_lock(read_only)
+        .event_sink(events::stderr_sink());
+    let mut kv_store = match KvStore::open_with_options(&dir, options) {
         Ok(kv_store) => kv_store,
         Err(e) => {
-            println!("Failed to create key-value store: {}", e);
-            process::exit(0);
+            eprintln!("{} {}", err.error("Failed to create key-value store:"), e);
+            process::exit(1);
         }
     };
 
     match args.cmd {
         Commands::Get { key } => {
-            let value = kv_store.get(key);
+            let value = kv_store.get(&key);
             match value {
                 Ok(value) => match value {
                     Some(value) => println!("{value}"),
-                    _ => println!("Key not found"),
+                    _ => println!("{}", out.warning("Key not found")),
                 },
                 Err(e) => {
-                    eprint!("Error getting value: {}", e);
+                    eprintln!("{} {}", err.error("Error getting value:"), e);
                     process::exit(1);
                 }
             }
         }
         Commands::Set { key, value } => match kv_store.set(key, value) {
             Ok(_) => (),
-            Err(_) => {
-                println!("Failed to set key");
+            Err(e) => {
+                eprintln!("{} {}", err.error("Failed to set key:"), e);
                 process::exit(1);
             }
         },
-        Commands::Rm { key } => match kv_store.remove(key) {
+        Commands::Rm {
+            prefix: Some(prefix),
+            yes,
+            ..
+        } => {
+            if !yes {
+                eprintln!(
+                    "{} pass --yes to remove every key under {:?}",
+                    err.error("Refusing to remove keys:"),
+                    prefix
+                );
+                process::exit(1);
+            }
+            match kv_store.remove_prefix(&prefix) {
+                Ok(removed) => println!("Removed {} keys under {:?}", removed, prefix),
+                Err(e) => {
+                    eprintln!("{}", err.error(&e.to_string()));
+                    process::exit(1);
+                }
+            }
+        }
+        Commands::Rm { key, .. } => match kv_store.remove(key.expect("clap requires a key")) {
             Ok(_) => (),
-            Err(_) => {
-                println!("Key not found");
+            Err(KvError::RemoveError(_)) => {
+                println!("{}", out.warning("Key not found"));
+                process::exit(1);
+            }
+            Err(e) => {
+                eprintln!("{}", err.error(&e.to_string()));
+                process::exit(1);
+            }
+        },
+        Commands::Incr { key, delta } => match kv_store.increment(&key, delta) {
+            Ok(value) => println!("{value}"),
+            Err(e) => {
+                eprintln!("{} {}", err.error("Failed to increment:"), e);
+                process::exit(1);
+            }
+        },
+        Commands::Compact {
+            dry_run: true,
+            output,
+        } => match kv_store.compact_dry_run() {
+            Ok(estimate) => match output {
+                OutputFormat::Table => print!("{}", report::compaction(&estimate).render(out)),
+                OutputFormat::Json => println!("{}", serde_json::to_string(&estimate).unwrap()),
+            },
+            Err(e) => {
+                eprintln!("{} {}", err.error("Failed to compact:"), e);
+                process::exit(1);
+            }
+        },
+        Commands::Compact {
+            dry_run: false,
+            output,
+        } => match kv_store.compact() {
+            Ok(stats) => match output {
+                OutputFormat::Table => print!("{}", report::compacted(&stats).render(out)),
+                OutputFormat::Json => println!("{}", serde_json::to_string(&stats).unwrap()),
+            },
+            Err(e) => {
+                eprintln!("{} {}", err.error("Failed to compact:"), e);
+                process::exit(1);
+            }
+        },
+        Commands::SwapPrefix { a, b } => match kv_store.swap_prefixes(&a, &b) {
+            Ok(stats) => println!(
+                "Moved {} keys from {:?} to {:?} and {} keys back in {} records",
+                stats.moved_from_a, a, b, stats.moved_from_b, stats.records_written
+            ),
+            Err(e) => {
+                eprintln!("{} {}", err.error("Failed to swap prefixes:"), e);
                 process::exit(1);
             }
         },
+        Commands::Import {
+            format,
+            key_col,
+            value_col,
+            has_header,
+            file,
+        } => {
+            let result = File::open(&file)
+                .map_err(KvError::from)
+                .and_then(|file| match format {
+                    ImportFormat::Jsonl => {
+                        kv_store.import(file, ExportFormat::JsonLines, ImportMode::Overwrite)
+                    }
+                    ImportFormat::Csv => kv_store.import_csv(file, key_col, value_col, has_header),
+                });
+            match result {
+                Ok(stats) => {
+                    println!("Imported {} entries", stats.imported);
+                    if let Some(line) = stats.first_malformed_line {
+                        println!(
+                            "{}",
+                            out.warning(&format!(
+                                "Skipped {} malformed rows, the first at line {}",
+                                stats.malformed, line
+                            ))
+                        );
+                    }
+                }
+                Err(e) => {
+                    eprintln!("{} {}", err.error("Failed to import:"), e);
+                    process::exit(1);
+                }
+            }
+        }
+        Commands::Log { .. } | Commands::WatchDir { .. } => unreachable!(),
+        #[cfg(feature = "test-util")]
+        Commands::GenFixture { .. } => unreachable!(),
     }
 
     process::exit(0);
 }
+
+fn log_command(cmd: LogCommands, dir: &Path) -> kvs::Result<()> {
+    match cmd {
+        LogCommands::Export {
+            format: LogFormat::Canonical,
+            out,
+        } => {
+            let mut writer: Box<dyn Write> = match out {
+                Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+                None => Box::new(BufWriter::new(io::stdout().lock())),
+            };
+            canonical::export(dir, &mut writer)?;
+            writer.flush()?;
+        }
+        LogCommands::Build {
+            from,
+            out,
+            diff_against,
+        } => {
+            let records = canonical::parse(BufReader::new(File::open(&from)?))?;
+            canonical::build(&records, &out)?;
+            println!("Built {} from {} records", out.display(), records.len());
+            if let Some(old) = diff_against {
+                let changes = canonical::diff(
+                    &canonical::store_contents(&old)?,
+                    &canonical::logical_contents(&records),
+                );
+                // Keys are shown as JSON strings, as in the canonical file.
+                for change in &changes {
+                    let (sign, key) = match *change {
+                        Change::Added(ref key) => ('+', key),
+                        Change::Removed(ref key) => ('-', key),
+                        Change::Changed(ref key) => ('~', key),
+                    };
+                    println!("{} {}", sign, serde_json::to_string(key)?);
+                }
+                println!("{} keys differ from {}", changes.len(), old.display());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Runs until killed or the store fails. Keys are shown as JSON strings, as in `log build`.
+fn watch_dir(dir: &Path, interval: Duration, prefix: &str) -> kvs::Result<()> {
+    let options = KvStoreOptions::new()
+        .read_only(true)
+        .event_sink(events::stderr_sink());
+    let mut store = KvStore::open_with_options(dir, options)?;
+    println!("Watching {} for keys under {:?}", dir.display(), prefix);
+    loop {
+        thread::sleep(interval);
+        if !store.changed_since_last_refresh()? {
+            continue;
+        }
+        // Under the reader lock like `get`, but only for the pass: held for the whole watch,
+        // it would put the writer's compactions off for as long as the watch runs.
+        let _reader_lock = store.reader_lock()?;
+        let refreshed = store.refresh()?;
+        if refreshed.reloaded {
+            println!(
+                "Log was compacted; reloaded {} records",
+                refreshed.records_applied
+            );
+        }
+        let mut keys = refreshed.changed_keys;
+        let mut seen = HashSet::new();
+        keys.retain(|key| key.starts_with(prefix) && seen.insert(key.clone()));
+        for key in keys {
+            match store.get(&key) {
+                Ok(Some(value)) => println!("set {} {}", serde_json::to_string(&key)?, value),
+                Ok(None) => println!("rm {}", serde_json::to_string(&key)?),
+                // Compacted again since the refresh; the next one reloads.
+                Err(KvError::StoreDisplaced(_)) => break,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+#[cfg(feature = "test-util")]
+fn gen_fixture(cmd: Commands) -> ! {
+    if let Commands::GenFixture {
+        out,
+        seed,
+        keys,
+        key_len,
+        value_len,
+        overwrite_factor,
+        delete_ratio,
+        no_compact,
+    } = cmd
+    {
+        let builder = kvs::testing::FixtureBuilder::new(seed)
+            .key_count(keys)
+            .key_len(key_len)
+            .value_len(value_len)
+            .overwrite_factor(overwrite_factor)
+            .delete_ratio(delete_ratio)
+            .compact(!no_compact);
+        match builder.build(&out) {
+            Ok(fixture) => println!("{}", fixture.manifest.path().display()),
+            Err(e) => {
+                eprintln!("Failed to generate fixture: {}", e);
+                process::exit(1);
+            }
+        }
+    }
+    process::exit(0);
+}
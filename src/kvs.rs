@@ -1,3 +1,38 @@
+pub mod accounting;
+pub mod backup;
+pub(crate) mod base64;
+pub mod bucket;
+pub mod canonical;
+pub mod changes;
+pub mod cli;
+pub(crate) mod codec;
+pub(crate) mod crc;
+pub mod dir_lock;
+pub mod display;
+pub(crate) mod ephemeral;
+pub mod events;
+pub mod fs_probe;
+pub mod fsutil;
+pub(crate) mod glob;
+pub(crate) mod import;
+pub(crate) mod index;
+pub mod kv_map;
 pub mod kv_store;
 pub mod kvs_client;
 pub mod kvs_server;
+pub mod merge;
+pub mod merge_policy;
+pub mod overlay;
+pub mod protocol;
+pub(crate) mod sample;
+pub(crate) mod segment;
+pub mod sharded_client;
+pub mod shedding;
+pub(crate) mod stats;
+#[cfg(feature = "test-util")]
+pub mod testing;
+pub mod typed_store;
+pub(crate) mod upload;
+pub(crate) mod value_reader;
+pub(crate) mod warm_up;
+pub mod write_batch;
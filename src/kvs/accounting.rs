@@ -0,0 +1,103 @@
+//! Per-prefix usage counters, kept when `KvStoreOptions::accounting_prefix_depth` is set.
+//!
+//! A key's prefix is its first `depth` segments split on `/`, so with a depth of 2 both
+//! `tenant/a/x` and `tenant/a/y` count towards `tenant/a`, and a key with fewer segments is
+//! its own prefix. Only `max_prefixes` prefixes are tracked; keys under any further prefix
+//! count towards `OVERFLOW_PREFIX` instead, so a burst of distinct keys cannot grow the
+//! table without bound.
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// Name under which usage beyond the tracked prefixes is reported.
+pub const OVERFLOW_PREFIX: &str = "<other>";
+
+/// Prefixes tracked when `KvStoreOptions::accounting_max_prefixes` is not set.
+pub const DEFAULT_MAX_PREFIXES: usize = 1024;
+
+/// Operations and payload bytes under one key prefix.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PrefixUsage {
+    pub gets: u64,
+    pub sets: u64,
+    pub removes: u64,
+    /// Value bytes returned by gets that found their key.
+    pub bytes_read: u64,
+    /// Key and value bytes of sets, and key bytes of removes.
+    pub bytes_written: u64,
+}
+
+#[derive(Debug)]
+pub(crate) struct Accounting {
+    depth: usize,
+    max_prefixes: usize,
+    usage: HashMap<String, PrefixUsage>,
+    overflow: PrefixUsage,
+}
+
+impl Accounting {
+    pub fn new(depth: usize, max_prefixes: usize) -> Accounting {
+        Accounting {
+            depth,
+            max_prefixes,
+            usage: HashMap::new(),
+            overflow: PrefixUsage::default(),
+        }
+    }
+
+    pub fn record_get(&mut self, key: &str, value_len: Option<usize>) {
+        let usage = self.usage_of(key);
+        usage.gets += 1;
+        usage.bytes_read += value_len.unwrap_or(0) as u64;
+    }
+
+    pub fn record_set(&mut self, key: &str, value_len: usize) {
+        let usage = self.usage_of(key);
+        usage.sets += 1;
+        usage.bytes_written += (key.len() + value_len) as u64;
+    }
+
+    pub fn record_remove(&mut self, key: &str) {
+        let usage = self.usage_of(key);
+        usage.removes += 1;
+        usage.bytes_written += key.len() as u64;
+    }
+
+    /// Usage per prefix in prefix order, with `OVERFLOW_PREFIX` last if anything overflowed.
+    pub fn snapshot(&self) -> Vec<(String, PrefixUsage)> {
+        let mut snapshot: Vec<(String, PrefixUsage)> = self
+            .usage
+            .iter()
+            .map(|(prefix, usage)| (prefix.clone(), *usage))
+            .collect();
+        snapshot.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+        if self.overflow != PrefixUsage::default() {
+            snapshot.push((OVERFLOW_PREFIX.to_owned(), self.overflow));
+        }
+        snapshot
+    }
+
+    pub fn reset(&mut self) {
+        self.usage.clear();
+        self.overflow = PrefixUsage::default();
+    }
+
+    fn usage_of(&mut self, key: &str) -> &mut PrefixUsage {
+        let prefix = prefix_of(key, self.depth);
+        if !self.usage.contains_key(prefix) {
+            if self.usage.len() >= self.max_prefixes {
+                return &mut self.overflow;
+            }
+            self.usage.insert(prefix.to_owned(), PrefixUsage::default());
+        }
+        self.usage.get_mut(prefix).expect("inserted above")
+    }
+}
+
+fn prefix_of(key: &str, depth: usize) -> &str {
+    match key.match_indices('/').nth(depth.saturating_sub(1)) {
+        Some((end, _)) if depth > 0 => &key[..end],
+        _ => key,
+    }
+}
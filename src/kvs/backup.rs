@@ -0,0 +1,175 @@
+//! Backups: a checkpoint of a store with a manifest that tells a whole copy from a damaged
+//! one.
+//!
+//! `KvStore::backup` writes a checkpoint, a compacted log under `DEFAULT_LOG_FILE_NAME`,
+//! and then `MANIFEST_FILE_NAME`, a `BackupManifest` with the log's length and checksum.
+//! The manifest goes last, so a backup cut short has none and is refused as corrupt rather
+//! than restored as a store missing keys. `KvStore::restore` copies the log into a temp file
+//! beside the destination's, checking it against the manifest as it goes, and renames it
+//! into place only once it matches.
+
+use crate::kvs::dir_lock::WriterLock;
+use crate::kvs::ephemeral;
+use crate::kvs::fsutil;
+use crate::kvs::kv_store::{KvError, Result, Snapshot, DEFAULT_LOG_FILE_NAME};
+use crate::kvs::protocol::Checksum;
+use crate::kvs::segment;
+use crate::kvs::stats;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+/// File the manifest is written to inside the backup directory.
+pub const MANIFEST_FILE_NAME: &str = "backup.manifest";
+
+/// What `KvStore::backup` wrote, as kept in its manifest.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BackupManifest {
+    /// Live keys in the backup.
+    pub keys: u64,
+    /// Length of the backup's log.
+    pub bytes: u64,
+    /// `KvStore::last_sequence` as of the backup.
+    pub sequence: u64,
+    /// `protocol::Checksum` of the backup's log.
+    pub checksum: u64,
+}
+
+/// Writes `snapshot` to `dest`, which must not exist or be empty, with its manifest.
+pub(crate) fn write(snapshot: &Snapshot, dest: &Path) -> Result<BackupManifest> {
+    let info = snapshot.checkpoint(dest)?;
+    // Read back, so the checksum is of what reached the disk.
+    let log = File::open(dest.join(DEFAULT_LOG_FILE_NAME))?;
+    let (bytes, checksum) = copy_summed(log, io::sink())?;
+    let manifest = BackupManifest {
+        keys: info.keys,
+        bytes,
+        sequence: info.sequence,
+        checksum,
+    };
+    let encoded = serde_json::to_vec_pretty(&manifest)?;
+    fsutil::atomic_write(&dest.join(MANIFEST_FILE_NAME), &encoded)?;
+    Ok(manifest)
+}
+
+/// Installs the backup in `src` as the store in `dest`; see `KvStore::restore`.
+pub(crate) fn restore(src: &Path, dest: &Path, force: bool) -> Result<()> {
+    let manifest = read_manifest(src)?;
+    let log = File::open(src.join(DEFAULT_LOG_FILE_NAME)).map_err(|e| match e.kind() {
+        io::ErrorKind::NotFound => corrupt(src, format!("{} is missing", DEFAULT_LOG_FILE_NAME)),
+        _ => e.into(),
+    })?;
+    let replacing = dest.exists() && fs::read_dir(dest)?.next().is_some();
+    if replacing && !force {
+        return Err(KvError::DirectoryNotEmpty(dest.to_path_buf()));
+    }
+    fs::create_dir_all(dest)?;
+    let _lock = WriterLock::acquire(dest, "").map_err(|e| match e.kind() {
+        io::ErrorKind::WouldBlock => KvError::StoreLocked(dest.to_path_buf()),
+        _ => e.into(),
+    })?;
+
+    let temp = fsutil::temp_path(dest, "restore");
+    let result = install(
+        src,
+        log,
+        &manifest,
+        &temp,
+        &dest.join(DEFAULT_LOG_FILE_NAME),
+    );
+    if result.is_err() {
+        let _ = fs::remove_file(&temp);
+    }
+    result?;
+    if replacing {
+        // Both describe the store that was there, not the one restored over it, as do the
+        // segments of its log, which would otherwise refuse to open beside the one file.
+        let segments = segment::list(dest, "")?;
+        let names = [ephemeral::MARKER_FILE_NAME, stats::SNAPSHOT_FILE_NAME]
+            .map(str::to_owned)
+            .into_iter()
+            .chain(segments.into_iter().map(|id| segment::file_name("", id)));
+        for name in names {
+            match fs::remove_file(dest.join(name)) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            }
+        }
+        fsutil::sync_dir(dest)?;
+    }
+    Ok(())
+}
+
+/// Copies `log` to `temp`, and renames it to `dest_log` if it matches `manifest`.
+fn install(
+    src: &Path,
+    log: File,
+    manifest: &BackupManifest,
+    temp: &Path,
+    dest_log: &Path,
+) -> Result<()> {
+    let mut out = io::BufWriter::new(fsutil::create_exclusive(temp)?);
+    let (bytes, checksum) = copy_summed(log, &mut out)?;
+    out.into_inner().map_err(io::IntoInnerError::into_error)?;
+    if bytes != manifest.bytes {
+        return Err(corrupt(
+            src,
+            format!(
+                "the log is {} bytes, the manifest says {}",
+                bytes, manifest.bytes
+            ),
+        ));
+    }
+    if checksum != manifest.checksum {
+        return Err(corrupt(
+            src,
+            format!(
+                "the log's checksum is {:016x}, the manifest says {:016x}",
+                checksum, manifest.checksum
+            ),
+        ));
+    }
+    fsutil::atomic_rename_into_place(temp, dest_log)?;
+    Ok(())
+}
+
+fn read_manifest(src: &Path) -> Result<BackupManifest> {
+    match fs::read(src.join(MANIFEST_FILE_NAME)) {
+        Ok(contents) => serde_json::from_slice(&contents)
+            .map_err(|e| corrupt(src, format!("unreadable manifest: {}", e))),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Err(corrupt(
+            src,
+            format!(
+                "{} is missing, so the backup may be incomplete",
+                MANIFEST_FILE_NAME
+            ),
+        )),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Copies `reader` to `writer`, returning the length and checksum of what was copied.
+fn copy_summed(mut reader: impl Read, mut writer: impl Write) -> io::Result<(u64, u64)> {
+    let mut checksum = Checksum::new();
+    let mut buf = vec![0; 64 * 1024];
+    let mut bytes = 0;
+    loop {
+        let n = match reader.read(&mut buf) {
+            Ok(0) => return Ok((bytes, checksum.value())),
+            Ok(n) => n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        checksum.update(&buf[..n]);
+        writer.write_all(&buf[..n])?;
+        bytes += n as u64;
+    }
+}
+
+fn corrupt(src: &Path, details: String) -> KvError {
+    KvError::BackupCorrupt {
+        path: src.to_path_buf(),
+        details,
+    }
+}
@@ -0,0 +1,48 @@
+//! Standard base64 with padding, for values that must travel as text: in canonical files,
+//! and in JSON log records of values that are not UTF-8.
+
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// `bytes` as base64.
+pub(crate) fn encode(bytes: &[u8]) -> String {
+    let mut out = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let group = chunk.iter().enumerate().fold(0u32, |group, (i, &byte)| {
+            group | u32::from(byte) << (16 - 8 * i)
+        });
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(BASE64[(group >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+/// The bytes `text` encodes, or why it is not base64.
+pub(crate) fn decode(text: &str) -> std::result::Result<Vec<u8>, String> {
+    let invalid = || format!("value `{}` is not valid base64", text);
+    if !text.len().is_multiple_of(4) {
+        return Err(invalid());
+    }
+    let mut out = Vec::with_capacity(text.len() / 4 * 3);
+    let quads = text.as_bytes().chunks(4);
+    let last = quads.len().saturating_sub(1);
+    for (n, quad) in quads.enumerate() {
+        let padding = quad.iter().rev().take_while(|&&c| c == b'=').count();
+        if padding > 2 || (padding > 0 && n != last) {
+            return Err(invalid());
+        }
+        let mut group = 0u32;
+        for &c in &quad[..4 - padding] {
+            let digit = BASE64.iter().position(|&b| b == c).ok_or_else(invalid)?;
+            group = group << 6 | digit as u32;
+        }
+        group <<= 6 * padding;
+        let bytes = [(group >> 16) as u8, (group >> 8) as u8, group as u8];
+        out.extend_from_slice(&bytes[..3 - padding]);
+    }
+    Ok(out)
+}
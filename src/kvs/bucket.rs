@@ -0,0 +1,102 @@
+//! Buckets: named namespaces of keys within one store.
+//!
+//! A key in a bucket is stored as `\0`, the bucket's name, `\0` and then the key, so a
+//! store holds any number of buckets beside its own keys without them clashing. Backslashes
+//! and NULs in the name are escaped as `\\` and `\0`, which keeps the second NUL the end of
+//! the name whatever the name and key hold, and so the stored key names exactly one bucket
+//! and one key in it. Plain keys starting with NUL are taken for bucket keys, and should
+//! not be used beside buckets.
+//!
+//! Buckets are nothing but their keys' encoding: compaction and reopening keep them as they
+//! keep any key, and a bucket exists for as long as it has a key.
+
+use crate::kvs::kv_store::{KvError, KvStore, Result};
+
+const SEPARATOR: char = '\0';
+
+/// The keys of one bucket of a store, from `KvStore::bucket`.
+pub struct Bucket<'a> {
+    store: &'a mut KvStore,
+    name: String,
+    prefix: String,
+}
+
+impl<'a> Bucket<'a> {
+    pub(crate) fn new(store: &'a mut KvStore, name: &str) -> Bucket<'a> {
+        Bucket {
+            store,
+            name: name.to_owned(),
+            prefix: prefix(name),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// As `KvStore::set`. `KvStoreOptions::max_key_size` applies to the key as stored, with
+    /// the bucket's name in front.
+    pub fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.store.set(self.stored_key(&key), value)
+    }
+
+    pub fn get(&self, key: &str) -> Result<Option<String>> {
+        self.store.get(&self.stored_key(key))
+    }
+
+    /// As `KvStore::remove`, failing with `KvError::RemoveError` naming `key` if the bucket
+    /// does not have it.
+    pub fn remove(&mut self, key: String) -> Result<()> {
+        match self.store.remove(self.stored_key(&key)) {
+            Err(KvError::RemoveError(_)) => Err(KvError::RemoveError(key)),
+            result => result,
+        }
+    }
+
+    /// The bucket's live keys, in order.
+    pub fn keys(&self) -> Result<Vec<String>> {
+        Ok(self
+            .store
+            .index_keys()?
+            .into_iter()
+            .filter_map(|key| key.strip_prefix(&self.prefix).map(str::to_owned))
+            .collect())
+    }
+
+    fn stored_key(&self, key: &str) -> String {
+        format!("{}{}", self.prefix, key)
+    }
+}
+
+/// What every stored key of bucket `name` starts with.
+pub(crate) fn prefix(name: &str) -> String {
+    let mut prefix = String::with_capacity(name.len() + 2);
+    prefix.push(SEPARATOR);
+    for c in name.chars() {
+        match c {
+            '\\' => prefix.push_str("\\\\"),
+            SEPARATOR => prefix.push_str("\\0"),
+            c => prefix.push(c),
+        }
+    }
+    prefix.push(SEPARATOR);
+    prefix
+}
+
+/// The name of the bucket `stored_key` belongs to, or `None` for a key of no bucket.
+pub(crate) fn name_of(stored_key: &str) -> Option<String> {
+    let rest = stored_key.strip_prefix(SEPARATOR)?;
+    let escaped = &rest[..rest.find(SEPARATOR)?];
+    let mut name = String::with_capacity(escaped.len());
+    let mut chars = escaped.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next()? {
+                '0' => name.push(SEPARATOR),
+                c => name.push(c),
+            },
+            c => name.push(c),
+        }
+    }
+    Some(name)
+}
@@ -0,0 +1,315 @@
+//! A line-oriented text form of a store's log for editing it offline: `kvs log export`
+//! writes it, and `kvs log build` turns an edited copy back into a store directory.
+//!
+//! The file starts with `HEADER`. Every other line is blank, a `#` comment, or one record
+//! with seven tab-separated fields:
+//!
+//! ```text
+//! index  sequence  timestamp  type  key  value  checksum
+//! ```
+//!
+//! `index` is the record's position in the exported log and is informational. `sequence`
+//! orders the records and must strictly increase down the file. Export numbers records by
+//! position rather than by the log's own sequence numbers, which compaction leaves in key
+//! order, and writes `-` as the timestamp, leaving out the times of the log's records.
+//! `type` is `set` or `rm`, `key` is a JSON string, and `value` is the value's bytes in
+//! base64, or `-` for a remove. `checksum` is `record_checksum` in hex; set it to `-` after
+//! editing a record, or `build` rejects the record as changed by accident.
+
+use crate::kvs::base64;
+use crate::kvs::fsutil;
+use crate::kvs::kv_store::{self, KvError, LogPin, RawRecord, Result};
+use crate::kvs::protocol::Checksum;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{BufRead, Write};
+use std::path::Path;
+
+pub const HEADER: &str = "# kvs canonical log v1";
+
+/// One record of a canonical file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CanonicalRecord {
+    pub index: u64,
+    pub sequence: u64,
+    /// Seconds since the Unix epoch, `None` where unknown.
+    pub timestamp: Option<u64>,
+    pub key: String,
+    /// `None` for a remove.
+    pub value: Option<Vec<u8>>,
+}
+
+/// Where a pinned export stands; see `export_pinned`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PinnedExport {
+    /// Live keys written.
+    pub keys: u64,
+    /// Mutations in the pinned log. `export` of the same log numbers the first write made
+    /// after the pin with this sequence, until a compaction renumbers the log.
+    pub sequence: u64,
+    /// `LogPin::log_offset` of the pin.
+    pub log_offset: u64,
+}
+
+/// How a key differs between two stores' logical contents.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Change {
+    Added(String),
+    Removed(String),
+    Changed(String),
+}
+
+/// Writes the log of the store in `dir` to `out`, returning the number of records. The
+/// store is not opened, so the log is exported as it is, stale records included.
+pub fn export<W: Write>(dir: &Path, out: &mut W) -> Result<u64> {
+    let records = kv_store::read_raw_log(dir)?;
+    writeln!(out, "{}", HEADER)?;
+    writeln!(
+        out,
+        "# index\tsequence\ttimestamp\ttype\tkey\tvalue\tchecksum"
+    )?;
+    for (index, record) in records.iter().enumerate() {
+        let (kind, key, value) = match *record {
+            RawRecord::Set { ref key, ref value } => ("set", key, Some(value.as_slice())),
+            RawRecord::Rm { ref key } => ("rm", key, None),
+        };
+        writeln!(
+            out,
+            "{}\t{}\t-\t{}\t{}\t{}\t{:016x}",
+            index,
+            index,
+            kind,
+            serde_json::to_string(key)?,
+            value.map(base64::encode).unwrap_or_else(|| "-".to_owned()),
+            record_checksum(key, value)
+        )?;
+    }
+    Ok(records.len() as u64)
+}
+
+/// Writes the live contents of a store as they stood at `pin`, one `set` record per key,
+/// in key order. Unlike `export`, this runs against a store that is open and being
+/// written: the pin fixes what is exported, so writes and compactions after it neither
+/// show up nor take anything away. The pinned sequence and log offset are written as a
+/// comment after the header, for picking up the writes made since.
+pub fn export_pinned<W: Write>(pin: &LogPin, out: &mut W) -> Result<PinnedExport> {
+    let records = pin.read_raw()?;
+    let pinned = PinnedExport {
+        keys: 0,
+        sequence: records.len() as u64,
+        log_offset: pin.log_offset(),
+    };
+    let contents = contents_of(records);
+    writeln!(out, "{}", HEADER)?;
+    writeln!(
+        out,
+        "# pinned at sequence {} (log offset {})",
+        pinned.sequence, pinned.log_offset
+    )?;
+    writeln!(
+        out,
+        "# index\tsequence\ttimestamp\ttype\tkey\tvalue\tchecksum"
+    )?;
+    for (index, (key, value)) in contents.iter().enumerate() {
+        writeln!(
+            out,
+            "{}\t{}\t-\tset\t{}\t{}\t{:016x}",
+            index,
+            index,
+            serde_json::to_string(key)?,
+            base64::encode(value),
+            record_checksum(key, Some(value))
+        )?;
+    }
+    Ok(PinnedExport {
+        keys: contents.len() as u64,
+        ..pinned
+    })
+}
+
+/// Parses and validates a canonical file. Fails with `KvError::CanonicalFormat` naming the
+/// first bad line.
+pub fn parse<R: BufRead>(reader: R) -> Result<Vec<CanonicalRecord>> {
+    let mut records: Vec<CanonicalRecord> = Vec::new();
+    let mut saw_header = false;
+    for (number, line) in reader.lines().enumerate() {
+        let number = number + 1;
+        let line = line?;
+        let line = line.strip_suffix('\r').unwrap_or(&line);
+        if !saw_header {
+            if line != HEADER {
+                return Err(format_error(number, format!("expected `{}`", HEADER)));
+            }
+            saw_header = true;
+            continue;
+        }
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let record = parse_line(line).map_err(|details| format_error(number, details))?;
+        if let Some(previous) = records.last() {
+            if record.sequence <= previous.sequence {
+                return Err(format_error(
+                    number,
+                    format!(
+                        "sequence {} does not follow {}; sequences must strictly increase",
+                        record.sequence, previous.sequence
+                    ),
+                ));
+            }
+        }
+        records.push(record);
+    }
+    if !saw_header {
+        return Err(format_error(1, format!("expected `{}`", HEADER)));
+    }
+    Ok(records)
+}
+
+/// Writes `records` as the log of a new store in `dir`, which must not exist or be empty.
+pub fn build(records: &[CanonicalRecord], dir: &Path) -> Result<()> {
+    if dir.exists() && fs::read_dir(dir)?.next().is_some() {
+        return Err(KvError::DirectoryNotEmpty(dir.to_path_buf()));
+    }
+    fs::create_dir_all(dir)?;
+    let mut log = Vec::new();
+    for record in records {
+        let raw = match record.value {
+            Some(ref value) => RawRecord::Set {
+                key: record.key.clone(),
+                value: value.clone(),
+            },
+            None => RawRecord::Rm {
+                key: record.key.clone(),
+            },
+        };
+        log.extend_from_slice(&kv_store::encode_raw_record(&raw)?);
+        log.push(b'\n');
+    }
+    fsutil::atomic_write(&dir.join(kv_store::DEFAULT_LOG_FILE_NAME), &log)?;
+    Ok(())
+}
+
+/// The key-value pairs `records` leave behind when replayed in order.
+pub fn logical_contents(records: &[CanonicalRecord]) -> BTreeMap<String, Vec<u8>> {
+    let mut contents = BTreeMap::new();
+    for record in records {
+        match record.value {
+            Some(ref value) => {
+                contents.insert(record.key.clone(), value.clone());
+            }
+            None => {
+                contents.remove(&record.key);
+            }
+        }
+    }
+    contents
+}
+
+/// The logical contents of the store in `dir`, read from its log without opening it.
+pub fn store_contents(dir: &Path) -> Result<BTreeMap<String, Vec<u8>>> {
+    Ok(contents_of(kv_store::read_raw_log(dir)?))
+}
+
+fn contents_of(records: Vec<RawRecord>) -> BTreeMap<String, Vec<u8>> {
+    let mut contents = BTreeMap::new();
+    for record in records {
+        match record {
+            RawRecord::Set { key, value } => {
+                contents.insert(key, value);
+            }
+            RawRecord::Rm { key } => {
+                contents.remove(&key);
+            }
+        }
+    }
+    contents
+}
+
+/// Keys whose presence or value differs from `old` to `new`, in key order.
+pub fn diff(old: &BTreeMap<String, Vec<u8>>, new: &BTreeMap<String, Vec<u8>>) -> Vec<Change> {
+    let mut changes = Vec::new();
+    for (key, value) in old {
+        match new.get(key) {
+            None => changes.push(Change::Removed(key.clone())),
+            Some(new_value) if new_value != value => changes.push(Change::Changed(key.clone())),
+            Some(_) => {}
+        }
+    }
+    for key in new.keys() {
+        if !old.contains_key(key) {
+            changes.push(Change::Added(key.clone()));
+        }
+    }
+    changes.sort_by(|a, b| change_key(a).cmp(change_key(b)));
+    changes
+}
+
+fn change_key(change: &Change) -> &str {
+    match *change {
+        Change::Added(ref key) | Change::Removed(ref key) | Change::Changed(ref key) => key,
+    }
+}
+
+/// FNV-1a over the record's type, key and value, separated so that moving bytes between
+/// fields changes it.
+pub fn record_checksum(key: &str, value: Option<&[u8]>) -> u64 {
+    let mut checksum = Checksum::new();
+    checksum.update(if value.is_some() { b"set\0" } else { b"rm\0" });
+    checksum.update(key.as_bytes());
+    if let Some(value) = value {
+        checksum.update(b"\0");
+        checksum.update(value);
+    }
+    checksum.value()
+}
+
+fn parse_line(line: &str) -> std::result::Result<CanonicalRecord, String> {
+    let fields: Vec<&str> = line.split('\t').collect();
+    if fields.len() != 7 {
+        return Err(format!(
+            "expected 7 tab-separated fields, found {}",
+            fields.len()
+        ));
+    }
+    let number = |name: &str, field: &str| {
+        field
+            .parse::<u64>()
+            .map_err(|_| format!("{} `{}` is not a number", name, field))
+    };
+    let index = number("index", fields[0])?;
+    let sequence = number("sequence", fields[1])?;
+    let timestamp = match fields[2] {
+        "-" => None,
+        field => Some(number("timestamp", field)?),
+    };
+    let key: String =
+        serde_json::from_str(fields[4]).map_err(|e| format!("key is not a JSON string: {}", e))?;
+    let value = match (fields[3], fields[5]) {
+        ("set", value) => Some(base64::decode(value)?),
+        ("rm", "-") => None,
+        ("rm", _) => return Err("an rm record has no value; use `-`".to_owned()),
+        (kind, _) => return Err(format!("unknown record type `{}`", kind)),
+    };
+    if fields[6] != "-" {
+        let expected = u64::from_str_radix(fields[6], 16)
+            .map_err(|_| format!("checksum `{}` is not hex", fields[6]))?;
+        if expected != record_checksum(&key, value.as_deref()) {
+            return Err(
+                "checksum does not match the record; set it to `-` if the edit is intended"
+                    .to_owned(),
+            );
+        }
+    }
+    Ok(CanonicalRecord {
+        index,
+        sequence,
+        timestamp,
+        key,
+        value,
+    })
+}
+
+fn format_error(line: usize, details: String) -> KvError {
+    KvError::CanonicalFormat { line, details }
+}
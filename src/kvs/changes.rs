@@ -0,0 +1,83 @@
+//! Change notifications: callbacks a store calls for every key its writes change.
+//!
+//! A subscriber hears of a write once its record is in the log and the index has it, and
+//! only if the write as a whole succeeded, so one that fails tells no one. Only writes made
+//! through the store count: compaction and reopening change no key, and the records
+//! `KvStore::refresh` picks up were written by another store, so none of them calls anyone.
+//! Callbacks run on the writing thread, inside the write, and a slow one slows the store;
+//! one that panics poisons it, as any panic inside an operation does.
+
+/// What a write did to a key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChangeOp {
+    Set,
+    Remove,
+}
+
+/// One key changed by a write, as passed to the callbacks of `KvStore::subscribe`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChangeEvent {
+    pub key: String,
+    pub op: ChangeOp,
+    /// The key's value after a set, with any merge operands applied, or `None` after a
+    /// remove. Bytes, as a value set with `KvStore::set_bytes` need not be UTF-8.
+    pub value: Option<Vec<u8>>,
+}
+
+impl ChangeEvent {
+    pub(crate) fn set(key: &str, value: Vec<u8>) -> ChangeEvent {
+        ChangeEvent {
+            key: key.to_owned(),
+            op: ChangeOp::Set,
+            value: Some(value),
+        }
+    }
+
+    pub(crate) fn remove(key: &str) -> ChangeEvent {
+        ChangeEvent {
+            key: key.to_owned(),
+            op: ChangeOp::Remove,
+            value: None,
+        }
+    }
+}
+
+/// Names a callback for `KvStore::unsubscribe`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SubscriptionId(u64);
+
+type Callback = Box<dyn Fn(&ChangeEvent) + Send>;
+
+/// A store's callbacks, in the order they subscribed.
+#[derive(Default)]
+pub(crate) struct Subscribers {
+    next_id: u64,
+    callbacks: Vec<(SubscriptionId, Callback)>,
+}
+
+impl Subscribers {
+    pub fn add(&mut self, callback: Callback) -> SubscriptionId {
+        let id = SubscriptionId(self.next_id);
+        self.next_id += 1;
+        self.callbacks.push((id, callback));
+        id
+    }
+
+    /// Returns whether `id` was subscribed.
+    pub fn remove(&mut self, id: SubscriptionId) -> bool {
+        let before = self.callbacks.len();
+        self.callbacks.retain(|(subscribed, _)| *subscribed != id);
+        self.callbacks.len() < before
+    }
+
+    /// No one would hear of a change, so there is no event to build.
+    pub fn is_empty(&self) -> bool {
+        self.callbacks.is_empty()
+    }
+
+    pub fn notify(&self, event: &ChangeEvent) {
+        for (_, callback) in &self.callbacks {
+            callback(event);
+        }
+    }
+}
@@ -0,0 +1,6 @@
+//! Pieces shared by the `kvs`, `kvs-client` and `kvs-server` binaries.
+
+pub mod args;
+pub mod report;
+pub mod style;
+pub mod table;
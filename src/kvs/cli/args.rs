@@ -0,0 +1,27 @@
+//! Value parsers for command-line arguments.
+
+use std::time::Duration;
+
+/// Parses `500ms`, `1.5s`, `2m` or a bare number of seconds.
+pub fn parse_duration(arg: &str) -> Result<Duration, String> {
+    let arg = arg.trim();
+    let (number, unit_secs) = if let Some(number) = arg.strip_suffix("ms") {
+        (number, 0.001)
+    } else if let Some(number) = arg.strip_suffix('s') {
+        (number, 1.0)
+    } else if let Some(number) = arg.strip_suffix('m') {
+        (number, 60.0)
+    } else {
+        (arg, 1.0)
+    };
+    let value: f64 = number
+        .trim()
+        .parse()
+        .map_err(|_| format!("invalid duration '{}', expected e.g. 500ms, 5s or 2m", arg))?;
+    Duration::try_from_secs_f64(value * unit_secs).map_err(|_| {
+        format!(
+            "invalid duration '{}': must be finite and not negative",
+            arg
+        )
+    })
+}
@@ -0,0 +1,56 @@
+//! Tables for the reports the binaries print, kept here so their layout can be tested
+//! without running a binary.
+
+use crate::kvs::cli::table::{Cell, Table};
+use crate::kvs::kv_store::{CompactionEstimate, CompactionStats};
+
+/// `kvs compact --dry-run`: what the log holds and what compacting would leave of it.
+pub fn compaction(estimate: &CompactionEstimate) -> Table {
+    Table::new()
+        .row(vec![
+            Cell::label("log size"),
+            Cell::bytes(estimate.current_log_bytes),
+        ])
+        .row(vec![
+            Cell::label("projected size"),
+            Cell::bytes(estimate.projected_log_bytes),
+        ])
+        .row(vec![
+            Cell::label("reclaimable"),
+            Cell::bytes(estimate.reclaimable_bytes),
+        ])
+        .row(vec![
+            Cell::label("stale records"),
+            Cell::number(estimate.stale_records),
+        ])
+        .row(vec![
+            Cell::label("tombstone records"),
+            Cell::number(estimate.tombstone_records),
+        ])
+}
+
+/// `kvs compact`: what compacting the log left of it. One row per field of
+/// `CompactionStats`: the log's length before and after, headers included, and the
+/// difference, which is what was reclaimed. Then the records dropped, which are sets
+/// overwritten or removed since the last compaction and the removes no longer needed to
+/// keep them dead. Last is how long it took.
+pub fn compacted(stats: &CompactionStats) -> Table {
+    Table::new()
+        .row(vec![
+            Cell::label("log size before"),
+            Cell::bytes(stats.log_bytes_before),
+        ])
+        .row(vec![
+            Cell::label("log size after"),
+            Cell::bytes(stats.log_bytes_after),
+        ])
+        .row(vec![
+            Cell::label("reclaimed"),
+            Cell::bytes(stats.log_bytes_before.saturating_sub(stats.log_bytes_after)),
+        ])
+        .row(vec![
+            Cell::label("records dropped"),
+            Cell::number(stats.records_dropped),
+        ])
+        .row(vec![Cell::label("took"), Cell::duration(stats.duration)])
+}
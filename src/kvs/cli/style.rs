@@ -0,0 +1,100 @@
+//! Terminal styling for the binaries' output.
+//!
+//! Each binary resolves its `--color` choice once at startup into a `Style` per output
+//! stream and renders every highlighted piece of text through it, so turning color off is
+//! guaranteed to remove every escape sequence rather than most of them.
+
+use std::env;
+use std::fmt;
+use std::io::{self, IsTerminal};
+
+/// Value of the binaries' `--color` flag.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum ColorChoice {
+    /// Color a stream only if it is a terminal and `NO_COLOR` is unset or empty.
+    #[default]
+    Auto,
+    /// Color even when redirected and when `NO_COLOR` is set.
+    Always,
+    Never,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stream {
+    Stdout,
+    Stderr,
+}
+
+impl ColorChoice {
+    pub fn use_color(self, stream: Stream) -> bool {
+        match self {
+            ColorChoice::Always => true,
+            ColorChoice::Never => false,
+            ColorChoice::Auto => {
+                let no_color = env::var_os("NO_COLOR").is_some_and(|value| !value.is_empty());
+                !no_color
+                    && match stream {
+                        Stream::Stdout => io::stdout().is_terminal(),
+                        Stream::Stderr => io::stderr().is_terminal(),
+                    }
+            }
+        }
+    }
+}
+
+/// Renders text for one stream, plain or with ANSI styling.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Style {
+    color: bool,
+}
+
+impl Style {
+    pub fn new(color: bool) -> Style {
+        Style { color }
+    }
+
+    pub fn resolve(choice: ColorChoice, stream: Stream) -> Style {
+        Style::new(choice.use_color(stream))
+    }
+
+    pub fn is_colored(&self) -> bool {
+        self.color
+    }
+
+    /// Bold red, for the start of a failure message.
+    pub fn error<'a>(&self, text: &'a str) -> Styled<'a> {
+        self.paint(text, "1;31")
+    }
+
+    /// Yellow, for answers that are not failures but not what was asked for either.
+    pub fn warning<'a>(&self, text: &'a str) -> Styled<'a> {
+        self.paint(text, "33")
+    }
+
+    /// Bold, for the names in `name: value` reports.
+    pub fn label<'a>(&self, text: &'a str) -> Styled<'a> {
+        self.paint(text, "1")
+    }
+
+    fn paint<'a>(&self, text: &'a str, code: &'static str) -> Styled<'a> {
+        Styled {
+            text,
+            code: if self.color { Some(code) } else { None },
+        }
+    }
+}
+
+/// Text with an optional SGR code, see `Style`.
+pub struct Styled<'a> {
+    text: &'a str,
+    code: Option<&'static str>,
+}
+
+impl fmt::Display for Styled<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.code {
+            Some(code) => write!(f, "\x1b[{}m{}\x1b[0m", code, self.text),
+            None => f.write_str(self.text),
+        }
+    }
+}
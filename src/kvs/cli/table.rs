@@ -0,0 +1,211 @@
+//! Aligned tables for the binaries' reports.
+//!
+//! A report builds a `Table` from its data and renders it only for `OutputFormat::Table`;
+//! `--output json` prints the data itself, so nothing here decides what a report contains.
+//! Units are formatted by hand rather than through the locale, so output is the same
+//! everywhere.
+
+use crate::kvs::cli::style::Style;
+use std::fmt::Display;
+use std::time::Duration;
+
+/// Value of the report commands' `--output` flag.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum OutputFormat {
+    #[default]
+    Table,
+    Json,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Align {
+    Left,
+    Right,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cell {
+    text: String,
+    align: Align,
+    label: bool,
+}
+
+impl Cell {
+    pub fn text<S: Into<String>>(text: S) -> Cell {
+        Cell {
+            text: text.into(),
+            align: Align::Left,
+            label: false,
+        }
+    }
+
+    /// Text styled like a header, for the names in a name/value table.
+    pub fn label<S: Into<String>>(text: S) -> Cell {
+        Cell {
+            label: true,
+            ..Cell::text(text)
+        }
+    }
+
+    pub fn number<N: Display>(number: N) -> Cell {
+        Cell {
+            text: number.to_string(),
+            align: Align::Right,
+            label: false,
+        }
+    }
+
+    pub fn bytes(bytes: u64) -> Cell {
+        Cell {
+            text: human_bytes(bytes),
+            align: Align::Right,
+            label: false,
+        }
+    }
+
+    pub fn duration(duration: Duration) -> Cell {
+        Cell {
+            text: human_duration(duration),
+            align: Align::Right,
+            label: false,
+        }
+    }
+
+    fn width(&self) -> usize {
+        self.text.chars().count()
+    }
+}
+
+/// Rows of cells rendered in columns as wide as their widest cell, two spaces apart.
+#[derive(Debug, Clone, Default)]
+pub struct Table {
+    header: Option<Vec<String>>,
+    rows: Vec<Vec<Cell>>,
+}
+
+impl Table {
+    pub fn new() -> Table {
+        Table::default()
+    }
+
+    /// Column titles, aligned like the cells below them.
+    pub fn header<I, S>(mut self, titles: I) -> Table
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.header = Some(titles.into_iter().map(Into::into).collect());
+        self
+    }
+
+    pub fn row(mut self, cells: Vec<Cell>) -> Table {
+        self.rows.push(cells);
+        self
+    }
+
+    /// One line per row, each ending in a newline, with the header and label cells
+    /// rendered through `style`. Trailing spaces are never written.
+    pub fn render(&self, style: Style) -> String {
+        let columns = self
+            .rows
+            .iter()
+            .map(Vec::len)
+            .chain(self.header.as_ref().map(Vec::len))
+            .max()
+            .unwrap_or(0);
+        let mut widths = vec![0; columns];
+        let mut aligns = vec![Align::Left; columns];
+        for row in &self.rows {
+            for (column, cell) in row.iter().enumerate() {
+                widths[column] = widths[column].max(cell.width());
+                aligns[column] = cell.align;
+            }
+        }
+        if let Some(ref header) = self.header {
+            for (column, title) in header.iter().enumerate() {
+                widths[column] = widths[column].max(title.chars().count());
+            }
+        }
+
+        let mut out = String::new();
+        if let Some(ref header) = self.header {
+            let cells = header.iter().zip(&aligns).map(|(title, &align)| Cell {
+                text: title.clone(),
+                align,
+                label: true,
+            });
+            render_line(&mut out, cells, &widths, style);
+        }
+        for row in &self.rows {
+            render_line(&mut out, row.iter().cloned(), &widths, style);
+        }
+        out
+    }
+}
+
+fn render_line<I: Iterator<Item = Cell>>(
+    out: &mut String,
+    cells: I,
+    widths: &[usize],
+    style: Style,
+) {
+    let mut line = String::new();
+    let mut pending_spaces = 0;
+    for (column, cell) in cells.enumerate() {
+        if column > 0 {
+            pending_spaces += 2;
+        }
+        let padding = widths[column] - cell.width();
+        if cell.align == Align::Right {
+            pending_spaces += padding;
+        }
+        // Spaces are only written before text, which keeps line ends clean.
+        if !cell.text.is_empty() {
+            line.extend(std::iter::repeat_n(' ', pending_spaces));
+            if cell.label {
+                line.push_str(&style.label(&cell.text).to_string());
+            } else {
+                line.push_str(&cell.text);
+            }
+            pending_spaces = 0;
+        }
+        if cell.align == Align::Left {
+            pending_spaces += padding;
+        }
+    }
+    out.push_str(&line);
+    out.push('\n');
+}
+
+/// `1023 B`, `1.0 KiB`, `1.4 GiB`: binary units with one decimal above 1023 bytes.
+pub fn human_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 6] = ["KiB", "MiB", "GiB", "TiB", "PiB", "EiB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut value = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    // Compare the rounded value, so 1048575 bytes reads 1.0 MiB rather than 1024.0 KiB.
+    while (value * 10.0).round() >= 10240.0 && unit + 1 < UNITS.len() {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", value, UNITS[unit])
+}
+
+/// `850 ns`, `12 µs`, `230 ms`, `1.5 s`, `2m 05s`: whole numbers below a second.
+pub fn human_duration(duration: Duration) -> String {
+    let nanos = duration.as_nanos();
+    if nanos < 1_000 {
+        format!("{} ns", nanos)
+    } else if nanos < 1_000_000 {
+        format!("{} µs", nanos / 1_000)
+    } else if nanos < 1_000_000_000 {
+        format!("{} ms", nanos / 1_000_000)
+    } else if duration.as_secs() < 60 {
+        format!("{:.1} s", duration.as_secs_f64())
+    } else {
+        let secs = duration.as_secs();
+        format!("{}m {:02}s", secs / 60, secs % 60)
+    }
+}
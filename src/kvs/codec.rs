@@ -0,0 +1,960 @@
+//! How records are laid out in the log: as JSON lines, or as frames of bincode's layout
+//! for `LogFormat::Bincode` or of MessagePack for `LogFormat::MessagePack`.
+//!
+//! Every log starts with a `HEADER_LEN`-byte header: `MAGIC`, the version of the layout
+//! as a little-endian `u16`, `LOG_VERSION` for this build, the format's codec id, and a
+//! newline. Logs written before there were headers start with a JSON record's `{`, which
+//! no header does, and still open as JSON; compacting one gives it a header. The version
+//! after this one will stop reading them.
+//!
+//! In a framed format each record after the header is a frame: its payload's length as a
+//! `u32`, the same length with every bit inverted, the payload, and a CRC-32 (see `crc`)
+//! of all of that, with the integers little-endian. The length is written twice so that a
+//! damaged length is told from a record cut short by a crash, which it could otherwise
+//! pass for, taking every record after it along. A newline follows each frame, as it does
+//! each JSON record, so in every format a record takes its size plus one byte of the log.
+//!
+//! A bincode payload is the `Command` as bincode lays out an enum: a `u32` variant index in
+//! the order `Command` declares them, then the fields in order, with integers little-endian
+//! and fixed-width, strings as a `u64` length and their bytes, and options as a `0` or a
+//! `1` and the value.
+//!
+//! A MessagePack payload is what the JSON record would be: a map of the variant's name to a
+//! map of its fields by name, leaving out those that are `None`, so a tool that reads
+//! MessagePack gets the same object it would get from the JSON. Keys and operands are
+//! strings and values binary, as they need not be UTF-8. Integers are always written as
+//! a `uint 64`, so that a record's length does not depend on its numbers.
+
+use crate::kvs::base64;
+use crate::kvs::crc::{self, Crc32};
+use crate::kvs::kv_store::{self, Command, KvError, LogBytes, LogRecord, Result};
+use std::borrow::Cow;
+use std::fmt;
+use std::io::{self, BufRead, Read, Seek, SeekFrom};
+use std::str;
+
+/// What every log's header starts with. The NUL keeps text tools from taking it for text.
+pub(crate) const MAGIC: &[u8; 4] = b"\0kvs";
+
+/// The version of the log's layout this build writes, and the only one it reads besides
+/// logs from before headers.
+pub(crate) const LOG_VERSION: u16 = 1;
+
+/// The length of a log's header.
+pub(crate) const HEADER_LEN: usize = 8;
+
+/// The two lengths before a frame's payload, and the CRC after it.
+const FRAME_OVERHEAD: usize = 12;
+
+const SET: u32 = 0;
+const RM: u32 = 1;
+const MERGE: u32 = 2;
+const SEQUENCE: u32 = 3;
+const GROUP: u32 = 4;
+const TOUCH: u32 = 5;
+
+/// How a store's log encodes its records; see `KvStoreOptions::format`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LogFormat {
+    /// A JSON object per line, which any text tool can read.
+    #[default]
+    Json,
+    /// Length-prefixed binary frames, smaller and cheaper to encode than JSON, as values
+    /// need no escaping and fields no names.
+    Bincode,
+    /// Length-prefixed MessagePack frames, which other languages' MessagePack libraries
+    /// read as the objects the JSON log holds.
+    MessagePack,
+}
+
+impl fmt::Display for LogFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            LogFormat::Json => "JSON",
+            LogFormat::Bincode => "bincode",
+            LogFormat::MessagePack => "MessagePack",
+        })
+    }
+}
+
+impl LogFormat {
+    const ALL: [LogFormat; 3] = [LogFormat::Json, LogFormat::Bincode, LogFormat::MessagePack];
+
+    /// The number a header names the format by.
+    fn codec_id(self) -> u8 {
+        match self {
+            LogFormat::Json => 0,
+            LogFormat::Bincode => 1,
+            LogFormat::MessagePack => 2,
+        }
+    }
+
+    /// What a new log of the format starts with before its first record.
+    pub(crate) fn header(self) -> [u8; HEADER_LEN] {
+        let mut header = [0; HEADER_LEN];
+        header[..4].copy_from_slice(MAGIC);
+        header[4..6].copy_from_slice(&LOG_VERSION.to_le_bytes());
+        header[6] = self.codec_id();
+        header[7] = b'\n';
+        header
+    }
+
+    /// Reads the next record and its newline into `buf`, after whatever an earlier call
+    /// that failed part way got of it, and returns the length of all that, 0 at the end of
+    /// the log. A record the log ends part way through is read as far as it goes, so it has
+    /// no newline, as is a frame whose lengths disagree, which is read to the end of the
+    /// log as there is no telling where it ends.
+    pub(crate) fn read_record<R: BufRead>(
+        self,
+        reader: &mut R,
+        buf: &mut Vec<u8>,
+    ) -> io::Result<usize> {
+        if self == LogFormat::Json {
+            reader.read_until(b'\n', buf)?;
+            return Ok(buf.len());
+        }
+        if buf.len() < 8 {
+            reader
+                .by_ref()
+                .take(8 - buf.len() as u64)
+                .read_to_end(buf)?;
+        }
+        match frame_len(buf) {
+            Some(len) => {
+                let rest = (len + FRAME_OVERHEAD + 1).saturating_sub(buf.len());
+                reader.by_ref().take(rest as u64).read_to_end(buf)?;
+            }
+            None if buf.len() == 8 => {
+                reader.read_to_end(buf)?;
+            }
+            None => {}
+        }
+        Ok(buf.len())
+    }
+}
+
+/// What the first bytes of a log say it is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Header {
+    /// Nothing, or part of a header a crash cut short, so the log has no records.
+    Empty,
+    /// A JSON log from before headers, which has records from its first byte.
+    Legacy,
+    /// A whole header, naming the format of the records after it.
+    Headed(LogFormat),
+}
+
+/// Why the first bytes of a log are not a header this build reads.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum BadHeader {
+    /// They are not a header at all, nor a record, so the file is not a log.
+    NotAKvsLog,
+    /// A header of a layout version other than `LOG_VERSION`.
+    UnsupportedVersion(u16),
+    /// A header of this version that names no format, which only damage makes.
+    Damaged(String),
+}
+
+/// Reads the header off `start`, the first `HEADER_LEN` bytes of a log or all of it if
+/// shorter.
+pub(crate) fn read_header(start: &[u8]) -> std::result::Result<Header, BadHeader> {
+    match start.first() {
+        None => return Ok(Header::Empty),
+        Some(b'{') => return Ok(Header::Legacy),
+        Some(_) => {}
+    }
+    if start.len() < HEADER_LEN
+        && LogFormat::ALL
+            .iter()
+            .any(|format| format.header().starts_with(start))
+    {
+        return Ok(Header::Empty);
+    }
+    if !start.starts_with(MAGIC) {
+        return Err(BadHeader::NotAKvsLog);
+    }
+    let version = match start.get(4..6) {
+        Some(version) => u16::from_le_bytes(version.try_into().expect("two bytes")),
+        None => return Err(BadHeader::Damaged("its header is cut short".to_owned())),
+    };
+    if version != LOG_VERSION {
+        return Err(BadHeader::UnsupportedVersion(version));
+    }
+    LogFormat::ALL
+        .into_iter()
+        .find(|format| format.header()[..] == *start)
+        .map(Header::Headed)
+        .ok_or_else(|| {
+            BadHeader::Damaged(match start.get(6) {
+                Some(id) if start.len() == HEADER_LEN => {
+                    format!("its header names codec {}, which there is none of", id)
+                }
+                _ => "its header is cut short".to_owned(),
+            })
+        })
+}
+
+/// The payload length a frame's first eight bytes give, `None` if they disagree or there
+/// are fewer of them.
+fn frame_len(frame: &[u8]) -> Option<usize> {
+    let len = u32::from_le_bytes(frame.get(..4)?.try_into().ok()?);
+    let check = u32::from_le_bytes(frame.get(4..8)?.try_into().ok()?);
+    (check == !len).then_some(len as usize)
+}
+
+/// What is wrong with a framed record, `None` if it is whole and matches its CRC.
+pub(crate) fn check_frame(record: &[u8]) -> Option<String> {
+    if let Some(damage) = check_frame_len(record, record.len()) {
+        return Some(damage);
+    }
+    let (covered, stored) = record.split_at(record.len() - 4);
+    let stored = u32::from_le_bytes(stored.try_into().expect("four bytes"));
+    check_frame_crc(stored, crc::crc32(covered))
+}
+
+/// `check_frame` of the `len`-byte record at `start` of `file`, hashed a buffer at a time
+/// rather than read whole, for records too large to hold.
+pub(crate) fn check_frame_at<F: Read + Seek>(
+    file: &mut F,
+    start: u64,
+    len: u64,
+) -> io::Result<Option<String>> {
+    file.seek(SeekFrom::Start(start))?;
+    let mut head = vec![0; len.min(8) as usize];
+    file.read_exact(&mut head)?;
+    if let Some(damage) = check_frame_len(&head, len as usize) {
+        return Ok(Some(damage));
+    }
+    let mut crc = Crc32::new();
+    crc.update(&head);
+    crc.update_from(file, len - 8 - 4)?;
+    let mut stored = [0; 4];
+    file.read_exact(&mut stored)?;
+    Ok(check_frame_crc(u32::from_le_bytes(stored), crc.value()))
+}
+
+/// What is wrong with the length of a `record_len`-byte frame that starts `head`.
+fn check_frame_len(head: &[u8], record_len: usize) -> Option<String> {
+    let len = match frame_len(head) {
+        Some(len) => len,
+        None => return Some("its length is damaged".to_owned()),
+    };
+    (record_len != len + FRAME_OVERHEAD).then(|| {
+        format!(
+            "it is {} bytes but its frame says {}",
+            record_len,
+            len + FRAME_OVERHEAD
+        )
+    })
+}
+
+fn check_frame_crc(stored: u32, computed: u32) -> Option<String> {
+    (stored != computed).then(|| {
+        format!(
+            "its CRC is {:08x} but its bytes sum to {:08x}",
+            stored, computed
+        )
+    })
+}
+
+/// Whether `record`, the last in the log, is what a crash part way through appending it
+/// leaves: a frame cut short, or the zeros some filesystems leave where the write never
+/// landed. A frame that is all there but fails its CRC is damage, not a torn write.
+pub(crate) fn is_torn_frame(record: &[u8]) -> bool {
+    match frame_len(record) {
+        Some(len) => record.len() < len + FRAME_OVERHEAD,
+        None => record.len() < 8 || record.iter().all(|&b| b == 0),
+    }
+}
+
+/// The longest value a set of `key` can have in a log of the framed `format`, as a frame's
+/// length is a `u32`.
+pub(crate) fn max_value_len(format: LogFormat, key: &str) -> u64 {
+    let head = set_head(format, key, u64::from(u32::MAX), 3).0.len();
+    let rest = set_rest(format, Some(0), Some(0), Some(0)).0.len();
+    u64::from(u32::MAX).saturating_sub((head + rest) as u64)
+}
+
+/// The frame of `command` in the framed `format`, without its newline.
+pub(crate) fn encode(format: LogFormat, command: &Command) -> Result<Vec<u8>> {
+    if let Command::Set {
+        ref key,
+        ref value,
+        expires_at,
+        timestamp,
+        seq,
+    } = *command
+    {
+        return encode_set(format, key, value.as_bytes(), expires_at, timestamp, seq);
+    }
+    let mut payload = Payload::default();
+    let key = match format {
+        LogFormat::MessagePack => payload.pack_command(command),
+        _ => payload.bincode_command(command),
+    };
+    frame(payload.0, key)
+}
+
+/// The frame of a set in the framed `format`, without its newline. The value is bytes, as
+/// neither needs text.
+pub(crate) fn encode_set(
+    format: LogFormat,
+    key: &str,
+    value: &[u8],
+    expires_at: Option<u64>,
+    timestamp: Option<u64>,
+    seq: Option<u64>,
+) -> Result<Vec<u8>> {
+    let options = options_present(expires_at, timestamp, seq);
+    let mut payload = set_head(format, key, value.len() as u64, options);
+    payload.0.extend_from_slice(value);
+    payload
+        .0
+        .extend_from_slice(&set_rest(format, expires_at, timestamp, seq).0);
+    frame(payload.0, key)
+}
+
+/// A set's payload up to its value's bytes, for a set with `options` of its three options.
+fn set_head(format: LogFormat, key: &str, value_len: u64, options: u8) -> Payload {
+    let mut head = Payload::default();
+    match format {
+        LogFormat::MessagePack => {
+            head.map(1);
+            head.str("Set");
+            head.map(2 + options);
+            head.str("key");
+            head.str(key);
+            head.str("value");
+            head.bin_len(value_len);
+        }
+        _ => {
+            head.u32(SET);
+            head.bytes(key.as_bytes());
+            head.u64(value_len);
+        }
+    }
+    head
+}
+
+/// A set's payload after its value's bytes.
+fn set_rest(
+    format: LogFormat,
+    expires_at: Option<u64>,
+    timestamp: Option<u64>,
+    seq: Option<u64>,
+) -> Payload {
+    let mut rest = Payload::default();
+    match format {
+        LogFormat::MessagePack => {
+            for (name, field) in [
+                ("expires_at", expires_at),
+                ("timestamp", timestamp),
+                ("seq", seq),
+            ] {
+                if let Some(n) = field {
+                    rest.str(name);
+                    rest.uint(n);
+                }
+            }
+        }
+        _ => {
+            rest.option(expires_at);
+            rest.option(timestamp);
+            rest.option(seq);
+        }
+    }
+    rest
+}
+
+/// How many of a record's options are there, which a MessagePack map counts.
+fn options_present(expires_at: Option<u64>, timestamp: Option<u64>, seq: Option<u64>) -> u8 {
+    [expires_at, timestamp, seq]
+        .iter()
+        .filter(|field| field.is_some())
+        .count() as u8
+}
+
+/// Frames the payload of a record of `key`, failing if it is too long for a frame.
+pub(crate) fn frame(payload: Vec<u8>, key: &str) -> Result<Vec<u8>> {
+    let len = u32::try_from(payload.len()).map_err(|_| KvError::ValueTooLarge {
+        key: key.to_owned(),
+        size: payload.len() as u64,
+        limit: u64::from(u32::MAX),
+    })?;
+    let mut record = Vec::with_capacity(payload.len() + FRAME_OVERHEAD);
+    record.extend_from_slice(&len.to_le_bytes());
+    record.extend_from_slice(&(!len).to_le_bytes());
+    record.extend_from_slice(&payload);
+    let crc = crc::crc32(&record);
+    record.extend_from_slice(&crc.to_le_bytes());
+    Ok(record)
+}
+
+/// A set written a piece at a time, for `KvStore::set_from_reader`: `start`, the value's
+/// bytes through `value`, and `finish`, whose pieces together make the record of a set
+/// with a timestamp and a sequence number but no expiry. In JSON the value is written in
+/// base64, as its bytes are not known to be UTF-8 until the last of them is in.
+pub(crate) struct StreamedSet {
+    format: LogFormat,
+    crc: Crc32,
+    /// The value's last bytes short of the three base64 encodes together, in JSON.
+    carry: Vec<u8>,
+}
+
+impl StreamedSet {
+    /// The record up to the value's bytes, for a set of `value_len` bytes, at most
+    /// `max_value_len` in a framed log.
+    pub fn start(format: LogFormat, key: &str, value_len: u64) -> Result<(StreamedSet, Vec<u8>)> {
+        let start = match format {
+            LogFormat::Json => {
+                let mut start = b"{\"Set\":{\"key\":".to_vec();
+                serde_json::to_writer(&mut start, key)?;
+                start.extend_from_slice(kv_store::BASE64_VALUE_FIELD);
+                start
+            }
+            framed => {
+                let head = set_head(framed, key, value_len, 2);
+                // The numbers are fixed-width, so any stand for the ones `finish` writes.
+                let rest = set_rest(framed, None, Some(0), Some(0));
+                let len = (head.0.len() as u64 + value_len) as u32 + rest.0.len() as u32;
+                let mut start = Vec::with_capacity(8 + head.0.len());
+                start.extend_from_slice(&len.to_le_bytes());
+                start.extend_from_slice(&(!len).to_le_bytes());
+                start.extend_from_slice(&head.0);
+                start
+            }
+        };
+        let mut crc = Crc32::new();
+        crc.update(&start);
+        let set = StreamedSet {
+            format,
+            crc,
+            carry: Vec::new(),
+        };
+        Ok((set, start))
+    }
+
+    /// Appends the next of the value's bytes to `out` as the record holds them.
+    pub fn value(&mut self, bytes: &[u8], out: &mut Vec<u8>) {
+        let from = out.len();
+        match self.format {
+            LogFormat::Json => {
+                self.carry.extend_from_slice(bytes);
+                let whole = self.carry.len() / 3 * 3;
+                out.extend_from_slice(base64::encode(&self.carry[..whole]).as_bytes());
+                self.carry.drain(..whole);
+            }
+            LogFormat::Bincode | LogFormat::MessagePack => out.extend_from_slice(bytes),
+        }
+        self.crc.update(&out[from..]);
+    }
+
+    /// The rest of the record after the value, without its newline.
+    pub fn finish(mut self, timestamp: u64, seq: u64) -> Vec<u8> {
+        if self.format == LogFormat::Json {
+            let fields = format!(
+                "{}\",\"timestamp\":{},\"seq\":{}",
+                base64::encode(&self.carry),
+                timestamp,
+                seq
+            );
+            self.crc.update(fields.as_bytes());
+            return [fields, self.crc.trailer()].concat().into_bytes();
+        }
+        let mut rest = set_rest(self.format, None, Some(timestamp), Some(seq));
+        self.crc.update(&rest.0);
+        rest.0.extend_from_slice(&self.crc.value().to_le_bytes());
+        rest.0
+    }
+}
+
+/// Decodes the payload of the frame `record` of the framed `format`, which `check_frame`
+/// passed.
+pub(crate) fn decode(format: LogFormat, record: &[u8]) -> Option<LogRecord<'_>> {
+    let payload = record.get(8..record.len().checked_sub(4)?)?;
+    match format {
+        LogFormat::MessagePack => decode_pack(payload),
+        _ => decode_bincode(payload),
+    }
+}
+
+fn decode_bincode(payload: &[u8]) -> Option<LogRecord<'_>> {
+    let mut fields = Fields(payload);
+    let decoded = match fields.u32()? {
+        SET => LogRecord::Set {
+            key: fields.text()?,
+            encoding: None,
+            value: LogBytes(Cow::Borrowed(fields.bytes()?)),
+            expires_at: fields.option()?,
+            timestamp: fields.option()?,
+            seq: fields.option()?,
+        },
+        RM => LogRecord::Rm {
+            key: fields.text()?,
+            seq: fields.option()?,
+        },
+        MERGE => LogRecord::Merge {
+            key: fields.text()?,
+            operand: fields.text()?,
+            expires_at: fields.option()?,
+            timestamp: fields.option()?,
+            seq: fields.option()?,
+        },
+        SEQUENCE => LogRecord::Sequence { seq: fields.u64()? },
+        GROUP => LogRecord::Group {
+            records: usize::try_from(fields.u64()?).ok()?,
+        },
+        TOUCH => LogRecord::Touch {
+            key: fields.text()?,
+            expires_at: fields.u64()?,
+        },
+        _ => return None,
+    };
+    fields.0.is_empty().then_some(decoded)
+}
+
+fn decode_pack(payload: &[u8]) -> Option<LogRecord<'_>> {
+    let mut unpack = Unpack(payload);
+    if unpack.map()? != 1 {
+        return None;
+    }
+    let variant = unpack.str()?;
+    let mut record = PackedFields::default();
+    for _ in 0..unpack.map()? {
+        match unpack.str()? {
+            "key" => record.key = Some(unpack.str()?),
+            "value" => record.value = Some(unpack.bin()?),
+            "operand" => record.operand = Some(unpack.str()?),
+            "expires_at" => record.expires_at = Some(unpack.uint()?),
+            "timestamp" => record.timestamp = Some(unpack.uint()?),
+            "seq" => record.seq = Some(unpack.uint()?),
+            "records" => record.records = Some(usize::try_from(unpack.uint()?).ok()?),
+            _ => return None,
+        }
+    }
+    let PackedFields {
+        key,
+        value,
+        operand,
+        expires_at,
+        timestamp,
+        seq,
+        records,
+    } = record;
+    let decoded = match variant {
+        "Set" => LogRecord::Set {
+            key: Cow::Borrowed(key?),
+            encoding: None,
+            value: LogBytes(Cow::Borrowed(value?)),
+            expires_at,
+            timestamp,
+            seq,
+        },
+        "Rm" => LogRecord::Rm {
+            key: Cow::Borrowed(key?),
+            seq,
+        },
+        "Merge" => LogRecord::Merge {
+            key: Cow::Borrowed(key?),
+            operand: Cow::Borrowed(operand?),
+            expires_at,
+            timestamp,
+            seq,
+        },
+        "Sequence" => LogRecord::Sequence { seq: seq? },
+        "Group" => LogRecord::Group { records: records? },
+        "Touch" => LogRecord::Touch {
+            key: Cow::Borrowed(key?),
+            expires_at: expires_at?,
+        },
+        _ => return None,
+    };
+    unpack.0.is_empty().then_some(decoded)
+}
+
+/// The fields of a MessagePack record, in whatever order it has them.
+#[derive(Default)]
+struct PackedFields<'a> {
+    key: Option<&'a str>,
+    value: Option<&'a [u8]>,
+    operand: Option<&'a str>,
+    expires_at: Option<u64>,
+    timestamp: Option<u64>,
+    seq: Option<u64>,
+    records: Option<usize>,
+}
+
+/// Reads a set's frame of the framed `format` from its start up to its value's bytes, and
+/// returns the value's length, or `None` if the frame is not a set's or ends first.
+pub(crate) fn read_to_value<R: Read>(format: LogFormat, frame: &mut R) -> io::Result<Option<u64>> {
+    match read_set_head(format, frame) {
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(None),
+        read => read,
+    }
+}
+
+fn read_set_head<R: Read>(format: LogFormat, frame: &mut R) -> io::Result<Option<u64>> {
+    let mut lengths = [0; 8];
+    frame.read_exact(&mut lengths)?;
+    let skip = |frame: &mut R, len: u64| -> io::Result<bool> {
+        Ok(io::copy(&mut frame.take(len), &mut io::sink())? == len)
+    };
+    if format == LogFormat::Bincode {
+        let mut field = [0; 12];
+        frame.read_exact(&mut field)?;
+        let mut fields = Fields(&field);
+        if fields.u32() != Some(SET) {
+            return Ok(None);
+        }
+        if !skip(frame, fields.u64().unwrap_or(0))? {
+            return Ok(None);
+        }
+        let mut len = [0; 8];
+        frame.read_exact(&mut len)?;
+        return Ok(Some(u64::from_le_bytes(len)));
+    }
+    // `{"Set": {` as `set_head` writes it, then `"key"`.
+    let mut start = [0; 10];
+    frame.read_exact(&mut start)?;
+    if start[..5] != [0x81, 0xa3, b'S', b'e', b't'] || start[5] & 0xf0 != 0x80 {
+        return Ok(None);
+    }
+    if start[6..] != *b"\xa3key" {
+        return Ok(None);
+    }
+    let key_len = match read_len(frame, StrOrBin::Str)? {
+        Some(len) => len,
+        None => return Ok(None),
+    };
+    let mut field = [0; 6];
+    if !skip(frame, key_len)? {
+        return Ok(None);
+    }
+    frame.read_exact(&mut field)?;
+    if field != *b"\xa5value" {
+        return Ok(None);
+    }
+    read_len(frame, StrOrBin::Bin)
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum StrOrBin {
+    Str,
+    Bin,
+}
+
+/// Reads the header of a MessagePack string or binary and returns its length.
+fn read_len<R: Read>(frame: &mut R, kind: StrOrBin) -> io::Result<Option<u64>> {
+    let mut marker = [0];
+    frame.read_exact(&mut marker)?;
+    let width = match (kind, marker[0]) {
+        (StrOrBin::Str, 0xa0..=0xbf) => return Ok(Some(u64::from(marker[0] & 0x1f))),
+        (StrOrBin::Str, 0xd9) | (StrOrBin::Bin, 0xc4) => 1,
+        (StrOrBin::Str, 0xda) | (StrOrBin::Bin, 0xc5) => 2,
+        (StrOrBin::Str, 0xdb) | (StrOrBin::Bin, 0xc6) => 4,
+        _ => return Ok(None),
+    };
+    let mut len = [0; 8];
+    frame.read_exact(&mut len[8 - width..])?;
+    Ok(Some(u64::from_be_bytes(len)))
+}
+
+/// Whether `tail` bytes are what could follow a set's value in a frame of the framed
+/// `format`: the options it may have, and the CRC.
+pub(crate) fn is_set_tail(format: LogFormat, tail: u64) -> bool {
+    let least = set_rest(format, None, None, None).0.len() + 4;
+    let most = set_rest(format, Some(0), Some(0), Some(0)).0.len() + 4;
+    (least as u64..=most as u64).contains(&tail)
+}
+
+#[derive(Default)]
+struct Payload(Vec<u8>);
+
+impl Payload {
+    fn u32(&mut self, n: u32) {
+        self.0.extend_from_slice(&n.to_le_bytes());
+    }
+
+    fn u64(&mut self, n: u64) {
+        self.0.extend_from_slice(&n.to_le_bytes());
+    }
+
+    fn bytes(&mut self, bytes: &[u8]) {
+        self.u64(bytes.len() as u64);
+        self.0.extend_from_slice(bytes);
+    }
+
+    fn option(&mut self, n: Option<u64>) {
+        match n {
+            Some(n) => {
+                self.0.push(1);
+                self.u64(n);
+            }
+            None => self.0.push(0),
+        }
+    }
+
+    /// Writes a command other than a set as bincode, returning its key.
+    fn bincode_command<'a>(&mut self, command: &'a Command) -> &'a str {
+        match *command {
+            Command::Set { ref key, .. } => key,
+            Command::Rm { ref key, seq } => {
+                self.u32(RM);
+                self.bytes(key.as_bytes());
+                self.option(seq);
+                key
+            }
+            Command::Merge {
+                ref key,
+                ref operand,
+                expires_at,
+                timestamp,
+                seq,
+            } => {
+                self.u32(MERGE);
+                self.bytes(key.as_bytes());
+                self.bytes(operand.as_bytes());
+                self.option(expires_at);
+                self.option(timestamp);
+                self.option(seq);
+                key
+            }
+            Command::Sequence { seq } => {
+                self.u32(SEQUENCE);
+                self.u64(seq);
+                ""
+            }
+            Command::Group { records } => {
+                self.u32(GROUP);
+                self.u64(records as u64);
+                ""
+            }
+            Command::Touch {
+                ref key,
+                expires_at,
+            } => {
+                self.u32(TOUCH);
+                self.bytes(key.as_bytes());
+                self.u64(expires_at);
+                key
+            }
+        }
+    }
+
+    /// Writes a command other than a set as MessagePack, returning its key.
+    fn pack_command<'a>(&mut self, command: &'a Command) -> &'a str {
+        self.map(1);
+        match *command {
+            Command::Set { ref key, .. } => key,
+            Command::Rm { ref key, seq } => {
+                self.str("Rm");
+                self.map(1 + options_present(seq, None, None));
+                self.str("key");
+                self.str(key);
+                self.named_options(&[("seq", seq)]);
+                key
+            }
+            Command::Merge {
+                ref key,
+                ref operand,
+                expires_at,
+                timestamp,
+                seq,
+            } => {
+                self.str("Merge");
+                self.map(2 + options_present(expires_at, timestamp, seq));
+                self.str("key");
+                self.str(key);
+                self.str("operand");
+                self.str(operand);
+                self.named_options(&[
+                    ("expires_at", expires_at),
+                    ("timestamp", timestamp),
+                    ("seq", seq),
+                ]);
+                key
+            }
+            Command::Sequence { seq } => {
+                self.str("Sequence");
+                self.map(1);
+                self.str("seq");
+                self.uint(seq);
+                ""
+            }
+            Command::Group { records } => {
+                self.str("Group");
+                self.map(1);
+                self.str("records");
+                self.uint(records as u64);
+                ""
+            }
+            Command::Touch {
+                ref key,
+                expires_at,
+            } => {
+                self.str("Touch");
+                self.map(2);
+                self.str("key");
+                self.str(key);
+                self.str("expires_at");
+                self.uint(expires_at);
+                key
+            }
+        }
+    }
+
+    fn named_options(&mut self, options: &[(&str, Option<u64>)]) {
+        for &(name, field) in options {
+            if let Some(n) = field {
+                self.str(name);
+                self.uint(n);
+            }
+        }
+    }
+
+    /// A MessagePack fixmap of `len` entries; no record has more than fifteen fields.
+    fn map(&mut self, len: u8) {
+        self.0.push(0x80 | len);
+    }
+
+    fn str(&mut self, s: &str) {
+        let len = s.len();
+        match len {
+            0..=31 => self.0.push(0xa0 | len as u8),
+            32..=0xff => self.0.extend_from_slice(&[0xd9, len as u8]),
+            0x100..=0xffff => {
+                self.0.push(0xda);
+                self.0.extend_from_slice(&(len as u16).to_be_bytes());
+            }
+            _ => {
+                self.0.push(0xdb);
+                self.0.extend_from_slice(&(len as u32).to_be_bytes());
+            }
+        }
+        self.0.extend_from_slice(s.as_bytes());
+    }
+
+    /// The header of a MessagePack binary of `len` bytes, which the caller writes after it.
+    fn bin_len(&mut self, len: u64) {
+        match len {
+            0..=0xff => self.0.extend_from_slice(&[0xc4, len as u8]),
+            0x100..=0xffff => {
+                self.0.push(0xc5);
+                self.0.extend_from_slice(&(len as u16).to_be_bytes());
+            }
+            _ => {
+                self.0.push(0xc6);
+                self.0.extend_from_slice(&(len as u32).to_be_bytes());
+            }
+        }
+    }
+
+    fn uint(&mut self, n: u64) {
+        self.0.push(0xcf);
+        self.0.extend_from_slice(&n.to_be_bytes());
+    }
+}
+
+/// A bincode payload being decoded, each method taking one field off the front.
+struct Fields<'a>(&'a [u8]);
+
+impl<'a> Fields<'a> {
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.0.len() < n {
+            return None;
+        }
+        let (taken, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Some(taken)
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        Some(u32::from_le_bytes(self.take(4)?.try_into().ok()?))
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        Some(u64::from_le_bytes(self.take(8)?.try_into().ok()?))
+    }
+
+    fn bytes(&mut self) -> Option<&'a [u8]> {
+        let len = usize::try_from(self.u64()?).ok()?;
+        self.take(len)
+    }
+
+    fn text(&mut self) -> Option<Cow<'a, str>> {
+        str::from_utf8(self.bytes()?).ok().map(Cow::Borrowed)
+    }
+
+    fn option(&mut self) -> Option<Option<u64>> {
+        match self.take(1)?[0] {
+            0 => Some(None),
+            1 => Some(Some(self.u64()?)),
+            _ => None,
+        }
+    }
+}
+
+/// A MessagePack payload being decoded, each method taking one value off the front. It
+/// takes any width MessagePack has for a value, not only the ones `Payload` writes.
+struct Unpack<'a>(&'a [u8]);
+
+impl<'a> Unpack<'a> {
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        let mut fields = Fields(self.0);
+        let taken = fields.take(n)?;
+        self.0 = fields.0;
+        Some(taken)
+    }
+
+    fn marker(&mut self) -> Option<u8> {
+        Some(self.take(1)?[0])
+    }
+
+    /// A big-endian unsigned integer `width` bytes wide.
+    fn be(&mut self, width: usize) -> Option<u64> {
+        let mut n = [0; 8];
+        n[8 - width..].copy_from_slice(self.take(width)?);
+        Some(u64::from_be_bytes(n))
+    }
+
+    fn map(&mut self) -> Option<u64> {
+        match self.marker()? {
+            marker @ 0x80..=0x8f => Some(u64::from(marker & 0x0f)),
+            0xde => self.be(2),
+            0xdf => self.be(4),
+            _ => None,
+        }
+    }
+
+    fn str(&mut self) -> Option<&'a str> {
+        let len = match self.marker()? {
+            marker @ 0xa0..=0xbf => u64::from(marker & 0x1f),
+            0xd9 => self.be(1)?,
+            0xda => self.be(2)?,
+            0xdb => self.be(4)?,
+            _ => return None,
+        };
+        str::from_utf8(self.take(usize::try_from(len).ok()?)?).ok()
+    }
+
+    fn bin(&mut self) -> Option<&'a [u8]> {
+        let len = match self.marker()? {
+            0xc4 => self.be(1)?,
+            0xc5 => self.be(2)?,
+            0xc6 => self.be(4)?,
+            _ => return None,
+        };
+        self.take(usize::try_from(len).ok()?)
+    }
+
+    fn uint(&mut self) -> Option<u64> {
+        match self.marker()? {
+            marker @ 0x00..=0x7f => Some(u64::from(marker)),
+            0xcc => self.be(1),
+            0xcd => self.be(2),
+            0xce => self.be(4),
+            0xcf => self.be(8),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,155 @@
+//! CRC-32 (IEEE 802.3, as zlib and PNG use it), sealing each log record against bit rot.
+//!
+//! A sealed record ends `,"crc":N}}`, where `N` is the CRC of every byte of the record
+//! before the comma, in decimal. The field goes last, inside the variant's object, so a
+//! sealed record is still one JSON object that older readers decode as before, ignoring
+//! the field, and a set still starts with its key and value for `ValueReader`.
+
+use std::io::{self, Read, Seek, SeekFrom};
+
+const POLYNOMIAL: u32 = 0xedb8_8320;
+
+const TABLE: [u32; 256] = table();
+
+const FIELD: &[u8] = b",\"crc\":";
+
+/// How much of the end of a record `check_at` looks through for its CRC: room for the field,
+/// more digits than any number in a record has, and the closing braces.
+const TAIL: u64 = 64;
+
+const fn table() -> [u32; 256] {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ POLYNOMIAL
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+/// A running CRC-32, for records written a piece at a time.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Crc32(u32);
+
+impl Crc32 {
+    pub fn new() -> Crc32 {
+        Crc32(!0)
+    }
+
+    pub fn update(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 = TABLE[((self.0 ^ u32::from(byte)) & 0xff) as usize] ^ (self.0 >> 8);
+        }
+    }
+
+    /// Runs the next `len` bytes of `reader` through the CRC, a buffer at a time.
+    pub fn update_from(&mut self, reader: &mut impl Read, len: u64) -> io::Result<()> {
+        let mut buf = [0; 8192];
+        let mut left = len;
+        while left > 0 {
+            let n = left.min(buf.len() as u64) as usize;
+            reader.read_exact(&mut buf[..n])?;
+            self.update(&buf[..n]);
+            left -= n as u64;
+        }
+        Ok(())
+    }
+
+    pub fn value(&self) -> u32 {
+        !self.0
+    }
+
+    /// What ends a record whose bytes before it went through this CRC.
+    pub fn trailer(&self) -> String {
+        format!(",\"crc\":{}}}}}", self.value())
+    }
+}
+
+pub(crate) fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = Crc32::new();
+    crc.update(bytes);
+    crc.value()
+}
+
+/// Seals `record`, one JSON object of one variant ending `}}`, with its CRC.
+pub(crate) fn seal(mut record: Vec<u8>) -> Vec<u8> {
+    debug_assert!(record.ends_with(b"}}"));
+    record.truncate(record.len() - 2);
+    let mut crc = Crc32::new();
+    crc.update(&record);
+    record.extend_from_slice(crc.trailer().as_bytes());
+    record
+}
+
+/// What checking the seal of a record found.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Seal {
+    /// The record's CRC matches.
+    Intact,
+    /// The record has no CRC, as ones written before there were CRCs do not.
+    Unsealed,
+    /// The record's bytes do not match its CRC, which is given with theirs.
+    Mismatch { stored: u32, computed: u32 },
+}
+
+/// Checks `record`, without its newline, against its CRC.
+pub(crate) fn check(record: &[u8]) -> Seal {
+    match seal_of(record) {
+        Some((covered, stored)) => compare(stored, crc32(&record[..covered])),
+        None => Seal::Unsealed,
+    }
+}
+
+/// `check` of the `len`-byte record at `start` of `file`, hashed a buffer at a time rather
+/// than read whole, for records too large to hold.
+pub(crate) fn check_at<F: Read + Seek>(file: &mut F, start: u64, len: u64) -> io::Result<Seal> {
+    let tail_len = len.min(TAIL);
+    file.seek(SeekFrom::Start(start + len - tail_len))?;
+    let mut tail = vec![0; tail_len as usize];
+    file.read_exact(&mut tail)?;
+    let (covered, stored) = match seal_of(&tail) {
+        Some(seal) => seal,
+        None => return Ok(Seal::Unsealed),
+    };
+    file.seek(SeekFrom::Start(start))?;
+    let mut crc = Crc32::new();
+    crc.update_from(file, len - tail_len + covered as u64)?;
+    Ok(compare(stored, crc.value()))
+}
+
+/// How many bytes at the start of `record` its CRC covers, with the CRC its digits hold,
+/// `None` if they hold none; `None` altogether for a record without a CRC.
+fn seal_of(record: &[u8]) -> Option<(usize, Option<u32>)> {
+    let body = record.strip_suffix(b"}}")?;
+    let digits = body.iter().rev().take_while(|b| b.is_ascii_digit()).count();
+    let (covered, digits) = body.split_at(body.len() - digits);
+    let covered = match covered.strip_suffix(FIELD) {
+        Some(covered) if !digits.is_empty() => covered,
+        _ => return None,
+    };
+    let stored = std::str::from_utf8(digits)
+        .ok()
+        .and_then(|digits| digits.parse().ok());
+    Some((covered.len(), stored))
+}
+
+fn compare(stored: Option<u32>, computed: u32) -> Seal {
+    // Too many digits for a CRC is damage as surely as the wrong ones.
+    match stored {
+        Some(stored) if stored == computed => Seal::Intact,
+        stored => Seal::Mismatch {
+            stored: stored.unwrap_or(u32::MAX),
+            computed,
+        },
+    }
+}
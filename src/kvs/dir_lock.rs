@@ -0,0 +1,170 @@
+//! Advisory locks that let one writer and any number of short-lived readers share a data
+//! directory.
+//!
+//! A writable store holds `WRITER_LOCK_FILE` exclusively for as long as it is open, so a
+//! second writer fails to open instead of interleaving appends. A read-only store opened
+//! with `KvStoreOptions::shared_lock` holds `READER_LOCK_FILE` shared, and the writer takes
+//! that file exclusively around the one step that takes a file away from readers, the
+//! rename of a compacted log over `db.log`. Readers that overlapped each other would keep
+//! that exclusive lock from ever being granted, so the writer first takes `GATE_LOCK_FILE`
+//! exclusively, and readers take the gate shared only for as long as it takes them to get
+//! their reader lock: a waiting writer holds new readers off until the readers already in
+//! have finished. The writer waits at most `READERS_WAIT` for them, and puts off what it
+//! needed them out for if they are still there, so a reader that stays open cannot stall
+//! writes. The locks are `flock`s, released by the OS when the process exits however it
+//! exits. Elsewhere there is nothing to lock with, and every lock is granted.
+//!
+//! Each store in a directory has locks of its own: the lock files of a store opened with
+//! `KvStoreOptions::store_name` are named with `prefix`, the store's name and a dot, so
+//! stores sharing a directory neither wait for nor exclude each other.
+
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+
+pub const WRITER_LOCK_FILE: &str = "writer.lock";
+pub const READER_LOCK_FILE: &str = "reader.lock";
+pub const GATE_LOCK_FILE: &str = "gate.lock";
+
+/// How long `exclude_readers` waits for readers to let go before giving up.
+pub const READERS_WAIT: Duration = Duration::from_millis(100);
+
+/// Held by a writable store; see the module documentation.
+pub(crate) struct WriterLock {
+    _file: File,
+}
+
+/// Held by a read-only store opened with `KvStoreOptions::shared_lock`, or `None` inside
+/// when the directory has no reader lock file and cannot be given one, i.e. is read-only
+/// and so has no writer to wait for it.
+pub struct ReaderLock {
+    _file: Option<File>,
+}
+
+/// Held by the writer while it replaces the log.
+pub(crate) struct ReadersExcluded {
+    _gate: File,
+    _readers: File,
+}
+
+impl WriterLock {
+    /// Fails with `io::ErrorKind::WouldBlock` when another store, in this process or
+    /// another, has the store in `dir` open for writing.
+    pub fn acquire(dir: &Path, prefix: &str) -> io::Result<WriterLock> {
+        let file = open_lock_file(&lock_path(dir, prefix, WRITER_LOCK_FILE))?;
+        flock(&file, Mode::Exclusive, false)?;
+        Ok(WriterLock { _file: file })
+    }
+}
+
+impl ReaderLock {
+    /// Waits while the writer is replacing the log.
+    pub fn acquire(dir: &Path, prefix: &str) -> io::Result<ReaderLock> {
+        let gate = match open_lock_file(&lock_path(dir, prefix, GATE_LOCK_FILE)) {
+            Ok(gate) => gate,
+            Err(ref e) if e.kind() == io::ErrorKind::ReadOnlyFilesystem => {
+                return Ok(ReaderLock { _file: None })
+            }
+            Err(e) => return Err(e),
+        };
+        flock(&gate, Mode::Shared, true)?;
+        let file = open_lock_file(&lock_path(dir, prefix, READER_LOCK_FILE))?;
+        flock(&file, Mode::Shared, true)?;
+        // Dropping the gate unlocks it.
+        Ok(ReaderLock { _file: Some(file) })
+    }
+}
+
+/// Waits until no reader holds the store's reader lock and keeps new ones out until dropped.
+/// Fails with `io::ErrorKind::WouldBlock`, letting new readers in again, if one still holds
+/// it after `READERS_WAIT`.
+pub(crate) fn exclude_readers(dir: &Path, prefix: &str) -> io::Result<ReadersExcluded> {
+    let deadline = Instant::now() + READERS_WAIT;
+    let gate = open_lock_file(&lock_path(dir, prefix, GATE_LOCK_FILE))?;
+    flock_until(&gate, Mode::Exclusive, deadline)?;
+    let readers = open_lock_file(&lock_path(dir, prefix, READER_LOCK_FILE))?;
+    flock_until(&readers, Mode::Exclusive, deadline)?;
+    Ok(ReadersExcluded {
+        _gate: gate,
+        _readers: readers,
+    })
+}
+
+/// Whether a reader holds the store's reader lock at the moment, found without waiting.
+pub(crate) fn readers_present(dir: &Path, prefix: &str) -> io::Result<bool> {
+    let readers = open_lock_file(&lock_path(dir, prefix, READER_LOCK_FILE))?;
+    // Closing the file unlocks it again.
+    match flock(&readers, Mode::Exclusive, false) {
+        Ok(()) => Ok(false),
+        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(true),
+        Err(e) => Err(e),
+    }
+}
+
+fn lock_path(dir: &Path, prefix: &str, name: &str) -> PathBuf {
+    dir.join(format!("{}{}", prefix, name))
+}
+
+fn open_lock_file(path: &Path) -> io::Result<File> {
+    match OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)
+    {
+        Ok(file) => Ok(file),
+        // A reader may lock a file it cannot write, as long as it exists.
+        Err(ref e) if e.kind() == io::ErrorKind::PermissionDenied && path.exists() => {
+            File::open(path)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Mode {
+    Shared,
+    Exclusive,
+}
+
+#[cfg(unix)]
+fn flock(file: &File, mode: Mode, wait: bool) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let mut operation = match mode {
+        Mode::Shared => libc::LOCK_SH,
+        Mode::Exclusive => libc::LOCK_EX,
+    };
+    if !wait {
+        operation |= libc::LOCK_NB;
+    }
+    loop {
+        if unsafe { libc::flock(file.as_raw_fd(), operation) } == 0 {
+            return Ok(());
+        }
+        let error = io::Error::last_os_error();
+        if error.kind() != io::ErrorKind::Interrupted {
+            return Err(error);
+        }
+    }
+}
+
+/// `flock` without waiting, tried again every millisecond until `deadline`.
+fn flock_until(file: &File, mode: Mode, deadline: Instant) -> io::Result<()> {
+    loop {
+        match flock(file, mode, false) {
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock && Instant::now() < deadline => {
+                thread::sleep(Duration::from_millis(1));
+            }
+            result => return result,
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn flock(_file: &File, _mode: Mode, _wait: bool) -> io::Result<()> {
+    Ok(())
+}
@@ -0,0 +1,59 @@
+//! Helpers for rendering keys and values into log lines and error messages without
+//! copying arbitrarily large payloads into them.
+
+use std::fmt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Default number of bytes of a key or value shown in messages.
+pub const DEFAULT_DISPLAY_CAP: usize = 256;
+
+static DISPLAY_CAP: AtomicUsize = AtomicUsize::new(DEFAULT_DISPLAY_CAP);
+
+/// Sets the process-wide cap used by `Truncated::new` and by `KvError`'s `Display`.
+pub fn set_display_cap(cap: usize) {
+    DISPLAY_CAP.store(cap, Ordering::Relaxed);
+}
+
+pub fn display_cap() -> usize {
+    DISPLAY_CAP.load(Ordering::Relaxed)
+}
+
+/// Displays at most `.1` bytes of `.0` (cut on a char boundary), followed by an ellipsis
+/// and the original length when anything was cut.
+pub struct Truncated<'a>(pub &'a str, pub usize);
+
+impl<'a> Truncated<'a> {
+    /// Truncates to the process-wide cap.
+    pub fn new(s: &'a str) -> Truncated<'a> {
+        Truncated(s, display_cap())
+    }
+}
+
+impl fmt::Display for Truncated<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let Truncated(s, cap) = *self;
+        if s.len() <= cap {
+            return f.write_str(s);
+        }
+        let mut end = cap;
+        while !s.is_char_boundary(end) {
+            end -= 1;
+        }
+        write!(f, "{}…(len={})", &s[..end], s.len())
+    }
+}
+
+impl fmt::Debug for Truncated<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "\"{}\"", self)
+    }
+}
+
+/// Displays only the length of a value, for deployments that must not log contents.
+pub struct Redacted<'a>(pub &'a str);
+
+impl fmt::Display for Redacted<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "<len={}>", self.0.len())
+    }
+}
@@ -0,0 +1,116 @@
+//! Ownership table for keys set with `Request::SetEphemeral`.
+//!
+//! Ownership lives in memory only and does not survive a restart. So that a crash cannot
+//! leave ephemeral keys behind as ordinary data, every ephemeral key is also listed in a
+//! marker file in the data directory, written before the key itself; on startup the server
+//! removes whatever the marker still lists.
+
+use crate::kvs::fsutil;
+use crate::kvs::kv_store::{KvStore, Result};
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+pub const MARKER_FILE_NAME: &str = "ephemeral.keys";
+
+/// Identifies a client connection for the lifetime of the server process.
+pub type ConnectionId = u64;
+
+pub struct EphemeralKeys {
+    owners: HashMap<String, ConnectionId>,
+    marker_path: PathBuf,
+}
+
+impl EphemeralKeys {
+    /// Removes ephemeral keys left over from a previous run and starts an empty table.
+    pub fn recover(store: &mut KvStore) -> Result<(EphemeralKeys, Vec<String>)> {
+        let marker_path = store.directory().join(MARKER_FILE_NAME);
+        let leftover: Vec<String> = match fs::read(&marker_path) {
+            Ok(contents) => serde_json::from_slice(&contents)?,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut removed = Vec::new();
+        for key in leftover {
+            if store.index_contains(&key)? {
+                store.remove(key.clone())?;
+                removed.push(key);
+            }
+        }
+
+        let table = EphemeralKeys {
+            owners: HashMap::new(),
+            marker_path,
+        };
+        table.persist()?;
+        Ok((table, removed))
+    }
+
+    /// Records `connection` as the owner of `key`, taking it over from any previous owner.
+    pub fn claim(&mut self, key: &str, connection: ConnectionId) -> Result<()> {
+        if self.owners.insert(key.to_owned(), connection) != Some(connection) {
+            self.persist()?;
+        }
+        Ok(())
+    }
+
+    /// Makes `key` an ordinary key again, e.g. after a plain set or remove.
+    pub fn release(&mut self, key: &str) -> Result<()> {
+        if self.owners.remove(key).is_some() {
+            self.persist()?;
+        }
+        Ok(())
+    }
+
+    /// Moves ownership along with `KvStore::swap_prefixes(a, b)`: a key owned under one
+    /// prefix is owned under the other afterwards, by the same connection, so a disconnect
+    /// removes the data it set and not what was swapped into its old name.
+    pub fn swap_prefixes(&mut self, a: &str, b: &str) -> Result<()> {
+        let moved: Vec<(String, String, ConnectionId)> = self
+            .owners
+            .iter()
+            .filter_map(|(key, &owner)| {
+                let renamed = if let Some(suffix) = key.strip_prefix(a) {
+                    format!("{}{}", b, suffix)
+                } else {
+                    format!("{}{}", a, key.strip_prefix(b)?)
+                };
+                Some((key.clone(), renamed, owner))
+            })
+            .collect();
+        if moved.is_empty() {
+            return Ok(());
+        }
+        for (key, _, _) in &moved {
+            self.owners.remove(key);
+        }
+        for (_, renamed, owner) in moved {
+            self.owners.insert(renamed, owner);
+        }
+        self.persist()
+    }
+
+    /// Forgets every key owned by `connection`, returning them so they can be removed.
+    pub fn release_connection(&mut self, connection: ConnectionId) -> Vec<String> {
+        let keys: Vec<String> = self
+            .owners
+            .iter()
+            .filter(|(_, owner)| **owner == connection)
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in &keys {
+            self.owners.remove(key);
+        }
+        keys
+    }
+
+    /// Rewrites the marker with the keys currently owned.
+    pub fn persist(&self) -> Result<()> {
+        let mut keys: Vec<&String> = self.owners.keys().collect();
+        keys.sort();
+        fsutil::atomic_write(&self.marker_path, &serde_json::to_vec(&keys)?)?;
+        Ok(())
+    }
+}
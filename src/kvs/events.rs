@@ -0,0 +1,168 @@
+//! Notices a `KvStore` has about itself, delivered to the sink set with
+//! `KvStoreOptions::event_sink` instead of being printed. Without a sink they are dropped,
+//! so an embedding application sees nothing on its stdout or stderr; the binaries install
+//! `stderr_sink` to keep printing the warnings.
+
+use crate::kvs::fs_probe::FilesystemAdvisory;
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+/// Receives every `StoreEvent`, on the thread that caused it.
+pub type EventSink = Box<dyn Fn(StoreEvent) + Send + Sync>;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum StoreEvent {
+    /// The data directory is on a filesystem with known rename or fsync problems, and
+    /// `require_safe_filesystem` is off.
+    UnsafeFilesystem {
+        dir: PathBuf,
+        advisory: FilesystemAdvisory,
+    },
+    /// Open removed a temp file an interrupted run left behind.
+    RemovedStaleTempFile(PathBuf),
+    /// Replay skipped records that change nothing, such as the `Get`s old versions logged.
+    SkippedRecords {
+        log: PathBuf,
+        count: u64,
+    },
+    CompactionStarted {
+        log_bytes: u64,
+    },
+    CompactionFinished {
+        log_bytes_before: u64,
+        log_bytes_after: u64,
+    },
+    /// `recover_append`, or a writable open, truncated a partial append off the log.
+    RecoveredPartialAppend {
+        log: PathBuf,
+        truncated_to: u64,
+    },
+    /// The stats snapshot could not be read, so lifetime stats start over.
+    StatsSnapshotUnreadable {
+        path: PathBuf,
+        error: String,
+    },
+    StatsSnapshotNotSaved {
+        error: String,
+    },
+    /// Dropping the store could not fsync the log, so writes since the last good fsync may
+    /// not survive a power loss.
+    SyncOnDropFailed {
+        log: PathBuf,
+        error: String,
+    },
+}
+
+impl StoreEvent {
+    /// Whether the event is something an operator should look at, as opposed to routine
+    /// progress.
+    pub fn is_warning(&self) -> bool {
+        match *self {
+            StoreEvent::UnsafeFilesystem { .. }
+            | StoreEvent::RemovedStaleTempFile(_)
+            | StoreEvent::SkippedRecords { .. }
+            | StoreEvent::RecoveredPartialAppend { .. }
+            | StoreEvent::StatsSnapshotUnreadable { .. }
+            | StoreEvent::StatsSnapshotNotSaved { .. }
+            | StoreEvent::SyncOnDropFailed { .. } => true,
+            StoreEvent::CompactionStarted { .. } | StoreEvent::CompactionFinished { .. } => false,
+        }
+    }
+}
+
+impl fmt::Display for StoreEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            StoreEvent::UnsafeFilesystem {
+                ref dir,
+                ref advisory,
+            } => write!(f, "{} ({})", advisory, dir.display()),
+            StoreEvent::RemovedStaleTempFile(ref path) => {
+                write!(
+                    f,
+                    "removed {} left behind by an interrupted run",
+                    path.display()
+                )
+            }
+            StoreEvent::SkippedRecords { ref log, count } => write!(
+                f,
+                "skipped {} non-mutating record(s) in {}",
+                count,
+                log.display()
+            ),
+            StoreEvent::CompactionStarted { log_bytes } => {
+                write!(f, "compacting a log of {} bytes", log_bytes)
+            }
+            StoreEvent::CompactionFinished {
+                log_bytes_before,
+                log_bytes_after,
+            } => write!(
+                f,
+                "compacted the log from {} to {} bytes",
+                log_bytes_before, log_bytes_after
+            ),
+            StoreEvent::RecoveredPartialAppend {
+                ref log,
+                truncated_to,
+            } => write!(
+                f,
+                "truncated a partial append off {} at offset {}",
+                log.display(),
+                truncated_to
+            ),
+            StoreEvent::StatsSnapshotUnreadable {
+                ref path,
+                ref error,
+            } => write!(
+                f,
+                "ignoring unreadable stats snapshot {}: {}",
+                path.display(),
+                error
+            ),
+            StoreEvent::StatsSnapshotNotSaved { ref error } => {
+                write!(f, "cannot save stats snapshot: {}", error)
+            }
+            StoreEvent::SyncOnDropFailed { ref log, ref error } => {
+                write!(f, "cannot sync {} on drop: {}", log.display(), error)
+            }
+        }
+    }
+}
+
+/// Prints warnings to stderr as the command-line tools always have, and drops the rest.
+pub fn stderr_sink() -> EventSink {
+    Box::new(|event| match event {
+        StoreEvent::UnsafeFilesystem { .. } => eprintln!("WARNING: {}", event),
+        event if event.is_warning() => eprintln!("Warning: {}", event),
+        _ => {}
+    })
+}
+
+/// The sink a store was opened with, cloned along with its options.
+#[derive(Clone, Default)]
+pub(crate) struct Events(Option<Arc<dyn Fn(StoreEvent) + Send + Sync>>);
+
+impl Events {
+    pub fn new(sink: EventSink) -> Events {
+        Events(Some(Arc::from(sink)))
+    }
+
+    /// A sink that panics loses the event; the operation that raised it carries on.
+    pub fn emit(&self, event: StoreEvent) {
+        if let Some(ref sink) = self.0 {
+            let _ = panic::catch_unwind(AssertUnwindSafe(|| sink(event)));
+        }
+    }
+}
+
+impl fmt::Debug for Events {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.0 {
+            Some(_) => write!(f, "Events(sink)"),
+            None => write!(f, "Events(silent)"),
+        }
+    }
+}
@@ -0,0 +1,140 @@
+//! Best-effort detection of filesystems whose rename and fsync semantics the log relies on
+//! but which do not reliably provide them.
+
+use crate::kvs::kv_store::{KvError, Result, SyncPolicy};
+use std::fmt;
+use std::path::Path;
+
+/// Filesystem a data directory lives on, as far as the platform lets us tell.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FilesystemKind {
+    Nfs,
+    Smb,
+    Overlay,
+    Vboxsf,
+    Tmpfs,
+    /// A filesystem without known problems.
+    Other,
+}
+
+impl fmt::Display for FilesystemKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match *self {
+            FilesystemKind::Nfs => "nfs",
+            FilesystemKind::Smb => "smb/cifs",
+            FilesystemKind::Overlay => "overlayfs",
+            FilesystemKind::Vboxsf => "vboxsf",
+            FilesystemKind::Tmpfs => "tmpfs",
+            FilesystemKind::Other => "other",
+        };
+        f.write_str(name)
+    }
+}
+
+/// A detected filesystem together with the specific risk it poses to the store.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FilesystemAdvisory {
+    pub filesystem: FilesystemKind,
+    pub risk: &'static str,
+}
+
+impl fmt::Display for FilesystemAdvisory {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "data directory is on {}: {}", self.filesystem, self.risk)
+    }
+}
+
+/// Source of the filesystem type for a path, so the decision logic can be tested with fakes.
+pub trait FilesystemProbe {
+    /// Returns `None` when detection isn't implemented on this platform.
+    fn probe(&self, path: &Path) -> Option<FilesystemKind>;
+}
+
+/// Probes the real filesystem with `statfs` where available.
+pub struct SystemProbe;
+
+#[cfg(target_os = "linux")]
+impl FilesystemProbe for SystemProbe {
+    fn probe(&self, path: &Path) -> Option<FilesystemKind> {
+        use std::ffi::CString;
+        use std::os::unix::ffi::OsStrExt;
+
+        const NFS_SUPER_MAGIC: i64 = 0x6969;
+        const SMB_SUPER_MAGIC: i64 = 0x517b;
+        const CIFS_MAGIC_NUMBER: i64 = 0xff53_4d42;
+        const SMB2_MAGIC_NUMBER: i64 = 0xfe53_4d42;
+        const OVERLAYFS_SUPER_MAGIC: i64 = 0x794c_7630;
+        const VBOXSF_SUPER_MAGIC: i64 = 0x786f_4256;
+        const TMPFS_MAGIC: i64 = 0x0102_1994;
+
+        let path = CString::new(path.as_os_str().as_bytes()).ok()?;
+        let mut stat: libc::statfs = unsafe { std::mem::zeroed() };
+        // SAFETY: `path` is NUL-terminated and `stat` is a properly sized out-parameter.
+        if unsafe { libc::statfs(path.as_ptr(), &mut stat) } != 0 {
+            return None;
+        }
+
+        // `f_type`'s integer type differs between architectures.
+        #[allow(clippy::unnecessary_cast)]
+        let kind = match stat.f_type as i64 {
+            NFS_SUPER_MAGIC => FilesystemKind::Nfs,
+            SMB_SUPER_MAGIC | CIFS_MAGIC_NUMBER | SMB2_MAGIC_NUMBER => FilesystemKind::Smb,
+            OVERLAYFS_SUPER_MAGIC => FilesystemKind::Overlay,
+            VBOXSF_SUPER_MAGIC => FilesystemKind::Vboxsf,
+            TMPFS_MAGIC => FilesystemKind::Tmpfs,
+            _ => FilesystemKind::Other,
+        };
+        Some(kind)
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+impl FilesystemProbe for SystemProbe {
+    fn probe(&self, _path: &Path) -> Option<FilesystemKind> {
+        None
+    }
+}
+
+/// Decides whether `kind` is a risk for a store using `sync_policy`.
+pub fn assess(kind: FilesystemKind, sync_policy: SyncPolicy) -> Option<FilesystemAdvisory> {
+    let risk = match kind {
+        FilesystemKind::Nfs | FilesystemKind::Smb => {
+            "network filesystems may not make renames atomic or fsync durable, \
+             which can corrupt the log during compaction"
+        }
+        FilesystemKind::Overlay => {
+            "overlay filesystems do not guarantee atomic rename over files from a lower layer"
+        }
+        FilesystemKind::Vboxsf => "VirtualBox shared folders ignore fsync and may reorder writes",
+        FilesystemKind::Tmpfs if sync_policy == SyncPolicy::Always => {
+            "tmpfs is memory-backed, so synced writes are still lost on reboot"
+        }
+        FilesystemKind::Tmpfs | FilesystemKind::Other => return None,
+    };
+    Some(FilesystemAdvisory {
+        filesystem: kind,
+        risk,
+    })
+}
+
+/// Probes `path` and applies the open-time policy: returns the advisory for a risky
+/// filesystem, or fails when `require_safe` is set. Undetectable filesystems are never an
+/// error.
+pub fn check_filesystem(
+    probe: &dyn FilesystemProbe,
+    path: &Path,
+    sync_policy: SyncPolicy,
+    require_safe: bool,
+) -> Result<(Option<FilesystemKind>, Option<FilesystemAdvisory>)> {
+    let kind = match probe.probe(path) {
+        Some(kind) => kind,
+        None => return Ok((None, None)),
+    };
+    let advisory = assess(kind, sync_policy);
+    if let Some(ref advisory) = advisory {
+        if require_safe {
+            return Err(KvError::UnsafeFilesystem(advisory.clone()));
+        }
+    }
+    Ok((Some(kind), advisory))
+}
@@ -0,0 +1,243 @@
+//! Temp files, atomic replacement, and retries of transient errors.
+//!
+//! Every temp artifact in a data directory is named `.{kind}-{pid}-{counter}.tmp` and created
+//! with `create_new`, so two stores sharing a directory by mistake fail loudly instead of
+//! writing into each other's files. A crash can leave such a file behind; the next open
+//! removes the ones whose process is gone, see `remove_stale_temp_files`.
+
+use std::error;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+static TEMP_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// A temp path in `dir` that no other call in any running process returns.
+pub fn temp_path(dir: &Path, kind: &str) -> PathBuf {
+    let counter = TEMP_COUNTER.fetch_add(1, Ordering::Relaxed);
+    dir.join(format!(".{}-{}-{}.tmp", kind, process::id(), counter))
+}
+
+/// Creates `path` for writing, failing with `AlreadyExists` rather than adopting a file
+/// someone else left there.
+pub fn create_exclusive(path: &Path) -> io::Result<File> {
+    OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create_new(true)
+        .open(path)
+}
+
+/// Replaces `path` with `bytes`: writes a temp file beside it, then
+/// `atomic_rename_into_place`. Readers see the old or the new contents, never a mix.
+pub fn atomic_write(path: &Path, bytes: &[u8]) -> io::Result<()> {
+    let dir = parent_dir(path);
+    let kind = path
+        .file_name()
+        .map(|name| name.to_string_lossy().trim_start_matches('.').to_owned())
+        .unwrap_or_default();
+    let temp = temp_path(dir, &kind);
+    let result = create_exclusive(&temp)
+        .and_then(|mut file| file.write_all(bytes))
+        .and_then(|_| atomic_rename_into_place(&temp, path));
+    if result.is_err() {
+        let _ = fs::remove_file(&temp);
+    }
+    result
+}
+
+/// Fsyncs `temp`, renames it to `destination` and fsyncs the directory, so that once this
+/// returns the new contents survive a crash.
+///
+/// On Windows the rename fails while `destination` has open handles, so callers that keep
+/// it open, like the log appender, close them first.
+pub fn atomic_rename_into_place(temp: &Path, destination: &Path) -> io::Result<()> {
+    File::open(temp)?.sync_all()?;
+    fs::rename(temp, destination)?;
+    sync_dir(parent_dir(destination))
+}
+
+/// Removes temp files of the given kinds from `dir` whose creating process is no longer
+/// running, returning their paths.
+pub fn remove_stale_temp_files(dir: &Path, kinds: &[&str]) -> io::Result<Vec<PathBuf>> {
+    let mut removed = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+        let pid = match parse_temp_name(&name) {
+            Some((kind, pid)) if kinds.contains(&kind) => pid,
+            _ => continue,
+        };
+        if !process_is_running(pid) {
+            fs::remove_file(entry.path())?;
+            removed.push(entry.path());
+        }
+    }
+    Ok(removed)
+}
+
+/// Errors network filesystems return for operations that succeed when tried again: EAGAIN,
+/// ETIMEDOUT and ESTALE.
+pub const TRANSIENT_ERRORS: &[io::ErrorKind] = &[
+    io::ErrorKind::WouldBlock,
+    io::ErrorKind::TimedOut,
+    io::ErrorKind::StaleNetworkFileHandle,
+];
+
+/// How `retry` treats transient errors, set with `KvStoreOptions::transient_retry`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Tries in all, the first included.
+    pub max_attempts: u32,
+    /// Wait before the first retry; each later one waits twice as long, give or take half.
+    pub base_delay: Duration,
+    pub retryable: Vec<io::ErrorKind>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 5,
+            base_delay: Duration::from_millis(10),
+            retryable: TRANSIENT_ERRORS.to_vec(),
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn is_retryable(&self, error: &io::Error) -> bool {
+        self.retryable.contains(&error.kind())
+    }
+
+    /// How long to wait after failed try number `attempt`, counting from 1.
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let backoff = self
+            .base_delay
+            .saturating_mul(1 << attempt.saturating_sub(1).min(16));
+        // Jitter from the clock is plenty to keep retrying processes from moving in step.
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|now| now.subsec_nanos())
+            .unwrap_or(0);
+        backoff.mul_f64(0.5 + f64::from(nanos % 1000) / 1000.0)
+    }
+}
+
+/// The error `retry` gives up with, wrapped in an `io::Error` of the last error's kind.
+/// `KvStore` reports it as `KvError::TransientIoExhausted`.
+#[derive(Debug)]
+pub struct RetriesExhausted {
+    pub attempts: u32,
+    pub last_error: io::Error,
+}
+
+impl fmt::Display for RetriesExhausted {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} (after {} attempts)", self.last_error, self.attempts)
+    }
+}
+
+impl error::Error for RetriesExhausted {}
+
+/// Runs `op`, which must be safe to repeat, until it succeeds or fails with an error
+/// `policy` does not retry, calling `on_retry` before each retry. Without a policy `op`
+/// runs once. Once `policy.max_attempts` tries have failed, the last error is returned
+/// wrapped in `RetriesExhausted`.
+pub fn retry<T>(
+    policy: Option<&RetryPolicy>,
+    on_retry: impl Fn(),
+    mut op: impl FnMut() -> io::Result<T>,
+) -> io::Result<T> {
+    let policy = match policy {
+        Some(policy) => policy,
+        None => return op(),
+    };
+    let mut attempt = 1;
+    loop {
+        match op() {
+            Err(e) if policy.is_retryable(&e) => {
+                if attempt >= policy.max_attempts {
+                    return Err(io::Error::new(
+                        e.kind(),
+                        RetriesExhausted {
+                            attempts: attempt,
+                            last_error: e,
+                        },
+                    ));
+                }
+                thread::sleep(policy.delay(attempt));
+                on_retry();
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Where `KvStore` retries transient errors; `testing::TransientFaults` injects them by site.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum IoSite {
+    /// Reading a value's record for a get.
+    Read,
+    /// Reading the log to rebuild the index.
+    Replay,
+    /// One write of an append.
+    Append,
+    /// Renaming a compacted log into place.
+    Rename,
+}
+
+/// Splits `.{kind}-{pid}-{counter}.tmp` into the kind and pid.
+fn parse_temp_name(name: &str) -> Option<(&str, u32)> {
+    let stem = name.strip_prefix('.')?.strip_suffix(".tmp")?;
+    let mut parts = stem.rsplitn(3, '-');
+    parts.next()?.parse::<u64>().ok()?;
+    let pid = parts.next()?.parse().ok()?;
+    Some((parts.next()?, pid))
+}
+
+fn parent_dir(path: &Path) -> &Path {
+    match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    }
+}
+
+/// Fsyncs `dir`, so that files created, renamed or removed in it stay that way after a
+/// crash.
+#[cfg(unix)]
+pub fn sync_dir(dir: &Path) -> io::Result<()> {
+    File::open(dir)?.sync_all()
+}
+
+// Directories cannot be opened for syncing here; the rename is as durable as the OS makes it.
+#[cfg(not(unix))]
+pub fn sync_dir(_dir: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(unix)]
+fn process_is_running(pid: u32) -> bool {
+    if pid == process::id() {
+        return true;
+    }
+    // Larger values would wrap to negative pids, which address process groups.
+    if pid > libc::pid_t::MAX as u32 {
+        return false;
+    }
+    // Signal 0 only checks that the process exists; EPERM means it does but isn't ours.
+    let ret = unsafe { libc::kill(pid as libc::pid_t, 0) };
+    ret == 0 || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+// Without a cheap liveness check, only our own temp files count as live.
+#[cfg(not(unix))]
+fn process_is_running(pid: u32) -> bool {
+    pid == process::id()
+}
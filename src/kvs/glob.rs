@@ -0,0 +1,76 @@
+//! Glob patterns for `KvStore::keys_matching`.
+//!
+//! `*` matches any run of characters, none included, and `?` any one character; a
+//! backslash makes the character after it literal, so `\*` matches a `*` and `\\` a
+//! backslash. A pattern matches a whole key, not part of one. Matching walks the key once,
+//! going back only to just after the last `*` seen, so it takes at most pattern length
+//! times key length steps, whatever the pattern.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Token {
+    Literal(char),
+    AnyChar,
+    AnyRun,
+}
+
+/// A parsed pattern.
+#[derive(Debug, Clone)]
+pub(crate) struct Pattern {
+    tokens: Vec<Token>,
+}
+
+impl Pattern {
+    pub fn new(pattern: &str) -> Pattern {
+        let mut tokens = Vec::new();
+        let mut chars = pattern.chars();
+        while let Some(c) = chars.next() {
+            tokens.push(match c {
+                // A trailing backslash has nothing to escape, so stands for itself.
+                '\\' => Token::Literal(chars.next().unwrap_or('\\')),
+                '?' => Token::AnyChar,
+                '*' => Token::AnyRun,
+                c => Token::Literal(c),
+            });
+        }
+        // Runs of `*` match what one does.
+        tokens.dedup_by(|a, b| *a == Token::AnyRun && *b == Token::AnyRun);
+        Pattern { tokens }
+    }
+
+    pub fn matches(&self, key: &str) -> bool {
+        let key: Vec<char> = key.chars().collect();
+        let (mut t, mut k) = (0, 0);
+        // The token after the last `*` and the key position that `*` was tried up to.
+        let mut retry: Option<(usize, usize)> = None;
+        while k < key.len() {
+            match self.tokens.get(t) {
+                Some(Token::AnyRun) => {
+                    t += 1;
+                    retry = Some((t, k));
+                    continue;
+                }
+                Some(Token::AnyChar) => {
+                    t += 1;
+                    k += 1;
+                    continue;
+                }
+                Some(&Token::Literal(c)) if c == key[k] => {
+                    t += 1;
+                    k += 1;
+                    continue;
+                }
+                _ => {}
+            }
+            // A mismatch: let the last `*` take one more character, or fail without one.
+            match retry {
+                Some((after_star, tried)) => {
+                    t = after_star;
+                    k = tried + 1;
+                    retry = Some((after_star, k));
+                }
+                None => return false,
+            }
+        }
+        self.tokens[t..].iter().all(|&token| token == Token::AnyRun)
+    }
+}
@@ -0,0 +1,201 @@
+//! The readers behind `KvStore::import` and `KvStore::import_csv`, which turn a file into
+//! rows for the store to load a batch at a time.
+//!
+//! Both read a line at a time, so neither holds more of the file than its longest row.
+//! CSV follows RFC 4180: fields are separated by commas, a field in double quotes may hold
+//! commas, newlines and doubled quotes, and rows end with `\n` or `\r\n`.
+
+use serde::Deserialize;
+use std::io::{self, BufRead, BufReader, Read};
+use std::mem;
+
+/// One row of an import.
+pub(crate) enum ImportRow {
+    Entry {
+        key: String,
+        value: String,
+    },
+    /// Not an entry of the format; `line` is where the row starts, counting from 1.
+    Malformed {
+        line: u64,
+    },
+}
+
+/// A line of `ExportFormat::JsonLines`.
+#[derive(Deserialize)]
+struct JsonEntry {
+    key: String,
+    value: String,
+}
+
+/// The rows of an `ExportFormat::JsonLines` file, blank lines left out.
+pub(crate) struct JsonLines<R> {
+    reader: BufReader<R>,
+    line: u64,
+}
+
+impl<R: Read> JsonLines<R> {
+    pub fn new(reader: R) -> JsonLines<R> {
+        JsonLines {
+            reader: BufReader::new(reader),
+            line: 0,
+        }
+    }
+}
+
+impl<R: Read> Iterator for JsonLines<R> {
+    type Item = io::Result<ImportRow>;
+
+    fn next(&mut self) -> Option<io::Result<ImportRow>> {
+        let mut buf = Vec::new();
+        loop {
+            buf.clear();
+            match self.reader.read_until(b'\n', &mut buf) {
+                Ok(0) => return None,
+                Ok(_) => {}
+                Err(e) => return Some(Err(e)),
+            }
+            self.line += 1;
+            if buf.iter().all(u8::is_ascii_whitespace) {
+                continue;
+            }
+            return Some(Ok(match serde_json::from_slice::<JsonEntry>(&buf) {
+                Ok(JsonEntry { key, value }) => ImportRow::Entry { key, value },
+                Err(_) => ImportRow::Malformed { line: self.line },
+            }));
+        }
+    }
+}
+
+/// The rows of a CSV file, taking the key and value from the given columns. Blank lines
+/// are left out, and so is the first row when it is a header.
+pub(crate) struct CsvRows<R> {
+    reader: BufReader<R>,
+    line: u64,
+    key_col: usize,
+    value_col: usize,
+    skip_header: bool,
+}
+
+impl<R: Read> CsvRows<R> {
+    pub fn new(reader: R, key_col: usize, value_col: usize, has_header: bool) -> CsvRows<R> {
+        CsvRows {
+            reader: BufReader::new(reader),
+            line: 0,
+            key_col,
+            value_col,
+            skip_header: has_header,
+        }
+    }
+
+    /// The next row with the line it starts on, or `None` at the end of the file.
+    fn next_record(&mut self) -> io::Result<Option<(u64, CsvRecord)>> {
+        let start = self.line + 1;
+        let mut record = CsvRecord::default();
+        let mut buf = Vec::new();
+        loop {
+            buf.clear();
+            if self.reader.read_until(b'\n', &mut buf)? == 0 {
+                if self.line + 1 == start {
+                    return Ok(None);
+                }
+                // The file ended inside a quoted field.
+                record.malformed = true;
+                return Ok(Some((start, record)));
+            }
+            self.line += 1;
+            if record.feed(&buf) {
+                return Ok(Some((start, record)));
+            }
+        }
+    }
+}
+
+impl<R: Read> Iterator for CsvRows<R> {
+    type Item = io::Result<ImportRow>;
+
+    fn next(&mut self) -> Option<io::Result<ImportRow>> {
+        loop {
+            let (line, record) = match self.next_record() {
+                Ok(Some(record)) => record,
+                Ok(None) => return None,
+                Err(e) => return Some(Err(e)),
+            };
+            if mem::take(&mut self.skip_header) {
+                continue;
+            }
+            let fields = match record.finish() {
+                Some(fields) => fields,
+                None => return Some(Ok(ImportRow::Malformed { line })),
+            };
+            if fields.len() == 1 && fields[0].is_empty() {
+                continue;
+            }
+            let field = |col: usize| {
+                let field = fields.get(col)?;
+                String::from_utf8(field.clone()).ok()
+            };
+            return Some(Ok(match (field(self.key_col), field(self.value_col)) {
+                (Some(key), Some(value)) => ImportRow::Entry { key, value },
+                _ => ImportRow::Malformed { line },
+            }));
+        }
+    }
+}
+
+/// A CSV row as its lines arrive.
+#[derive(Default)]
+struct CsvRecord {
+    fields: Vec<Vec<u8>>,
+    field: Vec<u8>,
+    /// Inside a quoted field.
+    quoted: bool,
+    /// The current field was quoted and its closing quote has been seen.
+    closed: bool,
+    malformed: bool,
+}
+
+impl CsvRecord {
+    /// Takes the next line of the row, newline included, returning whether the row ends
+    /// with it.
+    fn feed(&mut self, line: &[u8]) -> bool {
+        let mut bytes = line.iter().copied().peekable();
+        while let Some(byte) = bytes.next() {
+            if self.quoted {
+                if byte != b'"' {
+                    self.field.push(byte);
+                } else if bytes.peek() == Some(&b'"') {
+                    bytes.next();
+                    self.field.push(b'"');
+                } else {
+                    self.quoted = false;
+                    self.closed = true;
+                }
+                continue;
+            }
+            match byte {
+                b',' => {
+                    self.fields.push(mem::take(&mut self.field));
+                    self.closed = false;
+                }
+                b'\n' => return true,
+                b'\r' if bytes.peek() == Some(&b'\n') => {}
+                b'"' if self.field.is_empty() && !self.closed => self.quoted = true,
+                _ if self.closed => self.malformed = true,
+                b'"' => self.malformed = true,
+                _ => self.field.push(byte),
+            }
+        }
+        // Only the last line of a file may end without a newline.
+        !self.quoted
+    }
+
+    /// The row's fields, or `None` if it is not valid CSV.
+    fn finish(mut self) -> Option<Vec<Vec<u8>>> {
+        if self.malformed {
+            return None;
+        }
+        self.fields.push(self.field);
+        Some(self.fields)
+    }
+}
@@ -0,0 +1,598 @@
+//! The key -> log location index.
+//!
+//! By default every entry lives in a `HashMap`. With `KvStoreOptions::max_index_bytes` set,
+//! the map only holds a bounded hot tier of recently read or written entries and the full
+//! mapping lives in a key-sorted table on disk, with every `SPARSE_INTERVAL`th key kept in
+//! memory. A get that misses the hot tier binary-searches those keys, reads one block of the
+//! table and promotes what it found, so it costs up to two disk reads instead of one.
+//!
+//! Without a cold tier, range scans would sort every key on each call, so
+//! `KvStoreOptions::ordered_index` also keeps the live keys in a `BTreeSet`, which
+//! `KvStore::iter_from` builds the first time it runs if the option left it out. The cold
+//! table is sorted already and needs no such set.
+//!
+//! The table is scratch data derived from the log and is rebuilt on every open, so its temp
+//! files are renamed into place without the fsyncs of `fsutil::atomic_rename_into_place`.
+
+use crate::kvs::fsutil;
+use crate::kvs::kv_store::{CommandBuffer, Direction, Result};
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Seek, SeekFrom, Write};
+use std::ops::{Bound, RangeBounds};
+use std::path::{Path, PathBuf};
+
+/// File the cold tier is kept in inside the data directory.
+pub const COLD_TABLE_FILE_NAME: &str = "index.cold";
+
+/// Records per block of the cold table; one in-memory key per block.
+const SPARSE_INTERVAL: usize = 64;
+
+/// Rough cost of a hot entry beyond its key bytes: the map bucket, the `String` header and
+/// the slot itself.
+const ENTRY_OVERHEAD: usize = 64;
+
+/// Where `KvStore` lookups were answered from, since the store was opened.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct IndexStats {
+    /// Found in the in-memory tier. Every hit counts here when tiering is off.
+    pub hot_hits: u64,
+    /// Found in the on-disk tier and promoted.
+    pub cold_hits: u64,
+    /// Not present in either tier.
+    pub misses: u64,
+}
+
+struct Slot {
+    /// `None` is a tombstone shadowing an entry the cold tier may still hold.
+    location: Option<CommandBuffer>,
+    /// Differs from the cold tier, so evicting it means merging it into the table.
+    dirty: bool,
+    last_used: u64,
+}
+
+pub(crate) struct Index {
+    hot: HashMap<String, Slot>,
+    hot_bytes: usize,
+    clock: u64,
+    cold: Option<ColdTier>,
+    /// Live keys in order, kept with `KvStoreOptions::ordered_index` when there is no cold
+    /// tier.
+    ordered: Option<BTreeSet<String>>,
+    /// Live keys across both tiers.
+    len: usize,
+    stats: IndexStats,
+}
+
+struct ColdTier {
+    max_hot_bytes: usize,
+    table: ColdTable,
+}
+
+struct ColdTable {
+    path: PathBuf,
+    file: File,
+    /// First key of every block with the block's byte offset, in key order.
+    sparse: Vec<(String, u64)>,
+}
+
+/// Sorted `(key, location)` pairs as yielded by `Index::sorted_entries`.
+pub(crate) type SortedEntries = Box<dyn Iterator<Item = Result<(String, CommandBuffer)>>>;
+
+impl Index {
+    /// An index holding every entry in memory.
+    pub fn in_memory() -> Index {
+        Index {
+            hot: HashMap::new(),
+            hot_bytes: 0,
+            clock: 0,
+            cold: None,
+            ordered: None,
+            len: 0,
+            stats: IndexStats::default(),
+        }
+    }
+
+    /// An empty index whose cold tier is the table at `cold_path`, or an in-memory one when
+    /// `max_hot_bytes` is `None`, which keeps its keys in order too if `ordered`.
+    pub fn open(cold_path: &Path, max_hot_bytes: Option<usize>, ordered: bool) -> Result<Index> {
+        let mut index = Index::in_memory();
+        if ordered && max_hot_bytes.is_none() {
+            index.ordered = Some(BTreeSet::new());
+        }
+        if let Some(max_hot_bytes) = max_hot_bytes {
+            TableWriter::create(cold_path)?.finish()?;
+            let table = ColdTable::open(cold_path.to_path_buf())?;
+            index.cold = Some(ColdTier {
+                max_hot_bytes,
+                table,
+            });
+        }
+        Ok(index)
+    }
+
+    pub fn stats(&self) -> IndexStats {
+        self.stats
+    }
+
+    /// Live keys, counted as entries come and go, so the cold tier is not read.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn get(&mut self, key: &str) -> Result<Option<CommandBuffer>> {
+        self.clock += 1;
+        if let Some(slot) = self.hot.get_mut(key) {
+            slot.last_used = self.clock;
+            match slot.location {
+                Some(_) => self.stats.hot_hits += 1,
+                None => self.stats.misses += 1,
+            }
+            return Ok(slot.location);
+        }
+
+        let found = match self.cold {
+            Some(ref cold) => cold.table.get(key)?,
+            None => None,
+        };
+        match found {
+            Some(location) => {
+                self.stats.cold_hits += 1;
+                self.put(key.to_owned(), Some(location), false)?;
+            }
+            None => self.stats.misses += 1,
+        }
+        Ok(found)
+    }
+
+    /// Points `key` at `location`, returning where it pointed before.
+    pub fn insert(
+        &mut self,
+        key: String,
+        location: CommandBuffer,
+    ) -> Result<Option<CommandBuffer>> {
+        let previous = self.lookup(&key)?;
+        self.clock += 1;
+        if previous.is_none() {
+            self.len += 1;
+            if let Some(ref mut ordered) = self.ordered {
+                ordered.insert(key.clone());
+            }
+        }
+        self.put(key, Some(location), true)?;
+        Ok(previous)
+    }
+
+    /// Removes `key`, returning where it pointed.
+    pub fn remove(&mut self, key: &str) -> Result<Option<CommandBuffer>> {
+        let previous = self.lookup(key)?;
+        if previous.is_none() {
+            return Ok(None);
+        }
+        self.len -= 1;
+        if let Some(ref mut ordered) = self.ordered {
+            ordered.remove(key);
+        }
+        if self.cold.is_some() {
+            self.put(key.to_owned(), None, true)?;
+        } else if self.hot.remove(key).is_some() {
+            self.hot_bytes -= entry_cost(key);
+        }
+        Ok(previous)
+    }
+
+    /// Like `get`, but without promoting or counting the lookup.
+    pub fn lookup(&self, key: &str) -> Result<Option<CommandBuffer>> {
+        match (self.hot.get(key), &self.cold) {
+            (Some(slot), _) => Ok(slot.location),
+            (None, Some(cold)) => cold.table.get(key),
+            (None, None) => Ok(None),
+        }
+    }
+
+    /// Calls `f` with every live key, in no particular order, without collecting them.
+    pub fn for_each_key(&mut self, mut f: impl FnMut(&str)) -> Result<()> {
+        if self.cold.is_none() {
+            for (key, slot) in &self.hot {
+                if slot.location.is_some() {
+                    f(key);
+                }
+            }
+            return Ok(());
+        }
+        for entry in self.sorted_entries()? {
+            f(&entry?.0);
+        }
+        Ok(())
+    }
+
+    /// Every live entry in key order. With tiering on, dirty hot entries are merged into the
+    /// table first so the table alone is authoritative.
+    pub fn sorted_entries(&mut self) -> Result<SortedEntries> {
+        let cold = match self.cold {
+            Some(ref mut cold) => cold,
+            None => {
+                let mut entries: Vec<(String, CommandBuffer)> = self
+                    .hot
+                    .iter()
+                    .filter_map(|(key, slot)| slot.location.map(|l| (key.clone(), l)))
+                    .collect();
+                entries.sort_by(|a, b| a.0.cmp(&b.0));
+                return Ok(Box::new(entries.into_iter().map(Ok)));
+            }
+        };
+
+        let mut updates = Vec::new();
+        for (key, slot) in self.hot.iter_mut().filter(|(_, slot)| slot.dirty) {
+            updates.push((key.clone(), slot.location));
+            slot.dirty = false;
+        }
+        self.hot.retain(|_, slot| slot.location.is_some());
+        self.hot_bytes = self.hot.keys().map(|key| entry_cost(key)).sum();
+        cold.table.merge(updates)?;
+
+        let reader = BufReader::new(File::open(&cold.table.path)?);
+        Ok(Box::new(TableReader(reader)))
+    }
+
+    /// The live entries whose keys fall in `range`, in key order.
+    pub fn range_entries<R: RangeBounds<String>>(
+        &mut self,
+        range: R,
+    ) -> Result<Vec<(String, CommandBuffer)>> {
+        if let Some(ref ordered) = self.ordered {
+            return Ok(ordered
+                .range(range)
+                .filter_map(|key| {
+                    let location = self.hot.get(key)?.location?;
+                    Some((key.clone(), location))
+                })
+                .collect());
+        }
+        let mut entries = Vec::new();
+        for entry in self.sorted_entries()? {
+            let (key, location) = entry?;
+            if range.contains(&key) {
+                entries.push((key, location));
+            }
+        }
+        Ok(entries)
+    }
+
+    /// Up to `limit` live entries passing `keep`, walking from `start`, included, in
+    /// `direction`. Without a cold tier, the ordered key set is built if there is none and
+    /// kept from then on.
+    pub fn entries_from(
+        &mut self,
+        start: &str,
+        direction: Direction,
+        limit: usize,
+        keep: impl Fn(&str) -> bool,
+    ) -> Result<Vec<(String, CommandBuffer)>> {
+        if limit == 0 {
+            return Ok(Vec::new());
+        }
+        if self.cold.is_none() {
+            let hot = &self.hot;
+            let ordered = self.ordered.get_or_insert_with(|| {
+                hot.iter()
+                    .filter(|(_, slot)| slot.location.is_some())
+                    .map(|(key, _)| key.clone())
+                    .collect()
+            });
+            let keys: Box<dyn Iterator<Item = &String>> = match direction {
+                Direction::Forward => {
+                    Box::new(ordered.range::<str, _>((Bound::Included(start), Bound::Unbounded)))
+                }
+                Direction::Reverse => Box::new(
+                    ordered
+                        .range::<str, _>((Bound::Unbounded, Bound::Included(start)))
+                        .rev(),
+                ),
+            };
+            return Ok(keys
+                .filter(|key| keep(key))
+                .filter_map(|key| Some((key.clone(), hot.get(key)?.location?)))
+                .take(limit)
+                .collect());
+        }
+        let mut entries = VecDeque::new();
+        for entry in self.sorted_entries()? {
+            let (key, location) = entry?;
+            let in_range = match direction {
+                Direction::Forward => key.as_str() >= start,
+                Direction::Reverse => key.as_str() <= start,
+            };
+            if !in_range {
+                if direction == Direction::Reverse {
+                    break;
+                }
+                continue;
+            }
+            if !keep(&key) {
+                continue;
+            }
+            entries.push_back((key, location));
+            if entries.len() > limit {
+                // Walking back, the entries nearest `start` are the last ones read.
+                entries.pop_front();
+            } else if direction == Direction::Forward && entries.len() == limit {
+                break;
+            }
+        }
+        Ok(match direction {
+            Direction::Forward => entries.into(),
+            Direction::Reverse => entries.into_iter().rev().collect(),
+        })
+    }
+
+    /// Starts an index that will be filled in key order, as compaction does.
+    pub fn rebuild(&self, dir: &Path) -> Result<IndexBuilder> {
+        let cold = match self.cold {
+            Some(ref cold) => {
+                let path = cold.table.path.clone();
+                let temp = fsutil::temp_path(dir, "index");
+                let writer = TableWriter(BufWriter::new(fsutil::create_exclusive(&temp)?));
+                Some((cold.max_hot_bytes, writer, temp, path))
+            }
+            None => None,
+        };
+        Ok(IndexBuilder {
+            hot: HashMap::new(),
+            cold,
+            len: 0,
+        })
+    }
+
+    fn put(&mut self, key: String, location: Option<CommandBuffer>, dirty: bool) -> Result<()> {
+        let slot = Slot {
+            location,
+            dirty,
+            last_used: self.clock,
+        };
+        let cost = entry_cost(&key);
+        if self.hot.insert(key, slot).is_none() {
+            self.hot_bytes += cost;
+        }
+        self.spill_if_needed()
+    }
+
+    /// Evicts the least recently used hot entries down to half the budget, merging the dirty
+    /// ones into the table in a single pass.
+    fn spill_if_needed(&mut self) -> Result<()> {
+        let cold = match self.cold {
+            Some(ref mut cold) if self.hot_bytes > cold.max_hot_bytes => cold,
+            _ => return Ok(()),
+        };
+
+        let mut by_age: Vec<(u64, String)> = self
+            .hot
+            .iter()
+            .map(|(key, slot)| (slot.last_used, key.clone()))
+            .collect();
+        by_age.sort();
+
+        let target = cold.max_hot_bytes / 2;
+        let mut updates = Vec::new();
+        for (_, key) in by_age {
+            if self.hot_bytes <= target {
+                break;
+            }
+            if let Some(slot) = self.hot.remove(&key) {
+                self.hot_bytes -= entry_cost(&key);
+                if slot.dirty {
+                    updates.push((key, slot.location));
+                }
+            }
+        }
+        cold.table.merge(updates)
+    }
+}
+
+/// Collects the entries of a compacted log, see `Index::rebuild`.
+pub(crate) struct IndexBuilder {
+    hot: HashMap<String, Slot>,
+    cold: Option<(usize, TableWriter, PathBuf, PathBuf)>,
+    len: usize,
+}
+
+impl IndexBuilder {
+    /// Adds an entry; keys must arrive in ascending order when tiering is on.
+    pub fn push(&mut self, key: String, location: CommandBuffer) -> Result<()> {
+        self.len += 1;
+        match self.cold {
+            Some((_, ref mut writer, _, _)) => writer.push(&key, location),
+            None => {
+                let slot = Slot {
+                    location: Some(location),
+                    dirty: false,
+                    last_used: 0,
+                };
+                self.hot.insert(key, slot);
+                Ok(())
+            }
+        }
+    }
+
+    /// Builds the index that replaces `previous`, keeping its stats. `previous` is dropped
+    /// before its table is replaced, as Windows cannot rename over a file that is open.
+    pub fn finish(self, previous: Index) -> Result<Index> {
+        let mut index = Index::in_memory();
+        index.stats = previous.stats();
+        index.len = self.len;
+        let ordered = previous.ordered.is_some();
+        drop(previous);
+        match self.cold {
+            Some((max_hot_bytes, writer, temp, path)) => {
+                writer.finish()?;
+                fs::rename(&temp, &path)?;
+                let table = ColdTable::open(path)?;
+                index.cold = Some(ColdTier {
+                    max_hot_bytes,
+                    table,
+                });
+            }
+            None => {
+                index.hot_bytes = self.hot.keys().map(|key| entry_cost(key)).sum();
+                if ordered {
+                    index.ordered = Some(self.hot.keys().cloned().collect());
+                }
+                index.hot = self.hot;
+            }
+        }
+        Ok(index)
+    }
+}
+
+impl ColdTable {
+    fn open(path: PathBuf) -> Result<ColdTable> {
+        let file = File::open(&path)?;
+        let mut sparse = Vec::new();
+        let mut reader = BufReader::new(&file);
+        let mut offset = 0;
+        let mut line = Vec::new();
+        for record in 0.. {
+            line.clear();
+            let read = reader.read_until(b'\n', &mut line)?;
+            if read == 0 {
+                break;
+            }
+            if record % SPARSE_INTERVAL == 0 {
+                let (key, _, _, _, _): (String, u32, usize, usize, usize) =
+                    serde_json::from_slice(&line)?;
+                sparse.push((key, offset));
+            }
+            offset += read as u64;
+        }
+        Ok(ColdTable { path, file, sparse })
+    }
+
+    fn get(&self, key: &str) -> Result<Option<CommandBuffer>> {
+        let block = match self
+            .sparse
+            .partition_point(|(first, _)| first.as_str() <= key)
+        {
+            0 => return Ok(None),
+            n => n - 1,
+        };
+        let mut reader = BufReader::new(&self.file);
+        reader.seek(SeekFrom::Start(self.sparse[block].1))?;
+        let mut records = TableReader(reader);
+        for _ in 0..SPARSE_INTERVAL {
+            match records.next() {
+                Some(record) => {
+                    let (candidate, location) = record?;
+                    match candidate.as_str().cmp(key) {
+                        std::cmp::Ordering::Less => continue,
+                        std::cmp::Ordering::Equal => return Ok(Some(location)),
+                        std::cmp::Ordering::Greater => return Ok(None),
+                    }
+                }
+                None => break,
+            }
+        }
+        Ok(None)
+    }
+
+    /// Rewrites the table with `updates` applied; `None` deletes the key.
+    fn merge(&mut self, mut updates: Vec<(String, Option<CommandBuffer>)>) -> Result<()> {
+        if updates.is_empty() {
+            return Ok(());
+        }
+        updates.sort_by(|a, b| a.0.cmp(&b.0));
+        let mut updates = updates.into_iter().peekable();
+
+        let dir = self.path.parent().unwrap_or_else(|| Path::new("."));
+        let temp = fsutil::temp_path(dir, "index");
+        let mut writer = TableWriter(BufWriter::new(fsutil::create_exclusive(&temp)?));
+        let existing = TableReader(BufReader::new(File::open(&self.path)?));
+        for record in existing {
+            let (key, location) = record?;
+            let mut replaced = false;
+            while let Some((update_key, update)) = updates.next_if(|(next, _)| *next <= key) {
+                replaced |= update_key == key;
+                if let Some(update) = update {
+                    writer.push(&update_key, update)?;
+                }
+            }
+            if !replaced {
+                writer.push(&key, location)?;
+            }
+        }
+        for (key, update) in updates {
+            if let Some(update) = update {
+                writer.push(&key, update)?;
+            }
+        }
+        writer.finish()?;
+        // Let go of the old table first, as Windows cannot rename over a file that is open.
+        self.file = File::open(&temp)?;
+        let renamed = fs::rename(&temp, &self.path);
+        *self = ColdTable::open(self.path.clone())?;
+        renamed?;
+        Ok(())
+    }
+}
+
+/// Streams `(key, location)` records out of a table.
+struct TableReader<R>(R);
+
+impl<R: BufRead> Iterator for TableReader<R> {
+    type Item = Result<(String, CommandBuffer)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut line = Vec::new();
+        match self.0.read_until(b'\n', &mut line) {
+            Ok(0) => None,
+            Ok(_) => Some(
+                serde_json::from_slice(&line)
+                    .map(|(key, segment, start, size, value_len)| {
+                        let location = CommandBuffer {
+                            segment,
+                            start,
+                            size,
+                            value_len,
+                        };
+                        (key, location)
+                    })
+                    .map_err(Into::into),
+            ),
+            Err(e) => Some(Err(e.into())),
+        }
+    }
+}
+
+struct TableWriter(BufWriter<File>);
+
+impl TableWriter {
+    fn create(path: &Path) -> Result<TableWriter> {
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+        Ok(TableWriter(BufWriter::new(file)))
+    }
+
+    fn push(&mut self, key: &str, location: CommandBuffer) -> Result<()> {
+        let record = (
+            key,
+            location.segment,
+            location.start,
+            location.size,
+            location.value_len,
+        );
+        serde_json::to_writer(&mut self.0, &record)?;
+        self.0.write_all(b"\n")?;
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<()> {
+        self.0.flush()?;
+        Ok(())
+    }
+}
+
+fn entry_cost(key: &str) -> usize {
+    key.len() + ENTRY_OVERHEAD
+}
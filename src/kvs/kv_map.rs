@@ -0,0 +1,266 @@
+use crate::kvs::kv_store::{KvError, KvStore, Result};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::marker::PhantomData;
+use std::ops::{Bound, RangeBounds};
+
+/// Encodes a key into the string stored in the underlying `KvStore`.
+///
+/// Encodings must preserve ordering: for any two keys `a < b`, the encoded strings must
+/// compare the same way, so range scans over the store follow the key type's order.
+pub trait KeySerialize {
+    fn encode_key(&self) -> String;
+}
+
+/// Decodes a key previously produced by `KeySerialize::encode_key`.
+pub trait KeyDeserialize: KeySerialize + Sized {
+    fn decode_key(encoded: &str) -> Option<Self>;
+}
+
+// Integers are written as fixed-width big-endian hex, which sorts the same way the numbers do.
+impl KeySerialize for u32 {
+    fn encode_key(&self) -> String {
+        format!("{:08x}", self)
+    }
+}
+
+impl KeyDeserialize for u32 {
+    fn decode_key(encoded: &str) -> Option<Self> {
+        if encoded.len() != 8 {
+            return None;
+        }
+        u32::from_str_radix(encoded, 16).ok()
+    }
+}
+
+impl KeySerialize for u64 {
+    fn encode_key(&self) -> String {
+        format!("{:016x}", self)
+    }
+}
+
+impl KeyDeserialize for u64 {
+    fn decode_key(encoded: &str) -> Option<Self> {
+        if encoded.len() != 16 {
+            return None;
+        }
+        u64::from_str_radix(encoded, 16).ok()
+    }
+}
+
+// Flipping the sign bit moves negative numbers below the positive ones.
+impl KeySerialize for i64 {
+    fn encode_key(&self) -> String {
+        format!("{:016x}", (*self as u64) ^ (1 << 63))
+    }
+}
+
+impl KeyDeserialize for i64 {
+    fn decode_key(encoded: &str) -> Option<Self> {
+        u64::decode_key(encoded).map(|raw| (raw ^ (1 << 63)) as i64)
+    }
+}
+
+impl KeySerialize for String {
+    fn encode_key(&self) -> String {
+        self.clone()
+    }
+}
+
+impl KeyDeserialize for String {
+    fn decode_key(encoded: &str) -> Option<Self> {
+        Some(encoded.to_owned())
+    }
+}
+
+impl KeySerialize for &str {
+    fn encode_key(&self) -> String {
+        (*self).to_owned()
+    }
+}
+
+/// A typed view over the keys of a `KvStore` that share a prefix.
+///
+/// Keys are encoded with `KeySerialize` and values are stored as JSON. The prefix is stored
+/// length-delimited, so maps whose prefixes are prefixes of each other ("a" and "ab") never
+/// see each other's entries.
+pub struct KvMap<'s, K, V> {
+    store: &'s mut KvStore,
+    namespace: String,
+    max_depth: usize,
+    _marker: PhantomData<fn(K, V)>,
+}
+
+/// Deepest nesting of arrays and objects that `serde_json` reads back; values nested
+/// deeper serialize fine but then fail every `get`.
+pub const MAX_JSON_DEPTH: usize = 127;
+
+impl<'s, K: KeySerialize, V: Serialize + DeserializeOwned> KvMap<'s, K, V> {
+    pub fn new(store: &'s mut KvStore, prefix: &str) -> KvMap<'s, K, V> {
+        KvMap {
+            store,
+            namespace: format!("{}:{}:", prefix.len(), prefix),
+            max_depth: MAX_JSON_DEPTH,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Rejects values nested deeper than `max_depth` on insert. Defaults to, and is capped
+    /// at, `MAX_JSON_DEPTH`.
+    pub fn max_depth(mut self, max_depth: usize) -> KvMap<'s, K, V> {
+        self.max_depth = max_depth.min(MAX_JSON_DEPTH);
+        self
+    }
+
+    pub fn insert(&mut self, key: &K, value: &V) -> Result<()> {
+        let store_key = self.store_key(key);
+        let serialized = encode_value(&store_key, value, self.max_depth)?;
+        self.store.set(store_key, serialized)
+    }
+
+    pub fn get(&self, key: &K) -> Result<Option<V>> {
+        self.get_encoded(&self.store_key(key))
+    }
+
+    /// Removes the key, returning whether it was present.
+    pub fn remove(&mut self, key: &K) -> Result<bool> {
+        let store_key = self.store_key(key);
+        if !self.store.index_contains(&store_key)? {
+            return Ok(false);
+        }
+        self.store.remove(store_key)?;
+        Ok(true)
+    }
+
+    pub fn len(&self) -> Result<usize> {
+        Ok(self
+            .store
+            .index_keys()?
+            .iter()
+            .filter(|key| key.starts_with(&self.namespace))
+            .count())
+    }
+
+    pub fn is_empty(&self) -> Result<bool> {
+        Ok(self.len()? == 0)
+    }
+
+    fn store_key(&self, key: &K) -> String {
+        format!("{}{}", self.namespace, key.encode_key())
+    }
+
+    fn get_encoded(&self, store_key: &str) -> Result<Option<V>> {
+        match self.store.get(store_key)? {
+            Some(value) => decode_value(store_key, &value).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Store keys belonging to this map that fall within `range`, in ascending order.
+    fn sorted_store_keys(&self, range: (Bound<String>, Bound<String>)) -> Result<Vec<String>> {
+        let mut keys = self.store.index_keys()?;
+        keys.retain(|key| {
+            key.starts_with(&self.namespace)
+                && range.contains(&key[self.namespace.len()..].to_owned())
+        });
+        Ok(keys)
+    }
+}
+
+impl<K: KeyDeserialize, V: Serialize + DeserializeOwned> KvMap<'_, K, V> {
+    /// Iterates every entry of the map in ascending key order.
+    pub fn iter(&self) -> impl Iterator<Item = Result<(K, V)>> + '_ {
+        self.range(..)
+    }
+
+    /// Iterates the entries whose keys fall within `range`, in ascending key order.
+    pub fn range<R: RangeBounds<K>>(&self, range: R) -> impl Iterator<Item = Result<(K, V)>> + '_ {
+        let bounds = (
+            encode_bound(range.start_bound()),
+            encode_bound(range.end_bound()),
+        );
+        let (keys, error) = match self.sorted_store_keys(bounds) {
+            Ok(keys) => (keys, None),
+            Err(e) => (Vec::new(), Some(e)),
+        };
+        error.into_iter().map(Err).chain(
+            keys.into_iter()
+                .filter_map(move |store_key| self.decode_entry(&store_key).transpose()),
+        )
+    }
+
+    fn decode_entry(&self, store_key: &str) -> Result<Option<(K, V)>> {
+        let key = K::decode_key(&store_key[self.namespace.len()..]).ok_or_else(|| {
+            KvError::ValueSerializationError {
+                key: store_key.to_owned(),
+                details: "key does not decode as the map's key type".to_owned(),
+            }
+        })?;
+        Ok(self.get_encoded(store_key)?.map(|value| (key, value)))
+    }
+}
+
+/// `value` as the JSON stored at `key`, refused if nested deeper than `max_depth` as it
+/// could not be read back.
+pub(crate) fn encode_value<V: Serialize + ?Sized>(
+    key: &str,
+    value: &V,
+    max_depth: usize,
+) -> Result<String> {
+    let serialized =
+        serde_json::to_string(value).map_err(|e| KvError::ValueSerializationError {
+            key: key.to_owned(),
+            details: e.to_string(),
+        })?;
+    let depth = json_depth(&serialized);
+    if depth > max_depth {
+        return Err(KvError::ValueNotRoundTrippable {
+            key: key.to_owned(),
+            details: format!("nested {} levels deep, the limit is {}", depth, max_depth),
+        });
+    }
+    Ok(serialized)
+}
+
+/// The value `encode_value` stored at `key`.
+pub(crate) fn decode_value<V: DeserializeOwned>(key: &str, json: &str) -> Result<V> {
+    serde_json::from_str(json).map_err(|e| KvError::ValueSerializationError {
+        key: key.to_owned(),
+        details: e.to_string(),
+    })
+}
+
+/// Deepest nesting of arrays and objects in the JSON text `json`.
+fn json_depth(json: &str) -> usize {
+    let (mut depth, mut max_depth) = (0, 0);
+    let (mut in_string, mut escaped) = (false, false);
+    for byte in json.bytes() {
+        if in_string {
+            match byte {
+                _ if escaped => escaped = false,
+                b'\\' => escaped = true,
+                b'"' => in_string = false,
+                _ => {}
+            }
+            continue;
+        }
+        match byte {
+            b'"' => in_string = true,
+            b'[' | b'{' => {
+                depth += 1;
+                max_depth = max_depth.max(depth);
+            }
+            b']' | b'}' => depth -= 1,
+            _ => {}
+        }
+    }
+    max_depth
+}
+
+fn encode_bound<K: KeySerialize>(bound: Bound<&K>) -> Bound<String> {
+    match bound {
+        Bound::Included(key) => Bound::Included(key.encode_key()),
+        Bound::Excluded(key) => Bound::Excluded(key.encode_key()),
+        Bound::Unbounded => Bound::Unbounded,
+    }
+}
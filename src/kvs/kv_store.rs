@@ -1,8 +1,38 @@
-use serde;
+pub use crate::kvs::accounting::PrefixUsage;
+use crate::kvs::accounting::{self, Accounting};
+use crate::kvs::backup::{self, BackupManifest};
+use crate::kvs::base64;
+use crate::kvs::bucket::{self, Bucket};
+use crate::kvs::changes::{ChangeEvent, Subscribers, SubscriptionId};
+pub use crate::kvs::codec::LogFormat;
+use crate::kvs::codec::{self, BadHeader, Header, StreamedSet};
+use crate::kvs::crc::{self, Seal};
+use crate::kvs::dir_lock::{self, ReaderLock, ReadersExcluded, WriterLock};
+use crate::kvs::display::Truncated;
+use crate::kvs::events::{EventSink, Events, StoreEvent};
+use crate::kvs::fs_probe::{self, FilesystemAdvisory, FilesystemKind, SystemProbe};
+use crate::kvs::fsutil::{self, IoSite, RetryPolicy};
+use crate::kvs::glob::Pattern;
+use crate::kvs::import::{CsvRows, ImportRow, JsonLines};
+pub use crate::kvs::index::IndexStats;
+use crate::kvs::index::{self, Index};
+use crate::kvs::kv_map;
+use crate::kvs::merge::{MergeOperator, Merger};
+use crate::kvs::merge_policy::{MergePolicy, Policy, SegmentStats};
+use crate::kvs::overlay::StoreOverlay;
+use crate::kvs::sample::Sampler;
+use crate::kvs::segment;
+use crate::kvs::stats::{self, Stats};
+pub use crate::kvs::stats::{StatCounters, StoreStats};
+pub use crate::kvs::value_reader::ValueReader;
+use crate::kvs::warm_up::WarmUp;
+use crate::kvs::write_batch::WriteBatch;
+use serde::de::DeserializeOwned;
 use serde::Deserialize;
 use serde::Serialize;
-use serde_json;
-use std::collections::HashMap;
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::error;
 use std::fmt;
 use std::fs;
@@ -10,12 +40,58 @@ use std::fs::{File, OpenOptions};
 use std::io;
 use std::io::prelude::*;
 use std::io::SeekFrom;
+use std::mem;
+use std::net::SocketAddr;
+use std::ops::{Range, RangeBounds};
 use std::path::{Path, PathBuf};
 use std::str;
-use std::u64;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 pub type Result<T> = std::result::Result<T, KvError>;
 
+/// The log's file name inside the data directory unless `KvStoreOptions::log_file_name`
+/// says otherwise. The offline tools, such as `canonical`, always work on this one.
+pub const DEFAULT_LOG_FILE_NAME: &str = "db.log";
+
+/// The name `KvStore::list_stores` gives the store whose log is `DEFAULT_LOG_FILE_NAME`.
+pub const DEFAULT_STORE_NAME: &str = "db";
+
+/// What the log of a store opened with `KvStoreOptions::store_name` ends with.
+const STORE_LOG_EXTENSION: &str = ".log";
+
+/// The share of the log that must be dead, overwritten or removed since the last
+/// compaction, before a write compacts it, unless `KvStoreOptions::compaction_dead_ratio`
+/// says otherwise.
+pub const DEFAULT_COMPACTION_DEAD_RATIO: f64 = 0.5;
+
+/// Dead bytes since the last compaction at which a write compacts the log however small a
+/// share of it they are, unless `KvStoreOptions::compaction_dead_bytes` says otherwise.
+pub const DEFAULT_COMPACTION_DEAD_BYTES: u64 = 256 * 1024 * 1024;
+
+/// Dead bytes below which `KvStoreOptions::compaction_dead_ratio` compacts nothing, so
+/// that a small log is not rewritten every few writes.
+pub const MIN_COMPACTION_DEAD_BYTES: u64 = 64 * 1024;
+
+/// How long a segment grows before appends roll over to the next, for a segmented log
+/// opened without `KvStoreOptions::segment_size`.
+pub const DEFAULT_SEGMENT_SIZE: u64 = 64 * 1024 * 1024;
+
+/// The share of a sealed segment's record bytes that must be dead, overwritten or removed,
+/// before `TieredMergePolicy` rewrites it by default.
+pub const SEGMENT_DEAD_RATIO: f64 = 0.5;
+
+/// Kinds of `fsutil` temp files the store and its helpers create in the data directory.
+const TEMP_FILE_KINDS: &[&str] = &[
+    "checkpoint",
+    "compact",
+    "index",
+    "restore",
+    "ephemeral.keys",
+    "stats.json",
+];
+
 #[derive(Debug)]
 pub enum KvError {
     WriteError,
@@ -25,230 +101,6051 @@ pub enum KvError {
     ReadLogError,
     InvalidLogCommand,
     SerializationError,
+    NotADirectory(PathBuf),
+    DirectoryNotFound(PathBuf),
+    LogPathIsDirectory(PathBuf),
+    /// `KvStore::open_named` was given a name that is not ASCII letters, digits, `-` and `_`.
+    InvalidStoreName(String),
+    ValueSerializationError {
+        key: String,
+        details: String,
+    },
+    ValueNotRoundTrippable {
+        key: String,
+        details: String,
+    },
+    KeySerializationError(String),
+    KeyTooLarge {
+        key: String,
+        size: u64,
+        limit: u64,
+    },
+    ValueTooLarge {
+        key: String,
+        size: u64,
+        limit: u64,
+    },
+    ValueSourceFailed {
+        key: String,
+        read: u64,
+        len: u64,
+        error: String,
+    },
+    ConnectionError(String),
+    ServerError(String),
+    UnsafeFilesystem(FilesystemAdvisory),
+    StoreDisplaced(PathBuf),
+    CorruptRecord {
+        key: String,
+        offset: u64,
+        details: String,
+    },
+    LogPoisoned {
+        log: PathBuf,
+        offset: u64,
+    },
+    TempFileExists(PathBuf),
+    ConsistencyViolation {
+        key: String,
+        index: String,
+        log: String,
+    },
+    ShardUnavailable {
+        node: SocketAddr,
+    },
+    /// A `ShardedKvsClient` was given no nodes, or no ring points per node, to spread keys
+    /// over.
+    NoShardNodes,
+    /// A client request got no complete answer within its operation timeout. The
+    /// connection is abandoned: the late answer could otherwise be read as the next one's.
+    OperationTimedOut {
+        op: &'static str,
+        elapsed: Duration,
+    },
+    /// An fsync of the log failed; see `KvStore::sync`.
+    DurabilityLost {
+        original_error: String,
+    },
+    /// `StoreOverlay::commit` found `key` changed in the store since the overlay saw it.
+    OverlayConflict(String),
+    /// `KvStore::swap_prefixes` was given prefixes where one starts with the other, so
+    /// some keys would belong to both.
+    OverlappingPrefixes(String, String),
+    /// `KvStore::remove_prefix` was given an empty prefix, which would remove every key;
+    /// `KvStore::clear` does that on purpose.
+    EmptyPrefix,
+    /// The record at `offset` in the log does not match its CRC, so its bytes changed
+    /// after it was written; see `crc`.
+    Corruption {
+        offset: u64,
+        details: String,
+    },
+    /// The log at `log` is in the `found` format, and `KvStoreOptions::format` asked for
+    /// `requested`.
+    LogFormatMismatch {
+        log: PathBuf,
+        found: LogFormat,
+        requested: LogFormat,
+    },
+    /// The file at the log's path does not start with a log header, nor is it a log from
+    /// before headers, so it is some other file.
+    NotAKvsLog(PathBuf),
+    /// The log's header is of a layout `version` this build does not read, most likely
+    /// written by a newer one.
+    UnsupportedVersion {
+        log: PathBuf,
+        version: u16,
+    },
+    /// The data directory holds the log as one file, at this path, and segments of the
+    /// same log as well (see `KvStoreOptions::segment_size`), so which one is the store's
+    /// cannot be told.
+    MixedLogLayout(PathBuf),
+    /// A transient filesystem error outlasted `KvStoreOptions::transient_retry`.
+    TransientIoExhausted {
+        attempts: u32,
+        error: String,
+    },
+    /// An operation panicked part way through, so the index may no longer match the log.
+    /// Everything fails this way until `KvStore::clear_poison_and_verify` succeeds.
+    StorePoisoned {
+        cause: String,
+    },
+    /// A canonical log file (see `canonical`) failed to parse or validate at `line`.
+    CanonicalFormat {
+        line: usize,
+        details: String,
+    },
+    /// A new store was to be built in a directory that already has files.
+    DirectoryNotEmpty(PathBuf),
+    /// The backup at `path` does not match its manifest, or has none; nothing was restored.
+    BackupCorrupt {
+        path: PathBuf,
+        details: String,
+    },
+    /// A write to a store opened read-only, or a normal open of a store on a read-only
+    /// filesystem.
+    ReadOnlyFilesystem(PathBuf),
+    /// A writable open of a directory another store already has open for writing.
+    StoreLocked(PathBuf),
+    /// Compacting or clearing the store in this directory needs its readers out, and one
+    /// opened with `KvStoreOptions::shared_lock` still held on after `dir_lock::READERS_WAIT`;
+    /// nothing was changed.
+    ReadersActive(PathBuf),
+    /// An operation that needs `key` to exist, e.g. `KvStore::rename`, found it missing.
+    KeyNotFound(String),
+    NoMergeOperator(String),
+    /// `KvStore::increment` found a value that does not parse as an `i64`.
+    NotAnInteger {
+        key: String,
+        value: String,
+    },
+    /// `KvStore::increment` would have taken the counter past the range of an `i64`.
+    IntegerOverflow {
+        key: String,
+        value: i64,
+        delta: i64,
+    },
+    /// The server shed the request because too many were queued; nothing was applied.
+    ServerOverloaded {
+        retry_after: Duration,
+    },
 }
 
-pub struct KvStore<'a> {
-    store: HashMap<String, CommandBuffer>,
+pub struct KvStore {
+    // Behind a `RefCell` because a `get` can promote an entry out of the cold tier.
+    store: RefCell<Index>,
     log_path: PathBuf,
-    append_handle: File,
+    append_handle: LogAppender,
     log_size: usize,
+    /// The segment `log_path` is, which appends go to, or 0 for a log kept in one file; see
+    /// `KvStoreOptions::segment_size`. `log_size` and `header_len` are of it alone.
+    segment: u32,
+    /// The segments before it, oldest first.
+    sealed: Vec<Segment>,
+    /// The log's, which every record read and written is in.
+    format: LogFormat,
+    /// The bytes of the log before its first record: `codec::HEADER_LEN`, or none for a
+    /// log from before headers.
+    header_len: usize,
+    /// Set when an append failed after part of the record reached the log.
+    append_poisoned: bool,
+    /// The error of a failed fsync of the log. Once one fails, the kernel may have dropped
+    /// dirty pages it reported as written, so nothing since the last good fsync is known to
+    /// be durable, and retrying the fsync would wrongly succeed.
+    durability_lost: Option<String>,
+    log_stats: LogStats,
+    /// When each live key set by `set_with_ttl` expires, in milliseconds since the Unix
+    /// epoch. Kept beside the index rather than in it, as the cold tier has no room for
+    /// them. A key past its deadline reads as missing until compaction drops it.
+    expiries: HashMap<String, u64>,
+    /// Keys last written by `merge`, whose index entries point at their last operand.
+    merges: HashMap<String, MergeChain>,
+    /// The `Command::Touch` that gave each touched key its deadline, live until the key is
+    /// written again or compaction folds the deadline into its set.
+    touches: HashMap<String, CommandBuffer>,
+    /// Sets that replay dropped as expired, by key, for a later touch to bring back: one
+    /// made before the deadline passed extends it, whatever the clock says at replay.
+    expired_on_replay: HashMap<String, CommandBuffer>,
+    /// The number of the last write; see `last_sequence`.
+    sequence: u64,
     number_of_writes: u64,
-    path: &'a Path,
+    /// `dead_bytes` as the last compaction left them: none in a log kept in one file, and
+    /// in a segmented one, the dead bytes of the segments it left alone.
+    dead_after_compaction: usize,
+    /// Log bytes the compaction under way has written so far, headers included.
+    compaction_written: u64,
+    path: PathBuf,
+    sync_policy: SyncPolicy,
+    open_report: OpenReport,
+    options: KvStoreOptions,
+    log_identity: Option<FileIdentity>,
+    /// Why the store is poisoned, if it is. Shared with the `PanicGuard`s of operations in
+    /// flight, which outlive the borrow of the store they were taken from.
+    poison: Arc<Mutex<Option<String>>>,
+    warm_up: WarmUp,
+    // Shared with the store replacing this one in `reopen`, which must not wait for it.
+    writer_lock: Option<Arc<WriterLock>>,
+    _reader_lock: Option<ReaderLock>,
+    stats: Stats,
+    // Behind a `RefCell` because `get` is counted.
+    accounting: Option<RefCell<Accounting>>,
+    subscribers: Subscribers,
+    sampler: Sampler,
 }
 
-pub struct CommandBuffer {
-    start: usize,
-    size: usize,
+/// What `KvStore::refresh` picked up.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RefreshStats {
+    /// Records replayed that changed the index.
+    pub records_applied: u64,
+    /// The log had been replaced, so the index was rebuilt from the new one.
+    pub reloaded: bool,
+    /// Keys set or removed by the replayed records, in log order and possibly repeated.
+    /// Empty after a reload, which cannot tell what changed.
+    pub changed_keys: Vec<String>,
 }
 
-impl From<serde_json::Error> for KvError {
-    fn from(_: serde_json::Error) -> Self {
-        KvError::SerializationError
-    }
+/// What `KvStore::checkpoint` wrote.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CheckpointInfo {
+    /// Live keys copied.
+    pub keys: u64,
+    /// Length of the checkpoint's log.
+    pub bytes: u64,
+    /// `KvStore::last_sequence` as of the checkpoint, which holds every write numbered up
+    /// to it and none after.
+    pub sequence: u64,
 }
 
-impl From<std::io::Error> for KvError {
-    fn from(_: std::io::Error) -> Self {
-        KvError::OpenError
+/// The layout `KvStore::export` writes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat {
+    /// One JSON object per line, `{"key":...,"value":...}`, in key order.
+    JsonLines,
+}
+
+/// One line of `ExportFormat::JsonLines`.
+#[derive(Serialize)]
+struct ExportEntry<'a> {
+    key: &'a str,
+    value: &'a str,
+}
+
+/// What `KvStore::import` does with an entry whose key is already in the store.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImportMode {
+    /// Sets the key to the imported value.
+    Overwrite,
+    /// Leaves the key as it is. Of entries repeating a key, the first one imported wins.
+    SkipExisting,
+}
+
+/// Which way `KvStore::iter_from` walks the keys.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// Ascending key order.
+    Forward,
+    /// Descending key order.
+    Reverse,
+}
+
+/// What `KvStore::import` loaded.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ImportStats {
+    /// Entries written.
+    pub imported: u64,
+    /// Entries left out by `ImportMode::SkipExisting`.
+    pub skipped_existing: u64,
+    /// Lines that were not an entry of the format, and so were left out. For CSV, these are
+    /// rows that do not parse, lack the key or value column, or hold text that is not UTF-8.
+    pub malformed: u64,
+    /// The 1-based line number of the first one of them; for CSV, the line its row starts on.
+    pub first_malformed_line: Option<u64>,
+}
+
+impl ImportStats {
+    fn record_malformed(&mut self, line: u64) {
+        self.malformed += 1;
+        self.first_malformed_line.get_or_insert(line);
     }
 }
 
-impl error::Error for KvError {}
+/// Entries `KvStore::import` writes with one append.
+const IMPORT_BATCH_ENTRIES: usize = 4096;
+/// Key and value bytes past which `KvStore::import` writes a batch before it is full.
+const IMPORT_BATCH_BYTES: usize = 4 * 1024 * 1024;
 
-impl fmt::Display for KvError {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match *self {
-            KvError::OpenError => write!(f, "Error opening log file"),
-            KvError::RemoveError(ref key) => {
-                write!(f, "Error: Cannot remove {} - the key does not exist", key)
-            }
-            KvError::NoLogPathError => write!(f, "Error: Log path not provided"),
-            KvError::WriteError => write!(f, "Error writing to log file"),
-            KvError::ReadLogError => write!(f, "Error reading the log file"),
-            KvError::InvalidLogCommand => write!(f, "Error command in the log file"),
-            KvError::SerializationError => write!(f, "Error serializing the information"),
+/// What `KvStore::swap_prefixes` moved.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SwapStats {
+    /// Keys that were under the first prefix and are now under the second.
+    pub moved_from_a: u64,
+    /// Keys that were under the second prefix and are now under the first.
+    pub moved_from_b: u64,
+    /// Sets and removes written for the swap.
+    pub records_written: u64,
+}
+
+/// The log as it stood when `KvStore::pin_log` was called. The pin holds the log file
+/// open, so writes after it are not seen and a compaction that replaces the log leaves
+/// the pinned file readable until the pin is dropped.
+pub struct LogPin {
+    /// Each segment's file, oldest first, with the length of its header and its length at
+    /// the pin.
+    segments: Vec<(File, usize, u64)>,
+    format: LogFormat,
+}
+
+impl LogPin {
+    /// The length of the pinned log, all its segments together. The log only grows until
+    /// a compaction replaces it, so records of the same file past this offset are the
+    /// writes made after the pin.
+    pub fn log_offset(&self) -> u64 {
+        self.segments.iter().map(|&(_, _, end)| end).sum()
+    }
+
+    /// Every mutation in the pinned log, as `read_raw_log` reads them.
+    pub(crate) fn read_raw(&self) -> Result<Vec<RawRecord>> {
+        let mut records = Vec::new();
+        for &(ref file, header_len, end) in &self.segments {
+            let mut file = file;
+            let header = header_len as u64;
+            file.seek(SeekFrom::Start(header))?;
+            records.extend(read_raw_records(
+                self.format,
+                header_len,
+                io::BufReader::new(file.take(end.saturating_sub(header))),
+            )?);
         }
+        Ok(records)
     }
 }
 
-impl<'a> KvStore<'a> {
-    pub fn open(log_path: &Path) -> Result<KvStore> {
-        let path = log_path.join("db.log");
-        let file = OpenOptions::new()
-            .append(true)
-            .create(true)
-            .open(path.as_path())?;
+/// Read handles on every segment of a log, by id, opened together, so that reads through
+/// them see the log as it was then whatever compaction does to it afterwards.
+struct LogFiles(Vec<(u32, File)>);
 
-        let mut store = KvStore {
-            store: HashMap::new(),
-            log_path: path,
-            append_handle: file,
-            log_size: 0,
-            number_of_writes: 0,
-            path: log_path,
+impl LogFiles {
+    /// The bytes of the record at `location`, unverified.
+    fn read(&self, location: CommandBuffer) -> io::Result<Vec<u8>> {
+        let mut file = match self
+            .0
+            .binary_search_by_key(&location.segment, |&(id, _)| id)
+        {
+            Ok(n) => &self.0[n].1,
+            Err(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("segment {} of the log was not opened", location.segment),
+                ))
+            }
         };
+        file.seek(SeekFrom::Start(location.start as u64))?;
+        let mut buffer = vec![0; location.size];
+        file.read_exact(&mut buffer)?;
+        Ok(buffer)
+    }
+}
 
-        ensure_file_exists(store.log_path.as_path())?;
-        store.read_log_file()?;
-        store.compact_log()?;
-        Ok(store)
+/// A read view of the store as it was when `KvStore::snapshot` was taken: gets and
+/// iteration see none of the writes made since, so never part of a batch. Like a `LogPin`,
+/// the snapshot holds the log file open, so a compaction or `clear` that replaces the log
+/// leaves the snapshot's copy readable until it is dropped. Keys set with a TTL still
+/// expire on time, as they do in the store.
+pub struct Snapshot {
+    // Behind a `Mutex` as every read seeks the handle, which would otherwise race between
+    // threads sharing the snapshot.
+    log: Mutex<LogFiles>,
+    entries: BTreeMap<String, CommandBuffer>,
+    merges: HashMap<String, MergeChain>,
+    expiries: HashMap<String, u64>,
+    merge_operator: Merger,
+    sequence: u64,
+    format: LogFormat,
+}
+
+impl Snapshot {
+    pub fn get(&self, key: &str) -> Result<Option<String>> {
+        match self.entries.get(key) {
+            Some(&location) if !self.expired(key, now_millis()) => {
+                let bytes = self.read_value_bytes(key, location)?;
+                Ok(Some(decode_value(key, location.start as u64, bytes)?))
+            }
+            _ => Ok(None),
+        }
     }
 
-    pub fn set(&mut self, key: String, value: String) -> Result<()> {
-        self.increment_writes()?;
+    /// Every key of the snapshot with its value, in key order, reading each value as the
+    /// iterator reaches it.
+    pub fn iter(&self) -> impl Iterator<Item = Result<(String, String)>> + '_ {
+        let now = now_millis();
+        self.entries
+            .iter()
+            .filter(move |(key, _)| !self.expired(key, now))
+            .map(|(key, &location)| {
+                let bytes = self.read_value_bytes(key, location)?;
+                Ok((
+                    key.clone(),
+                    decode_value(key, location.start as u64, bytes)?,
+                ))
+            })
+    }
 
-        let command = Command::Set {
-            key: &key,
-            value: &value,
-        };
+    pub fn len(&self) -> usize {
+        let now = now_millis();
+        let expired = self.expiries.values().filter(|&&at| at <= now).count();
+        self.entries.len() - expired
+    }
 
-        let size = write_command_to_log_file(command, &mut self.append_handle)?;
-        let command_buffer: CommandBuffer = CommandBuffer {
-            start: self.log_size,
-            size: size + 1,
-        };
-        self.log_size += size + 1;
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
 
-        self.store.insert(key, command_buffer);
-        Ok(())
+    /// `KvStore::last_sequence` when the snapshot was taken: the snapshot holds exactly the
+    /// writes numbered up to this.
+    pub fn last_sequence(&self) -> u64 {
+        self.sequence
     }
 
-    pub fn remove(&mut self, key: String) -> Result<()> {
-        self.increment_writes()?;
+    /// Writes the snapshot to `dest` as `KvStore::checkpoint` does. This needs no borrow of
+    /// the store, so a caller sharing the store can take the snapshot under its lock and
+    /// write the copy after letting go.
+    pub fn checkpoint(&self, dest: &Path) -> Result<CheckpointInfo> {
+        if dest.exists() && fs::read_dir(dest)?.next().is_some() {
+            return Err(KvError::DirectoryNotEmpty(dest.to_path_buf()));
+        }
+        fs::create_dir_all(dest)?;
+        let temp = fsutil::temp_path(dest, "checkpoint");
+        let result = self.write_checkpoint(&temp, &dest.join(DEFAULT_LOG_FILE_NAME));
+        if result.is_err() {
+            let _ = fs::remove_file(&temp);
+        }
+        result
+    }
 
-        if self.store.remove(&key).is_some() {
-            let command = Command::Rm { key: &key };
-            write_command_to_log_file(command, &mut self.append_handle)?;
-            Ok(())
-        } else {
-            Err(KvError::RemoveError(key))
+    /// Writes a set per live key to `temp`, as compaction would, and renames it to `log`.
+    fn write_checkpoint(&self, temp: &Path, log: &Path) -> Result<CheckpointInfo> {
+        let mut out = io::BufWriter::new(fsutil::create_exclusive(temp)?);
+        let header = self.format.header();
+        out.write_all(&header)?;
+        let mut info = CheckpointInfo {
+            sequence: self.sequence,
+            bytes: header.len() as u64,
+            ..CheckpointInfo::default()
+        };
+        let mut highest = 0;
+        let now = now_millis();
+        for (key, &location) in &self.entries {
+            if self.expired(key, now) {
+                continue;
+            }
+            let found = self.read_record(location)?;
+            let value = self.value_of_record(key, &found)?;
+            let written = decode_record(self.format, &found)?;
+            let seq = written.seq();
+            highest = highest.max(seq.unwrap_or(0));
+            let mut record = encode_set(
+                self.format,
+                key,
+                &value,
+                self.expiries.get(key).copied(),
+                written.timestamp(),
+                seq,
+            )?;
+            record.push(b'\n');
+            out.write_all(&record)?;
+            info.keys += 1;
+            info.bytes += record.len() as u64;
+        }
+        if highest < self.sequence {
+            let record = sequence_record(self.format, self.sequence)?;
+            out.write_all(&record)?;
+            info.bytes += record.len() as u64;
         }
+        out.into_inner().map_err(io::IntoInnerError::into_error)?;
+        fsutil::atomic_rename_into_place(temp, log)?;
+        Ok(info)
     }
 
-    pub fn get(&self, key: &str) -> Result<Option<String>> {
-        if let Some(value) = self.store.get(key) {
-            let mut file = OpenOptions::new().read(true).open(&self.log_path)?;
+    fn expired(&self, key: &str, now: u64) -> bool {
+        matches!(self.expiries.get(key), Some(&expires_at) if expires_at <= now)
+    }
 
-            file.seek(SeekFrom::Start(value.start as u64))?;
-            let mut buffer = vec![0; value.size as usize];
-            file.read_exact(&mut buffer)?;
+    fn read_value_bytes(&self, key: &str, location: CommandBuffer) -> Result<Vec<u8>> {
+        self.value_of_record(key, &self.read_record(location)?)
+    }
 
-            let result = str::from_utf8(&buffer)?;
-            let command: Command = serde_json::from_str(string)?;
-            match command {
-                Command::Set { key, value } => Ok(Some(value.to_string())),
-                _ => Err(KvError::InvalidLogCommand),
+    /// As `KvStore::value_of_record`, against the snapshot's merge chains.
+    fn value_of_record(&self, key: &str, buffer: &[u8]) -> Result<Vec<u8>> {
+        match self.merges.get(key) {
+            Some(chain) => {
+                Ok(
+                    apply_merges(&self.merge_operator, self.format, key, chain, |location| {
+                        self.read_record(location)
+                    })?
+                    .into_bytes(),
+                )
             }
-        } else {
-            Ok(None)
+            None => parse_value_bytes(self.format, buffer),
         }
     }
 
-    pub fn read_log_file(&mut self) -> Result<()> {
-        let mut current_offset: usize = 0;
-        let lines = read_lines(&self.log_path)?;
+    fn read_record(&self, location: CommandBuffer) -> Result<Vec<u8>> {
+        let buffer = self
+            .log
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .read(location)?;
+        verify_record(self.format, &buffer, location.start)?;
+        Ok(buffer)
+    }
+}
+
+/// When appended records are forced to stable storage.
+///
+/// Whatever the policy, a compaction, `clear` and `acknowledge_durability_loss` are
+/// durable once they return, as they fsync the new log before renaming it into place and
+/// the directory after. Under `Never`, other writes are only durable after a `sync` or
+/// `flush`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SyncPolicy {
+    /// Leave flushing to the OS; a power loss can drop the most recent writes.
+    #[default]
+    Never,
+    /// `fsync` the log after every write before acknowledging it.
+    Always,
+}
+
+/// What `open` found and did while opening a store.
+#[derive(Debug, Clone, Default)]
+pub struct OpenReport {
+    /// Filesystem holding the data directory, `None` where detection isn't implemented.
+    pub filesystem: Option<FilesystemKind>,
+    /// Set when that filesystem is known to weaken rename or fsync guarantees.
+    pub filesystem_advisory: Option<FilesystemAdvisory>,
+    /// Well-formed log records that change nothing, such as a `Get` written by another
+    /// tool. Replay skips them and the compaction at open drops them.
+    pub skipped_records: u64,
+    /// The record a crash left half written at the end of the log, which open truncated
+    /// away; `None` when the log ended with a whole record.
+    pub torn_tail: Option<TornTail>,
+}
+
+/// The part of a record that `open` cut from the end of the log; see `OpenReport`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TornTail {
+    /// Where the torn record started, and so the log's length now.
+    pub offset: u64,
+    /// How many bytes of it were dropped.
+    pub bytes: u64,
+}
+
+/// Open-time configuration for a `KvStore`.
+#[derive(Debug, Clone, Default)]
+pub struct KvStoreOptions {
+    create_if_missing: bool,
+    log_file_name: Option<String>,
+    store_name: Option<String>,
+    compaction_threshold: Option<u64>,
+    no_auto_compaction: bool,
+    compaction_dead_ratio: Option<f64>,
+    compaction_dead_bytes: Option<u64>,
+    skip_open_compaction: bool,
+    max_key_size: Option<u64>,
+    max_value_size: Option<u64>,
+    merge_operator: Merger,
+    sync_policy: SyncPolicy,
+    require_safe_filesystem: bool,
+    max_index_bytes: Option<usize>,
+    paranoid_checks: bool,
+    persist_stats: bool,
+    accounting_prefix_depth: usize,
+    accounting_max_prefixes: Option<usize>,
+    ordered_index: bool,
+    sample_seed: Option<u64>,
+    format: Option<LogFormat>,
+    segment_size: Option<u64>,
+    merge_policy: Policy,
+    read_only: bool,
+    shared_lock: bool,
+    defer_warm_up: bool,
+    #[cfg(feature = "test-util")]
+    warm_up_delay: Duration,
+    #[cfg(feature = "test-util")]
+    write_time: Option<u64>,
+    transient_retry: Option<RetryPolicy>,
+    #[cfg(feature = "test-util")]
+    transient_faults: crate::kvs::testing::TransientFaults,
+    events: Events,
+}
+
+impl KvStoreOptions {
+    pub fn new() -> KvStoreOptions {
+        KvStoreOptions::default()
+    }
+
+    /// Create the data directory when it does not exist yet. Off by default, so a
+    /// mistyped path is reported instead of silently starting an empty store.
+    pub fn create_if_missing(mut self, create_if_missing: bool) -> KvStoreOptions {
+        self.create_if_missing = create_if_missing;
+        self
+    }
+
+    /// Keep the log in this file of the data directory instead of `DEFAULT_LOG_FILE_NAME`,
+    /// e.g. so that stores with different logs can share a directory's other files. The
+    /// command-line tools and `canonical` only know the default.
+    pub fn log_file_name(mut self, log_file_name: &str) -> KvStoreOptions {
+        self.log_file_name = Some(log_file_name.to_owned());
+        self
+    }
+
+    /// Also compact the log every this many writes. This is in addition to compacting
+    /// when enough of the log is dead (see `compaction_dead_ratio` and
+    /// `compaction_dead_bytes`), which still applies when this is set. Unset by default, as
+    /// is 0, and then only dead bytes trigger compaction. `auto_compaction` turns both off.
+    pub fn compaction_threshold(mut self, compaction_threshold: u64) -> KvStoreOptions {
+        self.compaction_threshold = Some(compaction_threshold);
+        self
+    }
+
+    /// Let writes compact the log, by dead bytes and by `compaction_threshold`, which is
+    /// the default. Turned off, the log grows until `KvStore::compact` is called or a
+    /// writable open compacts it (see `compact_on_open`).
+    pub fn auto_compaction(mut self, auto_compaction: bool) -> KvStoreOptions {
+        self.no_auto_compaction = !auto_compaction;
+        self
+    }
+
+    /// Compact the log once the bytes that died since the last compaction, records
+    /// overwritten or removed and the removes themselves, are more than this share of it,
+    /// instead of `DEFAULT_COMPACTION_DEAD_RATIO`. Counts only once at least
+    /// `MIN_COMPACTION_DEAD_BYTES` are dead; 1 or more never compacts on the share alone.
+    pub fn compaction_dead_ratio(mut self, ratio: f64) -> KvStoreOptions {
+        self.compaction_dead_ratio = Some(ratio);
+        self
+    }
+
+    /// Compact the log once this many bytes died since the last compaction, whatever share
+    /// of it they are, instead of `DEFAULT_COMPACTION_DEAD_BYTES`. Bounds what a large log
+    /// wastes before its share reaches `compaction_dead_ratio`.
+    pub fn compaction_dead_bytes(mut self, bytes: u64) -> KvStoreOptions {
+        self.compaction_dead_bytes = Some(bytes);
+        self
+    }
+
+    /// Compact the log as a writable open finishes, which is the default. Turning it off
+    /// makes opening a large log faster, at the cost of keeping its stale records until
+    /// the next compaction.
+    pub fn compact_on_open(mut self, compact_on_open: bool) -> KvStoreOptions {
+        self.skip_open_compaction = !compact_on_open;
+        self
+    }
+
+    /// Refuse sets of keys longer than this many bytes with `KvError::KeyTooLarge`, writing
+    /// nothing. Unlimited by default.
+    pub fn max_key_size(mut self, max_key_size: u64) -> KvStoreOptions {
+        self.max_key_size = Some(max_key_size);
+        self
+    }
+
+    /// Refuse sets of values longer than this many bytes with `KvError::ValueTooLarge`,
+    /// writing nothing. Unlimited by default. In a JSON log, text is measured after
+    /// escaping, as its record holds it, so a value of quotes or backslashes counts double.
+    /// A server also refuses a chunked upload of a longer value as it begins, before
+    /// spooling any of it.
+    pub fn max_value_size(mut self, max_value_size: u64) -> KvStoreOptions {
+        self.max_value_size = Some(max_value_size);
+        self
+    }
+
+    /// How `KvStore::merge` operands combine with values. Without one, merges are refused,
+    /// and a log that has merge operands in it cannot be read or compacted.
+    pub fn merge_operator(mut self, operator: Box<dyn MergeOperator>) -> KvStoreOptions {
+        self.merge_operator = Merger::new(operator);
+        self
+    }
+
+    /// Open the store named `name` among those sharing the data directory, as
+    /// `KvStore::open_named` does: its log is `<name>.log`, and its lock files, cold index
+    /// table and stats snapshot are named `<name>.` followed by their usual names, so it
+    /// shares no file with another store. The store named `DEFAULT_STORE_NAME` is the one
+    /// opened without a name. Overrides `log_file_name`.
+    pub fn store_name(mut self, name: &str) -> KvStoreOptions {
+        self.store_name = Some(name.to_owned());
+        self
+    }
 
-        for line_result in lines {
-            let line = line_result?;
-            self.read_line_into_store(&line, current_offset)?;
-            current_offset += line.len() + 1;
+    fn log_file(&self) -> String {
+        match self.store_name {
+            Some(ref name) => format!("{}{}", name, STORE_LOG_EXTENSION),
+            None => self
+                .log_file_name
+                .as_deref()
+                .unwrap_or(DEFAULT_LOG_FILE_NAME)
+                .to_owned(),
         }
+    }
 
-        self.log_size = current_offset;
-        Ok(())
+    /// What the names of the store's files other than its log start with.
+    fn file_prefix(&self) -> String {
+        match self.store_name {
+            Some(ref name) if name != DEFAULT_STORE_NAME => format!("{}.", name),
+            _ => String::new(),
+        }
     }
 
-    pub fn read_line_into_store(&mut self, line: &str, starting_offset: usize) -> Result<()> {
-        let command: Command = serde_json::from_str(&line)?;
-        let command_buffer: CommandBuffer = CommandBuffer {
-            start: starting_offset,
-            size: line.len(),
-        };
+    /// The path of the store's file `name` in the data directory `dir`.
+    fn store_file(&self, dir: &Path, name: &str) -> PathBuf {
+        dir.join(format!("{}{}", self.file_prefix(), name))
+    }
 
-        match command {
-            Command::Rm { key } => {
-                self.store.remove(&key.to_string());
-                Ok(())
-            }
-            Command::Set { key, value } => {
-                self.store.insert(key.to_string(), command_buffer);
-                Ok(())
+    /// What the names of the store's segments start with: its file prefix, or for a log
+    /// named by `log_file_name`, that name less `.log` and a dot, so stores sharing a
+    /// directory that way do not share segments either.
+    fn segment_prefix(&self) -> String {
+        match (&self.store_name, &self.log_file_name) {
+            (None, Some(name)) if name != DEFAULT_LOG_FILE_NAME => {
+                format!(
+                    "{}.",
+                    name.strip_suffix(STORE_LOG_EXTENSION).unwrap_or(name)
+                )
             }
-            _ => Err(KvError::InvalidLogCommand),
+            _ => self.file_prefix(),
         }
     }
 
-    fn increment_writes(&mut self) -> Result<()> {
-        self.number_of_writes += 1;
-
-        if self.number_of_writes % 10_000 == 0 {
-            self.compact_log()?;
+    /// The path in `dir` of segment `id` of the store's log, where 0 is the log kept in one
+    /// file.
+    fn segment_file(&self, dir: &Path, id: u32) -> PathBuf {
+        match id {
+            0 => dir.join(self.log_file()),
+            id => dir.join(segment::file_name(&self.segment_prefix(), id)),
         }
+    }
 
-        Ok(())
+    pub fn sync_policy(mut self, sync_policy: SyncPolicy) -> KvStoreOptions {
+        self.sync_policy = sync_policy;
+        self
     }
 
-    fn compact_log(&mut self) -> Result<()> {
-        let temp_log_file = self.path.join("temp.log");
-        let log_file = self.path.join("db.log");
+    /// Fail to open instead of warning when the data directory is on a filesystem with
+    /// known rename or fsync problems.
+    pub fn require_safe_filesystem(mut self, require_safe_filesystem: bool) -> KvStoreOptions {
+        self.require_safe_filesystem = require_safe_filesystem;
+        self
+    }
 
-        let mut file = OpenOptions::new()
-            .write(true)
-            .create(true)
-            .open(&temp_log_file)
-            .unwrap();
-
-        let mut updated_store: HashMap<String, CommandBuffer> = HashMap::new();
-        let mut offset_start = 0;
-
-        let mut number_of_keys = 0;
-        self.store.iter().for_each(|(key, _)| {
-            number_of_keys += 1;
-            if let Ok(Some(value)) = self.get(key) {
-                let command = Command::Set { key, value };
-                let size = write_command_to_log_file(command, &mut file)?;
-                let command_buffer = CommandBuffer {
-                    start: offset_start,
-                    size,
-                };
-                updated_store.insert(key.to_string(), command_buffer);
-                offset_start += size + 1;
+    /// Keep at most about this many bytes of the index in memory and the rest in a sorted
+    /// table in the data directory, for stores whose index does not fit in RAM. Off by
+    /// default: a get that misses the in-memory tier costs an extra disk read.
+    pub fn max_index_bytes(mut self, max_index_bytes: usize) -> KvStoreOptions {
+        self.max_index_bytes = Some(max_index_bytes);
+        self
+    }
+
+    /// Cross-check the index against the log as the store runs, failing with
+    /// `KvError::ConsistencyViolation` where they disagree: every write reads its record
+    /// back through the index, and every compaction re-reads the whole new log. That is an
+    /// extra read per write and a full scan per compaction, so this is meant for tests.
+    pub fn paranoid_checks(mut self, paranoid_checks: bool) -> KvStoreOptions {
+        self.paranoid_checks = paranoid_checks;
+        self
+    }
+
+    /// Keep lifetime counters across restarts in a `stats.json` snapshot in the data
+    /// directory, rewritten after each compaction and when the store is dropped. Off by
+    /// default, in which case `KvStore::stats` only knows about the current open.
+    pub fn persist_stats(mut self, persist_stats: bool) -> KvStoreOptions {
+        self.persist_stats = persist_stats;
+        self
+    }
+
+    /// Count operations and payload bytes per key prefix of `depth` `/`-separated
+    /// segments, for `KvStore::accounting_snapshot`. 0, the default, turns accounting off.
+    pub fn accounting_prefix_depth(mut self, depth: usize) -> KvStoreOptions {
+        self.accounting_prefix_depth = depth;
+        self
+    }
+
+    /// Most prefixes accounting tracks before the rest count as
+    /// `accounting::OVERFLOW_PREFIX`; `accounting::DEFAULT_MAX_PREFIXES` unless set.
+    pub fn accounting_max_prefixes(mut self, max_prefixes: usize) -> KvStoreOptions {
+        self.accounting_max_prefixes = Some(max_prefixes);
+        self
+    }
+
+    /// Keep the in-memory index's keys in order as well, so `KvStore::range` walks just the
+    /// keys in range instead of sorting them all on every call; each set of a new key and
+    /// each remove pays for a `BTreeSet` update. With `max_index_bytes` set it has no
+    /// effect, as the cold table is already sorted. Off by default, though the first
+    /// `KvStore::iter_from` turns it on until the store is reopened.
+    pub fn ordered_index(mut self, ordered_index: bool) -> KvStoreOptions {
+        self.ordered_index = ordered_index;
+        self
+    }
+
+    /// Seed the random choices of `KvStore::random_key` and `KvStore::sample_keys`, so that
+    /// a store with the same keys draws the same samples in the same order on every run.
+    /// Unset by default, when each store picks a seed of its own.
+    pub fn sample_seed(mut self, seed: u64) -> KvStoreOptions {
+        self.sample_seed = Some(seed);
+        self
+    }
+
+    /// Encode the records of a new log this way. A log that already has records keeps the
+    /// format it was written in, which open reads from its header, and opening it with
+    /// another fails with `KvError::LogFormatMismatch` rather than mixing the two. Unset by
+    /// default, when an existing log opens in its own format and a new one is JSON.
+    pub fn format(mut self, format: LogFormat) -> KvStoreOptions {
+        self.format = Some(format);
+        self
+    }
+
+    /// Keep the log in numbered segment files (see `segment`) instead of one, starting the
+    /// next once an append would take the last past this many bytes. Compaction then
+    /// rewrites only the segments `merge_policy` picks rather than the whole log, and
+    /// leaves the one appended to alone. A record, batch or group is never split, so a
+    /// segment runs past the size when one is larger than that.
+    ///
+    /// A writable open of a log kept in one file splits it into segments of this size, as
+    /// appends would have rolled it over, and an open after a crash part way through the
+    /// split starts it over. A log already in segments stays in them without this set,
+    /// rolling over every `DEFAULT_SEGMENT_SIZE` bytes; unset, a new log is one file.
+    pub fn segment_size(mut self, bytes: u64) -> KvStoreOptions {
+        self.segment_size = Some(bytes);
+        self
+    }
+
+    /// Which sealed segments compaction of a segmented log rewrites, and which of them it
+    /// merges into one (see `merge_policy`). `TieredMergePolicy` by default; `MergeAll`
+    /// rewrites the whole log each time, as compacting a log kept in one file does.
+    pub fn merge_policy(mut self, policy: Box<dyn MergePolicy>) -> KvStoreOptions {
+        self.merge_policy = Policy::new(policy);
+        self
+    }
+
+    /// Open without writing anything to the data directory, for stores on read-only media.
+    /// Nothing is created, leftover temp files are not removed, the log is not compacted,
+    /// the index stays in memory whatever `max_index_bytes` says, and stats are not saved.
+    /// Writes fail with `KvError::ReadOnlyFilesystem`. `KvStore::refresh` picks up what
+    /// another process writes to the store afterwards.
+    pub fn read_only(mut self, read_only: bool) -> KvStoreOptions {
+        self.read_only = read_only;
+        self
+    }
+
+    /// For a read-only store: hold the directory's shared reader lock while open (see
+    /// `dir_lock`), so the writer puts compactions off until it is dropped instead of
+    /// replacing the log, and gets never fail with `KvError::StoreDisplaced`. Meant for short-lived
+    /// stores, such as one CLI command against a live server's directory; a long-lived one
+    /// should `refresh` instead. The lock file is created if missing and the directory is
+    /// writable. Off by default.
+    pub fn shared_lock(mut self, shared_lock: bool) -> KvStoreOptions {
+        self.shared_lock = shared_lock;
+        self
+    }
+
+    /// Return from open as soon as the log is replayed and warm up in the background; see
+    /// `KvStore::ready`. The compaction open would run is skipped, and left to the next
+    /// automatic one or `KvStore::compact`. Off by default, when open compacts and the
+    /// store is ready once it returns.
+    pub fn defer_warm_up(mut self, defer_warm_up: bool) -> KvStoreOptions {
+        self.defer_warm_up = defer_warm_up;
+        self
+    }
+
+    /// Holds a deferred warm-up back for `delay` before it starts.
+    #[cfg(feature = "test-util")]
+    pub fn warm_up_delay(mut self, delay: Duration) -> KvStoreOptions {
+        self.warm_up_delay = delay;
+        self
+    }
+
+    /// Stamps every write with `millis` instead of the time it is made, so that the same
+    /// writes give a log that is the same byte for byte.
+    #[cfg(feature = "test-util")]
+    pub fn write_time(mut self, millis: u64) -> KvStoreOptions {
+        self.write_time = Some(millis);
+        self
+    }
+
+    /// Retry reads, appends and the compaction rename that fail with one of
+    /// `policy.retryable`, as network filesystems do now and then, counting the retries in
+    /// `StatCounters::transient_retries`. Off by default, so that a failing local disk is
+    /// not papered over.
+    pub fn transient_retry(mut self, policy: RetryPolicy) -> KvStoreOptions {
+        self.transient_retry = Some(policy);
+        self
+    }
+
+    /// Fails operations of the stores opened with these options where `faults` says.
+    #[cfg(feature = "test-util")]
+    pub fn transient_faults(
+        mut self,
+        faults: crate::kvs::testing::TransientFaults,
+    ) -> KvStoreOptions {
+        self.transient_faults = faults;
+        self
+    }
+
+    /// Where the store reports warnings and progress; see `events`. By default they are
+    /// dropped, and the store never prints anything.
+    pub fn event_sink(mut self, sink: EventSink) -> KvStoreOptions {
+        self.events = Events::new(sink);
+        self
+    }
+}
+
+/// Result of `KvStore::compact_dry_run`.
+///
+/// The projection assumes compaction rewrites each live record with the bytes it was read
+/// with, which holds for every record this crate wrote. A record written with different
+/// JSON formatting or escaping (e.g. by hand) is re-serialized and may change length.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub struct CompactionEstimate {
+    pub current_log_bytes: u64,
+    pub projected_log_bytes: u64,
+    pub reclaimable_bytes: u64,
+    /// Set records that were overwritten or removed since the last compaction.
+    pub stale_records: u64,
+    /// Rm records since the last compaction.
+    pub tombstone_records: u64,
+}
+
+/// What `KvStore::compact` did.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub struct CompactionStats {
+    /// Length of the log, headers included, before the compaction.
+    pub log_bytes_before: u64,
+    /// Length of the log, headers included, after it.
+    pub log_bytes_after: u64,
+    /// Records left out: sets overwritten or removed since the last compaction, and the
+    /// removes no longer needed to keep them dead.
+    pub records_dropped: u64,
+    /// How long the compaction took.
+    pub duration: Duration,
+}
+
+/// Running account of the log since the last compaction: `live_bytes` is what the records
+/// the index still points at occupy, newlines included, and the header none of it.
+/// `segment_live` splits it by segment.
+#[derive(Debug, Default)]
+struct LogStats {
+    live_bytes: usize,
+    segment_live: HashMap<u32, usize>,
+    stale_records: u64,
+    tombstone_records: u64,
+    /// The last write left no record for compaction to keep, so compacting writes a
+    /// `Sequence` record as well, outside `live_bytes`.
+    last_write_dropped: bool,
+}
+
+impl LogStats {
+    fn record_set(&mut self, previous: Option<CommandBuffer>, location: CommandBuffer) {
+        self.record_touch(previous, location);
+        self.last_write_dropped = false;
+    }
+
+    /// A `Command::Touch` at `location` in place of `previous`, the key's last one. Live
+    /// beside the set it gives a deadline, but carrying no sequence number, so it leaves
+    /// `last_write_dropped` as it was.
+    fn record_touch(&mut self, previous: Option<CommandBuffer>, location: CommandBuffer) {
+        self.drop_record(previous);
+        self.live_bytes += location.size + 1;
+        *self.segment_live.entry(location.segment).or_default() += location.size + 1;
+    }
+
+    fn record_rm(&mut self, previous: Option<CommandBuffer>) {
+        self.drop_record(previous);
+        self.tombstone_records += 1;
+        self.last_write_dropped = true;
+    }
+
+    /// A live record rewritten from `from` to `to`, which may differ in size and segment.
+    fn resize(&mut self, from: CommandBuffer, to: CommandBuffer) {
+        self.live_bytes = self.live_bytes + to.size - from.size;
+        if let Some(live) = self.segment_live.get_mut(&from.segment) {
+            *live -= from.size + 1;
+        }
+        *self.segment_live.entry(to.segment).or_default() += to.size + 1;
+    }
+
+    fn drop_record(&mut self, previous: Option<CommandBuffer>) {
+        if let Some(previous) = previous {
+            self.live_bytes -= previous.size + 1;
+            if let Some(live) = self.segment_live.get_mut(&previous.segment) {
+                *live -= previous.size + 1;
             }
-        });
+            self.stale_records += 1;
+        }
+    }
+
+    /// What the records of `segment` the index still points at occupy.
+    fn live_in(&self, segment: u32) -> usize {
+        self.segment_live.get(&segment).copied().unwrap_or(0)
+    }
+}
+
+/// Where a record sits in the log: in which segment, 0 for a log kept in one file, and at
+/// what offset of it. `size` excludes the trailing newline. `value_len` is the length of
+/// the value the record holds, unescaped, or of its operand for a merge.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CommandBuffer {
+    pub(crate) segment: u32,
+    pub(crate) start: usize,
+    pub(crate) size: usize,
+    pub(crate) value_len: usize,
+}
+
+/// The records a merged key's value is made from: the set it started from, if any, and the
+/// merges since, oldest first.
+#[derive(Debug, Clone)]
+struct MergeChain {
+    base: Option<CommandBuffer>,
+    operands: Vec<CommandBuffer>,
+}
 
-        if let Err(e) = fs::rename(temp_log_file, &log_file) {
-            eprintln!("failed to rename log fie: {}", e);
+impl MergeChain {
+    fn holds(&self, location: CommandBuffer) -> bool {
+        self.base == Some(location) || self.operands.contains(&location)
+    }
+
+    /// Points whichever of the chain's records is at `from` at `to`.
+    fn relocate(&mut self, from: CommandBuffer, to: CommandBuffer) {
+        for record in self.base.iter_mut().chain(&mut self.operands) {
+            if *record == from {
+                *record = to;
+            }
         }
+    }
+}
 
-        self.store = updated_store;
-        self.log_size = offset_start;
-        self.append_handle = OpenOptions::new().append(true).open(&log_file)?;
-        Ok(())
+/// A segment of a segmented log that appends have moved on from, and which only
+/// compaction writes to again.
+#[derive(Debug, Clone, Copy)]
+struct Segment {
+    id: u32,
+    len: usize,
+    header_len: usize,
+    /// `KvStore::sequence` as the segment was sealed, so any write numbered higher is in a
+    /// later one.
+    last_seq: u64,
+    /// Dead bytes the last rewrite had to keep, such as the removes that keep older
+    /// segments' records of their keys dead, which another rewrite would keep too.
+    kept: usize,
+    /// As `KvStore::log_identity`, for a read-only store.
+    identity: Option<FileIdentity>,
+    /// When the segment was sealed or last rewritten, or when its file was last written
+    /// for one sealed before the store was opened.
+    written: SystemTime,
+}
+
+impl From<serde_json::Error> for KvError {
+    fn from(_: serde_json::Error) -> Self {
+        KvError::SerializationError
     }
 }
 
-fn write_command_to_log_file(command: Command, file_handle: &mut File) -> Result<usize> {
-    let serialized = serde_json::to_string(&command)?;
-    writeln!(file_handle, "{}", serialized)?;
-    Ok(serialized.len())
+impl From<str::Utf8Error> for KvError {
+    fn from(_: str::Utf8Error) -> Self {
+        KvError::ReadLogError
+    }
 }
 
-fn read_lines<P>(filename: P) -> io::Result<io::Lines<io::BufReader<File>>>
-where
-    P: AsRef<Path>,
-{
-    let file = File::open(filename)?;
-    Ok(io::BufReader::new(file).lines())
+impl From<std::io::Error> for KvError {
+    fn from(e: std::io::Error) -> Self {
+        match e
+            .get_ref()
+            .and_then(|inner| inner.downcast_ref::<fsutil::RetriesExhausted>())
+        {
+            Some(exhausted) => KvError::TransientIoExhausted {
+                attempts: exhausted.attempts,
+                error: exhausted.last_error.to_string(),
+            },
+            None => KvError::OpenError,
+        }
+    }
 }
 
-fn ensure_file_exists<P: AsRef<Path>>(path: P) -> std::io::Result<()> {
-    OpenOptions::new().create(true).write(true).open(path)?;
-    Ok(())
+impl error::Error for KvError {}
+
+impl fmt::Display for KvError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            KvError::OpenError => write!(f, "Error opening log file"),
+            KvError::RemoveError(ref key) => {
+                write!(
+                    f,
+                    "Error: Cannot remove {} - the key does not exist",
+                    Truncated::new(key)
+                )
+            }
+            KvError::NoLogPathError => write!(f, "Error: Log path not provided"),
+            KvError::WriteError => write!(f, "Error writing to log file"),
+            KvError::ReadLogError => write!(f, "Error reading the log file"),
+            KvError::InvalidLogCommand => write!(f, "Error command in the log file"),
+            KvError::SerializationError => write!(f, "Error serializing the information"),
+            KvError::NotADirectory(ref path) => write!(
+                f,
+                "Error: {} is a file, not a directory - pass the directory that contains db.log",
+                path.display()
+            ),
+            KvError::DirectoryNotFound(ref path) => write!(
+                f,
+                "Error: directory {} does not exist - create it first or enable create_if_missing",
+                path.display()
+            ),
+            KvError::LogPathIsDirectory(ref path) => write!(
+                f,
+                "Error: {} is a directory, not a log file - it was probably left behind by a run \
+                 that was given a file path; move it out of the way and retry",
+                path.display()
+            ),
+            KvError::InvalidStoreName(ref name) => write!(
+                f,
+                "Error: {:?} is not a store name - use ASCII letters, digits, '-' and '_'",
+                Truncated::new(name)
+            ),
+            KvError::ValueSerializationError {
+                ref key,
+                ref details,
+            } => write!(
+                f,
+                "Error serializing the value stored at {}: {}",
+                Truncated::new(key),
+                Truncated::new(details)
+            ),
+            KvError::ValueNotRoundTrippable {
+                ref key,
+                ref details,
+            } => write!(
+                f,
+                "Error: the value for {} was not stored because it could not be read back: {}",
+                Truncated::new(key),
+                details
+            ),
+            KvError::KeySerializationError(ref details) => {
+                write!(f, "Error serializing a key: {}", details)
+            }
+            KvError::KeyTooLarge {
+                ref key,
+                size,
+                limit,
+            } => write!(
+                f,
+                "Error: the key {} is {} bytes, over the store's limit of {}",
+                Truncated::new(key),
+                size,
+                limit
+            ),
+            KvError::ValueTooLarge {
+                ref key,
+                size,
+                limit,
+            } => write!(
+                f,
+                "Error: the value for {} is {} bytes, over the store's limit of {}",
+                Truncated::new(key),
+                size,
+                limit
+            ),
+            KvError::ValueSourceFailed {
+                ref key,
+                read,
+                len,
+                ref error,
+            } => write!(
+                f,
+                "Error: reading the value for {} failed after {} of {} bytes, so nothing was \
+                 stored: {}",
+                Truncated::new(key),
+                read,
+                len,
+                error
+            ),
+            KvError::ConnectionError(ref details) => {
+                write!(f, "Error communicating with the server: {}", details)
+            }
+            // The server already truncates keys and values in the messages it sends.
+            KvError::ServerError(ref message) => write!(f, "{}", message),
+            KvError::UnsafeFilesystem(ref advisory) => write!(f, "Error: {}", advisory),
+            KvError::StoreDisplaced(ref path) => write!(
+                f,
+                "Error: {} was moved or replaced while the store was open - call reopen() \
+                 to load the data now at that path",
+                path.display()
+            ),
+            KvError::CorruptRecord {
+                ref key,
+                offset,
+                ref details,
+            } => write!(
+                f,
+                "Error: corrupt record for {} at log offset {}: {}",
+                Truncated::new(key),
+                offset,
+                details
+            ),
+            KvError::LogPoisoned { ref log, offset } => write!(
+                f,
+                "Error: an earlier append to {} failed part way - writes are refused until \
+                 recover_append() truncates the log back to offset {}",
+                log.display(),
+                offset
+            ),
+            KvError::TempFileExists(ref path) => write!(
+                f,
+                "Error: temp file {} already exists - another process may be using this data \
+                 directory",
+                path.display()
+            ),
+            KvError::ConsistencyViolation {
+                ref key,
+                ref index,
+                ref log,
+            } => write!(
+                f,
+                "Error: index and log disagree about {}: the index has {}, the log has {}",
+                Truncated::new(key),
+                index,
+                log
+            ),
+            KvError::ShardUnavailable { node } => write!(
+                f,
+                "Error: node {} is unavailable - the keys it owns cannot be read or written \
+                 until it is back",
+                node
+            ),
+            KvError::NoShardNodes => write!(
+                f,
+                "Error: a sharded client needs at least one node and one ring point per node"
+            ),
+            KvError::OperationTimedOut { op, elapsed } => write!(
+                f,
+                "Error: {} timed out after {:.1?} waiting for the server",
+                op, elapsed
+            ),
+            KvError::DurabilityLost { ref original_error } => write!(
+                f,
+                "Error: an fsync of the log failed ({}), so recent writes may not be durable; \
+                 writes needing durability are refused until the log is rewritten",
+                original_error
+            ),
+            KvError::CanonicalFormat { line, ref details } => {
+                write!(f, "Error: line {} of the canonical log: {}", line, details)
+            }
+            KvError::DirectoryNotEmpty(ref path) => {
+                write!(
+                    f,
+                    "Error: {} already exists and is not empty",
+                    path.display()
+                )
+            }
+            KvError::BackupCorrupt {
+                ref path,
+                ref details,
+            } => write!(
+                f,
+                "Error: the backup in {} is corrupt: {}",
+                path.display(),
+                details
+            ),
+            KvError::ReadOnlyFilesystem(ref path) => write!(
+                f,
+                "Error: {} is read-only - the store can only be read, with \
+                 KvStore::open_read_only or KvStoreOptions::read_only",
+                path.display()
+            ),
+            KvError::StoreLocked(ref path) => write!(
+                f,
+                "Error: {} is open for writing by another store, perhaps a running \
+                 kvs-server - open it read-only, or go through the server",
+                path.display()
+            ),
+            KvError::ReadersActive(ref path) => write!(
+                f,
+                "Error: readers of {} are holding its log, so it was left as it is - try \
+                 again once they are done",
+                path.display()
+            ),
+            KvError::KeyNotFound(ref key) => {
+                write!(f, "Error: {} - the key does not exist", Truncated::new(key))
+            }
+            KvError::NoMergeOperator(ref key) => write!(
+                f,
+                "Error: {} has merge operands, which only a store opened with a merge operator \
+                 can apply",
+                Truncated::new(key)
+            ),
+            KvError::NotAnInteger { ref key, ref value } => write!(
+                f,
+                "Error: {} holds {:?}, which is not an integer",
+                Truncated::new(key),
+                Truncated::new(value)
+            ),
+            KvError::IntegerOverflow {
+                ref key,
+                value,
+                delta,
+            } => write!(
+                f,
+                "Error: adding {} to {} at {} would overflow",
+                delta,
+                value,
+                Truncated::new(key)
+            ),
+            KvError::ServerOverloaded { retry_after } => write!(
+                f,
+                "Error: the server is overloaded and shed the request; retry after {:?}",
+                retry_after
+            ),
+            KvError::OverlayConflict(ref key) => write!(
+                f,
+                "Error: {} changed since the overlay read it; nothing was committed",
+                Truncated::new(key)
+            ),
+            KvError::OverlappingPrefixes(ref a, ref b) => write!(
+                f,
+                "Error: cannot swap prefixes {:?} and {:?}, one starts with the other",
+                Truncated::new(a),
+                Truncated::new(b)
+            ),
+            KvError::EmptyPrefix => write!(
+                f,
+                "Error: an empty prefix would remove every key - use clear for that"
+            ),
+            KvError::Corruption {
+                offset,
+                ref details,
+            } => write!(
+                f,
+                "Error: the log record at offset {} is corrupt: {}",
+                offset, details
+            ),
+            KvError::LogFormatMismatch {
+                ref log,
+                found,
+                requested,
+            } => write!(
+                f,
+                "Error: {} is a {} log and cannot be opened as {}",
+                log.display(),
+                found,
+                requested
+            ),
+            KvError::NotAKvsLog(ref log) => write!(
+                f,
+                "Error: {} is not a kvs log - check the store's directory and file prefix",
+                log.display()
+            ),
+            KvError::UnsupportedVersion { ref log, version } => write!(
+                f,
+                "Error: {} is a version {} log, and this build reads version {}",
+                log.display(),
+                version,
+                codec::LOG_VERSION
+            ),
+            KvError::MixedLogLayout(ref log) => write!(
+                f,
+                "Error: {} is beside segments of the same log - move one or the other out of \
+                 the data directory",
+                log.display()
+            ),
+            KvError::TransientIoExhausted {
+                attempts,
+                ref error,
+            } => write!(
+                f,
+                "Error: {} persisted through {} attempts; the filesystem may be unavailable",
+                error, attempts
+            ),
+            KvError::StorePoisoned { ref cause } => write!(
+                f,
+                "Error: the store is poisoned by {} - call clear_poison_and_verify() to \
+                 rebuild the index from the log",
+                cause
+            ),
+        }
+    }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-enum Command<'a> {
-    Set { key: &'a str, value: &'a str },
-    Get { key: &'a str },
-    Rm { key: &'a str },
+impl KvStore {
+    pub fn open(log_path: &Path) -> Result<KvStore> {
+        KvStore::open_with_options(log_path, KvStoreOptions::default())
+    }
+
+    /// Opens the store in `dir` without writing to it; see `KvStoreOptions::read_only`.
+    pub fn open_read_only(dir: &Path) -> Result<KvStore> {
+        KvStore::open_with_options(dir, KvStoreOptions::default().read_only(true))
+    }
+
+    pub fn open_with_options(log_path: &Path, options: KvStoreOptions) -> Result<KvStore> {
+        KvStore::open_locked(log_path, options, None)
+    }
+
+    /// Opens the store named `name` in `dir`, one of any number that share the directory
+    /// without sharing a file; see `KvStoreOptions::store_name`. Each compacts, locks and
+    /// recovers on its own. Names are ASCII letters, digits, `-` and `_`, and anything else
+    /// fails with `KvError::InvalidStoreName`.
+    pub fn open_named(dir: &Path, name: &str) -> Result<KvStore> {
+        check_store_name(name)?;
+        KvStore::open_with_options(dir, KvStoreOptions::default().store_name(name))
+    }
+
+    /// The names of the stores in `dir`, in order: those `open_named` can open, with
+    /// `DEFAULT_STORE_NAME` for the one `open` opens.
+    pub fn list_stores(dir: &Path) -> Result<Vec<String>> {
+        let mut names = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            let file_name = entry.file_name();
+            let name = match file_name
+                .to_str()
+                .and_then(|n| n.strip_suffix(STORE_LOG_EXTENSION))
+            {
+                Some(name) if check_store_name(name).is_ok() => name.to_owned(),
+                // A segmented log is a store too, under the name its segments start with.
+                Some(stem) => match segment::store_of(stem) {
+                    Some("") => DEFAULT_STORE_NAME.to_owned(),
+                    Some(name) if check_store_name(name).is_ok() => name.to_owned(),
+                    _ => continue,
+                },
+                None => continue,
+            };
+            if entry.file_type()?.is_file() {
+                names.push(name);
+            }
+        }
+        names.sort();
+        names.dedup();
+        Ok(names)
+    }
+
+    /// Whether `dir` holds a log of the store `open` opens, in one file or in segments.
+    pub fn log_exists(dir: &Path) -> bool {
+        dir.join(DEFAULT_LOG_FILE_NAME).is_file()
+            || segment::list(dir, "").is_ok_and(|ids| !ids.is_empty())
+    }
+
+    /// Opens the store, taking the lock `options` call for unless `writer_lock` is given.
+    fn open_locked(
+        log_path: &Path,
+        options: KvStoreOptions,
+        writer_lock: Option<Arc<WriterLock>>,
+    ) -> Result<KvStore> {
+        validate_data_directory(log_path, &options)?;
+        let (filesystem, filesystem_advisory) = fs_probe::check_filesystem(
+            &SystemProbe,
+            log_path,
+            options.sync_policy,
+            options.require_safe_filesystem,
+        )?;
+        if let Some(ref advisory) = filesystem_advisory {
+            options.events.emit(StoreEvent::UnsafeFilesystem {
+                dir: log_path.to_path_buf(),
+                advisory: advisory.clone(),
+            });
+        }
+
+        let read_only = options.read_only;
+        let writer_lock = match writer_lock {
+            Some(lock) => Some(lock),
+            None if !read_only => Some(Arc::new(
+                WriterLock::acquire(log_path, &options.file_prefix()).map_err(|e| {
+                    match e.kind() {
+                        io::ErrorKind::WouldBlock => KvError::StoreLocked(log_path.to_path_buf()),
+                        _ => read_only_filesystem(e, log_path),
+                    }
+                })?,
+            )),
+            None => None,
+        };
+        let reader_lock = match read_only && options.shared_lock {
+            true => Some(ReaderLock::acquire(log_path, &options.file_prefix())?),
+            false => None,
+        };
+        if !read_only {
+            let removed = fsutil::remove_stale_temp_files(log_path, TEMP_FILE_KINDS)
+                .map_err(|e| read_only_filesystem(e, log_path))?;
+            for stale in removed {
+                options.events.emit(StoreEvent::RemovedStaleTempFile(stale));
+            }
+        }
+
+        let single = options.segment_file(log_path, 0);
+        let marker = log_path.join(segment::split_marker(&options.segment_prefix()));
+        let mut ids = segment::list(log_path, &options.segment_prefix())?;
+        if marker.exists() && single.exists() {
+            // A split cut short: the log is still the one file. A writable open starts
+            // the split over.
+            if !read_only {
+                for &id in &ids {
+                    fs::remove_file(options.segment_file(log_path, id))?;
+                }
+                fs::remove_file(&marker)?;
+                fsutil::sync_dir(log_path)?;
+            }
+            ids.clear();
+        } else if marker.exists() && !read_only {
+            fs::remove_file(&marker)?;
+        }
+        if !ids.is_empty() && single.exists() {
+            return Err(KvError::MixedLogLayout(single));
+        }
+        if let (true, Some(size), false) = (ids.is_empty(), options.segment_size, read_only) {
+            if single.exists() {
+                split_into_segments(log_path, &options, size)?;
+                ids = segment::list(log_path, &options.segment_prefix())?;
+            }
+            if ids.is_empty() {
+                ids.push(1);
+            }
+        }
+        let (segment, sealed_ids) = match ids.split_last() {
+            Some((&last, earlier)) => (last, earlier),
+            None => (0, &[][..]),
+        };
+        let (sealed, requested) = read_sealed(log_path, &options, sealed_ids)?;
+
+        let path = options.segment_file(log_path, segment);
+        // A read-only store keeps a read handle here; `check_writable` stops every append.
+        let mut file = if read_only {
+            File::open(&path)?
+        } else {
+            OpenOptions::new()
+                .append(true)
+                .create(true)
+                .open(path.as_path())
+                .map_err(|e| read_only_filesystem(e, log_path))?
+        };
+        let (format, header_len) = match (read_layout(&path)?, requested) {
+            (Some((found, _)), Some(requested)) if found != requested => {
+                return Err(KvError::LogFormatMismatch {
+                    log: path,
+                    found,
+                    requested,
+                });
+            }
+            (Some(layout), _) => layout,
+            // A read-only store leaves the log to its writer, and `refresh` reads the header
+            // once there is one.
+            (None, requested) if read_only => (requested.unwrap_or_default(), 0),
+            (None, requested) => {
+                let format = requested.unwrap_or_default();
+                // Drops any part of a header a crash left, to write it whole.
+                file.set_len(0)?;
+                file.write_all(&format.header())?;
+                file.sync_all()?;
+                (format, codec::HEADER_LEN)
+            }
+        };
+        // The cold tier is a table written next to the log, so a read-only store does without.
+        let max_index_bytes = options.max_index_bytes.filter(|_| !read_only);
+
+        let mut store = KvStore {
+            store: RefCell::new(Index::open(
+                &options.store_file(log_path, index::COLD_TABLE_FILE_NAME),
+                max_index_bytes,
+                options.ordered_index,
+            )?),
+            log_path: path,
+            append_handle: LogAppender::new(file),
+            segment,
+            sealed,
+            append_poisoned: false,
+            durability_lost: None,
+            log_stats: LogStats::default(),
+            expiries: HashMap::new(),
+            merges: HashMap::new(),
+            touches: HashMap::new(),
+            expired_on_replay: HashMap::new(),
+            sequence: 0,
+            log_size: 0,
+            format,
+            header_len,
+            number_of_writes: 0,
+            dead_after_compaction: 0,
+            compaction_written: 0,
+            path: log_path.to_path_buf(),
+            sync_policy: options.sync_policy,
+            options: options.clone(),
+            log_identity: None,
+            poison: Arc::default(),
+            warm_up: WarmUp::finished(),
+            writer_lock,
+            _reader_lock: reader_lock,
+            open_report: OpenReport {
+                filesystem,
+                filesystem_advisory,
+                skipped_records: 0,
+                torn_tail: None,
+            },
+            stats: Stats::in_memory(),
+            accounting: match options.accounting_prefix_depth {
+                0 => None,
+                depth => Some(RefCell::new(Accounting::new(
+                    depth,
+                    options
+                        .accounting_max_prefixes
+                        .unwrap_or(accounting::DEFAULT_MAX_PREFIXES),
+                ))),
+            },
+            subscribers: Subscribers::default(),
+            sampler: Sampler::new(options.sample_seed),
+        };
+
+        if !read_only {
+            ensure_file_exists(store.log_path.as_path())?;
+        }
+        store.read_log_file()?;
+        if read_only {
+            // Lets `refresh` and `check_not_displaced` tell when a writer replaced the log.
+            store.log_identity = FileIdentity::of(&store.append_handle.file.metadata()?);
+        }
+        if options.persist_stats {
+            let path = options.store_file(log_path, stats::SNAPSHOT_FILE_NAME);
+            let empty = store.log_bytes() <= store.header_bytes();
+            store.stats = Stats::load(path, empty, &options.events);
+        }
+        if store.open_report.skipped_records > 0 {
+            options.events.emit(StoreEvent::SkippedRecords {
+                log: store.log_path.clone(),
+                count: store.open_report.skipped_records,
+            });
+        }
+        if options.defer_warm_up {
+            let mut files: Vec<PathBuf> = store
+                .sealed
+                .iter()
+                .map(|sealed| store.segment_path(sealed.id).into_owned())
+                .collect();
+            files.push(store.log_path.clone());
+            if max_index_bytes.is_some() {
+                files.push(options.store_file(log_path, index::COLD_TABLE_FILE_NAME));
+            }
+            #[cfg(feature = "test-util")]
+            let delay = options.warm_up_delay;
+            #[cfg(not(feature = "test-util"))]
+            let delay = Duration::ZERO;
+            store.warm_up = WarmUp::start(files, delay);
+        } else if !read_only && !options.skip_open_compaction {
+            match store.compact_log() {
+                // Left to a later write, so a reader cannot keep the store from opening.
+                Err(KvError::ReadersActive(_)) => {}
+                result => result?,
+            }
+        }
+        Ok(store)
+    }
+
+    /// Calls `callback` with every key a successful write changes from now on, once the
+    /// write is in the log; see `changes`. Removing a key that is not there, and other
+    /// writes that write nothing, call it with nothing.
+    pub fn subscribe(
+        &mut self,
+        callback: impl Fn(&ChangeEvent) + Send + 'static,
+    ) -> SubscriptionId {
+        self.subscribers.add(Box::new(callback))
+    }
+
+    /// Stops calling the callback `subscribe` returned `id` for. Returns whether it was
+    /// still subscribed.
+    pub fn unsubscribe(&mut self, id: SubscriptionId) -> bool {
+        self.subscribers.remove(id)
+    }
+
+    /// Tells the subscribers `key` was set, by the record at `location`. `value` is the
+    /// value the record holds, if the caller has it at hand and the key has no merges.
+    fn notify_set(&self, key: &str, location: CommandBuffer, value: Option<&[u8]>) -> Result<()> {
+        if self.subscribers.is_empty() {
+            return Ok(());
+        }
+        let value = match value {
+            Some(value) => value.to_vec(),
+            None => self.read_value_bytes(key, location)?,
+        };
+        self.subscribers.notify(&ChangeEvent::set(key, value));
+        Ok(())
+    }
+
+    /// Re-runs the open sequence for the same directory in place, e.g. after
+    /// `KvError::StoreDisplaced`. Anything only held in memory by the old log is dropped.
+    pub fn reopen(&mut self) -> Result<()> {
+        self.save_stats();
+        let store =
+            KvStore::open_locked(&self.path, self.options.clone(), self.writer_lock.clone())?;
+        let subscribers = mem::take(&mut self.subscribers);
+        let sampler = self.sampler.clone();
+        *self = store;
+        self.subscribers = subscribers;
+        self.sampler = sampler;
+        Ok(())
+    }
+
+    pub fn set(&mut self, key: String, value: String) -> Result<()> {
+        let _op = self.enter("set")?;
+        self.append_set(key, value.as_bytes(), None)
+    }
+
+    /// Sets `key` to expire once `ttl` has passed, after which it reads as missing
+    /// everywhere and the next compaction leaves it out. Setting the key again replaces the
+    /// expiry along with the value, though `increment` keeps it, and `rename`, `swap` and
+    /// `swap_prefixes` move it with the value. Deadlines are kept to the millisecond against
+    /// the system clock, so moving the clock moves them too.
+    pub fn set_with_ttl(&mut self, key: String, value: String, ttl: Duration) -> Result<()> {
+        let _op = self.enter("set_with_ttl")?;
+        self.append_set(key, value.as_bytes(), Some(deadline_after(ttl)))
+    }
+
+    /// Sets `key` to `value`, which need not be UTF-8. In a JSON log a value that is UTF-8
+    /// goes into the record's string as text, and any other is written in base64 with a
+    /// field that says so, so the log stays valid JSON. A value that is UTF-8 reads back
+    /// through `get` as well, and `get_bytes` reads any value.
+    pub fn set_bytes(&mut self, key: String, value: &[u8]) -> Result<()> {
+        let _op = self.enter("set_bytes")?;
+        self.append_set(key, value, None)
+    }
+
+    /// Sets `key` to the `len` bytes read from `reader`, copying them into the log a chunk
+    /// at a time rather than holding the value in memory. The record is one `set_bytes`
+    /// could write, so everything else reads it as usual, but in a JSON log the value is in
+    /// base64 even if it is UTF-8, as that is not known until the last byte is in.
+    ///
+    /// If `reader` fails or ends early, the part of the record already appended is
+    /// truncated off again and the error is `KvError::ValueSourceFailed`. Should that
+    /// truncation fail too, the log stays poisoned as after any partial append; a crash
+    /// part way leaves a record without its newline, which the next writable open drops.
+    pub fn set_from_reader<R: Read>(&mut self, key: String, reader: R, len: u64) -> Result<()> {
+        let _op = self.enter("set_from_reader")?;
+        self.check_not_displaced()?;
+        self.check_writable()?;
+        self.check_durable()?;
+        self.check_not_poisoned()?;
+        self.check_size(&key, len)?;
+        self.increment_writes(1)?;
+
+        let (mut set, prefix) = StreamedSet::start(self.format, &key, len)?;
+        self.roll_if_full(prefix.len() + len as usize)?;
+        self.append_record(&prefix)?;
+        let mut size = prefix.len();
+
+        let mut reader = reader.take(len);
+        let mut chunk = vec![0; 64 * 1024];
+        let mut escaped = Vec::with_capacity(chunk.len());
+        let mut read = 0;
+        while read < len {
+            let n = match reader.read(&mut chunk) {
+                Ok(0) => return Err(self.discard_value(key, read, len, "the reader ended early")),
+                Ok(n) => n,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(self.discard_value(key, read, len, &e.to_string())),
+            };
+            read += n as u64;
+            escaped.clear();
+            set.value(&chunk[..n], &mut escaped);
+            if let Err(e) = self.append_record(&escaped) {
+                self.append_poisoned = true;
+                return Err(e);
+            }
+            size += escaped.len();
+        }
+        let mut suffix = set.finish(self.write_time(), self.sequence + 1);
+        suffix.push(b'\n');
+        if let Err(e) = self.append_record(&suffix) {
+            self.append_poisoned = true;
+            return Err(e);
+        }
+        self.sequence += 1;
+        size += suffix.len() - 1;
+        self.sync_if_required()?;
+
+        let command_buffer = CommandBuffer {
+            segment: self.segment,
+            start: self.log_size,
+            size,
+            value_len: len as usize,
+        };
+        self.log_size += size + 1;
+        let previous = self.store.get_mut().insert(key.clone(), command_buffer)?;
+        self.log_stats.record_set(previous, command_buffer);
+        self.forget_history(&key);
+        self.stats.record_sets(1);
+        self.account(|accounting| accounting.record_set(&key, len as usize));
+        self.expiries.remove(&key);
+        self.notify_set(&key, command_buffer, None)?;
+        Ok(())
+    }
+
+    /// Records `operand` for the store's `MergeOperator` to combine with the value of `key`,
+    /// without reading that value: gets apply a key's operands in the order they were
+    /// merged, and compaction applies them once and keeps the result as a set. A key with
+    /// no value merges onto `None`, and one set with a TTL keeps its deadline. Fails with
+    /// `KvError::NoMergeOperator` on a store opened without an operator.
+    ///
+    /// `KvStoreOptions::max_value_size` limits the operand, not the value it merges into.
+    pub fn merge(&mut self, key: String, operand: String) -> Result<()> {
+        let _op = self.enter("merge")?;
+        self.check_not_displaced()?;
+        self.check_writable()?;
+        self.check_durable()?;
+        self.check_not_poisoned()?;
+        if self.options.merge_operator.get().is_none() {
+            return Err(KvError::NoMergeOperator(key));
+        }
+        self.check_value(&key, operand.as_bytes())?;
+        self.increment_writes(1)?;
+
+        let expired = self.expired(&key, now_millis());
+        let expires_at = if expired {
+            None
+        } else {
+            self.expiries.get(&key).copied()
+        };
+        let size = self.append(Command::Merge {
+            key: Cow::Borrowed(&key),
+            operand: Cow::Borrowed(&operand),
+            expires_at,
+            timestamp: Some(self.write_time()),
+            seq: Some(self.sequence + 1),
+        })?;
+        self.sequence += 1;
+        self.sync_if_required()?;
+        let command_buffer = CommandBuffer {
+            segment: self.segment,
+            start: self.log_size,
+            size,
+            value_len: operand.len(),
+        };
+        self.log_size += size + 1;
+
+        let mut previous = self.store.get_mut().insert(key.clone(), command_buffer)?;
+        if expired {
+            // What the key held is gone, so the merge starts from nothing.
+            self.log_stats.drop_record(previous.take());
+            self.forget_history(&key);
+            self.expiries.remove(&key);
+        }
+        self.log_stats.record_set(None, command_buffer);
+        self.push_merge(&key, previous, command_buffer);
+        self.stats.record_sets(1);
+        self.account(|accounting| accounting.record_set(&key, operand.len()));
+        self.notify_set(&key, command_buffer, None)?;
+        Ok(())
+    }
+
+    /// Adds the merge at `location` to the chain of `key`, starting one from `previous`,
+    /// the record the index pointed at, if the key has none.
+    fn push_merge(&mut self, key: &str, previous: Option<CommandBuffer>, location: CommandBuffer) {
+        match self.merges.get_mut(key) {
+            Some(chain) => chain.operands.push(location),
+            None => {
+                let chain = MergeChain {
+                    base: previous,
+                    operands: vec![location],
+                };
+                self.merges.insert(key.to_owned(), chain);
+            }
+        }
+    }
+
+    /// Drops the merge chain and the touch of `key`, whose index entry has just been
+    /// replaced or removed. The index only accounted for the chain's last record, so the
+    /// rest are stale now too, as is a touch of what the key held.
+    fn forget_history(&mut self, key: &str) {
+        if let Some(chain) = self.merges.remove(key) {
+            let earlier = &chain.operands[..chain.operands.len() - 1];
+            for &record in chain.base.iter().chain(earlier) {
+                self.log_stats.drop_record(Some(record));
+            }
+        }
+        let touch = self.touches.remove(key);
+        self.log_stats.drop_record(touch);
+    }
+
+    /// The value of `key` with its merge operands applied.
+    fn merged_value(&self, key: &str, chain: &MergeChain) -> Result<String> {
+        apply_merges(
+            &self.options.merge_operator,
+            self.format,
+            key,
+            chain,
+            |location| self.read_record(location),
+        )
+    }
+
+    /// The value of the record at the front of `buffer`, which the index has `key` at:
+    /// the set's value, or for a merge, the key's value with its operands applied.
+    fn value_of_record(&self, key: &str, buffer: &[u8]) -> Result<Vec<u8>> {
+        match self.merges.get(key) {
+            Some(chain) => Ok(self.merged_value(key, chain)?.into_bytes()),
+            None => parse_value_bytes(self.format, buffer),
+        }
+    }
+
+    /// Takes the start of a `set_from_reader` record back off the log after its reader
+    /// failed, leaving the log poisoned if that fails.
+    fn discard_value(&mut self, key: String, read: u64, len: u64, error: &str) -> KvError {
+        self.append_poisoned = true;
+        if let Err(e) = self.recover_append() {
+            return e;
+        }
+        KvError::ValueSourceFailed {
+            key,
+            read,
+            len,
+            error: error.to_owned(),
+        }
+    }
+
+    /// Makes an existing `key` expire once `ttl` has passed, replacing any expiry it had,
+    /// by writing its value again with the new deadline. Returns false, writing nothing,
+    /// when the key does not exist.
+    pub fn expire(&mut self, key: &str, ttl: Duration) -> Result<bool> {
+        let _op = self.enter("expire")?;
+        self.check_not_displaced()?;
+        let location = match self.live_location(key)? {
+            Some(location) => location,
+            None => return Ok(false),
+        };
+        let value = self.read_value_bytes(key, location)?;
+        self.append_set(key.to_owned(), &value, Some(deadline_after(ttl)))?;
+        Ok(true)
+    }
+
+    /// `expire` without writing the value again: appends a small `Command::Touch` with the
+    /// new deadline, which replay gives the key's set and compaction folds into it, so a
+    /// key touched over and over costs one record. Returns false, writing nothing, when the
+    /// key does not exist or has expired. A key built by `merge` is written whole, as
+    /// `expire` writes it, since its operands read back from its set.
+    pub fn touch(&mut self, key: &str, ttl: Duration) -> Result<bool> {
+        let _op = self.enter("touch")?;
+        self.check_not_displaced()?;
+        self.check_writable()?;
+        self.check_durable()?;
+        self.check_not_poisoned()?;
+        let location = match self.live_location(key)? {
+            Some(location) => location,
+            None => return Ok(false),
+        };
+        let expires_at = deadline_after(ttl);
+        if self.merges.contains_key(key) {
+            let value = self.read_value_bytes(key, location)?;
+            self.append_set(key.to_owned(), &value, Some(expires_at))?;
+            return Ok(true);
+        }
+        self.increment_writes(1)?;
+
+        let size = self.append(Command::Touch {
+            key: Cow::Borrowed(key),
+            expires_at,
+        })?;
+        self.sync_if_required()?;
+        let command_buffer = CommandBuffer {
+            segment: self.segment,
+            start: self.log_size,
+            size,
+            value_len: 0,
+        };
+        self.log_size += size + 1;
+
+        let previous = self.touches.insert(key.to_owned(), command_buffer);
+        self.log_stats.record_touch(previous, command_buffer);
+        self.expiries.insert(key.to_owned(), expires_at);
+        Ok(true)
+    }
+
+    /// Clears the expiry of an existing `key`, so it lasts until removed. Returns false
+    /// when the key does not exist; a key without an expiry is left as it is.
+    pub fn persist(&mut self, key: &str) -> Result<bool> {
+        let _op = self.enter("persist")?;
+        self.check_not_displaced()?;
+        let location = match self.live_location(key)? {
+            Some(location) => location,
+            None => return Ok(false),
+        };
+        if self.expiries.contains_key(key) {
+            let value = self.read_value_bytes(key, location)?;
+            self.append_set(key.to_owned(), &value, None)?;
+        }
+        Ok(true)
+    }
+
+    /// How long `key` has left before it expires, or `None` when it has no expiry. Fails
+    /// with `KvError::KeyNotFound` when the key does not exist, so that `None` only ever
+    /// means a key that lasts.
+    pub fn ttl(&self, key: &str) -> Result<Option<Duration>> {
+        let _op = self.enter("ttl")?;
+        self.check_not_displaced()?;
+        if self.live_location(key)?.is_none() {
+            return Err(KvError::KeyNotFound(key.to_owned()));
+        }
+        let now = now_millis();
+        Ok(self
+            .expiries
+            .get(key)
+            .map(|&expires_at| Duration::from_millis(expires_at.saturating_sub(now))))
+    }
+
+    /// Refuses a set of `value_len` bytes at `key` that is over `KvStoreOptions::max_key_size`
+    /// or `max_value_size`.
+    pub(crate) fn check_size(&self, key: &str, value_len: u64) -> Result<()> {
+        let key_len = key.len() as u64;
+        if let Some(limit) = self.options.max_key_size.filter(|&limit| key_len > limit) {
+            return Err(KvError::KeyTooLarge {
+                key: key.to_owned(),
+                size: key_len,
+                limit,
+            });
+        }
+        if let Some(limit) = self
+            .options
+            .max_value_size
+            .filter(|&limit| value_len > limit)
+        {
+            return Err(KvError::ValueTooLarge {
+                key: key.to_owned(),
+                size: value_len,
+                limit,
+            });
+        }
+        if self.format != LogFormat::Json && value_len > codec::max_value_len(self.format, key) {
+            return Err(KvError::ValueTooLarge {
+                key: key.to_owned(),
+                size: value_len,
+                limit: codec::max_value_len(self.format, key),
+            });
+        }
+        Ok(())
+    }
+
+    /// `check_size` of `value` as measured by `stored_value_len`.
+    fn check_value(&self, key: &str, value: &[u8]) -> Result<()> {
+        self.check_size(key, stored_value_len(self.format, value))
+    }
+
+    fn append_set(&mut self, key: String, value: &[u8], expires_at: Option<u64>) -> Result<()> {
+        self.check_not_displaced()?;
+        self.check_writable()?;
+        self.check_durable()?;
+        self.check_not_poisoned()?;
+        self.check_value(&key, value)?;
+        self.increment_writes(1)?;
+
+        let mut record = encode_set(
+            self.format,
+            &key,
+            value,
+            expires_at,
+            Some(self.write_time()),
+            Some(self.sequence + 1),
+        )?;
+        record.push(b'\n');
+        self.roll_if_full(record.len())?;
+        self.append_record(&record)?;
+        self.sequence += 1;
+        let size = record.len() - 1;
+        self.sync_if_required()?;
+        let command_buffer: CommandBuffer = CommandBuffer {
+            segment: self.segment,
+            start: self.log_size,
+            size,
+            value_len: value.len(),
+        };
+        self.log_size += size + 1;
+
+        let previous = self.store.get_mut().insert(key.clone(), command_buffer)?;
+        self.log_stats.record_set(previous, command_buffer);
+        self.forget_history(&key);
+        self.stats.record_sets(1);
+        self.account(|accounting| accounting.record_set(&key, value.len()));
+        match expires_at {
+            Some(expires_at) => self.expiries.insert(key.clone(), expires_at),
+            None => self.expiries.remove(&key),
+        };
+        if self.options.paranoid_checks {
+            self.check_record(&key, Some(value), command_buffer)?;
+        }
+        self.notify_set(&key, command_buffer, Some(value))?;
+        Ok(())
+    }
+
+    /// Sets `key` to `value` serialized as JSON, as `KvMap` stores its values. Values nested
+    /// deeper than `kv_map::MAX_JSON_DEPTH` are refused with
+    /// `KvError::ValueNotRoundTrippable`, as they could not be read back.
+    pub fn set_typed<T: Serialize + ?Sized>(&mut self, key: String, value: &T) -> Result<()> {
+        let serialized = kv_map::encode_value(&key, value, kv_map::MAX_JSON_DEPTH)?;
+        self.set(key, serialized)
+    }
+
+    /// The value at `key` deserialized from JSON, as `set_typed` stores it. A value that is
+    /// not JSON for a `T` fails with `KvError::ValueSerializationError`.
+    pub fn get_typed<T: DeserializeOwned>(&self, key: &str) -> Result<Option<T>> {
+        match self.get(key)? {
+            Some(value) => kv_map::decode_value(key, &value).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Sets every pair with one append, so either all of them are applied or none are:
+    /// an append that fails applies nothing, and the pairs are written as a group, so
+    /// reopening after a crash part way through the append drops what was written of them.
+    /// Later pairs win over earlier ones with the same key. Returns the number of pairs
+    /// applied.
+    pub fn set_batch(&mut self, pairs: Vec<(String, String)>) -> Result<usize> {
+        let writes = pairs
+            .into_iter()
+            .map(|(key, value)| (key, Some(value.into_bytes())))
+            .collect();
+        self.write_batch(writes)
+    }
+
+    /// `set_batch` for a mix of sets (`Some`) and removes (`None`), of values that need not
+    /// be UTF-8. A remove here is written even if the key does not exist, so callers decide
+    /// which removes to send. A single write needs no group, as a torn record is dropped on
+    /// its own.
+    pub(crate) fn write_batch(&mut self, writes: Vec<(String, Option<Vec<u8>>)>) -> Result<usize> {
+        let _op = self.enter("a batch write")?;
+        self.check_batch(&writes)?;
+        self.increment_writes(writes.len() as u64)?;
+        let grouped = writes.len() > 1;
+        self.append_batch(writes, &HashMap::new(), grouped, false)
+    }
+
+    /// What `write_batch` checks before writing anything.
+    fn check_batch(&self, writes: &[(String, Option<Vec<u8>>)]) -> Result<()> {
+        self.check_not_displaced()?;
+        self.check_writable()?;
+        self.check_durable()?;
+        self.check_not_poisoned()?;
+        for (key, value) in writes {
+            if let Some(value) = value {
+                self.check_value(key, value)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Appends `writes` as one record each, with one append, and applies them, leaving the
+    /// caller to count them toward the next compaction. A set of a key in `deadlines`
+    /// expires then, as a value moved from a key with a TTL keeps it; other sets carry no
+    /// expiry. When `grouped`, the records are led by a `Command::Group`, so a crash part
+    /// way through leaves none of them applied, and when `synced`, the append is fsynced
+    /// before any is applied whatever the `SyncPolicy`.
+    fn append_batch(
+        &mut self,
+        writes: Vec<(String, Option<Vec<u8>>)>,
+        deadlines: &HashMap<String, u64>,
+        grouped: bool,
+        synced: bool,
+    ) -> Result<usize> {
+        let mut records = Vec::new();
+        if grouped {
+            records = encode_command(
+                self.format,
+                &Command::Group {
+                    records: writes.len(),
+                },
+            )?;
+            records.push(b'\n');
+        }
+        let header = records.len();
+        let mut sizes = Vec::with_capacity(writes.len());
+        let mut seq = self.sequence;
+        let now = self.write_time();
+        for (key, value) in &writes {
+            let start = records.len();
+            seq += 1;
+            match value {
+                Some(value) => records.extend_from_slice(&encode_set(
+                    self.format,
+                    key,
+                    value,
+                    deadlines.get(key).copied(),
+                    Some(now),
+                    Some(seq),
+                )?),
+                None => records.extend_from_slice(&encode_command(
+                    self.format,
+                    &Command::Rm {
+                        key: Cow::Borrowed(key),
+                        seq: Some(seq),
+                    },
+                )?),
+            }
+            sizes.push(records.len() - start);
+            records.push(b'\n');
+        }
+        self.roll_if_full(records.len())?;
+        self.append_record(&records)?;
+        self.sequence = seq;
+        match synced {
+            true => self.sync_appended()?,
+            false => self.sync_if_required()?,
+        }
+        self.log_size += header;
+
+        let applied = writes.len();
+        let mut changes = Vec::new();
+        for ((key, value), size) in writes.into_iter().zip(sizes) {
+            let command_buffer = CommandBuffer {
+                segment: self.segment,
+                start: self.log_size,
+                size,
+                value_len: value.as_ref().map_or(0, Vec::len),
+            };
+            self.log_size += size + 1;
+            match deadlines.get(&key) {
+                Some(&expires_at) if value.is_some() => {
+                    self.expiries.insert(key.clone(), expires_at);
+                }
+                _ => {
+                    self.expiries.remove(&key);
+                }
+            }
+            self.forget_history(&key);
+            if value.is_some() {
+                let previous = self.store.get_mut().insert(key.clone(), command_buffer)?;
+                self.log_stats.record_set(previous, command_buffer);
+                self.stats.record_sets(1);
+            } else {
+                let previous = self.store.get_mut().remove(&key)?;
+                self.log_stats.record_rm(previous);
+                self.stats.record_remove();
+            }
+            self.account(|accounting| match value {
+                Some(ref value) => accounting.record_set(&key, value.len()),
+                None => accounting.record_remove(&key),
+            });
+            if self.options.paranoid_checks {
+                self.check_record(&key, value.as_deref(), command_buffer)?;
+            }
+            if !self.subscribers.is_empty() {
+                changes.push(match value {
+                    Some(value) => ChangeEvent::set(&key, value),
+                    None => ChangeEvent::remove(&key),
+                });
+            }
+        }
+        for change in &changes {
+            self.subscribers.notify(change);
+        }
+        Ok(applied)
+    }
+
+    /// Sets `key` to `new`, or removes it for `None`, only if its value is `expected` now,
+    /// where `None` expects it absent. Returns whether the value matched; when it did not,
+    /// nothing is written. Expecting absence and swapping in `None` matches an absent key
+    /// without writing anything either.
+    pub fn compare_and_swap(
+        &mut self,
+        key: String,
+        expected: Option<String>,
+        new: Option<String>,
+    ) -> Result<bool> {
+        let _op = self.enter("compare_and_swap")?;
+        if self.get(&key)? != expected {
+            return Ok(false);
+        }
+        if expected.is_some() || new.is_some() {
+            self.write_batch(vec![(key, new.map(String::into_bytes))])?;
+        }
+        Ok(true)
+    }
+
+    /// Exchanges the values of `key_a` and `key_b`, with one append of a set of each led by
+    /// a `Command::Group`, so that reopening after a crash part way through the append
+    /// finds both sets or neither. Fails with `KvError::KeyNotFound`, writing nothing, when
+    /// either key does not exist. Expiries are exchanged with the values, so a key takes
+    /// the TTL the other had, or none.
+    pub fn swap(&mut self, key_a: &str, key_b: &str) -> Result<()> {
+        let _op = self.enter("swap")?;
+        self.check_not_displaced()?;
+        self.check_writable()?;
+        let mut values = Vec::with_capacity(2);
+        for key in [key_a, key_b] {
+            let location = self
+                .live_location(key)?
+                .ok_or_else(|| KvError::KeyNotFound(key.to_owned()))?;
+            values.push(Some(self.read_value_bytes(key, location)?));
+        }
+        if key_a == key_b {
+            return Ok(());
+        }
+        let writes = vec![
+            (key_a.to_owned(), values.pop().flatten()),
+            (key_b.to_owned(), values.pop().flatten()),
+        ];
+        let mut deadlines = HashMap::new();
+        for (from, to) in [(key_a, key_b), (key_b, key_a)] {
+            if let Some(&expires_at) = self.expiries.get(from) {
+                deadlines.insert(to.to_owned(), expires_at);
+            }
+        }
+        self.check_batch(&writes)?;
+        self.increment_writes(2)?;
+        self.append_batch(writes, &deadlines, true, false)?;
+        Ok(())
+    }
+
+    /// Applies every write in `batch` or none of them, even across a crash; see
+    /// `write_batch`. The batch is encoded in full before anything is written, appended as
+    /// a group with one write, and fsynced whatever the `SyncPolicy` before the index
+    /// takes any of it. An empty batch writes nothing.
+    pub fn apply_batch(&mut self, batch: WriteBatch) -> Result<()> {
+        let _op = self.enter("apply_batch")?;
+        let writes = batch.into_writes();
+        self.check_batch(&writes)?;
+        if writes.is_empty() {
+            return Ok(());
+        }
+        self.increment_writes(writes.len() as u64)?;
+        self.append_batch(writes, &HashMap::new(), true, true)?;
+        Ok(())
+    }
+
+    /// Sets `key` only if it is not live now, returning whether it was set. A key that
+    /// is already there is left as it is, and the call writes nothing and so does not
+    /// count toward the next compaction.
+    pub fn set_if_absent(&mut self, key: String, value: String) -> Result<bool> {
+        let _op = self.enter("set_if_absent")?;
+        self.check_not_displaced()?;
+        if self.index_contains(&key)? {
+            return Ok(false);
+        }
+        self.set(key, value)?;
+        Ok(true)
+    }
+
+    /// The value at `key`, or if it has none, the one `f` makes, which is set first. `f` is
+    /// only called for a missing key, and a key that is there is returned without writing
+    /// anything. `f` runs outside the store's operations, so one that panics writes nothing
+    /// and leaves the store as it was, not poisoned.
+    pub fn get_or_insert_with<F: FnOnce() -> String>(
+        &mut self,
+        key: String,
+        f: F,
+    ) -> Result<String> {
+        if let Some(value) = self.get(&key)? {
+            return Ok(value);
+        }
+        let value = f();
+        self.set(key, value.clone())?;
+        Ok(value)
+    }
+
+    /// Adds `delta` to the integer held by `key`, taking a missing key as 0, and returns
+    /// the sum, which is written back as a set keeping any expiry the key has. A value that
+    /// is not an integer fails with `KvError::NotAnInteger` and is left as it is.
+    pub fn increment(&mut self, key: &str, delta: i64) -> Result<i64> {
+        let _op = self.enter("increment")?;
+        self.check_not_displaced()?;
+        self.check_writable()?;
+        let current = match self.live_location(key)? {
+            Some(location) => {
+                let value = self.read_value(key, location)?;
+                value.parse::<i64>().map_err(|_| KvError::NotAnInteger {
+                    key: key.to_owned(),
+                    value,
+                })?
+            }
+            None => 0,
+        };
+        let sum = current
+            .checked_add(delta)
+            .ok_or_else(|| KvError::IntegerOverflow {
+                key: key.to_owned(),
+                value: current,
+                delta,
+            })?;
+        let expires_at = self.expiries.get(key).copied();
+        self.append_set(key.to_owned(), sum.to_string().as_bytes(), expires_at)?;
+        Ok(sum)
+    }
+
+    /// Moves the value of `old` to `new`, overwriting `new` if it exists, with one append
+    /// of a set of `new` and a remove of `old` led by a `Command::Group`, so that reopening
+    /// after a crash part way through the append finds both or neither. `new` takes the
+    /// expiry of `old`, if it has one. Fails with `KvError::KeyNotFound` when `old` does not
+    /// exist.
+    pub fn rename(&mut self, old: &str, new: String) -> Result<()> {
+        let _op = self.enter("rename")?;
+        self.check_not_displaced()?;
+        self.check_writable()?;
+        let location = match self.live_location(old)? {
+            Some(location) => location,
+            None => return Err(KvError::KeyNotFound(old.to_owned())),
+        };
+        if new == old {
+            return Ok(());
+        }
+        let value = self.read_value_bytes(old, location)?;
+        let deadlines = match self.expiries.get(old) {
+            Some(&expires_at) => HashMap::from([(new.clone(), expires_at)]),
+            None => HashMap::new(),
+        };
+        let writes = vec![(new, Some(value)), (old.to_owned(), None)];
+        self.check_batch(&writes)?;
+        self.increment_writes(2)?;
+        self.append_batch(writes, &deadlines, true, false)?;
+        Ok(())
+    }
+
+    /// The keys of bucket `name`, kept apart from the store's own keys and every other
+    /// bucket's (see `bucket`). A bucket needs no creating: it is there once it has a key.
+    pub fn bucket(&mut self, name: &str) -> Bucket<'_> {
+        Bucket::new(self, name)
+    }
+
+    /// The names of the buckets with live keys, in order.
+    pub fn bucket_names(&self) -> Result<Vec<String>> {
+        let _op = self.enter("bucket_names")?;
+        self.check_not_displaced()?;
+        let mut names: Vec<String> = self
+            .index_keys()?
+            .iter()
+            .filter_map(|key| bucket::name_of(key))
+            .collect();
+        names.sort();
+        names.dedup();
+        Ok(names)
+    }
+
+    /// Removes every key of bucket `name` with one group of removes, which like `set_batch`
+    /// applies whole or not at all, a crash part way through the append included. Returns
+    /// the number of keys removed.
+    pub fn drop_bucket(&mut self, name: &str) -> Result<usize> {
+        let _op = self.enter("drop_bucket")?;
+        let prefix = bucket::prefix(name);
+        let writes: Vec<_> = self
+            .index_keys()?
+            .into_iter()
+            .filter(|key| key.starts_with(&prefix))
+            .map(|key| (key, None))
+            .collect();
+        if writes.is_empty() {
+            return Ok(0);
+        }
+        self.write_batch(writes)
+    }
+
+    /// Exchanges the keys under prefix `a` with those under `b`: `a` followed by anything
+    /// becomes `b` followed by the same, and the other way round. The swap is one group of
+    /// sets and removes, appended at once and fsynced before the index takes any of it, so
+    /// nothing reading this store sees a mix of the two, and reopening after a crash part
+    /// way through the append finds none of it. Swapping again undoes it. A key moved keeps
+    /// its expiry under its new name.
+    ///
+    /// Fails with `KvError::OverlappingPrefixes` when one prefix starts with the other.
+    pub fn swap_prefixes(&mut self, a: &str, b: &str) -> Result<SwapStats> {
+        if a.starts_with(b) || b.starts_with(a) {
+            return Err(KvError::OverlappingPrefixes(a.to_owned(), b.to_owned()));
+        }
+        let _op = self.enter("swap_prefixes")?;
+        let mut stats = SwapStats::default();
+        let mut writes: BTreeMap<String, Option<Vec<u8>>> = BTreeMap::new();
+        let mut deadlines = HashMap::new();
+        let mut vacated = Vec::new();
+        for key in self.index_keys()? {
+            let (suffix, to, moved) = if let Some(suffix) = key.strip_prefix(a) {
+                (suffix, b, &mut stats.moved_from_a)
+            } else if let Some(suffix) = key.strip_prefix(b) {
+                (suffix, a, &mut stats.moved_from_b)
+            } else {
+                continue;
+            };
+            let location = match self.live_location(&key)? {
+                Some(location) => location,
+                None => continue,
+            };
+            let value = self.read_value_bytes(&key, location)?;
+            let moved_to = format!("{}{}", to, suffix);
+            if let Some(&expires_at) = self.expiries.get(&key) {
+                deadlines.insert(moved_to.clone(), expires_at);
+            }
+            writes.insert(moved_to, Some(value));
+            *moved += 1;
+            vacated.push(key);
+        }
+        // A key whose counterpart moves into it is overwritten rather than removed.
+        for key in vacated {
+            writes.entry(key).or_insert(None);
+        }
+        if writes.is_empty() {
+            return Ok(stats);
+        }
+        let writes: Vec<_> = writes.into_iter().collect();
+        self.check_batch(&writes)?;
+        self.increment_writes(writes.len() as u64)?;
+        stats.records_written = self.append_batch(writes, &deadlines, true, true)? as u64;
+        Ok(stats)
+    }
+
+    /// Starts an in-memory overlay of uncommitted writes on top of this store; see
+    /// `StoreOverlay`.
+    pub fn fork(&self) -> StoreOverlay {
+        StoreOverlay::new(&self.path)
+    }
+
+    /// Pins the log as it is now, for an export that must see one point in time while
+    /// writes and compactions carry on; see `canonical::export_pinned`.
+    pub fn pin_log(&self) -> Result<LogPin> {
+        let _op = self.enter("pin_log")?;
+        self.check_not_displaced()?;
+        let mut segments = Vec::with_capacity(self.sealed.len() + 1);
+        for sealed in &self.sealed {
+            let file = self.open_segment(sealed.id)?;
+            segments.push((file, sealed.header_len, sealed.len as u64));
+        }
+        let file = self.with_retry(IoSite::Read, || File::open(&self.log_path))?;
+        segments.push((file, self.header_len, self.log_size as u64));
+        Ok(LogPin {
+            segments,
+            format: self.format,
+        })
+    }
+
+    /// Writes a copy of the store as it is now to `dest`, which must not exist or be empty:
+    /// a compacted log of the live keys, with their expiries and sequence numbers, under
+    /// `DEFAULT_LOG_FILE_NAME`, so `KvStore::open(dest)` opens it as a store of its own.
+    /// The copy is made from a `snapshot`, so it holds every write made before the call and
+    /// none after, and it is fsynced and renamed into place, so a copy that is there at all
+    /// is whole.
+    pub fn checkpoint(&self, dest: &Path) -> Result<CheckpointInfo> {
+        let _op = self.enter("checkpoint")?;
+        self.snapshot()?.checkpoint(dest)
+    }
+
+    /// Writes a `checkpoint` to `dest`, which must not exist or be empty, followed by a
+    /// manifest of its key count and log checksum (see the `backup` module), for `restore` to
+    /// check the copy against.
+    pub fn backup(&self, dest: &Path) -> Result<BackupManifest> {
+        let _op = self.enter("backup")?;
+        backup::write(&self.snapshot()?, dest)
+    }
+
+    /// Installs the backup `backup` wrote in `src` as the store in `dest`, which is created
+    /// if missing. The log is checked against the manifest before it replaces anything, and
+    /// a backup that fails the check is refused with `KvError::BackupCorrupt`. A `dest`
+    /// that already has files is refused with `KvError::DirectoryNotEmpty` unless `force`,
+    /// and one a store has open for writing with `KvError::StoreLocked` either way.
+    pub fn restore(src: &Path, dest: &Path, force: bool) -> Result<()> {
+        backup::restore(src, dest, force)
+    }
+
+    /// Takes a `Snapshot` of the store as it is now. Taking one copies the index, keys
+    /// included, so it costs about what `keys` does; reads through it cost what they do
+    /// through the store.
+    pub fn snapshot(&self) -> Result<Snapshot> {
+        let _op = self.enter("snapshot")?;
+        let log = self.open_log_files()?;
+        // Checked after the open, so that the handle is known to be of the indexed log.
+        self.check_not_displaced()?;
+        let entries = self
+            .store
+            .borrow_mut()
+            .sorted_entries()?
+            .collect::<Result<BTreeMap<_, _>>>()?;
+        Ok(Snapshot {
+            log: Mutex::new(log),
+            entries,
+            merges: self.merges.clone(),
+            expiries: self.expiries.clone(),
+            merge_operator: self.options.merge_operator.clone(),
+            sequence: self.sequence,
+            format: self.format,
+        })
+    }
+
+    pub fn remove(&mut self, key: String) -> Result<()> {
+        let _op = self.enter("remove")?;
+        self.check_not_displaced()?;
+        self.check_writable()?;
+        self.check_durable()?;
+        self.check_not_poisoned()?;
+        self.increment_writes(1)?;
+
+        if !self.index_contains(&key)? {
+            return Err(KvError::RemoveError(key));
+        }
+        let command = Command::Rm {
+            key: Cow::Borrowed(&key),
+            seq: Some(self.sequence + 1),
+        };
+        let size = self.append(command)?;
+        self.sequence += 1;
+        self.sync_if_required()?;
+        let command_buffer = CommandBuffer {
+            segment: self.segment,
+            start: self.log_size,
+            size,
+            value_len: 0,
+        };
+        self.log_size += size + 1;
+        let previous = self.store.get_mut().remove(&key)?;
+        self.log_stats.record_rm(previous);
+        self.expiries.remove(&key);
+        self.forget_history(&key);
+        self.stats.record_remove();
+        self.account(|accounting| accounting.record_remove(&key));
+        if self.options.paranoid_checks {
+            self.check_record(&key, None, command_buffer)?;
+        }
+        self.subscribers.notify(&ChangeEvent::remove(&key));
+        Ok(())
+    }
+
+    /// Removes `key` and returns the value it had, or `None`, writing nothing, when it was
+    /// not there.
+    pub fn pop(&mut self, key: &str) -> Result<Option<String>> {
+        let _op = self.enter("pop")?;
+        self.check_not_displaced()?;
+        self.check_writable()?;
+        let location = match self.live_location(key)? {
+            Some(location) => location,
+            None => return Ok(None),
+        };
+        let value = self.read_value(key, location)?;
+        self.write_batch(vec![(key.to_owned(), None)])?;
+        Ok(Some(value))
+    }
+
+    /// Removes every live key for which `f` returns false, with one group of removes, so
+    /// one append and, under `SyncPolicy::Always`, one fsync however many keys go, and a
+    /// crash part way through the append removes none of them. Only the keys are passed to
+    /// `f`, so no value is read. Returns the number of keys removed; the space they held
+    /// comes back at the next compaction.
+    pub fn retain<F: FnMut(&str) -> bool>(&mut self, mut f: F) -> Result<usize> {
+        let _op = self.enter("retain")?;
+        self.check_not_displaced()?;
+        self.check_writable()?;
+        let writes: Vec<_> = self
+            .index_keys()?
+            .into_iter()
+            .filter(|key| !f(key))
+            .map(|key| (key, None))
+            .collect();
+        if writes.is_empty() {
+            return Ok(0);
+        }
+        self.write_batch(writes)
+    }
+
+    /// Removes every live key starting with `prefix`, with one group of removes as `retain`
+    /// writes, and returns how many there were. Keys are found in the index, so no value is
+    /// read. An empty prefix fails with `KvError::EmptyPrefix` rather than removing
+    /// everything, which is what `clear` is for.
+    pub fn remove_prefix(&mut self, prefix: &str) -> Result<usize> {
+        if prefix.is_empty() {
+            return Err(KvError::EmptyPrefix);
+        }
+        self.retain(|key| !key.starts_with(prefix))
+    }
+
+    /// Compacts the log now instead of waiting for enough of it to die; see
+    /// `KvStoreOptions::compaction_dead_ratio`.
+    ///
+    /// This is also how a log from before records had CRCs is upgraded: compaction writes
+    /// every record it keeps with one. A record that fails its CRC fails the compaction
+    /// with `KvError::Corruption`, leaving the log as it was.
+    ///
+    /// A log kept in segments (see `KvStoreOptions::segment_size`) is compacted a run of
+    /// segments at a time, as `KvStoreOptions::merge_policy` picks them, each run merged
+    /// into one segment put in place atomically before the rest of it is removed.
+    ///
+    /// When `compact_dry_run` finds nothing to reclaim and nothing else needs rewriting,
+    /// this returns at once without touching the log, with no records dropped, the same
+    /// length before and after and a zero duration.
+    pub fn compact(&mut self) -> Result<CompactionStats> {
+        let _op = self.enter("compact")?;
+        self.check_not_displaced()?;
+        self.check_writable()?;
+        let log_bytes_before = self.log_bytes() as u64;
+        if self.nothing_to_compact()? {
+            return Ok(CompactionStats {
+                log_bytes_before,
+                log_bytes_after: log_bytes_before,
+                ..CompactionStats::default()
+            });
+        }
+        let started = Instant::now();
+        let dead_records =
+            |store: &KvStore| store.log_stats.stale_records + store.log_stats.tombstone_records;
+        let before = dead_records(self);
+        self.compact_log()?;
+        Ok(CompactionStats {
+            log_bytes_before,
+            log_bytes_after: self.log_bytes() as u64,
+            records_dropped: before.saturating_sub(dead_records(self)),
+            duration: started.elapsed(),
+        })
+    }
+
+    /// Whether a compaction would leave the log as it is: nothing reclaimable, no merge
+    /// chain or, in a log kept in one file, touch to fold into a set, no key past its TTL
+    /// to drop, no part of it from before
+    /// headers and CRCs to upgrade, and no lost fsync or partial append for a rewrite to
+    /// get past.
+    fn nothing_to_compact(&self) -> Result<bool> {
+        let headed = match self.segment {
+            0 => self.header_len > 0,
+            _ => self.sealed.iter().all(|sealed| sealed.header_len > 0),
+        };
+        let now = now_millis();
+        Ok(headed
+            && self.durability_lost.is_none()
+            && !self.append_poisoned
+            && self.merges.is_empty()
+            && (self.segment > 0 || self.touches.is_empty())
+            && self.expiries.values().all(|&expires_at| expires_at > now)
+            && self.estimate_compaction()?.reclaimable_bytes == 0)
+    }
+
+    /// Removes every key by putting an empty log in place of the current one with the same
+    /// atomic rename compaction uses, so a crash or failure part way leaves either the old
+    /// contents or none, never some of them. A segmented log first gets a remove of every
+    /// key, so that one crash part way through removing its segments replays none of
+    /// their keys; it is left with one empty segment.
+    pub fn clear(&mut self) -> Result<()> {
+        let _op = self.enter("clear")?;
+        self.check_not_displaced()?;
+        self.check_writable()?;
+        let cleared = match self.subscribers.is_empty() {
+            true => Vec::new(),
+            false => self.index_keys()?,
+        };
+        self.rewrite_log(false)?;
+        self.number_of_writes = 0;
+        self.dead_after_compaction = self.dead_bytes();
+        self.reset_accounting();
+        for key in cleared {
+            self.subscribers.notify(&ChangeEvent::remove(&key));
+        }
+        Ok(())
+    }
+
+    /// What `compact` would reclaim, from the running log accounting alone; no I/O.
+    pub fn compact_dry_run(&self) -> Result<CompactionEstimate> {
+        let _op = self.enter("compact_dry_run")?;
+        self.check_not_displaced()?;
+        self.estimate_compaction()
+    }
+
+    fn estimate_compaction(&self) -> Result<CompactionEstimate> {
+        let current_log_bytes = self.log_bytes() as u64;
+        let mut projected_log_bytes;
+        if self.segment > 0 {
+            // Only the runs the merge policy picks are rewritten, each to what it needs.
+            projected_log_bytes = self.log_size as u64;
+            let mut next = 0;
+            for run in self.compaction_runs() {
+                let left = &self.sealed[next..run.start];
+                projected_log_bytes += left.iter().map(|sealed| sealed.len as u64).sum::<u64>();
+                let needed: usize = self.sealed[run.clone()]
+                    .iter()
+                    .map(|sealed| self.log_stats.live_in(sealed.id) + sealed.kept)
+                    .sum();
+                if needed > 0 {
+                    projected_log_bytes += (codec::HEADER_LEN + needed) as u64;
+                }
+                next = run.end;
+            }
+            let left = &self.sealed[next..];
+            projected_log_bytes += left.iter().map(|sealed| sealed.len as u64).sum::<u64>();
+        } else {
+            // A compacted log always has a header, even if this one is from before headers.
+            projected_log_bytes = (codec::HEADER_LEN + self.log_stats.live_bytes) as u64;
+            if self.log_stats.last_write_dropped && self.sequence > 0 {
+                projected_log_bytes += sequence_record(self.format, self.sequence)?.len() as u64;
+            }
+        }
+        Ok(CompactionEstimate {
+            current_log_bytes,
+            projected_log_bytes,
+            reclaimable_bytes: current_log_bytes.saturating_sub(projected_log_bytes),
+            stale_records: self.log_stats.stale_records,
+            tombstone_records: self.log_stats.tombstone_records,
+        })
+    }
+
+    /// Truncates the log back to the end of the last complete record after a partial
+    /// append, and accepts writes again. Does nothing when the log is not poisoned.
+    pub fn recover_append(&mut self) -> Result<()> {
+        let _op = self.enter("recover_append")?;
+        if !self.append_poisoned {
+            return Ok(());
+        }
+        // Through a handle of its own: on Windows an append-only handle may not truncate.
+        let file = OpenOptions::new().write(true).open(&self.log_path)?;
+        file.set_len(self.log_size as u64)?;
+        file.sync_data()?;
+        self.append_poisoned = false;
+        self.options
+            .events
+            .emit(StoreEvent::RecoveredPartialAppend {
+                log: self.log_path.clone(),
+                truncated_to: self.log_size as u64,
+            });
+        Ok(())
+    }
+
+    /// Forces every write so far to stable storage, whatever the `SyncPolicy`.
+    ///
+    /// A failed fsync is sticky: it and every later `sync` fail with
+    /// `KvError::DurabilityLost`, as do all writes under `SyncPolicy::Always`, until
+    /// `acknowledge_durability_loss` rewrites the log.
+    pub fn sync(&mut self) -> Result<()> {
+        let _op = self.enter("sync")?;
+        self.check_not_displaced()?;
+        // Nothing was written, and Windows cannot flush a read handle.
+        if self.options.read_only {
+            return Ok(());
+        }
+        self.sync_log()
+    }
+
+    /// `sync` under the name `Write` users look for. Records are written straight to the
+    /// log file with no buffer of the store's own in between, so forcing them to stable
+    /// storage is all there is to flush.
+    pub fn flush(&mut self) -> Result<()> {
+        self.sync()
+    }
+
+    /// Whether an fsync of the log failed since it was last rewritten.
+    pub fn is_durability_lost(&self) -> bool {
+        self.durability_lost.is_some()
+    }
+
+    /// Compacts the live records into a fresh log, which is fsynced before it replaces the
+    /// old one, and only then clears the durability loss. If the rewrite fails the loss
+    /// stays and this can be retried.
+    pub fn acknowledge_durability_loss(&mut self) -> Result<()> {
+        self.check_not_displaced()?;
+        if self.durability_lost.is_none() {
+            return Ok(());
+        }
+        self.compact_log()?;
+        self.durability_lost = None;
+        Ok(())
+    }
+
+    /// Makes the next fsync of the log fail with `error`, as on EIO.
+    #[cfg(feature = "test-util")]
+    pub fn inject_sync_failure(&mut self, error: io::ErrorKind) {
+        self.append_handle.sync_fault = Some(error);
+    }
+
+    /// Whether writes are refused until `recover_append` runs.
+    pub fn is_append_poisoned(&self) -> bool {
+        self.append_poisoned
+    }
+
+    /// What panicked part way through an operation, if anything did; see
+    /// `KvError::StorePoisoned`.
+    pub fn poison_cause(&self) -> Option<String> {
+        self.poison
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    /// Poisons the store after a panic its operations did not see, such as one in the
+    /// server while it held the store. Keeps the first cause.
+    pub(crate) fn mark_poisoned(&self, cause: String) {
+        self.poison
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get_or_insert(cause);
+    }
+
+    /// Rebuilds the index from the log, the durable record of every write that completed
+    /// its append, and checks the two agree before accepting operations again. Works like
+    /// `reopen`, so a writable store also compacts its log; if it fails, the store stays
+    /// poisoned.
+    pub fn clear_poison_and_verify(&mut self) -> Result<()> {
+        self.reopen()?;
+        self.check_index_matches_log()
+    }
+
+    /// Makes the next append panic once its record is in the log, before the index hears
+    /// of it.
+    #[cfg(feature = "test-util")]
+    pub fn inject_panic_after_append(&mut self) {
+        self.append_handle.panic_fault = true;
+    }
+
+    /// Marks an operation in flight, failing if an earlier one panicked.
+    fn enter(&self, op: &'static str) -> Result<PanicGuard> {
+        match self.poison_cause() {
+            Some(cause) => Err(KvError::StorePoisoned { cause }),
+            None => Ok(PanicGuard {
+                poison: Arc::clone(&self.poison),
+                op,
+            }),
+        }
+    }
+
+    /// Makes the next append stop after `short_write.after_bytes` bytes of its record.
+    #[cfg(feature = "test-util")]
+    pub fn inject_short_write(&mut self, short_write: crate::kvs::testing::ShortWrite) {
+        self.append_handle.fault = Some(short_write);
+    }
+
+    /// Appends one record and its newline, returning the record's length without the newline.
+    /// Bytes are counted as they land, so a failure part way poisons the store instead of
+    /// leaving `log_size` behind the file.
+    fn append(&mut self, command: Command) -> Result<usize> {
+        let mut record = encode_command(self.format, &command)?;
+        record.push(b'\n');
+        self.roll_if_full(record.len())?;
+        self.append_record(&record)?;
+        Ok(record.len() - 1)
+    }
+
+    /// Seals the segment appended to and starts the next when appending `len` more bytes
+    /// would take it past `KvStoreOptions::segment_size`, unless it has no records yet.
+    /// Only ever between whole records, batches and groups.
+    fn roll_if_full(&mut self, len: usize) -> Result<()> {
+        if self.segment == 0 || self.log_size <= self.header_len {
+            return Ok(());
+        }
+        let limit = self.options.segment_size.unwrap_or(DEFAULT_SEGMENT_SIZE);
+        if (self.log_size + len) as u64 <= limit {
+            return Ok(());
+        }
+        // Nothing appends to the sealed segment again, so this is its last chance to reach
+        // the disk before later writes do. A failure is left for `check_durable` to refuse
+        // writes over, as it would after any other fsync.
+        if let Err(e) = self.sync_log() {
+            if self.sync_policy == SyncPolicy::Always {
+                return Err(e);
+            }
+        }
+        let id = self.segment + 1;
+        let path = self.segment_path(id).into_owned();
+        let mut file = OpenOptions::new()
+            .append(true)
+            .create_new(true)
+            .open(&path)?;
+        let created = file
+            .write_all(&self.format.header())
+            .and_then(|()| fsutil::sync_dir(&self.path));
+        if let Err(e) = created {
+            // Removed, so that the next append can create it afresh.
+            drop(file);
+            let _ = fs::remove_file(&path);
+            return Err(e.into());
+        }
+        self.sealed.push(Segment {
+            id: self.segment,
+            len: self.log_size,
+            header_len: self.header_len,
+            last_seq: self.sequence,
+            kept: 0,
+            identity: None,
+            written: SystemTime::now(),
+        });
+        // Swapping the file alone keeps any fault a test armed on the appender.
+        self.append_handle.file = file;
+        self.segment = id;
+        self.log_path = path;
+        self.log_size = codec::HEADER_LEN;
+        self.header_len = codec::HEADER_LEN;
+        Ok(())
+    }
+
+    /// Writes `record` to the end of the log, poisoning it if only part got there.
+    ///
+    /// A write that fails lands nothing, so under `KvStoreOptions::transient_retry` it is
+    /// retried from where the record got to; giving up still poisons the log if part of
+    /// the record is in it.
+    fn append_record(&mut self, record: &[u8]) -> Result<()> {
+        let mut written = 0;
+        let mut failures = 0;
+        while written < record.len() {
+            #[cfg(feature = "test-util")]
+            let result = self
+                .options
+                .transient_faults
+                .check(IoSite::Append)
+                .and_then(|()| self.append_handle.write(&record[written..]));
+            #[cfg(not(feature = "test-util"))]
+            let result = self.append_handle.write(&record[written..]);
+            match result {
+                Ok(0) => {
+                    self.append_poisoned = written > 0;
+                    return Err(KvError::WriteError);
+                }
+                Ok(n) => {
+                    written += n;
+                    failures = 0;
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => {
+                    failures += 1;
+                    match self.options.transient_retry {
+                        Some(ref policy) if policy.is_retryable(&e) => {
+                            if failures < policy.max_attempts {
+                                thread::sleep(policy.delay(failures));
+                                self.stats.record_retry();
+                                continue;
+                            }
+                            self.append_poisoned = written > 0;
+                            return Err(KvError::TransientIoExhausted {
+                                attempts: failures,
+                                error: e.to_string(),
+                            });
+                        }
+                        _ => {
+                            self.append_poisoned = written > 0;
+                            return Err(KvError::WriteError);
+                        }
+                    }
+                }
+            }
+        }
+        #[cfg(feature = "test-util")]
+        if mem::take(&mut self.append_handle.panic_fault) {
+            panic!("injected panic after an append");
+        }
+        Ok(())
+    }
+
+    fn check_writable(&self) -> Result<()> {
+        if self.options.read_only {
+            return Err(KvError::ReadOnlyFilesystem(self.path.clone()));
+        }
+        Ok(())
+    }
+
+    fn check_not_poisoned(&self) -> Result<()> {
+        if self.append_poisoned {
+            return Err(KvError::LogPoisoned {
+                log: self.log_path.clone(),
+                offset: self.log_size as u64,
+            });
+        }
+        Ok(())
+    }
+
+    pub fn get(&self, key: &str) -> Result<Option<String>> {
+        let _op = self.enter("get")?;
+        match self.get_located(key)? {
+            Some((location, bytes)) => Ok(Some(decode_value(key, location.start as u64, bytes)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// The value of `key` as the bytes `set_bytes` stored, whether or not they are UTF-8.
+    pub fn get_bytes(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        let _op = self.enter("get_bytes")?;
+        Ok(self.get_located(key)?.map(|(_, bytes)| bytes))
+    }
+
+    /// The value of `key` as a reader of the bytes `get_bytes` would return, streamed from
+    /// the log through a handle of its own rather than read into memory, for values too
+    /// large to hold. The reader keeps reading the value it was opened on whatever is
+    /// written or compacted afterwards. The record is read through once against its CRC
+    /// before the reader is returned, so a damaged value fails with `KvError::Corruption`
+    /// as it would from `get`, rather than streaming. Accounting counts the whole record
+    /// as read, since the value's own length is not known until it has been.
+    pub fn get_reader(&self, key: &str) -> Result<Option<ValueReader>> {
+        let _op = self.enter("get_reader")?;
+        self.check_not_displaced()?;
+        let location = match self.live_location(key)? {
+            Some(location) => location,
+            None => {
+                self.stats.record_get();
+                self.account(|accounting| accounting.record_get(key, None));
+                return Ok(None);
+            }
+        };
+        self.stats.record_get();
+        self.account(|accounting| accounting.record_get(key, Some(location.size)));
+        let mut log = self.open_segment(location.segment)?;
+        let (start, size) = (location.start as u64, location.size as u64);
+        verify_record_at(self.format, &mut log, start, size)?;
+        let located = match self.format {
+            LogFormat::Json => ValueReader::locate(log, start, size)?,
+            framed => ValueReader::locate_frame(framed, log, start, size)?,
+        };
+        match located {
+            Some(reader) => Ok(Some(reader)),
+            None => Ok(Some(ValueReader::buffered(
+                self.read_value_bytes(key, location)?,
+            ))),
+        }
+    }
+
+    /// A get's lookup, read and counting, returning where the value was found with its
+    /// bytes.
+    fn get_located(&self, key: &str) -> Result<Option<(CommandBuffer, Vec<u8>)>> {
+        let started = self.stats.first_get_pending().then(Instant::now);
+        self.check_not_displaced()?;
+        let location = self.live_location(key)?;
+        self.stats.record_get();
+        let value = match location {
+            Some(location) => Some((location, self.read_value_bytes(key, location)?)),
+            None => None,
+        };
+        self.account(|accounting| {
+            accounting.record_get(key, value.as_ref().map(|(_, bytes)| bytes.len()))
+        });
+        if let Some(started) = started {
+            self.stats.record_first_get(started.elapsed());
+        }
+        Ok(value)
+    }
+
+    /// The values of `keys`, in the same order, `None` where a key has none. The log is
+    /// opened once and the records read in log order, so the reads move forward through
+    /// the file instead of reopening it per key as `get` does. A key asked for twice is
+    /// read once. Each key counts as a get in the stats and accounting.
+    pub fn multi_get(&self, keys: &[String]) -> Result<Vec<Option<String>>> {
+        let _op = self.enter("multi_get")?;
+        self.check_not_displaced()?;
+        let mut locations = Vec::new();
+        {
+            let now = now_millis();
+            let mut index = self.store.borrow_mut();
+            for (n, key) in keys.iter().enumerate() {
+                if let Some(location) = index.get(key)? {
+                    if !self.expired(key, now) {
+                        locations.push((location, n));
+                    }
+                }
+            }
+        }
+        locations.sort_by_key(|&(location, _)| (location.segment, location.start));
+
+        let mut values = vec![None; keys.len()];
+        let files = self.open_log_files()?;
+        let mut previous: Option<(CommandBuffer, usize)> = None;
+        for (location, n) in locations {
+            let value = match previous {
+                Some((at, earlier)) if at == location => values[earlier].clone(),
+                _ => {
+                    let buffer = files.read(location)?;
+                    let bytes = self.value_of_record(&keys[n], &buffer)?;
+                    Some(decode_value(&keys[n], location.start as u64, bytes)?)
+                }
+            };
+            values[n] = value;
+            previous = Some((location, n));
+        }
+
+        for (key, value) in keys.iter().zip(&values) {
+            self.stats.record_get();
+            self.account(|accounting| accounting.record_get(key, value.as_ref().map(String::len)));
+        }
+        Ok(values)
+    }
+
+    /// Whether `key` has a value, answered from the index without reading the log. It is
+    /// fallible because with `max_index_bytes` set, a key in the cold tier is looked up in
+    /// the index table on disk.
+    pub fn contains_key(&self, key: &str) -> Result<bool> {
+        let _op = self.enter("contains_key")?;
+        self.check_not_displaced()?;
+        self.index_contains(key)
+    }
+
+    /// The length in bytes of the value `get_bytes` would return for `key`, or `None` when
+    /// it has none, answered from the index without reading the log: a value is measured
+    /// as it is written or replayed. Only a key with merge operands not yet compacted is
+    /// read, to apply them. Fallible for the reasons `contains_key` is.
+    pub fn value_size(&self, key: &str) -> Result<Option<u64>> {
+        let _op = self.enter("value_size")?;
+        self.check_not_displaced()?;
+        let location = match self.live_location(key)? {
+            Some(location) => location,
+            None => return Ok(None),
+        };
+        let len = match self.merges.get(key) {
+            Some(chain) => self.merged_value(key, chain)?.len(),
+            None => location.value_len,
+        };
+        Ok(Some(len as u64))
+    }
+
+    /// When `key` was last set or merged into, from the time kept in its log record, which
+    /// compaction carries over. `None` when it has no value, or when its record has no time,
+    /// as records written before there were times and by the offline tools do not.
+    pub fn last_modified(&self, key: &str) -> Result<Option<SystemTime>> {
+        let _op = self.enter("last_modified")?;
+        self.check_not_displaced()?;
+        let location = match self.live_location(key)? {
+            Some(location) => location,
+            None => return Ok(None),
+        };
+        let record = self.read_record(location)?;
+        let timestamp = decode_record(self.format, &record)?.timestamp();
+        Ok(timestamp.map(|millis| UNIX_EPOCH + Duration::from_millis(millis)))
+    }
+
+    /// Live keys, from the index rather than the log: a key set many times counts once,
+    /// and a removed one not at all.
+    pub fn len(&self) -> usize {
+        let now = now_millis();
+        let expired = self.expiries.values().filter(|&&at| at <= now).count();
+        self.store.borrow().len() - expired
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Live keys starting with `prefix`, counted in the index without reading the log.
+    /// Fallible for the reasons `contains_key` is.
+    pub fn count_prefix(&self, prefix: &str) -> Result<usize> {
+        let _op = self.enter("count_prefix")?;
+        self.check_not_displaced()?;
+        Ok(self
+            .index_keys()?
+            .iter()
+            .filter(|key| key.starts_with(prefix))
+            .count())
+    }
+
+    /// Live keys by their first segment, the part before the first `delimiter`, counted in
+    /// the index without reading the log. A key without the delimiter is a segment of its
+    /// own, and one starting with it counts under the empty segment. Fallible for the
+    /// reasons `contains_key` is.
+    pub fn prefix_histogram(&self, delimiter: char) -> Result<HashMap<String, usize>> {
+        let _op = self.enter("prefix_histogram")?;
+        self.check_not_displaced()?;
+        let mut histogram = HashMap::new();
+        for key in self.index_keys()? {
+            let segment = key.split(delimiter).next().unwrap_or_default();
+            *histogram.entry(segment.to_owned()).or_insert(0) += 1;
+        }
+        Ok(histogram)
+    }
+
+    /// A live key picked uniformly at random, or `None` for an empty store, as a sample of
+    /// one by `sample_keys`.
+    pub fn random_key(&self) -> Result<Option<String>> {
+        Ok(self.sample_keys(1)?.pop())
+    }
+
+    /// `n` distinct live keys picked uniformly at random, in key order, or every live key
+    /// when there are no more than `n`. The index is walked once, holding only the keys
+    /// picked so far, and no value is read. Each call draws a new sample; see
+    /// `KvStoreOptions::sample_seed` for repeatable ones. Fallible for the reasons
+    /// `contains_key` is.
+    pub fn sample_keys(&self, n: usize) -> Result<Vec<String>> {
+        let _op = self.enter("sample_keys")?;
+        self.check_not_displaced()?;
+        let now = now_millis();
+        let mut sample = self.sampler.sample(n);
+        self.store.borrow_mut().for_each_key(|key| {
+            if !self.expired(key, now) {
+                sample.offer(key);
+            }
+        })?;
+        Ok(sample.into_keys())
+    }
+
+    /// The sequence number of the last write, or 0 before the first. Every set, remove and
+    /// merge is numbered one past the write before it, a batch numbering its writes in
+    /// order, and the number is kept in the write's log record. Replay carries on from the
+    /// highest number in the log and compaction keeps each key's, so numbers never repeat
+    /// or go backwards across reopens, except that a store reopened after `clear` and
+    /// before any write numbers from 1 again, its log being empty.
+    pub fn last_sequence(&self) -> u64 {
+        self.sequence
+    }
+
+    /// Every live key, each once and in key order, read from the index alone. The keys are
+    /// collected up front, so the iterator does not hold the store.
+    pub fn keys(&self) -> Result<impl Iterator<Item = String>> {
+        let _op = self.enter("keys")?;
+        self.check_not_displaced()?;
+        Ok(self.index_keys()?.into_iter())
+    }
+
+    /// Every live key with its value, in key order. The locations are taken from the index
+    /// up front and the values read through one handle on the log, opened here, which keeps
+    /// reading the log it was opened on even if the store compacts meanwhile. A value that
+    /// cannot be read or parsed is an `Err` in its place, and the pairs after it still come.
+    pub fn iter(&self) -> Result<impl Iterator<Item = Result<(String, String)>>> {
+        let _op = self.enter("iter")?;
+        self.check_not_displaced()?;
+        let entries = self.sorted_locations(|_| true)?;
+        self.read_entries(entries)
+    }
+
+    /// Writes every live key with its value to `writer` in `format`, returning the number
+    /// of entries written. Values are read from the log one at a time as they are written
+    /// out, as `iter` reads them, so the store's contents are never held in memory at once.
+    /// Fails, with what was written so far left in `writer`, on the first value that cannot
+    /// be read or is not UTF-8.
+    pub fn export<W: Write>(&self, writer: W, format: ExportFormat) -> Result<u64> {
+        let _op = self.enter("export")?;
+        let mut writer = io::BufWriter::new(writer);
+        let mut written = 0;
+        for entry in self.iter()? {
+            let (key, value) = entry?;
+            match format {
+                ExportFormat::JsonLines => {
+                    serde_json::to_writer(
+                        &mut writer,
+                        &ExportEntry {
+                            key: &key,
+                            value: &value,
+                        },
+                    )?;
+                    writer.write_all(b"\n")?;
+                }
+            }
+            written += 1;
+        }
+        writer.flush()?;
+        Ok(written)
+    }
+
+    /// Loads the entries `reader` holds in `format`, as `export` writes them, appending them
+    /// a few thousand at a time, each batch written and synced as `set_batch` writes one,
+    /// rather than with an append per entry. Blank lines are passed over, and lines
+    /// that are not an entry are counted in `ImportStats::malformed` and left out.
+    ///
+    /// The entries count toward `KvStoreOptions::compaction_threshold`, and what they
+    /// overwrite toward `compaction_dead_ratio`, once the import is over, so a load
+    /// compacts at most once, at the end, rather than every so many entries as it goes. An
+    /// entry over `KvStoreOptions::max_key_size` or `max_value_size` fails the import with
+    /// the batches before it already applied, as does any other failed write.
+    pub fn import<R: Read>(
+        &mut self,
+        reader: R,
+        format: ExportFormat,
+        mode: ImportMode,
+    ) -> Result<ImportStats> {
+        let _op = self.enter("import")?;
+        let rows = match format {
+            ExportFormat::JsonLines => JsonLines::new(reader),
+        };
+        self.load_rows(rows, mode)
+    }
+
+    /// Loads the rows of a CSV file as `import` loads entries, taking each key from column
+    /// `key_col` and its value from `value_col`, counting from 0, and skipping the first
+    /// row when `has_header`. Quoted fields may hold commas, newlines and doubled quotes.
+    /// Keys that are already there are overwritten.
+    pub fn import_csv<R: Read>(
+        &mut self,
+        reader: R,
+        key_col: usize,
+        value_col: usize,
+        has_header: bool,
+    ) -> Result<ImportStats> {
+        let _op = self.enter("import_csv")?;
+        let rows = CsvRows::new(reader, key_col, value_col, has_header);
+        self.load_rows(rows, ImportMode::Overwrite)
+    }
+
+    fn load_rows(
+        &mut self,
+        rows: impl Iterator<Item = io::Result<ImportRow>>,
+        mode: ImportMode,
+    ) -> Result<ImportStats> {
+        let mut stats = ImportStats::default();
+        let result = self.load_batches(rows, mode, &mut stats);
+        let counted = self.increment_writes(stats.imported);
+        result?;
+        counted?;
+        Ok(stats)
+    }
+
+    fn load_batches(
+        &mut self,
+        rows: impl Iterator<Item = io::Result<ImportRow>>,
+        mode: ImportMode,
+        stats: &mut ImportStats,
+    ) -> Result<()> {
+        let mut batch = Vec::new();
+        let mut batch_bytes = 0;
+        // Keys in `batch`, which the index does not have yet.
+        let mut batched = HashSet::new();
+        for row in rows {
+            let (key, value) = match row? {
+                ImportRow::Entry { key, value } => (key, value),
+                ImportRow::Malformed { line } => {
+                    stats.record_malformed(line);
+                    continue;
+                }
+            };
+            if mode == ImportMode::SkipExisting
+                && (batched.contains(&key) || self.index_contains(&key)?)
+            {
+                stats.skipped_existing += 1;
+                continue;
+            }
+            batch_bytes += key.len() + value.len();
+            if mode == ImportMode::SkipExisting {
+                batched.insert(key.clone());
+            }
+            batch.push((key, Some(value.into_bytes())));
+            if batch.len() >= IMPORT_BATCH_ENTRIES || batch_bytes >= IMPORT_BATCH_BYTES {
+                let batch = mem::take(&mut batch);
+                self.check_batch(&batch)?;
+                stats.imported += self.append_batch(batch, &HashMap::new(), false, false)? as u64;
+                batch_bytes = 0;
+                batched.clear();
+            }
+        }
+        if !batch.is_empty() {
+            self.check_batch(&batch)?;
+            stats.imported += self.append_batch(batch, &HashMap::new(), false, false)? as u64;
+        }
+        Ok(())
+    }
+
+    /// Every live key starting with `prefix`, with its value, in key order; all of them for
+    /// an empty prefix. Keys are filtered in the index and only their values read from the
+    /// log. Fails on the first value that cannot be read.
+    pub fn scan_prefix(&self, prefix: &str) -> Result<Vec<(String, String)>> {
+        let _op = self.enter("scan_prefix")?;
+        self.check_not_displaced()?;
+        let entries = self.sorted_locations(|key| key.starts_with(prefix))?;
+        self.read_entries(entries)?.collect()
+    }
+
+    /// Every live key matching the glob `pattern`, in key order: `*` matches any run of
+    /// characters and `?` any one, a backslash makes the next character literal, and the
+    /// pattern must match the whole key. Matching is against the index, so no value is
+    /// read, and takes at most pattern length times key length steps per key, however many
+    /// `*`s the pattern has.
+    pub fn keys_matching(&self, pattern: &str) -> Result<Vec<String>> {
+        let _op = self.enter("keys_matching")?;
+        self.check_not_displaced()?;
+        let pattern = Pattern::new(pattern);
+        let mut keys = self.index_keys()?;
+        keys.retain(|key| pattern.matches(key));
+        Ok(keys)
+    }
+
+    /// `keys_matching` with the value of each key, as `scan_prefix` reads them.
+    pub fn get_matching(&self, pattern: &str) -> Result<Vec<(String, String)>> {
+        let _op = self.enter("get_matching")?;
+        self.check_not_displaced()?;
+        let pattern = Pattern::new(pattern);
+        let entries = self.sorted_locations(|key| pattern.matches(key))?;
+        self.read_entries(entries)?.collect()
+    }
+
+    /// Every live key in `range`, with its value, in key order. Fails on the first value that
+    /// cannot be read. See `KvStoreOptions::ordered_index` for making this cheap when the
+    /// range is a small part of the store.
+    pub fn range<R: RangeBounds<String>>(&self, range: R) -> Result<Vec<(String, String)>> {
+        let _op = self.enter("range")?;
+        self.check_not_displaced()?;
+        let now = now_millis();
+        let mut entries = self.store.borrow_mut().range_entries(range)?;
+        entries.retain(|(key, _)| !self.expired(key, now));
+        self.read_entries(entries)?.collect()
+    }
+
+    /// Up to `limit` live keys with their values, from `start` on in key order for
+    /// `Direction::Forward` or from `start` back in reverse key order for
+    /// `Direction::Reverse`. `start` need not be a key; if it is one, it comes first. To
+    /// page, pass the last key of one page as the `start` of the next and drop it. Without
+    /// `KvStoreOptions::max_index_bytes`, the first call puts the index's keys in order, as
+    /// `KvStoreOptions::ordered_index` does, and later ones walk just the keys they return.
+    /// With it, the cold table is read from its start, and in full for a reverse walk.
+    pub fn iter_from(
+        &self,
+        start: &str,
+        direction: Direction,
+        limit: usize,
+    ) -> Result<Vec<(String, String)>> {
+        let _op = self.enter("iter_from")?;
+        self.check_not_displaced()?;
+        let now = now_millis();
+        let entries = self
+            .store
+            .borrow_mut()
+            .entries_from(start, direction, limit, |key| !self.expired(key, now))?;
+        self.read_entries(entries)?.collect()
+    }
+
+    /// The index's entries whose keys pass `filter`, in key order.
+    fn sorted_locations(
+        &self,
+        filter: impl Fn(&str) -> bool,
+    ) -> Result<Vec<(String, CommandBuffer)>> {
+        let now = now_millis();
+        let mut entries = Vec::new();
+        for entry in self.store.borrow_mut().sorted_entries()? {
+            let (key, location) = entry?;
+            if filter(&key) && !self.expired(&key, now) {
+                entries.push((key, location));
+            }
+        }
+        Ok(entries)
+    }
+
+    /// Reads the values of `entries` through one handle on the log; see `iter`.
+    fn read_entries(
+        &self,
+        entries: Vec<(String, CommandBuffer)>,
+    ) -> Result<impl Iterator<Item = Result<(String, String)>>> {
+        let files = self.open_log_files()?;
+        // Merged values are made now, as the iterator does not hold the store.
+        let mut merged: HashMap<String, Result<String>> = entries
+            .iter()
+            .filter_map(|(key, _)| {
+                let chain = self.merges.get(key)?;
+                Some((key.clone(), self.merged_value(key, chain)))
+            })
+            .collect();
+        let format = self.format;
+        Ok(entries.into_iter().map(move |(key, location)| {
+            if let Some(value) = merged.remove(&key) {
+                return value.map(|value| (key, value));
+            }
+            let buffer = files.read(location)?;
+            verify_record(format, &buffer, location.start)?;
+            let value = parse_value(format, &key, location, &buffer)?;
+            Ok((key, value))
+        }))
+    }
+
+    fn read_value(&self, key: &str, location: CommandBuffer) -> Result<String> {
+        decode_value(
+            key,
+            location.start as u64,
+            self.read_value_bytes(key, location)?,
+        )
+    }
+
+    fn read_value_bytes(&self, key: &str, location: CommandBuffer) -> Result<Vec<u8>> {
+        self.value_of_record(key, &self.read_record(location)?)
+    }
+
+    fn read_record(&self, location: CommandBuffer) -> Result<Vec<u8>> {
+        let path = self.segment_path(location.segment);
+        let (buffer, file) = self
+            .with_retry(IoSite::Read, || {
+                let mut file = OpenOptions::new().read(true).open(&path)?;
+                file.seek(SeekFrom::Start(location.start as u64))?;
+                let mut buffer = vec![0; location.size];
+                file.read_exact(&mut buffer)?;
+                Ok((buffer, file))
+            })
+            .map_err(|e| self.segment_error(location.segment, e))?;
+        self.check_segment(location.segment, &file)?;
+        verify_record(self.format, &buffer, location.start)?;
+        Ok(buffer)
+    }
+
+    /// Opens segment `id` of the log for reading; see `check_segment`.
+    fn open_segment(&self, id: u32) -> Result<File> {
+        let path = self.segment_path(id);
+        let file = self
+            .with_retry(IoSite::Read, || File::open(&path))
+            .map_err(|e| self.segment_error(id, e))?;
+        self.check_segment(id, &file)?;
+        Ok(file)
+    }
+
+    /// Opens every segment of the log for reading, as `open_segment` does each.
+    fn open_log_files(&self) -> Result<LogFiles> {
+        let mut files = Vec::with_capacity(self.sealed.len() + 1);
+        for id in self.sealed.iter().map(|sealed| sealed.id) {
+            files.push((id, self.open_segment(id)?));
+        }
+        let file = self.with_retry(IoSite::Read, || File::open(&self.log_path))?;
+        files.push((self.segment, file));
+        Ok(LogFiles(files))
+    }
+
+    /// Fails with `KvError::StoreDisplaced` when `file`, just opened as sealed segment `id`,
+    /// is not the file a read-only store replayed, as a writer compacted it since.
+    fn check_segment(&self, id: u32, file: &File) -> Result<()> {
+        let expected = match self.sealed_index(id).and_then(|n| self.sealed[n].identity) {
+            Some(identity) => identity,
+            None => return Ok(()),
+        };
+        match FileIdentity::of(&file.metadata()?) == Some(expected) {
+            true => Ok(()),
+            false => Err(KvError::StoreDisplaced(self.segment_path(id).into_owned())),
+        }
+    }
+
+    /// `e`, from opening or reading segment `id`, as a `KvError`: a sealed segment missing
+    /// from under a read-only store was removed by a writer's compaction.
+    fn segment_error(&self, id: u32, e: io::Error) -> KvError {
+        if e.kind() == io::ErrorKind::NotFound && id != self.segment && self.options.read_only {
+            return KvError::StoreDisplaced(self.segment_path(id).into_owned());
+        }
+        e.into()
+    }
+
+    /// Where segment `id` is in `sealed`.
+    fn sealed_index(&self, id: u32) -> Option<usize> {
+        self.sealed
+            .binary_search_by_key(&id, |sealed| sealed.id)
+            .ok()
+    }
+
+    /// Runs `op`, which must be safe to repeat, under `KvStoreOptions::transient_retry`.
+    fn with_retry<T>(&self, site: IoSite, mut op: impl FnMut() -> io::Result<T>) -> io::Result<T> {
+        #[cfg(not(feature = "test-util"))]
+        let _ = site;
+        fsutil::retry(
+            self.options.transient_retry.as_ref(),
+            || self.stats.record_retry(),
+            || {
+                #[cfg(feature = "test-util")]
+                self.options.transient_faults.check(site)?;
+                op()
+            },
+        )
+    }
+
+    /// Paranoid check after a write: the index must point `key` at `location` (or at
+    /// nothing after a remove), and the log must hold exactly that write there.
+    fn check_record(&self, key: &str, value: Option<&[u8]>, location: CommandBuffer) -> Result<()> {
+        let violation = |index: String, log: String| KvError::ConsistencyViolation {
+            key: key.to_owned(),
+            index,
+            log,
+        };
+        let indexed = self.store.borrow().lookup(key)?;
+        if indexed != value.map(|_| location) {
+            return Err(violation(
+                describe_location(indexed),
+                format!("the write just made at {}", location.start),
+            ));
+        }
+
+        let found = match self.read_record(location) {
+            Ok(bytes) => bytes,
+            Err(e) => return Err(violation(describe_location(Some(location)), e.to_string())),
+        };
+        let matches = match (decode_record(self.format, &found), value) {
+            (
+                Ok(LogRecord::Set {
+                    key: k, value: v, ..
+                }),
+                Some(value),
+            ) => k == key && v.0.as_ref() == value,
+            (Ok(LogRecord::Rm { key: k, .. }), None) => k == key,
+            _ => false,
+        };
+        if !matches {
+            return Err(violation(
+                describe_location(Some(location)),
+                format!(
+                    "the record {}",
+                    Truncated::new(&String::from_utf8_lossy(&found))
+                ),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Paranoid check after a compaction: indexing the new log from scratch must give
+    /// exactly the index the compaction built.
+    fn check_index_matches_log(&mut self) -> Result<()> {
+        let mut from_log = BTreeMap::new();
+        // As `KvStore::expired_on_replay`.
+        let mut expired = HashMap::new();
+        let now = now_millis();
+        let segments: Vec<(u32, usize)> = self
+            .sealed
+            .iter()
+            .map(|sealed| (sealed.id, sealed.header_len))
+            .chain([(self.segment, self.header_len)])
+            .collect();
+        for (segment, header_len) in segments {
+            let mut offset = header_len;
+            let mut reader = io::BufReader::new(File::open(self.segment_path(segment))?);
+            reader.seek(SeekFrom::Start(offset as u64))?;
+            let mut line = Vec::new();
+            while self.format.read_record(&mut reader, &mut line)? > 0 {
+                let size = line.len() - usize::from(line.last() == Some(&b'\n'));
+                let record = decode_record(self.format, &line[..size])?;
+                let location = CommandBuffer {
+                    segment,
+                    start: offset,
+                    size,
+                    value_len: record.value_len(),
+                };
+                if let LogRecord::Set { ref key, .. }
+                | LogRecord::Rm { ref key, .. }
+                | LogRecord::Merge { ref key, .. } = record
+                {
+                    expired.remove(key.as_ref());
+                }
+                match record {
+                    // Replay drops a set that has expired, which a segment not yet
+                    // rewritten may still hold, until a touch after it brings it back.
+                    LogRecord::Set {
+                        key,
+                        expires_at: Some(expires_at),
+                        ..
+                    } if expires_at <= now => {
+                        from_log.remove(key.as_ref());
+                        expired.insert(key.into_owned(), location);
+                    }
+                    LogRecord::Merge {
+                        key,
+                        expires_at: Some(expires_at),
+                        ..
+                    } if expires_at <= now => {
+                        from_log.remove(key.as_ref());
+                    }
+                    LogRecord::Set { key, .. } | LogRecord::Merge { key, .. } => {
+                        from_log.insert(key.into_owned(), location);
+                    }
+                    LogRecord::Rm { key, .. } => {
+                        from_log.remove(key.as_ref());
+                    }
+                    LogRecord::Touch { key, expires_at } if expires_at <= now => {
+                        if let Some(set) = from_log.remove(key.as_ref()) {
+                            expired.insert(key.into_owned(), set);
+                        }
+                    }
+                    LogRecord::Touch { key, .. } => {
+                        if !from_log.contains_key(key.as_ref()) {
+                            if let Some(set) = expired.remove(key.as_ref()) {
+                                from_log.insert(key.into_owned(), set);
+                            }
+                        }
+                    }
+                    LogRecord::Get {} | LogRecord::Sequence { .. } | LogRecord::Group { .. } => {}
+                }
+                offset += line.len();
+                line.clear();
+            }
+        }
+
+        let mut from_index = self
+            .store
+            .get_mut()
+            .sorted_entries()?
+            .collect::<Result<BTreeMap<_, _>>>()?;
+        from_index.retain(|key, _| !self.expired(key, now));
+        if from_index == from_log {
+            return Ok(());
+        }
+
+        // Report the first key, in order, that the two disagree about.
+        let key = from_index
+            .keys()
+            .chain(from_log.keys())
+            .filter(|key| from_index.get(*key) != from_log.get(*key))
+            .min()
+            .cloned()
+            .unwrap_or_default();
+        Err(KvError::ConsistencyViolation {
+            index: describe_location(from_index.get(&key).copied()),
+            log: describe_location(from_log.get(&key).copied()),
+            key,
+        })
+    }
+
+    /// Fails when the log at our path is no longer the file we have open, i.e. the data
+    /// directory was moved or restored underneath us.
+    fn check_not_displaced(&self) -> Result<()> {
+        let expected = match self.log_identity {
+            Some(identity) => identity,
+            None => return Ok(()),
+        };
+        match fs::metadata(&self.log_path) {
+            Ok(metadata) if FileIdentity::of(&metadata) == Some(expected) => Ok(()),
+            Ok(_) => Err(KvError::StoreDisplaced(self.log_path.clone())),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                Err(KvError::StoreDisplaced(self.log_path.clone()))
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// The path of segment `id` of the log, where 0 is the log kept in one file.
+    fn segment_path(&self, id: u32) -> Cow<'_, Path> {
+        match id == self.segment {
+            true => Cow::Borrowed(&self.log_path),
+            false => Cow::Owned(self.options.segment_file(&self.path, id)),
+        }
+    }
+
+    /// The length of the log, all its segments together.
+    fn log_bytes(&self) -> usize {
+        self.sealed.iter().map(|sealed| sealed.len).sum::<usize>() + self.log_size
+    }
+
+    /// What of `log_bytes` is headers rather than records.
+    fn header_bytes(&self) -> usize {
+        self.sealed
+            .iter()
+            .map(|sealed| sealed.header_len)
+            .sum::<usize>()
+            + self.header_len
+    }
+
+    /// The data directory this store was opened from.
+    pub fn directory(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn open_report(&self) -> &OpenReport {
+        &self.open_report
+    }
+
+    /// Operation counters since the open and, with `KvStoreOptions::persist_stats`, over
+    /// the store's lifetime.
+    /// Whether the background warm-up of `KvStoreOptions::defer_warm_up` has finished.
+    /// Always true for a store opened without it. A store that is not ready yet serves
+    /// every request correctly, only the first ones may be slower.
+    pub fn ready(&self) -> bool {
+        self.warm_up.is_finished()
+    }
+
+    /// Waits up to `timeout` for the store to be `ready`, returning whether it is.
+    pub fn wait_ready(&self, timeout: Duration) -> bool {
+        self.warm_up.wait(timeout)
+    }
+
+    /// Operation counters, plus the size of the store and its log as of now. The writes
+    /// since the open are `since_open.sets` and `since_open.removes`.
+    pub fn stats(&self) -> StoreStats {
+        StoreStats {
+            live_keys: self.len() as u64,
+            log_bytes: self.log_bytes() as u64,
+            dead_bytes: self.dead_bytes() as u64,
+            ..self.stats.view()
+        }
+    }
+
+    /// The sealed segments of a segmented log, oldest first, as the merge policy sees them
+    /// at the next compaction; empty for a log kept in one file. The segment appended to
+    /// is left out, as compaction leaves it alone.
+    pub fn segment_stats(&self) -> Vec<SegmentStats> {
+        let now = SystemTime::now();
+        self.sealed
+            .iter()
+            .enumerate()
+            .map(|(n, sealed)| {
+                let records = sealed.len.saturating_sub(sealed.header_len);
+                let live = (self.log_stats.live_in(sealed.id) + sealed.kept).min(records);
+                SegmentStats {
+                    id: sealed.id,
+                    bytes: sealed.len as u64,
+                    live_bytes: live as u64,
+                    live_ratio: 1.0 - self.dead_ratio(n),
+                    age: now.duration_since(sealed.written).unwrap_or_default(),
+                }
+            })
+            .collect()
+    }
+
+    /// Usage per key prefix since the open or the last `reset_accounting`, in prefix order
+    /// with `accounting::OVERFLOW_PREFIX` last. Empty unless
+    /// `KvStoreOptions::accounting_prefix_depth` is set.
+    pub fn accounting_snapshot(&self) -> Vec<(String, PrefixUsage)> {
+        match self.accounting {
+            Some(ref accounting) => accounting.borrow().snapshot(),
+            None => Vec::new(),
+        }
+    }
+
+    pub fn reset_accounting(&mut self) {
+        if let Some(ref mut accounting) = self.accounting {
+            accounting.get_mut().reset();
+        }
+    }
+
+    fn account(&self, record: impl FnOnce(&mut Accounting)) {
+        if let Some(ref accounting) = self.accounting {
+            record(&mut accounting.borrow_mut());
+        }
+    }
+
+    /// Best effort: stats are advisory, so failing to save them is only a warning.
+    fn save_stats(&self) {
+        if self.options.read_only {
+            return;
+        }
+        if let Err(e) = self.stats.save() {
+            self.options.events.emit(StoreEvent::StatsSnapshotNotSaved {
+                error: e.to_string(),
+            });
+        }
+    }
+
+    /// Which index tier lookups were answered from since the store was opened.
+    pub fn index_stats(&self) -> IndexStats {
+        self.store.borrow().stats()
+    }
+
+    /// Under `SyncPolicy::Always` a write is only acknowledged once durable, which a store
+    /// that lost durability cannot promise.
+    fn check_durable(&self) -> Result<()> {
+        match self.durability_lost {
+            Some(ref original_error) if self.sync_policy == SyncPolicy::Always => {
+                Err(KvError::DurabilityLost {
+                    original_error: original_error.clone(),
+                })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Called right after an append. If the fsync fails the record is in the log but not
+    /// in the index, so appends are poisoned too until compaction or `recover_append`
+    /// drops it.
+    fn sync_if_required(&mut self) -> Result<()> {
+        match self.sync_policy {
+            SyncPolicy::Always => self.sync_appended(),
+            SyncPolicy::Never => Ok(()),
+        }
+    }
+
+    /// Fsyncs what was just appended, poisoning the log if that fails, as the append is
+    /// then in the file but not known to be on disk.
+    fn sync_appended(&mut self) -> Result<()> {
+        if let Err(e) = self.sync_log() {
+            self.append_poisoned = true;
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    fn sync_log(&mut self) -> Result<()> {
+        if self.durability_lost.is_none() {
+            if let Err(e) = self.append_handle.sync() {
+                self.durability_lost = Some(e.to_string());
+            }
+        }
+        match self.durability_lost {
+            Some(ref original_error) => Err(KvError::DurabilityLost {
+                original_error: original_error.clone(),
+            }),
+            None => Ok(()),
+        }
+    }
+
+    /// Every key in the store, in ascending order, leaving out expired ones.
+    pub(crate) fn index_keys(&self) -> Result<Vec<String>> {
+        let now = now_millis();
+        let mut keys = Vec::new();
+        for entry in self.store.borrow_mut().sorted_entries()? {
+            let (key, _) = entry?;
+            if !self.expired(&key, now) {
+                keys.push(key);
+            }
+        }
+        Ok(keys)
+    }
+
+    pub(crate) fn index_contains(&self, key: &str) -> Result<bool> {
+        Ok(self.live_location(key)?.is_some())
+    }
+
+    /// Where the index points `key`, or `None` when it has expired.
+    fn live_location(&self, key: &str) -> Result<Option<CommandBuffer>> {
+        let location = self.store.borrow_mut().get(key)?;
+        Ok(location.filter(|_| !self.expired(key, now_millis())))
+    }
+
+    /// Whether `key` was set with a TTL that had run out by `now`.
+    fn expired(&self, key: &str, now: u64) -> bool {
+        matches!(self.expiries.get(key), Some(&expires_at) if expires_at <= now)
+    }
+
+    pub fn read_log_file(&mut self) -> Result<()> {
+        for n in 0..self.sealed.len() {
+            self.replay_sealed(n, 0, None)?;
+        }
+        let file = self.with_retry(IoSite::Replay, || File::open(&self.log_path))?;
+        let len = file.metadata()?.len();
+        // A read-only store may be opened while a writer is part way through an append.
+        self.replay(file, 0, self.options.read_only, None)?;
+        if self.options.read_only {
+            return Ok(());
+        }
+        // A writable store, as the only writer, never replays again.
+        self.expired_on_replay = HashMap::new();
+        if (self.log_size as u64) < len {
+            // What is left is what a crash part way through an append leaves behind.
+            self.open_report.torn_tail = Some(TornTail {
+                offset: self.log_size as u64,
+                bytes: len - self.log_size as u64,
+            });
+            self.append_poisoned = true;
+            self.recover_append()?;
+        } else if len > 0 && !ends_with_newline(&self.log_path, len)? {
+            // Or the last record is whole but its newline is missing, which the next append
+            // must not run on from.
+            self.append_record(b"\n")?;
+            self.log_size += 1;
+        }
+        Ok(())
+    }
+
+    /// Applies the records of `file` from `offset` on, leaving `log_size` at the end of the
+    /// last one, and returns how many changed the index. With `complete_only` a trailing
+    /// record without its newline is left for later, as a writer may still be appending
+    /// it. A `Command::Group` the log ends part way through is left for later too, so
+    /// `log_size` stops at its start. The keys applied are pushed to `changed` when given.
+    ///
+    /// Only the last record can have been torn by a crash, so one that is not even JSON
+    /// there ends the replay short of it, even after a newline, while one anywhere else
+    /// fails with `KvError::Corruption`. JSON that is no record the store knows is never
+    /// taken for torn, so a record from a newer version is not cut away.
+    fn replay(
+        &mut self,
+        file: File,
+        offset: usize,
+        complete_only: bool,
+        mut changed: Option<&mut Vec<String>>,
+    ) -> Result<u64> {
+        let format = self.format;
+        let offset = offset.max(self.header_len);
+        let mut current_offset = offset;
+        let mut reader = io::BufReader::new(file);
+        reader.seek(SeekFrom::Start(offset as u64))?;
+        let mut line = Vec::new();
+        let mut applied = 0;
+        let mut note = |key: Option<String>| {
+            if let Some(key) = key {
+                applied += 1;
+                if let Some(ref mut changed) = changed {
+                    changed.push(key);
+                }
+            }
+        };
+        let mut group: Option<PendingGroup> = None;
+
+        loop {
+            line.clear();
+            // A read that fails part way keeps what it got in `line`, so a retry carries on.
+            let read = self.with_retry(IoSite::Replay, || {
+                format.read_record(&mut reader, &mut line)
+            })?;
+            if read == 0 {
+                break;
+            }
+            let torn = if line.last() == Some(&b'\n') {
+                line.pop();
+                reader.fill_buf()?.is_empty() && is_torn(format, &line)
+            } else {
+                // A last record without its newline is kept only if it is whole, as one
+                // written by another tool may be.
+                complete_only || cut_short(format, &line)
+            };
+            if torn {
+                break;
+            }
+            let offset = current_offset;
+            current_offset += read;
+            if let Some(ref mut pending) = group {
+                pending.lines.push((line.clone(), offset));
+                if pending.lines.len() == pending.records {
+                    let pending = group.take().expect("a group is being read");
+                    for (line, offset) in pending.lines {
+                        note(self.apply_record(&line, offset)?);
+                    }
+                }
+                continue;
+            }
+            match parse_record(format, &line, offset)? {
+                LogRecord::Group { records: 0 } => {}
+                LogRecord::Group { records } => {
+                    group = Some(PendingGroup {
+                        start: offset,
+                        records,
+                        lines: Vec::new(),
+                    })
+                }
+                command => note(self.apply_command(command, line.len(), offset)?),
+            }
+        }
+
+        self.log_size = match group {
+            Some(pending) => pending.start,
+            None => current_offset,
+        };
+        Ok(applied)
+    }
+
+    /// `replay` of sealed segment `n` from `from` on. A sealed segment holds whole records
+    /// and groups only, so one that does not replay to its end is corrupt.
+    fn replay_sealed(
+        &mut self,
+        n: usize,
+        from: usize,
+        changed: Option<&mut Vec<String>>,
+    ) -> Result<u64> {
+        let Segment { id, header_len, .. } = self.sealed[n];
+        let path = self.segment_path(id);
+        let file = self.with_retry(IoSite::Replay, || File::open(&path))?;
+        let metadata = file.metadata()?;
+        // `replay` works on the segment appended to, so this one stands in for it meanwhile.
+        let active = (self.segment, self.header_len, self.log_size);
+        self.segment = id;
+        self.header_len = header_len;
+        let applied = self.replay(file, from, false, changed);
+        let end = mem::replace(&mut self.log_size, active.2);
+        self.segment = active.0;
+        self.header_len = active.1;
+        let applied = applied?;
+        if (end as u64) < metadata.len() {
+            return Err(KvError::Corruption {
+                offset: end as u64,
+                details: format!("segment {} of the log ends part way through a record", id),
+            });
+        }
+        let identity = match self.options.read_only {
+            true => FileIdentity::of(&metadata),
+            false => None,
+        };
+        let sealed = &mut self.sealed[n];
+        sealed.len = end;
+        sealed.last_seq = self.sequence;
+        sealed.identity = identity;
+        sealed.written = metadata.modified().unwrap_or(sealed.written);
+        Ok(applied)
+    }
+
+    pub fn read_line_into_store(&mut self, line: &[u8], starting_offset: usize) -> Result<()> {
+        self.apply_record(line, starting_offset).map(|_| ())
+    }
+
+    /// The key a record changed, `None` for records that change nothing.
+    fn apply_record(&mut self, line: &[u8], starting_offset: usize) -> Result<Option<String>> {
+        let command = parse_record(self.format, line, starting_offset)?;
+        self.apply_command(command, line.len(), starting_offset)
+    }
+
+    /// `apply_record` for a record already decoded from the `size` bytes at
+    /// `starting_offset`.
+    fn apply_command(
+        &mut self,
+        command: LogRecord,
+        size: usize,
+        starting_offset: usize,
+    ) -> Result<Option<String>> {
+        let command_buffer: CommandBuffer = CommandBuffer {
+            segment: self.segment,
+            start: starting_offset,
+            size,
+            value_len: command.value_len(),
+        };
+        if let Some(seq) = command.seq() {
+            self.sequence = self.sequence.max(seq);
+        }
+
+        // Anything else written to a key leaves no expired set of it for a touch to bring
+        // back.
+        if let LogRecord::Set { ref key, .. }
+        | LogRecord::Rm { ref key, .. }
+        | LogRecord::Merge { ref key, .. } = command
+        {
+            self.expired_on_replay.remove(key.as_ref());
+        }
+
+        match command {
+            LogRecord::Rm { key, .. } => {
+                self.replay_remove(&key)?;
+                Ok(Some(key.into_owned()))
+            }
+            // Already expired, so it goes the way of a remove, unless a touch after it
+            // extended its deadline.
+            LogRecord::Set {
+                key,
+                expires_at: Some(expires_at),
+                ..
+            } if expires_at <= now_millis() => {
+                self.replay_remove(&key)?;
+                self.expired_on_replay
+                    .insert(key.clone().into_owned(), command_buffer);
+                Ok(Some(key.into_owned()))
+            }
+            LogRecord::Merge {
+                key,
+                expires_at: Some(expires_at),
+                ..
+            } if expires_at <= now_millis() => {
+                self.replay_remove(&key)?;
+                Ok(Some(key.into_owned()))
+            }
+            LogRecord::Merge {
+                key, expires_at, ..
+            } => {
+                let key = key.into_owned();
+                let previous = self.store.get_mut().insert(key.clone(), command_buffer)?;
+                self.log_stats.record_set(None, command_buffer);
+                self.push_merge(&key, previous, command_buffer);
+                match expires_at {
+                    Some(expires_at) => self.expiries.insert(key.clone(), expires_at),
+                    None => self.expiries.remove(&key),
+                };
+                Ok(Some(key))
+            }
+            LogRecord::Set {
+                key, expires_at, ..
+            } => {
+                let key = key.into_owned();
+                let previous = self.store.get_mut().insert(key.clone(), command_buffer)?;
+                self.log_stats.record_set(previous, command_buffer);
+                self.forget_history(&key);
+                match expires_at {
+                    Some(expires_at) => self.expiries.insert(key.clone(), expires_at),
+                    None => self.expiries.remove(&key),
+                };
+                Ok(Some(key))
+            }
+            LogRecord::Get {} => {
+                self.open_report.skipped_records += 1;
+                Ok(None)
+            }
+            LogRecord::Sequence { .. } => {
+                self.log_stats.last_write_dropped = true;
+                Ok(None)
+            }
+            // Only met outside `replay`, which reads the records after it as one.
+            LogRecord::Group { .. } => Ok(None),
+            LogRecord::Touch { key, expires_at } => {
+                self.replay_touch(key.into_owned(), expires_at, command_buffer)
+            }
+        }
+    }
+
+    /// `apply_command` of a remove of `key`, or of a write of it that has expired.
+    fn replay_remove(&mut self, key: &str) -> Result<()> {
+        let previous = self.store.get_mut().remove(key)?;
+        self.log_stats.record_rm(previous);
+        self.expiries.remove(key);
+        self.forget_history(key);
+        Ok(())
+    }
+
+    /// `apply_command` of the touch at `location` giving `key` the deadline `expires_at`.
+    /// The key's set takes the deadline, or when replay dropped that set as expired, which
+    /// it was not when touched, comes back with it. Returns the key when the touch makes it
+    /// appear or disappear.
+    fn replay_touch(
+        &mut self,
+        key: String,
+        expires_at: u64,
+        location: CommandBuffer,
+    ) -> Result<Option<String>> {
+        let expired = expires_at <= now_millis();
+        let live = self.store.get_mut().lookup(&key)?;
+        let dropped = match live {
+            None if !expired => self.expired_on_replay.remove(&key),
+            _ => None,
+        };
+        let revived = match (live, dropped) {
+            (Some(set), _) if expired => {
+                // Merges read their base from the index, so a chain cannot come back.
+                let merged = self.merges.contains_key(&key);
+                self.replay_remove(&key)?;
+                if !merged {
+                    self.expired_on_replay.insert(key.clone(), set);
+                }
+                self.log_stats.stale_records += 1;
+                return Ok(Some(key));
+            }
+            (Some(_), _) => false,
+            (None, Some(set)) => {
+                self.store.get_mut().insert(key.clone(), set)?;
+                self.log_stats.record_set(None, set);
+                // The set counted as a remove when it was dropped.
+                self.log_stats.tombstone_records =
+                    self.log_stats.tombstone_records.saturating_sub(1);
+                true
+            }
+            // A touch of a key removed or written again since.
+            (None, None) => {
+                self.log_stats.stale_records += 1;
+                return Ok(None);
+            }
+        };
+        let previous = self.touches.insert(key.clone(), location);
+        self.log_stats.record_touch(previous, location);
+        self.expiries.insert(key.clone(), expires_at);
+        Ok(revived.then_some(key))
+    }
+
+    /// Catches a read-only store up with a writer in another process: records appended
+    /// since the last refresh (or open) are replayed into the index, and if the log was
+    /// replaced, by the writer compacting it, the index is rebuilt from the new log. Until
+    /// then, gets on a store whose log was replaced fail with `KvError::StoreDisplaced`.
+    /// A record the writer is still appending is picked up by the next refresh.
+    ///
+    /// On a writable store, which is the only writer, there is never anything to pick up.
+    pub fn refresh(&mut self) -> Result<RefreshStats> {
+        let _op = self.enter("refresh")?;
+        let mut stats = RefreshStats::default();
+        if !self.options.read_only {
+            return Ok(stats);
+        }
+        let ids = segment::list(&self.path, &self.options.segment_prefix())?;
+        if self.layout_replaced(&ids)? {
+            stats.reloaded = true;
+            stats.records_applied = self.reload(&ids)?;
+            return Ok(stats);
+        }
+        // A log empty at open is in whatever format the writer made it.
+        if self.log_size == 0 {
+            if let Some((format, header_len)) = read_layout(&self.log_path)? {
+                self.format = format;
+                self.header_len = header_len;
+            }
+        }
+        let newer: Vec<u32> = ids.into_iter().filter(|&id| id > self.segment).collect();
+        let last = match newer.last() {
+            Some(&last) => last,
+            None => {
+                let file = File::open(&self.log_path)?;
+                stats.records_applied =
+                    self.replay(file, self.log_size, true, Some(&mut stats.changed_keys))?;
+                return Ok(stats);
+            }
+        };
+
+        // The writer has rolled over since, so the segment replayed last is sealed now, as
+        // are the new ones before the last.
+        let resume = self.sealed.len();
+        let from = self.log_size;
+        self.sealed.push(Segment {
+            id: self.segment,
+            len: 0,
+            header_len: self.header_len,
+            last_seq: 0,
+            kept: 0,
+            identity: None,
+            written: SystemTime::now(),
+        });
+        for &id in &newer[..newer.len() - 1] {
+            let header_len = read_layout(&self.segment_path(id))?.map_or(0, |(_, len)| len);
+            self.sealed.push(Segment {
+                id,
+                len: 0,
+                header_len,
+                last_seq: 0,
+                kept: 0,
+                identity: None,
+                written: SystemTime::now(),
+            });
+        }
+        self.segment = last;
+        self.log_path = self.options.segment_file(&self.path, last);
+        self.header_len = read_layout(&self.log_path)?.map_or(0, |(_, len)| len);
+        self.log_size = 0;
+        let file = File::open(&self.log_path)?;
+        self.log_identity = FileIdentity::of(&file.metadata()?);
+        self.append_handle = LogAppender::new(file.try_clone()?);
+
+        let changed = &mut stats.changed_keys;
+        stats.records_applied = self.replay_sealed(resume, from, Some(changed))?;
+        for n in resume + 1..self.sealed.len() {
+            stats.records_applied += self.replay_sealed(n, 0, Some(changed))?;
+        }
+        stats.records_applied += self.replay(file, 0, true, Some(changed))?;
+        Ok(stats)
+    }
+
+    /// Whether `refresh` may have something to do, from the lengths and identities of the
+    /// log's files alone. Cheap enough to poll.
+    pub fn changed_since_last_refresh(&self) -> Result<bool> {
+        let ids = segment::list(&self.path, &self.options.segment_prefix())?;
+        if self.layout_replaced(&ids)? || ids.last().is_some_and(|&id| id > self.segment) {
+            return Ok(true);
+        }
+        Ok(fs::metadata(&self.log_path)?.len() != self.log_size as u64)
+    }
+
+    /// The directory's shared reader lock, held until dropped, as `KvStoreOptions::shared_lock`
+    /// holds it for the whole life of a store. A long-lived read-only store takes it around
+    /// each `refresh` and the gets that follow, so the writer does not replace the log in
+    /// between, yet puts compaction off only for as long as one of them takes.
+    pub fn reader_lock(&self) -> Result<ReaderLock> {
+        Ok(ReaderLock::acquire(
+            &self.path,
+            &self.options.file_prefix(),
+        )?)
+    }
+
+    /// Whether a writer replaced or removed any of the log replayed so far, or moved it
+    /// from one file into segments, so that only a reload catches up with it. `ids` are
+    /// the segments in the directory now.
+    fn layout_replaced(&self, ids: &[u32]) -> Result<bool> {
+        if self.segment == 0 {
+            return Ok(!ids.is_empty() || self.log_replaced(&fs::metadata(&self.log_path)?));
+        }
+        let known = self
+            .sealed
+            .iter()
+            .map(|sealed| sealed.id)
+            .chain([self.segment]);
+        if !known.eq(ids.iter().copied().take(self.sealed.len() + 1)) {
+            return Ok(true);
+        }
+        for sealed in &self.sealed {
+            let metadata = match fs::metadata(self.segment_path(sealed.id)) {
+                Ok(metadata) => metadata,
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(true),
+                Err(e) => return Err(e.into()),
+            };
+            let identity = FileIdentity::of(&metadata);
+            if metadata.len() != sealed.len as u64
+                || sealed.identity.is_some_and(|i| Some(i) != identity)
+            {
+                return Ok(true);
+            }
+        }
+        match fs::metadata(&self.log_path) {
+            Ok(metadata) => Ok(self.log_replaced(&metadata)),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(true),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Rebuilds the index of a read-only store from the log as the directory holds it now,
+    /// the segments `ids` or the one file, returning how many records replayed changed it.
+    fn reload(&mut self, ids: &[u32]) -> Result<u64> {
+        let (segment, sealed_ids) = match ids.split_last() {
+            Some((&last, earlier)) => (last, earlier),
+            None => (0, &[][..]),
+        };
+        let (sealed, format) = read_sealed(&self.path, &self.options, sealed_ids)?;
+        self.segment = segment;
+        self.sealed = sealed;
+        self.log_path = self.options.segment_file(&self.path, segment);
+        // One compaction replaced may have gained a header.
+        let layout = read_layout(&self.log_path)?;
+        if let Some(format) = layout.map(|(format, _)| format).or(format) {
+            self.format = format;
+        }
+        self.header_len = layout.map_or(0, |(_, header_len)| header_len);
+        // Read the identity off the handle we replay, so a compaction racing this one is
+        // noticed by the next refresh.
+        let file = File::open(&self.log_path)?;
+        self.log_identity = FileIdentity::of(&file.metadata()?);
+        self.append_handle = LogAppender::new(file.try_clone()?);
+        *self.store.get_mut() = Index::open(&self.path, None, self.options.ordered_index)?;
+        self.log_stats = LogStats::default();
+        self.expiries.clear();
+        self.merges.clear();
+        self.touches.clear();
+        self.expired_on_replay.clear();
+        let mut applied = 0;
+        for n in 0..self.sealed.len() {
+            applied += self.replay_sealed(n, 0, None)?;
+        }
+        Ok(applied + self.replay(file, 0, true, None)?)
+    }
+
+    /// Whether the log at our path is not the one replayed up to `log_size`.
+    fn log_replaced(&self, metadata: &fs::Metadata) -> bool {
+        let identity = FileIdentity::of(metadata);
+        metadata.len() < self.log_size as u64
+            || matches!(self.log_identity, Some(replayed) if identity != Some(replayed))
+    }
+
+    /// The time to stamp a write with, see `Command::Set`.
+    fn write_time(&self) -> u64 {
+        #[cfg(feature = "test-util")]
+        if let Some(millis) = self.options.write_time {
+            return millis;
+        }
+        now_millis()
+    }
+
+    fn increment_writes(&mut self, writes: u64) -> Result<()> {
+        let before = self.number_of_writes;
+        self.number_of_writes += writes;
+
+        if self.options.no_auto_compaction {
+            return Ok(());
+        }
+        let counted = match self.options.compaction_threshold {
+            Some(threshold) if threshold > 0 => {
+                self.number_of_writes / threshold > before / threshold
+            }
+            _ => false,
+        };
+        if counted || self.dead_past_limit() {
+            match self.compact_log() {
+                // Put off while readers hold the log; the next write tries again.
+                Err(KvError::ReadersActive(_)) => {}
+                result => result?,
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Bytes of the log in records compaction would drop; see `StoreStats::dead_bytes`.
+    fn dead_bytes(&self) -> usize {
+        self.log_bytes()
+            .saturating_sub(self.header_bytes() + self.log_stats.live_bytes)
+    }
+
+    /// Whether enough of the log died since the last compaction for a write to compact it,
+    /// by `KvStoreOptions::compaction_dead_ratio` or `compaction_dead_bytes`.
+    fn dead_past_limit(&self) -> bool {
+        let dead = self.dead_bytes().saturating_sub(self.dead_after_compaction) as u64;
+        let ratio = self
+            .options
+            .compaction_dead_ratio
+            .unwrap_or(DEFAULT_COMPACTION_DEAD_RATIO);
+        let limit = self
+            .options
+            .compaction_dead_bytes
+            .unwrap_or(DEFAULT_COMPACTION_DEAD_BYTES);
+        dead >= limit
+            || (dead >= MIN_COMPACTION_DEAD_BYTES && dead as f64 > ratio * self.log_bytes() as f64)
+    }
+
+    pub(crate) fn compact_log(&mut self) -> Result<()> {
+        self.check_writable()?;
+        // A reader would hold up the rename at the end, so there is no rewriting for it.
+        if dir_lock::readers_present(&self.path, &self.options.file_prefix())? {
+            return Err(KvError::ReadersActive(self.path.clone()));
+        }
+        let log_bytes_before = self.log_bytes() as u64;
+        self.options.events.emit(StoreEvent::CompactionStarted {
+            log_bytes: log_bytes_before,
+        });
+        self.compaction_written = 0;
+        let result = self.rewrite_log(true);
+        // Counted from here whatever the outcome, so that a compaction that fails is tried
+        // again once as much more has died rather than on every write after it; one put off
+        // for readers is tried again by the next write.
+        if !matches!(result, Err(KvError::ReadersActive(_))) {
+            self.dead_after_compaction = self.dead_bytes();
+        }
+        if result.is_ok() {
+            let log_bytes_after = self.log_bytes() as u64;
+            self.stats.record_compaction(
+                log_bytes_before.saturating_sub(log_bytes_after),
+                self.compaction_written,
+            );
+            self.options.events.emit(StoreEvent::CompactionFinished {
+                log_bytes_before,
+                log_bytes_after,
+            });
+            self.save_stats();
+        }
+        result
+    }
+
+    /// Replaces the log with one holding a set per live key, or with an empty one when
+    /// `keep` is false. A segmented log is compacted a run of segments at a time instead.
+    fn rewrite_log(&mut self, keep: bool) -> Result<()> {
+        match (self.segment, keep) {
+            (0, _) => self.replace_log(keep, None),
+            (_, true) => self.compact_segments(),
+            (_, false) => self.clear_segments(),
+        }
+    }
+
+    /// `rewrite_log` of the log kept in one file, or of the segment appended to alone.
+    /// Readers are kept out for the rename, by `readers` if the caller already has them out.
+    fn replace_log(&mut self, keep: bool, readers: Option<ReadersExcluded>) -> Result<()> {
+        let temp_log_file = fsutil::temp_path(&self.path, "compact");
+        let log_file = self.log_path.clone();
+        let result = self.write_compacted_log(&temp_log_file, &log_file, keep, readers);
+        match result {
+            // Not ours to delete.
+            Err(KvError::TempFileExists(_)) | Ok(()) => {}
+            Err(_) => {
+                let _ = fs::remove_file(&temp_log_file);
+            }
+        }
+        result
+    }
+
+    fn write_compacted_log(
+        &mut self,
+        temp_log_file: &Path,
+        log_file: &Path,
+        keep: bool,
+        readers: Option<ReadersExcluded>,
+    ) -> Result<()> {
+        let mut file = match fsutil::create_exclusive(temp_log_file) {
+            Ok(file) => file,
+            Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                return Err(KvError::TempFileExists(temp_log_file.to_path_buf()))
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut updated_store = self.store.borrow().rebuild(&self.path)?;
+        file.write_all(&self.format.header())?;
+        let mut offset_start = codec::HEADER_LEN;
+        let now = now_millis();
+        let mut expiries = HashMap::new();
+        let mut highest = 0;
+
+        // Rewrite in key order so the compacted log only depends on the store's contents,
+        // not on the HashMap's per-process iteration order.
+        let entries = self.store.get_mut().sorted_entries()?;
+        for entry in entries {
+            let (key, location) = entry?;
+            let expires_at = self.expiries.get(&key).copied();
+            if !keep || matches!(expires_at, Some(expires_at) if expires_at <= now) {
+                continue;
+            }
+            // Each key keeps the number and time of its last write, a merge's being its last
+            // operand's, so compacting does not make every key look freshly written.
+            let found = self.read_record(location)?;
+            let value = self.value_of_record(&key, &found)?;
+            let written = decode_record(self.format, &found)?;
+            let seq = written.seq();
+            highest = highest.max(seq.unwrap_or(0));
+            let mut record = encode_set(
+                self.format,
+                &key,
+                &value,
+                expires_at,
+                written.timestamp(),
+                seq,
+            )?;
+            let size = record.len();
+            record.push(b'\n');
+            file.write_all(&record)?;
+            let command_buffer = CommandBuffer {
+                segment: self.segment,
+                start: offset_start,
+                size,
+                value_len: value.len(),
+            };
+            if let Some(expires_at) = expires_at {
+                expiries.insert(key.clone(), expires_at);
+            }
+            updated_store.push(key, command_buffer)?;
+            offset_start += size + 1;
+        }
+        let live_bytes = offset_start - codec::HEADER_LEN;
+        let last_write_dropped = keep && highest < self.sequence;
+        if last_write_dropped {
+            // The last write was a remove, or a set that has since expired, so no record
+            // left carries its number.
+            let record = sequence_record(self.format, self.sequence)?;
+            file.write_all(&record)?;
+            offset_start += record.len();
+        }
+
+        // Readers holding the shared lock have gets to make against the old log.
+        let _readers = match readers {
+            Some(readers) => readers,
+            None => self.exclude_readers()?,
+        };
+        // Windows cannot replace a file that has open handles, so the appender lets go of the
+        // old log first, holding the temp file until it is reopened below.
+        self.append_handle.file = file;
+        if let Err(e) = self.with_retry(IoSite::Rename, || {
+            fsutil::atomic_rename_into_place(temp_log_file, log_file)
+        }) {
+            match OpenOptions::new().append(true).open(log_file) {
+                Ok(file) => self.append_handle.file = file,
+                // The temp file is about to be removed, so nothing may be appended to it.
+                Err(_) => self.append_poisoned = true,
+            }
+            return Err(e.into());
+        }
+
+        let previous = mem::replace(self.store.get_mut(), Index::in_memory());
+        *self.store.get_mut() = updated_store.finish(previous)?;
+        self.expiries = expiries;
+        self.merges.clear();
+        self.touches.clear();
+        self.log_size = offset_start;
+        self.header_len = codec::HEADER_LEN;
+        self.compaction_written += offset_start as u64;
+        self.log_stats = LogStats {
+            live_bytes,
+            segment_live: HashMap::from([(self.segment, live_bytes)]),
+            last_write_dropped,
+            ..LogStats::default()
+        };
+        // The fresh log holds no partial record, so compaction also clears a poisoned append.
+        self.append_handle = LogAppender::new(OpenOptions::new().append(true).open(log_file)?);
+        self.append_poisoned = false;
+        self.log_identity = FileIdentity::of(&self.append_handle.file.metadata()?);
+        if self.options.paranoid_checks {
+            self.check_index_matches_log()?;
+        }
+        Ok(())
+    }
+
+    /// Compaction of a segmented log: rewrites the runs of sealed segments
+    /// `compaction_runs` gives, oldest first. After a lost fsync every segment is rewritten
+    /// in place instead, the one appended to as well, since none is known to be on disk.
+    fn compact_segments(&mut self) -> Result<()> {
+        let every = self.durability_lost.is_some();
+        let runs = match every {
+            true => (0..self.sealed.len()).map(|n| n..n + 1).collect(),
+            false => self.compaction_runs(),
+        };
+        // Removes keep their keys' records in earlier segments dead, which those segments'
+        // rewrites drop, so once every earlier segment is rewritten the removes can go too.
+        // Taken down as ids, since the indices shift as runs are merged and removed.
+        let mut rewritten = 0;
+        let mut plan = Vec::with_capacity(runs.len());
+        for run in runs {
+            let drop_removes = run.start == rewritten;
+            if drop_removes {
+                rewritten = run.end;
+            }
+            let ids: Vec<u32> = self.sealed[run].iter().map(|sealed| sealed.id).collect();
+            plan.push((ids, drop_removes));
+        }
+        let earlier_rewritten = rewritten == self.sealed.len();
+        for (ids, drop_removes) in plan {
+            self.rewrite_segments(&ids, drop_removes)?;
+        }
+        if every {
+            self.rewrite_segments(&[self.segment], earlier_rewritten)?;
+        }
+        if self.options.paranoid_checks {
+            self.check_index_matches_log()?;
+        }
+        Ok(())
+    }
+
+    /// The runs of sealed segments, as ranges of indices into `sealed`, that compaction
+    /// rewrites: those the merge policy picks, less any that are empty, overlap an earlier
+    /// one or run past the end, with each run `can_merge` refuses split into segments
+    /// rewritten one at a time. Segments from before headers are rewritten whatever the
+    /// policy says, as a rewrite upgrades them as it would the log in one file.
+    fn compaction_runs(&self) -> Vec<Range<usize>> {
+        let segment_size = self.options.segment_size.unwrap_or(DEFAULT_SEGMENT_SIZE);
+        let segments = self.segment_stats();
+        let mut picked = self
+            .options
+            .merge_policy
+            .get()
+            .select(&segments, segment_size);
+        picked.sort_by_key(|run| run.start);
+        let headerless = |range: Range<usize>| {
+            range
+                .filter(|&n| self.sealed[n].header_len == 0)
+                .map(|n| n..n + 1)
+        };
+        let mut runs = Vec::new();
+        let mut next = 0;
+        for run in picked {
+            if run.is_empty() || run.start < next || run.end > segments.len() {
+                continue;
+            }
+            runs.extend(headerless(next..run.start));
+            next = run.end;
+            match run.len() == 1 || self.can_merge(run.clone()) {
+                true => runs.push(run),
+                false => runs.extend(run.map(|n| n..n + 1)),
+            }
+        }
+        runs.extend(headerless(next..segments.len()));
+        runs
+    }
+
+    /// Whether the sealed segments of `run` can be merged into its last. Until the rest
+    /// are removed, a crash leaves replay to read their records and then the merged
+    /// segment's copies of them, which is harmless but for merge operands: those would be
+    /// applied twice, unless the set their chain starts from is in the run to reset the
+    /// value in between.
+    fn can_merge(&self, run: Range<usize>) -> bool {
+        let segments = &self.sealed[run];
+        let within = |location: &CommandBuffer, segments: &[Segment]| {
+            segments.iter().any(|sealed| sealed.id == location.segment)
+        };
+        let earlier = &segments[..segments.len() - 1];
+        self.merges.values().all(|chain| {
+            chain.base.is_some_and(|base| within(&base, segments))
+                || !chain
+                    .operands
+                    .iter()
+                    .any(|operand| within(operand, earlier))
+        })
+    }
+
+    /// The share of the record bytes of sealed segment `n` that are dead, leaving out what
+    /// its last rewrite had to keep.
+    fn dead_ratio(&self, n: usize) -> f64 {
+        let sealed = &self.sealed[n];
+        let records = sealed.len.saturating_sub(sealed.header_len);
+        if records == 0 {
+            return 1.0;
+        }
+        let needed = self.log_stats.live_in(sealed.id) + sealed.kept;
+        records.saturating_sub(needed) as f64 / records as f64
+    }
+
+    /// Whether a segment rewrite writes the set at `location`, which the index points at,
+    /// again with the deadline of the touch of `key` after it in the same segment, so that
+    /// the touch can go.
+    fn folds_touch(&self, key: &str, location: CommandBuffer) -> Result<bool> {
+        let touched = self
+            .touches
+            .get(key)
+            .is_some_and(|touch| touch.segment == location.segment && touch.start > location.start);
+        Ok(touched
+            && !self.merges.contains_key(key)
+            && !self.expired(key, now_millis())
+            && self.store.borrow().lookup(key)? == Some(location))
+    }
+
+    /// Rewrites the run of segments `ids`, oldest first, into one under the last id with
+    /// only the records replay still needs, copied as they are: those the index, a merge
+    /// chain or a touch points at, and the removes and expired sets that keep records of
+    /// their keys in earlier segments dead, unless `drop_removes` says no earlier segment
+    /// has such records left. The rest of the run is removed once the merged segment is in
+    /// place, oldest first, and a run of sealed segments left with no record is removed
+    /// whole. Only a run of one may be the segment appended to.
+    fn rewrite_segments(&mut self, ids: &[u32], drop_removes: bool) -> Result<()> {
+        let temp = fsutil::temp_path(&self.path, "compact");
+        let result = self.write_rewritten_segments(ids, &temp, drop_removes);
+        match result {
+            // Not ours to delete.
+            Err(KvError::TempFileExists(_)) | Ok(()) => {}
+            Err(_) => {
+                let _ = fs::remove_file(&temp);
+            }
+        }
+        result
+    }
+
+    fn write_rewritten_segments(
+        &mut self,
+        ids: &[u32],
+        temp: &Path,
+        drop_removes: bool,
+    ) -> Result<()> {
+        let (&id, earlier) = match ids.split_last() {
+            Some(split) => split,
+            None => return Ok(()),
+        };
+        let sealed = self.sealed_index(id);
+        let last_seq = match sealed {
+            Some(n) => self.sealed[n].last_seq,
+            None => self.sequence,
+        };
+        let path = self.segment_path(id).into_owned();
+        let file = match fsutil::create_exclusive(temp) {
+            Ok(file) => file,
+            Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                return Err(KvError::TempFileExists(temp.to_path_buf()))
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let mut out = io::BufWriter::new(file);
+        out.write_all(&self.format.header())?;
+
+        let mut written = codec::HEADER_LEN;
+        let mut kept = 0;
+        let mut highest = 0;
+        let (mut dropped_stale, mut dropped_removes) = (0, 0);
+        // Where the kept records the index, a chain or a touch points at move to, the keys
+        // to drop that expired and those whose touch went into their set, all applied once
+        // the new segment is in place.
+        let mut moved = Vec::new();
+        let mut expired = Vec::new();
+        let mut folded = HashSet::new();
+        let now = now_millis();
+        let mut line = Vec::new();
+        for &from in ids {
+            let (header_len, len) = match self.sealed_index(from) {
+                Some(n) => (self.sealed[n].header_len, self.sealed[n].len),
+                None => (self.header_len, self.log_size),
+            };
+            let mut reader = io::BufReader::new(File::open(self.segment_path(from))?);
+            reader.seek(SeekFrom::Start(header_len as u64))?;
+            let mut offset = header_len;
+            while offset < len {
+                line.clear();
+                let read = self.format.read_record(&mut reader, &mut line)?;
+                if read == 0 {
+                    break;
+                }
+                let size = read - usize::from(line.last() == Some(&b'\n'));
+                let record = &line[..size];
+                verify_record(self.format, record, offset)?;
+                let decoded = decode_record(self.format, record)?;
+                let location = CommandBuffer {
+                    segment: from,
+                    start: offset,
+                    size,
+                    value_len: decoded.value_len(),
+                };
+                offset += read;
+                // Kept as they are, but for records from before CRCs, which are sealed now,
+                // and sets whose touch is later in the same segment, which take its deadline
+                // instead.
+                let record = match decoded {
+                    LogRecord::Set {
+                        ref key,
+                        ref value,
+                        timestamp,
+                        seq,
+                        ..
+                    } if self.folds_touch(key, location)? => {
+                        folded.insert(key.clone().into_owned());
+                        let expires_at = self.expiries.get(key.as_ref()).copied();
+                        Cow::Owned(encode_set(
+                            self.format,
+                            key,
+                            &value.0,
+                            expires_at,
+                            timestamp,
+                            seq,
+                        )?)
+                    }
+                    _ if self.format == LogFormat::Json && crc::check(record) == Seal::Unsealed => {
+                        Cow::Owned(crc::seal(record.to_vec()))
+                    }
+                    _ => Cow::Borrowed(record),
+                };
+                let to = CommandBuffer {
+                    segment: id,
+                    start: written,
+                    size: record.len(),
+                    ..location
+                };
+                let keep = match decoded {
+                    LogRecord::Set { ref key, .. } | LogRecord::Merge { ref key, .. } => {
+                        let live = self.store.borrow().lookup(key)? == Some(location)
+                            || self
+                                .merges
+                                .get(key.as_ref())
+                                .is_some_and(|chain| chain.holds(location));
+                        if !live || (drop_removes && self.expired(key, now)) {
+                            if live {
+                                expired.push(key.clone().into_owned());
+                            }
+                            dropped_stale += 1;
+                            false
+                        } else {
+                            moved.push((key.clone().into_owned(), location, to));
+                            true
+                        }
+                    }
+                    // A remove of a key set again since, other than by merges that would
+                    // start from an earlier set if the remove were gone, keeps nothing dead.
+                    LogRecord::Rm { ref key, .. } => {
+                        let set_since = self.store.borrow().lookup(key)?.is_some()
+                            && !self.merges.contains_key(key.as_ref());
+                        if drop_removes || set_since {
+                            dropped_removes += 1;
+                            false
+                        } else {
+                            kept += record.len() + 1;
+                            true
+                        }
+                    }
+                    LogRecord::Touch { ref key, .. } => {
+                        let live = self.touches.get(key.as_ref()) == Some(&location);
+                        if live && !folded.contains(key.as_ref()) {
+                            moved.push((key.clone().into_owned(), location, to));
+                            true
+                        } else {
+                            dropped_stale += 1;
+                            false
+                        }
+                    }
+                    // Every group in a segment is whole, so its records need no header; what
+                    // a sequence record carries is written again below if it is still
+                    // needed.
+                    LogRecord::Get {} | LogRecord::Sequence { .. } | LogRecord::Group { .. } => {
+                        false
+                    }
+                };
+                if keep {
+                    highest = highest.max(decoded.seq().unwrap_or(0));
+                    out.write_all(&record)?;
+                    out.write_all(b"\n")?;
+                    written += record.len() + 1;
+                }
+            }
+        }
+        if last_seq == self.sequence && highest < self.sequence {
+            // No record kept carries the number of the last write.
+            let record = sequence_record(self.format, self.sequence)?;
+            out.write_all(&record)?;
+            written += record.len();
+            kept += record.len();
+        }
+        let file = out.into_inner().map_err(io::IntoInnerError::into_error)?;
+
+        // Readers holding the shared lock have gets to make against the old segments.
+        let _readers = self.exclude_readers()?;
+        match sealed {
+            Some(_) if written == codec::HEADER_LEN => {
+                drop(file);
+                fs::remove_file(temp)?;
+                for &gone in ids {
+                    fs::remove_file(self.segment_path(gone))?;
+                    self.sealed.retain(|sealed| sealed.id != gone);
+                    self.log_stats.segment_live.remove(&gone);
+                }
+                fsutil::sync_dir(&self.path)?;
+            }
+            Some(n) => {
+                drop(file);
+                self.with_retry(IoSite::Rename, || {
+                    fsutil::atomic_rename_into_place(temp, &path)
+                })?;
+                let sealed = &mut self.sealed[n];
+                sealed.len = written;
+                sealed.header_len = codec::HEADER_LEN;
+                sealed.kept = kept;
+                sealed.written = SystemTime::now();
+            }
+            None => {
+                // As in `write_compacted_log`, for Windows.
+                self.append_handle.file = file;
+                if let Err(e) = self.with_retry(IoSite::Rename, || {
+                    fsutil::atomic_rename_into_place(temp, &path)
+                }) {
+                    match OpenOptions::new().append(true).open(&path) {
+                        Ok(file) => self.append_handle.file = file,
+                        Err(_) => self.append_poisoned = true,
+                    }
+                    return Err(e.into());
+                }
+                self.append_handle = LogAppender::new(OpenOptions::new().append(true).open(&path)?);
+                self.append_poisoned = false;
+                self.log_identity = FileIdentity::of(&self.append_handle.file.metadata()?);
+                self.log_size = written;
+                self.header_len = codec::HEADER_LEN;
+            }
+        }
+        self.compaction_written += written as u64;
+
+        for (key, from, to) in moved {
+            // Each record kept was counted live once, wherever it moves to.
+            self.log_stats.resize(from, to);
+            let index = self.store.get_mut();
+            if index.lookup(&key)? == Some(from) {
+                index.insert(key.clone(), to)?;
+            }
+            if let Some(chain) = self.merges.get_mut(&key) {
+                chain.relocate(from, to);
+            }
+            if let Some(touch) = self.touches.get_mut(&key).filter(|touch| **touch == from) {
+                *touch = to;
+            }
+        }
+        for key in folded {
+            let touch = self.touches.remove(&key);
+            self.log_stats.drop_record(touch);
+        }
+        for key in expired {
+            let previous = self.store.get_mut().remove(&key)?;
+            self.log_stats.drop_record(previous);
+            self.forget_history(&key);
+            self.expiries.remove(&key);
+        }
+        let log_stats = &mut self.log_stats;
+        log_stats.stale_records = log_stats.stale_records.saturating_sub(dropped_stale);
+        log_stats.tombstone_records = log_stats.tombstone_records.saturating_sub(dropped_removes);
+
+        // Nothing points into the rest of the run any more, and a crash before they are all
+        // gone leaves replay to read them before the merged segment, as `can_merge` allows.
+        if sealed.is_some() && written > codec::HEADER_LEN && !earlier.is_empty() {
+            for &gone in earlier {
+                fs::remove_file(self.segment_path(gone))?;
+                self.sealed.retain(|sealed| sealed.id != gone);
+                self.log_stats.segment_live.remove(&gone);
+            }
+            fsutil::sync_dir(&self.path)?;
+        }
+        Ok(())
+    }
+
+    /// `clear` of a segmented log. A group of removes of every key goes into the segment
+    /// appended to first, then the sealed segments are removed, oldest first, and the last
+    /// one is emptied the way `replace_log` empties a log. A crash part way leaves the
+    /// removes to keep whatever segments are still there dead, so it leaves no key behind.
+    fn clear_segments(&mut self) -> Result<()> {
+        self.recover_append()?;
+        // Out before anything is written, and until the end, so that readers cannot leave
+        // it half done.
+        let readers = self.exclude_readers()?;
+        let mut keys = Vec::new();
+        if !self.sealed.is_empty() {
+            self.store
+                .get_mut()
+                .for_each_key(|key| keys.push(key.to_owned()))?;
+        }
+        if !keys.is_empty() {
+            let mut records = encode_command(
+                self.format,
+                &Command::Group {
+                    records: keys.len(),
+                },
+            )?;
+            records.push(b'\n');
+            for key in &keys {
+                records.extend_from_slice(&encode_command(
+                    self.format,
+                    &Command::Rm {
+                        key: Cow::Borrowed(key),
+                        seq: None,
+                    },
+                )?);
+                records.push(b'\n');
+            }
+            self.append_record(&records)?;
+            self.log_size += records.len();
+            // Whatever the sync policy, as the segments they keep dead are about to go.
+            self.append_handle.sync()?;
+        }
+        if !self.sealed.is_empty() {
+            while let Some(sealed) = self.sealed.first() {
+                fs::remove_file(self.segment_path(sealed.id))?;
+                self.sealed.remove(0);
+            }
+            fsutil::sync_dir(&self.path)?;
+        }
+        self.replace_log(false, Some(readers))
+    }
+
+    /// `dir_lock::exclude_readers` of this store, failing with `KvError::ReadersActive` when
+    /// its readers outstay the wait.
+    fn exclude_readers(&self) -> Result<ReadersExcluded> {
+        dir_lock::exclude_readers(&self.path, &self.options.file_prefix()).map_err(|e| {
+            match e.kind() {
+                io::ErrorKind::WouldBlock => KvError::ReadersActive(self.path.clone()),
+                _ => e.into(),
+            }
+        })
+    }
+}
+
+impl Drop for KvStore {
+    /// Fsyncs the log, whatever the `SyncPolicy`, and saves the stats snapshot, unless the
+    /// data directory was displaced, when both would land in whatever is at the path now.
+    /// A failed fsync is reported as `StoreEvent::SyncOnDropFailed`. The directory locks
+    /// are released as their files close.
+    fn drop(&mut self) {
+        if self.check_not_displaced().is_err() {
+            return;
+        }
+        if !self.options.read_only {
+            if let Err(e) = self.sync_log() {
+                self.options.events.emit(StoreEvent::SyncOnDropFailed {
+                    log: self.log_path.clone(),
+                    error: e.to_string(),
+                });
+            }
+        }
+        self.save_stats();
+    }
+}
+
+fn describe_location(location: Option<CommandBuffer>) -> String {
+    match location {
+        Some(location) if location.segment > 0 => format!(
+            "a record of {} bytes at {} of segment {}",
+            location.size, location.start, location.segment
+        ),
+        Some(location) => format!("a record of {} bytes at {}", location.size, location.start),
+        None => "nothing".to_owned(),
+    }
+}
+
+/// The log file as written by appends. With `test-util`, a `ShortWrite` can be armed to make
+/// the next append stop part way, and an error to make the next fsync fail.
+struct LogAppender {
+    file: File,
+    #[cfg(feature = "test-util")]
+    fault: Option<crate::kvs::testing::ShortWrite>,
+    #[cfg(feature = "test-util")]
+    sync_fault: Option<io::ErrorKind>,
+    #[cfg(feature = "test-util")]
+    panic_fault: bool,
+}
+
+impl LogAppender {
+    fn new(file: File) -> LogAppender {
+        LogAppender {
+            file,
+            #[cfg(feature = "test-util")]
+            fault: None,
+            #[cfg(feature = "test-util")]
+            sync_fault: None,
+            #[cfg(feature = "test-util")]
+            panic_fault: false,
+        }
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        #[cfg(feature = "test-util")]
+        if let Some(kind) = self.sync_fault.take() {
+            return Err(io::Error::new(kind, "injected fsync failure"));
+        }
+        self.file.sync_data()
+    }
+}
+
+impl Write for LogAppender {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        #[cfg(feature = "test-util")]
+        if let Some(ref mut fault) = self.fault {
+            if fault.after_bytes == 0 {
+                let kind = fault.error;
+                self.fault = None;
+                return Err(io::Error::new(kind, "injected short write"));
+            }
+            let len = buf.len().min(fault.after_bytes);
+            let written = self.file.write(&buf[..len])?;
+            fault.after_bytes -= written;
+            return Ok(written);
+        }
+        self.file.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// A mutation read straight from a log, with the value's bytes as stored.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum RawRecord {
+    Set { key: String, value: Vec<u8> },
+    Rm { key: String },
+}
+
+/// Every mutation in the default store's log in `dir`, its `db.log` or its segments, in
+/// log order, without opening the store. `Get` records are left out, as replay skips them.
+pub(crate) fn read_raw_log(dir: &Path) -> Result<Vec<RawRecord>> {
+    let ids = segment::list(dir, "")?;
+    let paths = match ids.is_empty() {
+        true => vec![dir.join(DEFAULT_LOG_FILE_NAME)],
+        false => ids
+            .into_iter()
+            .map(|id| dir.join(segment::file_name("", id)))
+            .collect(),
+    };
+    let mut records = Vec::new();
+    for path in paths {
+        let (format, header_len) = match read_layout(&path)? {
+            Some(layout) => layout,
+            None => continue,
+        };
+        let mut reader = io::BufReader::new(File::open(path)?);
+        reader.seek(SeekFrom::Start(header_len as u64))?;
+        records.extend(read_raw_records(format, header_len, reader)?);
+    }
+    Ok(records)
+}
+
+/// The format of the log at `path` and the length of its header, from its first bytes, or
+/// `None` when it is empty or holds only part of a header, as a crash while creating it
+/// leaves. A log from before headers is JSON with none.
+fn read_layout(path: &Path) -> Result<Option<(LogFormat, usize)>> {
+    let mut start = Vec::with_capacity(codec::HEADER_LEN);
+    File::open(path)?
+        .take(codec::HEADER_LEN as u64)
+        .read_to_end(&mut start)?;
+    match codec::read_header(&start) {
+        Ok(Header::Empty) => Ok(None),
+        Ok(Header::Legacy) => Ok(Some((LogFormat::Json, 0))),
+        Ok(Header::Headed(format)) => Ok(Some((format, codec::HEADER_LEN))),
+        Err(BadHeader::NotAKvsLog) => Err(KvError::NotAKvsLog(path.to_path_buf())),
+        Err(BadHeader::UnsupportedVersion(version)) => Err(KvError::UnsupportedVersion {
+            log: path.to_path_buf(),
+            version,
+        }),
+        Err(BadHeader::Damaged(details)) => Err(KvError::Corruption { offset: 0, details }),
+    }
+}
+
+/// The sealed segments `ids` of the log in `dir`, yet to be replayed, and the format they
+/// are in, or `KvStoreOptions::format` when none has a header to say. One in another format
+/// than that or the ones before it fails with `KvError::LogFormatMismatch`.
+/// Splits the log kept in one file in `dir` into segments of at most `size` bytes each, as
+/// appends would have rolled it over, keeping the records as they are and a group's
+/// records together. The split's marker is there from before the first segment is written
+/// until the one file is removed, so an open after a crash part way starts over.
+fn split_into_segments(dir: &Path, options: &KvStoreOptions, size: u64) -> Result<()> {
+    let single = options.segment_file(dir, 0);
+    let marker = dir.join(segment::split_marker(&options.segment_prefix()));
+    let mut log = io::BufReader::new(File::open(&single)?);
+    // A log from before headers stays without them, in every segment.
+    let (format, header) = match read_layout(&single)? {
+        Some((format, header_len)) => {
+            let mut header = vec![0; header_len];
+            log.read_exact(&mut header)?;
+            (format, header)
+        }
+        None => (options.format.unwrap_or_default(), Vec::new()),
+    };
+    File::create(&marker)
+        .and_then(|marker| marker.sync_all())
+        .map_err(|e| read_only_filesystem(e, dir))?;
+    fsutil::sync_dir(dir)?;
+
+    let create = |id| -> io::Result<io::BufWriter<File>> {
+        let mut out = io::BufWriter::new(fsutil::create_exclusive(&options.segment_file(dir, id))?);
+        out.write_all(&header)?;
+        Ok(out)
+    };
+    let mut id = 1;
+    let mut out = create(id)?;
+    let mut len = header.len();
+    let mut group_left = 0;
+    let mut record = Vec::new();
+    loop {
+        record.clear();
+        if format.read_record(&mut log, &mut record)? == 0 {
+            break;
+        }
+        if group_left == 0 && len > header.len() && (len + record.len()) as u64 > size {
+            out.into_inner()
+                .map_err(io::IntoInnerError::into_error)?
+                .sync_all()?;
+            id += 1;
+            out = create(id)?;
+            len = header.len();
+        }
+        let body = record.strip_suffix(b"\n").unwrap_or(&record);
+        group_left = match decode_record(format, body) {
+            _ if group_left > 0 => group_left - 1,
+            Ok(LogRecord::Group { records }) => records,
+            _ => 0,
+        };
+        out.write_all(&record)?;
+        len += record.len();
+    }
+    out.into_inner()
+        .map_err(io::IntoInnerError::into_error)?
+        .sync_all()?;
+    fsutil::sync_dir(dir)?;
+    fs::remove_file(&single)?;
+    fsutil::sync_dir(dir)?;
+    fs::remove_file(&marker)?;
+    fsutil::sync_dir(dir)?;
+    Ok(())
+}
+
+fn read_sealed(
+    dir: &Path,
+    options: &KvStoreOptions,
+    ids: &[u32],
+) -> Result<(Vec<Segment>, Option<LogFormat>)> {
+    let mut expected = options.format;
+    let mut sealed = Vec::with_capacity(ids.len());
+    for &id in ids {
+        let path = options.segment_file(dir, id);
+        let layout = read_layout(&path)?;
+        if let Some((found, _)) = layout {
+            if let Some(requested) = expected.filter(|&requested| requested != found) {
+                return Err(KvError::LogFormatMismatch {
+                    log: path,
+                    found,
+                    requested,
+                });
+            }
+            expected = Some(found);
+        }
+        sealed.push(Segment {
+            id,
+            len: 0,
+            header_len: layout.map_or(0, |(_, header_len)| header_len),
+            last_seq: 0,
+            kept: 0,
+            identity: None,
+            written: SystemTime::now(),
+        });
+    }
+    Ok((sealed, expected))
+}
+
+/// The mutations of a log of `format` read by `reader`, which starts after its header of
+/// `header_len` bytes.
+fn read_raw_records<R: BufRead>(
+    format: LogFormat,
+    header_len: usize,
+    mut reader: R,
+) -> Result<Vec<RawRecord>> {
+    let mut records = Vec::new();
+    // Where in `records` each key's set is, and the values of those exported as removes for
+    // having expired, so that a touch can carry either over its deadline.
+    let mut sets = HashMap::new();
+    let mut expired = HashMap::new();
+    let mut line = Vec::new();
+    let mut offset = header_len;
+    loop {
+        let read = format.read_record(&mut reader, &mut line)?;
+        if read == 0 {
+            break;
+        }
+        if line.last() == Some(&b'\n') {
+            line.pop();
+        }
+        verify_record(format, &line, offset)?;
+        offset += read;
+        match decode_record(format, &line)? {
+            // An expired set amounts to a remove now. The canonical format has nowhere to
+            // put the expiry of one still live, so it is exported as a plain set.
+            LogRecord::Set {
+                key,
+                value,
+                expires_at: Some(expires_at),
+                ..
+            } if expires_at <= now_millis() => {
+                sets.remove(key.as_ref());
+                expired.insert(key.clone().into_owned(), value.0.into_owned());
+                records.push(RawRecord::Rm {
+                    key: key.into_owned(),
+                });
+            }
+            LogRecord::Set { key, value, .. } => {
+                expired.remove(key.as_ref());
+                sets.insert(key.clone().into_owned(), records.len());
+                records.push(RawRecord::Set {
+                    key: key.into_owned(),
+                    value: value.0.into_owned(),
+                });
+            }
+            LogRecord::Rm { key, .. } => {
+                sets.remove(key.as_ref());
+                expired.remove(key.as_ref());
+                records.push(RawRecord::Rm {
+                    key: key.into_owned(),
+                });
+            }
+            LogRecord::Touch { key, expires_at } if expires_at <= now_millis() => {
+                if let Some(n) = sets.remove(key.as_ref()) {
+                    if let RawRecord::Set { ref value, .. } = records[n] {
+                        expired.insert(key.clone().into_owned(), value.clone());
+                    }
+                    records.push(RawRecord::Rm {
+                        key: key.into_owned(),
+                    });
+                }
+            }
+            LogRecord::Touch { key, .. } => {
+                if let Some(value) = expired.remove(key.as_ref()) {
+                    sets.insert(key.clone().into_owned(), records.len());
+                    records.push(RawRecord::Set {
+                        key: key.into_owned(),
+                        value,
+                    });
+                }
+            }
+            // Left to the store, which has the operator to apply it; compacting the log
+            // turns it into a set.
+            LogRecord::Merge { key, .. } => return Err(KvError::NoMergeOperator(key.into_owned())),
+            LogRecord::Get {} | LogRecord::Sequence { .. } | LogRecord::Group { .. } => {}
+        }
+        line.clear();
+    }
+    Ok(records)
+}
+
+/// The log line for `record`, without its newline, as the store would write it, with a
+/// value that is not UTF-8 in base64; see `encode_set`.
+pub(crate) fn encode_raw_record(record: &RawRecord) -> Result<Vec<u8>> {
+    match *record {
+        RawRecord::Rm { ref key } => Ok(crc::seal(serde_json::to_vec(&Command::Rm {
+            key: Cow::Borrowed(key),
+            seq: None,
+        })?)),
+        RawRecord::Set { ref key, ref value } => {
+            encode_set(LogFormat::Json, key, value, None, None, None)
+        }
+    }
+}
+
+/// `command` as a record of `format`, without its newline.
+fn encode_command(format: LogFormat, command: &Command) -> Result<Vec<u8>> {
+    match format {
+        LogFormat::Json => Ok(crc::seal(serde_json::to_vec(command)?)),
+        framed => codec::encode(framed, command),
+    }
+}
+
+/// The bytes `value` is measured as against `KvStoreOptions::max_value_size`: in JSON, its
+/// text after escaping, without the quotes; see `encode_set`.
+fn stored_value_len(format: LogFormat, value: &[u8]) -> u64 {
+    if format != LogFormat::Json {
+        return value.len() as u64;
+    }
+    // Base64 grows every value alike, so a value not UTF-8 is measured as it is.
+    if str::from_utf8(value).is_err() {
+        return value.len() as u64;
+    }
+    // As serde_json escapes: quote, backslash and the short control escapes take two
+    // bytes, any other control character six (`\u00XX`).
+    value
+        .iter()
+        .map(|&byte| match byte {
+            b'"' | b'\\' | b'\x08' | b'\x0c' | b'\n' | b'\r' | b'\t' => 2,
+            0..=0x1f => 6,
+            _ => 1,
+        })
+        .sum()
+}
+
+/// The record for a set, without its newline. In JSON, a value that is not UTF-8 is
+/// written as base64, marked by an `encoding` field ahead of it, since `Command` only
+/// holds text and a JSON string can hold nothing else.
+fn encode_set(
+    format: LogFormat,
+    key: &str,
+    value: &[u8],
+    expires_at: Option<u64>,
+    timestamp: Option<u64>,
+    seq: Option<u64>,
+) -> Result<Vec<u8>> {
+    if format != LogFormat::Json {
+        return codec::encode_set(format, key, value, expires_at, timestamp, seq);
+    }
+    if let Ok(text) = str::from_utf8(value) {
+        return Ok(crc::seal(serde_json::to_vec(&Command::Set {
+            key: Cow::Borrowed(key),
+            value: Cow::Borrowed(text),
+            expires_at,
+            timestamp,
+            seq,
+        })?));
+    }
+    let mut line = b"{\"Set\":{\"key\":".to_vec();
+    serde_json::to_writer(&mut line, key)?;
+    line.extend_from_slice(BASE64_VALUE_FIELD);
+    line.extend_from_slice(base64::encode(value).as_bytes());
+    line.push(b'"');
+    if let Some(expires_at) = expires_at {
+        line.extend_from_slice(format!(",\"expires_at\":{}", expires_at).as_bytes());
+    }
+    if let Some(timestamp) = timestamp {
+        line.extend_from_slice(format!(",\"timestamp\":{}", timestamp).as_bytes());
+    }
+    if let Some(seq) = seq {
+        line.extend_from_slice(format!(",\"seq\":{}", seq).as_bytes());
+    }
+    line.extend_from_slice(b"}}");
+    Ok(crc::seal(line))
+}
+
+/// The `Sequence` record compaction writes for `seq`, with its newline.
+fn sequence_record(format: LogFormat, seq: u64) -> Result<Vec<u8>> {
+    let mut record = encode_command(format, &Command::Sequence { seq })?;
+    record.push(b'\n');
+    Ok(record)
+}
+
+fn ends_with_newline(path: &Path, len: u64) -> io::Result<bool> {
+    let mut file = File::open(path)?;
+    file.seek(SeekFrom::Start(len - 1))?;
+    let mut last = [0];
+    file.read_exact(&mut last)?;
+    Ok(last[0] == b'\n')
+}
+
+/// Milliseconds since the Unix epoch, the unit of `KvStore::set_with_ttl` deadlines.
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_millis() as u64)
+}
+
+fn deadline_after(ttl: Duration) -> u64 {
+    now_millis().saturating_add(ttl.as_millis().try_into().unwrap_or(u64::MAX))
+}
+
+/// The value of `key` with the operands of its merge `chain` applied by `merger`, reading
+/// the chain's records with `read`.
+fn apply_merges(
+    merger: &Merger,
+    format: LogFormat,
+    key: &str,
+    chain: &MergeChain,
+    read: impl Fn(CommandBuffer) -> Result<Vec<u8>>,
+) -> Result<String> {
+    let operator = merger
+        .get()
+        .ok_or_else(|| KvError::NoMergeOperator(key.to_owned()))?;
+    let mut value = match chain.base {
+        Some(base) => Some(decode_value(
+            key,
+            base.start as u64,
+            parse_value_bytes(format, &read(base)?)?,
+        )?),
+        None => None,
+    };
+    for &location in &chain.operands {
+        match decode_record(format, &read(location)?)? {
+            LogRecord::Merge { operand, .. } => {
+                value = Some(operator.merge(key, value.as_deref(), &operand))
+            }
+            _ => return Err(KvError::InvalidLogCommand),
+        }
+    }
+    Ok(value.unwrap_or_default())
+}
+
+/// The value of the set record read from `location`.
+fn parse_value(
+    format: LogFormat,
+    key: &str,
+    location: CommandBuffer,
+    buffer: &[u8],
+) -> Result<String> {
+    decode_value(
+        key,
+        location.start as u64,
+        parse_value_bytes(format, buffer)?,
+    )
+}
+
+/// The value bytes of the set record in `buffer`.
+fn parse_value_bytes(format: LogFormat, buffer: &[u8]) -> Result<Vec<u8>> {
+    match decode_record(format, buffer)? {
+        LogRecord::Set { value: bytes, .. } => Ok(bytes.0.into_owned()),
+        _ => Err(KvError::InvalidLogCommand),
+    }
+}
+
+/// Decodes `record`, a record of `format` that `verify_record` passed.
+fn decode_record(format: LogFormat, record: &[u8]) -> Result<LogRecord<'_>> {
+    match format {
+        LogFormat::Json => Ok(decode_json(record)?),
+        framed => codec::decode(framed, record).ok_or(KvError::SerializationError),
+    }
+}
+
+/// Fails with `KvError::Corruption` if `record`, read from `offset`, does not match its
+/// CRC. JSON records written before the log had CRCs pass unchecked.
+fn verify_record(format: LogFormat, record: &[u8], offset: usize) -> Result<()> {
+    let damage = match format {
+        LogFormat::Json => seal_damage(crc::check(record)),
+        LogFormat::Bincode | LogFormat::MessagePack => codec::check_frame(record),
+    };
+    corrupt_at(offset as u64, damage)
+}
+
+/// `verify_record` of the `size`-byte record at `start` of `log`, read through a buffer at
+/// a time rather than whole.
+fn verify_record_at(format: LogFormat, log: &mut File, start: u64, size: u64) -> Result<()> {
+    let damage = match format {
+        LogFormat::Json => seal_damage(crc::check_at(log, start, size)?),
+        LogFormat::Bincode | LogFormat::MessagePack => codec::check_frame_at(log, start, size)?,
+    };
+    corrupt_at(start, damage)
+}
+
+fn seal_damage(seal: Seal) -> Option<String> {
+    match seal {
+        Seal::Intact | Seal::Unsealed => None,
+        Seal::Mismatch { stored, computed } => Some(format!(
+            "its CRC is {:08x} but its bytes sum to {:08x}",
+            stored, computed
+        )),
+    }
+}
+
+fn corrupt_at(offset: u64, damage: Option<String>) -> Result<()> {
+    match damage {
+        Some(details) => Err(KvError::Corruption { offset, details }),
+        None => Ok(()),
+    }
+}
+
+/// The record in `line`, read from `offset` while replaying, failing with
+/// `KvError::Corruption` if it does not match its CRC or is not a record at all.
+fn parse_record(format: LogFormat, line: &[u8], offset: usize) -> Result<LogRecord<'_>> {
+    verify_record(format, line, offset)?;
+    let not_a_record = |details: String| KvError::Corruption {
+        offset: offset as u64,
+        details,
+    };
+    match format {
+        LogFormat::Json => {
+            decode_json(line).map_err(|e| not_a_record(format!("it is not a log record: {}", e)))
+        }
+        framed => codec::decode(framed, line)
+            .ok_or_else(|| not_a_record("it is not a log record".to_owned())),
+    }
+}
+
+/// Whether `line`, the last of the log and without its newline, was cut off part way:
+/// JSON that does not parse, or a frame shorter than it says.
+fn cut_short(format: LogFormat, line: &[u8]) -> bool {
+    match format {
+        LogFormat::Json => serde_json::from_slice::<LogRecord>(line).is_err(),
+        LogFormat::Bincode | LogFormat::MessagePack => codec::is_torn_frame(line),
+    }
+}
+
+/// Whether `line` is what a crash part way through an append leaves: cut short or garbled
+/// JSON, rather than a record or whole JSON of some other shape, or a frame cut short.
+fn is_torn(format: LogFormat, line: &[u8]) -> bool {
+    match format {
+        LogFormat::Json => match serde_json::from_slice::<LogRecord>(line) {
+            Ok(_) => false,
+            Err(e) => e.is_eof() || e.is_syntax(),
+        },
+        LogFormat::Bincode | LogFormat::MessagePack => codec::is_torn_frame(line),
+    }
+}
+
+/// Produces the `String` handed to callers, which is the only place value bytes are
+/// required to be UTF-8.
+fn decode_value(key: &str, offset: u64, bytes: Vec<u8>) -> Result<String> {
+    String::from_utf8(bytes).map_err(|e| KvError::CorruptRecord {
+        key: key.to_owned(),
+        offset,
+        details: format!(
+            "value is not valid UTF-8 (first invalid byte at position {})",
+            e.utf8_error().valid_up_to()
+        ),
+    })
+}
+
+/// Names a read-only filesystem as such, so that the first write of an open points at
+/// `open_read_only` instead of failing as an `OpenError`.
+fn read_only_filesystem(e: io::Error, dir: &Path) -> KvError {
+    match e.kind() {
+        io::ErrorKind::ReadOnlyFilesystem => KvError::ReadOnlyFilesystem(dir.to_path_buf()),
+        _ => e.into(),
+    }
+}
+
+fn check_store_name(name: &str) -> Result<()> {
+    let valid = |c: char| c.is_ascii_alphanumeric() || c == '-' || c == '_';
+    // A name of digits alone would be taken for a segment of the default store's log.
+    if name.is_empty() || !name.chars().all(valid) || segment::is_id(name) {
+        return Err(KvError::InvalidStoreName(name.to_owned()));
+    }
+    Ok(())
+}
+
+fn validate_data_directory(path: &Path, options: &KvStoreOptions) -> Result<()> {
+    // `fs::metadata` follows symlinks, so a link to a directory is accepted here.
+    match fs::metadata(path) {
+        Ok(metadata) if !metadata.is_dir() => {
+            return Err(KvError::NotADirectory(path.to_path_buf()));
+        }
+        Ok(_) => {}
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+            if !options.create_if_missing || options.read_only {
+                return Err(KvError::DirectoryNotFound(path.to_path_buf()));
+            }
+            fs::create_dir_all(path)?;
+        }
+        Err(e) => return Err(e.into()),
+    }
+
+    let log_path = path.join(options.log_file());
+    if log_path.is_dir() {
+        return Err(KvError::LogPathIsDirectory(log_path));
+    }
+
+    Ok(())
+}
+
+/// Poisons its store if a panic unwinds out of the operation holding it.
+struct PanicGuard {
+    poison: Arc<Mutex<Option<String>>>,
+    op: &'static str,
+}
+
+impl Drop for PanicGuard {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            self.poison
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .get_or_insert_with(|| format!("a panic during {}", self.op));
+        }
+    }
+}
+
+/// Device and inode of a file, where the platform exposes them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct FileIdentity {
+    dev: u64,
+    ino: u64,
+}
+
+impl FileIdentity {
+    #[cfg(unix)]
+    fn of(metadata: &fs::Metadata) -> Option<FileIdentity> {
+        use std::os::unix::fs::MetadataExt;
+
+        Some(FileIdentity {
+            dev: metadata.dev(),
+            ino: metadata.ino(),
+        })
+    }
+
+    #[cfg(not(unix))]
+    fn of(_metadata: &fs::Metadata) -> Option<FileIdentity> {
+        None
+    }
+}
+
+fn ensure_file_exists<P: AsRef<Path>>(path: P) -> std::io::Result<()> {
+    OpenOptions::new().create(true).append(true).open(path)?;
+    Ok(())
+}
+
+/// A record as written to the log. Only mutations are ever persisted; requests that read
+/// the store belong to the wire `protocol::Request` and have no variant here.
+///
+/// Records are JSON, which escapes every newline and other control character inside a
+/// string, so a record is one line whatever its key and value hold, and the bytes counted
+/// as it is appended are the bytes replay reads back up to the newline.
+///
+/// Every record the store writes is sealed with a CRC (see `crc`), which reads check
+/// before trusting the record, failing with `KvError::Corruption` on a mismatch. Logs
+/// written before there were CRCs still open, their records read unchecked, and the
+/// next compaction rewrites them all sealed.
+#[derive(Serialize, Debug)]
+pub(crate) enum Command<'a> {
+    // `Cow` so strings needing no unescaping are borrowed from the log line, while ones
+    // containing quotes, newlines or other escapes still deserialize.
+    Set {
+        #[serde(borrow)]
+        key: Cow<'a, str>,
+        #[serde(borrow)]
+        value: Cow<'a, str>,
+        /// See `KvStore::set_with_ttl`. Left out when `None`, so records without an expiry
+        /// read the same as before there were expiries.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        expires_at: Option<u64>,
+        /// When the set was written, in milliseconds since the Unix epoch; see
+        /// `KvStore::last_modified`. Compaction keeps it. Left out by the offline tools, and
+        /// missing from records written before there were timestamps.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        timestamp: Option<u64>,
+        /// See `KvStore::last_sequence`. Written last, so that a set still starts with its
+        /// key and value for `ValueReader`, and left out by the offline tools, which write
+        /// records of no store's numbering.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        seq: Option<u64>,
+    },
+    Rm {
+        #[serde(borrow)]
+        key: Cow<'a, str>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        seq: Option<u64>,
+    },
+    /// See `KvStore::merge`. Carries the key's deadline, if it has one, so that replay
+    /// drops the merge along with the set it applies to.
+    Merge {
+        #[serde(borrow)]
+        key: Cow<'a, str>,
+        #[serde(borrow)]
+        operand: Cow<'a, str>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        expires_at: Option<u64>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        timestamp: Option<u64>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        seq: Option<u64>,
+    },
+    /// Written by compaction when the write holding the store's last sequence number does
+    /// not survive it, so that numbering carries on from there after a reopen.
+    Sequence { seq: u64 },
+    /// Leads the `records` records after it, which replay applies together once all of
+    /// them are in the log, and drops with the rest of the tail otherwise; see
+    /// `KvStore::set_batch`.
+    Group { records: usize },
+    /// See `KvStore::touch`: the key's new deadline, for replay to give the set before it
+    /// without the value being written again. Unnumbered, as the key's value is unchanged.
+    Touch {
+        #[serde(borrow)]
+        key: Cow<'a, str>,
+        expires_at: u64,
+    },
+}
+
+/// Read side of `Command`. Keys are decoded as text, values are kept as the raw bytes of
+/// the JSON string so that decoding a record never fails on their contents.
+#[derive(Deserialize, Debug)]
+pub(crate) enum LogRecord<'a> {
+    Set {
+        #[serde(borrow)]
+        key: Cow<'a, str>,
+        /// How `value` is written in a JSON record, when not as itself: see `encode_set`.
+        /// `decode_json` decodes it, so a record it returns holds the value's own bytes.
+        #[serde(default)]
+        encoding: Option<ValueEncoding>,
+        #[serde(borrow)]
+        value: LogBytes<'a>,
+        #[serde(default)]
+        expires_at: Option<u64>,
+        #[serde(default)]
+        timestamp: Option<u64>,
+        #[serde(default)]
+        seq: Option<u64>,
+    },
+    // Never written by this crate, but older builds declared it and other writers may
+    // emit it. It changes nothing, so replay counts and skips it; its key is not needed.
+    Get {},
+    Rm {
+        #[serde(borrow)]
+        key: Cow<'a, str>,
+        #[serde(default)]
+        seq: Option<u64>,
+    },
+    Merge {
+        #[serde(borrow)]
+        key: Cow<'a, str>,
+        #[serde(borrow)]
+        operand: Cow<'a, str>,
+        #[serde(default)]
+        expires_at: Option<u64>,
+        #[serde(default)]
+        timestamp: Option<u64>,
+        #[serde(default)]
+        seq: Option<u64>,
+    },
+    Sequence {
+        seq: u64,
+    },
+    Group {
+        records: usize,
+    },
+    Touch {
+        #[serde(borrow)]
+        key: Cow<'a, str>,
+        expires_at: u64,
+    },
+}
+
+impl LogRecord<'_> {
+    /// The length of the record's value, or its operand for a merge; 0 for the rest.
+    fn value_len(&self) -> usize {
+        match *self {
+            LogRecord::Set { ref value, .. } => value.0.len(),
+            LogRecord::Merge { ref operand, .. } => operand.len(),
+            LogRecord::Get {}
+            | LogRecord::Rm { .. }
+            | LogRecord::Sequence { .. }
+            | LogRecord::Group { .. }
+            | LogRecord::Touch { .. } => 0,
+        }
+    }
+
+    /// When the record was written, in milliseconds since the Unix epoch, `None` for records
+    /// without a time.
+    fn timestamp(&self) -> Option<u64> {
+        match *self {
+            LogRecord::Set { timestamp, .. } | LogRecord::Merge { timestamp, .. } => timestamp,
+            LogRecord::Get {}
+            | LogRecord::Rm { .. }
+            | LogRecord::Sequence { .. }
+            | LogRecord::Group { .. }
+            | LogRecord::Touch { .. } => None,
+        }
+    }
+
+    /// The sequence number the record was written with, `None` for records of writers
+    /// that number nothing.
+    fn seq(&self) -> Option<u64> {
+        match *self {
+            LogRecord::Set { seq, .. }
+            | LogRecord::Rm { seq, .. }
+            | LogRecord::Merge { seq, .. } => seq,
+            LogRecord::Sequence { seq } => Some(seq),
+            LogRecord::Get {} | LogRecord::Group { .. } | LogRecord::Touch { .. } => None,
+        }
+    }
+}
+
+/// A `Command::Group` that `KvStore::replay` has read the start of.
+struct PendingGroup {
+    start: usize,
+    records: usize,
+    /// The group's records read so far, each with its offset.
+    lines: Vec<(Vec<u8>, usize)>,
+}
+
+/// How the value of a JSON set record is written; see `encode_set`.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum ValueEncoding {
+    Base64,
+}
+
+/// What a JSON set record of a value in base64 has between its key and the value's
+/// string, up to the string's opening quote.
+pub(crate) const BASE64_VALUE_FIELD: &[u8] = b",\"encoding\":\"base64\",\"value\":\"";
+
+/// The JSON record `record` as a `LogRecord`, with a value written in base64 decoded.
+fn decode_json(record: &[u8]) -> serde_json::Result<LogRecord<'_>> {
+    let mut decoded = serde_json::from_slice(record)?;
+    if let LogRecord::Set {
+        ref mut encoding,
+        ref mut value,
+        ..
+    } = decoded
+    {
+        if let Some(ValueEncoding::Base64) = encoding.take() {
+            let text = str::from_utf8(&value.0).map_err(serde::de::Error::custom)?;
+            value.0 = Cow::Owned(base64::decode(text).map_err(serde::de::Error::custom)?);
+        }
+    }
+    Ok(decoded)
+}
+
+/// The unescaped bytes of a JSON string, without UTF-8 validation.
+#[derive(Debug)]
+pub(crate) struct LogBytes<'a>(pub(crate) Cow<'a, [u8]>);
+
+impl<'de: 'a, 'a> Deserialize<'de> for LogBytes<'a> {
+    fn deserialize<D: serde::Deserializer<'de>>(
+        deserializer: D,
+    ) -> std::result::Result<Self, D::Error> {
+        struct LogBytesVisitor;
+
+        impl<'de> serde::de::Visitor<'de> for LogBytesVisitor {
+            type Value = LogBytes<'de>;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a string")
+            }
+
+            fn visit_borrowed_bytes<E>(self, v: &'de [u8]) -> std::result::Result<Self::Value, E> {
+                Ok(LogBytes(Cow::Borrowed(v)))
+            }
+
+            fn visit_bytes<E>(self, v: &[u8]) -> std::result::Result<Self::Value, E> {
+                Ok(LogBytes(Cow::Owned(v.to_vec())))
+            }
+
+            fn visit_borrowed_str<E>(self, v: &'de str) -> std::result::Result<Self::Value, E> {
+                Ok(LogBytes(Cow::Borrowed(v.as_bytes())))
+            }
+
+            fn visit_str<E>(self, v: &str) -> std::result::Result<Self::Value, E> {
+                Ok(LogBytes(Cow::Owned(v.as_bytes().to_vec())))
+            }
+        }
+
+        deserializer.deserialize_bytes(LogBytesVisitor)
+    }
 }
@@ -0,0 +1,449 @@
+use crate::kvs::kv_store::{CompactionEstimate, KvError, PrefixUsage, Result, SwapStats};
+use crate::kvs::protocol::{
+    self, read_frame, write_frame, BulkApplied, Checksum, Health, Request, Response, CHUNK_BYTES,
+};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::str;
+use std::time::{Duration, Instant};
+
+pub const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+pub const DEFAULT_OPERATION_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Wait suggested by `KvError::ServerOverloaded` before retrying a shed request.
+pub const OVERLOAD_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Connection settings for a `KvsClient`.
+#[derive(Debug, Clone, Copy)]
+pub struct KvsClientOptions {
+    connect_timeout: Duration,
+    operation_timeout: Duration,
+}
+
+impl Default for KvsClientOptions {
+    fn default() -> KvsClientOptions {
+        KvsClientOptions {
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+            operation_timeout: DEFAULT_OPERATION_TIMEOUT,
+        }
+    }
+}
+
+impl KvsClientOptions {
+    pub fn new() -> KvsClientOptions {
+        KvsClientOptions::default()
+    }
+
+    /// How long to wait for each address to accept the connection.
+    pub fn connect_timeout(mut self, connect_timeout: Duration) -> KvsClientOptions {
+        self.connect_timeout = connect_timeout;
+        self
+    }
+
+    /// How long a whole request may take, from sending it to the last frame of the answer.
+    pub fn operation_timeout(mut self, operation_timeout: Duration) -> KvsClientOptions {
+        self.operation_timeout = operation_timeout;
+        self
+    }
+}
+
+/// Client for a `KvsServer`, holding one connection for all requests.
+///
+/// Once a request has timed out the connection is abandoned, and every later request
+/// fails with `KvError::ConnectionError`; connect again to carry on.
+pub struct KvsClient {
+    reader: BufReader<TcpStream>,
+    writer: BufWriter<TcpStream>,
+    options: KvsClientOptions,
+    /// Set by each public request before it talks to the server.
+    operation: Option<Operation>,
+    abandoned: bool,
+}
+
+/// The request in progress, for its timeout.
+struct Operation {
+    name: &'static str,
+    started: Instant,
+    timeout: Duration,
+}
+
+impl KvsClient {
+    pub fn connect<A: ToSocketAddrs>(addr: A) -> Result<KvsClient> {
+        KvsClient::connect_with_options(addr, KvsClientOptions::default())
+    }
+
+    /// Tries each address `addr` resolves to in turn, as `TcpStream::connect` does.
+    pub fn connect_with_options<A: ToSocketAddrs>(
+        addr: A,
+        options: KvsClientOptions,
+    ) -> Result<KvsClient> {
+        let mut last_error = None;
+        let mut stream = None;
+        for addr in addr.to_socket_addrs().map_err(connection_error)? {
+            match TcpStream::connect_timeout(&addr, options.connect_timeout) {
+                Ok(connected) => {
+                    stream = Some(connected);
+                    break;
+                }
+                Err(e) => last_error = Some(e),
+            }
+        }
+        let stream = match (stream, last_error) {
+            (Some(stream), _) => stream,
+            (None, Some(e)) => return Err(connection_error(e)),
+            (None, None) => {
+                return Err(KvError::ConnectionError(
+                    "address resolved to nothing".to_owned(),
+                ))
+            }
+        };
+        let reader = BufReader::new(stream.try_clone().map_err(connection_error)?);
+
+        Ok(KvsClient {
+            reader,
+            writer: BufWriter::new(stream),
+            options,
+            operation: None,
+            abandoned: false,
+        })
+    }
+
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        self.get_with_timeout(key, self.options.operation_timeout)
+    }
+
+    /// `get` with its own operation timeout in place of the client's.
+    pub fn get_with_timeout(&mut self, key: String, timeout: Duration) -> Result<Option<String>> {
+        self.begin("get", timeout)?;
+        self.request(Request::Get { key })
+    }
+
+    pub fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.begin("set", self.options.operation_timeout)?;
+        self.request(Request::Set { key, value }).map(|_| ())
+    }
+
+    /// Sets a key that the server removes when this client's connection closes, whether
+    /// cleanly or not. The key is readable from any connection meanwhile.
+    pub fn set_ephemeral(&mut self, key: String, value: String) -> Result<()> {
+        self.begin("set_ephemeral", self.options.operation_timeout)?;
+        self.request(Request::SetEphemeral { key, value })
+            .map(|_| ())
+    }
+
+    /// Fails with `KvError::RemoveError` when the key does not exist, as `KvStore::remove`
+    /// does.
+    pub fn remove(&mut self, key: String) -> Result<()> {
+        self.begin("remove", self.options.operation_timeout)?;
+        match self.request(Request::Rm { key: key.clone() }) {
+            Err(KvError::RemoveError(_)) => Err(KvError::RemoveError(key)),
+            result => result.map(|_| ()),
+        }
+    }
+
+    /// Sets `key` to the `len` bytes read from `reader`, uploading them in chunks so the
+    /// value may exceed the server's frame limit. The bytes need not be UTF-8; a value
+    /// that is not reads back through `get_writer`.
+    pub fn set_reader<R: Read>(&mut self, key: String, mut reader: R, len: u64) -> Result<()> {
+        let value_error = |details: String| KvError::ValueSerializationError {
+            key: key.clone(),
+            details,
+        };
+        self.begin("set_reader", self.options.operation_timeout)?;
+        self.send(&Request::SetBegin {
+            key: key.clone(),
+            total_len: len,
+        })?;
+
+        let mut buffer = vec![0; CHUNK_BYTES];
+        let mut read_total = 0;
+        let mut checksum = Checksum::new();
+        while read_total < len {
+            let room = CHUNK_BYTES.min((len - read_total) as usize);
+            let n = match reader.read(&mut buffer[..room]) {
+                Ok(0) => return Err(value_error(format!("reader ended before {} bytes", len))),
+                Ok(n) => n,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(value_error(e.to_string())),
+            };
+            read_total += n as u64;
+            checksum.update(&buffer[..n]);
+            self.send(&Request::SetChunk {
+                data: buffer[..n].to_vec(),
+            })?;
+        }
+
+        self.send(&Request::SetCommit {
+            checksum: checksum.value(),
+        })?;
+        self.receive_answer().map(|_| ())
+    }
+
+    /// Writes the value of `key` to `writer` as it arrives, returning whether the key exists.
+    pub fn get_writer<W: Write>(&mut self, key: String, mut writer: W) -> Result<bool> {
+        self.begin("get_writer", self.options.operation_timeout)?;
+        self.send(&Request::Get { key })?;
+        match self.receive()? {
+            Response::Ok(Some(value)) => {
+                writer
+                    .write_all(value.as_bytes())
+                    .map_err(|_| KvError::WriteError)?;
+                Ok(true)
+            }
+            Response::Ok(None) => Ok(false),
+            Response::ValueBegin { total_len } => {
+                self.receive_value_stream(total_len, &mut writer)?;
+                Ok(true)
+            }
+            response => answer(response).map(|_| false),
+        }
+    }
+
+    /// Sets every pair in one batch that the server applies entirely or not at all,
+    /// returning the number applied and the sequence number of the last. The batch is split
+    /// over as many frames as needed, but each pair must fit in one frame; larger values go
+    /// through `set_reader`. A batch over the server's `KvsServer::max_batch_bytes` fails
+    /// with `KvError::ServerError`, and nothing of it is applied.
+    pub fn set_bulk(&mut self, pairs: Vec<(String, String)>) -> Result<BulkApplied> {
+        self.begin("set_bulk", self.options.operation_timeout)?;
+        let mut frame = Vec::new();
+        let mut frame_bytes = 0;
+        for (key, value) in pairs {
+            let pair_bytes = key.len() + value.len();
+            if !frame.is_empty() && frame_bytes + pair_bytes > CHUNK_BYTES {
+                self.send(&Request::BulkSet {
+                    pairs: std::mem::take(&mut frame),
+                    more: true,
+                })?;
+                frame_bytes = 0;
+            }
+            frame_bytes += pair_bytes;
+            frame.push((key, value));
+        }
+        self.send(&Request::BulkSet {
+            pairs: frame,
+            more: false,
+        })?;
+
+        match self.receive()? {
+            Response::Applied(applied) => Ok(applied),
+            response => answer(response).and(Err(unexpected_frame())),
+        }
+    }
+
+    /// Asks the server what compacting its log would reclaim.
+    pub fn compact_dry_run(&mut self) -> Result<CompactionEstimate> {
+        self.begin("compact_dry_run", self.options.operation_timeout)?;
+        self.send(&Request::CompactDryRun)?;
+        match self.receive()? {
+            Response::CompactionEstimate(estimate) => Ok(estimate),
+            response => answer(response).and(Err(unexpected_frame())),
+        }
+    }
+
+    /// Asks the server whether it can still make writes durable.
+    pub fn ping(&mut self) -> Result<Health> {
+        self.begin("ping", self.options.operation_timeout)?;
+        self.send(&Request::Ping)?;
+        match self.receive()? {
+            Response::Health(health) => Ok(health),
+            response => answer(response).and(Err(unexpected_frame())),
+        }
+    }
+
+    /// Has the server swap the keys under two prefixes in one batch; see
+    /// `KvStore::swap_prefixes`.
+    pub fn swap_prefixes(&mut self, a: String, b: String) -> Result<SwapStats> {
+        self.begin("swap_prefixes", self.options.operation_timeout)?;
+        self.send(&Request::SwapPrefixes { a, b })?;
+        match self.receive()? {
+            Response::Swapped(stats) => Ok(stats),
+            response => answer(response).and(Err(unexpected_frame())),
+        }
+    }
+
+    /// Runs `KvStore::compare_and_swap` on the server, which makes it atomic with respect to
+    /// every other client's requests.
+    pub fn compare_and_swap(
+        &mut self,
+        key: String,
+        expected: Option<String>,
+        new: Option<String>,
+    ) -> Result<bool> {
+        self.begin("compare_and_swap", self.options.operation_timeout)?;
+        self.send(&Request::CompareAndSwap { key, expected, new })?;
+        match self.receive()? {
+            Response::Compared { swapped } => Ok(swapped),
+            response => answer(response).and(Err(unexpected_frame())),
+        }
+    }
+
+    /// Runs `KvStore::touch` on the server, returning false when the key does not exist.
+    pub fn touch(&mut self, key: String, ttl: Duration) -> Result<bool> {
+        self.begin("touch", self.options.operation_timeout)?;
+        let ttl_ms = u64::try_from(ttl.as_millis()).unwrap_or(u64::MAX);
+        self.send(&Request::Touch { key, ttl_ms })?;
+        match self.receive()? {
+            Response::Touched { found } => Ok(found),
+            response => answer(response).and(Err(unexpected_frame())),
+        }
+    }
+
+    /// Has the server rebuild a poisoned store's index from its log; see
+    /// `KvStore::clear_poison_and_verify`.
+    pub fn clear_poison_and_verify(&mut self) -> Result<()> {
+        self.begin("clear_poison", self.options.operation_timeout)?;
+        self.request(Request::ClearPoison).map(|_| ())
+    }
+
+    /// The server's usage per key prefix, cleared afterwards if `reset` is set.
+    pub fn usage(&mut self, reset: bool) -> Result<Vec<(String, PrefixUsage)>> {
+        self.begin("usage", self.options.operation_timeout)?;
+        self.send(&Request::Usage { reset })?;
+        match self.receive()? {
+            Response::Usage(usage) => Ok(usage),
+            response => answer(response).and(Err(unexpected_frame())),
+        }
+    }
+
+    fn request(&mut self, request: Request) -> Result<Option<String>> {
+        self.send(&request)?;
+        self.receive_answer()
+    }
+
+    fn begin(&mut self, name: &'static str, timeout: Duration) -> Result<()> {
+        if self.abandoned {
+            return Err(KvError::ConnectionError(
+                "connection abandoned after a request timed out".to_owned(),
+            ));
+        }
+        self.operation = Some(Operation {
+            name,
+            started: Instant::now(),
+            timeout,
+        });
+        Ok(())
+    }
+
+    /// Gives the socket what is left of the operation's timeout for its next read or write.
+    fn arm_timeout(&mut self) -> Result<()> {
+        let operation = self
+            .operation
+            .as_ref()
+            .expect("request without an operation");
+        let remaining = operation
+            .timeout
+            .saturating_sub(operation.started.elapsed());
+        if remaining.is_zero() {
+            return Err(self.timed_out());
+        }
+        let stream = self.writer.get_ref();
+        stream
+            .set_read_timeout(Some(remaining))
+            .and_then(|_| stream.set_write_timeout(Some(remaining)))
+            .map_err(connection_error)
+    }
+
+    fn timed_out(&mut self) -> KvError {
+        self.abandoned = true;
+        let operation = self
+            .operation
+            .as_ref()
+            .expect("request without an operation");
+        KvError::OperationTimedOut {
+            op: operation.name,
+            elapsed: operation.started.elapsed(),
+        }
+    }
+
+    fn io_error(&mut self, e: io::Error) -> KvError {
+        match e.kind() {
+            // Unix reports an expired socket timeout as `WouldBlock`, Windows as `TimedOut`.
+            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => self.timed_out(),
+            _ => connection_error(e),
+        }
+    }
+
+    fn send(&mut self, request: &Request) -> Result<()> {
+        self.arm_timeout()?;
+        write_frame(&mut self.writer, request).map_err(|e| self.io_error(e))
+    }
+
+    fn receive(&mut self) -> Result<Response> {
+        self.arm_timeout()?;
+        read_frame(&mut self.reader)
+            .map_err(|e| self.io_error(e))?
+            .ok_or_else(|| KvError::ConnectionError("server closed the connection".to_owned()))
+    }
+
+    fn receive_answer(&mut self) -> Result<Option<String>> {
+        match self.receive()? {
+            Response::ValueBegin { total_len } => {
+                let mut value = Vec::with_capacity(total_len as usize);
+                self.receive_value_stream(total_len, &mut value)?;
+                String::from_utf8(value)
+                    .map(Some)
+                    .map_err(|e| KvError::ConnectionError(e.to_string()))
+            }
+            response => answer(response),
+        }
+    }
+
+    fn receive_value_stream<W: Write>(&mut self, total_len: u64, writer: &mut W) -> Result<()> {
+        let mut received = 0;
+        let mut checksum = Checksum::new();
+        loop {
+            match self.receive()? {
+                Response::ValueChunk { data } => {
+                    received += data.len() as u64;
+                    checksum.update(&data);
+                    writer.write_all(&data).map_err(|_| KvError::WriteError)?;
+                }
+                Response::ValueCommit { checksum: expected }
+                    if received == total_len && expected == checksum.value() =>
+                {
+                    return Ok(());
+                }
+                Response::ValueCommit { .. } => {
+                    return Err(KvError::ConnectionError(
+                        "streamed value does not match its checksum".to_owned(),
+                    ))
+                }
+                response => return answer(response).map(|_| ()),
+            }
+        }
+    }
+}
+
+/// Turns a single-frame response into the caller's result.
+fn answer(response: Response) -> Result<Option<String>> {
+    match response {
+        Response::Ok(value) => Ok(value),
+        Response::Err(ref message) if message == protocol::OVERLOADED => {
+            Err(KvError::ServerOverloaded {
+                retry_after: OVERLOAD_BACKOFF,
+            })
+        }
+        Response::Err(message) => Err(KvError::ServerError(message)),
+        // The key is filled in by `remove`, the only request that can get this answer.
+        Response::KeyNotFound => Err(KvError::RemoveError(String::new())),
+        Response::ValueBegin { .. }
+        | Response::ValueChunk { .. }
+        | Response::ValueCommit { .. }
+        | Response::CompactionEstimate(_)
+        | Response::Applied(_)
+        | Response::Swapped(_)
+        | Response::Compared { .. }
+        | Response::Touched { .. }
+        | Response::Health(_)
+        | Response::Usage(_) => Err(unexpected_frame()),
+    }
+}
+
+fn unexpected_frame() -> KvError {
+    KvError::ConnectionError("unexpected frame in the server's answer".to_owned())
+}
+
+fn connection_error(e: io::Error) -> KvError {
+    KvError::ConnectionError(e.to_string())
+}
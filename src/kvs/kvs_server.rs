@@ -1,2 +1,667 @@
+use crate::kvs::display::{Redacted, Truncated};
+use crate::kvs::ephemeral::{ConnectionId, EphemeralKeys};
+use crate::kvs::kv_store::{KvError, KvStore, Result};
+use crate::kvs::protocol::{
+    self, read_frame_limited, write_frame, BulkApplied, Checksum, Health, Request, Response,
+    BATCH_PAIR_OVERHEAD, CHUNK_BYTES, MAX_BATCH_BYTES, MAX_FRAME_BYTES,
+};
+use crate::kvs::shedding::{LoadShedding, RequestClass, Shedder};
+use crate::kvs::upload::{self, Committed, Upload};
+use crate::kvs::value_reader::ValueReader;
+use slog::Logger;
+use std::any::Any;
+use std::fmt;
+use std::io::{self, BufReader, BufWriter, Read};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::thread;
+use std::time::Duration;
 
+/// Serves a `KvStore` over TCP, one thread per connection.
+pub struct KvsServer {
+    tcp_listener: TcpListener,
+    store: Arc<Mutex<KvStore>>,
+    ephemeral: Arc<Mutex<EphemeralKeys>>,
+    data_dir: PathBuf,
+    log: Logger,
+    redact_values: bool,
+    max_frame_bytes: usize,
+    max_batch_bytes: usize,
+    shedder: Arc<Shedder>,
+    request_delay: Duration,
+}
 
+impl KvsServer {
+    /// Binds `ip_addr` and takes over `store`, first removing any ephemeral keys and upload
+    /// spools a previous server left behind.
+    pub fn new(ip_addr: &str, mut store: KvStore, log: Logger) -> io::Result<KvsServer> {
+        let tcp_listener = TcpListener::bind(ip_addr)?;
+        let (ephemeral, removed) =
+            EphemeralKeys::recover(&mut store).map_err(|e| io::Error::other(e.to_string()))?;
+        if !removed.is_empty() {
+            info!(log, "removed ephemeral keys left by a previous run"; "count" => removed.len());
+        }
+        let data_dir = store.directory().to_path_buf();
+        let spools = upload::remove_leftover_spools(&data_dir)?;
+        if spools > 0 {
+            info!(log, "removed unfinished uploads left by a previous run"; "count" => spools);
+        }
+
+        Ok(KvsServer {
+            tcp_listener,
+            store: Arc::new(Mutex::new(store)),
+            ephemeral: Arc::new(Mutex::new(ephemeral)),
+            data_dir,
+            log,
+            redact_values: false,
+            max_frame_bytes: MAX_FRAME_BYTES,
+            max_batch_bytes: MAX_BATCH_BYTES,
+            shedder: Arc::new(Shedder::default()),
+            request_delay: Duration::ZERO,
+        })
+    }
+
+    /// Close connections that send a frame larger than this; values above it can still be
+    /// uploaded in chunks. Defaults to `protocol::MAX_FRAME_BYTES`.
+    pub fn max_frame_bytes(mut self, max_frame_bytes: usize) -> KvsServer {
+        self.max_frame_bytes = max_frame_bytes;
+        self
+    }
+
+    /// Refuse `BulkSet` batches larger than this, counted as `protocol::MAX_BATCH_BYTES`
+    /// is, rather than hold them in memory until their last frame. Defaults to
+    /// `protocol::MAX_BATCH_BYTES`.
+    pub fn max_batch_bytes(mut self, max_batch_bytes: usize) -> KvsServer {
+        self.max_batch_bytes = max_batch_bytes;
+        self
+    }
+
+    /// Log values as `<len=N>` instead of their (truncated) contents.
+    pub fn redact_values(mut self, redact_values: bool) -> KvsServer {
+        self.redact_values = redact_values;
+        self
+    }
+
+    /// Answer reads or writes `protocol::OVERLOADED` while too many requests are queued for
+    /// the store; see `shedding`. Off by default.
+    pub fn load_shedding(mut self, policy: LoadShedding) -> KvsServer {
+        self.shedder = Arc::new(Shedder::new(policy));
+        self
+    }
+
+    /// Holds the store for `delay` on every request, to build up a queue.
+    #[cfg(feature = "test-util")]
+    pub fn request_delay(mut self, delay: Duration) -> KvsServer {
+        self.request_delay = delay;
+        self
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.tcp_listener.local_addr()
+    }
+
+    pub fn listen_forever(&self) -> io::Result<()> {
+        let mut next_id: ConnectionId = 0;
+        for stream in self.tcp_listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    next_id += 1;
+                    let connection = Connection {
+                        id: next_id,
+                        store: Arc::clone(&self.store),
+                        ephemeral: Arc::clone(&self.ephemeral),
+                        data_dir: self.data_dir.clone(),
+                        log: self.log.clone(),
+                        redact_values: self.redact_values,
+                        max_frame_bytes: self.max_frame_bytes,
+                        max_batch_bytes: self.max_batch_bytes,
+                        shedder: Arc::clone(&self.shedder),
+                        request_delay: self.request_delay,
+                    };
+                    thread::spawn(move || connection.serve(stream));
+                }
+                Err(e) => warn!(self.log, "failed to accept connection"; "error" => %e),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+struct Connection {
+    id: ConnectionId,
+    store: Arc<Mutex<KvStore>>,
+    ephemeral: Arc<Mutex<EphemeralKeys>>,
+    data_dir: PathBuf,
+    log: Logger,
+    redact_values: bool,
+    max_frame_bytes: usize,
+    max_batch_bytes: usize,
+    shedder: Arc<Shedder>,
+    request_delay: Duration,
+}
+
+impl Connection {
+    fn serve(self, stream: TcpStream) {
+        let peer = stream
+            .peer_addr()
+            .map(|addr| addr.to_string())
+            .unwrap_or_default();
+        let log = self.log.new(o!("peer" => peer));
+        if let Err(e) = self.handle_connection(stream, &log) {
+            warn!(log, "connection closed with error"; "error" => %e);
+        }
+        self.remove_ephemeral_keys(&log);
+    }
+
+    fn remove_ephemeral_keys(&self, log: &Logger) {
+        // Lock order is always store, then ephemeral table.
+        let mut store = self.lock_store();
+        let mut ephemeral = lock(&self.ephemeral);
+        let keys = ephemeral.release_connection(self.id);
+        if keys.is_empty() {
+            return;
+        }
+        for key in keys {
+            match store.remove(key.clone()) {
+                Ok(()) | Err(KvError::RemoveError(_)) => {}
+                Err(e) => {
+                    error!(log, "failed to remove ephemeral key";
+                        "key" => %Truncated::new(&key), "error" => %e);
+                }
+            }
+        }
+        if let Err(e) = ephemeral.persist() {
+            error!(log, "failed to update the ephemeral key marker"; "error" => %e);
+        }
+    }
+
+    fn handle_connection(&self, stream: TcpStream, log: &Logger) -> io::Result<()> {
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut writer = BufWriter::new(stream);
+        let mut upload: Option<Upload> = None;
+        let mut bulk: Vec<(String, String)> = Vec::new();
+        let mut bulk_bytes = 0;
+        // Set once a batch goes over `max_batch_bytes`, until its last frame is answered.
+        let mut bulk_refused = false;
+
+        while let Some(request) =
+            read_frame_limited::<_, Request>(&mut reader, self.max_frame_bytes)?
+        {
+            let request = match request {
+                Request::SetChunk { data } => {
+                    match upload {
+                        Some(ref mut upload) => upload.push(&data),
+                        None => {
+                            return Err(io::Error::new(
+                                io::ErrorKind::InvalidData,
+                                "SetChunk outside of an upload",
+                            ))
+                        }
+                    }
+                    continue;
+                }
+                request => request,
+            };
+            self.log_request(log, &request);
+            if upload.is_some() && !matches!(request, Request::SetCommit { .. }) {
+                warn!(log, "upload abandoned without a commit");
+                upload = None;
+            }
+            if (!bulk.is_empty() || bulk_refused) && !matches!(request, Request::BulkSet { .. }) {
+                warn!(log, "bulk set abandoned before its last frame"; "pairs" => bulk.len());
+                bulk.clear();
+                bulk_bytes = 0;
+                bulk_refused = false;
+            }
+
+            let response = match request {
+                Request::SetBegin { key, total_len } => {
+                    let checked = self.lock_store().check_size(&key, total_len);
+                    upload = Some(match checked {
+                        Ok(()) => Upload::begin(&self.data_dir, key, total_len),
+                        Err(e) => Upload::refused(key, total_len, e.to_string()),
+                    });
+                    continue;
+                }
+                Request::SetCommit { checksum } => match upload.take() {
+                    Some(upload) => match upload.finish(checksum) {
+                        Ok(committed) => self.execute_upload(committed, log),
+                        Err(message) => Response::Err(message).into(),
+                    },
+                    None => Response::Err("SetCommit without SetBegin".to_owned()).into(),
+                },
+                Request::BulkSet { pairs, more } => {
+                    if !bulk_refused {
+                        bulk_bytes += pairs
+                            .iter()
+                            .map(|(key, value)| key.len() + value.len() + BATCH_PAIR_OVERHEAD)
+                            .sum::<usize>();
+                        if bulk_bytes > self.max_batch_bytes {
+                            warn!(log, "bulk set refused over the batch limit";
+                                "pairs" => bulk.len() + pairs.len(),
+                                "limit" => self.max_batch_bytes);
+                            bulk = Vec::new();
+                            bulk_refused = true;
+                        } else {
+                            bulk.extend(pairs);
+                        }
+                    }
+                    if more {
+                        continue;
+                    }
+                    bulk_bytes = 0;
+                    if std::mem::take(&mut bulk_refused) {
+                        Response::Err(format!(
+                            "bulk set is over the server's limit of {} bytes",
+                            self.max_batch_bytes
+                        ))
+                        .into()
+                    } else {
+                        let pairs = std::mem::take(&mut bulk);
+                        self.execute(Request::BulkSet { pairs, more: false }, log)
+                    }
+                }
+                request => self.execute(request, log),
+            };
+            match response {
+                Answer::Frame(response) => write_frame(&mut writer, &response)?,
+                Answer::Stream { total_len, value } => {
+                    write_value_stream(&mut writer, total_len, value)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn execute(&self, request: Request, log: &Logger) -> Answer {
+        self.execute_with(class_of(&request), log, |store| {
+            self.execute_locked(store, request, log)
+        })
+    }
+
+    /// Copies a committed upload into the store, as a write like any other.
+    fn execute_upload(&self, mut upload: Committed, log: &Logger) -> Answer {
+        self.execute_with(Some(RequestClass::Write), log, |store| {
+            self.apply_logged(store, log, |store| self.apply_upload(store, &mut upload))
+        })
+    }
+
+    /// Runs `run` on the locked store, unless it is shed as `class`.
+    fn execute_with<F>(&self, class: Option<RequestClass>, log: &Logger, run: F) -> Answer
+    where
+        F: FnOnce(&mut KvStore) -> Answer,
+    {
+        if let Some(class) = class {
+            if !self.shedder.admit(class, log) {
+                return Response::Err(protocol::OVERLOADED.to_owned()).into();
+            }
+        }
+        let _queued = self.shedder.enqueue();
+        let mut store = self.lock_store();
+        if !self.request_delay.is_zero() {
+            thread::sleep(self.request_delay);
+        }
+        // A panic here must not reach the connection thread, or unwind through the lock
+        // and fail every other connection's requests for good.
+        match panic::catch_unwind(AssertUnwindSafe(|| run(&mut store))) {
+            Ok(answer) => answer,
+            Err(panic) => {
+                store.mark_poisoned(format!("a panic in the server: {}", panic_message(&*panic)));
+                let cause = store.poison_cause().unwrap_or_default();
+                crit!(log, "a request panicked, refusing requests until the store is cleared";
+                    "cause" => %cause, "panic" => panic_message(&*panic));
+                Response::Err(KvError::StorePoisoned { cause }.to_string()).into()
+            }
+        }
+    }
+
+    /// Poisons the store if a thread panicked holding it outside `execute`.
+    fn lock_store(&self) -> MutexGuard<'_, KvStore> {
+        match self.store.lock() {
+            Ok(store) => store,
+            Err(poisoned) => {
+                let store = poisoned.into_inner();
+                store.mark_poisoned("a panic while the server held the store".to_owned());
+                self.store.clear_poison();
+                store
+            }
+        }
+    }
+
+    fn execute_locked(&self, store: &mut KvStore, request: Request, log: &Logger) -> Answer {
+        if let Request::ClearPoison = request {
+            return match store.clear_poison_and_verify() {
+                Ok(()) => {
+                    info!(log, "store rebuilt from its log, serving requests again");
+                    Response::Ok(None).into()
+                }
+                Err(e) => Response::Err(e.to_string()).into(),
+            };
+        }
+        if let Request::CompactDryRun = request {
+            return match store.compact_dry_run() {
+                Ok(estimate) => Response::CompactionEstimate(estimate).into(),
+                Err(e) => Response::Err(e.to_string()).into(),
+            };
+        }
+        if let Request::Usage { reset } = request {
+            let usage = store.accounting_snapshot();
+            if reset {
+                store.reset_accounting();
+            }
+            return Response::Usage(usage).into();
+        }
+        if let Request::Ping = request {
+            return Response::Health(Health {
+                durability_lost: store.is_durability_lost(),
+                append_poisoned: store.is_append_poisoned(),
+                queue_depth: self.shedder.depth() as u64,
+                shed_reads: self.shedder.shed().0,
+                shed_writes: self.shedder.shed().1,
+                poisoned: store.poison_cause().is_some(),
+                warming_up: !store.ready(),
+            })
+            .into();
+        }
+        self.apply_logged(store, log, |store| self.apply(store, request.clone()))
+    }
+
+    /// Runs `apply`, reopening a displaced store to run it again, and recovering and
+    /// logging a failed write.
+    fn apply_logged<F>(&self, store: &mut KvStore, log: &Logger, mut apply: F) -> Answer
+    where
+        F: FnMut(&mut KvStore) -> Result<Answer>,
+    {
+        let durability_lost_before = store.is_durability_lost();
+        let mut result = apply(store);
+        if let Err(KvError::StoreDisplaced(ref path)) = result {
+            error!(log, "data directory was moved or replaced underneath the server, reopening";
+                "log" => %path.display());
+            match store.reopen() {
+                Ok(()) => result = apply(store),
+                Err(e) => error!(log, "reopening the store failed"; "error" => %e),
+            }
+        }
+        // Only the failing write itself triggers recovery, so it is attempted once per
+        // partial append; if it fails, writes keep getting `LogPoisoned`.
+        if let Err(KvError::WriteError | KvError::TransientIoExhausted { .. }) = result {
+            if store.is_append_poisoned() {
+                crit!(
+                    log,
+                    "append failed part way through a record, truncating the log back \
+                    to the last complete record"
+                );
+                if let Err(e) = store.recover_append() {
+                    crit!(log, "recovering the log failed, writes are refused"; "error" => %e);
+                }
+            }
+        }
+        // Logged once, by the write whose fsync failed; later ones fail the same way.
+        if let Err(KvError::DurabilityLost { ref original_error }) = result {
+            if !durability_lost_before {
+                crit!(log, "an fsync of the log failed, recent writes may not be durable";
+                    "error" => %original_error);
+            }
+        }
+        match result {
+            Ok(answer) => answer,
+            Err(KvError::RemoveError(_)) => Response::KeyNotFound.into(),
+            Err(e) => Response::Err(e.to_string()).into(),
+        }
+    }
+
+    fn apply(&self, store: &mut KvStore, request: Request) -> Result<Answer> {
+        if let Request::Get { key } = request {
+            return get_answer(store, &key);
+        }
+        self.apply_write(store, request).map(Answer::Frame)
+    }
+
+    fn apply_write(&self, store: &mut KvStore, request: Request) -> Result<Response> {
+        match request {
+            Request::Set { key, value } => {
+                store.set(key.clone(), value)?;
+                lock(&self.ephemeral).release(&key)?;
+                Ok(Response::Ok(None))
+            }
+            Request::Rm { key } => {
+                store.remove(key.clone())?;
+                lock(&self.ephemeral).release(&key)?;
+                Ok(Response::Ok(None))
+            }
+            Request::BulkSet { pairs, .. } => {
+                let keys: Vec<String> = pairs.iter().map(|(key, _)| key.clone()).collect();
+                let count = store.set_batch(pairs)?;
+                let mut ephemeral = lock(&self.ephemeral);
+                for key in keys {
+                    ephemeral.release(&key)?;
+                }
+                Ok(Response::Applied(BulkApplied {
+                    count: count as u64,
+                    last_seq: store.last_sequence(),
+                }))
+            }
+            Request::SwapPrefixes { a, b } => {
+                let stats = store.swap_prefixes(&a, &b)?;
+                lock(&self.ephemeral).swap_prefixes(&a, &b)?;
+                Ok(Response::Swapped(stats))
+            }
+            Request::CompareAndSwap { key, expected, new } => {
+                let swapped = store.compare_and_swap(key.clone(), expected, new)?;
+                if swapped {
+                    lock(&self.ephemeral).release(&key)?;
+                }
+                Ok(Response::Compared { swapped })
+            }
+            Request::Touch { key, ttl_ms } => {
+                let found = store.touch(&key, Duration::from_millis(ttl_ms))?;
+                Ok(Response::Touched { found })
+            }
+            Request::SetEphemeral { key, value } => {
+                // Claim first so the marker lists the key before it can reach the log.
+                let mut ephemeral = lock(&self.ephemeral);
+                ephemeral.claim(&key, self.id)?;
+                if let Err(e) = store.set(key.clone(), value) {
+                    ephemeral.release(&key)?;
+                    return Err(e);
+                }
+                Ok(Response::Ok(None))
+            }
+            // Handled before a request gets here.
+            Request::Get { .. }
+            | Request::SetBegin { .. }
+            | Request::SetChunk { .. }
+            | Request::SetCommit { .. }
+            | Request::CompactDryRun
+            | Request::Ping
+            | Request::Usage { .. }
+            | Request::ClearPoison => Err(KvError::InvalidLogCommand),
+        }
+    }
+
+    fn apply_upload(&self, store: &mut KvStore, upload: &mut Committed) -> Result<Answer> {
+        let (key, len) = (upload.key.clone(), upload.len);
+        store.set_from_reader(key.clone(), upload.value()?, len)?;
+        lock(&self.ephemeral).release(&key)?;
+        Ok(Response::Ok(None).into())
+    }
+
+    fn log_request(&self, log: &Logger, request: &Request) {
+        match *request {
+            Request::Get { ref key }
+            | Request::Rm { ref key }
+            | Request::CompareAndSwap { ref key, .. } => {
+                debug!(log, "request"; "op" => request.op(), "key" => %Truncated::new(key));
+            }
+            Request::Set { ref key, ref value } | Request::SetEphemeral { ref key, ref value } => {
+                debug!(log, "request";
+                    "op" => request.op(),
+                    "key" => %Truncated::new(key),
+                    "value" => %LoggedValue { value, redact: self.redact_values });
+            }
+            Request::SetBegin { ref key, total_len } => {
+                debug!(log, "request";
+                    "op" => request.op(), "key" => %Truncated::new(key), "len" => total_len);
+            }
+            Request::Touch { ref key, ttl_ms } => {
+                debug!(log, "request";
+                    "op" => request.op(), "key" => %Truncated::new(key), "ttl_ms" => ttl_ms);
+            }
+            Request::SetChunk { .. }
+            | Request::SetCommit { .. }
+            | Request::CompactDryRun
+            | Request::Ping
+            | Request::ClearPoison => {
+                debug!(log, "request"; "op" => request.op());
+            }
+            Request::Usage { reset } => {
+                debug!(log, "request"; "op" => request.op(), "reset" => reset);
+            }
+            Request::SwapPrefixes { ref a, ref b } => {
+                debug!(log, "request";
+                    "op" => request.op(), "a" => %Truncated::new(a), "b" => %Truncated::new(b));
+            }
+            Request::BulkSet { ref pairs, more } => {
+                debug!(log, "request"; "op" => request.op(), "pairs" => pairs.len(), "more" => more);
+            }
+        }
+    }
+}
+
+/// The class a request is shed as, `None` for admin requests, which never are.
+fn class_of(request: &Request) -> Option<RequestClass> {
+    match *request {
+        Request::Get { .. } => Some(RequestClass::Read),
+        Request::Set { .. }
+        | Request::Rm { .. }
+        | Request::SetEphemeral { .. }
+        | Request::BulkSet { .. }
+        | Request::SwapPrefixes { .. }
+        | Request::CompareAndSwap { .. }
+        | Request::Touch { .. }
+        | Request::SetBegin { .. }
+        | Request::SetChunk { .. }
+        | Request::SetCommit { .. } => Some(RequestClass::Write),
+        Request::CompactDryRun | Request::Ping | Request::Usage { .. } | Request::ClearPoison => {
+            None
+        }
+    }
+}
+
+/// Locks the ephemeral table whether or not a thread panicked holding it; it is only
+/// ever changed alongside the store, which is poisoned in that case.
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+fn panic_message(panic: &(dyn Any + Send)) -> &str {
+    match panic.downcast_ref::<&str>() {
+        Some(message) => message,
+        None => panic
+            .downcast_ref::<String>()
+            .map_or("no message", String::as_str),
+    }
+}
+
+/// What a request is answered with: one frame, or a value sent in chunks.
+enum Answer {
+    Frame(Response),
+    /// A `Get` of a value over `CHUNK_BYTES` or not UTF-8, read from the log as it is sent
+    /// and so after the store is unlocked.
+    Stream {
+        total_len: u64,
+        value: ValueReader,
+    },
+}
+
+impl From<Response> for Answer {
+    fn from(response: Response) -> Answer {
+        Answer::Frame(response)
+    }
+}
+
+/// The answer to a `Get` of `key`. A value that fits a chunk is read whole, and sent as
+/// `Response::Ok` if it is UTF-8; a larger one is left in the log for a `ValueReader`,
+/// once `KvStore::get_reader` has checked its record's CRC, so that a damaged value is
+/// answered with `Response::Err` before any of it is sent.
+fn get_answer(store: &KvStore, key: &str) -> Result<Answer> {
+    if let Some(total_len) = store.value_size(key)? {
+        if total_len > CHUNK_BYTES as u64 {
+            return Ok(match store.get_reader(key)? {
+                Some(value) => Answer::Stream { total_len, value },
+                None => Response::Ok(None).into(),
+            });
+        }
+    }
+    Ok(match store.get_bytes(key)? {
+        None => Response::Ok(None).into(),
+        Some(bytes) => match String::from_utf8(bytes) {
+            Ok(value) => Response::Ok(Some(value)).into(),
+            Err(e) => {
+                let bytes = e.into_bytes();
+                Answer::Stream {
+                    total_len: bytes.len() as u64,
+                    value: ValueReader::buffered(bytes),
+                }
+            }
+        },
+    })
+}
+
+/// Sends a `Get` answer as `ValueBegin`, chunks and `ValueCommit`. A read of the log that
+/// fails part way closes the connection, as the value can no longer be answered with an
+/// error frame.
+fn write_value_stream<W: io::Write>(
+    writer: &mut W,
+    total_len: u64,
+    mut value: ValueReader,
+) -> io::Result<()> {
+    write_frame(writer, &Response::ValueBegin { total_len })?;
+    let mut checksum = Checksum::new();
+    let mut chunk = vec![0; CHUNK_BYTES];
+    loop {
+        let mut filled = 0;
+        while filled < chunk.len() {
+            match value.read(&mut chunk[filled..]) {
+                Ok(0) => break,
+                Ok(n) => filled += n,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        if filled == 0 {
+            break;
+        }
+        checksum.update(&chunk[..filled]);
+        write_frame(
+            writer,
+            &Response::ValueChunk {
+                data: chunk[..filled].to_vec(),
+            },
+        )?;
+    }
+    write_frame(
+        writer,
+        &Response::ValueCommit {
+            checksum: checksum.value(),
+        },
+    )
+}
+
+struct LoggedValue<'a> {
+    value: &'a str,
+    redact: bool,
+}
+
+impl fmt::Display for LoggedValue<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.redact {
+            write!(f, "{}", Redacted(self.value))
+        } else {
+            write!(f, "{}", Truncated::new(self.value))
+        }
+    }
+}
@@ -0,0 +1,41 @@
+//! Merge operators, which let `KvStore::merge` change a key's value without reading it.
+//!
+//! A merge appends its operand to the log and leaves combining it with the value to the
+//! reads that follow: a get applies the operands written since the key was last set, in
+//! order, and compaction does the same once and keeps the result as a set.
+
+use std::fmt;
+use std::sync::Arc;
+
+/// Combines a key's value with a merge operand, e.g. by appending to a list.
+///
+/// A store applies the operands of a key every time the key is read until the log is
+/// compacted, so `merge` must give the same result for the same arguments each time.
+pub trait MergeOperator: Send + Sync {
+    /// The value of `key` after `operand`, given `existing`, its value before it, or `None`
+    /// when it had none.
+    fn merge(&self, key: &str, existing: Option<&str>, operand: &str) -> String;
+}
+
+/// The operator a store was opened with, cloned along with its options.
+#[derive(Clone, Default)]
+pub(crate) struct Merger(Option<Arc<dyn MergeOperator>>);
+
+impl Merger {
+    pub fn new(operator: Box<dyn MergeOperator>) -> Merger {
+        Merger(Some(Arc::from(operator)))
+    }
+
+    pub fn get(&self) -> Option<&dyn MergeOperator> {
+        self.0.as_deref()
+    }
+}
+
+impl fmt::Debug for Merger {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.0 {
+            Some(_) => write!(f, "Merger(operator)"),
+            None => write!(f, "Merger(none)"),
+        }
+    }
+}
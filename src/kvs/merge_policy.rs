@@ -0,0 +1,173 @@
+//! Merge policies, which pick the segments of a segmented log compaction rewrites. Not to
+//! be confused with merge operators (see `merge`), which combine values.
+//!
+//! Compaction offers the policy the sealed segments, oldest first, and rewrites each run
+//! of adjacent segments it picks as one: the records replay still needs go into a segment
+//! under the id of the run's last, and the others are removed, so a run of one rewrites a
+//! segment in place. The segment appends go to is never offered. When compaction runs is
+//! up to `KvStore::compact` and the automatic thresholds, as for a log kept in one file.
+
+use crate::kvs::kv_store::SEGMENT_DEAD_RATIO;
+use std::collections::HashMap;
+use std::fmt;
+use std::ops::Range;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// A sealed segment of the log, as a `MergePolicy` sees it and `KvStore::segment_stats`
+/// reports it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SegmentStats {
+    /// The number in its file name.
+    pub id: u32,
+    /// Length of the segment file, header included.
+    pub bytes: u64,
+    /// What the records a rewrite would keep occupy: those the index, a merge chain or a
+    /// touch points at, and the removes the segment's last rewrite had to keep.
+    pub live_bytes: u64,
+    /// `live_bytes` as a share of the segment's record bytes, 0 for one without records,
+    /// which a rewrite removes.
+    pub live_ratio: f64,
+    /// Since the segment was sealed or last rewritten, or, for one sealed before the store
+    /// was opened, since its file was last written.
+    pub age: Duration,
+}
+
+/// Picks the runs of sealed segments compaction rewrites.
+pub trait MergePolicy: Send + Sync {
+    /// The runs of `segments` to rewrite, as ranges of indices into it, each rewritten into
+    /// one segment. `segment_size` is the length at which appends roll over to a new
+    /// segment. Runs that are empty, that overlap an earlier one or that run past the end
+    /// are ignored, and nothing is rewritten when none is left.
+    fn select(&self, segments: &[SegmentStats], segment_size: u64) -> Vec<Range<usize>>;
+}
+
+/// Rewrites every sealed segment into one at each compaction, which reclaims everything it
+/// can, as compacting a log kept in one file does, but copies every live byte each time.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MergeAll;
+
+impl MergePolicy for MergeAll {
+    fn select(&self, segments: &[SegmentStats], _segment_size: u64) -> Vec<Range<usize>> {
+        let all = 0..segments.len();
+        match all.is_empty() {
+            true => Vec::new(),
+            false => vec![all],
+        }
+    }
+}
+
+/// The default policy, which rewrites a segment when a good part of it is dead and merges
+/// runs of small ones, but leaves large segments with little dead in them alone however
+/// many there are.
+///
+/// Segments fall into size classes by `live_bytes`: class 0 below `segment_size /
+/// class_factor`, and each class after that `class_factor` times as wide as the one
+/// before. A segment is due for a rewrite when more than `max_dead_ratio` of it is dead,
+/// or when it is smaller than `segment_size` and its class holds more than
+/// `segments_per_class` segments. Each run of adjacent due segments is merged into as few
+/// segments as keep every merge under `segment_size * class_factor` live bytes.
+///
+/// A merge of a crowded class leaves segments a class or more larger, so crowding copies a
+/// record no more than a few times before its segment is out of reach; after that only a
+/// segment more than `max_dead_ratio` dead is copied, which writes less than it reclaims.
+#[derive(Debug, Clone, Copy)]
+pub struct TieredMergePolicy {
+    /// The share of a segment's record bytes that must be dead before it is rewritten on
+    /// that account alone. `SEGMENT_DEAD_RATIO` by default.
+    pub max_dead_ratio: f64,
+    /// How many segments of a class below `segment_size` there may be before they are
+    /// merged. 8 by default.
+    pub segments_per_class: usize,
+    /// How much wider each size class is than the one before. 4 by default.
+    pub class_factor: u64,
+}
+
+impl Default for TieredMergePolicy {
+    fn default() -> TieredMergePolicy {
+        TieredMergePolicy {
+            max_dead_ratio: SEGMENT_DEAD_RATIO,
+            segments_per_class: 8,
+            class_factor: 4,
+        }
+    }
+}
+
+impl TieredMergePolicy {
+    /// The size class of a segment with `live` bytes to keep.
+    fn class(&self, live: u64, segment_size: u64) -> u32 {
+        let factor = self.class_factor.max(2);
+        let mut bound = (segment_size / factor).max(1);
+        let mut class = 0;
+        while live >= bound {
+            class += 1;
+            bound = bound.saturating_mul(factor);
+        }
+        class
+    }
+}
+
+impl MergePolicy for TieredMergePolicy {
+    fn select(&self, segments: &[SegmentStats], segment_size: u64) -> Vec<Range<usize>> {
+        let classes: Vec<u32> = segments
+            .iter()
+            .map(|segment| self.class(segment.live_bytes, segment_size))
+            .collect();
+        let mut crowd = HashMap::<u32, usize>::new();
+        for (segment, &class) in segments.iter().zip(&classes) {
+            if segment.live_bytes < segment_size {
+                *crowd.entry(class).or_default() += 1;
+            }
+        }
+        let due = |n: usize| {
+            let segment = &segments[n];
+            1.0 - segment.live_ratio > self.max_dead_ratio
+                || (segment.live_bytes < segment_size
+                    && crowd[&classes[n]] > self.segments_per_class)
+        };
+
+        let cap = segment_size.saturating_mul(self.class_factor.max(2));
+        let mut runs = Vec::new();
+        let mut run: Option<(Range<usize>, u64)> = None;
+        for (n, segment) in segments.iter().enumerate() {
+            let live = segment.live_bytes;
+            run = match run {
+                Some((range, merged)) if due(n) && merged + live <= cap => {
+                    Some((range.start..n + 1, merged + live))
+                }
+                open => {
+                    runs.extend(open.map(|(range, _)| range));
+                    due(n).then_some((n..n + 1, live))
+                }
+            };
+        }
+        runs.extend(run.map(|(range, _)| range));
+        runs
+    }
+}
+
+/// The policy a store was opened with, cloned along with its options.
+#[derive(Clone)]
+pub(crate) struct Policy(Arc<dyn MergePolicy>);
+
+impl Policy {
+    pub fn new(policy: Box<dyn MergePolicy>) -> Policy {
+        Policy(Arc::from(policy))
+    }
+
+    pub fn get(&self) -> &dyn MergePolicy {
+        &*self.0
+    }
+}
+
+impl Default for Policy {
+    fn default() -> Policy {
+        Policy(Arc::new(TieredMergePolicy::default()))
+    }
+}
+
+impl fmt::Debug for Policy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Policy(merge policy)")
+    }
+}
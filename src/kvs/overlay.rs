@@ -0,0 +1,121 @@
+//! Speculative writes on top of a `KvStore`, started with `KvStore::fork`.
+//!
+//! An overlay holds its sets and removes in memory and never touches the log. Reads see the
+//! store with the overlay's writes applied. `commit` writes them to the store as one batch,
+//! and dropping the overlay discards them.
+//!
+//! The store's index keeps no per-key sequence numbers, so conflicts are found by value:
+//! the first time the overlay reads or writes a key it keeps a checksum of the key's value
+//! in the store, and `commit` fails if any of those keys now holds something else. A key
+//! changed and then changed back in between therefore does not count as a conflict.
+
+use crate::kvs::kv_store::{KvError, KvStore, Result};
+use crate::kvs::protocol::Checksum;
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+
+pub struct StoreOverlay {
+    directory: PathBuf,
+    /// Latest write per key; `None` is a remove.
+    writes: BTreeMap<String, Option<String>>,
+    /// Checksum of each touched key's value in the store when first seen, `None` when absent.
+    observed: HashMap<String, Option<u64>>,
+}
+
+impl StoreOverlay {
+    pub(crate) fn new(directory: &Path) -> StoreOverlay {
+        StoreOverlay {
+            directory: directory.to_path_buf(),
+            writes: BTreeMap::new(),
+            observed: HashMap::new(),
+        }
+    }
+
+    /// The value of `key` in `store` with this overlay's writes applied.
+    pub fn get(&mut self, store: &KvStore, key: &str) -> Result<Option<String>> {
+        if let Some(value) = self.writes.get(key) {
+            return Ok(value.clone());
+        }
+        self.read_base(store, key)
+    }
+
+    pub fn set(&mut self, store: &KvStore, key: String, value: String) -> Result<()> {
+        self.observe(store, &key)?;
+        self.writes.insert(key, Some(value));
+        Ok(())
+    }
+
+    /// Fails with `KvError::RemoveError` when the key does not exist in the overlay's view.
+    pub fn remove(&mut self, store: &KvStore, key: String) -> Result<()> {
+        if self.get(store, &key)?.is_none() {
+            return Err(KvError::RemoveError(key));
+        }
+        self.writes.insert(key, None);
+        Ok(())
+    }
+
+    /// Number of keys with a buffered write.
+    pub fn len(&self) -> usize {
+        self.writes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.writes.is_empty()
+    }
+
+    /// Applies the buffered writes to `store` in one `set_batch`-style append, returning
+    /// how many records were written. Fails with `KvError::OverlayConflict`, writing
+    /// nothing, if a key the overlay touched changed in the store since.
+    ///
+    /// # Panics
+    ///
+    /// If `store` is not open on the directory the overlay was forked from.
+    pub fn commit(self, store: &mut KvStore) -> Result<usize> {
+        assert_eq!(
+            store.directory(),
+            self.directory,
+            "overlay committed to a store it was not forked from"
+        );
+        for (key, observed) in &self.observed {
+            if digest(store.get(key)?.as_deref()) != *observed {
+                return Err(KvError::OverlayConflict(key.clone()));
+            }
+        }
+
+        // A remove of a key the store never had would only add a tombstone.
+        let observed = &self.observed;
+        let writes: Vec<(String, Option<Vec<u8>>)> = self
+            .writes
+            .into_iter()
+            .filter(|(key, value)| value.is_some() || observed[key].is_some())
+            .map(|(key, value)| (key, value.map(String::into_bytes)))
+            .collect();
+        if writes.is_empty() {
+            return Ok(0);
+        }
+        store.write_batch(writes)
+    }
+
+    fn read_base(&mut self, store: &KvStore, key: &str) -> Result<Option<String>> {
+        let value = store.get(key)?;
+        self.observed
+            .entry(key.to_owned())
+            .or_insert_with(|| digest(value.as_deref()));
+        Ok(value)
+    }
+
+    fn observe(&mut self, store: &KvStore, key: &str) -> Result<()> {
+        if !self.observed.contains_key(key) {
+            self.read_base(store, key)?;
+        }
+        Ok(())
+    }
+}
+
+fn digest(value: Option<&str>) -> Option<u64> {
+    value.map(|value| {
+        let mut checksum = Checksum::new();
+        checksum.update(value.as_bytes());
+        checksum.value()
+    })
+}
@@ -0,0 +1,282 @@
+//! Wire format shared by `KvsServer` and `KvsClient`.
+//!
+//! Every message is a frame: a 4-byte big-endian length followed by that many bytes of JSON.
+//! A connection carries any number of request/response pairs, in order.
+//!
+//! Values too large for one frame travel in chunks. An upload is `SetBegin`, any number of
+//! `SetChunk`s and a `SetCommit`, and only the commit is answered; any other request in
+//! between abandons the upload. A `Get` for a value over `CHUNK_BYTES` is answered with
+//! `ValueBegin`, the `ValueChunk`s and a `ValueCommit`. Both commits carry a `Checksum` of
+//! the whole value. Chunks carry bytes, in base64 inside the frame's JSON, so a value sent
+//! in chunks need not be UTF-8.
+
+use crate::kvs::base64;
+use crate::kvs::kv_store::{CompactionEstimate, PrefixUsage, SwapStats};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::io::{self, Read, Write};
+
+/// Frames larger than this are rejected instead of being buffered.
+pub const MAX_FRAME_BYTES: usize = 64 * 1024 * 1024;
+
+/// Most of one `BulkSet` batch a server holds before refusing it, counting each pair's
+/// key and value and `BATCH_PAIR_OVERHEAD` more.
+pub const MAX_BATCH_BYTES: usize = 256 * 1024 * 1024;
+
+/// What a pair counts for in a batch besides its key and value, so that a batch of empty
+/// pairs is held to the limit too.
+pub const BATCH_PAIR_OVERHEAD: usize = 64;
+
+/// Largest piece of a value sent in one `SetChunk` or `ValueChunk`; in base64 it takes
+/// a third more of the frame.
+pub const CHUNK_BYTES: usize = 64 * 1024;
+
+/// `Response::Err` message of a request shed under load; see `shedding`.
+pub const OVERLOADED: &str = "overloaded, retry later";
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum Request {
+    Get {
+        key: String,
+    },
+    Set {
+        key: String,
+        value: String,
+    },
+    Rm {
+        key: String,
+    },
+    /// Set a key that the server removes again once this connection closes.
+    SetEphemeral {
+        key: String,
+        value: String,
+    },
+    /// Start a chunked set of a `total_len`-byte value.
+    SetBegin {
+        key: String,
+        total_len: u64,
+    },
+    SetChunk {
+        #[serde(with = "chunk_data")]
+        data: Vec<u8>,
+    },
+    /// Store the uploaded value if it has `total_len` bytes matching `checksum`.
+    SetCommit {
+        checksum: u64,
+    },
+    /// Report what compacting the log would reclaim, without compacting.
+    CompactDryRun,
+    /// Set every pair at once, answered with `Applied`. A batch too large for one frame is
+    /// sent as several, all but the last with `more` set; those get no answer, and nothing
+    /// is applied until the last arrives. A batch over the server's `max_batch_bytes` is
+    /// dropped as soon as it is, the frames after it up to the last are discarded, and the
+    /// last is answered with `Response::Err`.
+    BulkSet {
+        pairs: Vec<(String, String)>,
+        more: bool,
+    },
+    /// Ask for the server's `Health`, e.g. from a load balancer check.
+    Ping,
+    /// Ask for `KvStore::accounting_snapshot`, then clear it if `reset` is set.
+    Usage {
+        reset: bool,
+    },
+    /// Run `KvStore::swap_prefixes`, answered with `Swapped`.
+    SwapPrefixes {
+        a: String,
+        b: String,
+    },
+    /// Run `KvStore::compare_and_swap`, answered with `Compared`.
+    CompareAndSwap {
+        key: String,
+        expected: Option<String>,
+        new: Option<String>,
+    },
+    /// Run `KvStore::clear_poison_and_verify`, answered with `Ok(None)` once the store
+    /// serves requests again.
+    ClearPoison,
+    /// Run `KvStore::touch` with a TTL of `ttl_ms` milliseconds, answered with `Touched`.
+    Touch {
+        key: String,
+        ttl_ms: u64,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum Response {
+    Ok(Option<String>),
+    Err(String),
+    /// An `Rm` for a key that does not exist, reported separately so clients can handle it
+    /// like a local `KvError::RemoveError`.
+    KeyNotFound,
+    /// First frame of a streamed `Get` answer.
+    ValueBegin {
+        total_len: u64,
+    },
+    ValueChunk {
+        #[serde(with = "chunk_data")]
+        data: Vec<u8>,
+    },
+    ValueCommit {
+        checksum: u64,
+    },
+    CompactionEstimate(CompactionEstimate),
+    /// A whole `BulkSet` batch was applied.
+    Applied(BulkApplied),
+    Health(Health),
+    Usage(Vec<(String, PrefixUsage)>),
+    Swapped(SwapStats),
+    /// Whether a `CompareAndSwap` found the expected value and so wrote the new one.
+    Compared {
+        swapped: bool,
+    },
+    /// Whether a `Touch` found the key and so gave it the new deadline.
+    Touched {
+        found: bool,
+    },
+}
+
+/// What a `BulkSet` applied.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct BulkApplied {
+    /// Pairs written, repeats of a key included.
+    pub count: u64,
+    /// `KvStore::last_sequence` after the batch: the number of its last pair, or of the
+    /// last write before it for an empty batch.
+    pub last_seq: u64,
+}
+
+/// Answer to `Ping`. A server with `durability_lost` or `append_poisoned` set still
+/// answers reads but should be taken out of rotation for writes; one with `poisoned` set
+/// answers nothing until cleared.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(default)]
+pub struct Health {
+    /// An fsync of the log failed; see `KvStore::sync`.
+    pub durability_lost: bool,
+    /// An append failed part way and the log could not be truncated back.
+    pub append_poisoned: bool,
+    /// Requests waiting for or holding the store, this one included.
+    pub queue_depth: u64,
+    /// Requests answered `OVERLOADED` since the server started; see `shedding`.
+    pub shed_reads: u64,
+    pub shed_writes: u64,
+    /// A request panicked and every request fails until `ClearPoison`.
+    pub poisoned: bool,
+    /// The store is still warming up (see `KvStore::ready`). Requests are answered
+    /// correctly, only slower, so a load balancer can send it a share of traffic first.
+    pub warming_up: bool,
+}
+
+impl Health {
+    pub fn is_healthy(&self) -> bool {
+        !self.durability_lost && !self.append_poisoned && !self.poisoned
+    }
+}
+
+impl Request {
+    /// Name of the operation, for logging.
+    pub fn op(&self) -> &'static str {
+        match *self {
+            Request::Get { .. } => "get",
+            Request::Set { .. } => "set",
+            Request::Rm { .. } => "rm",
+            Request::SetEphemeral { .. } => "set_ephemeral",
+            Request::SetBegin { .. } => "set_begin",
+            Request::SetChunk { .. } => "set_chunk",
+            Request::SetCommit { .. } => "set_commit",
+            Request::CompactDryRun => "compact_dry_run",
+            Request::BulkSet { .. } => "bulk_set",
+            Request::Ping => "ping",
+            Request::Usage { .. } => "usage",
+            Request::SwapPrefixes { .. } => "swap_prefixes",
+            Request::CompareAndSwap { .. } => "compare_and_swap",
+            Request::ClearPoison => "clear_poison",
+            Request::Touch { .. } => "touch",
+        }
+    }
+}
+
+/// FNV-1a over a value's bytes, fed one chunk at a time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Checksum(u64);
+
+impl Default for Checksum {
+    fn default() -> Checksum {
+        Checksum(0xcbf2_9ce4_8422_2325)
+    }
+}
+
+impl Checksum {
+    pub fn new() -> Checksum {
+        Checksum::default()
+    }
+
+    pub fn update(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 = (self.0 ^ u64::from(*byte)).wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+
+    pub fn value(&self) -> u64 {
+        self.0
+    }
+}
+
+/// The bytes of a chunk as a base64 string, since a frame is JSON.
+mod chunk_data {
+    use super::base64;
+    use serde::de::{self, Deserialize, Deserializer};
+    use serde::Serializer;
+
+    pub fn serialize<S: Serializer>(data: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&base64::encode(data))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        let text = String::deserialize(deserializer)?;
+        base64::decode(&text).map_err(|_| de::Error::custom("chunk data is not valid base64"))
+    }
+}
+
+pub fn write_frame<W: Write, T: Serialize>(writer: &mut W, message: &T) -> io::Result<()> {
+    let payload = serde_json::to_vec(message)?;
+    if payload.len() > MAX_FRAME_BYTES {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("frame of {} bytes exceeds the frame limit", payload.len()),
+        ));
+    }
+    writer.write_all(&(payload.len() as u32).to_be_bytes())?;
+    writer.write_all(&payload)?;
+    writer.flush()
+}
+
+/// Reads one frame, returning `None` on a clean end of stream between frames.
+pub fn read_frame<R: Read, T: DeserializeOwned>(reader: &mut R) -> io::Result<Option<T>> {
+    read_frame_limited(reader, MAX_FRAME_BYTES)
+}
+
+/// `read_frame` rejecting frames over `max_frame_bytes` instead of `MAX_FRAME_BYTES`.
+pub fn read_frame_limited<R: Read, T: DeserializeOwned>(
+    reader: &mut R,
+    max_frame_bytes: usize,
+) -> io::Result<Option<T>> {
+    let mut header = [0u8; 4];
+    match reader.read_exact(&mut header) {
+        Ok(()) => {}
+        Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+
+    let len = u32::from_be_bytes(header) as usize;
+    if len > max_frame_bytes {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("frame of {} bytes exceeds the frame limit", len),
+        ));
+    }
+    let mut payload = vec![0; len];
+    reader.read_exact(&mut payload)?;
+    Ok(Some(serde_json::from_slice(&payload)?))
+}
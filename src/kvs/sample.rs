@@ -0,0 +1,80 @@
+//! Random samples of a store's keys, for `KvStore::random_key` and `KvStore::sample_keys`.
+//!
+//! Each key is given a priority hashed from the key and a salt drawn for the sample, and
+//! the sample is the `n` keys of lowest priority. That is a uniform pick of `n` keys, held
+//! `n` at a time as the index is walked, and it does not depend on the order the index
+//! yields keys in, which for an in-memory index changes from one process to the next. So a
+//! store seeded with `KvStoreOptions::sample_seed` draws the same samples on every run.
+
+use crate::kvs::protocol::Checksum;
+use std::cell::Cell;
+use std::collections::hash_map::RandomState;
+use std::collections::BinaryHeap;
+use std::hash::{BuildHasher, Hasher};
+
+/// Draws the salt of each sample: SplitMix64 from the seed, or from one of the process's
+/// choosing.
+#[derive(Debug, Clone)]
+pub(crate) struct Sampler {
+    state: Cell<u64>,
+}
+
+impl Sampler {
+    pub fn new(seed: Option<u64>) -> Sampler {
+        let seed = seed.unwrap_or_else(|| RandomState::new().build_hasher().finish());
+        Sampler {
+            state: Cell::new(seed),
+        }
+    }
+
+    /// Starts the next sample, of up to `n` keys.
+    pub fn sample(&self, n: usize) -> Sample {
+        let state = self.state.get().wrapping_add(0x9e37_79b9_7f4a_7c15);
+        self.state.set(state);
+        Sample {
+            n,
+            salt: mix(state),
+            kept: BinaryHeap::new(),
+        }
+    }
+}
+
+pub(crate) struct Sample {
+    n: usize,
+    salt: u64,
+    /// The keys of lowest priority so far, the highest of them on top.
+    kept: BinaryHeap<(u64, String)>,
+}
+
+impl Sample {
+    pub fn offer(&mut self, key: &str) {
+        if self.n == 0 {
+            return;
+        }
+        let mut checksum = Checksum::new();
+        checksum.update(&self.salt.to_le_bytes());
+        checksum.update(key.as_bytes());
+        let priority = mix(checksum.value());
+        if self.kept.len() < self.n {
+            self.kept.push((priority, key.to_owned()));
+        } else if let Some(mut highest) = self.kept.peek_mut() {
+            if (priority, key) < (highest.0, highest.1.as_str()) {
+                *highest = (priority, key.to_owned());
+            }
+        }
+    }
+
+    /// The keys sampled, in key order.
+    pub fn into_keys(self) -> Vec<String> {
+        let mut keys: Vec<String> = self.kept.into_iter().map(|(_, key)| key).collect();
+        keys.sort();
+        keys
+    }
+}
+
+/// The SplitMix64 finalizer, spreading every bit of `z` over the result.
+fn mix(mut z: u64) -> u64 {
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
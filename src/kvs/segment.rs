@@ -0,0 +1,80 @@
+//! Names of the numbered files a segmented log is kept in; see
+//! `KvStoreOptions::segment_size`.
+//!
+//! Segment `n` of a store is its file prefix followed by `n` in at least six digits and
+//! `.log`, so the default store's are `000001.log`, `000002.log` and on, and those of the
+//! store named `name` are `name.000001.log` and on. Ids start at 1 and only grow: appends
+//! roll over to the segment after the last, and compaction rewrites a segment under its
+//! own name, merges a run of them under the name of the run's last or removes them, so
+//! replaying the segments in id order replays the writes in the order they were made,
+//! gaps and all.
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// What every segment's file name ends with.
+const EXTENSION: &str = ".log";
+
+/// The fewest digits of an id in a file name.
+const ID_DIGITS: usize = 6;
+
+/// What the name of the file that marks a split of a log in one file into segments under
+/// way ends with; see `split_marker`.
+const SPLIT_MARKER: &str = "segments.split";
+
+/// The file name of segment `id` of the store whose file names start with `prefix`.
+pub(crate) fn file_name(prefix: &str, id: u32) -> String {
+    format!("{}{:0width$}{}", prefix, id, EXTENSION, width = ID_DIGITS)
+}
+
+/// The file name of the marker that is there for as long as a writable open splits the
+/// log in one file of the store whose file names start with `prefix` into segments. With
+/// it there, the segments beside that file are a split cut short, not the store's log.
+pub(crate) fn split_marker(prefix: &str) -> String {
+    format!("{}{}", prefix, SPLIT_MARKER)
+}
+
+/// The id of the segment that `name` is the file of, for the store whose file names start
+/// with `prefix`.
+pub(crate) fn parse(prefix: &str, name: &str) -> Option<u32> {
+    let digits = name.strip_prefix(prefix)?.strip_suffix(EXTENSION)?;
+    if !is_id(digits) {
+        return None;
+    }
+    digits.parse().ok().filter(|&id| id > 0)
+}
+
+/// Whether `stem`, a file name less `.log`, ends in a segment id: all of it for the
+/// default store's segments, or what follows a store's name and a dot. Returns the store's
+/// name, empty for the default store.
+pub(crate) fn store_of(stem: &str) -> Option<&str> {
+    match stem.rsplit_once('.') {
+        Some((name, digits)) if is_id(digits) => Some(name),
+        None if is_id(stem) => Some(""),
+        _ => None,
+    }
+}
+
+/// Whether `digits` could be a segment id in a file name.
+pub(crate) fn is_id(digits: &str) -> bool {
+    digits.len() >= ID_DIGITS && digits.bytes().all(|b| b.is_ascii_digit())
+}
+
+/// The ids of the segments in `dir` of the store whose file names start with `prefix`, in
+/// order.
+pub(crate) fn list(dir: &Path, prefix: &str) -> io::Result<Vec<u32>> {
+    let mut ids = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let id = match entry.file_name().to_str().and_then(|n| parse(prefix, n)) {
+            Some(id) => id,
+            None => continue,
+        };
+        if entry.file_type()?.is_file() {
+            ids.push(id);
+        }
+    }
+    ids.sort_unstable();
+    Ok(ids)
+}
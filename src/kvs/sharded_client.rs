@@ -0,0 +1,185 @@
+//! Client that spreads keys over several independent `KvsServer`s.
+//!
+//! Each node is placed on a hash ring at `replicas_per_node` points derived only from its
+//! address, and a key belongs to the first point at or after its own hash. Clients given the
+//! same nodes, in any order, therefore agree on every key's owner. A node that cannot be
+//! reached is reported as `KvError::ShardUnavailable`; its keys are never sent elsewhere.
+
+use crate::kvs::kv_store::{KvError, Result};
+use crate::kvs::kvs_client::{KvsClient, KvsClientOptions};
+use crate::kvs::protocol::Checksum;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+/// Ring points per node used by `kvs-client --nodes`.
+pub const DEFAULT_REPLICAS_PER_NODE: u32 = 128;
+
+pub struct ShardedKvsClient {
+    nodes: Vec<SocketAddr>,
+    /// `(point, index into nodes)`, sorted by point.
+    ring: Vec<(u64, usize)>,
+    /// Connections are made on first use and dropped when they fail, so a node that comes
+    /// back is picked up by the next request.
+    clients: Vec<Option<KvsClient>>,
+    client_options: KvsClientOptions,
+}
+
+impl ShardedKvsClient {
+    /// Builds the ring without connecting; an unreachable node only fails the requests that
+    /// go to it. Fails with `KvError::NoShardNodes` when `nodes` is empty or
+    /// `replicas_per_node` is 0, as no key would have an owner.
+    pub fn new(nodes: Vec<SocketAddr>, replicas_per_node: u32) -> Result<ShardedKvsClient> {
+        ShardedKvsClient::with_options(nodes, replicas_per_node, KvsClientOptions::default())
+    }
+
+    /// `new`, connecting to each node with `client_options`.
+    pub fn with_options(
+        nodes: Vec<SocketAddr>,
+        replicas_per_node: u32,
+        client_options: KvsClientOptions,
+    ) -> Result<ShardedKvsClient> {
+        if nodes.is_empty() || replicas_per_node == 0 {
+            return Err(KvError::NoShardNodes);
+        }
+        let mut nodes = nodes;
+        nodes.sort();
+        nodes.dedup();
+
+        let mut ring = Vec::with_capacity(nodes.len() * replicas_per_node as usize);
+        for (index, node) in nodes.iter().enumerate() {
+            for replica in 0..replicas_per_node {
+                ring.push((ring_hash(format!("{}#{}", node, replica).as_bytes()), index));
+            }
+        }
+        // Nodes are sorted, so ties between equal points break the same way everywhere.
+        ring.sort_unstable();
+
+        let clients = nodes.iter().map(|_| None).collect();
+        Ok(ShardedKvsClient {
+            nodes,
+            ring,
+            clients,
+            client_options,
+        })
+    }
+
+    /// The node that stores `key`.
+    pub fn owner_of(&self, key: &str) -> SocketAddr {
+        self.nodes[self.owner_index(key)]
+    }
+
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        let owner = self.owner_index(&key);
+        self.with_node(owner, |client| client.get(key))
+    }
+
+    pub fn set(&mut self, key: String, value: String) -> Result<()> {
+        let owner = self.owner_index(&key);
+        self.with_node(owner, |client| client.set(key, value))
+    }
+
+    /// Fails with `KvError::RemoveError` when the key does not exist on its owner.
+    pub fn remove(&mut self, key: String) -> Result<()> {
+        let owner = self.owner_index(&key);
+        self.with_node(owner, |client| client.remove(key))
+    }
+
+    /// Returns false when the key does not exist on its owner.
+    pub fn touch(&mut self, key: String, ttl: Duration) -> Result<bool> {
+        let owner = self.owner_index(&key);
+        self.with_node(owner, |client| client.touch(key, ttl))
+    }
+
+    /// Gets every key in `keys` from its owner and returns the values in the order of
+    /// `keys`. The protocol has no batched get, so this costs one request per key, made on
+    /// one connection per owner. Fails as a whole if any owner is unavailable.
+    pub fn multi_get(&mut self, keys: &[String]) -> Result<Vec<Option<String>>> {
+        let mut by_owner: Vec<Vec<usize>> = vec![Vec::new(); self.nodes.len()];
+        for (position, key) in keys.iter().enumerate() {
+            by_owner[self.owner_index(key)].push(position);
+        }
+
+        let mut values = vec![None; keys.len()];
+        for (owner, positions) in by_owner.into_iter().enumerate() {
+            if positions.is_empty() {
+                continue;
+            }
+            self.with_node(owner, |client| {
+                for &position in &positions {
+                    values[position] = client.get(keys[position].clone())?;
+                }
+                Ok(())
+            })?;
+        }
+        Ok(values)
+    }
+
+    /// Sends each owner its share of `pairs` as one `KvsClient::set_bulk` batch, returning
+    /// the total applied. Each node applies its share entirely or not at all, but a node
+    /// failing does not undo the shares other nodes already applied.
+    pub fn set_bulk(&mut self, pairs: Vec<(String, String)>) -> Result<u64> {
+        let mut by_owner: Vec<Vec<(String, String)>> = vec![Vec::new(); self.nodes.len()];
+        for pair in pairs {
+            let owner = self.owner_index(&pair.0);
+            by_owner[owner].push(pair);
+        }
+
+        let mut applied = 0;
+        for (owner, share) in by_owner.into_iter().enumerate() {
+            if !share.is_empty() {
+                applied += self
+                    .with_node(owner, |client| client.set_bulk(share))?
+                    .count;
+            }
+        }
+        Ok(applied)
+    }
+
+    fn owner_index(&self, key: &str) -> usize {
+        let hash = ring_hash(key.as_bytes());
+        let point = self.ring.partition_point(|&(point, _)| point < hash);
+        self.ring[point % self.ring.len()].1
+    }
+
+    /// Runs `request` on node `index`, connecting first if needed. Connection failures
+    /// become `ShardUnavailable` and drop the connection; a timeout also drops it, since
+    /// the client abandons a connection once a request on it timed out. Other errors are
+    /// passed through.
+    fn with_node<T, F>(&mut self, index: usize, request: F) -> Result<T>
+    where
+        F: FnOnce(&mut KvsClient) -> Result<T>,
+    {
+        let node = self.nodes[index];
+        let slot = &mut self.clients[index];
+        if slot.is_none() {
+            let client = KvsClient::connect_with_options(node, self.client_options)
+                .map_err(|_| KvError::ShardUnavailable { node })?;
+            *slot = Some(client);
+        }
+        let client = slot.as_mut().expect("connected above");
+        match request(client) {
+            Err(KvError::ConnectionError(_)) => {
+                *slot = None;
+                Err(KvError::ShardUnavailable { node })
+            }
+            Err(e @ KvError::OperationTimedOut { .. }) => {
+                *slot = None;
+                Err(e)
+            }
+            result => result,
+        }
+    }
+}
+
+/// FNV-1a spread with the MurmurHash3 finalizer; FNV alone maps similar keys such as
+/// `key1`, `key2` to nearby points, which unbalances the ring.
+fn ring_hash(bytes: &[u8]) -> u64 {
+    let mut checksum = Checksum::new();
+    checksum.update(bytes);
+    let mut hash = checksum.value();
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51_afd7_ed55_8ccd);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+    hash ^ (hash >> 33)
+}
@@ -0,0 +1,127 @@
+//! Load shedding on the server's queue depth, set with `KvsServer::load_shedding`.
+//!
+//! Every request that needs the store waits for its lock, so the queue depth is the number
+//! of requests waiting for or holding it. Once that reaches a class's high watermark, new
+//! requests of the class are answered `protocol::OVERLOADED` straight away, without queuing,
+//! until the depth falls to its low watermark. Reads and writes shed independently, so reads
+//! can keep working while writes shed. Admin requests (`Ping`, `Usage`, `CompactDryRun`) are
+//! never shed.
+
+use slog::Logger;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+
+/// Queue depths at which a class of requests starts and stops shedding.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Watermarks {
+    pub high: usize,
+    pub low: usize,
+}
+
+impl Watermarks {
+    /// # Panics
+    ///
+    /// If `low` is not below `high`.
+    pub fn new(high: usize, low: usize) -> Watermarks {
+        assert!(low < high, "low watermark {} is not below {}", low, high);
+        Watermarks { high, low }
+    }
+}
+
+/// Watermarks per class of request; `None` never sheds that class.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LoadShedding {
+    pub reads: Option<Watermarks>,
+    pub writes: Option<Watermarks>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum RequestClass {
+    Read,
+    Write,
+}
+
+impl RequestClass {
+    fn name(self) -> &'static str {
+        match self {
+            RequestClass::Read => "reads",
+            RequestClass::Write => "writes",
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct ClassState {
+    shedding: AtomicBool,
+    shed: AtomicU64,
+}
+
+/// The queue depth and shedding state shared by all connections of a server.
+#[derive(Debug, Default)]
+pub(crate) struct Shedder {
+    policy: LoadShedding,
+    depth: AtomicUsize,
+    reads: ClassState,
+    writes: ClassState,
+}
+
+/// Counts its request in the queue depth until dropped.
+pub(crate) struct Queued<'a>(&'a AtomicUsize);
+
+impl Drop for Queued<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl Shedder {
+    pub fn new(policy: LoadShedding) -> Shedder {
+        Shedder {
+            policy,
+            ..Shedder::default()
+        }
+    }
+
+    /// Decides whether a request of `class` may join the queue, logging when the class
+    /// starts or stops shedding.
+    pub fn admit(&self, class: RequestClass, log: &Logger) -> bool {
+        let (watermarks, state) = match class {
+            RequestClass::Read => (self.policy.reads, &self.reads),
+            RequestClass::Write => (self.policy.writes, &self.writes),
+        };
+        let watermarks = match watermarks {
+            Some(watermarks) => watermarks,
+            None => return true,
+        };
+        let depth = self.depth();
+        if state.shedding.load(Ordering::SeqCst) {
+            if depth <= watermarks.low && state.shedding.swap(false, Ordering::SeqCst) {
+                info!(log, "queue drained, no longer shedding"; "class" => class.name(),
+                    "depth" => depth, "shed" => state.shed.load(Ordering::SeqCst));
+            }
+        } else if depth >= watermarks.high && !state.shedding.swap(true, Ordering::SeqCst) {
+            warn!(log, "queue too deep, shedding"; "class" => class.name(), "depth" => depth);
+        }
+        if state.shedding.load(Ordering::SeqCst) {
+            state.shed.fetch_add(1, Ordering::SeqCst);
+            return false;
+        }
+        true
+    }
+
+    pub fn enqueue(&self) -> Queued<'_> {
+        self.depth.fetch_add(1, Ordering::SeqCst);
+        Queued(&self.depth)
+    }
+
+    pub fn depth(&self) -> usize {
+        self.depth.load(Ordering::SeqCst)
+    }
+
+    /// Requests shed since the server started, as (reads, writes).
+    pub fn shed(&self) -> (u64, u64) {
+        (
+            self.reads.shed.load(Ordering::SeqCst),
+            self.writes.shed.load(Ordering::SeqCst),
+        )
+    }
+}
@@ -0,0 +1,204 @@
+//! Operation counters of a `KvStore`, and the snapshot that carries them across restarts
+//! when `KvStoreOptions::persist_stats` is on.
+//!
+//! The snapshot is a small JSON file in the data directory, rewritten atomically after each
+//! compaction and when the store is dropped, never by an ordinary read or write. It is
+//! advisory: a missing field reads as zero, and a snapshot that cannot be read only costs
+//! the lifetime history, never the open.
+
+use crate::kvs::events::{Events, StoreEvent};
+use crate::kvs::fsutil;
+use serde::{Deserialize, Serialize};
+use std::cell::Cell;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+pub const SNAPSHOT_FILE_NAME: &str = "stats.json";
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct StatCounters {
+    /// Keys written, counting each pair of a batch.
+    pub sets: u64,
+    pub removes: u64,
+    pub gets: u64,
+    pub compactions: u64,
+    /// Log bytes dropped by those compactions.
+    pub bytes_reclaimed: u64,
+    /// Log bytes those compactions wrote, headers included: what they cost in writes on top
+    /// of the records themselves.
+    pub bytes_rewritten: u64,
+    /// Operations tried again after a transient error; see `KvStoreOptions::transient_retry`.
+    pub transient_retries: u64,
+}
+
+impl StatCounters {
+    fn add(&self, other: &StatCounters) -> StatCounters {
+        StatCounters {
+            sets: self.sets + other.sets,
+            removes: self.removes + other.removes,
+            gets: self.gets + other.gets,
+            compactions: self.compactions + other.compactions,
+            bytes_reclaimed: self.bytes_reclaimed + other.bytes_reclaimed,
+            bytes_rewritten: self.bytes_rewritten + other.bytes_rewritten,
+            transient_retries: self.transient_retries + other.transient_retries,
+        }
+    }
+}
+
+/// Result of `KvStore::stats`. Times are seconds since the Unix epoch.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StoreStats {
+    pub since_open: StatCounters,
+    /// Equal to `since_open` unless stats are persisted and an earlier snapshot was read.
+    pub lifetime: StatCounters,
+    /// `None` when the store predates its first snapshot, or that snapshot was lost.
+    pub created_at: Option<u64>,
+    pub last_compaction_at: Option<u64>,
+    /// How long the first get since the open took, `None` before one has succeeded. It
+    /// pays for the cache misses a warm-up is there to take.
+    pub first_get_latency: Option<Duration>,
+    /// Live keys, as `KvStore::len` counts them.
+    pub live_keys: u64,
+    /// Length of the log.
+    pub log_bytes: u64,
+    /// Bytes of the log in records the next compaction drops: sets since overwritten or
+    /// removed, and the removes themselves. Kept up as the store writes, the way
+    /// `KvStore::compact_dry_run` estimates, without reading the log.
+    pub dead_bytes: u64,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+struct Snapshot {
+    counters: StatCounters,
+    created_at: Option<u64>,
+    last_compaction_at: Option<u64>,
+}
+
+/// The counters a store keeps, plus where they are persisted, if anywhere. In `Cell`s
+/// because `KvStore::get` takes `&self`.
+#[derive(Debug, Default)]
+pub(crate) struct Stats {
+    since_open: Cell<StatCounters>,
+    /// Lifetime counters as of the open.
+    base: StatCounters,
+    created_at: Option<u64>,
+    last_compaction_at: Option<u64>,
+    snapshot_path: Option<PathBuf>,
+    /// Counters changed since the snapshot was last written.
+    dirty: Cell<bool>,
+    first_get_latency: Cell<Option<Duration>>,
+}
+
+impl Stats {
+    pub fn in_memory() -> Stats {
+        Stats::default()
+    }
+
+    /// Loads the snapshot at `path`. `new_store` says the log was empty, so a missing
+    /// snapshot means the store is being created now rather than that it predates stats.
+    pub fn load(path: PathBuf, new_store: bool, events: &Events) -> Stats {
+        let snapshot = match fs::read(&path) {
+            Ok(contents) => serde_json::from_slice(&contents).map_err(|e| e.to_string()),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(Snapshot {
+                created_at: if new_store { Some(now()) } else { None },
+                ..Snapshot::default()
+            }),
+            Err(e) => Err(e.to_string()),
+        }
+        .unwrap_or_else(|error| {
+            events.emit(StoreEvent::StatsSnapshotUnreadable {
+                path: path.clone(),
+                error,
+            });
+            Snapshot::default()
+        });
+        Stats {
+            base: snapshot.counters,
+            created_at: snapshot.created_at,
+            last_compaction_at: snapshot.last_compaction_at,
+            snapshot_path: Some(path),
+            dirty: Cell::new(true),
+            ..Stats::default()
+        }
+    }
+
+    pub fn view(&self) -> StoreStats {
+        let since_open = self.since_open.get();
+        StoreStats {
+            since_open,
+            lifetime: self.base.add(&since_open),
+            created_at: self.created_at,
+            last_compaction_at: self.last_compaction_at,
+            first_get_latency: self.first_get_latency.get(),
+            ..StoreStats::default()
+        }
+    }
+
+    fn update(&self, change: impl FnOnce(&mut StatCounters)) {
+        let mut counters = self.since_open.get();
+        change(&mut counters);
+        self.since_open.set(counters);
+        self.dirty.set(true);
+    }
+
+    pub fn record_sets(&self, count: u64) {
+        self.update(|counters| counters.sets += count);
+    }
+
+    pub fn record_remove(&self) {
+        self.update(|counters| counters.removes += 1);
+    }
+
+    pub fn record_get(&self) {
+        self.update(|counters| counters.gets += 1);
+    }
+
+    pub fn record_retry(&self) {
+        self.update(|counters| counters.transient_retries += 1);
+    }
+
+    pub fn first_get_pending(&self) -> bool {
+        self.first_get_latency.get().is_none()
+    }
+
+    pub fn record_first_get(&self, latency: Duration) {
+        self.first_get_latency.set(Some(latency));
+    }
+
+    pub fn record_compaction(&mut self, bytes_reclaimed: u64, bytes_rewritten: u64) {
+        self.update(|counters| {
+            counters.compactions += 1;
+            counters.bytes_reclaimed += bytes_reclaimed;
+            counters.bytes_rewritten += bytes_rewritten;
+        });
+        self.last_compaction_at = Some(now());
+    }
+
+    /// Writes the snapshot if stats are persisted and changed since the last save.
+    pub fn save(&self) -> io::Result<()> {
+        let path = match self.snapshot_path {
+            Some(ref path) if self.dirty.get() => path,
+            _ => return Ok(()),
+        };
+        let snapshot = Snapshot {
+            counters: self.view().lifetime,
+            created_at: self.created_at,
+            last_compaction_at: self.last_compaction_at,
+        };
+        let bytes = serde_json::to_vec(&snapshot).map_err(io::Error::other)?;
+        fsutil::atomic_write(path, &bytes)?;
+        self.dirty.set(false);
+        Ok(())
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or(0)
+}
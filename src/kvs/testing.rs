@@ -0,0 +1,457 @@
+//! Deterministic store fixtures for tests and benchmarks.
+//!
+//! A `FixtureBuilder` writes a data directory of a chosen shape from a seed, plus a
+//! manifest of the expected final contents. Every key's history is derived from the seed
+//! and the key's index alone, so generation streams over the keys instead of keeping the
+//! dataset in memory, and the same seed always produces the same store.
+
+use crate::kvs::codec;
+use crate::kvs::fsutil::IoSite;
+use crate::kvs::kv_store::{KvError, KvStore, KvStoreOptions, Result};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, PoisonError};
+
+/// Armed with `KvStore::inject_short_write`: the next append writes `after_bytes` bytes of
+/// its record, then the following write fails with `error`, as on ENOSPC.
+#[derive(Debug, Clone, Copy)]
+pub struct ShortWrite {
+    pub after_bytes: usize,
+    pub error: io::ErrorKind,
+}
+
+/// Transient errors to inject into stores opened with `KvStoreOptions::transient_faults`;
+/// clones share the same faults, so a test can arm them while a store runs. An armed
+/// operation fails before it touches the file, as an NFS call that times out would.
+#[derive(Debug, Clone, Default)]
+pub struct TransientFaults(Arc<Mutex<HashMap<IoSite, (io::ErrorKind, u32)>>>);
+
+impl TransientFaults {
+    pub fn new() -> TransientFaults {
+        TransientFaults::default()
+    }
+
+    /// Fails the next `times` operations at `site` with `kind`.
+    pub fn arm(&self, site: IoSite, kind: io::ErrorKind, times: u32) {
+        self.faults().insert(site, (kind, times));
+    }
+
+    /// Failures armed at `site` that have not happened yet.
+    pub fn remaining(&self, site: IoSite) -> u32 {
+        self.faults().get(&site).map_or(0, |&(_, times)| times)
+    }
+
+    pub(crate) fn check(&self, site: IoSite) -> io::Result<()> {
+        let mut faults = self.faults();
+        match faults.get_mut(&site) {
+            Some(&mut (kind, ref mut times)) if *times > 0 => {
+                *times -= 1;
+                Err(io::Error::new(kind, "injected transient failure"))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn faults(&self) -> std::sync::MutexGuard<'_, HashMap<IoSite, (io::ErrorKind, u32)>> {
+        self.0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// Environment variable that, set to a number of bytes, makes `options()` give stores that
+/// `max_index_bytes`, so the suite runs against the cold tier of the index. CI runs the
+/// store and compaction tests with it set small enough to evict on nearly every write.
+pub const MAX_INDEX_BYTES_VAR: &str = "KVS_TEST_MAX_INDEX_BYTES";
+
+/// Options the test suite opens stores with: `paranoid_checks` is on, so an offset mistake
+/// fails the write that makes it rather than a later read, and `max_index_bytes` is set
+/// from `MAX_INDEX_BYTES_VAR` if it is.
+pub fn options() -> KvStoreOptions {
+    let options = KvStoreOptions::new().paranoid_checks(true);
+    match std::env::var(MAX_INDEX_BYTES_VAR) {
+        Ok(bytes) => options.max_index_bytes(
+            bytes
+                .parse()
+                .unwrap_or_else(|_| panic!("{} is not a number of bytes", MAX_INDEX_BYTES_VAR)),
+        ),
+        Err(_) => options,
+    }
+}
+
+/// `KvStore::open` with `options()`.
+pub fn open(dir: &Path) -> Result<KvStore> {
+    KvStore::open_with_options(dir, options())
+}
+
+/// The length of the header a log starts with before its first record.
+pub const LOG_HEADER_LEN: usize = codec::HEADER_LEN;
+
+/// The records of the `db.log` in `dir`, without the header before them, for tests that
+/// read the log itself.
+pub fn log_records(dir: &Path) -> Vec<u8> {
+    let mut log = std::fs::read(dir.join("db.log")).unwrap();
+    log.drain(..LOG_HEADER_LEN.min(log.len()));
+    log
+}
+
+/// The payloads of the frames in `records`, the `log_records` of a framed log, for tests
+/// that decode them with another codec.
+pub fn frame_payloads(mut records: &[u8]) -> Vec<Vec<u8>> {
+    let mut payloads = Vec::new();
+    while !records.is_empty() {
+        let len = u32::from_le_bytes(records[..4].try_into().unwrap()) as usize;
+        payloads.push(records[8..8 + len].to_vec());
+        // The CRC and the newline.
+        records = &records[8 + len + 5..];
+    }
+    payloads
+}
+
+/// `payload` as a record of a framed log, newline included, for tests that write records
+/// another codec encoded.
+pub fn log_frame(payload: Vec<u8>) -> Vec<u8> {
+    let mut record = codec::frame(payload, "").unwrap();
+    record.push(b'\n');
+    record
+}
+
+/// File the manifest is written to inside the fixture directory.
+pub const MANIFEST_FILE_NAME: &str = "fixture.manifest";
+
+/// SplitMix64; small, fast and stable across platforms and releases.
+#[derive(Debug, Clone)]
+pub struct FixtureRng(u64);
+
+impl FixtureRng {
+    pub fn new(seed: u64) -> FixtureRng {
+        FixtureRng(seed)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// Uniform in `min..=max`.
+    pub fn range(&mut self, min: usize, max: usize) -> usize {
+        if max <= min {
+            return min;
+        }
+        min + (self.next_u64() % (max - min + 1) as u64) as usize
+    }
+
+    /// True with probability `p`.
+    pub fn chance(&mut self, p: f64) -> bool {
+        ((self.next_u64() >> 11) as f64 / (1u64 << 53) as f64) < p
+    }
+}
+
+/// Distribution of generated key or value lengths, in bytes.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SizeDistribution {
+    Fixed(usize),
+    Uniform {
+        min: usize,
+        max: usize,
+    },
+    /// Mostly `small`, with `large_ratio` of the samples being `large`.
+    Bimodal {
+        small: usize,
+        large: usize,
+        large_ratio: f64,
+    },
+}
+
+impl SizeDistribution {
+    fn sample(&self, rng: &mut FixtureRng) -> usize {
+        match *self {
+            SizeDistribution::Fixed(len) => len,
+            SizeDistribution::Uniform { min, max } => rng.range(min, max),
+            SizeDistribution::Bimodal {
+                small,
+                large,
+                large_ratio,
+            } => {
+                if rng.chance(large_ratio) {
+                    large
+                } else {
+                    small
+                }
+            }
+        }
+    }
+}
+
+/// Parses `N`, `MIN-MAX` or `SMALL/LARGE@RATIO`.
+impl FromStr for SizeDistribution {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<SizeDistribution, String> {
+        let parse = |n: &str| {
+            n.trim()
+                .parse::<usize>()
+                .map_err(|_| format!("invalid size `{}`", n))
+        };
+
+        if let Some((sizes, ratio)) = s.split_once('@') {
+            let (small, large) = sizes
+                .split_once('/')
+                .ok_or_else(|| format!("expected SMALL/LARGE@RATIO, got `{}`", s))?;
+            let large_ratio = ratio
+                .trim()
+                .parse::<f64>()
+                .map_err(|_| format!("invalid ratio `{}`", ratio))?;
+            Ok(SizeDistribution::Bimodal {
+                small: parse(small)?,
+                large: parse(large)?,
+                large_ratio,
+            })
+        } else if let Some((min, max)) = s.split_once('-') {
+            Ok(SizeDistribution::Uniform {
+                min: parse(min)?,
+                max: parse(max)?,
+            })
+        } else {
+            Ok(SizeDistribution::Fixed(parse(s)?))
+        }
+    }
+}
+
+/// Builds a store directory of a given shape from a seed.
+#[derive(Debug, Clone)]
+pub struct FixtureBuilder {
+    seed: u64,
+    key_count: usize,
+    key_len: SizeDistribution,
+    value_len: SizeDistribution,
+    overwrite_factor: f64,
+    delete_ratio: f64,
+    compact: bool,
+}
+
+/// A generated fixture directory.
+#[derive(Debug, Clone)]
+pub struct Fixture {
+    pub dir: PathBuf,
+    pub manifest: FixtureManifest,
+}
+
+/// Expected final contents of a fixture: one JSON line per live key with the length and
+/// FNV-1a hash of its value, so huge values are not duplicated.
+#[derive(Debug, Clone)]
+pub struct FixtureManifest {
+    path: PathBuf,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct ManifestEntry {
+    key: String,
+    len: usize,
+    hash: u64,
+}
+
+struct KeyPlan {
+    key: String,
+    writes: usize,
+    deleted: bool,
+}
+
+impl Default for FixtureBuilder {
+    fn default() -> FixtureBuilder {
+        FixtureBuilder {
+            seed: 0,
+            key_count: 1_000,
+            key_len: SizeDistribution::Uniform { min: 8, max: 24 },
+            value_len: SizeDistribution::Uniform { min: 16, max: 128 },
+            overwrite_factor: 1.0,
+            delete_ratio: 0.0,
+            compact: true,
+        }
+    }
+}
+
+impl FixtureBuilder {
+    pub fn new(seed: u64) -> FixtureBuilder {
+        FixtureBuilder {
+            seed,
+            ..FixtureBuilder::default()
+        }
+    }
+
+    pub fn key_count(mut self, key_count: usize) -> FixtureBuilder {
+        self.key_count = key_count;
+        self
+    }
+
+    pub fn key_len(mut self, key_len: SizeDistribution) -> FixtureBuilder {
+        self.key_len = key_len;
+        self
+    }
+
+    pub fn value_len(mut self, value_len: SizeDistribution) -> FixtureBuilder {
+        self.value_len = value_len;
+        self
+    }
+
+    /// Average number of writes per key; 1.0 writes every key once, 3.5 writes each key three
+    /// or four times.
+    pub fn overwrite_factor(mut self, overwrite_factor: f64) -> FixtureBuilder {
+        self.overwrite_factor = overwrite_factor.max(1.0);
+        self
+    }
+
+    /// Fraction of keys whose last operation is a remove.
+    pub fn delete_ratio(mut self, delete_ratio: f64) -> FixtureBuilder {
+        self.delete_ratio = delete_ratio.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Compact the store once everything has been written.
+    pub fn compact(mut self, compact: bool) -> FixtureBuilder {
+        self.compact = compact;
+        self
+    }
+
+    /// Writes the fixture into `dir`, creating it if needed.
+    pub fn build(&self, dir: &Path) -> Result<Fixture> {
+        // A fixed write time, or no two builds would write the same log.
+        let options = KvStoreOptions::new().create_if_missing(true).write_time(0);
+        let mut store = KvStore::open_with_options(dir, options)?;
+
+        // Interleave the keys' writes round by round, so overwrites land far apart in the
+        // log the way real churn does.
+        let max_writes = self.overwrite_factor.ceil() as usize;
+        for round in 0..max_writes {
+            for index in 0..self.key_count {
+                let plan = self.plan(index);
+                if round < plan.writes {
+                    store.set(plan.key, self.value(index, round))?;
+                }
+            }
+        }
+        for index in 0..self.key_count {
+            let plan = self.plan(index);
+            if plan.deleted {
+                store.remove(plan.key)?;
+            }
+        }
+        if self.compact {
+            store.compact_log()?;
+        }
+        drop(store);
+
+        let path = dir.join(MANIFEST_FILE_NAME);
+        let mut writer = BufWriter::new(File::create(&path)?);
+        for index in 0..self.key_count {
+            let plan = self.plan(index);
+            if plan.deleted {
+                continue;
+            }
+            let value = self.value(index, plan.writes - 1);
+            let entry = ManifestEntry {
+                key: plan.key,
+                len: value.len(),
+                hash: fnv1a(value.as_bytes()),
+            };
+            writeln!(writer, "{}", serde_json::to_string(&entry)?)?;
+        }
+        writer.flush()?;
+
+        Ok(Fixture {
+            dir: dir.to_path_buf(),
+            manifest: FixtureManifest { path },
+        })
+    }
+
+    fn key_rng(&self, index: usize) -> FixtureRng {
+        let mut rng =
+            FixtureRng::new(self.seed ^ (index as u64).wrapping_mul(0xa076_1d64_78bd_642f));
+        rng.next_u64();
+        rng
+    }
+
+    fn plan(&self, index: usize) -> KeyPlan {
+        let mut rng = self.key_rng(index);
+
+        // The index keeps keys unique; the random tail pads them to the sampled length.
+        let mut key = format!("{:x}-", index);
+        let len = self.key_len.sample(&mut rng);
+        while key.len() < len {
+            key.push(alphanumeric(rng.next_u64()));
+        }
+
+        let extra = self.overwrite_factor - 1.0;
+        let writes = 1 + extra.floor() as usize + rng.chance(extra.fract()) as usize;
+        let deleted = rng.chance(self.delete_ratio);
+        KeyPlan {
+            key,
+            writes,
+            deleted,
+        }
+    }
+
+    fn value(&self, index: usize, version: usize) -> String {
+        let mut rng = FixtureRng::new(self.key_rng(index).next_u64() ^ version as u64);
+        let len = self.value_len.sample(&mut rng);
+        (0..len).map(|_| alphanumeric(rng.next_u64())).collect()
+    }
+}
+
+impl FixtureManifest {
+    pub fn open(path: &Path) -> FixtureManifest {
+        FixtureManifest {
+            path: path.to_path_buf(),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Scans `store` and compares it to the manifest, returning a description of every
+    /// difference; an empty result means the store holds exactly the expected contents.
+    pub fn verify(&self, store: &KvStore) -> Result<Vec<String>> {
+        let mut expected: HashMap<String, (usize, u64)> = HashMap::new();
+        for line in BufReader::new(File::open(&self.path)?).lines() {
+            let entry: ManifestEntry = serde_json::from_str(&line?)?;
+            expected.insert(entry.key, (entry.len, entry.hash));
+        }
+
+        let mut problems = Vec::new();
+        let mut seen = 0;
+        for key in store.index_keys()? {
+            let value = store.get(&key)?.ok_or(KvError::ReadLogError)?;
+            match expected.get(&key) {
+                Some(&(len, hash)) if len == value.len() && hash == fnv1a(value.as_bytes()) => {
+                    seen += 1;
+                }
+                Some(_) => problems.push(format!("value of {} differs from the manifest", key)),
+                None => problems.push(format!("unexpected key {}", key)),
+            }
+        }
+        if seen < expected.len() {
+            for key in expected.keys() {
+                if !store.index_contains(key)? {
+                    problems.push(format!("missing key {}", key));
+                }
+            }
+        }
+        Ok(problems)
+    }
+}
+
+fn alphanumeric(random: u64) -> char {
+    const CHARS: &[u8] = b"abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789";
+    CHARS[(random % CHARS.len() as u64) as usize] as char
+}
+
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
@@ -0,0 +1,126 @@
+//! A `KvStore` whose keys and values are any serde types rather than strings.
+//!
+//! Keys are stored as canonical JSON: serialized through `serde_json::Value`, whose objects
+//! keep their fields sorted, so a key always maps to the same store key however its type
+//! orders its fields. Two keys are the same key exactly when their JSON is the same, which
+//! is what lets a reopened log rebuild the same index. Types that serialize the same value
+//! differently from one call to the next, such as a `HashSet`, do not make usable keys.
+
+use crate::kvs::kv_map::{self, MAX_JSON_DEPTH};
+use crate::kvs::kv_store::{CompactionStats, KvError, KvStore, KvStoreOptions, Result};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::marker::PhantomData;
+use std::path::Path;
+
+/// A store of `K` to `V`, which owns its `KvStore` and every key in it. Values are stored
+/// as JSON, the way `KvMap` stores them.
+pub struct TypedStore<K, V> {
+    store: KvStore,
+    _marker: PhantomData<fn(K, V)>,
+}
+
+impl<K: Serialize + DeserializeOwned, V: Serialize + DeserializeOwned> TypedStore<K, V> {
+    pub fn open(dir: &Path) -> Result<TypedStore<K, V>> {
+        KvStore::open(dir).map(TypedStore::new)
+    }
+
+    pub fn open_with_options(dir: &Path, options: KvStoreOptions) -> Result<TypedStore<K, V>> {
+        KvStore::open_with_options(dir, options).map(TypedStore::new)
+    }
+
+    /// Wraps a store all of whose keys were written by a `TypedStore<K, V>`.
+    pub fn new(store: KvStore) -> TypedStore<K, V> {
+        TypedStore {
+            store,
+            _marker: PhantomData,
+        }
+    }
+
+    pub fn into_inner(self) -> KvStore {
+        self.store
+    }
+
+    /// The underlying store, for its stats and maintenance operations.
+    pub fn store(&self) -> &KvStore {
+        &self.store
+    }
+
+    pub fn set(&mut self, key: &K, value: &V) -> Result<()> {
+        let store_key = encode_key(key)?;
+        let serialized = kv_map::encode_value(&store_key, value, MAX_JSON_DEPTH)?;
+        self.store.set(store_key, serialized)
+    }
+
+    pub fn get(&self, key: &K) -> Result<Option<V>> {
+        self.get_encoded(&encode_key(key)?)
+    }
+
+    /// Removes the key, returning whether it was present.
+    pub fn remove(&mut self, key: &K) -> Result<bool> {
+        let store_key = encode_key(key)?;
+        if !self.store.index_contains(&store_key)? {
+            return Ok(false);
+        }
+        self.store.remove(store_key)?;
+        Ok(true)
+    }
+
+    pub fn len(&self) -> usize {
+        self.store.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.store.is_empty()
+    }
+
+    pub fn compact(&mut self) -> Result<CompactionStats> {
+        self.store.compact()
+    }
+
+    /// Every key, in the order of their JSON rather than of `K`.
+    pub fn keys(&self) -> Result<Vec<K>> {
+        self.store
+            .keys()?
+            .map(|store_key| decode_key(&store_key))
+            .collect()
+    }
+
+    /// Every entry, in the order of `keys`.
+    pub fn iter(&self) -> impl Iterator<Item = Result<(K, V)>> + '_ {
+        let (keys, error) = match self.store.index_keys() {
+            Ok(keys) => (keys, None),
+            Err(e) => (Vec::new(), Some(e)),
+        };
+        error.into_iter().map(Err).chain(
+            keys.into_iter()
+                .filter_map(move |store_key| self.decode_entry(&store_key).transpose()),
+        )
+    }
+
+    fn get_encoded(&self, store_key: &str) -> Result<Option<V>> {
+        match self.store.get(store_key)? {
+            Some(value) => kv_map::decode_value(store_key, &value).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn decode_entry(&self, store_key: &str) -> Result<Option<(K, V)>> {
+        let key = decode_key(store_key)?;
+        Ok(self.get_encoded(store_key)?.map(|value| (key, value)))
+    }
+}
+
+/// The canonical JSON of `key`, the string it is stored under.
+fn encode_key<K: Serialize>(key: &K) -> Result<String> {
+    serde_json::to_value(key)
+        .and_then(|value| serde_json::to_string(&value))
+        .map_err(|e| KvError::KeySerializationError(e.to_string()))
+}
+
+fn decode_key<K: DeserializeOwned>(store_key: &str) -> Result<K> {
+    serde_json::from_str(store_key).map_err(|e| KvError::ValueSerializationError {
+        key: store_key.to_owned(),
+        details: format!("key does not decode as the store's key type: {}", e),
+    })
+}
@@ -0,0 +1,145 @@
+//! Server side of chunked sets: the chunks of an upload are spooled to a temp file in the
+//! data directory, so nothing reaches the store until `SetCommit` checks the length and
+//! checksum, and is then copied into the store from the file. The temp file is deleted once
+//! the commit is done with it, on abort, and, for files left by a crash, when the server
+//! starts.
+
+use crate::kvs::fsutil;
+use crate::kvs::protocol::Checksum;
+use std::fs::{self, File};
+use std::io::{self, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+/// `fsutil` temp file kind of upload spools.
+pub const SPOOL_KIND: &str = "upload";
+
+pub struct Upload {
+    key: String,
+    total_len: u64,
+    received: u64,
+    checksum: Checksum,
+    spool: Spool,
+    /// First problem seen, reported when the upload is committed.
+    error: Option<String>,
+}
+
+/// The temp file, removed when dropped.
+struct Spool {
+    path: PathBuf,
+    file: Option<File>,
+}
+
+impl Upload {
+    pub fn begin(dir: &Path, key: String, total_len: u64) -> Upload {
+        let path = fsutil::temp_path(dir, SPOOL_KIND);
+        let (file, error) = match fsutil::create_exclusive(&path) {
+            Ok(file) => (Some(file), None),
+            Err(e) => (None, Some(format!("cannot create upload spool: {}", e))),
+        };
+        Upload {
+            key,
+            total_len,
+            received: 0,
+            checksum: Checksum::new(),
+            spool: Spool { path, file },
+            error,
+        }
+    }
+
+    pub fn push(&mut self, data: &[u8]) {
+        if self.error.is_some() {
+            return;
+        }
+        self.received += data.len() as u64;
+        if self.received > self.total_len {
+            self.error = Some(format!(
+                "upload is longer than the announced {} bytes",
+                self.total_len
+            ));
+            return;
+        }
+        self.checksum.update(data);
+        if let Some(ref mut file) = self.spool.file {
+            if let Err(e) = file.write_all(data) {
+                self.error = Some(format!("cannot write upload spool: {}", e));
+            }
+        }
+    }
+
+    /// An upload that fails at its commit with `error`, spooling nothing in the meantime.
+    pub fn refused(key: String, total_len: u64, error: String) -> Upload {
+        Upload {
+            key,
+            total_len,
+            received: 0,
+            checksum: Checksum::new(),
+            spool: Spool {
+                path: PathBuf::new(),
+                file: None,
+            },
+            error: Some(error),
+        }
+    }
+
+    /// Checks the upload against the commit, returning what it uploaded.
+    pub fn finish(mut self, checksum: u64) -> Result<Committed, String> {
+        if let Some(error) = self.error.take() {
+            return Err(error);
+        }
+        if self.received != self.total_len {
+            return Err(format!(
+                "upload ended after {} of {} bytes",
+                self.received, self.total_len
+            ));
+        }
+        if self.checksum.value() != checksum {
+            return Err("upload checksum mismatch".to_owned());
+        }
+        Ok(Committed {
+            key: self.key,
+            len: self.total_len,
+            spool: self.spool,
+        })
+    }
+}
+
+/// A checked upload: its value is in the spool file, which is removed when this is dropped.
+pub struct Committed {
+    pub key: String,
+    pub len: u64,
+    spool: Spool,
+}
+
+impl Committed {
+    /// The spool file, rewound to the value's first byte.
+    pub fn value(&mut self) -> io::Result<&mut File> {
+        let file =
+            self.spool.file.as_mut().ok_or_else(|| {
+                io::Error::new(io::ErrorKind::NotFound, "upload spool is missing")
+            })?;
+        file.seek(SeekFrom::Start(0))?;
+        Ok(file)
+    }
+}
+
+impl Drop for Spool {
+    fn drop(&mut self) {
+        if self.file.take().is_some() {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+/// Deletes spool files left in `dir` by a server that stopped mid-upload.
+pub fn remove_leftover_spools(dir: &Path) -> io::Result<usize> {
+    let mut removed = 0;
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let prefix = format!(".{}-", SPOOL_KIND);
+        if entry.file_name().to_string_lossy().starts_with(&prefix) {
+            fs::remove_file(entry.path())?;
+            removed += 1;
+        }
+    }
+    Ok(removed)
+}
@@ -0,0 +1,302 @@
+//! Streaming reads of values straight out of the log, for `KvStore::get_reader`.
+//!
+//! A set record starts `{"Set":{"key":"...","value":"`, so the value's JSON string begins
+//! right after the key's and the reader finds it by skipping the key. It then unescapes the
+//! string as it is read, the way the log's records are decoded, and stops at the closing
+//! quote, so it never holds more of the record than its buffer. A value that is not UTF-8
+//! is written in base64, with an `encoding` field between the key and the value (see
+//! `kv_store::BASE64_VALUE_FIELD`), and is decoded as it is read the same way. Records laid
+//! out some other way, as other writers may make them, are decoded whole instead. A set in
+//! a `LogFormat::Bincode` or `LogFormat::MessagePack` log holds its value's bytes as they
+//! are, after their length, so the reader only has to find them.
+
+use crate::kvs::base64;
+use crate::kvs::codec::{self, LogFormat};
+use crate::kvs::kv_store::BASE64_VALUE_FIELD;
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Cursor, Read, Seek, SeekFrom, Take};
+use std::str;
+
+const VALUE_PREFIX: &[u8] = b"{\"Set\":{\"key\":\"";
+const VALUE_FIELD: &[u8] = b",\"value\":\"";
+
+/// The bytes of one value, as `KvStore::get_bytes` would return them.
+pub struct ValueReader {
+    source: Source,
+}
+
+enum Source {
+    Log {
+        log: Take<BufReader<File>>,
+        /// Unescaped bytes that did not fit in the caller's buffer.
+        pending: VecDeque<u8>,
+        done: bool,
+    },
+    /// A value's string in base64.
+    Base64 {
+        log: Take<BufReader<File>>,
+        /// Decoded bytes that did not fit in the caller's buffer.
+        pending: VecDeque<u8>,
+        done: bool,
+    },
+    /// The value's bytes in a frame.
+    Raw(Take<BufReader<File>>),
+    Buffered(Cursor<Vec<u8>>),
+}
+
+impl ValueReader {
+    /// A reader over the value of the `size`-byte set record at `start` of `log`, or `None`
+    /// if the record does not start the usual way.
+    pub(crate) fn locate(mut log: File, start: u64, size: u64) -> io::Result<Option<ValueReader>> {
+        log.seek(SeekFrom::Start(start))?;
+        let mut log = BufReader::new(log).take(size);
+        if !expect(&mut log, VALUE_PREFIX)? || !skip_string(&mut log)? {
+            return Ok(None);
+        }
+        let mut field = vec![0; VALUE_FIELD.len()];
+        if !read_all(&mut log, &mut field)? {
+            return Ok(None);
+        }
+        let pending = VecDeque::new();
+        let source = if field == VALUE_FIELD {
+            Source::Log {
+                log,
+                pending,
+                done: false,
+            }
+        } else if BASE64_VALUE_FIELD.starts_with(&field)
+            && expect(&mut log, &BASE64_VALUE_FIELD[field.len()..])?
+        {
+            Source::Base64 {
+                log,
+                pending,
+                done: false,
+            }
+        } else {
+            return Ok(None);
+        };
+        Ok(Some(ValueReader { source }))
+    }
+
+    /// As `locate`, for a frame of a log of the framed `format`. A frame whose value would
+    /// not end where its options and CRC could start is left to be decoded whole, which
+    /// tells what is wrong.
+    pub(crate) fn locate_frame(
+        format: LogFormat,
+        mut log: File,
+        start: u64,
+        size: u64,
+    ) -> io::Result<Option<ValueReader>> {
+        log.seek(SeekFrom::Start(start))?;
+        let mut log = BufReader::new(log).take(size);
+        let len = match codec::read_to_value(format, &mut log)? {
+            Some(len) => len,
+            None => return Ok(None),
+        };
+        match log.limit().checked_sub(len) {
+            Some(tail) if codec::is_set_tail(format, tail) => {}
+            _ => return Ok(None),
+        }
+        log.set_limit(len);
+        Ok(Some(ValueReader {
+            source: Source::Raw(log),
+        }))
+    }
+
+    /// A reader over a value already read into memory.
+    pub(crate) fn buffered(value: Vec<u8>) -> ValueReader {
+        ValueReader {
+            source: Source::Buffered(Cursor::new(value)),
+        }
+    }
+}
+
+impl Read for ValueReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let (log, pending, done) = match self.source {
+            Source::Buffered(ref mut value) => return value.read(buf),
+            Source::Raw(ref mut value) => return value.read(buf),
+            Source::Base64 {
+                ref mut log,
+                ref mut pending,
+                ref mut done,
+            } => return read_base64(log, pending, done, buf),
+            Source::Log {
+                ref mut log,
+                ref mut pending,
+                ref mut done,
+            } => (log, pending, done),
+        };
+        let mut written = 0;
+        while written < buf.len() {
+            if let Some(byte) = pending.pop_front() {
+                buf[written] = byte;
+                written += 1;
+                continue;
+            }
+            if *done {
+                break;
+            }
+            let available = log.fill_buf()?;
+            if available.is_empty() {
+                return Err(invalid("the value's string is not terminated"));
+            }
+            let plain = available
+                .iter()
+                .position(|&byte| byte == b'"' || byte == b'\\')
+                .unwrap_or(available.len());
+            if plain > 0 {
+                let n = plain.min(buf.len() - written);
+                buf[written..written + n].copy_from_slice(&available[..n]);
+                log.consume(n);
+                written += n;
+                continue;
+            }
+            let quote = available[0] == b'"';
+            log.consume(1);
+            if quote {
+                *done = true;
+            } else {
+                unescape(log, pending)?;
+            }
+        }
+        Ok(written)
+    }
+}
+
+/// `ValueReader::read` of a value's string in base64.
+fn read_base64(
+    log: &mut Take<BufReader<File>>,
+    pending: &mut VecDeque<u8>,
+    done: &mut bool,
+    buf: &mut [u8],
+) -> io::Result<usize> {
+    let mut written = 0;
+    while written < buf.len() {
+        if let Some(byte) = pending.pop_front() {
+            buf[written] = byte;
+            written += 1;
+            continue;
+        }
+        if *done {
+            break;
+        }
+        let available = log.fill_buf()?;
+        if available.is_empty() {
+            return Err(invalid("the value's string is not terminated"));
+        }
+        if available[0] == b'"' {
+            log.consume(1);
+            *done = true;
+            continue;
+        }
+        // Whole groups of four where the buffer has them, otherwise the next four read
+        // one at a time, as a group may straddle the end of the buffer.
+        let text = available
+            .iter()
+            .position(|&byte| byte == b'"')
+            .unwrap_or(available.len());
+        let quads = if text >= 4 {
+            let quads = available[..text / 4 * 4].to_vec();
+            log.consume(quads.len());
+            quads
+        } else {
+            let mut quad = vec![0; 4];
+            log.read_exact(&mut quad)?;
+            quad
+        };
+        let decoded = str::from_utf8(&quads)
+            .ok()
+            .and_then(|text| base64::decode(text).ok())
+            .ok_or_else(|| invalid("the value's string is not valid base64"))?;
+        pending.extend(decoded);
+    }
+    Ok(written)
+}
+
+/// Decodes the escape after a backslash into `out`.
+fn unescape(log: &mut impl Read, out: &mut VecDeque<u8>) -> io::Result<()> {
+    let byte = match read_byte(log)? {
+        b'"' => b'"',
+        b'\\' => b'\\',
+        b'/' => b'/',
+        b'b' => 0x08,
+        b'f' => 0x0c,
+        b'n' => b'\n',
+        b'r' => b'\r',
+        b't' => b'\t',
+        b'u' => {
+            let unit = read_hex(log)?;
+            let code = if (0xd800..0xdc00).contains(&unit) {
+                if read_byte(log)? != b'\\' || read_byte(log)? != b'u' {
+                    return Err(invalid("unpaired surrogate in the value's string"));
+                }
+                let low = read_hex(log)?;
+                if !(0xdc00..0xe000).contains(&low) {
+                    return Err(invalid("unpaired surrogate in the value's string"));
+                }
+                0x10000 + ((unit - 0xd800) << 10) + (low - 0xdc00)
+            } else {
+                unit
+            };
+            let c = char::from_u32(code)
+                .ok_or_else(|| invalid("unpaired surrogate in the value's string"))?;
+            out.extend(c.encode_utf8(&mut [0; 4]).as_bytes());
+            return Ok(());
+        }
+        _ => return Err(invalid("invalid escape in the value's string")),
+    };
+    out.push_back(byte);
+    Ok(())
+}
+
+fn read_byte(log: &mut impl Read) -> io::Result<u8> {
+    let mut byte = [0];
+    log.read_exact(&mut byte)?;
+    Ok(byte[0])
+}
+
+fn read_hex(log: &mut impl Read) -> io::Result<u32> {
+    let mut digits = [0; 4];
+    log.read_exact(&mut digits)?;
+    str::from_utf8(&digits)
+        .ok()
+        .and_then(|digits| u32::from_str_radix(digits, 16).ok())
+        .ok_or_else(|| invalid("invalid \\u escape in the value's string"))
+}
+
+/// Reads `expected` from `log`, returning whether that is what was there.
+fn expect(log: &mut impl Read, expected: &[u8]) -> io::Result<bool> {
+    let mut found = vec![0; expected.len()];
+    Ok(read_all(log, &mut found)? && found == expected)
+}
+
+/// Fills `buf` from `log`, returning false if `log` ends first.
+fn read_all(log: &mut impl Read, buf: &mut [u8]) -> io::Result<bool> {
+    match log.read_exact(buf) {
+        Ok(()) => Ok(true),
+        Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// Skips the rest of a JSON string up to and including its closing quote, returning whether
+/// there was one.
+fn skip_string(log: &mut impl Read) -> io::Result<bool> {
+    loop {
+        match read_byte(log) {
+            Ok(b'"') => return Ok(true),
+            Ok(b'\\') => {
+                read_byte(log)?;
+            }
+            Ok(_) => {}
+            Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(false),
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
@@ -0,0 +1,90 @@
+//! Background warm-up of a store opened with `KvStoreOptions::defer_warm_up`.
+//!
+//! Replaying the log is all a store needs to answer correctly, so a deferred open returns
+//! right after it. A warm-up thread then reads the log and the cold index table through
+//! once, so the first gets find their pages cached instead of paying for the misses, and
+//! `KvStore::ready` turns true when it is done. The thread only reads files by path and
+//! never holds the store, so requests are served while it runs.
+
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex, PoisonError};
+use std::thread;
+use std::time::Duration;
+
+const PREFETCH_CHUNK_BYTES: usize = 1 << 20;
+
+#[derive(Default)]
+struct State {
+    finished: Mutex<bool>,
+    changed: Condvar,
+}
+
+pub(crate) struct WarmUp(Arc<State>);
+
+impl WarmUp {
+    /// Nothing to do: the store is ready when it opens.
+    pub fn finished() -> WarmUp {
+        let warm_up = WarmUp(Arc::default());
+        warm_up.0.finish();
+        warm_up
+    }
+
+    /// Prefetches `files` on a thread of its own after `delay`. Files that cannot be read
+    /// are skipped; the warm-up only saves time.
+    pub fn start(files: Vec<PathBuf>, delay: Duration) -> WarmUp {
+        let warm_up = WarmUp(Arc::default());
+        let state = Arc::clone(&warm_up.0);
+        let spawned = thread::Builder::new()
+            .name("kvs-warm-up".to_owned())
+            .spawn(move || {
+                thread::sleep(delay);
+                for path in files {
+                    let _ = prefetch(&path);
+                }
+                state.finish();
+            });
+        if spawned.is_err() {
+            warm_up.0.finish();
+        }
+        warm_up
+    }
+
+    pub fn is_finished(&self) -> bool {
+        *self
+            .0
+            .finished
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Waits up to `timeout` for the warm-up to finish, returning whether it has.
+    pub fn wait(&self, timeout: Duration) -> bool {
+        let finished = self
+            .0
+            .finished
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let (finished, _) = self
+            .0
+            .changed
+            .wait_timeout_while(finished, timeout, |finished| !*finished)
+            .unwrap_or_else(PoisonError::into_inner);
+        *finished
+    }
+}
+
+impl State {
+    fn finish(&self) {
+        *self.finished.lock().unwrap_or_else(PoisonError::into_inner) = true;
+        self.changed.notify_all();
+    }
+}
+
+fn prefetch(path: &Path) -> io::Result<()> {
+    let mut file = File::open(path)?;
+    let mut buffer = vec![0; PREFETCH_CHUNK_BYTES];
+    while file.read(&mut buffer)? > 0 {}
+    Ok(())
+}
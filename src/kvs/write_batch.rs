@@ -0,0 +1,45 @@
+//! Sets and removes collected to be applied together by `KvStore::apply_batch`.
+//!
+//! A batch is written as one group of records led by a `Group` header, in one append that
+//! is fsynced before the index takes any of it, so replay after a crash finds the whole
+//! batch or none of it: a group cut short at the end of the log is dropped with the rest
+//! of the torn tail. Nothing is encoded until the batch is applied, and a write that fails
+//! to encode fails the batch with nothing written.
+
+/// Writes for `KvStore::apply_batch`, applied in the order they were added, so a later
+/// write of a key wins over an earlier one.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct WriteBatch {
+    writes: Vec<(String, Option<Vec<u8>>)>,
+}
+
+impl WriteBatch {
+    pub fn new() -> WriteBatch {
+        WriteBatch::default()
+    }
+
+    /// Sets `key` to `value`. Like `KvStore::set_batch`, the set carries no expiry, so a
+    /// key set with a TTL loses it.
+    pub fn put(&mut self, key: String, value: String) {
+        self.writes.push((key, Some(value.into_bytes())));
+    }
+
+    /// Removes `key`. The remove is written even if the key does not exist when the batch
+    /// is applied.
+    pub fn delete(&mut self, key: String) {
+        self.writes.push((key, None));
+    }
+
+    /// The number of writes added.
+    pub fn len(&self) -> usize {
+        self.writes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.writes.is_empty()
+    }
+
+    pub(crate) fn into_writes(self) -> Vec<(String, Option<Vec<u8>>)> {
+        self.writes
+    }
+}
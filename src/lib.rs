@@ -1,7 +1,49 @@
+#[macro_use]
+extern crate slog;
+
 mod kvs;
 
+pub use crate::kvs::accounting;
+pub use crate::kvs::backup;
+pub use crate::kvs::backup::BackupManifest;
+pub use crate::kvs::bucket;
+pub use crate::kvs::bucket::Bucket;
+pub use crate::kvs::canonical;
+pub use crate::kvs::changes;
+pub use crate::kvs::changes::{ChangeEvent, ChangeOp, SubscriptionId};
+pub use crate::kvs::cli;
+pub use crate::kvs::dir_lock;
+pub use crate::kvs::display;
+pub use crate::kvs::events;
+pub use crate::kvs::events::StoreEvent;
+pub use crate::kvs::fs_probe;
+pub use crate::kvs::fsutil;
+pub use crate::kvs::kv_map;
+pub use crate::kvs::kv_map::{KeyDeserialize, KeySerialize, KvMap};
 pub use crate::kvs::kv_store;
+pub use crate::kvs::kv_store::{
+    CheckpointInfo, CompactionEstimate, CompactionStats, Direction, ExportFormat, ImportMode,
+    ImportStats, IndexStats, KvError, KvStore, KvStoreOptions, LogFormat, LogPin, OpenReport,
+    PrefixUsage, RefreshStats, Result, Snapshot, StatCounters, StoreStats, SwapStats, SyncPolicy,
+    TornTail, ValueReader,
+};
 pub use crate::kvs::kvs_client;
-pub use crate::kvs::kvs_engine;
+pub use crate::kvs::kvs_client::{KvsClient, KvsClientOptions};
 pub use crate::kvs::kvs_server;
-pub use crate::kvs::sled_kv_engine;
+pub use crate::kvs::kvs_server::KvsServer;
+pub use crate::kvs::merge;
+pub use crate::kvs::merge::MergeOperator;
+pub use crate::kvs::merge_policy;
+pub use crate::kvs::merge_policy::{MergeAll, MergePolicy, SegmentStats, TieredMergePolicy};
+pub use crate::kvs::overlay;
+pub use crate::kvs::overlay::StoreOverlay;
+pub use crate::kvs::protocol;
+pub use crate::kvs::sharded_client;
+pub use crate::kvs::sharded_client::ShardedKvsClient;
+pub use crate::kvs::shedding;
+#[cfg(feature = "test-util")]
+pub use crate::kvs::testing;
+pub use crate::kvs::typed_store;
+pub use crate::kvs::typed_store::TypedStore;
+pub use crate::kvs::write_batch;
+pub use crate::kvs::write_batch::WriteBatch;
@@ -0,0 +1,200 @@
+//! User-visible CLI behaviour, checked against a local store through `kvs` and against a
+//! server through `kvs-client`. `CASES` is the single source of truth for both modes.
+
+use assert_cmd::prelude::*;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::process::{Child, Command, Output, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+/// One CLI invocation and everything it must print.
+struct Step {
+    args: &'static [&'static str],
+    stdout: &'static str,
+    stderr: &'static str,
+    success: bool,
+}
+
+const fn ok(args: &'static [&'static str], stdout: &'static str) -> Step {
+    Step {
+        args,
+        stdout,
+        stderr: "",
+        success: true,
+    }
+}
+
+const fn fails(args: &'static [&'static str], stdout: &'static str) -> Step {
+    Step {
+        args,
+        stdout,
+        stderr: "",
+        success: false,
+    }
+}
+
+/// Each case runs against a fresh, empty store.
+const CASES: &[(&str, &[Step])] = &[
+    (
+        "set then get",
+        &[
+            ok(&["set", "key1", "value1"], ""),
+            ok(&["get", "key1"], "value1\n"),
+        ],
+    ),
+    (
+        "get missing key",
+        &[ok(&["get", "key1"], "Key not found\n")],
+    ),
+    (
+        "repeated sets keep the last value",
+        &[
+            ok(&["set", "key1", "value1"], ""),
+            ok(&["set", "key1", "value2"], ""),
+            ok(&["set", "key1", "value3"], ""),
+            ok(&["get", "key1"], "value3\n"),
+        ],
+    ),
+    (
+        "remove existing key",
+        &[
+            ok(&["set", "key1", "value1"], ""),
+            ok(&["rm", "key1"], ""),
+            ok(&["get", "key1"], "Key not found\n"),
+        ],
+    ),
+    (
+        "remove missing key",
+        &[fails(&["rm", "key1"], "Key not found\n")],
+    ),
+    (
+        "remove twice",
+        &[
+            ok(&["set", "key1", "value1"], ""),
+            ok(&["rm", "key1"], ""),
+            fails(&["rm", "key1"], "Key not found\n"),
+        ],
+    ),
+    (
+        "unicode keys and values",
+        &[
+            ok(&["set", "ключ", "värde 値 🚀"], ""),
+            ok(&["get", "ключ"], "värde 値 🚀\n"),
+        ],
+    ),
+    (
+        "empty value",
+        &[ok(&["set", "key1", ""], ""), ok(&["get", "key1"], "\n")],
+    ),
+    (
+        "values with quotes, escapes and newlines",
+        &[
+            ok(&["set", "key1", "a \"quoted\"\\\n\tvalue"], ""),
+            ok(&["get", "key1"], "a \"quoted\"\\\n\tvalue\n"),
+        ],
+    ),
+];
+
+trait Mode {
+    fn run(&self, args: &[&str]) -> Output;
+}
+
+struct Local(TempDir);
+
+impl Mode for Local {
+    fn run(&self, args: &[&str]) -> Output {
+        Command::cargo_bin("kvs")
+            .unwrap()
+            .args(args)
+            .arg("--dir")
+            .arg(self.0.path())
+            .output()
+            .unwrap()
+    }
+}
+
+struct Remote {
+    _dir: TempDir,
+    server: Child,
+    addr: SocketAddr,
+}
+
+impl Remote {
+    fn start() -> Remote {
+        let dir = TempDir::new().unwrap();
+        let addr = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let server = Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(["--addr", &addr.to_string(), "--engine", "kvs", "--data-dir"])
+            .arg(dir.path())
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap();
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while TcpStream::connect(addr).is_err() {
+            assert!(Instant::now() < deadline, "kvs-server did not start");
+            thread::sleep(Duration::from_millis(20));
+        }
+        Remote {
+            _dir: dir,
+            server,
+            addr,
+        }
+    }
+}
+
+impl Mode for Remote {
+    fn run(&self, args: &[&str]) -> Output {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(["--addr", &self.addr.to_string()])
+            .args(args)
+            .output()
+            .unwrap()
+    }
+}
+
+impl Drop for Remote {
+    fn drop(&mut self) {
+        let _ = self.server.kill();
+        let _ = self.server.wait();
+    }
+}
+
+fn run_cases(mode_name: &str, start: impl Fn() -> Box<dyn Mode>) {
+    for (case, steps) in CASES {
+        let mode = start();
+        for step in steps.iter() {
+            let output = mode.run(step.args);
+            let context = format!("{} / {} / {:?}", mode_name, case, step.args);
+            assert_eq!(
+                String::from_utf8_lossy(&output.stdout),
+                step.stdout,
+                "{}",
+                context
+            );
+            assert_eq!(
+                String::from_utf8_lossy(&output.stderr),
+                step.stderr,
+                "{}",
+                context
+            );
+            assert_eq!(output.status.success(), step.success, "{}", context);
+        }
+    }
+}
+
+#[test]
+fn local_cli_matches_expectations() {
+    run_cases("kvs", || Box::new(Local(TempDir::new().unwrap())));
+}
+
+#[test]
+fn remote_cli_matches_expectations() {
+    run_cases("kvs-client", || Box::new(Remote::start()));
+}
@@ -0,0 +1,138 @@
+use kvs::accounting::OVERFLOW_PREFIX;
+use kvs::testing;
+use kvs::{KvStore, KvsClient, KvsServer, PrefixUsage};
+use std::thread;
+use tempfile::TempDir;
+
+fn open(dir: &TempDir, max_prefixes: usize) -> KvStore {
+    let options = testing::options()
+        .accounting_prefix_depth(2)
+        .accounting_max_prefixes(max_prefixes);
+    KvStore::open_with_options(dir.path(), options).unwrap()
+}
+
+fn usage_of(store: &KvStore, prefix: &str) -> PrefixUsage {
+    store
+        .accounting_snapshot()
+        .into_iter()
+        .find(|(name, _)| name == prefix)
+        .map(|(_, usage)| usage)
+        .unwrap_or_default()
+}
+
+#[test]
+fn counts_ops_and_payload_bytes_per_prefix() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = open(&temp_dir, 10);
+
+    // "acme/users" gets 2 sets of 12 + 5 and 12 + 7 bytes, a remove of 12 and two gets.
+    store
+        .set("acme/users/1".to_owned(), "alice".to_owned())
+        .unwrap();
+    store
+        .set("acme/users/2".to_owned(), "bobbert".to_owned())
+        .unwrap();
+    store.remove("acme/users/2".to_owned()).unwrap();
+    store.get("acme/users/1").unwrap();
+    store.get("acme/users/2").unwrap();
+    // Keys with fewer segments than the depth are their own prefix.
+    store.set("acme".to_owned(), "x".to_owned()).unwrap();
+    store
+        .set_batch(vec![
+            ("beta/logs/a".to_owned(), "1234567890".to_owned()),
+            ("beta/logs/b".to_owned(), "12345".to_owned()),
+        ])
+        .unwrap();
+
+    assert_eq!(
+        store.accounting_snapshot(),
+        vec![
+            (
+                "acme".to_owned(),
+                PrefixUsage {
+                    sets: 1,
+                    bytes_written: 5,
+                    ..PrefixUsage::default()
+                }
+            ),
+            (
+                "acme/users".to_owned(),
+                PrefixUsage {
+                    gets: 2,
+                    sets: 2,
+                    removes: 1,
+                    bytes_read: 5,
+                    bytes_written: 17 + 19 + 12,
+                }
+            ),
+            (
+                "beta/logs".to_owned(),
+                PrefixUsage {
+                    sets: 2,
+                    bytes_written: 21 + 16,
+                    ..PrefixUsage::default()
+                }
+            ),
+        ]
+    );
+
+    store.reset_accounting();
+    assert!(store.accounting_snapshot().is_empty());
+    store.get("beta/logs/a").unwrap();
+    assert_eq!(usage_of(&store, "beta/logs").bytes_read, 10);
+}
+
+#[test]
+fn prefixes_beyond_the_cap_share_the_overflow_bucket() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = open(&temp_dir, 2);
+    for tenant in ["a", "b", "c", "d"] {
+        store
+            .set(format!("{}/t/key", tenant), "value".to_owned())
+            .unwrap();
+    }
+    // A prefix already tracked keeps being counted under its own name.
+    store
+        .set("a/t/other".to_owned(), "value".to_owned())
+        .unwrap();
+
+    let snapshot = store.accounting_snapshot();
+    let prefixes: Vec<&str> = snapshot.iter().map(|(name, _)| name.as_str()).collect();
+    assert_eq!(prefixes, ["a/t", "b/t", OVERFLOW_PREFIX]);
+    assert_eq!(usage_of(&store, "a/t").sets, 2);
+    assert_eq!(
+        usage_of(&store, OVERFLOW_PREFIX),
+        PrefixUsage {
+            sets: 2,
+            bytes_written: 2 * (7 + 5),
+            ..PrefixUsage::default()
+        }
+    );
+}
+
+#[test]
+fn accounting_is_off_by_default() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = testing::open(temp_dir.path()).unwrap();
+    store.set("a/b".to_owned(), "c".to_owned()).unwrap();
+    assert!(store.accounting_snapshot().is_empty());
+}
+
+#[test]
+fn usage_request_reports_and_resets() {
+    let temp_dir = TempDir::new().unwrap();
+    let log = slog::Logger::root(slog::Discard, slog::o!());
+    let server = KvsServer::new("127.0.0.1:0", open(&temp_dir, 10), log).unwrap();
+    let addr = server.local_addr().unwrap();
+    thread::spawn(move || server.listen_forever());
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    client
+        .set("acme/users/1".to_owned(), "alice".to_owned())
+        .unwrap();
+    let usage = client.usage(true).unwrap();
+    assert_eq!(usage.len(), 1);
+    assert_eq!(usage[0].0, "acme/users");
+    assert_eq!(usage[0].1.bytes_written, 17);
+    assert!(client.usage(false).unwrap().is_empty());
+}
@@ -0,0 +1,112 @@
+use kvs::backup::MANIFEST_FILE_NAME;
+use kvs::testing;
+use kvs::{KvError, KvStore};
+use std::fs;
+use std::path::Path;
+use tempfile::TempDir;
+
+fn filled(dir: &Path) -> KvStore {
+    let mut store = testing::open(dir).unwrap();
+    for i in 0..200 {
+        store
+            .set(format!("key{}", i), format!("value{}", i))
+            .unwrap();
+    }
+    for i in 0..50 {
+        store.remove(format!("key{}", i)).unwrap();
+    }
+    store
+}
+
+#[test]
+fn backup_restores_to_a_store_with_the_same_contents() {
+    let temp_dir = TempDir::new().unwrap();
+    let backups = TempDir::new().unwrap();
+    let store = filled(temp_dir.path());
+    let dest = backups.path().join("backup");
+    let manifest = store.backup(&dest).unwrap();
+    assert_eq!(manifest.keys, 150);
+    assert_eq!(manifest.sequence, 250);
+    assert_eq!(
+        manifest.bytes,
+        fs::metadata(dest.join("db.log")).unwrap().len()
+    );
+    assert!(dest.join(MANIFEST_FILE_NAME).is_file());
+
+    let restored = backups.path().join("restored");
+    KvStore::restore(&dest, &restored, false).unwrap();
+    let restored = testing::open(&restored).unwrap();
+    let original: Vec<_> = store.iter().unwrap().map(Result::unwrap).collect();
+    let copied: Vec<_> = restored.iter().unwrap().map(Result::unwrap).collect();
+    assert_eq!(copied, original);
+    assert_eq!(restored.last_sequence(), 250);
+}
+
+#[test]
+fn corrupted_backups_are_refused() {
+    let temp_dir = TempDir::new().unwrap();
+    let backups = TempDir::new().unwrap();
+    let store = filled(temp_dir.path());
+    let dest = backups.path().join("backup");
+    store.backup(&dest).unwrap();
+
+    let log = dest.join("db.log");
+    let mut bytes = fs::read(&log).unwrap();
+    let middle = bytes.len() / 2;
+    bytes[middle] ^= 0x01;
+    fs::write(&log, &bytes).unwrap();
+    let restored = backups.path().join("restored");
+    match KvStore::restore(&dest, &restored, false) {
+        Err(err @ KvError::BackupCorrupt { .. }) => {
+            assert!(err.to_string().contains("checksum"), "{}", err)
+        }
+        other => panic!("expected BackupCorrupt, got {:?}", other),
+    }
+    assert!(!restored.join("db.log").exists());
+
+    // A backup cut short before its manifest was written is refused too.
+    fs::remove_file(dest.join(MANIFEST_FILE_NAME)).unwrap();
+    assert!(matches!(
+        KvStore::restore(&dest, &restored, false),
+        Err(KvError::BackupCorrupt { .. })
+    ));
+    assert!(!restored.join("db.log").exists());
+}
+
+#[test]
+fn restoring_over_a_store_needs_force() {
+    let temp_dir = TempDir::new().unwrap();
+    let backups = TempDir::new().unwrap();
+    let store = filled(temp_dir.path());
+    let dest = backups.path().join("backup");
+    store.backup(&dest).unwrap();
+    drop(store);
+
+    let other = TempDir::new().unwrap();
+    let mut existing = testing::open(other.path()).unwrap();
+    existing
+        .set("only here".to_owned(), "value".to_owned())
+        .unwrap();
+    assert!(matches!(
+        KvStore::restore(&dest, other.path(), true),
+        Err(KvError::StoreLocked(_))
+    ));
+    drop(existing);
+    assert!(matches!(
+        KvStore::restore(&dest, other.path(), false),
+        Err(KvError::DirectoryNotEmpty(_))
+    ));
+    assert_eq!(
+        testing::open(other.path())
+            .unwrap()
+            .get("only here")
+            .unwrap(),
+        Some("value".to_owned())
+    );
+
+    KvStore::restore(&dest, other.path(), true).unwrap();
+    let restored = testing::open(other.path()).unwrap();
+    assert_eq!(restored.get("only here").unwrap(), None);
+    assert_eq!(restored.get("key199").unwrap(), Some("value199".to_owned()));
+    assert_eq!(restored.len(), 150);
+}
@@ -0,0 +1,108 @@
+use kvs::testing::{self, FixtureRng};
+use kvs::{KvStore, KvStoreOptions};
+use std::collections::BTreeMap;
+use std::io::Read;
+use tempfile::TempDir;
+
+/// Characters that line-delimited logs, JSON escaping and UTF-8 lengths tend to get wrong.
+const PALETTE: [char; 14] = [
+    '\n', '\r', '"', '\\', '\t', '\0', '\u{1b}', '\u{2028}', ' ', 'a', 'Z', 'é', '键', '🦀',
+];
+
+fn string(rng: &mut FixtureRng, max_len: usize) -> String {
+    let len = rng.range(1, max_len);
+    let mut s: String = (0..len)
+        .map(|_| PALETTE[rng.range(0, PALETTE.len() - 1)])
+        .collect();
+    if rng.chance(0.2) {
+        s.push_str("\r\n");
+    }
+    s
+}
+
+/// Random sets, batches and removes of awkward keys and values, mirrored in a map.
+fn churn(store: &mut KvStore, seed: u64) -> BTreeMap<String, String> {
+    let mut rng = FixtureRng::new(seed);
+    let mut model = BTreeMap::new();
+    for _ in 0..300 {
+        match rng.range(0, 9) {
+            0..=5 => {
+                let (key, value) = (string(&mut rng, 12), string(&mut rng, 40));
+                store.set(key.clone(), value.clone()).unwrap();
+                model.insert(key, value);
+            }
+            6 | 7 => {
+                let pairs: Vec<(String, String)> = (0..rng.range(1, 4))
+                    .map(|_| (string(&mut rng, 12), string(&mut rng, 40)))
+                    .collect();
+                store.set_batch(pairs.clone()).unwrap();
+                model.extend(pairs);
+            }
+            _ => {
+                let key = match model.keys().nth(rng.range(0, model.len())) {
+                    Some(key) => key.clone(),
+                    None => continue,
+                };
+                store.remove(key.clone()).unwrap();
+                model.remove(&key);
+            }
+        }
+    }
+    model
+}
+
+fn assert_matches(store: &KvStore, model: &BTreeMap<String, String>, context: &str) {
+    for (key, value) in model {
+        assert_eq!(
+            store.get(key).unwrap().as_ref(),
+            Some(value),
+            "{} {:?}",
+            context,
+            key
+        );
+        let mut streamed = String::new();
+        store
+            .get_reader(key)
+            .unwrap()
+            .unwrap()
+            .read_to_string(&mut streamed)
+            .unwrap();
+        assert_eq!(&streamed, value, "{} {:?}", context, key);
+    }
+    let scanned: BTreeMap<String, String> = store.scan_prefix("").unwrap().into_iter().collect();
+    assert_eq!(&scanned, model, "{}", context);
+}
+
+#[test]
+fn awkward_keys_and_values_survive_reopen_and_compaction() {
+    for (seed, options) in [
+        (1, testing::options()),
+        (2, testing::options().compaction_threshold(50)),
+        (3, testing::options().max_index_bytes(512)),
+    ] {
+        let temp_dir = TempDir::new().unwrap();
+        let open = |options: &KvStoreOptions| {
+            KvStore::open_with_options(temp_dir.path(), options.clone()).unwrap()
+        };
+        let mut store = open(&options);
+        let model = churn(&mut store, seed);
+        assert!(model.keys().any(|key| key.contains('\n')));
+        assert!(model.keys().any(|key| key.contains("\r\n")));
+        assert!(model.keys().any(|key| key.contains('"')));
+        assert_matches(&store, &model, &format!("seed {} as written", seed));
+        drop(store);
+
+        let mut store = open(&options);
+        assert_matches(&store, &model, &format!("seed {} reopened", seed));
+        store.compact().unwrap();
+        assert_matches(&store, &model, &format!("seed {} compacted", seed));
+        drop(store);
+
+        let store = open(&options.compact_on_open(false));
+        assert_matches(
+            &store,
+            &model,
+            &format!("seed {} compacted and reopened", seed),
+        );
+    }
+}
@@ -0,0 +1,114 @@
+use kvs::testing;
+use kvs::KvError;
+use std::fs;
+use tempfile::TempDir;
+
+#[test]
+fn buckets_keep_their_keys_apart() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = testing::open(temp_dir.path()).unwrap();
+    store.set("key".to_owned(), "root".to_owned()).unwrap();
+    store
+        .bucket("users")
+        .set("key".to_owned(), "in users".to_owned())
+        .unwrap();
+    // Names and keys holding the separator, or what escapes it, stay apart too.
+    let awkward = ["us\0ers", "users\\", "users\\0", ""];
+    for name in awkward {
+        store
+            .bucket(name)
+            .set("key".to_owned(), format!("in {:?}", name))
+            .unwrap();
+    }
+    store
+        .bucket("users")
+        .set("\0nested\0".to_owned(), "odd key".to_owned())
+        .unwrap();
+
+    assert_eq!(store.get("key").unwrap(), Some("root".to_owned()));
+    let users = store.bucket("users");
+    assert_eq!(users.get("key").unwrap(), Some("in users".to_owned()));
+    assert_eq!(users.get("\0nested\0").unwrap(), Some("odd key".to_owned()));
+    assert_eq!(users.get("missing").unwrap(), None);
+    assert_eq!(
+        users.keys().unwrap(),
+        vec!["\0nested\0".to_owned(), "key".to_owned()]
+    );
+    for name in awkward {
+        let bucket = store.bucket(name);
+        assert_eq!(bucket.get("key").unwrap(), Some(format!("in {:?}", name)));
+        assert_eq!(bucket.keys().unwrap(), vec!["key".to_owned()]);
+    }
+    assert_eq!(
+        store.bucket_names().unwrap(),
+        vec!["", "us\0ers", "users", "users\\", "users\\0"]
+    );
+
+    let mut users = store.bucket("users");
+    users.remove("key".to_owned()).unwrap();
+    match users.remove("key".to_owned()) {
+        Err(KvError::RemoveError(key)) => assert_eq!(key, "key"),
+        other => panic!("expected RemoveError, got {:?}", other),
+    }
+    assert_eq!(store.get("key").unwrap(), Some("root".to_owned()));
+}
+
+#[test]
+fn buckets_survive_compaction_and_reopen_and_drop_whole() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = testing::open(temp_dir.path()).unwrap();
+    for i in 0..100 {
+        let name = if i % 2 == 0 { "even" } else { "odd" };
+        store
+            .bucket(name)
+            .set(format!("key{:03}", i), i.to_string())
+            .unwrap();
+    }
+    store.set("plain".to_owned(), "value".to_owned()).unwrap();
+    store.compact().unwrap();
+    drop(store);
+
+    let mut store = testing::open(temp_dir.path()).unwrap();
+    assert_eq!(store.bucket_names().unwrap(), vec!["even", "odd"]);
+    assert_eq!(store.bucket("odd").keys().unwrap().len(), 50);
+    assert_eq!(
+        store.bucket("even").get("key042").unwrap(),
+        Some("42".to_owned())
+    );
+
+    let before = store.last_sequence();
+    assert_eq!(store.drop_bucket("even").unwrap(), 50);
+    assert_eq!(store.last_sequence(), before + 50);
+    assert_eq!(store.drop_bucket("even").unwrap(), 0);
+    assert_eq!(store.drop_bucket("missing").unwrap(), 0);
+    assert_eq!(store.bucket_names().unwrap(), vec!["odd"]);
+    drop(store);
+
+    let mut store = testing::open(temp_dir.path()).unwrap();
+    assert!(store.bucket("even").keys().unwrap().is_empty());
+    assert_eq!(store.bucket("odd").keys().unwrap().len(), 50);
+    assert_eq!(store.get("plain").unwrap(), Some("value".to_owned()));
+    assert_eq!(store.len(), 51);
+}
+
+#[test]
+fn drop_bucket_cut_short_by_a_crash_removes_nothing() {
+    let temp_dir = TempDir::new().unwrap();
+    let log = temp_dir.path().join("db.log");
+    let mut store = testing::open(temp_dir.path()).unwrap();
+    for i in 0..10 {
+        store
+            .bucket("doomed")
+            .set(format!("key{}", i), i.to_string())
+            .unwrap();
+    }
+    let before = fs::metadata(&log).unwrap().len();
+    assert_eq!(store.drop_bucket("doomed").unwrap(), 10);
+    drop(store);
+
+    let full = fs::read(&log).unwrap();
+    fs::write(&log, &full[..full.len() - 3]).unwrap();
+    let mut store = testing::open(temp_dir.path()).unwrap();
+    assert_eq!(store.open_report().torn_tail.unwrap().offset, before);
+    assert_eq!(store.bucket("doomed").keys().unwrap().len(), 10);
+}
@@ -0,0 +1,369 @@
+use assert_cmd::prelude::*;
+use kvs::protocol::{read_frame, write_frame, BulkApplied, Request, Response, CHUNK_BYTES};
+use kvs::testing::{self, ShortWrite};
+use kvs::{KvError, KvStore, KvsClient, KvsServer};
+use predicates::str::contains;
+use std::fs;
+use std::io::{self, BufReader};
+use std::net::{SocketAddr, TcpStream};
+use std::path::Path;
+use std::process::Command;
+use std::thread;
+use tempfile::TempDir;
+
+fn start_server(store: KvStore) -> SocketAddr {
+    let log = slog::Logger::root(slog::Discard, slog::o!());
+    let server = KvsServer::new("127.0.0.1:0", store, log).unwrap();
+    let addr = server.local_addr().unwrap();
+    thread::spawn(move || server.listen_forever());
+    addr
+}
+
+fn pairs(count: usize, value_len: usize) -> Vec<(String, String)> {
+    (0..count)
+        .map(|i| {
+            (
+                format!("key{}", i),
+                format!("{:0>width$}", i, width = value_len),
+            )
+        })
+        .collect()
+}
+
+fn short_write(after_bytes: usize) -> ShortWrite {
+    ShortWrite {
+        after_bytes,
+        error: io::ErrorKind::StorageFull,
+    }
+}
+
+#[test]
+fn set_batch_applies_all_pairs() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = testing::open(temp_dir.path()).unwrap();
+    let mut batch = pairs(100, 8);
+    batch.push(("key0".to_owned(), "last wins".to_owned()));
+
+    assert_eq!(store.set_batch(batch).unwrap(), 101);
+    assert_eq!(store.get("key0").unwrap(), Some("last wins".to_owned()));
+    assert_eq!(store.get("key99").unwrap(), Some("00000099".to_owned()));
+    assert_eq!(store.compact_dry_run().unwrap().stale_records, 1);
+    drop(store);
+
+    let store = testing::open(temp_dir.path()).unwrap();
+    assert_eq!(store.get("key0").unwrap(), Some("last wins".to_owned()));
+    assert_eq!(store.get("key50").unwrap(), Some("00000050".to_owned()));
+}
+
+#[test]
+fn failed_set_batch_applies_nothing() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = testing::open(temp_dir.path()).unwrap();
+    store.set("kept".to_owned(), "value".to_owned()).unwrap();
+
+    // Fails part way through the batch.
+    store.inject_short_write(short_write(150));
+    assert!(matches!(
+        store.set_batch(pairs(10, 8)),
+        Err(KvError::WriteError)
+    ));
+    store.recover_append().unwrap();
+    for (key, _) in pairs(10, 8) {
+        assert_eq!(store.get(&key).unwrap(), None);
+    }
+    drop(store);
+
+    let store = testing::open(temp_dir.path()).unwrap();
+    assert_eq!(store.get("key0").unwrap(), None);
+    assert_eq!(store.get("kept").unwrap(), Some("value".to_owned()));
+}
+
+#[test]
+fn set_batch_cut_short_by_a_crash_applies_nothing() {
+    let temp_dir = TempDir::new().unwrap();
+    let log_path = temp_dir.path().join("db.log");
+    let mut store = testing::open(temp_dir.path()).unwrap();
+    store.set("kept".to_owned(), "value".to_owned()).unwrap();
+    let good_len = fs::metadata(&log_path).unwrap().len() as usize;
+    store.set_batch(pairs(10, 8)).unwrap();
+    drop(store);
+
+    let contents = fs::read(&log_path).unwrap();
+    fs::write(&log_path, &contents[..(good_len + contents.len()) / 2]).unwrap();
+    let mut store = testing::open(temp_dir.path()).unwrap();
+    assert_eq!(
+        store.open_report().torn_tail.unwrap().offset,
+        good_len as u64
+    );
+    for (key, _) in pairs(10, 8) {
+        assert_eq!(store.get(&key).unwrap(), None);
+    }
+    assert_eq!(store.get("kept").unwrap(), Some("value".to_owned()));
+    store.set("after".to_owned(), "crash".to_owned()).unwrap();
+    drop(store);
+
+    let store = testing::open(temp_dir.path()).unwrap();
+    assert_eq!(store.get("key0").unwrap(), None);
+    assert_eq!(store.get("after").unwrap(), Some("crash".to_owned()));
+}
+
+#[test]
+fn bulk_set_larger_than_one_frame() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = start_server(testing::open(temp_dir.path()).unwrap());
+    let batch = pairs(5_000, 100);
+    let batch_bytes: usize = batch.iter().map(|(k, v)| k.len() + v.len()).sum();
+    assert!(batch_bytes > 5 * CHUNK_BYTES);
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    assert_eq!(client.set_bulk(batch.clone()).unwrap().count, 5_000);
+    for (key, value) in batch.into_iter().step_by(97) {
+        assert_eq!(client.get(key).unwrap(), Some(value));
+    }
+    let empty = client.set_bulk(Vec::new()).unwrap();
+    assert_eq!(empty.count, 0);
+    assert_eq!(empty.last_seq, 5_000);
+}
+
+#[test]
+fn nothing_is_visible_before_the_last_frame() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = start_server(testing::open(temp_dir.path()).unwrap());
+    let stream = TcpStream::connect(addr).unwrap();
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    let mut writer = stream;
+    let mut other = KvsClient::connect(addr).unwrap();
+
+    write_frame(
+        &mut writer,
+        &Request::BulkSet {
+            pairs: pairs(3, 4),
+            more: true,
+        },
+    )
+    .unwrap();
+    thread::sleep(std::time::Duration::from_millis(50));
+    assert_eq!(other.get("key0".to_owned()).unwrap(), None);
+
+    write_frame(
+        &mut writer,
+        &Request::BulkSet {
+            pairs: vec![("tail".to_owned(), "t".to_owned())],
+            more: false,
+        },
+    )
+    .unwrap();
+    let response: Response = read_frame(&mut reader).unwrap().unwrap();
+    assert_eq!(
+        response,
+        Response::Applied(BulkApplied {
+            count: 4,
+            last_seq: 4
+        })
+    );
+    assert_eq!(
+        other.get("key2".to_owned()).unwrap(),
+        Some("0002".to_owned())
+    );
+
+    // A batch interrupted by another request is dropped.
+    write_frame(
+        &mut writer,
+        &Request::BulkSet {
+            pairs: vec![("dropped".to_owned(), "d".to_owned())],
+            more: true,
+        },
+    )
+    .unwrap();
+    write_frame(
+        &mut writer,
+        &Request::Get {
+            key: "tail".to_owned(),
+        },
+    )
+    .unwrap();
+    let response: Response = read_frame(&mut reader).unwrap().unwrap();
+    assert_eq!(response, Response::Ok(Some("t".to_owned())));
+    assert_eq!(other.get("dropped".to_owned()).unwrap(), None);
+}
+
+#[test]
+fn a_batch_over_the_server_limit_is_dropped_and_refused() {
+    let temp_dir = TempDir::new().unwrap();
+    let log = slog::Logger::root(slog::Discard, slog::o!());
+    let server = KvsServer::new("127.0.0.1:0", testing::open(temp_dir.path()).unwrap(), log)
+        .unwrap()
+        .max_batch_bytes(64 * 1024);
+    let addr = server.local_addr().unwrap();
+    thread::spawn(move || server.listen_forever());
+    let stream = TcpStream::connect(addr).unwrap();
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    let mut writer = stream;
+
+    // Well past the limit, each frame under it: the frames after the limit are discarded.
+    for _ in 0..20 {
+        write_frame(
+            &mut writer,
+            &Request::BulkSet {
+                pairs: pairs(50, 100),
+                more: true,
+            },
+        )
+        .unwrap();
+    }
+    write_frame(
+        &mut writer,
+        &Request::BulkSet {
+            pairs: vec![("tail".to_owned(), "t".to_owned())],
+            more: false,
+        },
+    )
+    .unwrap();
+    let response: Response = read_frame(&mut reader).unwrap().unwrap();
+    assert!(
+        matches!(response, Response::Err(ref message) if message.contains("limit")),
+        "{:?}",
+        response
+    );
+
+    // The connection carries on, with nothing of the refused batch applied.
+    let mut client = KvsClient::connect(addr).unwrap();
+    assert_eq!(client.get("key0".to_owned()).unwrap(), None);
+    assert_eq!(client.get("tail".to_owned()).unwrap(), None);
+    write_frame(
+        &mut writer,
+        &Request::BulkSet {
+            pairs: pairs(50, 100),
+            more: false,
+        },
+    )
+    .unwrap();
+    let response: Response = read_frame(&mut reader).unwrap().unwrap();
+    assert!(matches!(
+        response,
+        Response::Applied(BulkApplied { count: 50, .. })
+    ));
+    assert!(matches!(
+        client.set_bulk(pairs(1_000, 100)),
+        Err(KvError::ServerError(ref message)) if message.contains("limit")
+    ));
+}
+
+#[test]
+fn bulk_set_failing_mid_batch_applies_nothing() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = testing::open(temp_dir.path()).unwrap();
+    store.inject_short_write(short_write(CHUNK_BYTES * 2));
+    let addr = start_server(store);
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    let batch = pairs(5_000, 100);
+    assert!(client.set_bulk(batch.clone()).is_err());
+    for (key, _) in batch.iter().step_by(50) {
+        assert_eq!(client.get(key.clone()).unwrap(), None);
+    }
+
+    // The server recovered the log, so the retry succeeds.
+    assert_eq!(client.set_bulk(batch).unwrap().count, 5_000);
+    assert_eq!(
+        client.get("key4999".to_owned()).unwrap().map(|v| v.len()),
+        Some(100)
+    );
+}
+
+#[test]
+fn bulk_set_with_an_oversized_value_mid_batch_applies_nothing() {
+    let temp_dir = TempDir::new().unwrap();
+    let store =
+        KvStore::open_with_options(temp_dir.path(), testing::options().max_value_size(16)).unwrap();
+    let addr = start_server(store);
+    let mut client = KvsClient::connect(addr).unwrap();
+    client.set("before".to_owned(), "value".to_owned()).unwrap();
+
+    let mut batch = pairs(5, 8);
+    batch.insert(2, ("big".to_owned(), "x".repeat(17)));
+    assert!(matches!(
+        client.set_bulk(batch),
+        Err(KvError::ServerError(ref message)) if message.contains("big")
+    ));
+    for (key, _) in pairs(5, 8) {
+        assert_eq!(client.get(key).unwrap(), None);
+    }
+    assert_eq!(client.get("big".to_owned()).unwrap(), None);
+
+    // Nothing was numbered either: the next batch carries on from the set before.
+    let applied = client.set_bulk(pairs(5, 8)).unwrap();
+    assert_eq!(applied.count, 5);
+    assert_eq!(applied.last_seq, 6);
+}
+
+fn write_import_file(dir: &Path, count: usize) -> std::path::PathBuf {
+    let path = dir.join("import.ndjson");
+    let lines: Vec<String> = (0..count)
+        .map(|i| format!("{{\"key\":\"key{}\",\"value\":\"value \\\"{}\\\"\"}}", i, i))
+        .collect();
+    fs::write(&path, lines.join("\n") + "\n").unwrap();
+    path
+}
+
+#[test]
+fn cli_import_uses_bulk_batches() {
+    let data_dir = TempDir::new().unwrap();
+    let input_dir = TempDir::new().unwrap();
+    let addr = start_server(testing::open(data_dir.path()).unwrap());
+    let input = write_import_file(input_dir.path(), 2_500);
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args([
+            "--addr",
+            &addr.to_string(),
+            "import",
+            "--bulk-size",
+            "1000",
+            "--in",
+        ])
+        .arg(&input)
+        .assert()
+        .success()
+        .stdout("Imported 2500 pairs\n");
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    assert_eq!(
+        client.get("key2499".to_owned()).unwrap(),
+        Some("value \"2499\"".to_owned())
+    );
+
+    fs::write(&input, "{\"key\":\"a\",\"value\":\"b\"}\nnot json\n").unwrap();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["--addr", &addr.to_string(), "import", "--in"])
+        .arg(&input)
+        .assert()
+        .failure()
+        .stderr(contains("line 2"));
+    assert_eq!(client.get("a".to_owned()).unwrap(), None);
+}
+
+#[test]
+fn cli_import_accepts_crlf_line_ends() {
+    let data_dir = TempDir::new().unwrap();
+    let input_dir = TempDir::new().unwrap();
+    let addr = start_server(testing::open(data_dir.path()).unwrap());
+    let input = input_dir.path().join("import.ndjson");
+    fs::write(
+        &input,
+        "{\"key\":\"a\",\"value\":\"1\"}\r\n\r\n{\"key\":\"b\",\"value\":\"2\"}\r\n",
+    )
+    .unwrap();
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["--addr", &addr.to_string(), "import", "--in"])
+        .arg(&input)
+        .assert()
+        .success()
+        .stdout("Imported 2 pairs\n");
+    let mut client = KvsClient::connect(addr).unwrap();
+    assert_eq!(client.get("b".to_owned()).unwrap(), Some("2".to_owned()));
+}
@@ -0,0 +1,184 @@
+use assert_cmd::prelude::*;
+use kvs::canonical;
+use kvs::testing;
+use predicates::str::contains;
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+use tempfile::TempDir;
+
+fn kvs_log(args: &[&str]) -> Command {
+    let mut cmd = Command::cargo_bin("kvs").unwrap();
+    cmd.arg("log").args(args);
+    cmd
+}
+
+/// A store whose log, left uncompacted, holds overwrites, a remove and awkward text.
+fn populated_store() -> TempDir {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = testing::open(temp_dir.path()).unwrap();
+    store.set("plain".to_owned(), "value".to_owned()).unwrap();
+    store
+        .set("quoted \"key\"\t".to_owned(), "line\nbreak\r\n".to_owned())
+        .unwrap();
+    store.set("gone".to_owned(), "soon".to_owned()).unwrap();
+    store
+        .set("plain".to_owned(), "überschrieben".to_owned())
+        .unwrap();
+    store.remove("gone".to_owned()).unwrap();
+    temp_dir
+}
+
+fn export(dir: &Path) -> String {
+    let output = kvs_log(&["export", "--format", "canonical", "--dir"])
+        .arg(dir)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    String::from_utf8(output.stdout).unwrap()
+}
+
+#[test]
+fn round_trip_keeps_logical_contents() {
+    let source = populated_store();
+    let exported = export(source.path());
+    assert!(exported.starts_with(canonical::HEADER));
+    assert_eq!(exported.lines().filter(|l| !l.starts_with('#')).count(), 5);
+
+    let work = TempDir::new().unwrap();
+    let file = work.path().join("canonical.txt");
+    fs::write(&file, &exported).unwrap();
+    let rebuilt = work.path().join("rebuilt");
+    kvs_log(&["build", "--from"])
+        .arg(&file)
+        .arg("--out")
+        .arg(&rebuilt)
+        .arg("--diff-against")
+        .arg(source.path())
+        .assert()
+        .success()
+        .stdout(contains("0 keys differ"));
+
+    assert_eq!(
+        canonical::store_contents(&rebuilt).unwrap(),
+        canonical::store_contents(source.path()).unwrap()
+    );
+    // Nothing was lost: even the stale records were carried over.
+    assert_eq!(export(&rebuilt), exported);
+    let store = testing::open(&rebuilt).unwrap();
+    assert_eq!(
+        store.get("quoted \"key\"\t").unwrap(),
+        Some("line\nbreak\r\n".to_owned())
+    );
+    assert_eq!(store.get("gone").unwrap(), None);
+}
+
+#[test]
+fn edited_value_is_built_and_reported() {
+    let source = populated_store();
+    let exported = export(source.path());
+    // "value" -> "fixed"; the checksum is dropped to mark the edit as intended.
+    let edited: Vec<String> = exported
+        .lines()
+        .map(|line| {
+            if line.starts_with("3\t") {
+                let mut fields: Vec<&str> = line.split('\t').collect();
+                fields[5] = "Zml4ZWQ=";
+                fields[6] = "-";
+                fields.join("\t")
+            } else {
+                line.to_owned()
+            }
+        })
+        .collect();
+
+    let work = TempDir::new().unwrap();
+    let file = work.path().join("canonical.txt");
+    // Written with CRLF line ends, as some editors do.
+    fs::write(&file, edited.join("\r\n") + "\r\n").unwrap();
+    let rebuilt = work.path().join("rebuilt");
+    kvs_log(&["build", "--from"])
+        .arg(&file)
+        .arg("--out")
+        .arg(&rebuilt)
+        .arg("--diff-against")
+        .arg(source.path())
+        .assert()
+        .success()
+        .stdout(contains("~ \"plain\"\n1 keys differ"));
+
+    let store = testing::open(&rebuilt).unwrap();
+    assert_eq!(store.get("plain").unwrap(), Some("fixed".to_owned()));
+}
+
+#[test]
+fn build_rejects_bad_edits() {
+    let source = populated_store();
+    let exported = export(source.path());
+    let work = TempDir::new().unwrap();
+    let file = work.path().join("canonical.txt");
+
+    let cases = [
+        // Swapping two records breaks the sequence order.
+        (
+            exported.replacen("\n2\t2\t", "\n2\t9\t", 1),
+            "line 6 of the canonical log: sequence 3",
+        ),
+        // A changed value that still carries the old checksum.
+        (
+            exported.replacen("dmFsdWU=", "Zml4ZWQ=", 1),
+            "line 3 of the canonical log: checksum does not match",
+        ),
+        (
+            exported.replacen("dmFsdWU=", "dmFsd?U=", 1),
+            "not valid base64",
+        ),
+        (
+            exported.replacen(canonical::HEADER, "# something else", 1),
+            "line 1 of",
+        ),
+    ];
+    for (contents, message) in cases {
+        fs::write(&file, contents).unwrap();
+        let out = work.path().join("out");
+        kvs_log(&["build", "--from"])
+            .arg(&file)
+            .arg("--out")
+            .arg(&out)
+            .assert()
+            .failure()
+            .stderr(contains(message));
+        assert!(!out.exists());
+    }
+
+    // Never into a directory that already holds something.
+    fs::write(&file, &exported).unwrap();
+    kvs_log(&["build", "--from"])
+        .arg(&file)
+        .arg("--out")
+        .arg(source.path())
+        .assert()
+        .failure()
+        .stderr(contains("is not empty"));
+}
+
+#[test]
+fn values_that_are_not_utf8_survive_the_round_trip() {
+    let source = TempDir::new().unwrap();
+    let mut log = b"{\"Set\":{\"key\":\"binary\",\"value\":\"a\xff\\\"\\u0001b\"}}\n".to_vec();
+    log.extend_from_slice(b"{\"Set\":{\"key\":\"text\",\"value\":\"ok\"}}\n");
+    fs::write(source.path().join("db.log"), &log).unwrap();
+
+    let mut exported = Vec::new();
+    canonical::export(source.path(), &mut exported).unwrap();
+    let records = canonical::parse(exported.as_slice()).unwrap();
+    assert_eq!(records[0].value, Some(b"a\xff\"\x01b".to_vec()));
+
+    let work = TempDir::new().unwrap();
+    let rebuilt = work.path().join("rebuilt");
+    canonical::build(&records, &rebuilt).unwrap();
+    assert_eq!(
+        canonical::store_contents(&rebuilt).unwrap(),
+        canonical::store_contents(source.path()).unwrap()
+    );
+}
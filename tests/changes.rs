@@ -0,0 +1,103 @@
+use kvs::testing;
+use kvs::{ChangeEvent, ChangeOp, KvStore, MergeOperator};
+use std::sync::{Arc, Mutex};
+use tempfile::TempDir;
+
+struct Concat;
+
+impl MergeOperator for Concat {
+    fn merge(&self, _key: &str, existing: Option<&str>, operand: &str) -> String {
+        format!("{}{}", existing.unwrap_or(""), operand)
+    }
+}
+
+fn set(key: &str, value: &str) -> ChangeEvent {
+    ChangeEvent {
+        key: key.to_owned(),
+        op: ChangeOp::Set,
+        value: Some(value.as_bytes().to_vec()),
+    }
+}
+
+fn remove(key: &str) -> ChangeEvent {
+    ChangeEvent {
+        key: key.to_owned(),
+        op: ChangeOp::Remove,
+        value: None,
+    }
+}
+
+fn recorded(store: &mut KvStore) -> Arc<Mutex<Vec<ChangeEvent>>> {
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let sink = Arc::clone(&events);
+    store.subscribe(move |event| sink.lock().unwrap().push(event.clone()));
+    events
+}
+
+#[test]
+fn subscribers_see_each_successful_write_in_order() {
+    let temp_dir = TempDir::new().unwrap();
+    let options = testing::options()
+        .merge_operator(Box::new(Concat))
+        .max_value_size(16);
+    let mut store = KvStore::open_with_options(temp_dir.path(), options).unwrap();
+    let events = recorded(&mut store);
+
+    store.set("a".to_owned(), "1".to_owned()).unwrap();
+    store.set_bytes("b".to_owned(), &[0xff]).unwrap();
+    store.merge("a".to_owned(), "+".to_owned()).unwrap();
+    store
+        .set_from_reader("c".to_owned(), &b"streamed"[..], 8)
+        .unwrap();
+    // Writes that fail or write nothing tell no one.
+    assert!(store.remove("missing".to_owned()).is_err());
+    assert!(store.set("big".to_owned(), "x".repeat(17)).is_err());
+    assert!(!store.set_if_absent("a".to_owned(), "2".to_owned()).unwrap());
+    store.remove("b".to_owned()).unwrap();
+    store
+        .set_batch(vec![
+            ("d".to_owned(), "4".to_owned()),
+            ("a".to_owned(), "5".to_owned()),
+        ])
+        .unwrap();
+    store.rename("d", "e".to_owned()).unwrap();
+    let before_compaction = events.lock().unwrap().len();
+    store.compact().unwrap();
+    store.reopen().unwrap();
+    assert_eq!(events.lock().unwrap().len(), before_compaction);
+    store.clear().unwrap();
+
+    let mut expected = vec![
+        set("a", "1"),
+        ChangeEvent {
+            value: Some(vec![0xff]),
+            ..set("b", "")
+        },
+        set("a", "1+"),
+        set("c", "streamed"),
+        remove("b"),
+        set("d", "4"),
+        set("a", "5"),
+        set("e", "4"),
+        remove("d"),
+    ];
+    expected.extend(["a", "c", "e"].map(remove));
+    assert_eq!(*events.lock().unwrap(), expected);
+}
+
+#[test]
+fn unsubscribed_callbacks_are_not_called() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = testing::open(temp_dir.path()).unwrap();
+    let kept = recorded(&mut store);
+    let dropped = Arc::new(Mutex::new(0));
+    let counter = Arc::clone(&dropped);
+    let id = store.subscribe(move |_| *counter.lock().unwrap() += 1);
+
+    store.set("a".to_owned(), "1".to_owned()).unwrap();
+    assert!(store.unsubscribe(id));
+    assert!(!store.unsubscribe(id));
+    store.set("b".to_owned(), "2".to_owned()).unwrap();
+    assert_eq!(*dropped.lock().unwrap(), 1);
+    assert_eq!(*kept.lock().unwrap(), vec![set("a", "1"), set("b", "2")]);
+}
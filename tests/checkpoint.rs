@@ -0,0 +1,83 @@
+use kvs::testing;
+use kvs::{KvError, KvStore};
+use std::collections::BTreeSet;
+use std::fs;
+use std::thread;
+use tempfile::TempDir;
+
+fn keys(store: &KvStore) -> BTreeSet<String> {
+    store.keys().unwrap().collect()
+}
+
+#[test]
+fn checkpoint_taken_mid_burst_holds_exactly_the_writes_before_it() {
+    let temp_dir = TempDir::new().unwrap();
+    let copies = TempDir::new().unwrap();
+    let mut store = testing::open(temp_dir.path()).unwrap();
+    for i in 0..500 {
+        store
+            .set(format!("key{}", i), format!("value{}", i))
+            .unwrap();
+    }
+    let at_snapshot = keys(&store);
+
+    // The copy is written from a snapshot on another thread while the burst carries on.
+    let snapshot = store.snapshot().unwrap();
+    let first = copies.path().join("first");
+    let writer = {
+        let first = first.clone();
+        thread::spawn(move || snapshot.checkpoint(&first).unwrap())
+    };
+    for i in 500..1000 {
+        store
+            .set(format!("key{}", i), format!("value{}", i))
+            .unwrap();
+        if i % 5 == 0 {
+            store.remove(format!("key{}", i - 500)).unwrap();
+        }
+    }
+    store.compact().unwrap();
+    let info = writer.join().unwrap();
+    assert_eq!(info.keys, 500);
+    assert_eq!(info.sequence, 500);
+    assert_eq!(
+        info.bytes,
+        fs::metadata(first.join("db.log")).unwrap().len()
+    );
+
+    let second = copies.path().join("second");
+    let info = store.checkpoint(&second).unwrap();
+    assert_eq!(info.keys, store.len() as u64);
+    assert_eq!(info.sequence, store.last_sequence());
+
+    let copy = KvStore::open(&first).unwrap();
+    assert_eq!(keys(&copy), at_snapshot);
+    assert_eq!(copy.get("key5").unwrap(), Some("value5".to_owned()));
+    assert_eq!(copy.last_sequence(), 500);
+    drop(copy);
+
+    // The later copy is a store of its own, carrying on from the original's numbering.
+    let mut copy = KvStore::open(&second).unwrap();
+    assert_eq!(keys(&copy), keys(&store));
+    assert_eq!(copy.get("key999").unwrap(), Some("value999".to_owned()));
+    assert_eq!(copy.last_sequence(), store.last_sequence());
+    copy.set("only-in-copy".to_owned(), "x".to_owned()).unwrap();
+    drop(copy);
+    assert_eq!(store.get("only-in-copy").unwrap(), None);
+    let copy = KvStore::open(&second).unwrap();
+    assert_eq!(copy.get("only-in-copy").unwrap(), Some("x".to_owned()));
+}
+
+#[test]
+fn checkpoint_refuses_a_directory_in_use() {
+    let temp_dir = TempDir::new().unwrap();
+    let dest = TempDir::new().unwrap();
+    let mut store = testing::open(temp_dir.path()).unwrap();
+    store.set("key".to_owned(), "value".to_owned()).unwrap();
+    fs::write(dest.path().join("notes.txt"), "keep me").unwrap();
+    match store.checkpoint(dest.path()) {
+        Err(KvError::DirectoryNotEmpty(path)) => assert_eq!(path, dest.path()),
+        other => panic!("expected DirectoryNotEmpty, got {:?}", other),
+    }
+    assert_eq!(fs::read_dir(dest.path()).unwrap().count(), 1);
+}
@@ -0,0 +1,226 @@
+use kvs::testing;
+use kvs::{KvError, KvStore, LogFormat};
+use std::fs;
+use std::path::Path;
+use tempfile::TempDir;
+
+fn log_path(dir: &Path) -> std::path::PathBuf {
+    dir.join("db.log")
+}
+
+/// Where the record holding `needle` starts, and where `needle` is in the log.
+fn find(dir: &Path, needle: &[u8]) -> (usize, usize) {
+    let contents = fs::read(log_path(dir)).unwrap();
+    let at = contents
+        .windows(needle.len())
+        .position(|w| w == needle)
+        .unwrap();
+    let start = contents[..at]
+        .iter()
+        .rposition(|&b| b == b'\n')
+        .map_or(0, |newline| newline + 1);
+    (start, at)
+}
+
+/// Overwrites the byte at `at` with `byte`, leaving the record valid JSON.
+fn overwrite(dir: &Path, at: usize, byte: u8) {
+    let mut contents = fs::read(log_path(dir)).unwrap();
+    contents[at] = byte;
+    fs::write(log_path(dir), contents).unwrap();
+}
+
+fn assert_corruption_at<T: std::fmt::Debug>(result: kvs::Result<T>, expected: usize) {
+    match result {
+        Err(KvError::Corruption { offset, details }) => {
+            assert_eq!(offset, expected as u64, "{}", details);
+        }
+        other => panic!("unexpected result: {:?}", other),
+    }
+}
+
+#[test]
+fn every_record_written_is_sealed() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = testing::open(temp_dir.path()).unwrap();
+    store.set("plain".to_owned(), "value".to_owned()).unwrap();
+    store.set_bytes("bytes".to_owned(), b"\xff\xfe").unwrap();
+    let streamed = b"from \"a\" reader";
+    store
+        .set_from_reader("streamed".to_owned(), &streamed[..], streamed.len() as u64)
+        .unwrap();
+    store
+        .set_batch(vec![("batched".to_owned(), "value".to_owned())])
+        .unwrap();
+    store.swap("plain", "batched").unwrap();
+    store.remove("batched".to_owned()).unwrap();
+
+    // Not UTF-8, for the value set as bytes.
+    let contents = testing::log_records(temp_dir.path());
+    let lines: Vec<String> = contents
+        .split(|&b| b == b'\n')
+        .filter(|line| !line.is_empty())
+        .map(|line| String::from_utf8_lossy(line).into_owned())
+        .collect();
+    // Two sets, a set from a reader, a batch, a swap's group and its two sets, a remove.
+    assert_eq!(lines.len(), 8, "{:?}", lines);
+    for line in lines {
+        assert!(line.contains(",\"crc\":"), "{}", line);
+    }
+
+    drop(store);
+    let store = testing::open(temp_dir.path()).unwrap();
+    assert_eq!(store.get("plain").unwrap(), Some("value".to_owned()));
+    assert_eq!(
+        store.get_bytes("bytes").unwrap(),
+        Some(b"\xff\xfe".to_vec())
+    );
+    assert_eq!(
+        store.get("streamed").unwrap(),
+        Some("from \"a\" reader".to_owned())
+    );
+    assert_eq!(store.get("batched").unwrap(), None);
+}
+
+#[test]
+fn a_changed_value_byte_fails_reads_of_that_key() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = testing::open(temp_dir.path()).unwrap();
+    store.set("good".to_owned(), "fine".to_owned()).unwrap();
+    store
+        .set("bad".to_owned(), "0123456789".to_owned())
+        .unwrap();
+
+    let (start, at) = find(temp_dir.path(), b"0123456789");
+    overwrite(temp_dir.path(), at + 5, b'7');
+
+    assert_corruption_at(store.get("bad"), start);
+    assert_eq!(store.get("good").unwrap(), Some("fine".to_owned()));
+    let scanned: Vec<_> = store.iter().unwrap().collect();
+    assert!(
+        scanned
+            .iter()
+            .any(|entry| matches!(entry, Err(KvError::Corruption { .. }))),
+        "{:?}",
+        scanned
+    );
+}
+
+#[test]
+fn a_changed_byte_of_a_streamed_value_fails_the_reader_in_every_format() {
+    let value: String = (0..40_000)
+        .map(|i| (b'a' + (i % 26) as u8) as char)
+        .collect();
+    let marker = "0123456789";
+    for format in [LogFormat::Json, LogFormat::Bincode, LogFormat::MessagePack] {
+        let temp_dir = TempDir::new().unwrap();
+        let options = testing::options().format(format);
+        let mut store = KvStore::open_with_options(temp_dir.path(), options).unwrap();
+        store.set("good".to_owned(), value.clone()).unwrap();
+        let large = format!("{}{}{}", &value[..20_000], marker, &value[20_000..]);
+        store.set("bad".to_owned(), large).unwrap();
+
+        let (start, at) = find(temp_dir.path(), marker.as_bytes());
+        overwrite(temp_dir.path(), at + 5, b'7');
+
+        let mut read = String::new();
+        let mut reader = store.get_reader("good").unwrap().unwrap();
+        std::io::Read::read_to_string(&mut reader, &mut read).unwrap();
+        assert_eq!(read, value, "{:?}", format);
+        match store.get_reader("bad").map(drop) {
+            // Where a frame starts is not a newline away, so only JSON's offset is known.
+            Err(KvError::Corruption { offset, .. }) if format != LogFormat::Json => {
+                assert!(offset < at as u64, "{:?}", format)
+            }
+            result => assert_corruption_at(result, start),
+        }
+        assert!(matches!(store.get("bad"), Err(KvError::Corruption { .. })));
+    }
+}
+
+#[test]
+fn reopening_fails_on_a_changed_record() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = testing::open(temp_dir.path()).unwrap();
+    store.set("first".to_owned(), "one".to_owned()).unwrap();
+    store.set("second".to_owned(), "two".to_owned()).unwrap();
+    store.set("third".to_owned(), "three".to_owned()).unwrap();
+    drop(store);
+
+    let (start, at) = find(temp_dir.path(), b"\"second\"");
+    overwrite(temp_dir.path(), at + 1, b'S');
+    assert_corruption_at(testing::open(temp_dir.path()).map(drop), start);
+}
+
+#[test]
+fn a_changed_checksum_is_caught_too() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = testing::open(temp_dir.path()).unwrap();
+    store.set("key".to_owned(), "value".to_owned()).unwrap();
+    drop(store);
+
+    let (start, at) = find(temp_dir.path(), b"\"crc\":");
+    let digit = at + "\"crc\":".len();
+    let contents = fs::read(log_path(temp_dir.path())).unwrap();
+    let changed = if contents[digit] == b'9' { b'1' } else { b'9' };
+    overwrite(temp_dir.path(), digit, changed);
+    assert_corruption_at(testing::open(temp_dir.path()).map(drop), start);
+}
+
+#[test]
+fn a_changed_group_header_fails_reopening() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = testing::open(temp_dir.path()).unwrap();
+    store.set("a".to_owned(), "1".to_owned()).unwrap();
+    store.set("b".to_owned(), "2".to_owned()).unwrap();
+    store.swap("a", "b").unwrap();
+    drop(store);
+
+    let (start, at) = find(temp_dir.path(), b"{\"Group\":{\"records\":2");
+    overwrite(temp_dir.path(), at + "{\"Group\":{\"records\":".len(), b'3');
+    assert_corruption_at(testing::open(temp_dir.path()).map(drop), start);
+}
+
+#[test]
+fn logs_without_checksums_open_and_compaction_seals_them() {
+    let temp_dir = TempDir::new().unwrap();
+    fs::write(
+        log_path(temp_dir.path()),
+        "{\"Set\":{\"key\":\"old\",\"value\":\"format\"}}\n\
+         {\"Set\":{\"key\":\"gone\",\"value\":\"soon\",\"seq\":2}}\n\
+         {\"Rm\":{\"key\":\"gone\",\"seq\":3}}\n",
+    )
+    .unwrap();
+    let mut store = testing::open(temp_dir.path()).unwrap();
+    assert_eq!(store.get("old").unwrap(), Some("format".to_owned()));
+    assert_eq!(store.get("gone").unwrap(), None);
+    store.set("new".to_owned(), "record".to_owned()).unwrap();
+
+    store.compact().unwrap();
+    let contents = String::from_utf8(testing::log_records(temp_dir.path())).unwrap();
+    for line in contents.lines() {
+        assert!(line.contains(",\"crc\":"), "{}", line);
+    }
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    assert_eq!(store.get("old").unwrap(), Some("format".to_owned()));
+    assert_eq!(store.get("new").unwrap(), Some("record".to_owned()));
+    assert_eq!(store.get("gone").unwrap(), None);
+}
+
+#[test]
+fn compaction_fails_on_a_changed_record_and_keeps_the_log() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = testing::open(temp_dir.path()).unwrap();
+    store.set("key".to_owned(), "value".to_owned()).unwrap();
+    store.set("other".to_owned(), "fine".to_owned()).unwrap();
+    // Something to reclaim, or compaction would not read the log at all.
+    store.set("other".to_owned(), "again".to_owned()).unwrap();
+
+    let (start, at) = find(temp_dir.path(), b"\"value\"");
+    overwrite(temp_dir.path(), at + "\"value\":\"".len(), b'V');
+    let before = fs::read(log_path(temp_dir.path())).unwrap();
+
+    assert_corruption_at(store.compact(), start);
+    assert_eq!(fs::read(log_path(temp_dir.path())).unwrap(), before);
+}
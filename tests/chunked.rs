@@ -0,0 +1,258 @@
+use kvs::protocol::{read_frame, write_frame, Checksum, Request, Response};
+use kvs::testing;
+use kvs::{KvStore, KvsClient, KvsServer};
+use std::fs;
+use std::io::{BufReader, Seek, SeekFrom, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+const FRAME_LIMIT: usize = 512 * 1024;
+
+fn start_server(dir: &Path) -> SocketAddr {
+    let log = slog::Logger::root(slog::Discard, slog::o!());
+    let store = testing::open(dir).unwrap();
+    let server = KvsServer::new("127.0.0.1:0", store, log)
+        .unwrap()
+        .max_frame_bytes(FRAME_LIMIT);
+    let addr = server.local_addr().unwrap();
+    thread::spawn(move || server.listen_forever());
+    addr
+}
+
+/// Larger than the frame limit, with multibyte chars that chunk boundaries will split.
+fn big_value() -> String {
+    "αβγ-0123456789-".repeat(3 * FRAME_LIMIT / 16)
+}
+
+fn spool_files(dir: &Path) -> Vec<String> {
+    fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+        .filter(|name| name.starts_with(".upload-"))
+        .collect()
+}
+
+#[test]
+fn value_larger_than_frame_limit_round_trips() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = start_server(temp_dir.path());
+    let value = big_value();
+    assert!(value.len() > FRAME_LIMIT);
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    client
+        .set_reader("big".to_owned(), value.as_bytes(), value.len() as u64)
+        .unwrap();
+
+    let mut streamed = Vec::new();
+    assert!(client.get_writer("big".to_owned(), &mut streamed).unwrap());
+    assert_eq!(streamed, value.as_bytes());
+    assert_eq!(client.get("big".to_owned()).unwrap(), Some(value.clone()));
+    assert!(!client.get_writer("missing".to_owned(), Vec::new()).unwrap());
+
+    // A plain set of the same value is one oversized frame.
+    assert!(client.set("plain".to_owned(), value).is_err());
+    assert!(spool_files(temp_dir.path()).is_empty());
+}
+
+#[test]
+fn binary_value_round_trips() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = start_server(temp_dir.path());
+    // Every byte value, invalid UTF-8 included, over several chunks.
+    let value: Vec<u8> = (0..=255u8).cycle().take(3 * FRAME_LIMIT + 7).collect();
+    let small = [0xff, 0xfe, 0x00, b'"', 0x80];
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    client
+        .set_reader("binary".to_owned(), &value[..], value.len() as u64)
+        .unwrap();
+    client
+        .set_reader("small".to_owned(), &small[..], small.len() as u64)
+        .unwrap();
+
+    let mut streamed = Vec::new();
+    assert!(client
+        .get_writer("binary".to_owned(), &mut streamed)
+        .unwrap());
+    assert_eq!(streamed, value);
+    let mut streamed = Vec::new();
+    assert!(client
+        .get_writer("small".to_owned(), &mut streamed)
+        .unwrap());
+    assert_eq!(streamed, small);
+
+    // The upload went into the log as it is.
+    let store = KvStore::open_read_only(temp_dir.path()).unwrap();
+    assert_eq!(store.get_bytes("binary").unwrap(), Some(value));
+    assert_eq!(store.get_bytes("small").unwrap(), Some(small.to_vec()));
+}
+
+#[test]
+fn small_values_upload_in_one_chunk() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = start_server(temp_dir.path());
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    client
+        .set_reader("small".to_owned(), "ünïcode".as_bytes(), 9)
+        .unwrap();
+    client.set_reader("empty".to_owned(), &b""[..], 0).unwrap();
+    assert_eq!(
+        client.get("small".to_owned()).unwrap(),
+        Some("ünïcode".to_owned())
+    );
+    assert_eq!(client.get("empty".to_owned()).unwrap(), Some(String::new()));
+}
+
+fn raw_connection(addr: SocketAddr) -> (TcpStream, BufReader<TcpStream>) {
+    let stream = TcpStream::connect(addr).unwrap();
+    let reader = BufReader::new(stream.try_clone().unwrap());
+    (stream, reader)
+}
+
+fn wait_for_no_spools(dir: &Path) {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while !spool_files(dir).is_empty() {
+        assert!(Instant::now() < deadline, "upload spool was not removed");
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+#[test]
+fn upload_without_commit_leaves_nothing() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = start_server(temp_dir.path());
+
+    let (mut stream, _reader) = raw_connection(addr);
+    write_frame(
+        &mut stream,
+        &Request::SetBegin {
+            key: "partial".to_owned(),
+            total_len: 10,
+        },
+    )
+    .unwrap();
+    write_frame(
+        &mut stream,
+        &Request::SetChunk {
+            data: b"12345".to_vec(),
+        },
+    )
+    .unwrap();
+    drop(stream);
+
+    wait_for_no_spools(temp_dir.path());
+    let mut client = KvsClient::connect(addr).unwrap();
+    assert_eq!(client.get("partial".to_owned()).unwrap(), None);
+}
+
+#[test]
+fn checksum_mismatch_is_rejected() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = start_server(temp_dir.path());
+
+    let (mut stream, mut reader) = raw_connection(addr);
+    let data = b"value".to_vec();
+    let mut checksum = Checksum::new();
+    checksum.update(b"other");
+    for request in [
+        Request::SetBegin {
+            key: "corrupt".to_owned(),
+            total_len: data.len() as u64,
+        },
+        Request::SetChunk { data },
+        Request::SetCommit {
+            checksum: checksum.value(),
+        },
+    ] {
+        write_frame(&mut stream, &request).unwrap();
+    }
+    let response: Response = read_frame(&mut reader).unwrap().unwrap();
+    assert_eq!(
+        response,
+        Response::Err("upload checksum mismatch".to_owned())
+    );
+
+    // An upload abandoned by another request is not stored either.
+    write_frame(
+        &mut stream,
+        &Request::SetBegin {
+            key: "abandoned".to_owned(),
+            total_len: 1,
+        },
+    )
+    .unwrap();
+    write_frame(
+        &mut stream,
+        &Request::Get {
+            key: "corrupt".to_owned(),
+        },
+    )
+    .unwrap();
+    let response: Response = read_frame(&mut reader).unwrap().unwrap();
+    assert_eq!(response, Response::Ok(None));
+
+    wait_for_no_spools(temp_dir.path());
+    let mut client = KvsClient::connect(addr).unwrap();
+    assert_eq!(client.get("abandoned".to_owned()).unwrap(), None);
+}
+
+#[test]
+fn a_damaged_large_value_is_answered_with_an_error_frame() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = start_server(temp_dir.path());
+    let value = big_value();
+    let mut client = KvsClient::connect(addr).unwrap();
+    client
+        .set_reader("big".to_owned(), value.as_bytes(), value.len() as u64)
+        .unwrap();
+    client.set("small".to_owned(), "fine".to_owned()).unwrap();
+
+    // Changed in place under the running server, as bit rot would.
+    let log = temp_dir.path().join("db.log");
+    let contents = fs::read(&log).unwrap();
+    let at = contents.len() / 2;
+    // Uploads are logged in base64, so another letter still decodes.
+    let changed = if contents[at] == b'A' { b'B' } else { b'A' };
+    let mut file = fs::OpenOptions::new().write(true).open(&log).unwrap();
+    file.seek(SeekFrom::Start(at as u64)).unwrap();
+    file.write_all(&[changed]).unwrap();
+    drop(file);
+
+    let (mut stream, mut reader) = raw_connection(addr);
+    write_frame(
+        &mut stream,
+        &Request::Get {
+            key: "big".to_owned(),
+        },
+    )
+    .unwrap();
+    let response: Response = read_frame(&mut reader).unwrap().unwrap();
+    assert!(
+        matches!(response, Response::Err(ref message) if message.contains("CRC")),
+        "{:?}",
+        response
+    );
+    write_frame(
+        &mut stream,
+        &Request::Get {
+            key: "small".to_owned(),
+        },
+    )
+    .unwrap();
+    let response: Response = read_frame(&mut reader).unwrap().unwrap();
+    assert_eq!(response, Response::Ok(Some("fine".to_owned())));
+}
+
+#[test]
+fn leftover_spools_are_removed_at_startup() {
+    let temp_dir = TempDir::new().unwrap();
+    fs::write(temp_dir.path().join(".upload-3-1.tmp"), "half a value").unwrap();
+
+    start_server(temp_dir.path());
+    assert!(spool_files(temp_dir.path()).is_empty());
+}
@@ -0,0 +1,106 @@
+use assert_cmd::prelude::*;
+use predicates::str::contains;
+use std::fs;
+use std::process::Command;
+use tempfile::TempDir;
+
+#[test]
+fn cli_dir_flag_uses_given_directory() {
+    let temp_dir = TempDir::new().unwrap();
+    let other = TempDir::new().unwrap();
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["set", "key1", "value1", "--dir"])
+        .arg(temp_dir.path())
+        .current_dir(&other)
+        .assert()
+        .success();
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1", "--dir"])
+        .arg(temp_dir.path())
+        .current_dir(&other)
+        .assert()
+        .success()
+        .stdout("value1\n");
+
+    assert!(!other.path().join("db.log").exists());
+}
+
+#[test]
+fn cli_dir_flag_rejects_file() {
+    let temp_dir = TempDir::new().unwrap();
+    let log = temp_dir.path().join("db.log");
+    fs::write(&log, "").unwrap();
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1", "--dir"])
+        .arg(&log)
+        .assert()
+        .failure()
+        .stderr(contains("is a file, not a directory"));
+}
+
+#[test]
+fn cli_dir_flag_rejects_missing_directory() {
+    let temp_dir = TempDir::new().unwrap();
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1", "--dir"])
+        .arg(temp_dir.path().join("missing"))
+        .assert()
+        .failure()
+        .stderr(contains("does not exist"));
+}
+
+#[test]
+fn cli_dir_flag_reports_log_directory() {
+    let temp_dir = TempDir::new().unwrap();
+    fs::create_dir(temp_dir.path().join("db.log")).unwrap();
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1", "--dir"])
+        .arg(temp_dir.path())
+        .assert()
+        .failure()
+        .stderr(contains("is a directory, not a log file"));
+}
+
+#[test]
+fn cli_import_loads_csv_columns() {
+    let temp_dir = TempDir::new().unwrap();
+    let csv = temp_dir.path().join("users.csv");
+    fs::write(&csv, "alice,\"Alice, A.\"\nbob,\"Bob\nB.\"\ncarol\n").unwrap();
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args([
+            "import",
+            "--format",
+            "csv",
+            "--key-col",
+            "0",
+            "--value-col",
+            "1",
+        ])
+        .arg(&csv)
+        .arg("--dir")
+        .arg(temp_dir.path())
+        .assert()
+        .success()
+        .stdout(contains("Imported 2 entries"))
+        .stdout(contains("Skipped 1 malformed rows, the first at line 4"));
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "bob", "--dir"])
+        .arg(temp_dir.path())
+        .assert()
+        .success()
+        .stdout("Bob\nB.\n");
+}
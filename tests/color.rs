@@ -0,0 +1,169 @@
+use assert_cmd::prelude::*;
+use kvs::cli::style::{ColorChoice, Stream, Style};
+use std::io::{BufRead, BufReader};
+use std::net::TcpListener;
+use std::process::{Command, Output, Stdio};
+use tempfile::TempDir;
+
+const ESC: u8 = 0x1b;
+
+fn has_escapes(bytes: &[u8]) -> bool {
+    bytes.contains(&ESC)
+}
+
+fn kvs(dir: &TempDir, args: &[&str]) -> Command {
+    let mut cmd = Command::cargo_bin("kvs").unwrap();
+    cmd.args(args).arg("--dir").arg(dir.path());
+    cmd
+}
+
+fn dead_addr() -> String {
+    TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .to_string()
+}
+
+/// `kvs rm` of a missing key writes "Key not found" to stdout, and a missing `--dir`
+/// an open error to stderr.
+fn kvs_outputs(color: &[&str], no_color: bool) -> (Output, Output) {
+    let dir = TempDir::new().unwrap();
+    let missing = dir.path().join("missing");
+    let mut rm = kvs(&dir, &["rm", "key"]);
+    let mut get = Command::cargo_bin("kvs").unwrap();
+    get.args(["get", "key", "--dir"]).arg(&missing);
+    for cmd in [&mut rm, &mut get] {
+        cmd.args(color);
+        if no_color {
+            cmd.env("NO_COLOR", "1");
+        } else {
+            cmd.env_remove("NO_COLOR");
+        }
+    }
+    (rm.output().unwrap(), get.output().unwrap())
+}
+
+#[test]
+fn kvs_is_plain_unless_color_is_forced() {
+    for (args, no_color) in [
+        (&[][..], false),
+        (&["--color", "auto"][..], false),
+        (&["--color", "never"][..], false),
+        (&["--color", "auto"][..], true),
+    ] {
+        let (rm, get) = kvs_outputs(args, no_color);
+        assert_eq!(rm.stdout, b"Key not found\n");
+        assert!(!has_escapes(&get.stderr), "{:?}", args);
+        assert!(String::from_utf8_lossy(&get.stderr).starts_with("Failed to create"));
+    }
+}
+
+#[test]
+fn kvs_color_always_styles_output_even_with_no_color() {
+    for no_color in [false, true] {
+        let (rm, get) = kvs_outputs(&["--color", "always"], no_color);
+        assert!(has_escapes(&rm.stdout));
+        assert!(has_escapes(&get.stderr));
+        assert!(String::from_utf8_lossy(&rm.stdout).contains("Key not found"));
+    }
+}
+
+#[test]
+fn kvs_compact_report_follows_color_choice() {
+    let dir = TempDir::new().unwrap();
+    let plain = kvs(&dir, &["compact", "--color", "never"])
+        .output()
+        .unwrap();
+    assert!(!has_escapes(&plain.stdout));
+    assert!(String::from_utf8_lossy(&plain.stdout).starts_with("log size before   8 B\n"));
+
+    let colored = kvs(&dir, &["compact", "--color", "always"])
+        .output()
+        .unwrap();
+    assert!(has_escapes(&colored.stdout));
+}
+
+#[test]
+fn client_errors_follow_color_choice() {
+    let addr = dead_addr();
+    for (choice, colored) in [("never", false), ("always", true), ("auto", false)] {
+        let output = Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(["--addr", &addr, "--color", choice, "get", "key"])
+            .output()
+            .unwrap();
+        assert!(!output.status.success());
+        assert_eq!(has_escapes(&output.stderr), colored, "--color {}", choice);
+    }
+}
+
+/// The first log line of a kvs-server started with `color`, which is "listening".
+fn server_first_log_line(color: &str) -> String {
+    let dir = TempDir::new().unwrap();
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", &dead_addr(), "--engine", "kvs", "--color", color])
+        .arg("--data-dir")
+        .arg(dir.path())
+        // The colored decorator needs a terminfo entry to know the escapes.
+        .env("TERM", "xterm")
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut line = String::new();
+    BufReader::new(child.stderr.take().unwrap())
+        .read_line(&mut line)
+        .unwrap();
+    child.kill().unwrap();
+    child.wait().unwrap();
+    line
+}
+
+#[test]
+fn server_log_follows_color_choice() {
+    let plain = server_first_log_line("never");
+    assert!(plain.contains("listening"));
+    assert!(!has_escapes(plain.as_bytes()));
+
+    let colored = server_first_log_line("always");
+    assert!(colored.contains("listening"));
+    assert!(has_escapes(colored.as_bytes()));
+}
+
+#[test]
+fn server_errors_follow_color_choice() {
+    let dir = TempDir::new().unwrap();
+    let file = dir.path().join("db.log");
+    std::fs::write(&file, "").unwrap();
+    for (choice, colored) in [("never", false), ("always", true)] {
+        let output = Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args([
+                "--addr",
+                "127.0.0.1:0",
+                "--engine",
+                "kvs",
+                "--color",
+                choice,
+            ])
+            .arg("--data-dir")
+            .arg(&file)
+            .output()
+            .unwrap();
+        assert!(!output.status.success());
+        assert_eq!(has_escapes(&output.stderr), colored, "--color {}", choice);
+    }
+}
+
+#[test]
+fn style_renders_plain_text_without_color() {
+    let plain = Style::new(false);
+    assert_eq!(plain.error("failed").to_string(), "failed");
+    assert_eq!(plain.label("name:").to_string(), "name:");
+
+    let colored = Style::resolve(ColorChoice::Always, Stream::Stdout);
+    assert!(colored.is_colored());
+    assert_eq!(colored.warning("hm").to_string(), "\x1b[33mhm\x1b[0m");
+    assert!(!Style::resolve(ColorChoice::Never, Stream::Stderr).is_colored());
+}
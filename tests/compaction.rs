@@ -0,0 +1,373 @@
+use assert_cmd::prelude::*;
+use kvs::kv_store::MIN_COMPACTION_DEAD_BYTES;
+use kvs::testing;
+use kvs::{CompactionEstimate, CompactionStats, KvStore, KvStoreOptions, KvsClient, KvsServer};
+use predicates::str::contains;
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+fn log_len(dir: &Path) -> u64 {
+    fs::metadata(dir.join("db.log")).unwrap().len()
+}
+
+/// 300 sets, 200 overwrites and 50 removes, escapes and multibyte values included.
+fn churn(store: &mut KvStore) {
+    for i in 0..300 {
+        store
+            .set(format!("key{}", i), format!("value \"{}\" é", i))
+            .unwrap();
+    }
+    for i in 0..200 {
+        store.set(format!("key{}", i), "x".repeat(i % 17)).unwrap();
+    }
+    for i in (0..300).step_by(6) {
+        store.remove(format!("key{}", i)).unwrap();
+    }
+}
+
+fn assert_estimate_matches_compaction(options: KvStoreOptions) {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = KvStore::open_with_options(temp_dir.path(), options).unwrap();
+    churn(&mut store);
+
+    let estimate = store.compact_dry_run().unwrap();
+    assert_eq!(estimate.current_log_bytes, log_len(temp_dir.path()));
+    assert_eq!(estimate.stale_records, 250);
+    assert_eq!(estimate.tombstone_records, 50);
+    assert_eq!(
+        estimate.reclaimable_bytes,
+        estimate.current_log_bytes - estimate.projected_log_bytes
+    );
+
+    store.compact().unwrap();
+    // Every record here was written by the store, so the projection is exact.
+    assert_eq!(log_len(temp_dir.path()), estimate.projected_log_bytes);
+
+    let after = store.compact_dry_run().unwrap();
+    assert_eq!(after.current_log_bytes, estimate.projected_log_bytes);
+    assert_eq!(after.reclaimable_bytes, 0);
+    assert_eq!(after.stale_records, 0);
+    assert_eq!(after.tombstone_records, 0);
+}
+
+#[test]
+fn dry_run_matches_real_compaction() {
+    assert_estimate_matches_compaction(testing::options());
+}
+
+#[test]
+fn dry_run_matches_real_compaction_with_tiered_index() {
+    assert_estimate_matches_compaction(testing::options().max_index_bytes(512));
+}
+
+#[test]
+fn dry_run_does_not_touch_the_log() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = testing::open(temp_dir.path()).unwrap();
+    churn(&mut store);
+    let before = fs::read(temp_dir.path().join("db.log")).unwrap();
+
+    store.compact_dry_run().unwrap();
+    assert_eq!(fs::read(temp_dir.path().join("db.log")).unwrap(), before);
+}
+
+#[test]
+fn cli_compact_dry_run_reports_estimate() {
+    let temp_dir = TempDir::new().unwrap();
+    for value in ["one", "two", "three"] {
+        Command::cargo_bin("kvs")
+            .unwrap()
+            .args(["set", "key", value, "--dir"])
+            .arg(temp_dir.path())
+            .assert()
+            .success();
+    }
+
+    // Opening the store compacts it, so a fresh process finds nothing to reclaim.
+    let output = Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["compact", "--dry-run", "--output", "json", "--dir"])
+        .arg(temp_dir.path())
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let estimate: CompactionEstimate = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(estimate.current_log_bytes, log_len(temp_dir.path()));
+    assert_eq!(estimate.reclaimable_bytes, 0);
+    assert_eq!(estimate.stale_records, 0);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["compact", "--dir"])
+        .arg(temp_dir.path())
+        .assert()
+        .success()
+        .stdout(contains("reclaimed         0 B\n"))
+        .stdout(contains("records dropped     0\n"));
+}
+
+#[test]
+fn cli_compact_reports_what_it_reclaimed() {
+    let temp_dir = TempDir::new().unwrap();
+    let options = testing::options().auto_compaction(false);
+    let mut store = KvStore::open_with_options(temp_dir.path(), options).unwrap();
+    churn(&mut store);
+    drop(store);
+    let before = log_len(temp_dir.path());
+
+    let output = Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["compact", "--output", "json", "--dir"])
+        .arg(temp_dir.path())
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let stats: CompactionStats = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(stats.log_bytes_before, before);
+    assert_eq!(stats.log_bytes_after, log_len(temp_dir.path()));
+    assert!(stats.log_bytes_after < before);
+    assert_eq!(stats.records_dropped, 300);
+}
+
+#[test]
+fn compact_reports_the_bytes_it_saved() {
+    let temp_dir = TempDir::new().unwrap();
+    let options = testing::options().auto_compaction(false);
+    let mut store = KvStore::open_with_options(temp_dir.path(), options).unwrap();
+    churn(&mut store);
+    let before = log_len(temp_dir.path());
+    let compactions = store.stats().since_open.compactions;
+
+    let stats = store.compact().unwrap();
+    assert_eq!(stats.log_bytes_before, before);
+    assert_eq!(stats.log_bytes_after, log_len(temp_dir.path()));
+    assert!(stats.log_bytes_after < stats.log_bytes_before);
+    // The overwritten and removed sets, and the removes.
+    assert_eq!(stats.records_dropped, 300);
+    assert!(stats.duration > Duration::ZERO);
+    assert_eq!(store.stats().since_open.compactions, compactions + 1);
+}
+
+#[test]
+fn compacting_a_compacted_log_does_nothing() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = testing::open(temp_dir.path()).unwrap();
+    churn(&mut store);
+    store.compact().unwrap();
+    let contents = fs::read(temp_dir.path().join("db.log")).unwrap();
+    let compactions = store.stats().since_open.compactions;
+
+    let stats = store.compact().unwrap();
+    let len = contents.len() as u64;
+    assert_eq!(
+        stats,
+        CompactionStats {
+            log_bytes_before: len,
+            log_bytes_after: len,
+            records_dropped: 0,
+            duration: Duration::ZERO,
+        }
+    );
+    assert_eq!(store.stats().since_open.compactions, compactions);
+    assert_eq!(fs::read(temp_dir.path().join("db.log")).unwrap(), contents);
+
+    // A key past its TTL is still dropped, though it counts as live until then.
+    store
+        .set_with_ttl("brief".to_owned(), "v".to_owned(), Duration::from_millis(1))
+        .unwrap();
+    let with_brief = log_len(temp_dir.path());
+    thread::sleep(Duration::from_millis(5));
+    let stats = store.compact().unwrap();
+    assert_eq!(stats.log_bytes_before, with_brief);
+    assert_eq!(stats.log_bytes_after, log_len(temp_dir.path()));
+    assert!(stats.log_bytes_after < with_brief);
+}
+
+#[test]
+fn client_compact_dry_run() {
+    let temp_dir = TempDir::new().unwrap();
+    let log = slog::Logger::root(slog::Discard, slog::o!());
+    let server =
+        KvsServer::new("127.0.0.1:0", testing::open(temp_dir.path()).unwrap(), log).unwrap();
+    let addr = server.local_addr().unwrap();
+    thread::spawn(move || server.listen_forever());
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    client.set("key".to_owned(), "one".to_owned()).unwrap();
+    client.set("key".to_owned(), "two".to_owned()).unwrap();
+    client.remove("key".to_owned()).unwrap();
+
+    // All that survives is the record keeping the sequence number of the remove.
+    let estimate = client.compact_dry_run().unwrap();
+    let sequence_record = "{\"Sequence\":{\"seq\":3,\"crc\":1757398447}}\n";
+    let projected = (testing::LOG_HEADER_LEN + sequence_record.len()) as u64;
+    assert_eq!(estimate.projected_log_bytes, projected);
+    assert_eq!(
+        estimate.reclaimable_bytes,
+        log_len(temp_dir.path()) - projected
+    );
+    assert_eq!(estimate.stale_records, 2);
+    assert_eq!(estimate.tombstone_records, 1);
+}
+
+#[test]
+fn compaction_threshold_and_compact_on_open_are_configurable() {
+    let temp_dir = TempDir::new().unwrap();
+    let options = testing::options()
+        .compaction_threshold(5)
+        .compact_on_open(false);
+    let compactions = |store: &KvStore| store.stats().since_open.compactions;
+
+    let mut store = KvStore::open_with_options(temp_dir.path(), options.clone()).unwrap();
+    assert_eq!(compactions(&store), 0);
+    for i in 0..4 {
+        store.set("key".to_owned(), format!("value{}", i)).unwrap();
+    }
+    assert_eq!(compactions(&store), 0);
+    store.set("key".to_owned(), "value4".to_owned()).unwrap();
+    assert_eq!(compactions(&store), 1);
+    store
+        .set_batch(vec![("key".to_owned(), "a".to_owned()); 12])
+        .unwrap();
+    assert_eq!(compactions(&store), 2);
+    store.set("key".to_owned(), "last".to_owned()).unwrap();
+    let logged = log_len(temp_dir.path());
+    drop(store);
+
+    // Without the compaction at open, the stale records are still in the log.
+    let store = KvStore::open_with_options(temp_dir.path(), options).unwrap();
+    assert_eq!(compactions(&store), 0);
+    assert_eq!(log_len(temp_dir.path()), logged);
+    drop(store);
+    let store = testing::open(temp_dir.path()).unwrap();
+    assert_eq!(compactions(&store), 1);
+    assert!(log_len(temp_dir.path()) < logged);
+    drop(store);
+
+    let mut store =
+        KvStore::open_with_options(temp_dir.path(), testing::options().compaction_threshold(0))
+            .unwrap();
+    for i in 0..50 {
+        store.set("key".to_owned(), format!("value{}", i)).unwrap();
+    }
+    assert_eq!(compactions(&store), 1);
+}
+
+#[test]
+fn overwriting_one_key_keeps_the_log_a_small_multiple_of_it() {
+    let temp_dir = TempDir::new().unwrap();
+    // Without paranoid checks, which would read every one of the writes back.
+    let options = KvStoreOptions::new().compact_on_open(false);
+    let mut store = KvStore::open_with_options(temp_dir.path(), options).unwrap();
+    let mut largest = 0;
+    for i in 0..1_000_000 {
+        store.set("key".to_owned(), format!("{:0400}", i)).unwrap();
+        largest = largest.max(store.stats().log_bytes);
+    }
+    // A log is not compacted for less than the minimum, however small the live data.
+    let record = store.compact_dry_run().unwrap().projected_log_bytes;
+    assert!(
+        largest <= 2 * MIN_COMPACTION_DEAD_BYTES + 16 * record,
+        "{} for a {} byte record",
+        largest,
+        record
+    );
+    assert_eq!(log_len(temp_dir.path()), store.stats().log_bytes);
+    assert!(store.stats().since_open.compactions > 1000);
+}
+
+#[test]
+fn compaction_is_triggered_by_dead_bytes_not_writes() {
+    let temp_dir = TempDir::new().unwrap();
+    let options = testing::options().compact_on_open(false);
+    let compactions = |store: &KvStore| store.stats().since_open.compactions;
+    let mut store = KvStore::open_with_options(temp_dir.path(), options.clone()).unwrap();
+
+    // New keys leave nothing dead, however many there are.
+    for i in 0..2_000 {
+        store.set(format!("key{}", i), "x".repeat(64)).unwrap();
+    }
+    assert_eq!(compactions(&store), 0);
+    assert_eq!(store.stats().dead_bytes, 0);
+
+    // Overwrites kill the records they replace, until more than half the log is dead.
+    let mut overwritten = 0;
+    while compactions(&store) == 0 {
+        let before = store.stats().dead_bytes;
+        store
+            .set(format!("key{}", overwritten), "y".to_owned())
+            .unwrap();
+        overwritten += 1;
+        if compactions(&store) == 0 {
+            assert!(store.stats().dead_bytes > before);
+        }
+    }
+    assert!((1_000..=2_000).contains(&overwritten), "{}", overwritten);
+    // The compaction went before the write that set it off, whose overwrite is all that
+    // has died since.
+    let dead = store.stats().dead_bytes;
+    assert!(dead > 0 && dead < MIN_COMPACTION_DEAD_BYTES, "{}", dead);
+    drop(store);
+
+    // An absolute limit compacts long before the share is reached, counting the removes
+    // as well as what they remove.
+    let options = options.compaction_dead_bytes(2048);
+    let mut store = KvStore::open_with_options(temp_dir.path(), options).unwrap();
+    let mut removed = 0;
+    while compactions(&store) == 0 {
+        store.remove(format!("key{}", removed)).unwrap();
+        removed += 1;
+    }
+    assert!((5..30).contains(&removed), "{}", removed);
+    assert!(store.stats().dead_bytes < 2048);
+}
+
+#[test]
+fn only_auto_compaction_turns_off_dead_byte_compaction() {
+    let compactions = |store: &KvStore| store.stats().since_open.compactions;
+    let overwrite = |store: &mut KvStore| {
+        for i in 0..2_000 {
+            store.set("key".to_owned(), format!("{:0100}", i)).unwrap();
+        }
+    };
+
+    // A threshold of 0 counts no writes, but dead bytes still compact.
+    let temp_dir = TempDir::new().unwrap();
+    let options = testing::options()
+        .compaction_threshold(0)
+        .compact_on_open(false);
+    let mut store = KvStore::open_with_options(temp_dir.path(), options).unwrap();
+    overwrite(&mut store);
+    assert!(compactions(&store) > 0);
+
+    let temp_dir = TempDir::new().unwrap();
+    let options = testing::options()
+        .compaction_threshold(10)
+        .auto_compaction(false)
+        .compact_on_open(false);
+    let mut store = KvStore::open_with_options(temp_dir.path(), options).unwrap();
+    overwrite(&mut store);
+    assert_eq!(compactions(&store), 0);
+    assert!(store.stats().dead_bytes > MIN_COMPACTION_DEAD_BYTES);
+    store.compact().unwrap();
+    assert_eq!(compactions(&store), 1);
+}
+
+#[test]
+fn log_file_name_is_configurable() {
+    let temp_dir = TempDir::new().unwrap();
+    let options = testing::options().log_file_name("custom.log");
+    let mut store = KvStore::open_with_options(temp_dir.path(), options.clone()).unwrap();
+    store.set("key".to_owned(), "value".to_owned()).unwrap();
+    store.compact().unwrap();
+    drop(store);
+
+    assert!(temp_dir.path().join("custom.log").is_file());
+    assert!(!temp_dir.path().join("db.log").exists());
+    let store = KvStore::open_with_options(temp_dir.path(), options).unwrap();
+    assert_eq!(store.get("key").unwrap(), Some("value".to_owned()));
+}
@@ -0,0 +1,53 @@
+use kvs::testing;
+use kvs::{KvsClient, KvsServer};
+use std::thread;
+use tempfile::TempDir;
+
+const CLIENTS: usize = 4;
+const INCREMENTS: usize = 25;
+
+/// Clients incrementing one counter by compare-and-swap lose none of each other's updates.
+#[test]
+fn racing_increments_over_the_protocol_all_land() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = testing::open(temp_dir.path()).unwrap();
+    let log = slog::Logger::root(slog::Discard, slog::o!());
+    let server = KvsServer::new("127.0.0.1:0", store, log).unwrap();
+    let addr = server.local_addr().unwrap();
+    thread::spawn(move || server.listen_forever());
+
+    let clients: Vec<_> = (0..CLIENTS)
+        .map(|_| {
+            thread::spawn(move || {
+                let mut client = KvsClient::connect(addr).unwrap();
+                for _ in 0..INCREMENTS {
+                    loop {
+                        let current = client.get("counter".to_owned()).unwrap();
+                        let next = current
+                            .as_deref()
+                            .map_or(0, |count| count.parse::<usize>().unwrap())
+                            + 1;
+                        if client
+                            .compare_and_swap("counter".to_owned(), current, Some(next.to_string()))
+                            .unwrap()
+                        {
+                            break;
+                        }
+                    }
+                }
+            })
+        })
+        .collect();
+    for client in clients {
+        client.join().unwrap();
+    }
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    assert_eq!(
+        client.get("counter".to_owned()).unwrap(),
+        Some((CLIENTS * INCREMENTS).to_string())
+    );
+    assert!(!client
+        .compare_and_swap("counter".to_owned(), None, Some("0".to_owned()))
+        .unwrap());
+}
@@ -0,0 +1,93 @@
+use kvs::testing;
+use kvs::KvStore;
+use std::collections::HashMap;
+use tempfile::TempDir;
+
+fn histogram(pairs: &[(&str, usize)]) -> HashMap<String, usize> {
+    pairs
+        .iter()
+        .map(|&(segment, count)| (segment.to_owned(), count))
+        .collect()
+}
+
+fn fill(store: &mut KvStore, keys: &[&str]) {
+    for key in keys {
+        store.set(key.to_string(), "v".to_owned()).unwrap();
+    }
+}
+
+#[test]
+fn count_prefix_counts_live_keys() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = testing::open(temp_dir.path()).unwrap();
+    fill(
+        &mut store,
+        &["user:1", "user:2", "user:3", "users", "order:1"],
+    );
+    store.set("user:1".to_owned(), "again".to_owned()).unwrap();
+    store.remove("user:3".to_owned()).unwrap();
+
+    assert_eq!(store.count_prefix("user:").unwrap(), 2);
+    assert_eq!(store.count_prefix("user").unwrap(), 3);
+    assert_eq!(store.count_prefix("").unwrap(), 4);
+    assert_eq!(store.count_prefix("nothing").unwrap(), 0);
+}
+
+#[test]
+fn histogram_groups_by_first_segment() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = testing::open(temp_dir.path()).unwrap();
+    fill(
+        &mut store,
+        &[
+            "user:1:name",
+            "user:2",
+            "order:1",
+            "plain",
+            ":leading",
+            "gone:1",
+        ],
+    );
+    store.remove("gone:1".to_owned()).unwrap();
+    assert_eq!(
+        store.prefix_histogram(':').unwrap(),
+        histogram(&[("user", 2), ("order", 1), ("plain", 1), ("", 1)])
+    );
+    drop(store);
+
+    let store = testing::open(temp_dir.path()).unwrap();
+    assert_eq!(
+        store.prefix_histogram('/').unwrap(),
+        histogram(&[
+            ("user:1:name", 1),
+            ("user:2", 1),
+            ("order:1", 1),
+            ("plain", 1),
+            (":leading", 1),
+        ])
+    );
+}
+
+#[test]
+fn histogram_splits_on_unicode_delimiters() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = testing::open(temp_dir.path()).unwrap();
+    fill(
+        &mut store,
+        &["café→menu", "café→hours", "thé→menu", "тест", "a→b→c"],
+    );
+    assert_eq!(
+        store.prefix_histogram('→').unwrap(),
+        histogram(&[("café", 2), ("thé", 1), ("тест", 1), ("a", 1)])
+    );
+    assert_eq!(store.count_prefix("café→").unwrap(), 2);
+
+    let tiered = TempDir::new().unwrap();
+    let mut store =
+        KvStore::open_with_options(tiered.path(), testing::options().max_index_bytes(64)).unwrap();
+    fill(&mut store, &["café→menu", "café→hours", "thé→menu"]);
+    assert_eq!(
+        store.prefix_histogram('→').unwrap(),
+        histogram(&[("café", 2), ("thé", 1)])
+    );
+}
@@ -0,0 +1,193 @@
+use assert_cmd::prelude::*;
+use kvs::testing;
+use kvs::{KvError, KvStore, KvStoreOptions, KvsClient, KvsServer};
+use predicates::str::contains;
+use std::path::Path;
+use std::process::Command;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+const KEYS: usize = 20;
+
+fn shared() -> KvStoreOptions {
+    testing::options().read_only(true).shared_lock(true)
+}
+
+fn round_of(value: &str) -> u64 {
+    value.strip_prefix("round").unwrap().parse().unwrap()
+}
+
+/// One read-only command's worth of work: open, read every key, drop.
+fn scan(dir: &Path) -> Vec<u64> {
+    let store = KvStore::open_with_options(dir, shared()).unwrap();
+    (0..KEYS)
+        .map(|i| round_of(&store.get(&format!("key{}", i)).unwrap().unwrap()))
+        .collect()
+}
+
+#[test]
+fn second_writer_is_refused_until_the_first_is_dropped() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = testing::open(temp_dir.path()).unwrap();
+    store.set("key".to_owned(), "value".to_owned()).unwrap();
+
+    match testing::open(temp_dir.path()) {
+        Err(KvError::StoreLocked(path)) => assert_eq!(path, temp_dir.path()),
+        other => panic!("expected StoreLocked, got {:?}", other.map(drop)),
+    }
+    let reader = KvStore::open_with_options(temp_dir.path(), shared()).unwrap();
+    assert_eq!(reader.get("key").unwrap(), Some("value".to_owned()));
+    drop(reader);
+
+    // Reopening in place keeps the lock rather than contending with itself.
+    store.reopen().unwrap();
+    store.set("key".to_owned(), "again".to_owned()).unwrap();
+    drop(store);
+    let store = testing::open(temp_dir.path()).unwrap();
+    assert_eq!(store.get("key").unwrap(), Some("again".to_owned()));
+}
+
+#[test]
+fn compaction_is_put_off_while_a_reader_holds_the_shared_lock() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = testing::open(temp_dir.path()).unwrap();
+    for round in 0..3 {
+        store
+            .set("key".to_owned(), format!("round{}", round))
+            .unwrap();
+    }
+    let compactions = |store: &KvStore| store.stats().since_open.compactions;
+    let before = compactions(&store);
+    let reader = KvStore::open_with_options(temp_dir.path(), shared()).unwrap();
+
+    // Asked for, it fails rather than waiting for the reader.
+    let started = Instant::now();
+    match store.compact() {
+        Err(KvError::ReadersActive(path)) => assert_eq!(path, temp_dir.path()),
+        other => panic!("expected ReadersActive, got {:?}", other.map(drop)),
+    }
+    assert!(started.elapsed() < Duration::from_secs(1));
+
+    // Writes that would compact carry on without it.
+    let started = Instant::now();
+    for i in 0..2_000 {
+        store.set("key".to_owned(), format!("{:0100}", i)).unwrap();
+    }
+    assert!(started.elapsed() < Duration::from_secs(20));
+    assert_eq!(compactions(&store), before);
+    // The reader still reads the log as it was when it opened.
+    assert_eq!(reader.get("key").unwrap(), Some("round2".to_owned()));
+
+    // The first write once the reader is gone compacts.
+    drop(reader);
+    store.set("key".to_owned(), "last".to_owned()).unwrap();
+    assert_eq!(compactions(&store), before + 1);
+    assert_eq!(store.get("key").unwrap(), Some("last".to_owned()));
+}
+
+#[test]
+fn a_refreshing_reader_holds_compaction_off_only_while_it_holds_the_lock() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = testing::open(temp_dir.path()).unwrap();
+    store.set("key".to_owned(), "round0".to_owned()).unwrap();
+    let options = testing::options().read_only(true);
+    let mut reader = KvStore::open_with_options(temp_dir.path(), options).unwrap();
+    store.set("key".to_owned(), "round1".to_owned()).unwrap();
+
+    let lock = reader.reader_lock().unwrap();
+    match store.compact() {
+        Err(KvError::ReadersActive(path)) => assert_eq!(path, temp_dir.path()),
+        other => panic!("expected ReadersActive, got {:?}", other.map(drop)),
+    }
+    reader.refresh().unwrap();
+    assert_eq!(reader.get("key").unwrap(), Some("round1".to_owned()));
+    drop(lock);
+
+    store.compact().unwrap();
+    let _lock = reader.reader_lock().unwrap();
+    assert!(reader.refresh().unwrap().reloaded);
+    assert_eq!(reader.get("key").unwrap(), Some("round1".to_owned()));
+}
+
+#[test]
+fn read_only_scans_against_a_live_server_never_fail_or_go_back() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = testing::open(temp_dir.path()).unwrap();
+    for i in 0..KEYS {
+        store.set(format!("key{}", i), "round0".to_owned()).unwrap();
+    }
+    let log = slog::Logger::root(slog::Discard, slog::o!());
+    let server = KvsServer::new("127.0.0.1:0", store, log).unwrap();
+    let addr = server.local_addr().unwrap();
+    thread::spawn(move || server.listen_forever());
+
+    // Bulk rounds of 2,500 writes cross the store's compaction threshold every fourth one.
+    let stop = Arc::new(AtomicBool::new(false));
+    let writer = {
+        let stop = Arc::clone(&stop);
+        thread::spawn(move || {
+            let mut client = KvsClient::connect(addr).unwrap();
+            let mut round = 0;
+            while !stop.load(Ordering::SeqCst) || round < 12 {
+                round += 1;
+                let pairs = (0..2_500)
+                    .map(|n| (format!("key{}", n % KEYS), format!("round{}", round)))
+                    .collect();
+                client.set_bulk(pairs).unwrap();
+            }
+            round
+        })
+    };
+
+    let readers: Vec<_> = (0..3)
+        .map(|_| {
+            let dir = temp_dir.path().to_path_buf();
+            let stop = Arc::clone(&stop);
+            thread::spawn(move || {
+                let mut last = vec![0; KEYS];
+                let mut scans = 0;
+                while !stop.load(Ordering::SeqCst) {
+                    let rounds = scan(&dir);
+                    for (key, (&previous, &round)) in last.iter().zip(&rounds).enumerate() {
+                        assert!(round >= previous, "key{} went back", key);
+                    }
+                    last = rounds;
+                    scans += 1;
+                }
+                scans
+            })
+        })
+        .collect();
+
+    while !writer.is_finished() {
+        let rounds_done = scan(temp_dir.path()).iter().copied().min().unwrap();
+        if rounds_done >= 12 {
+            stop.store(true, Ordering::SeqCst);
+        }
+        thread::yield_now();
+    }
+    stop.store(true, Ordering::SeqCst);
+    let rounds = writer.join().unwrap();
+    for reader in readers {
+        assert!(reader.join().unwrap() > 0);
+    }
+    assert!(scan(temp_dir.path()).iter().all(|&round| round == rounds));
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key3", "--dir"])
+        .arg(temp_dir.path())
+        .assert()
+        .success()
+        .stdout(format!("round{}\n", rounds));
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["set", "key3", "offline", "--dir"])
+        .arg(temp_dir.path())
+        .assert()
+        .failure()
+        .stderr(contains("open for writing by another store"));
+}
@@ -0,0 +1,103 @@
+#![cfg(unix)]
+
+use kvs::testing;
+use kvs::{KvError, KvsClient, KvsServer};
+use std::fs;
+use std::path::Path;
+use std::thread;
+use tempfile::TempDir;
+
+/// Builds a standalone store at `dir` holding `key -> value`, standing in for a restored
+/// backup.
+fn restored_copy(dir: &Path, key: &str, value: &str) {
+    fs::create_dir(dir).unwrap();
+    let mut store = testing::open(dir).unwrap();
+    store.set(key.to_owned(), value.to_owned()).unwrap();
+}
+
+#[test]
+fn replaced_directory_is_detected_and_recovered_by_reopen() {
+    let temp_dir = TempDir::new().unwrap();
+    let live = temp_dir.path().join("live");
+    fs::create_dir(&live).unwrap();
+
+    let mut store = testing::open(&live).unwrap();
+    store.set("key".to_owned(), "original".to_owned()).unwrap();
+
+    let restored = temp_dir.path().join("restored");
+    restored_copy(&restored, "key", "restored");
+    fs::rename(&live, temp_dir.path().join("old")).unwrap();
+    fs::rename(&restored, &live).unwrap();
+
+    assert!(matches!(store.get("key"), Err(KvError::StoreDisplaced(_))));
+    assert!(matches!(
+        store.set("key".to_owned(), "lost".to_owned()),
+        Err(KvError::StoreDisplaced(_))
+    ));
+    assert!(matches!(
+        store.remove("key".to_owned()),
+        Err(KvError::StoreDisplaced(_))
+    ));
+
+    store.reopen().unwrap();
+    assert_eq!(store.get("key").unwrap(), Some("restored".to_owned()));
+    store.set("key".to_owned(), "after".to_owned()).unwrap();
+    drop(store);
+
+    let store = testing::open(&live).unwrap();
+    assert_eq!(store.get("key").unwrap(), Some("after".to_owned()));
+}
+
+#[test]
+fn moved_directory_is_detected() {
+    let temp_dir = TempDir::new().unwrap();
+    let live = temp_dir.path().join("live");
+    fs::create_dir(&live).unwrap();
+
+    let mut store = testing::open(&live).unwrap();
+    store.set("key".to_owned(), "value".to_owned()).unwrap();
+    fs::rename(&live, temp_dir.path().join("moved")).unwrap();
+
+    let err = store.get("key").unwrap_err();
+    assert!(matches!(err, KvError::StoreDisplaced(_)));
+    assert!(err.to_string().contains("moved or replaced"));
+    assert!(matches!(store.reopen(), Err(KvError::DirectoryNotFound(_))));
+}
+
+#[test]
+fn own_compaction_is_not_displacement() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = testing::open(temp_dir.path()).unwrap();
+
+    // Crosses the compaction threshold, which renames a new log into place.
+    for i in 0..10_050 {
+        store.set(format!("key{}", i % 10), i.to_string()).unwrap();
+    }
+    assert_eq!(store.get("key9").unwrap(), Some("10049".to_owned()));
+}
+
+#[test]
+fn server_reopens_displaced_store_once() {
+    let temp_dir = TempDir::new().unwrap();
+    let live = temp_dir.path().join("live");
+    fs::create_dir(&live).unwrap();
+
+    let log = slog::Logger::root(slog::Discard, slog::o!());
+    let store = testing::open(&live).unwrap();
+    let server = KvsServer::new("127.0.0.1:0", store, log).unwrap();
+    let addr = server.local_addr().unwrap();
+    thread::spawn(move || server.listen_forever());
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    client.set("key".to_owned(), "original".to_owned()).unwrap();
+
+    let restored = temp_dir.path().join("restored");
+    restored_copy(&restored, "key", "restored");
+    fs::rename(&live, temp_dir.path().join("old")).unwrap();
+    fs::rename(&restored, &live).unwrap();
+
+    assert_eq!(
+        client.get("key".to_owned()).unwrap(),
+        Some("restored".to_owned())
+    );
+}
@@ -0,0 +1,138 @@
+use assert_cmd::prelude::*;
+use kvs::display::{Redacted, Truncated, DEFAULT_DISPLAY_CAP};
+use kvs::testing;
+use kvs::{KvError, KvsClient, KvsServer};
+use std::io::{self, Write};
+use std::process::Command;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use tempfile::TempDir;
+
+// Room for the ellipsis and the "(len=N)" suffix.
+const SUFFIX_ALLOWANCE: usize = 32;
+
+fn huge(len: usize) -> String {
+    "0123456789".repeat(len / 10)
+}
+
+#[derive(Clone, Default)]
+struct Capture(Arc<Mutex<Vec<u8>>>);
+
+impl Write for Capture {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Capture {
+    fn contents(&self) -> String {
+        String::from_utf8(self.0.lock().unwrap().clone()).unwrap()
+    }
+}
+
+fn start_server(redact_values: bool) -> (TempDir, Capture, std::net::SocketAddr) {
+    use slog::Drain;
+
+    let temp_dir = TempDir::new().unwrap();
+    let capture = Capture::default();
+    let decorator = slog_term::PlainSyncDecorator::new(capture.clone());
+    let drain = slog_term::FullFormat::new(decorator).build().fuse();
+    let log = slog::Logger::root(drain, slog::o!());
+
+    let store = testing::open(temp_dir.path()).unwrap();
+    let server = KvsServer::new("127.0.0.1:0", store, log)
+        .unwrap()
+        .redact_values(redact_values);
+    let addr = server.local_addr().unwrap();
+    thread::spawn(move || server.listen_forever());
+    (temp_dir, capture, addr)
+}
+
+#[test]
+fn truncated_caps_output_and_reports_length() {
+    let value = huge(4 * 1024 * 1024);
+    let rendered = Truncated(&value, 256).to_string();
+    assert!(rendered.len() <= 256 + SUFFIX_ALLOWANCE);
+    assert!(rendered.starts_with("0123456789"));
+    assert!(rendered.ends_with("…(len=4194300)"));
+
+    assert_eq!(Truncated("short", 256).to_string(), "short");
+}
+
+#[test]
+fn truncated_cuts_on_char_boundary() {
+    let value = "é".repeat(10);
+    assert_eq!(Truncated(&value, 5).to_string(), "éé…(len=20)");
+}
+
+#[test]
+fn redacted_shows_only_length() {
+    assert_eq!(Redacted("secret").to_string(), "<len=6>");
+}
+
+#[test]
+fn error_messages_truncate_keys() {
+    let key = huge(4 * 1024 * 1024);
+    let message = KvError::RemoveError(key.clone()).to_string();
+    assert!(message.len() <= DEFAULT_DISPLAY_CAP + 100);
+
+    let message = KvError::ValueSerializationError {
+        key: key.clone(),
+        details: key,
+    }
+    .to_string();
+    assert!(message.len() <= 2 * (DEFAULT_DISPLAY_CAP + SUFFIX_ALLOWANCE) + 100);
+}
+
+#[test]
+fn server_request_log_truncates_values() {
+    let (_temp_dir, capture, addr) = start_server(false);
+    let value = huge(4 * 1024 * 1024);
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    client.set(huge(1024 * 1024), value.clone()).unwrap();
+    assert_eq!(client.get(huge(1024 * 1024)).unwrap(), Some(value));
+
+    let err = client.remove("x".repeat(2 * 1024 * 1024)).unwrap_err();
+    assert!(err.to_string().len() <= DEFAULT_DISPLAY_CAP + 100);
+
+    let log = capture.contents();
+    assert!(log.contains("op: set"));
+    assert!(log.contains("(len=4194300)"));
+    for line in log.lines() {
+        assert!(line.len() < 4 * DEFAULT_DISPLAY_CAP, "log line too long");
+    }
+}
+
+#[test]
+fn server_redact_values_removes_content() {
+    let (_temp_dir, capture, addr) = start_server(true);
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    client
+        .set("session".to_owned(), "top-secret-token".repeat(100_000))
+        .unwrap();
+
+    let log = capture.contents();
+    assert!(log.contains("<len=1600000>"));
+    assert!(!log.contains("top-secret-token"));
+}
+
+#[test]
+fn cli_error_output_does_not_echo_key() {
+    let key = huge(100 * 1024);
+
+    let (_temp_dir, _capture, addr) = start_server(false);
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["--addr", &addr.to_string(), "rm", &key])
+        .assert()
+        .failure()
+        .stdout("Key not found\n")
+        .stderr("");
+}
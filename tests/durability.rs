@@ -0,0 +1,171 @@
+use kvs::protocol::Health;
+use kvs::testing;
+use kvs::{KvError, KvStore, KvsClient, KvsServer, StoreEvent, SyncPolicy};
+use std::io;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use tempfile::TempDir;
+
+fn open_always(dir: &Path) -> KvStore {
+    KvStore::open_with_options(dir, testing::options().sync_policy(SyncPolicy::Always)).unwrap()
+}
+
+fn is_durability_lost<T: std::fmt::Debug>(result: kvs::Result<T>) -> bool {
+    match result {
+        Err(KvError::DurabilityLost { original_error }) => {
+            assert!(original_error.contains("injected fsync failure"));
+            true
+        }
+        other => panic!("expected DurabilityLost, got {:?}", other),
+    }
+}
+
+#[test]
+fn failed_fsync_is_sticky_under_sync_always() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = open_always(temp_dir.path());
+    store.set("before".to_owned(), "1".to_owned()).unwrap();
+
+    store.inject_sync_failure(io::ErrorKind::Other);
+    assert!(is_durability_lost(
+        store.set("failed".to_owned(), "2".to_owned())
+    ));
+    assert!(store.is_durability_lost());
+
+    // The fault is gone, but the loss is not: a later fsync could succeed without the
+    // earlier pages ever reaching the disk.
+    assert!(is_durability_lost(
+        store.set("later".to_owned(), "3".to_owned())
+    ));
+    assert!(is_durability_lost(store.remove("before".to_owned())));
+    assert!(is_durability_lost(
+        store.set_batch(vec![("batch".to_owned(), "4".to_owned())])
+    ));
+    assert!(is_durability_lost(store.sync()));
+    assert_eq!(store.get("before").unwrap(), Some("1".to_owned()));
+    assert_eq!(store.get("failed").unwrap(), None);
+
+    store.acknowledge_durability_loss().unwrap();
+    assert!(!store.is_durability_lost());
+    assert!(!store.is_append_poisoned());
+    store.set("after".to_owned(), "5".to_owned()).unwrap();
+    store.sync().unwrap();
+    drop(store);
+
+    let store = open_always(temp_dir.path());
+    assert_eq!(store.get("before").unwrap(), Some("1".to_owned()));
+    assert_eq!(store.get("failed").unwrap(), None);
+    assert_eq!(store.get("after").unwrap(), Some("5".to_owned()));
+}
+
+#[test]
+fn failed_explicit_sync_is_sticky_without_blocking_unsynced_writes() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = testing::open(temp_dir.path()).unwrap();
+    store.set("key".to_owned(), "1".to_owned()).unwrap();
+
+    store.inject_sync_failure(io::ErrorKind::Other);
+    assert!(is_durability_lost(store.sync()));
+    assert!(is_durability_lost(store.sync()));
+
+    // SyncPolicy::Never never promised durability, so its writes carry on.
+    store.set("key".to_owned(), "2".to_owned()).unwrap();
+    assert!(store.is_durability_lost());
+
+    store.acknowledge_durability_loss().unwrap();
+    store.sync().unwrap();
+    assert_eq!(store.get("key").unwrap(), Some("2".to_owned()));
+}
+
+fn start_server(store: KvStore) -> SocketAddr {
+    let log = slog::Logger::root(slog::Discard, slog::o!());
+    let server = KvsServer::new("127.0.0.1:0", store, log).unwrap();
+    let addr = server.local_addr().unwrap();
+    thread::spawn(move || server.listen_forever());
+    addr
+}
+
+#[test]
+fn ping_reports_lost_durability() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = open_always(temp_dir.path());
+    store.set("key".to_owned(), "value".to_owned()).unwrap();
+    store.inject_sync_failure(io::ErrorKind::Other);
+    let addr = start_server(store);
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    // The ping itself is the one request in the queue.
+    assert_eq!(
+        client.ping().unwrap(),
+        Health {
+            queue_depth: 1,
+            ..Health::default()
+        }
+    );
+    assert!(client.ping().unwrap().is_healthy());
+
+    match client.set("key".to_owned(), "lost".to_owned()) {
+        Err(KvError::ServerError(message)) => assert!(message.contains("fsync")),
+        other => panic!("expected a server error, got {:?}", other),
+    }
+    let health = client.ping().unwrap();
+    assert!(health.durability_lost);
+    assert!(!health.is_healthy());
+    assert_eq!(
+        client.get("key".to_owned()).unwrap(),
+        Some("value".to_owned())
+    );
+}
+
+#[test]
+fn flushed_writes_survive_a_reopen() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = testing::open(temp_dir.path()).unwrap();
+    store.set("flushed".to_owned(), "1".to_owned()).unwrap();
+    store.flush().unwrap();
+    drop(store);
+
+    let mut store = testing::open(temp_dir.path()).unwrap();
+    assert_eq!(store.get("flushed").unwrap(), Some("1".to_owned()));
+    store.inject_sync_failure(io::ErrorKind::Other);
+    assert!(is_durability_lost(store.flush()));
+    assert!(is_durability_lost(store.sync()));
+}
+
+#[test]
+fn drop_syncs_and_reports_a_failed_fsync() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = testing::open(temp_dir.path()).unwrap();
+    for i in 0..100 {
+        store
+            .set(format!("key{}", i), format!("value{}", i))
+            .unwrap();
+    }
+    drop(store);
+
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let sink = {
+        let events = Arc::clone(&events);
+        Box::new(move |event| events.lock().unwrap().push(event))
+    };
+    let mut store =
+        KvStore::open_with_options(temp_dir.path(), testing::options().event_sink(sink)).unwrap();
+    for i in 0..100 {
+        assert_eq!(
+            store.get(&format!("key{}", i)).unwrap(),
+            Some(format!("value{}", i))
+        );
+    }
+    store.inject_sync_failure(io::ErrorKind::Other);
+    drop(store);
+    let failed: Vec<StoreEvent> = events
+        .lock()
+        .unwrap()
+        .drain(..)
+        .filter(|event| matches!(event, StoreEvent::SyncOnDropFailed { .. }))
+        .collect();
+    assert_eq!(failed.len(), 1);
+    assert!(failed[0].to_string().contains("injected fsync failure"));
+}
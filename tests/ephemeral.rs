@@ -0,0 +1,150 @@
+use assert_cmd::prelude::*;
+use kvs::testing;
+use kvs::{KvsClient, KvsServer};
+use std::net::{SocketAddr, TcpListener};
+use std::path::Path;
+use std::process::{Child, Command};
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+fn spawn_server(dir: &Path) -> SocketAddr {
+    let log = slog::Logger::root(slog::Discard, slog::o!());
+    let store = testing::open(dir).unwrap();
+    let server = KvsServer::new("127.0.0.1:0", store, log).unwrap();
+    let addr = server.local_addr().unwrap();
+    thread::spawn(move || server.listen_forever());
+    addr
+}
+
+/// Polls `key` from a fresh connection until it disappears, failing after five seconds.
+fn wait_until_gone(addr: SocketAddr, key: &str) {
+    let mut observer = KvsClient::connect(addr).unwrap();
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while observer.get(key.to_owned()).unwrap().is_some() {
+        assert!(
+            Instant::now() < deadline,
+            "ephemeral key {} outlived its owner",
+            key
+        );
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+#[test]
+fn ephemeral_key_is_visible_while_owner_is_connected() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = spawn_server(temp_dir.path());
+
+    let mut owner = KvsClient::connect(addr).unwrap();
+    owner
+        .set_ephemeral("lock".to_owned(), "worker-1".to_owned())
+        .unwrap();
+
+    let mut other = KvsClient::connect(addr).unwrap();
+    assert_eq!(
+        other.get("lock".to_owned()).unwrap(),
+        Some("worker-1".to_owned())
+    );
+
+    drop(owner);
+    wait_until_gone(addr, "lock");
+}
+
+#[test]
+fn plain_set_makes_ephemeral_key_durable() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = spawn_server(temp_dir.path());
+
+    let mut owner = KvsClient::connect(addr).unwrap();
+    owner
+        .set_ephemeral("kept".to_owned(), "temporary".to_owned())
+        .unwrap();
+    owner
+        .set_ephemeral("dropped".to_owned(), "temporary".to_owned())
+        .unwrap();
+    let mut other = KvsClient::connect(addr).unwrap();
+    other.set("kept".to_owned(), "durable".to_owned()).unwrap();
+    drop(owner);
+
+    wait_until_gone(addr, "dropped");
+    assert_eq!(
+        other.get("kept".to_owned()).unwrap(),
+        Some("durable".to_owned())
+    );
+}
+
+#[test]
+fn ephemeral_ownership_follows_a_prefix_swap() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = spawn_server(temp_dir.path());
+
+    let mut owner = KvsClient::connect(addr).unwrap();
+    owner
+        .set_ephemeral("blue/lock".to_owned(), "worker-1".to_owned())
+        .unwrap();
+    let mut other = KvsClient::connect(addr).unwrap();
+    other
+        .set("green/lock".to_owned(), "durable".to_owned())
+        .unwrap();
+    other
+        .swap_prefixes("blue/".to_owned(), "green/".to_owned())
+        .unwrap();
+    drop(owner);
+
+    // The ephemeral value went to green/lock, and blue/lock now holds the durable one.
+    wait_until_gone(addr, "green/lock");
+    assert_eq!(
+        other.get("blue/lock".to_owned()).unwrap(),
+        Some("durable".to_owned())
+    );
+}
+
+fn free_port() -> SocketAddr {
+    TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+}
+
+fn start_server_process(dir: &Path, addr: SocketAddr) -> (Child, KvsClient) {
+    let child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", &addr.to_string(), "--engine", "kvs", "--data-dir"])
+        .arg(dir)
+        .spawn()
+        .unwrap();
+    let deadline = Instant::now() + Duration::from_secs(5);
+    loop {
+        match KvsClient::connect(addr) {
+            Ok(client) => return (child, client),
+            Err(e) => {
+                assert!(Instant::now() < deadline, "server did not start: {}", e);
+                thread::sleep(Duration::from_millis(20));
+            }
+        }
+    }
+}
+
+#[test]
+fn ephemeral_keys_are_removed_after_a_crash() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = free_port();
+
+    let (mut child, mut client) = start_server_process(temp_dir.path(), addr);
+    client
+        .set_ephemeral("session".to_owned(), "alive".to_owned())
+        .unwrap();
+    client.set("config".to_owned(), "kept".to_owned()).unwrap();
+    child.kill().unwrap();
+    child.wait().unwrap();
+
+    let (mut child, mut client) = start_server_process(temp_dir.path(), addr);
+    assert_eq!(client.get("session".to_owned()).unwrap(), None);
+    assert_eq!(
+        client.get("config".to_owned()).unwrap(),
+        Some("kept".to_owned())
+    );
+    child.kill().unwrap();
+    child.wait().unwrap();
+}
@@ -0,0 +1,155 @@
+use assert_cmd::prelude::*;
+use kvs::testing::{self, ShortWrite};
+use kvs::{KvStore, KvStoreOptions, StoreEvent};
+use predicates::prelude::*;
+use predicates::str::contains;
+use std::env;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::process::Command;
+use std::sync::{Arc, Mutex};
+use tempfile::TempDir;
+
+/// Far above any real pid, so temp files carrying it belong to a dead process.
+const DEAD_PID: u32 = 999_999_999;
+
+const WORKLOAD_DIR_VAR: &str = "KVS_EVENTS_WORKLOAD_DIR";
+const START: &str = "--- workload start ---";
+const END: &str = "--- workload end ---";
+
+/// A directory whose open has something to report: skipped records, a leftover temp file
+/// and an unreadable stats snapshot.
+fn eventful_dir(dir: &Path) {
+    let log = [
+        r#"{"Set":{"key":"a","value":"1"}}"#,
+        r#"{"Get":{"key":"a"}}"#,
+        r#"{"Get":{"key":"a"}}"#,
+    ];
+    fs::write(dir.join("db.log"), log.join("\n") + "\n").unwrap();
+    fs::write(dir.join(format!(".compact-{}-0.tmp", DEAD_PID)), "partial").unwrap();
+    fs::write(dir.join("stats.json"), "not json").unwrap();
+}
+
+/// Opens with `options` and exercises every path that reports an event.
+fn workload(dir: &Path, options: KvStoreOptions) {
+    let options = options.persist_stats(true);
+    let mut store = KvStore::open_with_options(dir, options.clone()).unwrap();
+    for i in 0..100 {
+        store.set(format!("key{}", i), "value".to_owned()).unwrap();
+    }
+    store.inject_short_write(ShortWrite {
+        after_bytes: 5,
+        error: io::ErrorKind::StorageFull,
+    });
+    assert!(store.set("torn".to_owned(), "value".to_owned()).is_err());
+    store.recover_append().unwrap();
+    // Something to reclaim, so that `compact` compacts.
+    store.set("key0".to_owned(), "again".to_owned()).unwrap();
+    store.compact().unwrap();
+    drop(store);
+    let store = KvStore::open_with_options(dir, options).unwrap();
+    assert_eq!(store.get("key99").unwrap(), Some("value".to_owned()));
+}
+
+#[test]
+fn events_reach_the_sink() {
+    let temp_dir = TempDir::new().unwrap();
+    eventful_dir(temp_dir.path());
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let sink = Arc::clone(&events);
+    let options =
+        testing::options().event_sink(Box::new(move |event| sink.lock().unwrap().push(event)));
+    workload(temp_dir.path(), options);
+
+    let events = events.lock().unwrap();
+    let stale = temp_dir.path().join(format!(".compact-{}-0.tmp", DEAD_PID));
+    assert!(events.contains(&StoreEvent::RemovedStaleTempFile(stale)));
+    assert!(events.contains(&StoreEvent::SkippedRecords {
+        log: temp_dir.path().join("db.log"),
+        count: 2,
+    }));
+    assert!(events
+        .iter()
+        .any(|event| matches!(event, StoreEvent::StatsSnapshotUnreadable { .. })));
+    assert!(events.iter().any(|event| matches!(
+        event,
+        StoreEvent::RecoveredPartialAppend { log, .. } if log.ends_with("db.log")
+    )));
+    // At both opens and from `compact`.
+    let finished: Vec<&StoreEvent> = events
+        .iter()
+        .filter(|event| matches!(event, StoreEvent::CompactionFinished { .. }))
+        .collect();
+    assert_eq!(finished.len(), 3);
+    assert!(events.iter().all(|event| !event.to_string().is_empty()));
+}
+
+/// Run by `silent_by_default` in a child process, with output capture off; does nothing
+/// when run as part of the suite.
+#[test]
+fn silent_workload() {
+    let dir = match env::var_os(WORKLOAD_DIR_VAR) {
+        Some(dir) => dir,
+        None => return,
+    };
+    println!("{}", START);
+    eprintln!("{}", START);
+    workload(Path::new(&dir), KvStoreOptions::new());
+    println!("{}", END);
+    eprintln!("{}", END);
+}
+
+fn between_markers(output: &[u8]) -> String {
+    let output = String::from_utf8_lossy(output);
+    let start = output.find(START).expect("no start marker") + START.len();
+    let end = output.find(END).expect("no end marker");
+    output[start..end].trim().to_owned()
+}
+
+#[test]
+fn silent_by_default() {
+    let temp_dir = TempDir::new().unwrap();
+    eventful_dir(temp_dir.path());
+    let output = Command::new(env::current_exe().unwrap())
+        .args([
+            "silent_workload",
+            "--exact",
+            "--nocapture",
+            "--test-threads=1",
+        ])
+        .env(WORKLOAD_DIR_VAR, temp_dir.path())
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{:?}", output);
+    assert_eq!(between_markers(&output.stdout), "");
+    assert_eq!(between_markers(&output.stderr), "");
+}
+
+#[test]
+fn cli_still_prints_warnings() {
+    let temp_dir = TempDir::new().unwrap();
+    eventful_dir(temp_dir.path());
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["set", "b", "2", "--dir"])
+        .arg(temp_dir.path())
+        .assert()
+        .success()
+        .stderr(
+            contains("Warning: removed").and(contains("Warning: skipped 2 non-mutating record(s)")),
+        );
+    // `get` opens read-only, so it leaves temp files alone, but still warns about the rest.
+    eventful_dir(temp_dir.path());
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "a", "--dir"])
+        .arg(temp_dir.path())
+        .assert()
+        .success()
+        .stdout("1\n")
+        .stderr(
+            contains("Warning: skipped 2 non-mutating record(s)")
+                .and(contains("Warning: removed").not()),
+        );
+}
@@ -0,0 +1,51 @@
+use kvs::testing;
+use kvs::{ExportFormat, KvStore};
+use serde_json::Value;
+use std::io::BufRead;
+use std::time::Duration;
+use tempfile::TempDir;
+
+#[test]
+fn exported_lines_import_into_a_fresh_store() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = testing::open(temp_dir.path()).unwrap();
+    let pairs = [
+        ("plain", "value"),
+        ("quote \" and \\ backslash", "line\nbreak\ttab"),
+        ("caf\u{e9} \u{1f600}", "\u{1f600} \u{0}"),
+        ("", "empty key"),
+        ("empty value", ""),
+    ];
+    for (key, value) in pairs {
+        store.set(key.to_owned(), value.to_owned()).unwrap();
+    }
+    store.set("removed".to_owned(), "gone".to_owned()).unwrap();
+    store.remove("removed".to_owned()).unwrap();
+    store
+        .set_with_ttl("expired".to_owned(), "gone".to_owned(), Duration::ZERO)
+        .unwrap();
+
+    let mut out = Vec::new();
+    assert_eq!(store.export(&mut out, ExportFormat::JsonLines).unwrap(), 5);
+    let mut keys = Vec::new();
+    let imported_dir = TempDir::new().unwrap();
+    let mut imported = testing::open(imported_dir.path()).unwrap();
+    for line in out.lines() {
+        let entry: Value = serde_json::from_str(&line.unwrap()).unwrap();
+        let object = entry.as_object().unwrap();
+        assert_eq!(object.len(), 2);
+        let key = object["key"].as_str().unwrap().to_owned();
+        let value = object["value"].as_str().unwrap().to_owned();
+        keys.push(key.clone());
+        imported.set(key, value).unwrap();
+    }
+
+    let mut expected_keys: Vec<&str> = pairs.iter().map(|&(key, _)| key).collect();
+    expected_keys.sort();
+    assert_eq!(keys, expected_keys);
+    drop(imported);
+    let imported = KvStore::open(imported_dir.path()).unwrap();
+    let original: Vec<_> = store.iter().unwrap().map(Result::unwrap).collect();
+    let copied: Vec<_> = imported.iter().unwrap().map(Result::unwrap).collect();
+    assert_eq!(copied, original);
+}
@@ -0,0 +1,120 @@
+use assert_cmd::prelude::*;
+use kvs::testing::{self, FixtureBuilder, SizeDistribution};
+use std::fs;
+use std::process::Command;
+use tempfile::TempDir;
+
+fn churn_builder(seed: u64) -> FixtureBuilder {
+    FixtureBuilder::new(seed)
+        .key_count(500)
+        .key_len(SizeDistribution::Uniform { min: 4, max: 40 })
+        .value_len(SizeDistribution::Bimodal {
+            small: 8,
+            large: 4096,
+            large_ratio: 0.02,
+        })
+        .overwrite_factor(2.5)
+        .delete_ratio(0.2)
+}
+
+#[test]
+fn same_seed_produces_identical_store() {
+    let first = TempDir::new().unwrap();
+    let second = TempDir::new().unwrap();
+
+    churn_builder(42).build(first.path()).unwrap();
+    churn_builder(42).build(second.path()).unwrap();
+
+    let first_log = fs::read(first.path().join("db.log")).unwrap();
+    assert!(!first_log.is_empty());
+    assert_eq!(first_log, fs::read(second.path().join("db.log")).unwrap());
+    assert_eq!(
+        fs::read(first.path().join("fixture.manifest")).unwrap(),
+        fs::read(second.path().join("fixture.manifest")).unwrap()
+    );
+}
+
+#[test]
+fn different_seeds_produce_different_stores() {
+    let first = TempDir::new().unwrap();
+    let second = TempDir::new().unwrap();
+
+    churn_builder(1).build(first.path()).unwrap();
+    churn_builder(2).build(second.path()).unwrap();
+
+    assert_ne!(
+        fs::read(first.path().join("db.log")).unwrap(),
+        fs::read(second.path().join("db.log")).unwrap()
+    );
+}
+
+#[test]
+fn manifest_matches_open_and_scan() {
+    let temp_dir = TempDir::new().unwrap();
+    let fixture = churn_builder(7)
+        .compact(false)
+        .build(temp_dir.path())
+        .unwrap();
+
+    let store = testing::open(&fixture.dir).unwrap();
+    assert_eq!(
+        fixture.manifest.verify(&store).unwrap(),
+        Vec::<String>::new()
+    );
+
+    let lines = fs::read_to_string(fixture.manifest.path()).unwrap();
+    let live = lines.lines().count();
+    assert!(live > 300 && live < 500, "unexpected live count {}", live);
+}
+
+#[test]
+fn manifest_detects_tampering() {
+    let temp_dir = TempDir::new().unwrap();
+    let fixture = churn_builder(7).build(temp_dir.path()).unwrap();
+
+    let mut store = testing::open(&fixture.dir).unwrap();
+    store.set("extra".to_owned(), "value".to_owned()).unwrap();
+    let problems = fixture.manifest.verify(&store).unwrap();
+    assert_eq!(problems, vec!["unexpected key extra".to_owned()]);
+}
+
+#[test]
+fn cli_gen_fixture_matches_builder() {
+    let from_cli = TempDir::new().unwrap();
+    let from_lib = TempDir::new().unwrap();
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args([
+            "gen-fixture",
+            "--seed",
+            "9",
+            "--keys",
+            "200",
+            "--key-len",
+            "10",
+        ])
+        .args(["--value-len", "8/512@0.1", "--overwrite-factor", "2"])
+        .args(["--delete-ratio", "0.25", "--out"])
+        .arg(from_cli.path())
+        .assert()
+        .success();
+
+    FixtureBuilder::new(9)
+        .key_count(200)
+        .key_len(SizeDistribution::Fixed(10))
+        .value_len(SizeDistribution::Bimodal {
+            small: 8,
+            large: 512,
+            large_ratio: 0.1,
+        })
+        .overwrite_factor(2.0)
+        .delete_ratio(0.25)
+        .build(from_lib.path())
+        .unwrap();
+
+    assert_eq!(
+        fs::read(from_cli.path().join("db.log")).unwrap(),
+        fs::read(from_lib.path().join("db.log")).unwrap()
+    );
+}
@@ -0,0 +1,89 @@
+use kvs::fs_probe::{assess, check_filesystem, FilesystemKind, FilesystemProbe};
+use kvs::testing;
+use kvs::{KvError, SyncPolicy};
+use std::path::Path;
+
+struct FakeProbe(Option<FilesystemKind>);
+
+impl FilesystemProbe for FakeProbe {
+    fn probe(&self, _path: &Path) -> Option<FilesystemKind> {
+        self.0
+    }
+}
+
+#[test]
+fn risky_filesystems_produce_advisories() {
+    for kind in [
+        FilesystemKind::Nfs,
+        FilesystemKind::Smb,
+        FilesystemKind::Overlay,
+        FilesystemKind::Vboxsf,
+    ] {
+        let advisory = assess(kind, SyncPolicy::Never).unwrap();
+        assert_eq!(advisory.filesystem, kind);
+        assert!(advisory.to_string().contains(&kind.to_string()));
+    }
+    assert_eq!(assess(FilesystemKind::Other, SyncPolicy::Always), None);
+}
+
+#[test]
+fn tmpfs_is_only_a_risk_when_durability_is_configured() {
+    assert_eq!(assess(FilesystemKind::Tmpfs, SyncPolicy::Never), None);
+    assert!(assess(FilesystemKind::Tmpfs, SyncPolicy::Always).is_some());
+}
+
+#[test]
+fn check_warns_or_fails_depending_on_requirement() {
+    let probe = FakeProbe(Some(FilesystemKind::Nfs));
+    let (kind, advisory) =
+        check_filesystem(&probe, Path::new("/data"), SyncPolicy::Never, false).unwrap();
+    assert_eq!(kind, Some(FilesystemKind::Nfs));
+    assert!(advisory.is_some());
+
+    match check_filesystem(&probe, Path::new("/data"), SyncPolicy::Never, true) {
+        Err(KvError::UnsafeFilesystem(advisory)) => {
+            assert_eq!(advisory.filesystem, FilesystemKind::Nfs)
+        }
+        other => panic!("unexpected result: {:?}", other),
+    }
+}
+
+#[test]
+fn undetectable_filesystem_is_a_no_op() {
+    let probe = FakeProbe(None);
+    let result = check_filesystem(&probe, Path::new("/data"), SyncPolicy::Always, true).unwrap();
+    assert_eq!(result, (None, None));
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn tmpfs_store_reports_advisory() {
+    use kvs::fs_probe::SystemProbe;
+    use kvs::KvStore;
+
+    let shm = Path::new("/dev/shm");
+    if SystemProbe.probe(shm) != Some(FilesystemKind::Tmpfs) {
+        eprintln!("/dev/shm is not tmpfs here, skipping");
+        return;
+    }
+    let temp_dir = tempfile::TempDir::new_in(shm).unwrap();
+
+    let store = testing::open(temp_dir.path()).unwrap();
+    assert_eq!(store.open_report().filesystem, Some(FilesystemKind::Tmpfs));
+    assert_eq!(store.open_report().filesystem_advisory, None);
+    drop(store);
+
+    let options = testing::options().sync_policy(SyncPolicy::Always);
+    let store = KvStore::open_with_options(temp_dir.path(), options).unwrap();
+    let advisory = store.open_report().filesystem_advisory.clone().unwrap();
+    assert_eq!(advisory.filesystem, FilesystemKind::Tmpfs);
+    drop(store);
+
+    let options = testing::options()
+        .sync_policy(SyncPolicy::Always)
+        .require_safe_filesystem(true);
+    assert!(matches!(
+        KvStore::open_with_options(temp_dir.path(), options),
+        Err(KvError::UnsafeFilesystem(_))
+    ));
+}
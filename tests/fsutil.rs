@@ -0,0 +1,110 @@
+use kvs::fsutil::{atomic_write, create_exclusive, temp_path};
+use kvs::testing;
+use kvs::KvsServer;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::process;
+use tempfile::TempDir;
+
+/// Far above any real pid, so temp files carrying it belong to a dead process.
+const DEAD_PID: u32 = 999_999_999;
+
+fn temp_files(dir: &Path) -> Vec<String> {
+    let mut names: Vec<String> = fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+        .filter(|name| name.ends_with(".tmp"))
+        .collect();
+    names.sort();
+    names
+}
+
+#[test]
+fn create_exclusive_fails_on_existing_file() {
+    let temp_dir = TempDir::new().unwrap();
+    let path = temp_dir.path().join(".compact-1-1.tmp");
+    fs::write(&path, "theirs").unwrap();
+
+    let err = create_exclusive(&path).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+    assert_eq!(fs::read_to_string(&path).unwrap(), "theirs");
+}
+
+#[test]
+fn temp_paths_are_unique_and_carry_the_pid() {
+    let temp_dir = TempDir::new().unwrap();
+    let first = temp_path(temp_dir.path(), "compact");
+    let second = temp_path(temp_dir.path(), "compact");
+    assert_ne!(first, second);
+
+    let name = first.file_name().unwrap().to_string_lossy().into_owned();
+    assert!(name.starts_with(&format!(".compact-{}-", process::id())));
+    assert!(name.ends_with(".tmp"));
+}
+
+#[test]
+fn atomic_write_replaces_contents_and_leaves_no_temp() {
+    let temp_dir = TempDir::new().unwrap();
+    let path = temp_dir.path().join("ephemeral.keys");
+    fs::write(&path, "old contents that are longer").unwrap();
+
+    atomic_write(&path, b"new").unwrap();
+    assert_eq!(fs::read_to_string(&path).unwrap(), "new");
+    assert!(temp_files(temp_dir.path()).is_empty());
+}
+
+#[test]
+fn interrupted_compaction_leaves_recoverable_state() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = testing::open(temp_dir.path()).unwrap();
+    store.set("key".to_owned(), "value".to_owned()).unwrap();
+    drop(store);
+
+    // A crash after the compacted copy was partly written but before the rename.
+    let stale = temp_dir.path().join(format!(".compact-{}-0.tmp", DEAD_PID));
+    fs::write(&stale, "{\"Set\":{\"key\":\"key\",\"va").unwrap();
+    // A temp file of a process that is still running is left alone.
+    let live = temp_dir
+        .path()
+        .join(format!(".compact-{}-999999.tmp", process::id()));
+    fs::write(&live, "in progress").unwrap();
+
+    let store = testing::open(temp_dir.path()).unwrap();
+    assert_eq!(store.get("key").unwrap(), Some("value".to_owned()));
+    assert!(!stale.exists());
+    assert!(live.exists());
+}
+
+#[test]
+fn interrupted_marker_and_index_writes_leave_recoverable_state() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = testing::open(temp_dir.path()).unwrap();
+    store.set("key".to_owned(), "value".to_owned()).unwrap();
+    drop(store);
+
+    // Crashes between writing a temp file and renaming it into place.
+    fs::write(temp_dir.path().join("ephemeral.keys"), "[]").unwrap();
+    fs::write(
+        temp_dir
+            .path()
+            .join(format!(".ephemeral.keys-{}-4.tmp", DEAD_PID)),
+        "[\"key\"]",
+    )
+    .unwrap();
+    fs::write(
+        temp_dir.path().join(format!(".index-{}-9.tmp", DEAD_PID)),
+        "[\"key\",0,",
+    )
+    .unwrap();
+
+    let log = slog::Logger::root(slog::Discard, slog::o!());
+    let store = testing::open(temp_dir.path()).unwrap();
+    let server = KvsServer::new("127.0.0.1:0", store, log).unwrap();
+    assert!(temp_files(temp_dir.path()).is_empty());
+    drop(server);
+
+    // The marker rename never happened, so the server did not treat the key as ephemeral.
+    let store = testing::open(temp_dir.path()).unwrap();
+    assert_eq!(store.get("key").unwrap(), Some("value".to_owned()));
+}
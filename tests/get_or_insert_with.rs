@@ -0,0 +1,69 @@
+use kvs::kv_store::DEFAULT_LOG_FILE_NAME;
+use kvs::testing;
+use std::cell::Cell;
+use std::fs;
+use std::panic::{self, AssertUnwindSafe};
+use tempfile::TempDir;
+
+#[test]
+fn closure_runs_only_for_a_missing_key() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = testing::open(temp_dir.path()).unwrap();
+    let calls = Cell::new(0);
+    let compute = || {
+        calls.set(calls.get() + 1);
+        "computed".to_owned()
+    };
+
+    assert_eq!(
+        store.get_or_insert_with("key".to_owned(), compute).unwrap(),
+        "computed"
+    );
+    assert_eq!(calls.get(), 1);
+    let log = temp_dir.path().join(DEFAULT_LOG_FILE_NAME);
+    let len = fs::metadata(&log).unwrap().len();
+    assert_eq!(
+        store.get_or_insert_with("key".to_owned(), compute).unwrap(),
+        "computed"
+    );
+    assert_eq!(calls.get(), 1);
+    // A present key writes nothing.
+    assert_eq!(fs::metadata(&log).unwrap().len(), len);
+    drop(store);
+
+    let mut store = testing::open(temp_dir.path()).unwrap();
+    assert_eq!(
+        store.get_or_insert_with("key".to_owned(), compute).unwrap(),
+        "computed"
+    );
+    assert_eq!(calls.get(), 1);
+    assert_eq!(store.get("key").unwrap(), Some("computed".to_owned()));
+}
+
+#[test]
+fn panicking_closure_writes_nothing() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = testing::open(temp_dir.path()).unwrap();
+    store.set("other".to_owned(), "kept".to_owned()).unwrap();
+    let log = temp_dir.path().join(DEFAULT_LOG_FILE_NAME);
+    let len = fs::metadata(&log).unwrap().len();
+
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+        store.get_or_insert_with("key".to_owned(), || panic!("no value"))
+    }));
+    assert!(result.is_err());
+    assert_eq!(fs::metadata(&log).unwrap().len(), len);
+    assert_eq!(store.get("key").unwrap(), None);
+
+    // Not poisoned, so the next write goes ahead.
+    assert_eq!(
+        store
+            .get_or_insert_with("key".to_owned(), || "later".to_owned())
+            .unwrap(),
+        "later"
+    );
+    drop(store);
+    let store = testing::open(temp_dir.path()).unwrap();
+    assert_eq!(store.get("key").unwrap(), Some("later".to_owned()));
+    assert_eq!(store.get("other").unwrap(), Some("kept".to_owned()));
+}
@@ -0,0 +1,113 @@
+use kvs::protocol::Checksum;
+use kvs::testing;
+use kvs::KvStore;
+use std::fs;
+use std::io::{self, Read};
+use tempfile::TempDir;
+
+const LARGE: usize = 50 * 1024 * 1024;
+
+/// Deterministic bytes covering every value, escapes and invalid UTF-8 included.
+fn noise(len: usize) -> Vec<u8> {
+    let mut state: u64 = 0x2545_f491_4f6c_dd1d;
+    (0..len)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as u8
+        })
+        .collect()
+}
+
+fn checksum(bytes: &[u8]) -> u64 {
+    let mut checksum = Checksum::new();
+    checksum.update(bytes);
+    checksum.value()
+}
+
+/// Reads `reader` to the end through a buffer of `chunk` bytes, checksumming as it goes.
+fn stream(mut reader: impl Read, chunk: usize) -> (u64, usize) {
+    let mut checksum = Checksum::new();
+    let mut buf = vec![0; chunk];
+    let mut total = 0;
+    loop {
+        let n = reader.read(&mut buf).unwrap();
+        if n == 0 {
+            return (checksum.value(), total);
+        }
+        checksum.update(&buf[..n]);
+        total += n;
+    }
+}
+
+#[test]
+fn large_value_streams_with_a_matching_checksum() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = testing::open(temp_dir.path()).unwrap();
+    let value = noise(LARGE);
+    let expected = checksum(&value);
+    store.set_bytes("large".to_owned(), &value).unwrap();
+    drop(value);
+
+    let reader = store.get_reader("large").unwrap().unwrap();
+    assert_eq!(stream(reader, 64 * 1024), (expected, LARGE));
+
+    // Opened before a compaction, the reader still reads the value it was opened on.
+    let reader = store.get_reader("large").unwrap().unwrap();
+    store.set("large".to_owned(), "small".to_owned()).unwrap();
+    store.compact().unwrap();
+    assert_eq!(stream(reader, 1 << 20), (expected, LARGE));
+    assert!(store.get_reader("missing").unwrap().is_none());
+}
+
+#[test]
+fn escaped_values_read_back_as_get_bytes_returns_them() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = testing::open(temp_dir.path()).unwrap();
+    let values: Vec<(&str, Vec<u8>)> = vec![
+        ("empty", Vec::new()),
+        (
+            "text",
+            "quote \" slash \\ tab \t caf\u{e9} \u{1f600}".into(),
+        ),
+        ("key \"with\" escapes\n", b"plain".to_vec()),
+        ("noise", noise(4096)),
+    ];
+    for (key, value) in &values {
+        store.set_bytes(key.to_string(), value).unwrap();
+    }
+    for (key, value) in &values {
+        for chunk in [1, 3, 7, 1024] {
+            let mut read = Vec::new();
+            let mut reader = store.get_reader(key).unwrap().unwrap();
+            let mut buf = vec![0; chunk];
+            loop {
+                match reader.read(&mut buf).unwrap() {
+                    0 => break,
+                    n => read.extend_from_slice(&buf[..n]),
+                }
+            }
+            assert_eq!(&read, value, "{} in chunks of {}", key, chunk);
+        }
+    }
+}
+
+#[test]
+fn records_laid_out_otherwise_are_read_whole() {
+    let temp_dir = TempDir::new().unwrap();
+    fs::write(
+        temp_dir.path().join("db.log"),
+        "{\"Set\":{\"value\":\"surrogates \\ud83d\\ude00 and \\u00e9\",\"key\":\"reordered\"}}\n",
+    )
+    .unwrap();
+    let options = testing::options().compact_on_open(false);
+    let store = KvStore::open_with_options(temp_dir.path(), options).unwrap();
+
+    let mut read = String::new();
+    let mut reader = store.get_reader("reordered").unwrap().unwrap();
+    reader.read_to_string(&mut read).unwrap();
+    assert_eq!(read, "surrogates \u{1f600} and \u{e9}");
+    assert_eq!(io::copy(&mut reader, &mut io::sink()).unwrap(), 0);
+    assert_eq!(store.get("reordered").unwrap(), Some(read));
+}
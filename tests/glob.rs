@@ -0,0 +1,91 @@
+use kvs::testing;
+use kvs::KvStore;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+fn store_with(dir: &TempDir, keys: &[&str]) -> KvStore {
+    let mut store = testing::open(dir.path()).unwrap();
+    for key in keys {
+        store
+            .set(key.to_string(), format!("value of {}", key))
+            .unwrap();
+    }
+    store
+}
+
+#[test]
+fn patterns_match_whole_keys() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = store_with(
+        &temp_dir,
+        &[
+            "user:1:email",
+            "user:22:email",
+            "user:1:email:old",
+            "xuser:1:email",
+            "user::email",
+            "user:\u{e9}:email",
+        ],
+    );
+    assert_eq!(
+        store.keys_matching("user:*:email").unwrap(),
+        vec![
+            "user:1:email",
+            "user:22:email",
+            "user::email",
+            "user:\u{e9}:email"
+        ]
+    );
+    assert_eq!(
+        store.keys_matching("user:?:email").unwrap(),
+        vec!["user:1:email", "user:\u{e9}:email"]
+    );
+    // Anchored at both ends: no match inside a key, or of a key's start or end alone.
+    assert!(store.keys_matching("user:1").unwrap().is_empty());
+    assert!(store.keys_matching("1:email").unwrap().is_empty());
+    assert_eq!(store.keys_matching("*user:1:email").unwrap().len(), 2);
+    assert_eq!(store.keys_matching("user:1:email*").unwrap().len(), 2);
+    assert_eq!(store.keys_matching("*").unwrap().len(), 6);
+    assert_eq!(store.keys_matching("**:email").unwrap().len(), 5);
+    assert!(store.keys_matching("").unwrap().is_empty());
+
+    assert_eq!(
+        store.get_matching("user:2*").unwrap(),
+        vec![(
+            "user:22:email".to_owned(),
+            "value of user:22:email".to_owned()
+        )]
+    );
+}
+
+#[test]
+fn escaped_wildcards_match_themselves() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = store_with(
+        &temp_dir,
+        &["a*b", "axb", "a?b", "a\\b", "a\\xb", "trailing\\"],
+    );
+    assert_eq!(store.keys_matching("a\\*b").unwrap(), vec!["a*b"]);
+    assert_eq!(store.keys_matching("a\\?b").unwrap(), vec!["a?b"]);
+    assert_eq!(store.keys_matching("a\\\\b").unwrap(), vec!["a\\b"]);
+    assert_eq!(
+        store.keys_matching("a?b").unwrap(),
+        vec!["a*b", "a?b", "a\\b", "axb"]
+    );
+    assert_eq!(
+        store.keys_matching("trailing\\").unwrap(),
+        vec!["trailing\\"]
+    );
+}
+
+#[test]
+fn pathological_patterns_finish_quickly() {
+    let temp_dir = TempDir::new().unwrap();
+    let long = "a".repeat(10_000);
+    let store = store_with(&temp_dir, &[&long, &format!("{}b", long)]);
+    let started = Instant::now();
+    let matched = store.keys_matching("a*a*a*a*a*a*a*a*a*a*b").unwrap();
+    assert_eq!(matched, vec![format!("{}b", long)]);
+    assert!(store.keys_matching("*a*a*a*a*a*a*c").unwrap().is_empty());
+    assert!(started.elapsed() < Duration::from_secs(5));
+}
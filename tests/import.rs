@@ -0,0 +1,114 @@
+use kvs::testing;
+use kvs::{ExportFormat, ImportMode, ImportStats};
+use tempfile::TempDir;
+
+#[test]
+fn export_imports_back_with_malformed_lines_counted() {
+    let source_dir = TempDir::new().unwrap();
+    let mut source = testing::open(source_dir.path()).unwrap();
+    // Enough entries for several batches.
+    for i in 0..10_000 {
+        source
+            .set(format!("key \"{}\"\n", i), format!("value \u{e9} {}", i))
+            .unwrap();
+    }
+    let mut exported = Vec::new();
+    source
+        .export(&mut exported, ExportFormat::JsonLines)
+        .unwrap();
+    exported.extend_from_slice(b"\nnot json\n{\"key\":\"no value\"}\n\xff\xfe\n");
+
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = testing::open(temp_dir.path()).unwrap();
+    let stats = store
+        .import(
+            &exported[..],
+            ExportFormat::JsonLines,
+            ImportMode::Overwrite,
+        )
+        .unwrap();
+    assert_eq!(
+        stats,
+        ImportStats {
+            imported: 10_000,
+            skipped_existing: 0,
+            malformed: 3,
+            first_malformed_line: Some(10_002),
+        }
+    );
+    let original: Vec<_> = source.iter().unwrap().map(Result::unwrap).collect();
+    let imported: Vec<_> = store.iter().unwrap().map(Result::unwrap).collect();
+    assert_eq!(imported, original);
+}
+
+#[test]
+fn skip_existing_keeps_keys_already_there() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = testing::open(temp_dir.path()).unwrap();
+    store.set("kept".to_owned(), "original".to_owned()).unwrap();
+    let lines = "{\"key\":\"kept\",\"value\":\"imported\"}\n\
+                 {\"key\":\"new\",\"value\":\"first\"}\n\
+                 {\"key\":\"new\",\"value\":\"second\"}\n";
+
+    let stats = store
+        .import(
+            lines.as_bytes(),
+            ExportFormat::JsonLines,
+            ImportMode::SkipExisting,
+        )
+        .unwrap();
+    assert_eq!((stats.imported, stats.skipped_existing), (1, 2));
+    assert_eq!(store.get("kept").unwrap(), Some("original".to_owned()));
+    assert_eq!(store.get("new").unwrap(), Some("first".to_owned()));
+
+    let stats = store
+        .import(
+            lines.as_bytes(),
+            ExportFormat::JsonLines,
+            ImportMode::Overwrite,
+        )
+        .unwrap();
+    assert_eq!((stats.imported, stats.skipped_existing), (3, 0));
+    assert_eq!(store.get("kept").unwrap(), Some("imported".to_owned()));
+    assert_eq!(store.get("new").unwrap(), Some("second".to_owned()));
+}
+
+#[test]
+fn csv_rows_load_with_quoted_fields_and_short_rows_counted() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = testing::open(temp_dir.path()).unwrap();
+    let csv = "id,name,note\r\n\
+               1,plain,first\r\n\
+               2,\"with, comma\",\"multi\nline \"\"quoted\"\"\r\nnote\"\n\
+               3\n\
+               \n\
+               \"4\",\"\",last\n\
+               5,\"unterminated,note\n";
+
+    let stats = store.import_csv(csv.as_bytes(), 0, 2, true).unwrap();
+    assert_eq!(
+        stats,
+        ImportStats {
+            imported: 3,
+            skipped_existing: 0,
+            malformed: 2,
+            first_malformed_line: Some(6),
+        }
+    );
+    assert_eq!(store.get("1").unwrap(), Some("first".to_owned()));
+    assert_eq!(
+        store.get("2").unwrap(),
+        Some("multi\nline \"quoted\"\r\nnote".to_owned())
+    );
+    assert_eq!(store.get("4").unwrap(), Some("last".to_owned()));
+    assert_eq!(store.get("id").unwrap(), None);
+    drop(store);
+
+    // Without the header skipped, it loads as a row of its own.
+    let mut store = testing::open(temp_dir.path()).unwrap();
+    let stats = store.import_csv(csv.as_bytes(), 1, 0, false).unwrap();
+    assert_eq!((stats.imported, stats.malformed), (4, 2));
+    assert_eq!(store.get("name").unwrap(), Some("id".to_owned()));
+    assert_eq!(store.get("with, comma").unwrap(), Some("2".to_owned()));
+    assert_eq!(store.get("").unwrap(), Some("4".to_owned()));
+}
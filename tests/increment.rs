@@ -0,0 +1,70 @@
+use assert_cmd::prelude::*;
+use kvs::testing;
+use kvs::{KvError, KvStore};
+use predicates::str::contains;
+use std::process::Command;
+use tempfile::TempDir;
+
+#[test]
+fn increment_counts_from_zero_and_persists() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = testing::open(temp_dir.path()).unwrap();
+    assert_eq!(store.increment("hits", 1).unwrap(), 1);
+    assert_eq!(store.increment("hits", 41).unwrap(), 42);
+    assert_eq!(store.increment("hits", -50).unwrap(), -8);
+    store.set("stock".to_owned(), "10".to_owned()).unwrap();
+    assert_eq!(store.increment("stock", -3).unwrap(), 7);
+    drop(store);
+
+    let mut store = testing::open(temp_dir.path()).unwrap();
+    assert_eq!(store.get("hits").unwrap(), Some("-8".to_owned()));
+    assert_eq!(store.increment("stock", 0).unwrap(), 7);
+}
+
+#[test]
+fn increment_leaves_non_integers_alone() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = testing::open(temp_dir.path()).unwrap();
+    store.set("name".to_owned(), "ada".to_owned()).unwrap();
+    match store.increment("name", 1) {
+        Err(KvError::NotAnInteger { key, value }) => {
+            assert_eq!(key, "name");
+            assert_eq!(value, "ada");
+        }
+        other => panic!("expected NotAnInteger, got {:?}", other),
+    }
+    assert_eq!(store.get("name").unwrap(), Some("ada".to_owned()));
+
+    store.set("max".to_owned(), i64::MAX.to_string()).unwrap();
+    assert!(matches!(
+        store.increment("max", 1),
+        Err(KvError::IntegerOverflow {
+            value: i64::MAX,
+            delta: 1,
+            ..
+        })
+    ));
+    assert_eq!(store.get("max").unwrap(), Some(i64::MAX.to_string()));
+}
+
+#[test]
+fn cli_incr() {
+    let temp_dir = TempDir::new().unwrap();
+    let incr = |args: &[&str]| {
+        Command::cargo_bin("kvs")
+            .unwrap()
+            .arg("incr")
+            .args(args)
+            .arg("--dir")
+            .arg(temp_dir.path())
+            .assert()
+    };
+    incr(&["visits"]).success().stdout("1\n");
+    incr(&["visits", "10"]).success().stdout("11\n");
+    incr(&["visits", "-20"]).success().stdout("-9\n");
+
+    let mut store = KvStore::open(temp_dir.path()).unwrap();
+    store.set("name".to_owned(), "ada".to_owned()).unwrap();
+    drop(store);
+    incr(&["name"]).failure().stderr(contains("not an integer"));
+}
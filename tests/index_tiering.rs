@@ -0,0 +1,172 @@
+use kvs::testing::{self, FixtureBuilder, SizeDistribution};
+use kvs::{KvError, KvMap, KvStore, KvStoreOptions};
+use std::path::Path;
+use tempfile::TempDir;
+
+/// Small enough that nearly every operation spills, so the cold path runs constantly.
+fn tiny_hot_tier() -> KvStoreOptions {
+    testing::options().max_index_bytes(256)
+}
+
+fn open_tiered(dir: &Path) -> KvStore {
+    KvStore::open_with_options(dir, tiny_hot_tier()).unwrap()
+}
+
+#[test]
+fn tiered_get_set_overwrite_remove() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = open_tiered(temp_dir.path());
+
+    for i in 0..200 {
+        store
+            .set(format!("key{}", i), format!("value{}", i))
+            .unwrap();
+    }
+    for i in (0..200).step_by(3) {
+        store.set(format!("key{}", i), format!("new{}", i)).unwrap();
+    }
+    for i in (0..200).step_by(5) {
+        store.remove(format!("key{}", i)).unwrap();
+    }
+
+    for i in 0..200 {
+        let expected = match i {
+            _ if i % 5 == 0 => None,
+            _ if i % 3 == 0 => Some(format!("new{}", i)),
+            _ => Some(format!("value{}", i)),
+        };
+        assert_eq!(store.get(&format!("key{}", i)).unwrap(), expected);
+    }
+    assert!(matches!(
+        store.remove("key0".to_owned()),
+        Err(KvError::RemoveError(_))
+    ));
+    assert_eq!(store.get("never-set").unwrap(), None);
+
+    let stats = store.index_stats();
+    assert!(stats.cold_hits > 0, "{:?}", stats);
+    assert!(stats.misses > 0, "{:?}", stats);
+}
+
+#[test]
+fn tiered_store_survives_reopen() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = open_tiered(temp_dir.path());
+    for i in 0..100 {
+        store
+            .set(format!("key{}", i), format!("value{}", i))
+            .unwrap();
+    }
+    store.remove("key7".to_owned()).unwrap();
+    drop(store);
+
+    let store = open_tiered(temp_dir.path());
+    for i in 0..100 {
+        let expected = if i == 7 {
+            None
+        } else {
+            Some(format!("value{}", i))
+        };
+        assert_eq!(store.get(&format!("key{}", i)).unwrap(), expected);
+    }
+    drop(store);
+
+    let in_memory = testing::open(temp_dir.path()).unwrap();
+    assert_eq!(in_memory.get("key99").unwrap(), Some("value99".to_owned()));
+}
+
+#[test]
+fn tiered_store_compacts_during_writes() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = open_tiered(temp_dir.path());
+
+    // 10,000 writes triggers a compaction part way through.
+    for round in 0..500 {
+        for i in 0..20 {
+            store
+                .set(format!("key{}", i), format!("{}-{}", i, round))
+                .unwrap();
+        }
+    }
+    for i in 0..20 {
+        assert_eq!(
+            store.get(&format!("key{}", i)).unwrap(),
+            Some(format!("{}-499", i))
+        );
+    }
+}
+
+#[test]
+fn tiered_store_matches_fixture_manifest() {
+    let temp_dir = TempDir::new().unwrap();
+    let fixture = FixtureBuilder::new(11)
+        .key_count(500)
+        .key_len(SizeDistribution::Uniform { min: 4, max: 40 })
+        .overwrite_factor(2.5)
+        .delete_ratio(0.2)
+        .compact(false)
+        .build(temp_dir.path())
+        .unwrap();
+
+    let store = open_tiered(&fixture.dir);
+    assert_eq!(
+        fixture.manifest.verify(&store).unwrap(),
+        Vec::<String>::new()
+    );
+}
+
+#[test]
+fn kv_map_ranges_over_both_tiers() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = open_tiered(temp_dir.path());
+    let mut map: KvMap<u32, String> = KvMap::new(&mut store, "numbers");
+    for i in 0..100u32 {
+        map.insert(&i, &i.to_string()).unwrap();
+    }
+    map.remove(&50).unwrap();
+
+    let keys: Vec<u32> = map.range(40..60).map(|entry| entry.unwrap().0).collect();
+    let expected: Vec<u32> = (40..60).filter(|i| *i != 50).collect();
+    assert_eq!(keys, expected);
+    assert_eq!(map.len().unwrap(), 99);
+}
+
+#[test]
+fn tiered_len_counts_keys_in_both_tiers() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = open_tiered(temp_dir.path());
+    for i in 0..100 {
+        store.set(format!("key{}", i), "value".to_owned()).unwrap();
+    }
+    for i in 0..10 {
+        store.remove(format!("key{}", i)).unwrap();
+        store
+            .set(format!("key{}", i + 50), "again".to_owned())
+            .unwrap();
+    }
+    assert_eq!(store.len(), 90);
+    store.compact().unwrap();
+    assert_eq!(store.len(), 90);
+    drop(store);
+
+    assert_eq!(open_tiered(temp_dir.path()).len(), 90);
+}
+
+#[test]
+fn tiered_keys_merge_both_tiers() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = open_tiered(temp_dir.path());
+    for i in 0..100 {
+        store
+            .set(format!("key{:03}", i), "value".to_owned())
+            .unwrap();
+    }
+    for i in (0..100).step_by(3) {
+        store.remove(format!("key{:03}", i)).unwrap();
+    }
+    let expected: Vec<String> = (0..100)
+        .filter(|i| i % 3 != 0)
+        .map(|i| format!("key{:03}", i))
+        .collect();
+    assert_eq!(store.keys().unwrap().collect::<Vec<_>>(), expected);
+}
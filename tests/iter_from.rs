@@ -0,0 +1,153 @@
+use kvs::testing;
+use kvs::{Direction, KvStore, KvStoreOptions};
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+/// Plain, ordered and tiered indexes all walk keys the same way.
+fn configurations() -> Vec<(&'static str, KvStoreOptions)> {
+    vec![
+        ("plain", testing::options()),
+        ("ordered", testing::options().ordered_index(true)),
+        ("tiered", testing::options().max_index_bytes(256)),
+    ]
+}
+
+fn keys(pairs: Vec<(String, String)>) -> Vec<String> {
+    pairs.into_iter().map(|(key, _)| key).collect()
+}
+
+fn filled(dir: &TempDir, options: KvStoreOptions) -> KvStore {
+    let mut store = KvStore::open_with_options(dir.path(), options).unwrap();
+    for n in (10..=50).step_by(10) {
+        store
+            .set(format!("k{}", n), format!("value {}", n))
+            .unwrap();
+    }
+    store
+}
+
+#[test]
+fn start_is_included_when_present() {
+    for (name, options) in configurations() {
+        let temp_dir = TempDir::new().unwrap();
+        let store = filled(&temp_dir, options);
+        assert_eq!(
+            store.iter_from("k20", Direction::Forward, 2).unwrap(),
+            [
+                ("k20".to_owned(), "value 20".to_owned()),
+                ("k30".to_owned(), "value 30".to_owned()),
+            ],
+            "{}",
+            name
+        );
+        assert_eq!(
+            keys(store.iter_from("k40", Direction::Reverse, 2).unwrap()),
+            ["k40", "k30"],
+            "{}",
+            name
+        );
+        assert_eq!(
+            keys(store.iter_from("k50", Direction::Forward, 10).unwrap()),
+            ["k50"],
+            "{}",
+            name
+        );
+        assert_eq!(
+            keys(store.iter_from("k10", Direction::Reverse, 10).unwrap()),
+            ["k10"],
+            "{}",
+            name
+        );
+        assert!(store
+            .iter_from("k20", Direction::Forward, 0)
+            .unwrap()
+            .is_empty());
+    }
+}
+
+#[test]
+fn absent_start_walks_from_its_neighbours() {
+    for (name, options) in configurations() {
+        let temp_dir = TempDir::new().unwrap();
+        let store = filled(&temp_dir, options);
+        assert_eq!(
+            keys(store.iter_from("k25", Direction::Forward, 2).unwrap()),
+            ["k30", "k40"],
+            "{}",
+            name
+        );
+        assert_eq!(
+            keys(store.iter_from("k25", Direction::Reverse, 5).unwrap()),
+            ["k20", "k10"],
+            "{}",
+            name
+        );
+        assert_eq!(
+            keys(store.iter_from("", Direction::Forward, 10).unwrap()),
+            ["k10", "k20", "k30", "k40", "k50"],
+            "{}",
+            name
+        );
+        assert_eq!(
+            keys(store.iter_from("l", Direction::Reverse, 3).unwrap()),
+            ["k50", "k40", "k30"],
+            "{}",
+            name
+        );
+        assert!(store
+            .iter_from("k51", Direction::Forward, 10)
+            .unwrap()
+            .is_empty());
+        assert!(store
+            .iter_from("k", Direction::Reverse, 10)
+            .unwrap()
+            .is_empty());
+    }
+}
+
+#[test]
+fn later_writes_reach_the_sorted_view() {
+    for (name, options) in configurations() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut store = filled(&temp_dir, options.clone());
+        assert_eq!(
+            store.iter_from("k", Direction::Forward, 100).unwrap().len(),
+            5
+        );
+
+        store.remove("k30".to_owned()).unwrap();
+        store.set("k35".to_owned(), "new".to_owned()).unwrap();
+        store.set("k20".to_owned(), "changed".to_owned()).unwrap();
+        store
+            .set_with_ttl(
+                "k15".to_owned(),
+                "gone soon".to_owned(),
+                Duration::from_millis(20),
+            )
+            .unwrap();
+        thread::sleep(Duration::from_millis(60));
+        assert_eq!(
+            store.iter_from("k40", Direction::Reverse, 3).unwrap(),
+            [
+                ("k40".to_owned(), "value 40".to_owned()),
+                ("k35".to_owned(), "new".to_owned()),
+                ("k20".to_owned(), "changed".to_owned()),
+            ],
+            "{}",
+            name
+        );
+
+        // Compaction and reopening rebuild the index, order included.
+        store.compact().unwrap();
+        store.remove("k40".to_owned()).unwrap();
+        drop(store);
+        let store = KvStore::open_with_options(temp_dir.path(), options).unwrap();
+        assert_eq!(
+            keys(store.iter_from("k20", Direction::Forward, 10).unwrap()),
+            ["k20", "k35", "k50"],
+            "{}",
+            name
+        );
+    }
+}
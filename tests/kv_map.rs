@@ -0,0 +1,208 @@
+use kvs::testing;
+use kvs::{KvError, KvMap, KvStore};
+use serde::{Deserialize, Serialize};
+use tempfile::TempDir;
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+struct Order {
+    customer: String,
+    items: Vec<String>,
+    total_cents: u64,
+}
+
+fn order(id: u64) -> Order {
+    Order {
+        customer: format!("customer-{}", id % 7),
+        items: vec![format!("item-{}", id), "shipping".to_owned()],
+        total_cents: id * 100,
+    }
+}
+
+#[test]
+fn kv_map_stores_structs_and_scans_numeric_window() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = testing::open(temp_dir.path()).unwrap();
+
+    {
+        let mut orders: KvMap<u64, Order> = KvMap::new(&mut store, "orders");
+        // Insert out of order and across digit-count boundaries (9 vs 10, 99 vs 100).
+        for id in (0..300).rev() {
+            orders.insert(&id, &order(id)).unwrap();
+        }
+        assert_eq!(orders.len().unwrap(), 300);
+        assert_eq!(orders.get(&42).unwrap(), Some(order(42)));
+        assert_eq!(orders.get(&1000).unwrap(), None);
+
+        let window: Vec<(u64, Order)> = orders.range(95..105).map(|r| r.unwrap()).collect();
+        let ids: Vec<u64> = window.iter().map(|(id, _)| *id).collect();
+        assert_eq!(ids, (95..105).collect::<Vec<u64>>());
+        assert_eq!(window[0].1, order(95));
+
+        assert!(orders.remove(&100).unwrap());
+        assert!(!orders.remove(&100).unwrap());
+        let ids: Vec<u64> = orders.range(99..=101).map(|r| r.unwrap().0).collect();
+        assert_eq!(ids, vec![99, 101]);
+    }
+
+    drop(store);
+    let mut store = testing::open(temp_dir.path()).unwrap();
+    let orders: KvMap<u64, Order> = KvMap::new(&mut store, "orders");
+    assert_eq!(orders.len().unwrap(), 299);
+    assert_eq!(orders.iter().count(), 299);
+    assert_eq!(orders.get(&299).unwrap(), Some(order(299)));
+}
+
+#[test]
+fn kv_map_signed_keys_keep_numeric_order() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = testing::open(temp_dir.path()).unwrap();
+    let mut readings: KvMap<i64, i64> = KvMap::new(&mut store, "readings");
+
+    for key in [5, -1, i64::MIN, 0, i64::MAX, -300] {
+        readings.insert(&key, &(key / 2)).unwrap();
+    }
+    let keys: Vec<i64> = readings.iter().map(|r| r.unwrap().0).collect();
+    assert_eq!(keys, vec![i64::MIN, -300, -1, 0, 5, i64::MAX]);
+}
+
+#[test]
+fn kv_maps_with_different_prefixes_do_not_interfere() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = testing::open(temp_dir.path()).unwrap();
+
+    KvMap::<String, u32>::new(&mut store, "a")
+        .insert(&"b:x".to_owned(), &1)
+        .unwrap();
+    KvMap::<String, u32>::new(&mut store, "a:b")
+        .insert(&"x".to_owned(), &2)
+        .unwrap();
+    KvMap::<String, u32>::new(&mut store, "ab")
+        .insert(&"x".to_owned(), &3)
+        .unwrap();
+
+    let a: KvMap<String, u32> = KvMap::new(&mut store, "a");
+    assert_eq!(a.len().unwrap(), 1);
+    assert_eq!(a.get(&"b:x".to_owned()).unwrap(), Some(1));
+    assert_eq!(a.get(&"x".to_owned()).unwrap(), None);
+
+    let a_b: KvMap<String, u32> = KvMap::new(&mut store, "a:b");
+    assert_eq!(a_b.len().unwrap(), 1);
+    assert_eq!(a_b.get(&"x".to_owned()).unwrap(), Some(2));
+
+    let ab: KvMap<&str, u32> = KvMap::new(&mut store, "ab");
+    assert_eq!(ab.get(&"x").unwrap(), Some(3));
+}
+
+#[test]
+fn kv_map_type_mismatch_reports_key() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = testing::open(temp_dir.path()).unwrap();
+
+    KvMap::<u64, String>::new(&mut store, "orders")
+        .insert(&7, &"not an order".to_owned())
+        .unwrap();
+
+    let orders: KvMap<u64, Order> = KvMap::new(&mut store, "orders");
+    match orders.get(&7) {
+        Err(err @ KvError::ValueSerializationError { .. }) => {
+            assert!(err.to_string().contains("6:orders:0000000000000007"));
+        }
+        other => panic!("unexpected result: {:?}", other.map(|_| ())),
+    }
+}
+
+fn nested(depth: usize) -> serde_json::Value {
+    (0..depth).fold(serde_json::Value::Null, |inner, _| {
+        serde_json::Value::Array(vec![inner])
+    })
+}
+
+#[test]
+fn kv_map_rejects_values_too_deep_to_read_back() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = testing::open(temp_dir.path()).unwrap();
+    let mut map: KvMap<String, serde_json::Value> = KvMap::new(&mut store, "json");
+
+    // Serializes fine, but serde_json refuses to parse it again.
+    let too_deep = nested(kvs::kv_map::MAX_JSON_DEPTH + 1);
+    assert!(serde_json::from_str::<serde_json::Value>(&too_deep.to_string()).is_err());
+    match map.insert(&"deep".to_owned(), &too_deep) {
+        Err(KvError::ValueNotRoundTrippable { key, .. }) => assert!(key.ends_with("deep")),
+        other => panic!("expected ValueNotRoundTrippable, got {:?}", other),
+    }
+    assert_eq!(map.get(&"deep".to_owned()).unwrap(), None);
+
+    // Brackets inside strings are not nesting.
+    let deepest = nested(kvs::kv_map::MAX_JSON_DEPTH);
+    let brackets = serde_json::Value::String("[[[{{{\"\\".repeat(100));
+    for (key, value) in [("deepest", &deepest), ("brackets", &brackets)] {
+        map.insert(&key.to_owned(), value).unwrap();
+        assert_eq!(map.get(&key.to_owned()).unwrap().as_ref(), Some(value));
+    }
+
+    let mut shallow: KvMap<String, serde_json::Value> =
+        KvMap::new(&mut store, "shallow").max_depth(3);
+    shallow.insert(&"ok".to_owned(), &nested(3)).unwrap();
+    assert!(matches!(
+        shallow.insert(&"deep".to_owned(), &nested(4)),
+        Err(KvError::ValueNotRoundTrippable { .. })
+    ));
+}
+
+#[test]
+fn kv_map_measures_values_after_escaping() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store =
+        KvStore::open_with_options(temp_dir.path(), testing::options().max_value_size(64)).unwrap();
+    let mut map: KvMap<String, String> = KvMap::new(&mut store, "text");
+
+    // 42 bytes of JSON, but the log escapes each backslash and quote again, to 84.
+    let backslashes = "\\".repeat(20);
+    match map.insert(&"slashes".to_owned(), &backslashes) {
+        Err(KvError::ValueTooLarge { size, limit, .. }) => assert_eq!((size, limit), (84, 64)),
+        other => panic!("expected ValueTooLarge, got {:?}", other),
+    }
+    assert_eq!(map.get(&"slashes".to_owned()).unwrap(), None);
+
+    let plain = "v".repeat(40);
+    map.insert(&"plain".to_owned(), &plain).unwrap();
+    assert_eq!(map.get(&"plain".to_owned()).unwrap(), Some(plain));
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+struct Note {
+    title: String,
+    body: String,
+    order: Order,
+    tags: Vec<(String, Option<u32>)>,
+}
+
+#[test]
+fn typed_values_round_trip_through_compaction_and_reopen() {
+    let temp_dir = TempDir::new().unwrap();
+    let note = Note {
+        title: "Grüße \"aus\" Köln 🎉".to_owned(),
+        body: "line one\nline two\r\n\ttabbed\u{0}nul\\".to_owned(),
+        order: order(3),
+        tags: vec![("日本語".to_owned(), Some(1)), ("none".to_owned(), None)],
+    };
+    let mut store = testing::open(temp_dir.path()).unwrap();
+    store.set_typed("note".to_owned(), &note).unwrap();
+    store
+        .set("plain".to_owned(), "not json".to_owned())
+        .unwrap();
+    assert_eq!(store.get_typed::<Note>("note").unwrap(), Some(note.clone()));
+    store.compact().unwrap();
+    drop(store);
+
+    // Every record is still one line, so the log reads back as before.
+    let log = String::from_utf8(testing::log_records(temp_dir.path())).unwrap();
+    assert_eq!(log.lines().count(), 2);
+    let store = testing::open(temp_dir.path()).unwrap();
+    assert_eq!(store.get_typed::<Note>("note").unwrap(), Some(note));
+    assert_eq!(store.get_typed::<Note>("missing").unwrap(), None);
+    match store.get_typed::<Note>("plain") {
+        Err(KvError::ValueSerializationError { key, .. }) => assert_eq!(key, "plain"),
+        other => panic!("expected ValueSerializationError, got {:?}", other),
+    }
+}
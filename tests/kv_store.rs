@@ -0,0 +1,784 @@
+use kvs::testing;
+use kvs::{KvError, KvStore};
+use serde_json::Value;
+use std::fs;
+use std::io::Read;
+use tempfile::TempDir;
+
+#[test]
+fn open_rejects_file_path() {
+    let temp_dir = TempDir::new().unwrap();
+    let log = temp_dir.path().join("db.log");
+    fs::write(&log, "").unwrap();
+
+    match testing::open(&log) {
+        Err(KvError::NotADirectory(path)) => assert_eq!(path, log),
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("opened a file as a store directory"),
+    }
+}
+
+#[test]
+fn open_missing_directory_requires_create_if_missing() {
+    let temp_dir = TempDir::new().unwrap();
+    let missing = temp_dir.path().join("nested").join("store");
+
+    match testing::open(&missing) {
+        Err(KvError::DirectoryNotFound(path)) => assert_eq!(path, missing),
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("opened a missing directory"),
+    }
+    assert!(!missing.exists());
+
+    let options = testing::options().create_if_missing(true);
+    let mut store = KvStore::open_with_options(&missing, options).unwrap();
+    store.set("key".to_owned(), "value".to_owned()).unwrap();
+    assert!(missing.join("db.log").is_file());
+}
+
+#[test]
+fn open_reports_log_left_as_directory() {
+    let temp_dir = TempDir::new().unwrap();
+    fs::create_dir(temp_dir.path().join("db.log")).unwrap();
+
+    let err = testing::open(temp_dir.path()).err().unwrap();
+    assert!(matches!(err, KvError::LogPathIsDirectory(_)));
+    let message = err.to_string();
+    assert!(message.contains("is a directory, not a log file"));
+    assert!(message.contains("move it out of the way"));
+}
+
+#[cfg(unix)]
+#[test]
+fn open_follows_directory_symlink() {
+    let temp_dir = TempDir::new().unwrap();
+    let real = temp_dir.path().join("real");
+    let link = temp_dir.path().join("link");
+    fs::create_dir(&real).unwrap();
+    std::os::unix::fs::symlink(&real, &link).unwrap();
+
+    let mut store = testing::open(&link).unwrap();
+    store.set("key".to_owned(), "value".to_owned()).unwrap();
+    drop(store);
+
+    let store = testing::open(&real).unwrap();
+    assert_eq!(store.get("key").unwrap(), Some("value".to_owned()));
+}
+
+/// The keys of the set records in the log, leaving out the record compaction keeps the
+/// last sequence number in.
+fn logged_keys(dir: &std::path::Path) -> Vec<String> {
+    String::from_utf8(testing::log_records(dir))
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+        .filter(|record| record.get("Sequence").is_none())
+        .map(|record| record["Set"]["key"].as_str().unwrap().to_owned())
+        .collect()
+}
+
+#[test]
+fn compaction_writes_records_in_key_order() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = testing::open(temp_dir.path()).unwrap();
+
+    let mut expected = Vec::new();
+    for i in (0..200).rev() {
+        let key = format!("key{}", (i * 37) % 200);
+        store.set(key.clone(), format!("value{}", i)).unwrap();
+        expected.push(key);
+    }
+    for i in 0..50 {
+        store.remove(format!("key{}", i * 3)).unwrap();
+        expected.retain(|key| *key != format!("key{}", i * 3));
+    }
+    store.set("key0".to_owned(), "again".to_owned()).unwrap();
+    expected.push("key0".to_owned());
+    expected.sort();
+
+    let before: Vec<Option<String>> = (0..200)
+        .map(|i| store.get(&format!("key{}", i)).unwrap())
+        .collect();
+    drop(store);
+
+    // Opening compacts the log.
+    let store = testing::open(temp_dir.path()).unwrap();
+    let after: Vec<Option<String>> = (0..200)
+        .map(|i| store.get(&format!("key{}", i)).unwrap())
+        .collect();
+    assert_eq!(before, after);
+    assert_eq!(store.get("key0").unwrap(), Some("again".to_owned()));
+    assert_eq!(store.get("key3").unwrap(), None);
+
+    // Written in string order, "key10" before "key2", one record per live key.
+    let keys = logged_keys(temp_dir.path());
+    assert_eq!(keys.len(), 151);
+    assert_eq!(keys, expected);
+}
+
+#[test]
+fn invalid_utf8_in_value_reports_position() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = testing::open(temp_dir.path()).unwrap();
+    store.set("good".to_owned(), "fine".to_owned()).unwrap();
+    // The '5' of the value is a byte that is never valid UTF-8. Overwriting one in the log
+    // would break the record's CRC instead, as tests/checksum.rs checks.
+    store.set_bytes("bad".to_owned(), b"01234\xff6789").unwrap();
+
+    let contents = fs::read(temp_dir.path().join("db.log")).unwrap();
+    let record_start = contents
+        .windows(b"{\"Set\":{\"key\":\"bad\"".len())
+        .position(|w| w == b"{\"Set\":{\"key\":\"bad\"")
+        .unwrap();
+
+    match store.get("bad") {
+        Err(KvError::CorruptRecord {
+            key,
+            offset,
+            details,
+        }) => {
+            assert_eq!(key, "bad");
+            assert_eq!(offset, record_start as u64);
+            assert!(details.contains("position 5"), "{}", details);
+        }
+        other => panic!("unexpected result: {:?}", other),
+    }
+    assert_eq!(store.get("good").unwrap(), Some("fine".to_owned()));
+}
+
+#[test]
+fn multibyte_values_round_trip() {
+    let temp_dir = TempDir::new().unwrap();
+    let values = [
+        ("emoji", "🦀🔑🗝️ keys"),
+        ("cjk", "键值存储"),
+        ("mixed", "naïve café \"quoted\"\nnewline"),
+    ];
+
+    let mut store = testing::open(temp_dir.path()).unwrap();
+    for (key, value) in values {
+        store.set(key.to_owned(), value.to_owned()).unwrap();
+    }
+    store.set("after".to_owned(), "ascii".to_owned()).unwrap();
+    for (key, value) in values {
+        assert_eq!(store.get(key).unwrap().as_deref(), Some(value));
+    }
+    drop(store);
+
+    // Reopening replays the log and compacts it, both of which depend on byte offsets.
+    for _ in 0..2 {
+        let store = testing::open(temp_dir.path()).unwrap();
+        for (key, value) in values {
+            assert_eq!(store.get(key).unwrap().as_deref(), Some(value));
+        }
+        assert_eq!(store.get("after").unwrap(), Some("ascii".to_owned()));
+    }
+}
+
+#[test]
+fn paranoid_checks_catch_offset_drift() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = testing::open(temp_dir.path()).unwrap();
+    store.set("a".to_owned(), "1".to_owned()).unwrap();
+    let end = fs::metadata(temp_dir.path().join("db.log")).unwrap().len();
+
+    // Another writer appends behind the store's back, so its next record lands past the
+    // offset it believes is the end of the log.
+    let mut log = fs::OpenOptions::new()
+        .append(true)
+        .open(temp_dir.path().join("db.log"))
+        .unwrap();
+    std::io::Write::write_all(&mut log, b"{\"Set\":{\"key\":\"x\",\"value\":\"2\"}}\n").unwrap();
+
+    match store.set("b".to_owned(), "3".to_owned()) {
+        Err(KvError::ConsistencyViolation { key, index, log }) => {
+            assert_eq!(key, "b");
+            assert!(index.contains(&format!("at {}", end)), "{}", index);
+            assert!(log.contains("\"key\":\"x\""), "{}", log);
+        }
+        other => panic!("expected ConsistencyViolation, got {:?}", other),
+    }
+}
+
+#[test]
+fn get_records_in_the_log_are_skipped_and_counted() {
+    let temp_dir = TempDir::new().unwrap();
+    let log = [
+        r#"{"Set":{"key":"a","value":"1"}}"#,
+        r#"{"Get":{"key":"a"}}"#,
+        r#"{"Set":{"key":"b","value":"2"}}"#,
+        r#"{"Rm":{"key":"a"}}"#,
+        r#"{"Get":{"key":"b"}}"#,
+        r#"{"Set":{"key":"c","value":"3"}}"#,
+    ];
+    fs::write(temp_dir.path().join("db.log"), log.join("\n") + "\n").unwrap();
+
+    let store = testing::open(temp_dir.path()).unwrap();
+    assert_eq!(store.open_report().skipped_records, 2);
+    assert_eq!(store.get("a").unwrap(), None);
+    assert_eq!(store.get("b").unwrap(), Some("2".to_owned()));
+    assert_eq!(store.get("c").unwrap(), Some("3".to_owned()));
+    drop(store);
+
+    // The compaction at open rewrote the log without them.
+    let log = fs::read_to_string(temp_dir.path().join("db.log")).unwrap();
+    assert!(!log.contains("Get"));
+    let store = testing::open(temp_dir.path()).unwrap();
+    assert_eq!(store.open_report().skipped_records, 0);
+    assert_eq!(store.get("c").unwrap(), Some("3".to_owned()));
+}
+
+#[test]
+fn unknown_log_records_still_fail_to_open() {
+    let temp_dir = TempDir::new().unwrap();
+    fs::write(
+        temp_dir.path().join("db.log"),
+        "{\"Append\":{\"key\":\"a\",\"value\":\"1\"}}\n",
+    )
+    .unwrap();
+    assert!(testing::open(temp_dir.path()).is_err());
+}
+
+#[test]
+fn contains_key_follows_sets_and_removes() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = testing::open(temp_dir.path()).unwrap();
+    assert!(!store.contains_key("key").unwrap());
+
+    store.set("key".to_owned(), "value".to_owned()).unwrap();
+    assert!(store.contains_key("key").unwrap());
+    assert!(!store.contains_key("other").unwrap());
+
+    store.remove("key".to_owned()).unwrap();
+    assert!(!store.contains_key("key").unwrap());
+
+    store.set("key".to_owned(), "again".to_owned()).unwrap();
+    assert!(store.contains_key("key").unwrap());
+    drop(store);
+
+    let store = testing::open(temp_dir.path()).unwrap();
+    assert!(store.contains_key("key").unwrap());
+}
+
+#[test]
+fn contains_key_does_not_read_the_log() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = testing::open(temp_dir.path()).unwrap();
+    store.set("key".to_owned(), "value".to_owned()).unwrap();
+    // A value the index points at but that no longer parses fails a get, not contains_key.
+    let log = temp_dir.path().join("db.log");
+    let garbled = fs::read(&log)
+        .unwrap()
+        .iter()
+        .map(|_| b'x')
+        .collect::<Vec<u8>>();
+    fs::write(&log, garbled).unwrap();
+
+    assert!(store.get("key").is_err());
+    assert!(store.contains_key("key").unwrap());
+}
+
+#[test]
+fn len_counts_live_keys_across_compaction_and_reopen() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = testing::open(temp_dir.path()).unwrap();
+    assert!(store.is_empty());
+
+    for i in 0..10 {
+        store.set(format!("key{}", i), "first".to_owned()).unwrap();
+    }
+    for i in 0..5 {
+        store.set(format!("key{}", i), "second".to_owned()).unwrap();
+    }
+    store.remove("key9".to_owned()).unwrap();
+    store.remove("key0".to_owned()).unwrap();
+    assert!(store.remove("key0".to_owned()).is_err());
+    assert_eq!(store.len(), 8);
+    assert!(!store.is_empty());
+
+    store.compact().unwrap();
+    assert_eq!(store.len(), 8);
+    store.set("key0".to_owned(), "back".to_owned()).unwrap();
+    assert_eq!(store.len(), 9);
+    drop(store);
+
+    let mut store = testing::open(temp_dir.path()).unwrap();
+    assert_eq!(store.len(), 9);
+    for i in 0..9 {
+        store.remove(format!("key{}", i)).unwrap();
+    }
+    assert!(store.is_empty());
+}
+
+#[test]
+fn keys_lists_each_live_key_once() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = testing::open(temp_dir.path()).unwrap();
+    assert_eq!(store.keys().unwrap().count(), 0);
+
+    for round in 0..3 {
+        for key in ["b", "a", "d", "c"] {
+            store.set(key.to_owned(), format!("{}", round)).unwrap();
+        }
+    }
+    store.remove("d".to_owned()).unwrap();
+    store.remove("a".to_owned()).unwrap();
+    store.set("a".to_owned(), "back".to_owned()).unwrap();
+    assert_eq!(store.keys().unwrap().collect::<Vec<_>>(), ["a", "b", "c"]);
+    drop(store);
+
+    // Reopened from the log uncompacted, removes and all.
+    let store = KvStore::open_read_only(temp_dir.path()).unwrap();
+    assert_eq!(store.keys().unwrap().collect::<Vec<_>>(), ["a", "b", "c"]);
+}
+
+fn overwritten_store(dir: &std::path::Path) -> KvStore {
+    let mut store = testing::open(dir).unwrap();
+    for i in 0..300 {
+        store
+            .set(format!("key{:03}", i), "first".to_owned())
+            .unwrap();
+    }
+    for i in (0..300).step_by(2) {
+        store
+            .set(format!("key{:03}", i), format!("second{}", i))
+            .unwrap();
+    }
+    for i in (0..300).step_by(5) {
+        store.remove(format!("key{:03}", i)).unwrap();
+    }
+    store
+}
+
+fn expected_value(i: usize) -> Option<String> {
+    match (i % 5, i % 2) {
+        (0, _) => None,
+        (_, 0) => Some(format!("second{}", i)),
+        _ => Some("first".to_owned()),
+    }
+}
+
+#[test]
+fn iter_yields_live_pairs_in_key_order() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = overwritten_store(temp_dir.path());
+    let expected: Vec<(String, String)> = (0..300)
+        .filter_map(|i| Some((format!("key{:03}", i), expected_value(i)?)))
+        .collect();
+
+    let pairs: Vec<(String, String)> = store.iter().unwrap().map(Result::unwrap).collect();
+    assert_eq!(pairs, expected);
+
+    // Values still come from the log the iterator was opened on.
+    let mut iter = store.iter().unwrap();
+    let first = iter.next().unwrap().unwrap();
+    store.compact().unwrap();
+    assert_eq!(first, expected[0]);
+    assert_eq!(iter.map(Result::unwrap).collect::<Vec<_>>(), &expected[1..]);
+}
+
+#[test]
+fn iter_reports_an_unreadable_value_and_carries_on() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = overwritten_store(temp_dir.path());
+
+    // Break the JSON of key151's record in place, keeping its length.
+    let log = temp_dir.path().join("db.log");
+    let mut bytes = fs::read(&log).unwrap();
+    let record = br#"{"Set":{"key":"key151","value":"first","#;
+    let start = bytes
+        .windows(record.len())
+        .position(|window| window == record)
+        .unwrap();
+    bytes[start] = b'[';
+    fs::write(&log, bytes).unwrap();
+
+    let results: Vec<_> = store.iter().unwrap().collect();
+    assert_eq!(results.len(), store.len());
+    let failed: Vec<usize> = results
+        .iter()
+        .enumerate()
+        .filter(|(_, result)| result.is_err())
+        .map(|(n, _)| n)
+        .collect();
+    assert_eq!(failed.len(), 1);
+    let keys: Vec<String> = store.keys().unwrap().collect();
+    assert_eq!(keys[failed[0]], "key151");
+    assert_eq!(
+        results.last().unwrap().as_ref().unwrap(),
+        &("key299".to_owned(), "first".to_owned())
+    );
+}
+
+#[test]
+fn scan_prefix_returns_live_pairs_under_the_prefix() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = testing::open(temp_dir.path()).unwrap();
+    for (key, value) in [
+        ("user:123:name", "ada"),
+        ("user:123:mail", "ada@example.com"),
+        ("user:1234:name", "bob"),
+        ("user:123:old", "gone"),
+        ("user:12", "short"),
+        ("zeit:ä", "umlaut"),
+        ("zeit:äb", "umlaut and more"),
+        ("zeit:a", "plain"),
+    ] {
+        store.set(key.to_owned(), value.to_owned()).unwrap();
+    }
+    store.remove("user:123:old".to_owned()).unwrap();
+    store
+        .set("user:123:name".to_owned(), "ada l.".to_owned())
+        .unwrap();
+
+    let pairs = |prefix: &str| -> Vec<(String, String)> { store.scan_prefix(prefix).unwrap() };
+    let owned = |pairs: &[(&str, &str)]| -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|&(key, value)| (key.to_owned(), value.to_owned()))
+            .collect()
+    };
+    assert_eq!(
+        pairs("user:123:"),
+        owned(&[
+            ("user:123:mail", "ada@example.com"),
+            ("user:123:name", "ada l.")
+        ])
+    );
+    assert_eq!(
+        pairs("zeit:ä"),
+        owned(&[("zeit:ä", "umlaut"), ("zeit:äb", "umlaut and more")])
+    );
+    // "ä" and "a" differ as characters, whatever their bytes have in common.
+    assert_eq!(pairs("zeit:a"), owned(&[("zeit:a", "plain")]));
+    assert_eq!(pairs("zeit:äb"), owned(&[("zeit:äb", "umlaut and more")]));
+    assert!(pairs("nothing").is_empty());
+    assert_eq!(pairs("").len(), 7);
+}
+
+#[test]
+fn multi_get_answers_in_request_order() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = testing::open(temp_dir.path()).unwrap();
+    for i in 0..100 {
+        store
+            .set(format!("key{}", i), format!("value{}", i))
+            .unwrap();
+    }
+    store.set("key5".to_owned(), "newer".to_owned()).unwrap();
+    store.remove("key7".to_owned()).unwrap();
+
+    let request: Vec<String> = ["key90", "key5", "missing", "key7", "key90", "key0", "key5"]
+        .iter()
+        .map(|key| key.to_string())
+        .collect();
+    let gets_before = store.stats().since_open.gets;
+    assert_eq!(
+        store.multi_get(&request).unwrap(),
+        [
+            Some("value90".to_owned()),
+            Some("newer".to_owned()),
+            None,
+            None,
+            Some("value90".to_owned()),
+            Some("value0".to_owned()),
+            Some("newer".to_owned()),
+        ]
+    );
+    assert_eq!(store.stats().since_open.gets - gets_before, 7);
+    assert!(store.multi_get(&[]).unwrap().is_empty());
+}
+
+#[test]
+fn compare_and_swap_writes_only_on_a_match() {
+    let temp_dir = TempDir::new().unwrap();
+    let log = temp_dir.path().join("db.log");
+    let mut store = testing::open(temp_dir.path()).unwrap();
+    let cas = |store: &mut KvStore, expected: Option<&str>, new: Option<&str>| {
+        store
+            .compare_and_swap(
+                "key".to_owned(),
+                expected.map(str::to_owned),
+                new.map(str::to_owned),
+            )
+            .unwrap()
+    };
+
+    // Insert if absent, then a second insert finds the key taken.
+    assert!(cas(&mut store, None, Some("one")));
+    assert!(!cas(&mut store, None, Some("other")));
+    assert_eq!(store.get("key").unwrap(), Some("one".to_owned()));
+
+    // Conditional update.
+    assert!(cas(&mut store, Some("one"), Some("two")));
+    let logged = fs::metadata(&log).unwrap().len();
+    assert!(!cas(&mut store, Some("one"), Some("three")));
+    assert!(!cas(&mut store, Some("one"), None));
+    assert_eq!(fs::metadata(&log).unwrap().len(), logged);
+    assert_eq!(store.get("key").unwrap(), Some("two".to_owned()));
+
+    // Conditional delete, after which expecting absence matches without writing.
+    assert!(cas(&mut store, Some("two"), None));
+    assert_eq!(store.get("key").unwrap(), None);
+    let logged = fs::metadata(&log).unwrap().len();
+    assert!(cas(&mut store, None, None));
+    assert!(!cas(&mut store, Some("two"), None));
+    assert_eq!(fs::metadata(&log).unwrap().len(), logged);
+    drop(store);
+
+    let store = testing::open(temp_dir.path()).unwrap();
+    assert_eq!(store.get("key").unwrap(), None);
+}
+
+#[test]
+fn set_if_absent_claims_a_key_once() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = testing::open(temp_dir.path()).unwrap();
+    assert!(store
+        .set_if_absent("job".to_owned(), "first".to_owned())
+        .unwrap());
+    assert!(!store
+        .set_if_absent("job".to_owned(), "second".to_owned())
+        .unwrap());
+    assert_eq!(store.get("job").unwrap(), Some("first".to_owned()));
+
+    store.remove("job".to_owned()).unwrap();
+    assert!(store
+        .set_if_absent("job".to_owned(), "again".to_owned())
+        .unwrap());
+    assert_eq!(store.get("job").unwrap(), Some("again".to_owned()));
+    drop(store);
+
+    let mut store = testing::open(temp_dir.path()).unwrap();
+    assert_eq!(store.get("job").unwrap(), Some("again".to_owned()));
+    assert!(!store
+        .set_if_absent("job".to_owned(), "later".to_owned())
+        .unwrap());
+}
+
+#[test]
+fn refused_set_if_absent_does_not_count_toward_compaction() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = testing::open(temp_dir.path()).unwrap();
+    store.set("job".to_owned(), "taken".to_owned()).unwrap();
+    let log_bytes = fs::metadata(temp_dir.path().join("db.log")).unwrap().len();
+    let compactions = store.stats().since_open.compactions;
+    for _ in 0..20_000 {
+        assert!(!store
+            .set_if_absent("job".to_owned(), "mine".to_owned())
+            .unwrap());
+    }
+    assert_eq!(store.stats().since_open.compactions, compactions);
+    assert_eq!(
+        fs::metadata(temp_dir.path().join("db.log")).unwrap().len(),
+        log_bytes
+    );
+}
+
+#[test]
+fn pop_returns_the_value_once() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = testing::open(temp_dir.path()).unwrap();
+    for i in 0..10 {
+        store
+            .set(format!("job{}", i), format!("task{}", i))
+            .unwrap();
+    }
+    store.set("job3".to_owned(), "retried".to_owned()).unwrap();
+
+    assert_eq!(store.pop("job3").unwrap(), Some("retried".to_owned()));
+    assert_eq!(store.pop("job3").unwrap(), None);
+    assert_eq!(store.get("job3").unwrap(), None);
+    assert_eq!(store.pop("never").unwrap(), None);
+    assert_eq!(store.len(), 9);
+
+    store.compact().unwrap();
+    assert_eq!(store.pop("job4").unwrap(), Some("task4".to_owned()));
+    assert_eq!(store.pop("job3").unwrap(), None);
+    drop(store);
+
+    let mut store = testing::open(temp_dir.path()).unwrap();
+    assert_eq!(store.get("job4").unwrap(), None);
+    assert_eq!(store.pop("job5").unwrap(), Some("task5".to_owned()));
+    assert_eq!(store.len(), 7);
+}
+
+#[test]
+fn rename_moves_the_value_and_survives_compaction() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = testing::open(temp_dir.path()).unwrap();
+    store.set("user:1".to_owned(), "ada".to_owned()).unwrap();
+    store.set("user:2".to_owned(), "grace".to_owned()).unwrap();
+    store
+        .set("people/3".to_owned(), "stale".to_owned())
+        .unwrap();
+
+    store.rename("user:1", "people/1".to_owned()).unwrap();
+    store.rename("user:2", "people/3".to_owned()).unwrap();
+    store.rename("people/1", "people/1".to_owned()).unwrap();
+    assert_eq!(store.get("user:1").unwrap(), None);
+    assert_eq!(store.get("people/1").unwrap(), Some("ada".to_owned()));
+    assert_eq!(store.get("people/3").unwrap(), Some("grace".to_owned()));
+    assert_eq!(store.len(), 2);
+
+    match store.rename("user:1", "people/9".to_owned()) {
+        Err(KvError::KeyNotFound(key)) => assert_eq!(key, "user:1"),
+        other => panic!("expected KeyNotFound, got {:?}", other),
+    }
+    assert_eq!(store.get("people/9").unwrap(), None);
+    drop(store);
+
+    let mut store = testing::open(temp_dir.path()).unwrap();
+    assert_eq!(store.get("people/1").unwrap(), Some("ada".to_owned()));
+    assert_eq!(store.get("user:2").unwrap(), None);
+    store.compact().unwrap();
+    assert_eq!(store.get("people/3").unwrap(), Some("grace".to_owned()));
+    assert_eq!(logged_keys(temp_dir.path()), ["people/1", "people/3"]);
+}
+
+#[test]
+fn rename_cut_short_by_a_crash_leaves_the_old_key() {
+    let temp_dir = TempDir::new().unwrap();
+    let log = temp_dir.path().join("db.log");
+    let mut store = testing::open(temp_dir.path()).unwrap();
+    store.set("user:1".to_owned(), "ada".to_owned()).unwrap();
+    let before = fs::metadata(&log).unwrap().len() as usize;
+    store.rename("user:1", "people/1".to_owned()).unwrap();
+    drop(store);
+    let full = fs::read(&log).unwrap();
+    let header = full[before..].iter().position(|&b| b == b'\n').unwrap() + 1;
+
+    // Anywhere after the group's header and before the remove's trailing newline.
+    for cut in before + header..full.len() - 1 {
+        let crashed = TempDir::new().unwrap();
+        fs::write(crashed.path().join("db.log"), &full[..cut]).unwrap();
+        let store = testing::open(crashed.path()).unwrap();
+        assert_eq!(
+            store.get("user:1").unwrap(),
+            Some("ada".to_owned()),
+            "cut at {}",
+            cut
+        );
+        assert_eq!(store.get("people/1").unwrap(), None, "cut at {}", cut);
+    }
+}
+
+#[test]
+fn clear_empties_the_store_or_leaves_it_whole() {
+    let temp_dir = TempDir::new().unwrap();
+    let faults = testing::TransientFaults::new();
+    let options = testing::options().transient_faults(faults.clone());
+    let mut store = KvStore::open_with_options(temp_dir.path(), options.clone()).unwrap();
+    for i in 0..50 {
+        store
+            .set(format!("key{}", i), format!("value{}", i))
+            .unwrap();
+    }
+
+    // A clear whose rename fails changes nothing, in memory or on disk.
+    faults.arm(kvs::fsutil::IoSite::Rename, std::io::ErrorKind::TimedOut, 1);
+    assert!(store.clear().is_err());
+    assert_eq!(store.len(), 50);
+    assert_eq!(store.get("key7").unwrap(), Some("value7".to_owned()));
+    store.reopen().unwrap();
+    assert_eq!(store.len(), 50);
+
+    store.clear().unwrap();
+    assert!(store.is_empty());
+    assert_eq!(store.get("key7").unwrap(), None);
+    assert_eq!(
+        fs::metadata(temp_dir.path().join("db.log")).unwrap().len(),
+        testing::LOG_HEADER_LEN as u64
+    );
+    store.set("after".to_owned(), "clear".to_owned()).unwrap();
+    drop(store);
+
+    let store = KvStore::open_with_options(temp_dir.path(), options).unwrap();
+    assert_eq!(store.keys().unwrap().collect::<Vec<_>>(), ["after"]);
+}
+
+#[test]
+fn binary_values_round_trip_through_compaction_and_reopen() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = testing::open(temp_dir.path()).unwrap();
+    let blob: Vec<u8> = vec![0x00, b'"', 0x0a, b'\\', 0xff, 0xc3, 0x28, b'x'];
+    store.set_bytes("blob".to_owned(), &blob).unwrap();
+    store
+        .set_bytes("text".to_owned(), "caf\u{e9}".as_bytes())
+        .unwrap();
+    store.set("plain".to_owned(), "value".to_owned()).unwrap();
+
+    assert_eq!(store.get_bytes("blob").unwrap(), Some(blob.clone()));
+    assert_eq!(store.get("text").unwrap(), Some("caf\u{e9}".to_owned()));
+    assert_eq!(store.get_bytes("plain").unwrap(), Some(b"value".to_vec()));
+    assert_eq!(store.get_bytes("missing").unwrap(), None);
+    match store.get("blob") {
+        Err(KvError::CorruptRecord { key, .. }) => assert_eq!(key, "blob"),
+        other => panic!("expected CorruptRecord, got {:?}", other),
+    }
+
+    store.compact().unwrap();
+    assert_eq!(store.get_bytes("blob").unwrap(), Some(blob.clone()));
+    drop(store);
+
+    let store = testing::open(temp_dir.path()).unwrap();
+    assert_eq!(store.get_bytes("blob").unwrap(), Some(blob));
+    assert_eq!(store.get("text").unwrap(), Some("caf\u{e9}".to_owned()));
+    assert_eq!(store.len(), 3);
+}
+
+/// Every record of the log in `dir`, each parsed as JSON from text that must be UTF-8.
+fn parsed_records(dir: &std::path::Path) -> Vec<Value> {
+    let log = testing::log_records(dir);
+    let text = std::str::from_utf8(&log).expect("the log is not UTF-8");
+    text.lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect()
+}
+
+fn set_record<'a>(records: &'a [Value], key: &str) -> &'a Value {
+    records
+        .iter()
+        .rev()
+        .filter_map(|record| record.get("Set"))
+        .find(|set| set["key"] == key)
+        .unwrap()
+}
+
+#[test]
+fn binary_values_keep_the_json_log_valid() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = testing::open(temp_dir.path()).unwrap();
+    let blob: Vec<u8> = vec![0x00, b'"', 0x0a, 0xff, 0xc3, 0x28];
+    store.set_bytes("blob".to_owned(), &blob).unwrap();
+    store
+        .set_bytes("text".to_owned(), "caf\u{e9}".as_bytes())
+        .unwrap();
+    // Over one chunk of the reader, and not a multiple of three bytes.
+    let streamed: Vec<u8> = (0..200_001).map(|i| (i % 251) as u8).collect();
+    store
+        .set_from_reader("streamed".to_owned(), &streamed[..], streamed.len() as u64)
+        .unwrap();
+
+    let records = parsed_records(temp_dir.path());
+    assert_eq!(set_record(&records, "blob")["encoding"], "base64");
+    assert_eq!(set_record(&records, "blob")["value"], "ACIK/8Mo");
+    // A value that is UTF-8 is written as itself, for anything reading the log.
+    assert_eq!(set_record(&records, "text").get("encoding"), None);
+    assert_eq!(set_record(&records, "text")["value"], "caf\u{e9}");
+    assert_eq!(set_record(&records, "streamed")["encoding"], "base64");
+
+    let mut read = Vec::new();
+    let mut reader = store.get_reader("streamed").unwrap().unwrap();
+    reader.read_to_end(&mut read).unwrap();
+    assert_eq!(read, streamed);
+    assert_eq!(store.value_size("streamed").unwrap(), Some(200_001));
+
+    store.compact().unwrap();
+    parsed_records(temp_dir.path());
+    drop(store);
+    let store = testing::open(temp_dir.path()).unwrap();
+    assert_eq!(store.get_bytes("blob").unwrap(), Some(blob));
+    assert_eq!(store.get("text").unwrap(), Some("caf\u{e9}".to_owned()));
+    assert_eq!(store.get_bytes("streamed").unwrap(), Some(streamed));
+}
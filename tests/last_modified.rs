@@ -0,0 +1,82 @@
+use kvs::testing;
+use std::fs;
+use std::thread;
+use std::time::{Duration, SystemTime};
+use tempfile::TempDir;
+
+const TICK: Duration = Duration::from_millis(20);
+
+#[test]
+fn overwrites_move_the_time_forward() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = testing::open(temp_dir.path()).unwrap();
+    assert_eq!(store.last_modified("key").unwrap(), None);
+
+    let before = SystemTime::now() - Duration::from_millis(1);
+    store.set("key".to_owned(), "1".to_owned()).unwrap();
+    let first = store.last_modified("key").unwrap().unwrap();
+    assert!(first >= before && first <= SystemTime::now(), "{:?}", first);
+
+    let mut last = first;
+    for value in ["2", "3"] {
+        thread::sleep(TICK);
+        store.set("key".to_owned(), value.to_owned()).unwrap();
+        let modified = store.last_modified("key").unwrap().unwrap();
+        assert!(modified > last, "{:?} is not after {:?}", modified, last);
+        last = modified;
+    }
+    thread::sleep(TICK);
+    store
+        .set_batch(vec![("key".to_owned(), "4".to_owned())])
+        .unwrap();
+    assert!(store.last_modified("key").unwrap().unwrap() > last);
+    last = store.last_modified("key").unwrap().unwrap();
+    thread::sleep(TICK);
+    store
+        .set_from_reader("key".to_owned(), &b"5"[..], 1)
+        .unwrap();
+    assert!(store.last_modified("key").unwrap().unwrap() > last);
+
+    store.remove("key".to_owned()).unwrap();
+    assert_eq!(store.last_modified("key").unwrap(), None);
+}
+
+#[test]
+fn times_survive_compaction_and_reopen() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = testing::open(temp_dir.path()).unwrap();
+    store.set("early".to_owned(), "1".to_owned()).unwrap();
+    thread::sleep(TICK);
+    store.set("late".to_owned(), "2".to_owned()).unwrap();
+    let early = store.last_modified("early").unwrap().unwrap();
+    let late = store.last_modified("late").unwrap().unwrap();
+    assert!(early < late);
+
+    thread::sleep(TICK);
+    store.compact().unwrap();
+    assert_eq!(store.last_modified("early").unwrap(), Some(early));
+    assert_eq!(store.last_modified("late").unwrap(), Some(late));
+    drop(store);
+
+    let store = testing::open(temp_dir.path()).unwrap();
+    assert_eq!(store.last_modified("early").unwrap(), Some(early));
+    assert_eq!(store.last_modified("late").unwrap(), Some(late));
+}
+
+#[test]
+fn records_without_a_time_read_as_unknown() {
+    let temp_dir = TempDir::new().unwrap();
+    fs::write(
+        temp_dir.path().join("db.log"),
+        "{\"Set\":{\"key\":\"old\",\"value\":\"format\"}}\n",
+    )
+    .unwrap();
+    let mut store = testing::open(temp_dir.path()).unwrap();
+    assert_eq!(store.get("old").unwrap(), Some("format".to_owned()));
+    assert_eq!(store.last_modified("old").unwrap(), None);
+
+    store.set("new".to_owned(), "format".to_owned()).unwrap();
+    assert!(store.last_modified("new").unwrap().is_some());
+    store.compact().unwrap();
+    assert_eq!(store.last_modified("old").unwrap(), None);
+}
@@ -0,0 +1,462 @@
+use kvs::testing;
+use kvs::{KvError, KvStore, KvStoreOptions, LogFormat, MergeOperator};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::Read;
+use std::path::Path;
+use std::time::{Duration, UNIX_EPOCH};
+use tempfile::TempDir;
+
+const HEADER: &[u8] = b"\0kvs\x01\x00\x01\n";
+
+struct Concat;
+
+impl MergeOperator for Concat {
+    fn merge(&self, _key: &str, existing: Option<&str>, operand: &str) -> String {
+        format!("{}{}", existing.unwrap_or(""), operand)
+    }
+}
+
+fn bincode() -> KvStoreOptions {
+    testing::options()
+        .format(LogFormat::Bincode)
+        .merge_operator(Box::new(Concat))
+}
+
+fn log_path(dir: &Path) -> std::path::PathBuf {
+    dir.join("db.log")
+}
+
+/// Writes one of every kind of record to a bincode store in `dir`.
+fn fill(dir: &Path) {
+    let mut store = KvStore::open_with_options(dir, bincode()).unwrap();
+    store.set("plain".to_owned(), "value".to_owned()).unwrap();
+    store
+        .set("quoted".to_owned(), "a \"b\"\nc \u{20ac}".to_owned())
+        .unwrap();
+    store
+        .set_bytes("bytes".to_owned(), b"\xff\0\n\xfe")
+        .unwrap();
+    let streamed = b"from \"a\" reader";
+    store
+        .set_from_reader("streamed".to_owned(), &streamed[..], streamed.len() as u64)
+        .unwrap();
+    store
+        .set_batch(vec![
+            ("first".to_owned(), "1".to_owned()),
+            ("second".to_owned(), "2".to_owned()),
+        ])
+        .unwrap();
+    store.swap("first", "second").unwrap();
+    store.merge("merged".to_owned(), "ab".to_owned()).unwrap();
+    store.merge("merged".to_owned(), "cd".to_owned()).unwrap();
+    store
+        .set_with_ttl(
+            "lasting".to_owned(),
+            "v".to_owned(),
+            Duration::from_secs(3600),
+        )
+        .unwrap();
+    store.touch("plain", Duration::from_secs(3600)).unwrap();
+    store.set("gone".to_owned(), "soon".to_owned()).unwrap();
+    store.remove("gone".to_owned()).unwrap();
+}
+
+fn assert_filled(store: &KvStore) {
+    assert_eq!(store.get("plain").unwrap(), Some("value".to_owned()));
+    assert_eq!(
+        store.get("quoted").unwrap(),
+        Some("a \"b\"\nc \u{20ac}".to_owned())
+    );
+    assert_eq!(
+        store.get_bytes("bytes").unwrap(),
+        Some(b"\xff\0\n\xfe".to_vec())
+    );
+    assert_eq!(
+        store.get("streamed").unwrap(),
+        Some("from \"a\" reader".to_owned())
+    );
+    assert_eq!(store.get("first").unwrap(), Some("2".to_owned()));
+    assert_eq!(store.get("second").unwrap(), Some("1".to_owned()));
+    assert_eq!(store.get("merged").unwrap(), Some("abcd".to_owned()));
+    assert_eq!(store.get("lasting").unwrap(), Some("v".to_owned()));
+    assert_eq!(store.get("gone").unwrap(), None);
+}
+
+#[test]
+fn a_bincode_log_starts_with_its_header_and_holds_no_json() {
+    let temp_dir = TempDir::new().unwrap();
+    fill(temp_dir.path());
+    let contents = fs::read(log_path(temp_dir.path())).unwrap();
+    assert!(contents.starts_with(HEADER));
+    assert!(!contents.windows(6).any(|w| w == b"\"Set\":"));
+}
+
+#[test]
+fn a_bincode_store_survives_reopening() {
+    let temp_dir = TempDir::new().unwrap();
+    fill(temp_dir.path());
+    let store = KvStore::open_with_options(temp_dir.path(), bincode()).unwrap();
+    assert_filled(&store);
+    assert_eq!(store.open_report().torn_tail, None);
+}
+
+#[test]
+fn a_bincode_store_survives_compaction() {
+    let temp_dir = TempDir::new().unwrap();
+    fill(temp_dir.path());
+    let mut store = KvStore::open_with_options(temp_dir.path(), bincode()).unwrap();
+    store.compact().unwrap();
+    assert_filled(&store);
+    assert!(fs::read(log_path(temp_dir.path()))
+        .unwrap()
+        .starts_with(HEADER));
+
+    store
+        .set("after".to_owned(), "compaction".to_owned())
+        .unwrap();
+    drop(store);
+    let store = KvStore::open_with_options(temp_dir.path(), bincode()).unwrap();
+    assert_filled(&store);
+    assert_eq!(store.get("after").unwrap(), Some("compaction".to_owned()));
+}
+
+#[test]
+fn the_format_is_read_from_the_log_without_being_asked_for() {
+    let temp_dir = TempDir::new().unwrap();
+    fill(temp_dir.path());
+    let options = testing::options().merge_operator(Box::new(Concat));
+    let mut store = KvStore::open_with_options(temp_dir.path(), options).unwrap();
+    assert_filled(&store);
+    store.set("more".to_owned(), "writes".to_owned()).unwrap();
+    drop(store);
+    let store = KvStore::open_with_options(temp_dir.path(), bincode()).unwrap();
+    assert_eq!(store.get("more").unwrap(), Some("writes".to_owned()));
+}
+
+#[test]
+fn a_json_log_is_not_opened_as_bincode() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = testing::open(temp_dir.path()).unwrap();
+    store.set("key".to_owned(), "value".to_owned()).unwrap();
+    drop(store);
+    let before = fs::read(log_path(temp_dir.path())).unwrap();
+
+    match KvStore::open_with_options(temp_dir.path(), bincode()) {
+        Err(KvError::LogFormatMismatch {
+            found, requested, ..
+        }) => {
+            assert_eq!(found, LogFormat::Json);
+            assert_eq!(requested, LogFormat::Bincode);
+        }
+        Err(e) => panic!("unexpected error: {:?}", e),
+        Ok(_) => panic!("opened a JSON log as bincode"),
+    }
+    assert_eq!(fs::read(log_path(temp_dir.path())).unwrap(), before);
+}
+
+#[test]
+fn a_bincode_log_is_not_opened_as_json() {
+    let temp_dir = TempDir::new().unwrap();
+    fill(temp_dir.path());
+    let options = testing::options().format(LogFormat::Json);
+    match KvStore::open_with_options(temp_dir.path(), options) {
+        Err(e @ KvError::LogFormatMismatch { .. }) => {
+            assert!(e.to_string().contains("is a bincode log"), "{}", e);
+        }
+        Err(e) => panic!("unexpected error: {:?}", e),
+        Ok(_) => panic!("opened a bincode log as JSON"),
+    }
+}
+
+#[test]
+fn an_empty_log_takes_the_format_asked_for() {
+    let temp_dir = TempDir::new().unwrap();
+    fs::write(log_path(temp_dir.path()), b"").unwrap();
+    drop(KvStore::open_with_options(temp_dir.path(), bincode()).unwrap());
+    assert_eq!(fs::read(log_path(temp_dir.path())).unwrap(), HEADER);
+}
+
+#[test]
+fn part_of_a_header_is_rewritten_whole() {
+    let temp_dir = TempDir::new().unwrap();
+    fs::write(log_path(temp_dir.path()), &HEADER[..5]).unwrap();
+    let mut store = KvStore::open_with_options(temp_dir.path(), bincode()).unwrap();
+    store.set("key".to_owned(), "value".to_owned()).unwrap();
+    drop(store);
+    let store = KvStore::open_with_options(temp_dir.path(), bincode()).unwrap();
+    assert_eq!(store.get("key").unwrap(), Some("value".to_owned()));
+}
+
+#[test]
+fn a_header_naming_no_format_is_corruption() {
+    let temp_dir = TempDir::new().unwrap();
+    fill(temp_dir.path());
+    let mut contents = fs::read(log_path(temp_dir.path())).unwrap();
+    contents[6] = 9;
+    fs::write(log_path(temp_dir.path()), contents).unwrap();
+    match KvStore::open_with_options(temp_dir.path(), bincode()) {
+        Err(KvError::Corruption { offset, .. }) => assert_eq!(offset, 0),
+        Err(e) => panic!("unexpected error: {:?}", e),
+        Ok(_) => panic!("opened a log with a damaged header"),
+    }
+}
+
+#[test]
+fn a_torn_frame_at_the_end_is_cut_away() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = KvStore::open_with_options(temp_dir.path(), bincode()).unwrap();
+    store.set("kept".to_owned(), "value".to_owned()).unwrap();
+    let good_len = fs::metadata(log_path(temp_dir.path())).unwrap().len();
+    store.set("torn".to_owned(), "value".to_owned()).unwrap();
+    drop(store);
+    let contents = fs::read(log_path(temp_dir.path())).unwrap();
+    let torn = &contents[good_len as usize..contents.len() - 7];
+    fs::write(
+        log_path(temp_dir.path()),
+        [&contents[..good_len as usize], torn].concat(),
+    )
+    .unwrap();
+
+    let store = KvStore::open_with_options(temp_dir.path(), bincode()).unwrap();
+    let tail = store.open_report().torn_tail.unwrap();
+    assert_eq!(tail.offset, good_len);
+    assert_eq!(tail.bytes, torn.len() as u64);
+    assert_eq!(store.get("kept").unwrap(), Some("value".to_owned()));
+    assert_eq!(store.get("torn").unwrap(), None);
+}
+
+#[test]
+fn a_changed_value_byte_is_corruption() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = KvStore::open_with_options(temp_dir.path(), bincode()).unwrap();
+    store.set("good".to_owned(), "fine".to_owned()).unwrap();
+    let start = fs::metadata(log_path(temp_dir.path())).unwrap().len();
+    store
+        .set("bad".to_owned(), "0123456789".to_owned())
+        .unwrap();
+
+    let mut contents = fs::read(log_path(temp_dir.path())).unwrap();
+    let at = contents
+        .windows(10)
+        .position(|w| w == b"0123456789")
+        .unwrap();
+    contents[at + 5] = b'7';
+    fs::write(log_path(temp_dir.path()), contents).unwrap();
+
+    match store.get("bad") {
+        Err(KvError::Corruption { offset, .. }) => assert_eq!(offset, start),
+        other => panic!("unexpected result: {:?}", other),
+    }
+    assert_eq!(store.get("good").unwrap(), Some("fine".to_owned()));
+    drop(store);
+    match KvStore::open_with_options(temp_dir.path(), bincode()) {
+        Err(KvError::Corruption { offset, .. }) => assert_eq!(offset, start),
+        Err(e) => panic!("unexpected error: {:?}", e),
+        Ok(_) => panic!("opened a log with a changed record"),
+    }
+}
+
+#[test]
+fn values_stream_from_a_bincode_log() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = KvStore::open_with_options(temp_dir.path(), bincode()).unwrap();
+    let value = vec![b'x'; 100_000];
+    store.set_bytes("big".to_owned(), &value).unwrap();
+    let mut streamed = Vec::new();
+    store
+        .get_reader("big")
+        .unwrap()
+        .unwrap()
+        .read_to_end(&mut streamed)
+        .unwrap();
+    assert_eq!(streamed, value);
+}
+
+#[test]
+fn snapshots_and_checkpoints_of_a_bincode_store() {
+    let temp_dir = TempDir::new().unwrap();
+    fill(temp_dir.path());
+    let mut store = KvStore::open_with_options(temp_dir.path(), bincode()).unwrap();
+    let snapshot = store.snapshot().unwrap();
+    store.set("plain".to_owned(), "changed".to_owned()).unwrap();
+    assert_eq!(snapshot.get("plain").unwrap(), Some("value".to_owned()));
+    assert_eq!(snapshot.get("merged").unwrap(), Some("abcd".to_owned()));
+
+    let dest = temp_dir.path().join("checkpoint");
+    snapshot.checkpoint(&dest).unwrap();
+    let checkpoint = KvStore::open_with_options(&dest, bincode()).unwrap();
+    assert_filled(&checkpoint);
+}
+
+#[test]
+fn appends_after_reopening_land_in_the_same_format() {
+    let temp_dir = TempDir::new().unwrap();
+    fill(temp_dir.path());
+    let mut store = KvStore::open_with_options(temp_dir.path(), bincode()).unwrap();
+    store.set("later".to_owned(), "write".to_owned()).unwrap();
+    drop(store);
+    let contents = fs::read(log_path(temp_dir.path())).unwrap();
+    assert!(!contents.windows(6).any(|w| w == b"\"Set\":"));
+    let store = KvStore::open_with_options(temp_dir.path(), bincode()).unwrap();
+    assert_eq!(store.get("later").unwrap(), Some("write".to_owned()));
+}
+
+/// A record as another program would declare it for the `bincode` crate: the variants in
+/// the order the log numbers them, and the fields in the order it writes them.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+enum Record {
+    Set {
+        key: String,
+        value: Vec<u8>,
+        expires_at: Option<u64>,
+        timestamp: Option<u64>,
+        seq: Option<u64>,
+    },
+    Rm {
+        key: String,
+        seq: Option<u64>,
+    },
+    Merge {
+        key: String,
+        operand: String,
+        expires_at: Option<u64>,
+        timestamp: Option<u64>,
+        seq: Option<u64>,
+    },
+    Sequence {
+        seq: u64,
+    },
+    Group {
+        records: u64,
+    },
+    Touch {
+        key: String,
+        expires_at: u64,
+    },
+}
+
+/// The records of the log in `dir` as the `bincode` crate decodes them, checking that it
+/// encodes each back to the same bytes.
+fn decoded_records(dir: &Path) -> Vec<Record> {
+    testing::frame_payloads(&testing::log_records(dir))
+        .iter()
+        .map(|payload| {
+            let record: Record = bincode::deserialize(payload).unwrap();
+            assert_eq!(
+                &bincode::serialize(&record).unwrap(),
+                payload,
+                "{:?}",
+                record
+            );
+            record
+        })
+        .collect()
+}
+
+#[test]
+fn every_record_matches_the_bincode_crate() {
+    let temp_dir = TempDir::new().unwrap();
+    fill(temp_dir.path());
+    let records = decoded_records(temp_dir.path());
+    let variant = |record: &Record| match *record {
+        Record::Set { .. } => "Set",
+        Record::Rm { .. } => "Rm",
+        Record::Merge { .. } => "Merge",
+        Record::Sequence { .. } => "Sequence",
+        Record::Group { .. } => "Group",
+        Record::Touch { .. } => "Touch",
+    };
+    let mut variants: Vec<&str> = records.iter().map(variant).collect();
+    variants.dedup();
+    for expected in ["Set", "Rm", "Merge", "Group", "Touch"] {
+        assert!(
+            variants.contains(&expected),
+            "no {} in {:?}",
+            expected,
+            variants
+        );
+    }
+    assert!(records.iter().any(|record| matches!(
+        record,
+        Record::Set { key, value, seq: Some(3), .. } if key == "bytes" && value == b"\xff\0\n\xfe"
+    )));
+
+    // Compaction leaves a `Sequence` when the last write does not survive it.
+    let mut store = KvStore::open_with_options(temp_dir.path(), bincode()).unwrap();
+    store.compact().unwrap();
+    let last_seq = store.last_sequence();
+    drop(store);
+    let records = decoded_records(temp_dir.path());
+    assert_eq!(records.last(), Some(&Record::Sequence { seq: last_seq }));
+}
+
+#[test]
+fn records_encoded_by_the_bincode_crate_are_read() {
+    let temp_dir = TempDir::new().unwrap();
+    let later = UNIX_EPOCH.elapsed().unwrap().as_millis() as u64 + 3_600_000;
+    let written_at = 1_700_000_000_000;
+    let set = |key: &str, value: &[u8], seq| Record::Set {
+        key: key.to_owned(),
+        value: value.to_vec(),
+        expires_at: None,
+        timestamp: None,
+        seq: Some(seq),
+    };
+    let records = vec![
+        Record::Group { records: 2 },
+        set("a", b"1", 1),
+        set("b", b"\xff\xfe", 2),
+        Record::Set {
+            key: "c\u{e9}".to_owned(),
+            value: b"3".to_vec(),
+            expires_at: Some(later),
+            timestamp: Some(written_at),
+            seq: Some(3),
+        },
+        Record::Merge {
+            key: "c\u{e9}".to_owned(),
+            operand: "4".to_owned(),
+            expires_at: Some(later),
+            timestamp: Some(written_at),
+            seq: Some(4),
+        },
+        set("gone", b"x", 5),
+        Record::Rm {
+            key: "gone".to_owned(),
+            seq: Some(6),
+        },
+        Record::Sequence { seq: 300 },
+    ];
+    let mut log = HEADER.to_vec();
+    for record in &records {
+        log.extend(testing::log_frame(bincode::serialize(record).unwrap()));
+    }
+    fs::write(log_path(temp_dir.path()), log).unwrap();
+
+    let mut store = KvStore::open_with_options(temp_dir.path(), bincode()).unwrap();
+    assert_eq!(store.open_report().torn_tail, None);
+    let check = |store: &KvStore| {
+        assert_eq!(store.get("a").unwrap(), Some("1".to_owned()));
+        assert_eq!(store.get_bytes("b").unwrap(), Some(b"\xff\xfe".to_vec()));
+        assert_eq!(store.get("c\u{e9}").unwrap(), Some("34".to_owned()));
+        assert_eq!(
+            store.last_modified("c\u{e9}").unwrap(),
+            Some(UNIX_EPOCH + Duration::from_millis(written_at))
+        );
+        assert_eq!(store.get("gone").unwrap(), None);
+        assert_eq!(store.last_sequence(), 300);
+    };
+    check(&store);
+    let mut read = Vec::new();
+    store
+        .get_reader("b")
+        .unwrap()
+        .unwrap()
+        .read_to_end(&mut read)
+        .unwrap();
+    assert_eq!(read, b"\xff\xfe");
+
+    store.compact().unwrap();
+    check(&store);
+}
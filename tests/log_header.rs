@@ -0,0 +1,133 @@
+use kvs::testing::{self, LOG_HEADER_LEN};
+use kvs::{KvError, KvStore};
+use std::fs;
+use std::path::Path;
+use tempfile::TempDir;
+
+const JSON_HEADER: &[u8] = b"\0kvs\x01\x00\x00\n";
+
+/// Two records as they were written before logs had a header.
+const LEGACY: &str = "{\"Set\":{\"key\":\"old\",\"value\":\"log\",\"seq\":1}}\n\
+                      {\"Set\":{\"key\":\"gone\",\"value\":\"soon\",\"seq\":2}}\n\
+                      {\"Rm\":{\"key\":\"gone\",\"seq\":3}}\n";
+
+fn log_path(dir: &Path) -> std::path::PathBuf {
+    dir.join("db.log")
+}
+
+fn assert_unopened(dir: &Path, before: &[u8], expected: impl Fn(&KvError) -> bool) {
+    match testing::open(dir) {
+        Err(e) => assert!(expected(&e), "unexpected error: {:?}", e),
+        Ok(_) => panic!("opened {}", log_path(dir).display()),
+    }
+    assert_eq!(fs::read(log_path(dir)).unwrap(), before);
+}
+
+#[test]
+fn a_new_log_starts_with_its_header() {
+    let temp_dir = TempDir::new().unwrap();
+    drop(testing::open(temp_dir.path()).unwrap());
+    assert_eq!(fs::read(log_path(temp_dir.path())).unwrap(), JSON_HEADER);
+    assert_eq!(JSON_HEADER.len(), LOG_HEADER_LEN);
+
+    let mut store = testing::open(temp_dir.path()).unwrap();
+    store.set("key".to_owned(), "value".to_owned()).unwrap();
+    store.compact().unwrap();
+    store
+        .set("after".to_owned(), "compaction".to_owned())
+        .unwrap();
+    drop(store);
+    let contents = fs::read(log_path(temp_dir.path())).unwrap();
+    assert!(contents.starts_with(JSON_HEADER));
+    assert_eq!(contents.windows(4).filter(|w| w == b"\0kvs").count(), 1);
+
+    let store = testing::open(temp_dir.path()).unwrap();
+    assert_eq!(store.get("key").unwrap(), Some("value".to_owned()));
+    assert_eq!(store.get("after").unwrap(), Some("compaction".to_owned()));
+}
+
+#[test]
+fn a_log_from_before_headers_opens_and_compaction_adds_one() {
+    let temp_dir = TempDir::new().unwrap();
+    fs::write(log_path(temp_dir.path()), LEGACY).unwrap();
+    let options = testing::options().compact_on_open(false);
+    let mut store = KvStore::open_with_options(temp_dir.path(), options).unwrap();
+    assert_eq!(store.get("old").unwrap(), Some("log".to_owned()));
+    assert_eq!(store.get("gone").unwrap(), None);
+    store.set("new".to_owned(), "record".to_owned()).unwrap();
+    assert!(fs::read(log_path(temp_dir.path()))
+        .unwrap()
+        .starts_with(LEGACY.as_bytes()));
+
+    store.compact().unwrap();
+    assert!(fs::read(log_path(temp_dir.path()))
+        .unwrap()
+        .starts_with(JSON_HEADER));
+    assert_eq!(store.get("new").unwrap(), Some("record".to_owned()));
+    drop(store);
+
+    let store = testing::open(temp_dir.path()).unwrap();
+    assert_eq!(store.get("old").unwrap(), Some("log".to_owned()));
+    assert_eq!(store.get("new").unwrap(), Some("record".to_owned()));
+    assert_eq!(store.get("gone").unwrap(), None);
+}
+
+#[test]
+fn a_reader_follows_a_log_from_before_headers_through_compaction() {
+    let temp_dir = TempDir::new().unwrap();
+    fs::write(log_path(temp_dir.path()), LEGACY).unwrap();
+    let options = testing::options().compact_on_open(false);
+    let mut writer = KvStore::open_with_options(temp_dir.path(), options).unwrap();
+    let mut reader = KvStore::open_read_only(temp_dir.path()).unwrap();
+    assert_eq!(reader.get("old").unwrap(), Some("log".to_owned()));
+
+    writer.set("new".to_owned(), "record".to_owned()).unwrap();
+    writer.compact().unwrap();
+    writer.set("later".to_owned(), "write".to_owned()).unwrap();
+    assert!(reader.refresh().unwrap().reloaded);
+    assert_eq!(reader.get("old").unwrap(), Some("log".to_owned()));
+    assert_eq!(reader.get("new").unwrap(), Some("record".to_owned()));
+    assert_eq!(reader.get("later").unwrap(), Some("write".to_owned()));
+    assert_eq!(reader.get("gone").unwrap(), None);
+}
+
+#[test]
+fn a_file_that_is_not_a_kvs_log_is_refused_and_left_alone() {
+    let temp_dir = TempDir::new().unwrap();
+    let before = b"PK\x03\x04 some archive".to_vec();
+    fs::write(log_path(temp_dir.path()), &before).unwrap();
+    assert_unopened(temp_dir.path(), &before, |e| {
+        matches!(e, KvError::NotAKvsLog(log) if *log == log_path(temp_dir.path()))
+            && e.to_string().contains("is not a kvs log")
+    });
+}
+
+#[test]
+fn a_log_of_another_version_is_refused_and_left_alone() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = testing::open(temp_dir.path()).unwrap();
+    store.set("key".to_owned(), "value".to_owned()).unwrap();
+    drop(store);
+    let mut before = fs::read(log_path(temp_dir.path())).unwrap();
+    before[4] = 2;
+    fs::write(log_path(temp_dir.path()), &before).unwrap();
+
+    assert_unopened(temp_dir.path(), &before, |e| {
+        matches!(e, KvError::UnsupportedVersion { version: 2, .. })
+            && e.to_string()
+                .ends_with("is a version 2 log, and this build reads version 1")
+    });
+}
+
+#[test]
+fn a_header_naming_no_format_is_corruption() {
+    let temp_dir = TempDir::new().unwrap();
+    drop(testing::open(temp_dir.path()).unwrap());
+    let mut before = fs::read(log_path(temp_dir.path())).unwrap();
+    before[6] = 7;
+    fs::write(log_path(temp_dir.path()), &before).unwrap();
+
+    assert_unopened(temp_dir.path(), &before, |e| {
+        matches!(e, KvError::Corruption { offset: 0, .. })
+    });
+}
@@ -0,0 +1,151 @@
+use kvs::testing;
+use kvs::{KvError, KvStore, KvStoreOptions, MergeOperator};
+use std::fs;
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+/// Keeps a comma-separated list, appending each operand.
+struct ListAppend;
+
+impl MergeOperator for ListAppend {
+    fn merge(&self, _key: &str, existing: Option<&str>, operand: &str) -> String {
+        match existing {
+            Some(existing) => format!("{},{}", existing, operand),
+            None => operand.to_owned(),
+        }
+    }
+}
+
+fn with_merges() -> KvStoreOptions {
+    testing::options().merge_operator(Box::new(ListAppend))
+}
+
+fn open(dir: &Path) -> KvStore {
+    KvStore::open_with_options(dir, with_merges()).unwrap()
+}
+
+fn log(dir: &Path) -> String {
+    fs::read_to_string(dir.join("db.log")).unwrap()
+}
+
+#[test]
+fn merges_apply_in_order_across_reopen_and_compaction() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = open(temp_dir.path());
+    store.set("list".to_owned(), "a".to_owned()).unwrap();
+    for item in ["b", "c", "d"] {
+        store.merge("list".to_owned(), item.to_owned()).unwrap();
+    }
+    store.merge("fresh".to_owned(), "x".to_owned()).unwrap();
+    store.merge("fresh".to_owned(), "y".to_owned()).unwrap();
+    assert_eq!(store.get("list").unwrap(), Some("a,b,c,d".to_owned()));
+    assert_eq!(store.get("fresh").unwrap(), Some("x,y".to_owned()));
+    assert_eq!(store.len(), 2);
+    let pairs: Vec<(String, String)> = store.iter().unwrap().map(Result::unwrap).collect();
+    assert_eq!(
+        pairs,
+        vec![
+            ("fresh".to_owned(), "x,y".to_owned()),
+            ("list".to_owned(), "a,b,c,d".to_owned()),
+        ]
+    );
+    assert_eq!(log(temp_dir.path()).matches("\"Merge\"").count(), 5);
+    drop(store);
+
+    // Replay applies the operands again, without a compaction in between.
+    let options = with_merges().compact_on_open(false);
+    let mut store = KvStore::open_with_options(temp_dir.path(), options).unwrap();
+    assert_eq!(store.get("list").unwrap(), Some("a,b,c,d".to_owned()));
+    store.merge("list".to_owned(), "e".to_owned()).unwrap();
+
+    store.compact().unwrap();
+    assert!(!log(temp_dir.path()).contains("\"Merge\""));
+    assert_eq!(store.get("list").unwrap(), Some("a,b,c,d,e".to_owned()));
+    assert_eq!(store.get("fresh").unwrap(), Some("x,y".to_owned()));
+    drop(store);
+
+    // Compacted, the log reads without an operator.
+    let store = testing::open(temp_dir.path()).unwrap();
+    assert_eq!(store.get("list").unwrap(), Some("a,b,c,d,e".to_owned()));
+}
+
+#[test]
+fn sets_and_removes_replace_pending_merges() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = open(temp_dir.path());
+    store.merge("key".to_owned(), "1".to_owned()).unwrap();
+    store.merge("key".to_owned(), "2".to_owned()).unwrap();
+    store.set("key".to_owned(), "reset".to_owned()).unwrap();
+    store.merge("key".to_owned(), "3".to_owned()).unwrap();
+    assert_eq!(store.get("key").unwrap(), Some("reset,3".to_owned()));
+
+    store.remove("key".to_owned()).unwrap();
+    assert_eq!(store.get("key").unwrap(), None);
+    store.merge("key".to_owned(), "4".to_owned()).unwrap();
+    assert_eq!(store.pop("key").unwrap(), Some("4".to_owned()));
+    store.merge("key".to_owned(), "5".to_owned()).unwrap();
+    drop(store);
+
+    let store = open(temp_dir.path());
+    assert_eq!(store.get("key").unwrap(), Some("5".to_owned()));
+}
+
+#[test]
+fn merges_keep_the_deadline_of_the_value_they_extend() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = open(temp_dir.path());
+    store
+        .set_with_ttl(
+            "session".to_owned(),
+            "a".to_owned(),
+            Duration::from_millis(200),
+        )
+        .unwrap();
+    store.merge("session".to_owned(), "b".to_owned()).unwrap();
+    assert_eq!(store.get("session").unwrap(), Some("a,b".to_owned()));
+    assert!(store.ttl("session").unwrap().is_some());
+
+    thread::sleep(Duration::from_millis(250));
+    assert_eq!(store.get("session").unwrap(), None);
+    // Past its deadline, the key merges onto nothing, and for good.
+    store.merge("session".to_owned(), "c".to_owned()).unwrap();
+    assert_eq!(store.get("session").unwrap(), Some("c".to_owned()));
+    assert_eq!(store.ttl("session").unwrap(), None);
+    drop(store);
+
+    let store = open(temp_dir.path());
+    assert_eq!(store.get("session").unwrap(), Some("c".to_owned()));
+}
+
+#[test]
+fn merges_need_an_operator() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = testing::open(temp_dir.path()).unwrap();
+    match store.merge("key".to_owned(), "1".to_owned()) {
+        Err(KvError::NoMergeOperator(key)) => assert_eq!(key, "key"),
+        other => panic!("expected NoMergeOperator, got {:?}", other),
+    }
+    store.set("plain".to_owned(), "value".to_owned()).unwrap();
+    drop(store);
+
+    let mut store = open(temp_dir.path());
+    store.merge("key".to_owned(), "1".to_owned()).unwrap();
+    drop(store);
+
+    // The operands cannot be applied, so neither reading nor compacting them is possible.
+    let options = testing::options().compact_on_open(false);
+    let store = KvStore::open_with_options(temp_dir.path(), options).unwrap();
+    assert_eq!(store.get("plain").unwrap(), Some("value".to_owned()));
+    assert!(matches!(store.get("key"), Err(KvError::NoMergeOperator(_))));
+    drop(store);
+    assert!(matches!(
+        testing::open(temp_dir.path()).map(drop),
+        Err(KvError::NoMergeOperator(_))
+    ));
+    assert_eq!(
+        open(temp_dir.path()).get("key").unwrap(),
+        Some("1".to_owned())
+    );
+}
@@ -0,0 +1,324 @@
+use kvs::testing::{self, FixtureRng};
+use kvs::{
+    KvStore, KvStoreOptions, MergeAll, MergeOperator, MergePolicy, SegmentStats, TieredMergePolicy,
+};
+use std::collections::HashMap;
+use std::fs;
+use std::ops::Range;
+use std::path::Path;
+use std::time::Duration;
+use tempfile::TempDir;
+
+const SEGMENT_SIZE: u64 = 4096;
+
+struct ListAppend;
+
+impl MergeOperator for ListAppend {
+    fn merge(&self, _key: &str, existing: Option<&str>, operand: &str) -> String {
+        match existing {
+            Some(existing) => format!("{},{}", existing, operand),
+            None => operand.to_owned(),
+        }
+    }
+}
+
+/// Picks the same runs whatever the segments.
+struct Pick(Vec<Range<usize>>);
+
+impl MergePolicy for Pick {
+    fn select(&self, _segments: &[SegmentStats], _segment_size: u64) -> Vec<Range<usize>> {
+        self.0.clone()
+    }
+}
+
+fn segmented(bytes: u64) -> KvStoreOptions {
+    testing::options()
+        .segment_size(bytes)
+        .auto_compaction(false)
+        .compact_on_open(false)
+}
+
+/// The log files in `dir` and what they hold, by name.
+fn logs(dir: &Path) -> HashMap<String, Vec<u8>> {
+    fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap())
+        .filter(|entry| entry.file_name().to_string_lossy().ends_with(".log"))
+        .map(|entry| {
+            let name = entry.file_name().into_string().unwrap();
+            (name, fs::read(entry.path()).unwrap())
+        })
+        .collect()
+}
+
+fn value(i: usize) -> String {
+    format!("value {} {}", i, "x".repeat(i % 23))
+}
+
+/// What a workload cost under one policy.
+#[derive(Debug)]
+struct Outcome {
+    /// Log bytes compactions wrote.
+    rewritten: u64,
+    /// Log bytes the writes appended: what the log holds plus what compactions dropped.
+    appended: u64,
+    log_bytes: u64,
+}
+
+impl Outcome {
+    fn write_amplification(&self) -> f64 {
+        self.rewritten as f64 / self.appended as f64
+    }
+}
+
+/// Runs 10,000 sets of the keys `key` picks, compacting every 500, and checks the store
+/// holds what was written last.
+fn simulate(
+    policy: Box<dyn MergePolicy>,
+    mut key: impl FnMut(&mut FixtureRng, usize) -> String,
+) -> Outcome {
+    let temp_dir = TempDir::new().unwrap();
+    let options = segmented(SEGMENT_SIZE).merge_policy(policy);
+    let mut store = KvStore::open_with_options(temp_dir.path(), options).unwrap();
+    let mut rng = FixtureRng::new(221);
+    let mut expected = HashMap::new();
+    for i in 0..10_000 {
+        let key = key(&mut rng, i);
+        store.set(key.clone(), value(i)).unwrap();
+        expected.insert(key, value(i));
+        if i % 500 == 499 {
+            store.compact().unwrap();
+        }
+    }
+    let stats = store.stats();
+    drop(store);
+
+    let store = KvStore::open_with_options(temp_dir.path(), testing::options()).unwrap();
+    assert_eq!(store.len(), expected.len());
+    for (key, value) in &expected {
+        assert_eq!(store.get(key).unwrap().as_ref(), Some(value), "{}", key);
+    }
+    Outcome {
+        rewritten: stats.since_open.bytes_rewritten,
+        appended: stats.log_bytes + stats.since_open.bytes_reclaimed,
+        log_bytes: stats.log_bytes,
+    }
+}
+
+/// The workload `key` picks under the default tiered policy and under `MergeAll`, whose
+/// log the tiered one keeps within twice the size.
+fn compare(key: impl Fn(&mut FixtureRng, usize) -> String) -> (f64, f64) {
+    let tiered = simulate(Box::new(TieredMergePolicy::default()), &key);
+    let merge_all = simulate(Box::new(MergeAll), &key);
+    assert!(
+        tiered.log_bytes <= 2 * merge_all.log_bytes,
+        "{:?} against {:?}",
+        tiered,
+        merge_all
+    );
+    (
+        tiered.write_amplification(),
+        merge_all.write_amplification(),
+    )
+}
+
+/// Every key is as likely to be written. Segments die evenly, and the tiered policy copies
+/// a byte fewer than twice on average, under 60% of what `MergeAll` copies.
+#[test]
+fn uniform_churn_costs_well_under_merging_everything() {
+    let (tiered, merge_all) = compare(|rng, _| format!("key{}", rng.range(0, 1999)));
+    assert!(tiered < 2.0, "{}", tiered);
+    assert!(tiered < 0.6 * merge_all, "{} against {}", tiered, merge_all);
+}
+
+/// Nine writes in ten go to 50 hot keys, the rest to keys written once. Segments die
+/// almost whole, so the tiered policy copies under half a byte per byte written, under a
+/// quarter of what `MergeAll` copies, which copies the cold keys at every compaction.
+#[test]
+fn hot_key_churn_leaves_cold_keys_where_they_are() {
+    let (tiered, merge_all) = compare(|rng, i| match rng.chance(0.9) {
+        true => format!("hot{}", rng.range(0, 49)),
+        false => format!("cold{}", i),
+    });
+    assert!(tiered < 0.5, "{}", tiered);
+    assert!(
+        tiered < 0.25 * merge_all,
+        "{} against {}",
+        tiered,
+        merge_all
+    );
+}
+
+/// Nothing dies, so the tiered policy only merges crowded segments of a size, copying each
+/// byte about once, where `MergeAll` copies the whole log at every compaction.
+#[test]
+fn append_only_copies_each_record_about_once() {
+    let (tiered, merge_all) = compare(|_, i| format!("key{}", i));
+    assert!(tiered < 1.25, "{}", tiered);
+    assert!(
+        tiered < 0.25 * merge_all,
+        "{} against {}",
+        tiered,
+        merge_all
+    );
+}
+
+#[test]
+fn segment_stats_describe_each_sealed_segment() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = testing::open(temp_dir.path()).unwrap();
+    assert!(store.segment_stats().is_empty());
+    drop(store);
+
+    let mut store = KvStore::open_with_options(temp_dir.path(), segmented(512)).unwrap();
+    for i in 0..40 {
+        store.set(format!("key{}", i), value(i)).unwrap();
+    }
+    let files = logs(temp_dir.path());
+    let stats = store.segment_stats();
+    // All but the segment appended to.
+    assert_eq!(stats.len(), files.len() - 1);
+    for segment in &stats {
+        let name = format!("{:06}.log", segment.id);
+        assert_eq!(segment.bytes, files[&name].len() as u64, "{}", name);
+        assert_eq!(segment.live_bytes, segment.bytes - 8, "{}", name);
+        assert_eq!(segment.live_ratio, 1.0, "{}", name);
+        assert!(segment.age < Duration::from_secs(60), "{:?}", segment);
+    }
+
+    // The first segment's keys written again, so that it holds nothing live.
+    for i in 0..40 {
+        store.set(format!("key{}", i), value(i + 1)).unwrap();
+    }
+    let first = store.segment_stats()[0];
+    assert_eq!(first.id, stats[0].id);
+    assert_eq!(first.live_bytes, 0);
+    assert_eq!(first.live_ratio, 0.0);
+}
+
+#[test]
+fn merge_all_merges_every_sealed_segment_into_the_last() {
+    let temp_dir = TempDir::new().unwrap();
+    let options = || segmented(512).merge_policy(Box::new(MergeAll));
+    let mut store = KvStore::open_with_options(temp_dir.path(), options()).unwrap();
+    for i in 0..60 {
+        store.set(format!("key{}", i % 40), value(i)).unwrap();
+    }
+    let last = store.segment_stats().last().unwrap().id;
+    store.compact().unwrap();
+    let stats = store.segment_stats();
+    assert_eq!(stats.len(), 1);
+    assert_eq!(stats[0].id, last);
+    assert_eq!(stats[0].live_ratio, 1.0);
+    assert_eq!(logs(temp_dir.path()).len(), 2);
+    drop(store);
+
+    let store = KvStore::open_with_options(temp_dir.path(), options()).unwrap();
+    for i in 20..60 {
+        assert_eq!(
+            store.get(&format!("key{}", i % 40)).unwrap(),
+            Some(value(i))
+        );
+    }
+}
+
+#[test]
+fn a_policy_of_ones_own_picks_what_compaction_rewrites() {
+    let temp_dir = TempDir::new().unwrap();
+    let write = |policy: Pick| {
+        let options = segmented(512).merge_policy(Box::new(policy));
+        let mut store = KvStore::open_with_options(temp_dir.path(), options).unwrap();
+        for i in 0..40 {
+            store.set(format!("key{}", i), value(i)).unwrap();
+        }
+        store
+    };
+
+    // Runs that are empty or past the end are no runs at all, so nothing is rewritten.
+    #[allow(clippy::reversed_empty_ranges)]
+    let mut store = write(Pick(vec![3..1, 2..2, 0..1000]));
+    store.remove("key0".to_owned()).unwrap();
+    let before = logs(temp_dir.path());
+    store.compact().unwrap();
+    assert_eq!(logs(temp_dir.path()), before);
+    drop(store);
+    fs::remove_dir_all(temp_dir.path()).unwrap();
+    fs::create_dir(temp_dir.path()).unwrap();
+
+    // The first two merged into the second, and the rest left alone.
+    let first_two = 0..2;
+    let mut store = write(Pick(vec![first_two]));
+    let before = logs(temp_dir.path());
+    let ids: Vec<u32> = store.segment_stats().iter().map(|s| s.id).collect();
+    store.compact().unwrap();
+    let after = logs(temp_dir.path());
+    let name = |id: u32| format!("{:06}.log", id);
+    assert!(!after.contains_key(&name(ids[0])));
+    assert_eq!(
+        after[&name(ids[1])].len(),
+        before[&name(ids[0])].len() + before[&name(ids[1])].len() - 8
+    );
+    for &id in &ids[2..] {
+        assert_eq!(after[&name(id)], before[&name(id)]);
+    }
+    for i in 0..40 {
+        assert_eq!(store.get(&format!("key{}", i)).unwrap(), Some(value(i)));
+    }
+}
+
+/// Writes a merge chain of `list` across three segments, starting from a set of it if
+/// `base`, and fills each segment with keys of its own.
+fn spread_chain(store: &mut KvStore, base: bool) {
+    let operands = ["b", "c", "d"];
+    if base {
+        store.set("list".to_owned(), "a".to_owned()).unwrap();
+    }
+    for (n, operand) in operands.iter().enumerate() {
+        store.merge("list".to_owned(), operand.to_string()).unwrap();
+        for i in 0..12 {
+            store.set(format!("key{}.{}", n, i), value(i)).unwrap();
+        }
+    }
+}
+
+/// Compacts with `MergeAll`, then puts back the segment files the compaction removed, as
+/// a crash before it could remove them would leave them, and reopens.
+fn compact_and_crash(temp_dir: &TempDir, base: bool) -> (usize, usize, KvStore) {
+    let options = || {
+        segmented(256)
+            .merge_policy(Box::new(MergeAll))
+            .merge_operator(Box::new(ListAppend))
+    };
+    let mut store = KvStore::open_with_options(temp_dir.path(), options()).unwrap();
+    spread_chain(&mut store, base);
+    let before = logs(temp_dir.path());
+    store.compact().unwrap();
+    let after = logs(temp_dir.path());
+    drop(store);
+    for (name, contents) in &before {
+        if !after.contains_key(name) {
+            fs::write(temp_dir.path().join(name), contents).unwrap();
+        }
+    }
+    let store = KvStore::open_with_options(temp_dir.path(), options()).unwrap();
+    (before.len(), after.len(), store)
+}
+
+#[test]
+fn a_merge_chain_replayed_twice_after_a_crash_starts_from_its_set() {
+    let temp_dir = TempDir::new().unwrap();
+    let (before, after, store) = compact_and_crash(&temp_dir, true);
+    assert!(after < before, "{} segments left of {}", after, before);
+    assert_eq!(store.get("list").unwrap(), Some("a,b,c,d".to_owned()));
+    assert_eq!(store.get("key2.11").unwrap(), Some(value(11)));
+}
+
+#[test]
+fn a_merge_chain_without_a_set_keeps_its_segments_apart() {
+    let temp_dir = TempDir::new().unwrap();
+    let (before, after, store) = compact_and_crash(&temp_dir, false);
+    // Merging them would leave a crash to apply the earlier operands twice.
+    assert_eq!(after, before);
+    assert_eq!(store.get("list").unwrap(), Some("b,c,d".to_owned()));
+    assert_eq!(store.get("key2.11").unwrap(), Some(value(11)));
+}
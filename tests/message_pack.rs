@@ -0,0 +1,481 @@
+use kvs::testing;
+use kvs::{KvError, KvStore, KvStoreOptions, LogFormat, MergeOperator};
+use serde::de::{self, Deserializer, Visitor};
+use serde::{Deserialize, Serialize, Serializer};
+use std::fmt;
+use std::fs;
+use std::io::Read;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tempfile::TempDir;
+
+const HEADER: &[u8] = b"\0kvs\x01\x00\x02\n";
+
+struct Concat;
+
+impl MergeOperator for Concat {
+    fn merge(&self, _key: &str, existing: Option<&str>, operand: &str) -> String {
+        format!("{}{}", existing.unwrap_or(""), operand)
+    }
+}
+
+fn message_pack() -> KvStoreOptions {
+    testing::options()
+        .format(LogFormat::MessagePack)
+        .merge_operator(Box::new(Concat))
+}
+
+fn open(dir: &Path) -> KvStore {
+    KvStore::open_with_options(dir, message_pack()).unwrap()
+}
+
+fn log_path(dir: &Path) -> std::path::PathBuf {
+    dir.join("db.log")
+}
+
+/// Keys and values in several scripts, with the bytes JSON escapes and a frame might be
+/// mistaken for.
+fn pairs() -> Vec<(String, String)> {
+    vec![
+        (
+            "caf\u{e9}".to_owned(),
+            "cr\u{e8}me br\u{fb}l\u{e9}e".to_owned(),
+        ),
+        (
+            "\u{65e5}\u{672c}".to_owned(),
+            "\u{6771}\u{4eac}\n\u{5927}\u{962a}".to_owned(),
+        ),
+        (
+            "\u{1f600} grin".to_owned(),
+            "\u{1f600}\u{1f601}\u{1f602}".to_owned(),
+        ),
+        (
+            "\u{41f}\u{440}\u{438}\u{432}\u{435}\u{442}".to_owned(),
+            "\"quoted\"\r\n\ttabbed\\".to_owned(),
+        ),
+        ("nul\0key".to_owned(), "\0\n\0".to_owned()),
+        ("long".to_owned(), "\u{e9}".repeat(40_000)),
+    ]
+}
+
+fn assert_pairs(store: &KvStore) {
+    for (key, value) in pairs() {
+        assert_eq!(store.get(&key).unwrap(), Some(value), "{:?}", key);
+    }
+}
+
+#[test]
+fn non_ascii_keys_and_values_survive_reopening() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = open(temp_dir.path());
+    for (key, value) in pairs() {
+        store.set(key, value).unwrap();
+    }
+    assert_pairs(&store);
+    drop(store);
+
+    let store = open(temp_dir.path());
+    assert_pairs(&store);
+    assert_eq!(store.open_report().torn_tail, None);
+    let contents = fs::read(log_path(temp_dir.path())).unwrap();
+    assert!(contents.starts_with(HEADER));
+}
+
+#[test]
+fn non_ascii_keys_and_values_survive_compaction() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = open(temp_dir.path());
+    for (key, value) in pairs() {
+        store.set(key.clone(), "overwritten".to_owned()).unwrap();
+        store.set(key, value).unwrap();
+    }
+    store
+        .merge("m\u{fc}nchen".to_owned(), "\u{df}".to_owned())
+        .unwrap();
+    store
+        .merge("m\u{fc}nchen".to_owned(), "\u{20ac}".to_owned())
+        .unwrap();
+    store.set("gone".to_owned(), "soon".to_owned()).unwrap();
+    store.remove("gone".to_owned()).unwrap();
+    store.compact().unwrap();
+    assert_pairs(&store);
+    assert_eq!(
+        store.get("m\u{fc}nchen").unwrap(),
+        Some("\u{df}\u{20ac}".to_owned())
+    );
+    drop(store);
+
+    let store = open(temp_dir.path());
+    assert_pairs(&store);
+    assert_eq!(
+        store.get("m\u{fc}nchen").unwrap(),
+        Some("\u{df}\u{20ac}".to_owned())
+    );
+    assert_eq!(store.get("gone").unwrap(), None);
+}
+
+#[test]
+fn bytes_and_streamed_values_round_trip() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = open(temp_dir.path());
+    store
+        .set_bytes("\u{e9}bytes".to_owned(), b"\xff\n\xfe\0")
+        .unwrap();
+    let streamed = "\u{263a} streamed\nover lines".as_bytes();
+    store
+        .set_from_reader("\u{263a}".to_owned(), streamed, streamed.len() as u64)
+        .unwrap();
+    store
+        .set_batch(vec![("b\u{e4}tch".to_owned(), "\u{f6}ne".to_owned())])
+        .unwrap();
+    drop(store);
+
+    let store = open(temp_dir.path());
+    assert_eq!(
+        store.get_bytes("\u{e9}bytes").unwrap(),
+        Some(b"\xff\n\xfe\0".to_vec())
+    );
+    let mut read = Vec::new();
+    store
+        .get_reader("\u{263a}")
+        .unwrap()
+        .unwrap()
+        .read_to_end(&mut read)
+        .unwrap();
+    assert_eq!(read, streamed);
+    assert_eq!(
+        store.get("b\u{e4}tch").unwrap(),
+        Some("\u{f6}ne".to_owned())
+    );
+}
+
+#[test]
+fn a_record_is_the_json_object_as_message_pack() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = open(temp_dir.path());
+    store.set("k".to_owned(), "v".to_owned()).unwrap();
+    store.remove("k".to_owned()).unwrap();
+    drop(store);
+
+    let contents = fs::read(log_path(temp_dir.path())).unwrap();
+    let frames = &contents[HEADER.len()..];
+    let set_len = u32::from_le_bytes(frames[..4].try_into().unwrap()) as usize;
+    let set = &frames[8..8 + set_len];
+    let mut expected = vec![0x81, 0xa3, b'S', b'e', b't', 0x84];
+    expected.extend_from_slice(b"\xa3key\xa1k\xa5value\xc4\x01v\xa9timestamp\xcf");
+    assert_eq!(&set[..expected.len()], &expected[..]);
+    let seq_at = set.len() - 13;
+    assert_eq!(&set[seq_at..seq_at + 5], b"\xa3seq\xcf");
+    assert_eq!(&set[seq_at + 5..], &1u64.to_be_bytes());
+    assert_eq!(frames[8 + set_len + 4], b'\n');
+
+    let rm = &frames[8 + set_len + 5..];
+    let rm_len = u32::from_le_bytes(rm[..4].try_into().unwrap()) as usize;
+    let mut expected = vec![0x81, 0xa2, b'R', b'm', 0x82];
+    expected.extend_from_slice(b"\xa3key\xa1k\xa3seq\xcf");
+    expected.extend_from_slice(&2u64.to_be_bytes());
+    assert_eq!(&rm[8..8 + rm_len], &expected[..]);
+}
+
+#[test]
+fn a_message_pack_log_is_not_opened_as_bincode_or_json() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = open(temp_dir.path());
+    store.set("key".to_owned(), "value".to_owned()).unwrap();
+    drop(store);
+
+    for format in [LogFormat::Bincode, LogFormat::Json] {
+        match KvStore::open_with_options(temp_dir.path(), testing::options().format(format)) {
+            Err(KvError::LogFormatMismatch {
+                found, requested, ..
+            }) => {
+                assert_eq!(found, LogFormat::MessagePack);
+                assert_eq!(requested, format);
+            }
+            Err(e) => panic!("unexpected error: {:?}", e),
+            Ok(_) => panic!("opened a MessagePack log as {}", format),
+        }
+    }
+    // Without a format asked for, the header says which it is.
+    let store = KvStore::open_with_options(temp_dir.path(), testing::options()).unwrap();
+    assert_eq!(store.get("key").unwrap(), Some("value".to_owned()));
+}
+
+#[test]
+fn a_torn_frame_at_the_end_is_cut_away() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = open(temp_dir.path());
+    store
+        .set("kept".to_owned(), "\u{e9}t\u{e9}".to_owned())
+        .unwrap();
+    let good_len = fs::metadata(log_path(temp_dir.path())).unwrap().len() as usize;
+    store.set("torn".to_owned(), "value\n".to_owned()).unwrap();
+    drop(store);
+    let contents = fs::read(log_path(temp_dir.path())).unwrap();
+    fs::write(log_path(temp_dir.path()), &contents[..contents.len() - 3]).unwrap();
+
+    let store = open(temp_dir.path());
+    let tail = store.open_report().torn_tail.unwrap();
+    assert_eq!(tail.offset, good_len as u64);
+    assert_eq!(store.get("kept").unwrap(), Some("\u{e9}t\u{e9}".to_owned()));
+    assert_eq!(store.get("torn").unwrap(), None);
+}
+
+/// A value as MessagePack binary, which a `Vec<u8>` is not to serde.
+#[derive(Debug, PartialEq)]
+struct Bin(Vec<u8>);
+
+impl Serialize for Bin {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(&self.0)
+    }
+}
+
+impl<'de> Deserialize<'de> for Bin {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Bin, D::Error> {
+        struct BinVisitor;
+
+        impl Visitor<'_> for BinVisitor {
+            type Value = Bin;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("MessagePack binary")
+            }
+
+            fn visit_bytes<E: de::Error>(self, bytes: &[u8]) -> Result<Bin, E> {
+                Ok(Bin(bytes.to_vec()))
+            }
+        }
+
+        deserializer.deserialize_bytes(BinVisitor)
+    }
+}
+
+/// A record as another program would declare it from the JSON log's objects.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+enum Record {
+    Set {
+        key: String,
+        value: Bin,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        expires_at: Option<u64>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        timestamp: Option<u64>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        seq: Option<u64>,
+    },
+    Rm {
+        key: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        seq: Option<u64>,
+    },
+    Merge {
+        key: String,
+        operand: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        expires_at: Option<u64>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        timestamp: Option<u64>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        seq: Option<u64>,
+    },
+    Sequence {
+        seq: u64,
+    },
+    Group {
+        records: u64,
+    },
+    Touch {
+        key: String,
+        expires_at: u64,
+    },
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64
+}
+
+/// The records of the log in `dir` as `rmp_serde` decodes them, with the write times,
+/// which differ from run to run, checked and cleared.
+fn decoded_records(dir: &Path) -> Vec<Record> {
+    let now = now_millis();
+    let in_past = |timestamp: Option<u64>| matches!(timestamp, Some(t) if t <= now);
+    testing::frame_payloads(&testing::log_records(dir))
+        .iter()
+        .map(|payload| match rmp_serde::from_slice(payload).unwrap() {
+            Record::Set {
+                key,
+                value,
+                expires_at,
+                timestamp,
+                seq,
+            } => {
+                assert!(in_past(timestamp), "{:?}", key);
+                Record::Set {
+                    key,
+                    value,
+                    expires_at: expires_at.map(|t| u64::from(t > now)),
+                    timestamp: None,
+                    seq,
+                }
+            }
+            Record::Merge {
+                key,
+                operand,
+                expires_at,
+                timestamp,
+                seq,
+            } => {
+                assert!(in_past(timestamp), "{:?}", key);
+                Record::Merge {
+                    key,
+                    operand,
+                    expires_at: expires_at.map(|t| u64::from(t > now)),
+                    timestamp: None,
+                    seq,
+                }
+            }
+            Record::Touch { key, expires_at } => Record::Touch {
+                key,
+                expires_at: u64::from(expires_at > now),
+            },
+            record => record,
+        })
+        .collect()
+}
+
+fn set(key: &str, value: &[u8], expires_at: Option<u64>, seq: u64) -> Record {
+    Record::Set {
+        key: key.to_owned(),
+        value: Bin(value.to_vec()),
+        expires_at,
+        timestamp: None,
+        seq: Some(seq),
+    }
+}
+
+#[test]
+fn every_record_decodes_with_rmp_serde() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = open(temp_dir.path());
+    store.set("k".to_owned(), "v".to_owned()).unwrap();
+    store.set_bytes("b\u{e4}r".to_owned(), b"\xff\0\n").unwrap();
+    store.touch("b\u{e4}r", Duration::from_secs(3600)).unwrap();
+    store
+        .set_with_ttl(
+            "lasting".to_owned(),
+            "v".to_owned(),
+            Duration::from_secs(3600),
+        )
+        .unwrap();
+    store.merge("lasting".to_owned(), "w".to_owned()).unwrap();
+    store.remove("k".to_owned()).unwrap();
+    store
+        .set_batch(vec![
+            ("first".to_owned(), "1".to_owned()),
+            ("second".to_owned(), "2".to_owned()),
+        ])
+        .unwrap();
+
+    // Expiries read as 1 if they lie ahead.
+    assert_eq!(
+        decoded_records(temp_dir.path()),
+        vec![
+            set("k", b"v", None, 1),
+            set("b\u{e4}r", b"\xff\0\n", None, 2),
+            Record::Touch {
+                key: "b\u{e4}r".to_owned(),
+                expires_at: 1,
+            },
+            set("lasting", b"v", Some(1), 3),
+            Record::Merge {
+                key: "lasting".to_owned(),
+                operand: "w".to_owned(),
+                expires_at: Some(1),
+                timestamp: None,
+                seq: Some(4),
+            },
+            Record::Rm {
+                key: "k".to_owned(),
+                seq: Some(5),
+            },
+            Record::Group { records: 2 },
+            set("first", b"1", None, 6),
+            set("second", b"2", None, 7),
+        ]
+    );
+
+    // Compaction leaves a `Sequence` when the last write does not survive it.
+    store.remove("second".to_owned()).unwrap();
+    store.compact().unwrap();
+    let records = decoded_records(temp_dir.path());
+    assert_eq!(records.last(), Some(&Record::Sequence { seq: 8 }));
+}
+
+#[test]
+fn records_encoded_by_rmp_serde_are_read() {
+    let temp_dir = TempDir::new().unwrap();
+    let later = now_millis() + 3_600_000;
+    let written_at = 1_700_000_000_000;
+    let records = vec![
+        Record::Group { records: 2 },
+        set("a", b"1", None, 1),
+        set("b", b"\xff\xfe", None, 2),
+        Record::Set {
+            key: "c\u{e9}".to_owned(),
+            value: Bin(b"3".to_vec()),
+            expires_at: Some(later),
+            timestamp: Some(written_at),
+            seq: Some(3),
+        },
+        Record::Merge {
+            key: "c\u{e9}".to_owned(),
+            operand: "4".to_owned(),
+            expires_at: Some(later),
+            timestamp: Some(written_at),
+            seq: Some(4),
+        },
+        set("gone", &[b'x'; 300], None, 5),
+        Record::Rm {
+            key: "gone".to_owned(),
+            seq: Some(6),
+        },
+        Record::Sequence { seq: 300 },
+    ];
+    let mut log = HEADER.to_vec();
+    for record in &records {
+        log.extend(testing::log_frame(rmp_serde::to_vec_named(record).unwrap()));
+    }
+    fs::write(log_path(temp_dir.path()), log).unwrap();
+
+    let mut store = open(temp_dir.path());
+    assert_eq!(store.open_report().torn_tail, None);
+    let check = |store: &KvStore| {
+        assert_eq!(store.get("a").unwrap(), Some("1".to_owned()));
+        assert_eq!(store.get_bytes("b").unwrap(), Some(b"\xff\xfe".to_vec()));
+        assert_eq!(store.get("c\u{e9}").unwrap(), Some("34".to_owned()));
+        assert_eq!(
+            store.last_modified("c\u{e9}").unwrap(),
+            Some(UNIX_EPOCH + Duration::from_millis(written_at))
+        );
+        assert_eq!(store.get("gone").unwrap(), None);
+        assert_eq!(store.last_sequence(), 300);
+    };
+    check(&store);
+    let mut read = Vec::new();
+    store
+        .get_reader("b")
+        .unwrap()
+        .unwrap()
+        .read_to_end(&mut read)
+        .unwrap();
+    assert_eq!(read, b"\xff\xfe");
+
+    store.compact().unwrap();
+    check(&store);
+    store.set("d".to_owned(), "5".to_owned()).unwrap();
+    assert_eq!(store.last_sequence(), 301);
+}
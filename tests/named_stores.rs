@@ -0,0 +1,78 @@
+use kvs::testing;
+use kvs::{KvError, KvStore};
+use std::thread;
+use tempfile::TempDir;
+
+#[test]
+fn stores_sharing_a_directory_keep_apart_while_compacting() {
+    let temp_dir = TempDir::new().unwrap();
+    let writers: Vec<_> = ["alpha", "beta"]
+        .into_iter()
+        .map(|name| {
+            let dir = temp_dir.path().to_path_buf();
+            thread::spawn(move || {
+                // Small limits, so both compact and spill their index many times over.
+                let options = testing::options()
+                    .store_name(name)
+                    .compaction_threshold(100)
+                    .max_index_bytes(4096);
+                let mut store = KvStore::open_with_options(&dir, options).unwrap();
+                for i in 0..2_000 {
+                    store
+                        .set(format!("key{}", i % 300), format!("{} {}", name, i))
+                        .unwrap();
+                }
+                store
+                    .set(format!("only {}", name), name.to_owned())
+                    .unwrap();
+            })
+        })
+        .collect();
+    for writer in writers {
+        writer.join().unwrap();
+    }
+
+    assert_eq!(
+        KvStore::list_stores(temp_dir.path()).unwrap(),
+        vec!["alpha", "beta"]
+    );
+    for (name, other) in [("alpha", "beta"), ("beta", "alpha")] {
+        let store = KvStore::open_named(temp_dir.path(), name).unwrap();
+        assert_eq!(store.len(), 301);
+        assert_eq!(store.get("key0").unwrap(), Some(format!("{} 1800", name)));
+        assert_eq!(store.get(&format!("only {}", other)).unwrap(), None);
+    }
+}
+
+#[test]
+fn a_named_store_has_its_own_lock() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut default = testing::open(temp_dir.path()).unwrap();
+    let mut tenant = KvStore::open_named(temp_dir.path(), "tenant-1").unwrap();
+    default.set("key".to_owned(), "default".to_owned()).unwrap();
+    tenant.set("key".to_owned(), "tenant".to_owned()).unwrap();
+    assert!(matches!(
+        KvStore::open_named(temp_dir.path(), "tenant-1"),
+        Err(KvError::StoreLocked(_))
+    ));
+    // The default store goes by `DEFAULT_STORE_NAME`.
+    assert!(matches!(
+        KvStore::open_named(temp_dir.path(), kvs::kv_store::DEFAULT_STORE_NAME),
+        Err(KvError::StoreLocked(_))
+    ));
+    drop(default);
+    drop(tenant);
+
+    assert_eq!(
+        KvStore::list_stores(temp_dir.path()).unwrap(),
+        vec!["db", "tenant-1"]
+    );
+    let default = KvStore::open_named(temp_dir.path(), "db").unwrap();
+    assert_eq!(default.get("key").unwrap(), Some("default".to_owned()));
+    for name in ["", "../escape", ".hidden", "a b"] {
+        assert!(matches!(
+            KvStore::open_named(temp_dir.path(), name),
+            Err(KvError::InvalidStoreName(_))
+        ));
+    }
+}
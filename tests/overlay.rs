@@ -0,0 +1,128 @@
+use kvs::testing;
+use kvs::KvError;
+use tempfile::TempDir;
+
+fn set(store: &mut kvs::KvStore, key: &str, value: &str) {
+    store.set(key.to_owned(), value.to_owned()).unwrap();
+}
+
+#[test]
+fn overlay_writes_stay_in_memory_until_commit() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = testing::open(temp_dir.path()).unwrap();
+    set(&mut store, "kept", "base");
+    set(&mut store, "changed", "base");
+    set(&mut store, "removed", "base");
+    let log_len = std::fs::metadata(temp_dir.path().join("db.log"))
+        .unwrap()
+        .len();
+
+    let mut overlay = store.fork();
+    overlay
+        .set(&store, "changed".to_owned(), "overlay".to_owned())
+        .unwrap();
+    overlay
+        .set(&store, "added".to_owned(), "overlay".to_owned())
+        .unwrap();
+    overlay.remove(&store, "removed".to_owned()).unwrap();
+    assert!(matches!(
+        overlay.remove(&store, "removed".to_owned()),
+        Err(KvError::RemoveError(_))
+    ));
+    assert_eq!(overlay.len(), 3);
+
+    assert_eq!(
+        overlay.get(&store, "kept").unwrap(),
+        Some("base".to_owned())
+    );
+    assert_eq!(
+        overlay.get(&store, "changed").unwrap(),
+        Some("overlay".to_owned())
+    );
+    assert_eq!(overlay.get(&store, "removed").unwrap(), None);
+    assert_eq!(store.get("changed").unwrap(), Some("base".to_owned()));
+    assert_eq!(store.get("added").unwrap(), None);
+    assert_eq!(store.get("removed").unwrap(), Some("base".to_owned()));
+    assert_eq!(
+        std::fs::metadata(temp_dir.path().join("db.log"))
+            .unwrap()
+            .len(),
+        log_len
+    );
+
+    assert_eq!(overlay.commit(&mut store).unwrap(), 3);
+    assert_eq!(store.get("changed").unwrap(), Some("overlay".to_owned()));
+    assert_eq!(store.get("added").unwrap(), Some("overlay".to_owned()));
+    assert_eq!(store.get("removed").unwrap(), None);
+    drop(store);
+
+    let store = testing::open(temp_dir.path()).unwrap();
+    assert_eq!(store.get("added").unwrap(), Some("overlay".to_owned()));
+    assert_eq!(store.get("removed").unwrap(), None);
+}
+
+#[test]
+fn dropped_overlay_changes_nothing() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = testing::open(temp_dir.path()).unwrap();
+    set(&mut store, "key", "base");
+
+    let mut overlay = store.fork();
+    overlay
+        .set(&store, "key".to_owned(), "overlay".to_owned())
+        .unwrap();
+    drop(overlay);
+    assert_eq!(store.get("key").unwrap(), Some("base".to_owned()));
+}
+
+#[test]
+fn commit_fails_when_a_touched_key_changed() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = testing::open(temp_dir.path()).unwrap();
+    set(&mut store, "read", "base");
+    set(&mut store, "untouched", "base");
+
+    let mut overlay = store.fork();
+    assert_eq!(
+        overlay.get(&store, "read").unwrap(),
+        Some("base".to_owned())
+    );
+    overlay
+        .set(&store, "written".to_owned(), "overlay".to_owned())
+        .unwrap();
+
+    // Writes to keys the overlay never touched do not conflict.
+    set(&mut store, "untouched", "changed");
+    set(&mut store, "read", "changed");
+    match overlay.commit(&mut store) {
+        Err(KvError::OverlayConflict(key)) => assert_eq!(key, "read"),
+        other => panic!("expected OverlayConflict, got {:?}", other),
+    }
+    assert_eq!(store.get("written").unwrap(), None);
+
+    // A key the overlay created conflicts with the same key created in the store.
+    let mut overlay = store.fork();
+    overlay
+        .set(&store, "new".to_owned(), "overlay".to_owned())
+        .unwrap();
+    set(&mut store, "new", "store");
+    assert!(matches!(
+        overlay.commit(&mut store),
+        Err(KvError::OverlayConflict(_))
+    ));
+    assert_eq!(store.get("new").unwrap(), Some("store".to_owned()));
+}
+
+#[test]
+fn set_then_remove_of_a_new_key_commits_nothing() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = testing::open(temp_dir.path()).unwrap();
+
+    let mut overlay = store.fork();
+    overlay
+        .set(&store, "temp".to_owned(), "value".to_owned())
+        .unwrap();
+    overlay.remove(&store, "temp".to_owned()).unwrap();
+    assert_eq!(overlay.commit(&mut store).unwrap(), 0);
+    assert_eq!(store.compact_dry_run().unwrap().tombstone_records, 0);
+}
@@ -0,0 +1,139 @@
+use kvs::canonical::{self, PinnedExport};
+use kvs::testing;
+use kvs::KvStore;
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+const KEYS: usize = 40;
+
+/// Stalls on every write, so the export spans many of the writer's operations.
+struct Throttled(Vec<u8>);
+
+impl Write for Throttled {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        thread::sleep(Duration::from_millis(1));
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn contents(store: &KvStore) -> BTreeMap<String, Vec<u8>> {
+    (0..KEYS)
+        .filter_map(|i| {
+            let key = format!("key{}", i);
+            let value = store.get(&key).unwrap()?;
+            Some((key, value.into_bytes()))
+        })
+        .collect()
+}
+
+fn dumped(dump: &[u8]) -> BTreeMap<String, Vec<u8>> {
+    canonical::logical_contents(&canonical::parse(dump).unwrap())
+}
+
+#[test]
+fn pinned_export_ignores_writes_and_compactions_after_the_pin() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = testing::open(temp_dir.path()).unwrap();
+    for i in 0..KEYS {
+        store.set(format!("key{}", i), "first".to_owned()).unwrap();
+    }
+    store.set("key0".to_owned(), "second".to_owned()).unwrap();
+    store.remove("key1".to_owned()).unwrap();
+    let expected = contents(&store);
+
+    let pin = store.pin_log().unwrap();
+    for i in 0..KEYS {
+        store.set(format!("key{}", i), "after".to_owned()).unwrap();
+    }
+    store.remove("key2".to_owned()).unwrap();
+    store.set("key1".to_owned(), "back".to_owned()).unwrap();
+    store.compact().unwrap();
+
+    let mut dump = Vec::new();
+    let pinned = canonical::export_pinned(&pin, &mut dump).unwrap();
+    assert_eq!(
+        pinned,
+        PinnedExport {
+            keys: KEYS as u64 - 1,
+            sequence: KEYS as u64 + 2,
+            log_offset: pin.log_offset(),
+        }
+    );
+    let text = String::from_utf8(dump.clone()).unwrap();
+    assert!(text.contains(&format!(
+        "# pinned at sequence {} (log offset {})",
+        KEYS + 2,
+        pin.log_offset()
+    )));
+    assert_eq!(dumped(&dump), expected);
+    drop(pin);
+
+    assert_eq!(store.get("key1").unwrap(), Some("back".to_owned()));
+    assert_eq!(store.get("key2").unwrap(), None);
+}
+
+#[test]
+fn slow_export_under_load_matches_the_pinned_state() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = Arc::new(Mutex::new(testing::open(temp_dir.path()).unwrap()));
+    let stop = Arc::new(AtomicBool::new(false));
+    let compactions = Arc::new(AtomicU64::new(0));
+
+    let writer = {
+        let store = Arc::clone(&store);
+        let stop = Arc::clone(&stop);
+        let compactions = Arc::clone(&compactions);
+        thread::spawn(move || {
+            let mut round = 0;
+            while !stop.load(Ordering::SeqCst) {
+                let mut store = store.lock().unwrap();
+                let key = format!("key{}", round % KEYS);
+                if round % 7 == 3 {
+                    // The key may already be gone, which fails harmlessly.
+                    let _ = store.remove(key);
+                } else {
+                    store.set(key, format!("round{}", round)).unwrap();
+                }
+                if round % 100 == 99 {
+                    store.compact().unwrap();
+                    compactions.fetch_add(1, Ordering::SeqCst);
+                }
+                round += 1;
+                drop(store);
+                thread::yield_now();
+            }
+        })
+    };
+
+    thread::sleep(Duration::from_millis(50));
+    let (pin, expected) = {
+        let store = store.lock().unwrap();
+        (store.pin_log().unwrap(), contents(&store))
+    };
+    let compactions_at_pin = compactions.load(Ordering::SeqCst);
+
+    let mut out = Throttled(Vec::new());
+    let pinned = canonical::export_pinned(&pin, &mut out).unwrap();
+    // Let the writer get past at least one compaction with the pin still held.
+    while compactions.load(Ordering::SeqCst) == compactions_at_pin {
+        thread::sleep(Duration::from_millis(1));
+    }
+    stop.store(true, Ordering::SeqCst);
+    writer.join().unwrap();
+
+    assert_eq!(pinned.keys, expected.len() as u64);
+    assert_eq!(dumped(&out.0), expected);
+    // The export is made from the pin, so a second one agrees though the log has moved on.
+    let mut again = Vec::new();
+    canonical::export_pinned(&pin, &mut again).unwrap();
+    assert_eq!(again, out.0);
+}
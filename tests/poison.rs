@@ -0,0 +1,132 @@
+use kvs::events::StoreEvent;
+use kvs::testing;
+use kvs::{KvError, KvStore, KvsClient, KvsServer};
+use std::net::SocketAddr;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use tempfile::TempDir;
+
+fn is_poisoned<T: std::fmt::Debug>(result: kvs::Result<T>) -> bool {
+    match result {
+        Err(KvError::StorePoisoned { cause }) => {
+            assert!(cause.contains("set"), "{}", cause);
+            true
+        }
+        other => panic!("expected StorePoisoned, got {:?}", other),
+    }
+}
+
+fn is_poisoned_on_server<T: std::fmt::Debug>(result: kvs::Result<T>) -> bool {
+    match result {
+        Err(KvError::ServerError(message)) => message.contains("poisoned"),
+        other => panic!("expected a server error, got {:?}", other),
+    }
+}
+
+fn start_server(store: KvStore) -> SocketAddr {
+    let log = slog::Logger::root(slog::Discard, slog::o!());
+    let server = KvsServer::new("127.0.0.1:0", store, log).unwrap();
+    let addr = server.local_addr().unwrap();
+    thread::spawn(move || server.listen_forever());
+    addr
+}
+
+#[test]
+fn panic_poisons_the_store_until_cleared() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = testing::open(temp_dir.path()).unwrap();
+    store.set("before".to_owned(), "1".to_owned()).unwrap();
+
+    store.inject_panic_after_append();
+    let panicked = panic::catch_unwind(AssertUnwindSafe(|| {
+        store.set("appended".to_owned(), "2".to_owned())
+    }));
+    assert!(panicked.is_err());
+    assert!(store.poison_cause().is_some());
+
+    assert!(is_poisoned(store.get("before")));
+    assert!(is_poisoned(store.set("after".to_owned(), "3".to_owned())));
+    assert!(is_poisoned(store.remove("before".to_owned())));
+    assert!(is_poisoned(store.compact()));
+
+    store.clear_poison_and_verify().unwrap();
+    assert_eq!(store.poison_cause(), None);
+    assert_eq!(store.get("before").unwrap(), Some("1".to_owned()));
+    // Its append completed before the panic, so the log has it.
+    assert_eq!(store.get("appended").unwrap(), Some("2".to_owned()));
+    assert_eq!(store.get("after").unwrap(), None);
+    store.set("after".to_owned(), "3".to_owned()).unwrap();
+    drop(store);
+
+    let store = testing::open(temp_dir.path()).unwrap();
+    assert_eq!(store.get("appended").unwrap(), Some("2".to_owned()));
+    assert_eq!(store.get("after").unwrap(), Some("3".to_owned()));
+}
+
+#[test]
+fn panicking_event_sink_does_not_poison_the_store() {
+    let temp_dir = TempDir::new().unwrap();
+    let options = testing::options().event_sink(Box::new(|event| {
+        if let StoreEvent::CompactionStarted { .. } = event {
+            panic!("sink failed");
+        }
+    }));
+    let mut store = KvStore::open_with_options(temp_dir.path(), options).unwrap();
+    store.set("key".to_owned(), "value".to_owned()).unwrap();
+    store.compact().unwrap();
+    assert_eq!(store.poison_cause(), None);
+    assert_eq!(store.get("key").unwrap(), Some("value".to_owned()));
+}
+
+#[test]
+fn server_survives_a_panic_and_serves_again_once_cleared() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = testing::open(temp_dir.path()).unwrap();
+    store.set("before".to_owned(), "1".to_owned()).unwrap();
+    store.inject_panic_after_append();
+    let addr = start_server(store);
+
+    // Readers on other connections keep going through the panic; they may see the store
+    // poisoned but never lose their connection.
+    let stop = Arc::new(AtomicBool::new(false));
+    let readers: Vec<_> = (0..4)
+        .map(|_| {
+            let stop = Arc::clone(&stop);
+            thread::spawn(move || {
+                let mut client = KvsClient::connect(addr).unwrap();
+                while !stop.load(Ordering::SeqCst) {
+                    match client.get("before".to_owned()) {
+                        Ok(value) => assert_eq!(value, Some("1".to_owned())),
+                        result => assert!(is_poisoned_on_server(result)),
+                    }
+                }
+            })
+        })
+        .collect();
+
+    let mut writer = KvsClient::connect(addr).unwrap();
+    assert!(is_poisoned_on_server(
+        writer.set("appended".to_owned(), "2".to_owned())
+    ));
+    assert!(is_poisoned_on_server(writer.get("before".to_owned())));
+    assert!(writer.ping().unwrap().poisoned);
+
+    writer.clear_poison_and_verify().unwrap();
+    assert!(writer.ping().unwrap().is_healthy());
+    assert_eq!(
+        writer.get("appended".to_owned()).unwrap(),
+        Some("2".to_owned())
+    );
+    writer.set("after".to_owned(), "3".to_owned()).unwrap();
+    assert_eq!(
+        writer.get("after".to_owned()).unwrap(),
+        Some("3".to_owned())
+    );
+
+    stop.store(true, Ordering::SeqCst);
+    for reader in readers {
+        reader.join().unwrap();
+    }
+}
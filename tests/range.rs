@@ -0,0 +1,90 @@
+use kvs::testing;
+use kvs::{KvStore, KvStoreOptions};
+use std::ops::Bound;
+use tempfile::TempDir;
+
+/// Plain, ordered and tiered indexes all answer range scans the same way.
+fn configurations() -> Vec<(&'static str, KvStoreOptions)> {
+    vec![
+        ("plain", testing::options()),
+        ("ordered", testing::options().ordered_index(true)),
+        ("tiered", testing::options().max_index_bytes(256)),
+    ]
+}
+
+fn days(store: &mut KvStore) {
+    for month in 1..=3 {
+        for day in [1, 15, 28] {
+            let key = format!("2024-{:02}-{:02}", month, day);
+            store.set(key.clone(), format!("first {}", key)).unwrap();
+        }
+    }
+    store
+        .set("2024-01-15".to_owned(), "second".to_owned())
+        .unwrap();
+    store.remove("2024-01-28".to_owned()).unwrap();
+}
+
+fn keys(pairs: Vec<(String, String)>) -> Vec<String> {
+    pairs.into_iter().map(|(key, _)| key).collect()
+}
+
+#[test]
+fn range_respects_bounds_and_order() {
+    for (name, options) in configurations() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut store = KvStore::open_with_options(temp_dir.path(), options.clone()).unwrap();
+        days(&mut store);
+
+        let january = store
+            .range("2024-01-01".to_owned().."2024-02-01".to_owned())
+            .unwrap();
+        assert_eq!(
+            january,
+            [
+                ("2024-01-01".to_owned(), "first 2024-01-01".to_owned()),
+                ("2024-01-15".to_owned(), "second".to_owned()),
+            ],
+            "{}",
+            name
+        );
+        assert_eq!(
+            keys(
+                store
+                    .range("2024-02-01".to_owned()..="2024-03-01".to_owned())
+                    .unwrap()
+            ),
+            ["2024-02-01", "2024-02-15", "2024-02-28", "2024-03-01"],
+            "{}",
+            name
+        );
+        assert_eq!(
+            keys(
+                store
+                    .range((Bound::Excluded("2024-03-01".to_owned()), Bound::Unbounded))
+                    .unwrap()
+            ),
+            ["2024-03-15", "2024-03-28"],
+            "{}",
+            name
+        );
+        assert_eq!(store.range(..).unwrap().len(), 8, "{}", name);
+        assert!(store.range("2025".to_owned()..).unwrap().is_empty());
+
+        // Compaction and reopening rebuild the index, order included.
+        store.compact().unwrap();
+        store.remove("2024-02-15".to_owned()).unwrap();
+        drop(store);
+        let store = KvStore::open_with_options(temp_dir.path(), options).unwrap();
+        assert_eq!(
+            keys(
+                store
+                    .range("2024-02".to_owned().."2024-03".to_owned())
+                    .unwrap()
+            ),
+            ["2024-02-01", "2024-02-28"],
+            "{}",
+            name
+        );
+    }
+}
@@ -0,0 +1,172 @@
+use kvs::testing::{self, FixtureBuilder};
+use kvs::{KvError, KvStore, KvStoreOptions};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use tempfile::TempDir;
+
+/// Far above any real pid, so a temp file carrying it is one a normal open would remove.
+const DEAD_PID: u32 = 999_999_999;
+
+const LONG: Duration = Duration::from_secs(3_600);
+
+/// Every entry under `dir`, the directory itself included, with its length and mtime.
+fn snapshot(dir: &Path) -> BTreeMap<PathBuf, (u64, SystemTime)> {
+    let mut entries = BTreeMap::new();
+    for entry in walkdir::WalkDir::new(dir) {
+        let entry = entry.unwrap();
+        let metadata = entry.metadata().unwrap();
+        entries.insert(
+            entry.path().to_path_buf(),
+            (metadata.len(), metadata.modified().unwrap()),
+        );
+    }
+    entries
+}
+
+/// An uncompacted fixture with overwrites, removes, a stats snapshot and a leftover temp
+/// file, so that a normal open would rewrite, create and delete files.
+fn fixture(dir: &Path) -> kvs::testing::Fixture {
+    let fixture = FixtureBuilder::new(7)
+        .key_count(300)
+        .overwrite_factor(2.5)
+        .delete_ratio(0.2)
+        .compact(false)
+        .build(dir)
+        .unwrap();
+    let options = testing::options().persist_stats(true);
+    let store = KvStore::open_with_options(dir, options).unwrap();
+    store.get("absent").unwrap();
+    drop(store);
+    fs::write(dir.join(format!(".compact-{}-0.tmp", DEAD_PID)), "partial").unwrap();
+    fixture
+}
+
+fn assert_refused<T: std::fmt::Debug>(result: kvs::Result<T>) {
+    match result {
+        Err(KvError::ReadOnlyFilesystem(_)) => {}
+        other => panic!("expected ReadOnlyFilesystem, got {:?}", other),
+    }
+}
+
+#[cfg(unix)]
+fn set_read_only(dir: &Path, read_only: bool) {
+    use std::os::unix::fs::PermissionsExt;
+
+    for entry in fs::read_dir(dir).unwrap() {
+        let mode = if read_only { 0o444 } else { 0o644 };
+        fs::set_permissions(entry.unwrap().path(), fs::Permissions::from_mode(mode)).unwrap();
+    }
+    let mode = if read_only { 0o555 } else { 0o755 };
+    fs::set_permissions(dir, fs::Permissions::from_mode(mode)).unwrap();
+}
+
+#[cfg(not(unix))]
+fn set_read_only(dir: &Path, read_only: bool) {
+    for entry in fs::read_dir(dir).unwrap() {
+        let path = entry.unwrap().path();
+        let mut permissions = fs::metadata(&path).unwrap().permissions();
+        permissions.set_readonly(read_only);
+        fs::set_permissions(&path, permissions).unwrap();
+    }
+}
+
+#[test]
+fn read_only_open_writes_nothing() {
+    let temp_dir = TempDir::new().unwrap();
+    let fixture = fixture(temp_dir.path());
+    set_read_only(temp_dir.path(), true);
+    let before = snapshot(temp_dir.path());
+
+    let options = testing::options()
+        .read_only(true)
+        .persist_stats(true)
+        .max_index_bytes(1024);
+    let mut store = KvStore::open_with_options(temp_dir.path(), options).unwrap();
+    assert!(fixture.manifest.verify(&store).unwrap().is_empty());
+    // The snapshot is read, with the one get of the earlier open.
+    let stats = store.stats();
+    assert_eq!(stats.lifetime.gets, stats.since_open.gets + 1);
+
+    assert_refused(store.set("key".to_owned(), "value".to_owned()));
+    assert_refused(store.remove("key".to_owned()));
+    assert_refused(store.set_batch(vec![("key".to_owned(), "value".to_owned())]));
+    assert_refused(store.compact());
+    assert_refused(store.clear());
+    let existing = store.keys().unwrap().next().unwrap();
+    assert_refused(store.set_with_ttl("key".to_owned(), "value".to_owned(), LONG));
+    assert_refused(store.set_if_absent("key".to_owned(), "value".to_owned()));
+    assert_refused(store.compare_and_swap("key".to_owned(), None, Some("value".to_owned())));
+    assert_refused(store.pop(&existing));
+    assert_refused(store.rename(&existing, "key".to_owned()));
+    assert_refused(store.increment("key", 1));
+    assert_refused(store.expire(&existing, LONG));
+    let mut overlay = store.fork();
+    overlay
+        .set(&store, "key".to_owned(), "value".to_owned())
+        .unwrap();
+    assert_refused(overlay.commit(&mut store));
+    store.sync().unwrap();
+    store.flush().unwrap();
+    store.recover_append().unwrap();
+    drop(store);
+    drop(KvStore::open_read_only(temp_dir.path()).unwrap());
+
+    assert_eq!(snapshot(temp_dir.path()), before);
+    set_read_only(temp_dir.path(), false);
+}
+
+#[test]
+fn read_only_open_never_creates() {
+    let temp_dir = TempDir::new().unwrap();
+    let missing = temp_dir.path().join("missing");
+    let options = KvStoreOptions::new()
+        .create_if_missing(true)
+        .read_only(true);
+    assert!(matches!(
+        KvStore::open_with_options(&missing, options),
+        Err(KvError::DirectoryNotFound(_))
+    ));
+    assert!(!missing.exists());
+
+    // Nor the log of an empty directory.
+    assert!(KvStore::open_read_only(temp_dir.path()).is_err());
+    assert!(!temp_dir.path().join("db.log").exists());
+}
+
+/// Needs root and mount(8), so it only runs with `KVS_TEST_READ_ONLY_MOUNT=1`.
+#[cfg(target_os = "linux")]
+#[test]
+fn read_only_bind_mount() {
+    use std::process::Command;
+
+    if std::env::var_os("KVS_TEST_READ_ONLY_MOUNT").is_none() {
+        eprintln!("KVS_TEST_READ_ONLY_MOUNT is not set, skipping");
+        return;
+    }
+    let source = TempDir::new().unwrap();
+    let target = TempDir::new().unwrap();
+    let fixture = fixture(source.path());
+    let mount = |args: &[&str]| {
+        let status = Command::new("mount").args(args).status().unwrap();
+        assert!(status.success(), "mount {:?} failed", args);
+    };
+    let target_path = target.path().to_str().unwrap();
+    mount(&["--bind", source.path().to_str().unwrap(), target_path]);
+    mount(&["-o", "remount,bind,ro", target_path]);
+
+    let result = std::panic::catch_unwind(|| {
+        let before = snapshot(target.path());
+        let err = KvStore::open(target.path()).err().unwrap();
+        assert!(matches!(err, KvError::ReadOnlyFilesystem(_)));
+        assert!(err.to_string().contains("open_read_only"));
+        let store = KvStore::open_read_only(target.path()).unwrap();
+        assert!(fixture.manifest.verify(&store).unwrap().is_empty());
+        drop(store);
+        assert_eq!(snapshot(target.path()), before);
+    });
+    let status = Command::new("umount").arg(target_path).status().unwrap();
+    assert!(status.success());
+    result.unwrap();
+}
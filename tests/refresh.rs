@@ -0,0 +1,173 @@
+use assert_cmd::prelude::*;
+use kvs::{KvError, KvStore, RefreshStats};
+use std::fs::OpenOptions;
+use std::io::{BufRead, BufReader, Write};
+use std::process::{Command, Stdio};
+use std::sync::mpsc;
+use std::thread;
+use tempfile::TempDir;
+
+const BATCHES: usize = 8;
+const BATCH_SIZE: usize = 25;
+
+fn key(batch: usize, i: usize) -> String {
+    format!("batch{}/key{}", batch, i)
+}
+
+/// A writer thread writes batches, compacting after half of them, and waits for the
+/// read-only handle to check each one before writing the next.
+#[test]
+fn refresh_follows_a_writer_through_a_compaction() {
+    let temp_dir = TempDir::new().unwrap();
+    let dir = temp_dir.path().to_path_buf();
+    let mut writer_store = KvStore::open(&dir).unwrap();
+    let mut reader = KvStore::open_read_only(&dir).unwrap();
+    assert!(!reader.changed_since_last_refresh().unwrap());
+
+    let (written, wait_written) = mpsc::channel();
+    let (checked, wait_checked) = mpsc::channel::<()>();
+    let writer = thread::spawn(move || {
+        for batch in 0..BATCHES {
+            for i in 0..BATCH_SIZE {
+                writer_store
+                    .set(key(batch, i), format!("v{}", batch))
+                    .unwrap();
+            }
+            // Overwrite part of the previous batch, so a compaction has something to drop.
+            if batch > 0 {
+                writer_store.remove(key(batch - 1, 0)).unwrap();
+            }
+            let compacted = batch == BATCHES / 2;
+            if compacted {
+                writer_store.compact().unwrap();
+            }
+            written.send(compacted).unwrap();
+            wait_checked.recv().unwrap();
+        }
+    });
+
+    for batch in 0..BATCHES {
+        let compacted = wait_written.recv().unwrap();
+        assert!(reader.changed_since_last_refresh().unwrap());
+        if compacted && cfg!(unix) {
+            match reader.get(&key(0, 1)) {
+                Err(KvError::StoreDisplaced(_)) => {}
+                other => panic!("expected StoreDisplaced, got {:?}", other),
+            }
+        }
+
+        let refreshed = reader.refresh().unwrap();
+        assert_eq!(refreshed.reloaded, compacted, "batch {}", batch);
+        if compacted {
+            // Everything live, minus the removed first key of each earlier batch.
+            assert_eq!(
+                refreshed.records_applied,
+                ((batch + 1) * BATCH_SIZE - batch) as u64
+            );
+        } else {
+            let removes = if batch > 0 { 1 } else { 0 };
+            assert_eq!(refreshed.records_applied, (BATCH_SIZE + removes) as u64);
+            assert_eq!(refreshed.changed_keys.len(), BATCH_SIZE + removes);
+        }
+        assert!(!reader.changed_since_last_refresh().unwrap());
+
+        for i in 0..BATCH_SIZE {
+            assert_eq!(
+                reader.get(&key(batch, i)).unwrap(),
+                Some(format!("v{}", batch))
+            );
+        }
+        if batch > 0 {
+            assert_eq!(reader.get(&key(batch - 1, 0)).unwrap(), None);
+            assert_eq!(
+                reader.get(&key(batch - 1, 1)).unwrap(),
+                Some(format!("v{}", batch - 1))
+            );
+        }
+        checked.send(()).unwrap();
+    }
+    writer.join().unwrap();
+    assert_eq!(reader.refresh().unwrap(), RefreshStats::default());
+}
+
+#[test]
+fn refresh_waits_for_a_record_still_being_appended() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = KvStore::open(temp_dir.path()).unwrap();
+    store.set("a".to_owned(), "1".to_owned()).unwrap();
+    drop(store);
+    let mut reader = KvStore::open_read_only(temp_dir.path()).unwrap();
+
+    let record = br#"{"Set":{"key":"b","value":"2"}}"#;
+    let mut log = OpenOptions::new()
+        .append(true)
+        .open(temp_dir.path().join("db.log"))
+        .unwrap();
+    log.write_all(&record[..10]).unwrap();
+    assert!(reader.changed_since_last_refresh().unwrap());
+    assert_eq!(reader.refresh().unwrap().records_applied, 0);
+    assert_eq!(reader.get("b").unwrap(), None);
+
+    log.write_all(&record[10..]).unwrap();
+    log.write_all(b"\n").unwrap();
+    let refreshed = reader.refresh().unwrap();
+    assert_eq!(refreshed.records_applied, 1);
+    assert_eq!(refreshed.changed_keys, vec!["b".to_owned()]);
+    assert_eq!(reader.get("b").unwrap(), Some("2".to_owned()));
+    assert_eq!(reader.get("a").unwrap(), Some("1".to_owned()));
+}
+
+#[test]
+fn refresh_is_a_no_op_on_a_writable_store() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = KvStore::open(temp_dir.path()).unwrap();
+    store.set("a".to_owned(), "1".to_owned()).unwrap();
+    assert!(!store.changed_since_last_refresh().unwrap());
+    assert_eq!(store.refresh().unwrap(), RefreshStats::default());
+    assert_eq!(store.get("a").unwrap(), Some("1".to_owned()));
+}
+
+#[test]
+fn cli_watch_dir_prints_changes_under_the_prefix() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = KvStore::open(temp_dir.path()).unwrap();
+    store.set("app/gone".to_owned(), "x".to_owned()).unwrap();
+
+    let mut watcher = Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["watch-dir", "--interval", "20ms", "app/", "--dir"])
+        .arg(temp_dir.path())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut lines = BufReader::new(watcher.stdout.take().unwrap()).lines();
+    assert!(lines.next().unwrap().unwrap().starts_with("Watching"));
+
+    store
+        .set("other/key".to_owned(), "ignored".to_owned())
+        .unwrap();
+    store.set("app/key".to_owned(), "first".to_owned()).unwrap();
+    store
+        .set("app/key".to_owned(), "second".to_owned())
+        .unwrap();
+    store.remove("app/gone".to_owned()).unwrap();
+
+    // The writes may be picked up over several polls; the remove is the last of them.
+    let mut seen = Vec::new();
+    while !seen.contains(&r#"rm "app/gone""#.to_owned()) {
+        seen.push(lines.next().unwrap().unwrap());
+    }
+    watcher.kill().unwrap();
+    watcher.wait().unwrap();
+    assert!(
+        seen.iter().all(|line| !line.contains("other/")),
+        "{:?}",
+        seen
+    );
+    assert_eq!(
+        seen[seen.len() - 2],
+        r#"set "app/key" second"#,
+        "{:?}",
+        seen
+    );
+}
@@ -0,0 +1,96 @@
+use assert_cmd::prelude::*;
+use kvs::testing;
+use kvs::{KvError, KvStore};
+use predicates::str::contains;
+use std::process::Command;
+use tempfile::TempDir;
+
+fn users(store: &mut KvStore) {
+    for user in ["user:123:", "user:1234:", "user:124:"] {
+        for field in ["name", "email"] {
+            store
+                .set(format!("{}{}", user, field), field.to_owned())
+                .unwrap();
+        }
+    }
+    store.set("user:123".to_owned(), "bare".to_owned()).unwrap();
+}
+
+#[test]
+fn remove_prefix_removes_only_keys_under_it() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = testing::open(temp_dir.path()).unwrap();
+    users(&mut store);
+
+    assert_eq!(store.remove_prefix("user:123:").unwrap(), 2);
+    assert_eq!(store.get("user:123:name").unwrap(), None);
+    assert_eq!(
+        store.get("user:1234:name").unwrap(),
+        Some("name".to_owned())
+    );
+    assert_eq!(store.get("user:123").unwrap(), Some("bare".to_owned()));
+    assert_eq!(store.remove_prefix("user:123:").unwrap(), 0);
+    drop(store);
+
+    let mut store = testing::open(temp_dir.path()).unwrap();
+    assert_eq!(store.get("user:123:email").unwrap(), None);
+    assert_eq!(store.remove_prefix("user:12").unwrap(), 5);
+    assert_eq!(store.scan_prefix("").unwrap(), []);
+}
+
+#[test]
+fn remove_prefix_refuses_an_empty_prefix() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = testing::open(temp_dir.path()).unwrap();
+    users(&mut store);
+    match store.remove_prefix("") {
+        Err(KvError::EmptyPrefix) => {}
+        other => panic!("expected EmptyPrefix, got {:?}", other),
+    }
+    assert_eq!(store.scan_prefix("").unwrap().len(), 7);
+}
+
+#[test]
+fn cli_rm_prefix_needs_confirmation() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = KvStore::open(temp_dir.path()).unwrap();
+    users(&mut store);
+    drop(store);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["rm", "--prefix", "user:123:", "--dir"])
+        .arg(temp_dir.path())
+        .assert()
+        .failure()
+        .stderr(contains("--yes"));
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    assert_eq!(store.get("user:123:name").unwrap(), Some("name".to_owned()));
+    drop(store);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["rm", "--prefix", "user:123:", "--yes", "--dir"])
+        .arg(temp_dir.path())
+        .assert()
+        .success()
+        .stdout(contains("Removed 2 keys"));
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["rm", "--prefix", "", "--yes", "--dir"])
+        .arg(temp_dir.path())
+        .assert()
+        .failure()
+        .stderr(contains("use clear"));
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["rm", "user:123", "--prefix", "user:", "--dir"])
+        .arg(temp_dir.path())
+        .assert()
+        .failure();
+
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    assert_eq!(store.get("user:123:name").unwrap(), None);
+    assert_eq!(store.get("user:124:name").unwrap(), Some("name".to_owned()));
+    assert_eq!(store.get("user:123").unwrap(), Some("bare".to_owned()));
+}
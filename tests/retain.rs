@@ -0,0 +1,78 @@
+use kvs::kv_store::DEFAULT_LOG_FILE_NAME;
+use kvs::testing;
+use std::fs;
+use tempfile::TempDir;
+
+/// Keys named after the day they were written, `event:<day>:<n>`.
+fn day_of(key: &str) -> Option<u32> {
+    key.strip_prefix("event:")?.split(':').next()?.parse().ok()
+}
+
+#[test]
+fn retain_removes_rejected_keys_and_compaction_reclaims_them() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = testing::open(temp_dir.path()).unwrap();
+    for day in 1..=10 {
+        for n in 0..20 {
+            store
+                .set(format!("event:{}:{}", day, n), "x".repeat(100))
+                .unwrap();
+        }
+    }
+    store.set("config".to_owned(), "kept".to_owned()).unwrap();
+    store.compact().unwrap();
+    let log = temp_dir.path().join(DEFAULT_LOG_FILE_NAME);
+    let before = fs::metadata(&log).unwrap().len();
+
+    let mut seen = 0;
+    let removed = store
+        .retain(|key| {
+            seen += 1;
+            day_of(key).is_none_or(|day| day > 7)
+        })
+        .unwrap();
+    assert_eq!(seen, 201);
+    assert_eq!(removed, 140);
+    assert_eq!(store.get("event:7:0").unwrap(), None);
+    assert_eq!(store.get("event:8:19").unwrap(), Some("x".repeat(100)));
+    assert_eq!(store.get("config").unwrap(), Some("kept".to_owned()));
+    // Nothing left to reject, so nothing is written.
+    let len = fs::metadata(&log).unwrap().len();
+    assert_eq!(store.retain(|key| day_of(key) != Some(1)).unwrap(), 0);
+    assert_eq!(fs::metadata(&log).unwrap().len(), len);
+
+    store.compact().unwrap();
+    let after = fs::metadata(&log).unwrap().len();
+    assert!(after < before / 2, "{} is not well under {}", after, before);
+    drop(store);
+
+    let store = testing::open(temp_dir.path()).unwrap();
+    assert_eq!(store.get("event:1:0").unwrap(), None);
+    assert_eq!(store.get("event:9:5").unwrap(), Some("x".repeat(100)));
+}
+
+#[test]
+fn retain_cut_short_by_a_crash_removes_nothing() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = testing::open(temp_dir.path()).unwrap();
+    for day in 1..=3 {
+        store
+            .set(format!("event:{}:0", day), "x".to_owned())
+            .unwrap();
+    }
+    let log = temp_dir.path().join(DEFAULT_LOG_FILE_NAME);
+    let before = fs::metadata(&log).unwrap().len() as usize;
+    assert_eq!(store.retain(|key| day_of(key) == Some(3)).unwrap(), 2);
+    drop(store);
+
+    let full = fs::read(&log).unwrap();
+    fs::write(&log, &full[..full.len() - 3]).unwrap();
+    let store = testing::open(temp_dir.path()).unwrap();
+    assert_eq!(store.open_report().torn_tail.unwrap().offset, before as u64);
+    for day in 1..=3 {
+        assert_eq!(
+            store.get(&format!("event:{}:0", day)).unwrap(),
+            Some("x".to_owned())
+        );
+    }
+}
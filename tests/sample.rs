@@ -0,0 +1,99 @@
+use kvs::testing;
+use kvs::{KvStore, KvStoreOptions};
+use std::collections::{HashMap, HashSet};
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+fn seeded(seed: u64) -> KvStoreOptions {
+    testing::options().sample_seed(seed)
+}
+
+fn fill(store: &mut KvStore, keys: usize) {
+    for n in 0..keys {
+        store.set(format!("key{:03}", n), "v".to_owned()).unwrap();
+    }
+}
+
+#[test]
+fn samples_are_distinct_live_keys() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = testing::open(temp_dir.path()).unwrap();
+    assert_eq!(store.random_key().unwrap(), None);
+    assert!(store.sample_keys(3).unwrap().is_empty());
+
+    fill(&mut store, 50);
+    store.remove("key007".to_owned()).unwrap();
+    store
+        .set_with_ttl(
+            "key008".to_owned(),
+            "v".to_owned(),
+            Duration::from_millis(20),
+        )
+        .unwrap();
+    thread::sleep(Duration::from_millis(60));
+    for _ in 0..20 {
+        let sample = store.sample_keys(10).unwrap();
+        assert_eq!(sample.len(), 10);
+        assert!(
+            sample.windows(2).all(|pair| pair[0] < pair[1]),
+            "{:?}",
+            sample
+        );
+        assert!(!sample.contains(&"key007".to_owned()));
+        assert!(!sample.contains(&"key008".to_owned()));
+        let key = store.random_key().unwrap().unwrap();
+        assert!(key != "key007" && key != "key008");
+    }
+    assert!(store.sample_keys(0).unwrap().is_empty());
+}
+
+#[test]
+fn sampling_more_than_there_are_returns_them_all() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = testing::open(temp_dir.path()).unwrap();
+    fill(&mut store, 5);
+    let all: Vec<String> = (0..5).map(|n| format!("key{:03}", n)).collect();
+    assert_eq!(store.sample_keys(5).unwrap(), all);
+    assert_eq!(store.sample_keys(100).unwrap(), all);
+}
+
+#[test]
+fn random_key_is_roughly_uniform() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = KvStore::open_with_options(temp_dir.path(), seeded(7)).unwrap();
+    fill(&mut store, 10);
+    let mut counts: HashMap<String, usize> = HashMap::new();
+    for _ in 0..2000 {
+        *counts
+            .entry(store.random_key().unwrap().unwrap())
+            .or_default() += 1;
+    }
+    assert_eq!(counts.len(), 10);
+    for (key, count) in counts {
+        assert!(
+            (120..=280).contains(&count),
+            "{} drawn {} times",
+            key,
+            count
+        );
+    }
+}
+
+#[test]
+fn a_seed_repeats_the_samples_whatever_the_index() {
+    let draw = |options: KvStoreOptions| {
+        let temp_dir = TempDir::new().unwrap();
+        let mut store = KvStore::open_with_options(temp_dir.path(), options).unwrap();
+        fill(&mut store, 200);
+        (0..5)
+            .map(|_| store.sample_keys(4).unwrap())
+            .collect::<Vec<_>>()
+    };
+    let first = draw(seeded(42));
+    assert_eq!(draw(seeded(42)), first);
+    assert_eq!(draw(seeded(42).max_index_bytes(256)), first);
+    // Each call draws afresh, and another seed draws otherwise.
+    assert!(first.iter().collect::<HashSet<_>>().len() > 1);
+    assert_ne!(draw(seeded(43)), first);
+}
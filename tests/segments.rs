@@ -0,0 +1,475 @@
+use assert_cmd::prelude::*;
+use kvs::testing;
+use kvs::{KvError, KvStore, KvStoreOptions, LogFormat};
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+fn segmented(bytes: u64) -> KvStoreOptions {
+    testing::options().segment_size(bytes)
+}
+
+/// The log files in `dir`, in name order.
+fn logs(dir: &Path) -> Vec<String> {
+    let mut names: Vec<String> = fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .filter(|name| name.ends_with(".log"))
+        .collect();
+    names.sort();
+    names
+}
+
+fn value(i: usize) -> String {
+    format!("value {} {}", i, "x".repeat(i % 17))
+}
+
+/// Writes keys `0..keys` `rounds` times over, removing every fifth on the last round.
+fn fill(store: &mut KvStore, keys: usize, rounds: usize) {
+    for round in 0..rounds {
+        for i in 0..keys {
+            store
+                .set(format!("key{}", i), value(i * rounds + round))
+                .unwrap();
+        }
+    }
+    for i in (0..keys).step_by(5) {
+        store.remove(format!("key{}", i)).unwrap();
+    }
+}
+
+fn assert_filled(store: &KvStore, keys: usize, rounds: usize) {
+    for i in 0..keys {
+        let expected = match i % 5 {
+            0 => None,
+            _ => Some(value(i * rounds + rounds - 1)),
+        };
+        assert_eq!(
+            store.get(&format!("key{}", i)).unwrap(),
+            expected,
+            "key{}",
+            i
+        );
+    }
+}
+
+#[test]
+fn writes_survive_reopening_and_compaction_at_every_segment_size() {
+    for size in [256, 1024, 4096, 65536] {
+        let temp_dir = TempDir::new().unwrap();
+        let mut store = KvStore::open_with_options(temp_dir.path(), segmented(size)).unwrap();
+        fill(&mut store, 60, 4);
+        assert_filled(&store, 60, 4);
+        drop(store);
+
+        let mut store = KvStore::open_with_options(temp_dir.path(), segmented(size)).unwrap();
+        assert_filled(&store, 60, 4);
+        store.compact().unwrap();
+        assert_filled(&store, 60, 4);
+        store
+            .set("after".to_owned(), "compaction".to_owned())
+            .unwrap();
+        drop(store);
+
+        let store = KvStore::open_with_options(temp_dir.path(), segmented(size)).unwrap();
+        assert_filled(&store, 60, 4);
+        assert_eq!(store.get("after").unwrap(), Some("compaction".to_owned()));
+        assert!(
+            !logs(temp_dir.path()).contains(&"db.log".to_owned()),
+            "{}",
+            size
+        );
+    }
+}
+
+#[test]
+fn appends_roll_over_to_numbered_segments() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = KvStore::open_with_options(temp_dir.path(), segmented(512)).unwrap();
+    assert_eq!(logs(temp_dir.path()), ["000001.log"]);
+    for i in 0..40 {
+        store.set(format!("key{}", i), value(i)).unwrap();
+    }
+    let names = logs(temp_dir.path());
+    assert!(names.len() > 2, "{:?}", names);
+    for (n, name) in names.iter().enumerate() {
+        assert_eq!(*name, format!("{:06}.log", n + 1));
+        let contents = fs::read(temp_dir.path().join(name)).unwrap();
+        assert!(contents.starts_with(b"\0kvs"), "{}", name);
+    }
+    // Only the last may run past the size, and only by the record that started it.
+    for name in &names[..names.len() - 1] {
+        let len = fs::metadata(temp_dir.path().join(name)).unwrap().len();
+        assert!(len <= 512, "{} is {} bytes", name, len);
+    }
+}
+
+#[test]
+fn a_log_in_one_file_moves_into_the_first_segment() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = testing::open(temp_dir.path()).unwrap();
+    store.set("old".to_owned(), "log".to_owned()).unwrap();
+    drop(store);
+    let before = fs::read(temp_dir.path().join("db.log")).unwrap();
+
+    let mut store = KvStore::open_with_options(temp_dir.path(), segmented(256)).unwrap();
+    assert_eq!(logs(temp_dir.path()), ["000001.log"]);
+    assert_eq!(
+        fs::read(temp_dir.path().join("000001.log")).unwrap(),
+        before
+    );
+    assert_eq!(store.get("old").unwrap(), Some("log".to_owned()));
+    for i in 0..20 {
+        store.set(format!("key{}", i), value(i)).unwrap();
+    }
+    drop(store);
+
+    // Segments stay segments without the option.
+    let store = testing::open(temp_dir.path()).unwrap();
+    assert_eq!(store.get("old").unwrap(), Some("log".to_owned()));
+    assert_eq!(store.get("key19").unwrap(), Some(value(19)));
+    assert!(!temp_dir.path().join("db.log").exists());
+}
+
+#[test]
+fn a_log_in_one_file_is_split_into_segments() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = testing::open(temp_dir.path()).unwrap();
+    // Nothing dead, so opening does not compact the split away.
+    for i in 0..60 {
+        store.set(format!("key{}", i), value(i)).unwrap();
+    }
+    let batch: Vec<(String, String)> = (0..4).map(|i| (format!("batch{}", i), value(i))).collect();
+    store.set_batch(batch.clone()).unwrap();
+    drop(store);
+    let before = fs::metadata(temp_dir.path().join("db.log")).unwrap().len();
+
+    // The default merge policy would merge the many segments of one size the split
+    // leaves, so the open does not compact; the one below does.
+    let options = segmented(512).compact_on_open(false);
+    let store = KvStore::open_with_options(temp_dir.path(), options).unwrap();
+    let names = logs(temp_dir.path());
+    assert!(names.len() as u64 > before / 512, "{:?}", names);
+    let mut records = 0;
+    for (n, name) in names.iter().enumerate() {
+        assert_eq!(*name, format!("{:06}.log", n + 1));
+        let contents = fs::read(temp_dir.path().join(name)).unwrap();
+        assert!(contents.starts_with(b"\0kvs"), "{}", name);
+        records += contents.len() as u64 - 8;
+    }
+    // The records as they were, each segment with a header of its own.
+    assert_eq!(records, before - 8);
+    assert!(!temp_dir.path().join("db.log").exists());
+    assert!(!temp_dir.path().join("segments.split").exists());
+    for i in 0..60 {
+        assert_eq!(store.get(&format!("key{}", i)).unwrap(), Some(value(i)));
+    }
+    for (key, value) in &batch {
+        assert_eq!(store.get(key).unwrap().as_ref(), Some(value));
+    }
+    drop(store);
+
+    let mut store = testing::open(temp_dir.path()).unwrap();
+    store.compact().unwrap();
+    assert!(logs(temp_dir.path()).len() < names.len());
+    for i in 0..60 {
+        assert_eq!(store.get(&format!("key{}", i)).unwrap(), Some(value(i)));
+    }
+}
+
+#[test]
+fn a_split_cut_short_starts_over() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = testing::open(temp_dir.path()).unwrap();
+    fill(&mut store, 60, 4);
+    drop(store);
+    // As a crash leaves it: the marker, and the first segment written part way.
+    let log = fs::read(temp_dir.path().join("db.log")).unwrap();
+    fs::write(temp_dir.path().join("segments.split"), b"").unwrap();
+    fs::write(temp_dir.path().join("000001.log"), &log[..300]).unwrap();
+
+    // A reader takes the one file for the log, and leaves the rest to the writer.
+    let reader = KvStore::open_read_only(temp_dir.path()).unwrap();
+    assert_filled(&reader, 60, 4);
+    drop(reader);
+    assert!(temp_dir.path().join("segments.split").exists());
+
+    let store = KvStore::open_with_options(temp_dir.path(), segmented(512)).unwrap();
+    assert_filled(&store, 60, 4);
+    assert!(logs(temp_dir.path()).len() > 2);
+    assert!(!temp_dir.path().join("db.log").exists());
+    assert!(!temp_dir.path().join("segments.split").exists());
+}
+
+#[test]
+fn compaction_removes_segments_left_without_live_records() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = KvStore::open_with_options(temp_dir.path(), segmented(512)).unwrap();
+    for i in 0..30 {
+        store.set(format!("key{}", i), value(i)).unwrap();
+    }
+    let first = logs(temp_dir.path());
+    for i in 0..30 {
+        store.set(format!("key{}", i), "new".to_owned()).unwrap();
+    }
+    store.compact().unwrap();
+    let after = logs(temp_dir.path());
+    for name in &first[..first.len() - 1] {
+        assert!(!after.contains(name), "{} survived in {:?}", name, after);
+    }
+    for i in 0..30 {
+        assert_eq!(
+            store.get(&format!("key{}", i)).unwrap(),
+            Some("new".to_owned())
+        );
+    }
+    drop(store);
+
+    let store = KvStore::open_with_options(temp_dir.path(), segmented(512)).unwrap();
+    for i in 0..30 {
+        assert_eq!(
+            store.get(&format!("key{}", i)).unwrap(),
+            Some("new".to_owned())
+        );
+    }
+}
+
+#[test]
+fn removals_outlive_compaction_of_the_segments_they_remove_from() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = KvStore::open_with_options(temp_dir.path(), segmented(512)).unwrap();
+    store.set("gone".to_owned(), "soon".to_owned()).unwrap();
+    for i in 0..30 {
+        store.set(format!("key{}", i), value(i)).unwrap();
+    }
+    store.remove("gone".to_owned()).unwrap();
+    for i in 0..30 {
+        store.set(format!("key{}", i), value(i + 1)).unwrap();
+    }
+    store.compact().unwrap();
+    assert_eq!(store.get("gone").unwrap(), None);
+    drop(store);
+
+    let store = KvStore::open_with_options(temp_dir.path(), segmented(512)).unwrap();
+    assert_eq!(store.get("gone").unwrap(), None);
+    assert_eq!(store.get("key29").unwrap(), Some(value(30)));
+}
+
+#[test]
+fn a_log_file_beside_segments_is_refused() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = KvStore::open_with_options(temp_dir.path(), segmented(256)).unwrap();
+    store.set("key".to_owned(), "value".to_owned()).unwrap();
+    drop(store);
+    fs::copy(
+        temp_dir.path().join("000001.log"),
+        temp_dir.path().join("db.log"),
+    )
+    .unwrap();
+
+    match KvStore::open_with_options(temp_dir.path(), segmented(256)) {
+        Err(e @ KvError::MixedLogLayout(_)) => {
+            assert!(e.to_string().contains("db.log"), "{}", e);
+        }
+        Err(e) => panic!("unexpected error: {:?}", e),
+        Ok(_) => panic!("opened a log beside its segments"),
+    }
+}
+
+#[test]
+fn a_reader_follows_rollovers_and_compaction() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut writer = KvStore::open_with_options(temp_dir.path(), segmented(512)).unwrap();
+    writer.set("first".to_owned(), "write".to_owned()).unwrap();
+    let mut reader = KvStore::open_read_only(temp_dir.path()).unwrap();
+    assert_eq!(reader.get("first").unwrap(), Some("write".to_owned()));
+
+    for i in 0..30 {
+        writer.set(format!("key{}", i), value(i)).unwrap();
+    }
+    assert!(reader.changed_since_last_refresh().unwrap());
+    let stats = reader.refresh().unwrap();
+    assert!(!stats.reloaded);
+    assert_eq!(stats.records_applied, 30);
+    assert_eq!(reader.get("key29").unwrap(), Some(value(29)));
+    assert!(!reader.changed_since_last_refresh().unwrap());
+
+    for i in 0..30 {
+        writer.set(format!("key{}", i), "new".to_owned()).unwrap();
+    }
+    writer.compact().unwrap();
+    assert!(reader.refresh().unwrap().reloaded);
+    assert_eq!(reader.get("first").unwrap(), Some("write".to_owned()));
+    for i in 0..30 {
+        assert_eq!(
+            reader.get(&format!("key{}", i)).unwrap(),
+            Some("new".to_owned())
+        );
+    }
+}
+
+#[test]
+fn snapshots_and_scans_span_segments() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = KvStore::open_with_options(temp_dir.path(), segmented(256)).unwrap();
+    fill(&mut store, 40, 2);
+    let snapshot = store.snapshot().unwrap();
+    store.set("key1".to_owned(), "changed".to_owned()).unwrap();
+    assert_eq!(snapshot.get("key1").unwrap(), Some(value(3)));
+
+    let scanned: Vec<(String, String)> = store.iter().unwrap().map(Result::unwrap).collect();
+    assert_eq!(scanned.len(), 32);
+    assert!(scanned.contains(&("key1".to_owned(), "changed".to_owned())));
+
+    let dest = temp_dir.path().join("checkpoint");
+    snapshot.checkpoint(&dest).unwrap();
+    let checkpoint = testing::open(&dest).unwrap();
+    assert_filled(&checkpoint, 40, 2);
+}
+
+#[test]
+fn clear_leaves_one_segment() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = KvStore::open_with_options(temp_dir.path(), segmented(256)).unwrap();
+    fill(&mut store, 40, 2);
+    store.clear().unwrap();
+    assert_eq!(logs(temp_dir.path()).len(), 1);
+    assert_eq!(store.get("key1").unwrap(), None);
+    store.set("after".to_owned(), "clear".to_owned()).unwrap();
+    drop(store);
+
+    let store = KvStore::open_with_options(temp_dir.path(), segmented(256)).unwrap();
+    assert_eq!(store.get("key1").unwrap(), None);
+    assert_eq!(store.get("after").unwrap(), Some("clear".to_owned()));
+}
+
+#[test]
+fn a_named_store_keeps_its_segments_apart() {
+    let temp_dir = TempDir::new().unwrap();
+    let options = segmented(256).store_name("cache");
+    let mut named = KvStore::open_with_options(temp_dir.path(), options).unwrap();
+    let mut default = KvStore::open_with_options(temp_dir.path(), segmented(256)).unwrap();
+    for i in 0..20 {
+        named.set(format!("key{}", i), "named".to_owned()).unwrap();
+        default
+            .set(format!("key{}", i), "default".to_owned())
+            .unwrap();
+    }
+    let names = logs(temp_dir.path());
+    assert!(
+        names.contains(&"cache.000002.log".to_owned()),
+        "{:?}",
+        names
+    );
+    assert!(names.contains(&"000002.log".to_owned()), "{:?}", names);
+    assert_eq!(
+        KvStore::list_stores(temp_dir.path()).unwrap(),
+        ["cache", "db"]
+    );
+    drop(named);
+    drop(default);
+
+    let named = KvStore::open_named(temp_dir.path(), "cache").unwrap();
+    assert_eq!(named.get("key19").unwrap(), Some("named".to_owned()));
+    let default = KvStore::open(temp_dir.path()).unwrap();
+    assert_eq!(default.get("key19").unwrap(), Some("default".to_owned()));
+    assert!(matches!(
+        KvStore::open_named(temp_dir.path(), "000001"),
+        Err(KvError::InvalidStoreName(_))
+    ));
+}
+
+#[test]
+fn compaction_seals_records_from_before_checksums() {
+    let temp_dir = TempDir::new().unwrap();
+    fs::write(
+        temp_dir.path().join("db.log"),
+        "{\"Set\":{\"key\":\"old\",\"value\":\"format\",\"seq\":1}}\n\
+         {\"Set\":{\"key\":\"gone\",\"value\":\"soon\",\"seq\":2}}\n\
+         {\"Rm\":{\"key\":\"gone\",\"seq\":3}}\n",
+    )
+    .unwrap();
+    let options = segmented(256).compact_on_open(false);
+    let mut store = KvStore::open_with_options(temp_dir.path(), options).unwrap();
+    for i in 0..20 {
+        store.set(format!("key{}", i), value(i)).unwrap();
+    }
+    store.compact().unwrap();
+    // Segment 1 may have been merged into those after it.
+    let first = fs::read(temp_dir.path().join(&logs(temp_dir.path())[0])).unwrap();
+    assert!(first.starts_with(b"\0kvs"));
+    let records = String::from_utf8(first[8..].to_vec()).unwrap();
+    assert!(records.contains("\"old\""), "{}", records);
+    for line in records.lines() {
+        assert!(line.contains(",\"crc\":"), "{}", line);
+    }
+    drop(store);
+
+    let store = KvStore::open_with_options(temp_dir.path(), segmented(256)).unwrap();
+    assert_eq!(store.get("old").unwrap(), Some("format".to_owned()));
+    assert_eq!(store.get("gone").unwrap(), None);
+}
+
+#[test]
+fn segments_of_a_bincode_log() {
+    let temp_dir = TempDir::new().unwrap();
+    let options = || segmented(256).format(LogFormat::Bincode);
+    let mut store = KvStore::open_with_options(temp_dir.path(), options()).unwrap();
+    fill(&mut store, 40, 3);
+    store.compact().unwrap();
+    assert_filled(&store, 40, 3);
+    drop(store);
+
+    assert!(logs(temp_dir.path()).len() > 1);
+    let store = KvStore::open_with_options(temp_dir.path(), options()).unwrap();
+    assert_filled(&store, 40, 3);
+}
+
+#[test]
+fn cli_get_reads_a_segmented_store_another_process_has_open() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = KvStore::open_with_options(temp_dir.path(), segmented(256)).unwrap();
+    fill(&mut store, 20, 2);
+    assert!(!temp_dir.path().join("db.log").exists());
+
+    // The writer still holds the store, so only a read-only open succeeds.
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key3", "--dir"])
+        .arg(temp_dir.path())
+        .assert()
+        .success()
+        .stdout(format!("{}\n", value(3 * 2 + 1)));
+}
+
+#[test]
+fn touches_fold_into_their_sets_when_segments_are_rewritten() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = KvStore::open_with_options(temp_dir.path(), segmented(4096)).unwrap();
+    let short = Duration::from_millis(100);
+    store
+        .set_with_ttl("touched".to_owned(), "kept".to_owned(), short)
+        .unwrap();
+    for _ in 0..50 {
+        store.touch("touched", Duration::from_secs(3_600)).unwrap();
+    }
+    // Enough to seal the segment holding the set and its touches.
+    for i in 0..100 {
+        store.set(format!("key{}", i), value(i)).unwrap();
+    }
+    assert!(logs(temp_dir.path()).len() > 1);
+    store.compact().unwrap();
+    for name in logs(temp_dir.path()) {
+        let log = fs::read(temp_dir.path().join(&name)).unwrap();
+        assert!(!String::from_utf8_lossy(&log).contains("Touch"), "{}", name);
+    }
+    drop(store);
+
+    thread::sleep(short * 2);
+    let store = KvStore::open_with_options(temp_dir.path(), segmented(4096)).unwrap();
+    assert_eq!(store.get("touched").unwrap(), Some("kept".to_owned()));
+    assert!(store.ttl("touched").unwrap().unwrap() > Duration::from_secs(60));
+}
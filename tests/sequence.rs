@@ -0,0 +1,134 @@
+use kvs::testing;
+use kvs::{KvStore, MergeOperator};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+use tempfile::TempDir;
+
+struct Concat;
+
+impl MergeOperator for Concat {
+    fn merge(&self, _key: &str, existing: Option<&str>, operand: &str) -> String {
+        format!("{}{}", existing.unwrap_or(""), operand)
+    }
+}
+
+fn open(dir: &Path) -> KvStore {
+    let options = testing::options().merge_operator(Box::new(Concat));
+    KvStore::open_with_options(dir, options).unwrap()
+}
+
+/// The `seq` of the last record of each key still set, read straight from the log.
+fn logged_sequences(dir: &Path) -> BTreeMap<String, u64> {
+    let mut sequences = BTreeMap::new();
+    let log = testing::log_records(dir);
+    for line in std::str::from_utf8(&log).unwrap().lines() {
+        let record: Value = serde_json::from_str(line).unwrap();
+        let (kind, fields) = record.as_object().unwrap().iter().next().unwrap();
+        if kind == "Group" {
+            continue;
+        }
+        let seq = fields["seq"].as_u64().unwrap();
+        if kind == "Sequence" {
+            continue;
+        }
+        let key = fields["key"].as_str().unwrap().to_owned();
+        match kind.as_str() {
+            "Rm" => sequences.remove(&key),
+            _ => sequences.insert(key, seq),
+        };
+    }
+    sequences
+}
+
+#[test]
+fn every_write_takes_the_next_number() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = open(temp_dir.path());
+    assert_eq!(store.last_sequence(), 0);
+    store.set("a".to_owned(), "1".to_owned()).unwrap();
+    assert_eq!(store.last_sequence(), 1);
+    store.set_bytes("b".to_owned(), &[0xff, 0x00]).unwrap();
+    store
+        .set_from_reader("c".to_owned(), &b"streamed"[..], 8)
+        .unwrap();
+    store.merge("c".to_owned(), "!".to_owned()).unwrap();
+    store.remove("a".to_owned()).unwrap();
+    assert_eq!(store.last_sequence(), 5);
+    store
+        .set_batch(vec![
+            ("d".to_owned(), "4".to_owned()),
+            ("e".to_owned(), "5".to_owned()),
+        ])
+        .unwrap();
+    assert_eq!(store.last_sequence(), 7);
+
+    // Reads and writes that write nothing leave the number where it is.
+    assert!(store.remove("missing".to_owned()).is_err());
+    assert!(!store.set_if_absent("d".to_owned(), "x".to_owned()).unwrap());
+    assert_eq!(store.get("c").unwrap(), Some("streamed!".to_owned()));
+    assert_eq!(store.last_sequence(), 7);
+    assert_eq!(
+        logged_sequences(temp_dir.path()),
+        BTreeMap::from([
+            ("b".to_owned(), 2),
+            ("c".to_owned(), 4),
+            ("d".to_owned(), 6),
+            ("e".to_owned(), 7),
+        ])
+    );
+}
+
+#[test]
+fn numbers_carry_on_across_reopen_and_compaction() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = open(temp_dir.path());
+    for i in 0..20 {
+        store.set(format!("key{}", i % 5), i.to_string()).unwrap();
+    }
+    store.merge("key1".to_owned(), "+".to_owned()).unwrap();
+    let before = logged_sequences(temp_dir.path());
+    store.compact().unwrap();
+    assert_eq!(logged_sequences(temp_dir.path()), before);
+    assert_eq!(store.last_sequence(), 21);
+
+    // The newest write is a remove, which compaction leaves no record of.
+    store.remove("key3".to_owned()).unwrap();
+    assert_eq!(store.last_sequence(), 22);
+    store.compact().unwrap();
+    let mut expected = before.clone();
+    expected.remove("key3");
+    assert_eq!(logged_sequences(temp_dir.path()), expected);
+    drop(store);
+
+    // Opening compacts again, and the number is still where it was.
+    let mut store = open(temp_dir.path());
+    assert_eq!(store.last_sequence(), 22);
+    store.set("key3".to_owned(), "back".to_owned()).unwrap();
+    assert_eq!(store.last_sequence(), 23);
+    drop(store);
+
+    let options = testing::options().compact_on_open(false);
+    let mut store = KvStore::open_with_options(temp_dir.path(), options).unwrap();
+    assert_eq!(store.last_sequence(), 23);
+    store.remove("key0".to_owned()).unwrap();
+    drop(store);
+    let store = open(temp_dir.path());
+    assert_eq!(store.last_sequence(), 24);
+    assert_eq!(store.get("key1").unwrap(), Some("16+".to_owned()));
+}
+
+#[test]
+fn logs_without_numbers_start_from_one() {
+    let temp_dir = TempDir::new().unwrap();
+    fs::write(
+        temp_dir.path().join("db.log"),
+        "{\"Set\":{\"key\":\"old\",\"value\":\"1\"}}\n{\"Rm\":{\"key\":\"old\"}}\n",
+    )
+    .unwrap();
+    let mut store = testing::open(temp_dir.path()).unwrap();
+    assert_eq!(store.last_sequence(), 0);
+    store.set("new".to_owned(), "2".to_owned()).unwrap();
+    assert_eq!(store.last_sequence(), 1);
+}
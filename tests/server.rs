@@ -0,0 +1,35 @@
+use assert_cmd::prelude::*;
+use predicates::str::contains;
+use std::fs;
+use std::process::Command;
+use tempfile::TempDir;
+
+fn server_with_data_dir(dir: &std::path::Path) -> Command {
+    let mut cmd = Command::cargo_bin("kvs-server").unwrap();
+    cmd.args(["--addr", "127.0.0.1:0", "--engine", "kvs", "--data-dir"])
+        .arg(dir);
+    cmd
+}
+
+#[test]
+fn server_data_dir_rejects_file() {
+    let temp_dir = TempDir::new().unwrap();
+    let log = temp_dir.path().join("db.log");
+    fs::write(&log, "").unwrap();
+
+    server_with_data_dir(&log)
+        .assert()
+        .failure()
+        .stderr(contains("is a file, not a directory"));
+}
+
+#[test]
+fn server_data_dir_reports_log_directory() {
+    let temp_dir = TempDir::new().unwrap();
+    fs::create_dir(temp_dir.path().join("db.log")).unwrap();
+
+    server_with_data_dir(temp_dir.path())
+        .assert()
+        .failure()
+        .stderr(contains("is a directory, not a log file"));
+}
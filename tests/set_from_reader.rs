@@ -0,0 +1,177 @@
+use kvs::protocol::Checksum;
+use kvs::testing;
+use kvs::{KvError, KvStore, StoreEvent};
+use std::fs::{self, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use tempfile::TempDir;
+
+const LARGE: u64 = 100 * 1024 * 1024;
+
+/// `len` generated bytes, mostly text with the odd quote, backslash and newline, failing
+/// once `fail_at` of them have been read.
+struct Synthetic {
+    len: u64,
+    position: u64,
+    fail_at: Option<u64>,
+}
+
+impl Synthetic {
+    fn new(len: u64) -> Synthetic {
+        Synthetic {
+            len,
+            position: 0,
+            fail_at: None,
+        }
+    }
+
+    fn byte_at(position: u64) -> u8 {
+        match position % 997 {
+            0 => b'"',
+            1 => b'\\',
+            2 => b'\n',
+            n => b'a' + (n % 26) as u8,
+        }
+    }
+}
+
+impl Read for Synthetic {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.fail_at.is_some_and(|fail_at| self.position >= fail_at) {
+            return Err(io::Error::other("the source went away"));
+        }
+        let end = self.fail_at.unwrap_or(self.len).min(self.len);
+        let n = (end - self.position).min(buf.len() as u64) as usize;
+        for byte in &mut buf[..n] {
+            *byte = Synthetic::byte_at(self.position);
+            self.position += 1;
+        }
+        Ok(n)
+    }
+}
+
+fn checksum(reader: impl Read) -> u64 {
+    let mut checksum = Checksum::new();
+    let mut reader = io::BufReader::with_capacity(1 << 20, reader);
+    loop {
+        let buf = io::BufRead::fill_buf(&mut reader).unwrap();
+        if buf.is_empty() {
+            return checksum.value();
+        }
+        checksum.update(buf);
+        let n = buf.len();
+        io::BufRead::consume(&mut reader, n);
+    }
+}
+
+fn log_len(dir: &Path) -> u64 {
+    fs::metadata(dir.join("db.log")).unwrap().len()
+}
+
+#[test]
+fn large_value_is_copied_from_a_reader() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = testing::open(temp_dir.path()).unwrap();
+    store
+        .set_from_reader("large".to_owned(), Synthetic::new(LARGE), LARGE)
+        .unwrap();
+    store.set("after".to_owned(), "small".to_owned()).unwrap();
+    let expected = checksum(Synthetic::new(LARGE));
+
+    let reader = store.get_reader("large").unwrap().unwrap();
+    assert_eq!(checksum(reader), expected);
+    drop(store);
+
+    let store = testing::open(temp_dir.path()).unwrap();
+    let reader = store.get_reader("large").unwrap().unwrap();
+    assert_eq!(checksum(reader), expected);
+    assert_eq!(store.get("after").unwrap(), Some("small".to_owned()));
+}
+
+#[test]
+fn failing_reader_leaves_nothing_behind() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = testing::open(temp_dir.path()).unwrap();
+    store.set("kept".to_owned(), "value".to_owned()).unwrap();
+    let good_len = log_len(temp_dir.path());
+
+    let failing = Synthetic {
+        fail_at: Some(200_000),
+        ..Synthetic::new(1_000_000)
+    };
+    match store.set_from_reader("doomed".to_owned(), failing, 1_000_000) {
+        Err(KvError::ValueSourceFailed { read, len, .. }) => {
+            assert_eq!((read, len), (200_000, 1_000_000))
+        }
+        other => panic!("expected ValueSourceFailed, got {:?}", other),
+    }
+    match store.set_from_reader("short".to_owned(), Synthetic::new(10), 11) {
+        Err(err @ KvError::ValueSourceFailed { .. }) => {
+            assert!(err.to_string().contains("ended early"), "{}", err)
+        }
+        other => panic!("expected ValueSourceFailed, got {:?}", other),
+    }
+    assert!(!store.is_append_poisoned());
+    assert_eq!(log_len(temp_dir.path()), good_len);
+    assert_eq!(store.get("doomed").unwrap(), None);
+
+    store.set("later".to_owned(), "value".to_owned()).unwrap();
+    drop(store);
+    let store = testing::open(temp_dir.path()).unwrap();
+    assert_eq!(store.get("doomed").unwrap(), None);
+    assert_eq!(store.get("kept").unwrap(), Some("value".to_owned()));
+    assert_eq!(store.get("later").unwrap(), Some("value".to_owned()));
+}
+
+#[test]
+fn partial_record_left_by_a_crash_is_dropped_on_open() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = testing::open(temp_dir.path()).unwrap();
+    store.set("kept".to_owned(), "value".to_owned()).unwrap();
+    drop(store);
+    let good_len = log_len(temp_dir.path());
+    OpenOptions::new()
+        .append(true)
+        .open(temp_dir.path().join("db.log"))
+        .unwrap()
+        .write_all(b"{\"Set\":{\"key\":\"torn\",\"value\":\"abc")
+        .unwrap();
+
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let sink = Arc::clone(&events);
+    let options = testing::options()
+        .compact_on_open(false)
+        .event_sink(Box::new(move |event| sink.lock().unwrap().push(event)));
+    let mut store = KvStore::open_with_options(temp_dir.path(), options).unwrap();
+    assert_eq!(log_len(temp_dir.path()), good_len);
+    assert!(events.lock().unwrap().iter().any(|event| matches!(
+        event,
+        StoreEvent::RecoveredPartialAppend { truncated_to, .. } if *truncated_to == good_len
+    )));
+    assert_eq!(store.get("torn").unwrap(), None);
+    store.set("next".to_owned(), "value".to_owned()).unwrap();
+    drop(store);
+
+    let store = testing::open(temp_dir.path()).unwrap();
+    assert_eq!(store.get("kept").unwrap(), Some("value".to_owned()));
+    assert_eq!(store.get("next").unwrap(), Some("value".to_owned()));
+}
+
+#[test]
+fn whole_last_record_without_its_newline_is_kept() {
+    let temp_dir = TempDir::new().unwrap();
+    fs::write(
+        temp_dir.path().join("db.log"),
+        "{\"Set\":{\"key\":\"a\",\"value\":\"1\"}}",
+    )
+    .unwrap();
+    let options = testing::options().compact_on_open(false);
+    let mut store = KvStore::open_with_options(temp_dir.path(), options).unwrap();
+    store.set("b".to_owned(), "2".to_owned()).unwrap();
+    drop(store);
+
+    let store = testing::open(temp_dir.path()).unwrap();
+    assert_eq!(store.get("a").unwrap(), Some("1".to_owned()));
+    assert_eq!(store.get("b").unwrap(), Some("2".to_owned()));
+}
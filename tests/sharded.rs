@@ -0,0 +1,210 @@
+use assert_cmd::prelude::*;
+use kvs::sharded_client::DEFAULT_REPLICAS_PER_NODE;
+use kvs::testing;
+use kvs::{KvError, KvsClient, KvsServer, ShardedKvsClient};
+use predicates::str::contains;
+use std::net::{SocketAddr, TcpListener};
+use std::process::Command;
+use std::thread;
+use tempfile::TempDir;
+
+const REPLICAS: u32 = 64;
+
+fn start_server(dir: &TempDir) -> SocketAddr {
+    let log = slog::Logger::root(slog::Discard, slog::o!());
+    let store = testing::open(dir.path()).unwrap();
+    let server = KvsServer::new("127.0.0.1:0", store, log).unwrap();
+    let addr = server.local_addr().unwrap();
+    thread::spawn(move || server.listen_forever());
+    addr
+}
+
+/// An address nothing listens on.
+fn dead_node() -> SocketAddr {
+    TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+}
+
+/// Addresses that need nothing listening on them, so the ring is the same every run.
+fn fixed_nodes() -> Vec<SocketAddr> {
+    ["10.0.0.1:4000", "10.0.0.2:4000", "10.0.0.3:4000"]
+        .iter()
+        .map(|addr| addr.parse().unwrap())
+        .collect()
+}
+
+#[test]
+fn keys_spread_evenly_over_the_nodes() {
+    let nodes = fixed_nodes();
+    let sharded = ShardedKvsClient::new(nodes.clone(), REPLICAS).unwrap();
+    let mut counts = vec![0; nodes.len()];
+    for i in 0..10_000 {
+        let owner = sharded.owner_of(&format!("key{}", i));
+        counts[nodes.iter().position(|node| *node == owner).unwrap()] += 1;
+    }
+    for count in counts {
+        assert!(count > 2_500 && count < 4_200, "unbalanced: {}", count);
+    }
+}
+
+#[test]
+fn keys_live_only_on_their_owner() {
+    let dirs: Vec<TempDir> = (0..3).map(|_| TempDir::new().unwrap()).collect();
+    let nodes: Vec<SocketAddr> = dirs.iter().map(start_server).collect();
+    let mut sharded = ShardedKvsClient::new(nodes.clone(), REPLICAS).unwrap();
+
+    let keys: Vec<String> = (0..10_000).map(|i| format!("key{}", i)).collect();
+    for key in &keys {
+        sharded
+            .set(key.clone(), format!("value of {}", key))
+            .unwrap();
+    }
+
+    let mut direct: Vec<KvsClient> = nodes
+        .iter()
+        .map(|node| KvsClient::connect(node).unwrap())
+        .collect();
+    for key in &keys {
+        let owner = sharded.owner_of(key);
+        for (node, client) in nodes.iter().zip(&mut direct) {
+            let value = client.get(key.clone()).unwrap();
+            if *node == owner {
+                assert_eq!(value, Some(format!("value of {}", key)));
+            } else {
+                assert_eq!(value, None, "{} also stored on {}", key, node);
+            }
+        }
+    }
+
+    let values = sharded.multi_get(&keys).unwrap();
+    for (key, value) in keys.iter().zip(values) {
+        assert_eq!(value, Some(format!("value of {}", key)));
+    }
+}
+
+#[test]
+fn ring_does_not_depend_on_node_order() {
+    let nodes = fixed_nodes();
+    let mut reversed = nodes.clone();
+    reversed.reverse();
+
+    let first = ShardedKvsClient::new(nodes, REPLICAS).unwrap();
+    let second = ShardedKvsClient::new(reversed, REPLICAS).unwrap();
+    for i in 0..1_000 {
+        let key = format!("key{}", i);
+        assert_eq!(first.owner_of(&key), second.owner_of(&key));
+    }
+}
+
+#[test]
+fn ring_without_points_is_refused() {
+    assert!(matches!(
+        ShardedKvsClient::new(Vec::new(), REPLICAS),
+        Err(KvError::NoShardNodes)
+    ));
+    assert!(matches!(
+        ShardedKvsClient::new(fixed_nodes(), 0),
+        Err(KvError::NoShardNodes)
+    ));
+}
+
+#[test]
+fn down_node_is_reported_not_rerouted() {
+    let dir = TempDir::new().unwrap();
+    let live = start_server(&dir);
+    let dead = dead_node();
+    let mut sharded = ShardedKvsClient::new(vec![live, dead], REPLICAS).unwrap();
+
+    let keys: Vec<String> = (0..100).map(|i| format!("key{}", i)).collect();
+    let dead_key = keys
+        .iter()
+        .find(|key| sharded.owner_of(key) == dead)
+        .unwrap();
+    let live_key = keys
+        .iter()
+        .find(|key| sharded.owner_of(key) == live)
+        .unwrap();
+
+    match sharded.set(dead_key.clone(), "value".to_owned()) {
+        Err(KvError::ShardUnavailable { node }) => assert_eq!(node, dead),
+        other => panic!("expected ShardUnavailable, got {:?}", other),
+    }
+    sharded.set(live_key.clone(), "value".to_owned()).unwrap();
+    assert_eq!(
+        sharded.get(live_key.clone()).unwrap(),
+        Some("value".to_owned())
+    );
+    assert!(matches!(
+        sharded.multi_get(&[live_key.clone(), dead_key.clone()]),
+        Err(KvError::ShardUnavailable { node }) if node == dead
+    ));
+
+    let mut direct = KvsClient::connect(live).unwrap();
+    assert_eq!(direct.get(dead_key.clone()).unwrap(), None);
+}
+
+#[test]
+fn remove_reports_missing_keys_by_name() {
+    let dirs: Vec<TempDir> = (0..2).map(|_| TempDir::new().unwrap()).collect();
+    let nodes: Vec<SocketAddr> = dirs.iter().map(start_server).collect();
+    let mut sharded = ShardedKvsClient::new(nodes, REPLICAS).unwrap();
+
+    sharded.set("key".to_owned(), "value".to_owned()).unwrap();
+    sharded.remove("key".to_owned()).unwrap();
+    match sharded.remove("key".to_owned()) {
+        Err(KvError::RemoveError(key)) => assert_eq!(key, "key"),
+        other => panic!("expected RemoveError, got {:?}", other),
+    }
+}
+
+#[test]
+fn cli_nodes_mode() {
+    let dirs: Vec<TempDir> = (0..3).map(|_| TempDir::new().unwrap()).collect();
+    let nodes: Vec<SocketAddr> = dirs.iter().map(start_server).collect();
+    let node_list = nodes
+        .iter()
+        .map(SocketAddr::to_string)
+        .collect::<Vec<_>>()
+        .join(",");
+
+    for i in 0..10 {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(["--nodes", &node_list, "set"])
+            .arg(format!("key{}", i))
+            .arg(format!("value{}", i))
+            .assert()
+            .success();
+    }
+    // The CLI builds its ring with the default replica count.
+    let sharded = ShardedKvsClient::new(nodes, DEFAULT_REPLICAS_PER_NODE).unwrap();
+    for i in 0..10 {
+        let key = format!("key{}", i);
+        let mut owner = KvsClient::connect(sharded.owner_of(&key)).unwrap();
+        assert_eq!(owner.get(key).unwrap(), Some(format!("value{}", i)));
+
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(["--nodes", &node_list, "get"])
+            .arg(format!("key{}", i))
+            .assert()
+            .success()
+            .stdout(format!("value{}\n", i));
+    }
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args([
+            "--nodes",
+            &node_list,
+            "--addr",
+            "127.0.0.1:4000",
+            "get",
+            "key",
+        ])
+        .assert()
+        .failure()
+        .stderr(contains("cannot be used with"));
+}
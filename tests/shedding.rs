@@ -0,0 +1,138 @@
+use assert_cmd::prelude::*;
+use kvs::kvs_client::OVERLOAD_BACKOFF;
+use kvs::shedding::{LoadShedding, Watermarks};
+use kvs::testing;
+use kvs::{KvError, KvsClient, KvsServer};
+use predicates::str::contains;
+use std::net::SocketAddr;
+use std::process::Command;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+fn start_server(dir: &TempDir, shedding: LoadShedding) -> SocketAddr {
+    let log = slog::Logger::root(slog::Discard, slog::o!());
+    let server = KvsServer::new("127.0.0.1:0", testing::open(dir.path()).unwrap(), log)
+        .unwrap()
+        .load_shedding(shedding)
+        .request_delay(Duration::from_millis(10));
+    let addr = server.local_addr().unwrap();
+    thread::spawn(move || server.listen_forever());
+    addr
+}
+
+/// Writers that keep the queue full until `stop`, counting the writes shed.
+fn flood(addr: SocketAddr, writers: usize, stop: &Arc<AtomicBool>) -> Vec<thread::JoinHandle<u64>> {
+    (0..writers)
+        .map(|writer| {
+            let stop = Arc::clone(stop);
+            thread::spawn(move || {
+                let mut client = KvsClient::connect(addr).unwrap();
+                let mut shed = 0;
+                while !stop.load(Ordering::SeqCst) {
+                    match client.set(format!("key{}", writer), "value".to_owned()) {
+                        Ok(()) => {}
+                        Err(KvError::ServerOverloaded { retry_after }) => {
+                            assert_eq!(retry_after, OVERLOAD_BACKOFF);
+                            shed += 1;
+                            thread::sleep(Duration::from_millis(2));
+                        }
+                        Err(e) => panic!("unexpected error: {}", e),
+                    }
+                }
+                shed
+            })
+        })
+        .collect()
+}
+
+#[test]
+fn writes_shed_under_a_flood_and_recover_after() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = start_server(
+        &temp_dir,
+        LoadShedding {
+            reads: None,
+            writes: Some(Watermarks::new(4, 1)),
+        },
+    );
+    let stop = Arc::new(AtomicBool::new(false));
+    let writers = flood(addr, 12, &stop);
+    thread::sleep(Duration::from_millis(300));
+
+    // Admin requests and reads still get through, if only after the queue ahead of them.
+    let mut admin = KvsClient::connect(addr).unwrap();
+    let health = admin.ping().unwrap();
+    assert!(health.shed_writes > 0);
+    assert_eq!(health.shed_reads, 0);
+    assert!(health.queue_depth >= 1);
+    assert_eq!(admin.get("missing".to_owned()).unwrap(), None);
+
+    stop.store(true, Ordering::SeqCst);
+    let shed: u64 = writers.into_iter().map(|w| w.join().unwrap()).sum();
+    assert!(shed > 0);
+
+    // Below the low watermark the next write is served again.
+    admin.set("after".to_owned(), "flood".to_owned()).unwrap();
+    assert_eq!(
+        admin.get("after".to_owned()).unwrap(),
+        Some("flood".to_owned())
+    );
+    let health = admin.ping().unwrap();
+    assert_eq!(health.shed_writes, shed);
+    assert_eq!(health.queue_depth, 1);
+}
+
+#[test]
+fn reads_shed_on_their_own_watermarks() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = start_server(
+        &temp_dir,
+        LoadShedding {
+            reads: Some(Watermarks::new(3, 0)),
+            writes: None,
+        },
+    );
+    let stop = Arc::new(AtomicBool::new(false));
+    let writers = flood(addr, 6, &stop);
+    thread::sleep(Duration::from_millis(200));
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    for _ in 0..5 {
+        match client.get("key0".to_owned()) {
+            Err(KvError::ServerOverloaded { .. }) => {}
+            other => panic!("read was not shed: {:?}", other),
+        }
+    }
+    stop.store(true, Ordering::SeqCst);
+    // Writes have no watermarks, so none of them was shed.
+    let shed: u64 = writers.into_iter().map(|w| w.join().unwrap()).sum();
+    assert_eq!(shed, 0);
+
+    assert_eq!(
+        client.get("key0".to_owned()).unwrap(),
+        Some("value".to_owned())
+    );
+    assert_eq!(client.ping().unwrap().shed_reads, 5);
+}
+
+#[test]
+fn server_rejects_inverted_watermarks() {
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args([
+            "--addr",
+            "127.0.0.1:0",
+            "--engine",
+            "kvs",
+            "--write-high-watermark",
+            "4",
+            "--write-low-watermark",
+            "4",
+        ])
+        .assert()
+        .failure()
+        .stderr(contains("Invalid write watermarks"));
+}
@@ -0,0 +1,102 @@
+use kvs::testing::{self, ShortWrite};
+use kvs::{KvError, KvsClient, KvsServer};
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::thread;
+use tempfile::TempDir;
+
+fn log_len(dir: &Path) -> u64 {
+    fs::metadata(dir.join("db.log")).unwrap().len()
+}
+
+fn short_write(after_bytes: usize) -> ShortWrite {
+    ShortWrite {
+        after_bytes,
+        error: io::ErrorKind::StorageFull,
+    }
+}
+
+#[test]
+fn partial_append_poisons_until_recovered() {
+    // `{"Set":{"key":"doomed","value":"value"}}` plus its newline is 41 bytes; cut it after the
+    // first byte, in the middle and just before the newline.
+    for after_bytes in [1, 20, 40] {
+        let temp_dir = TempDir::new().unwrap();
+        let mut store = testing::open(temp_dir.path()).unwrap();
+        store.set("kept".to_owned(), "value".to_owned()).unwrap();
+        let good_len = log_len(temp_dir.path());
+
+        store.inject_short_write(short_write(after_bytes));
+        assert!(matches!(
+            store.set("doomed".to_owned(), "value".to_owned()),
+            Err(KvError::WriteError)
+        ));
+        assert!(store.is_append_poisoned());
+        assert_eq!(log_len(temp_dir.path()), good_len + after_bytes as u64);
+
+        match store.set("later".to_owned(), "value".to_owned()) {
+            Err(KvError::LogPoisoned { offset, .. }) => assert_eq!(offset, good_len),
+            other => panic!("expected LogPoisoned, got {:?}", other),
+        }
+        assert!(matches!(
+            store.remove("kept".to_owned()),
+            Err(KvError::LogPoisoned { .. })
+        ));
+        assert_eq!(store.get("kept").unwrap(), Some("value".to_owned()));
+        assert_eq!(store.get("doomed").unwrap(), None);
+
+        store.recover_append().unwrap();
+        assert!(!store.is_append_poisoned());
+        assert_eq!(log_len(temp_dir.path()), good_len);
+
+        store.set("later".to_owned(), "value".to_owned()).unwrap();
+        store.remove("kept".to_owned()).unwrap();
+        drop(store);
+
+        let store = testing::open(temp_dir.path()).unwrap();
+        assert_eq!(store.get("kept").unwrap(), None);
+        assert_eq!(store.get("doomed").unwrap(), None);
+        assert_eq!(store.get("later").unwrap(), Some("value".to_owned()));
+    }
+}
+
+#[test]
+fn failure_before_any_byte_does_not_poison() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = testing::open(temp_dir.path()).unwrap();
+    store.set("kept".to_owned(), "value".to_owned()).unwrap();
+    let good_len = log_len(temp_dir.path());
+
+    store.inject_short_write(short_write(0));
+    assert!(matches!(
+        store.remove("kept".to_owned()),
+        Err(KvError::WriteError)
+    ));
+    assert!(!store.is_append_poisoned());
+    assert_eq!(log_len(temp_dir.path()), good_len);
+    assert_eq!(store.get("kept").unwrap(), Some("value".to_owned()));
+
+    store.set("next".to_owned(), "value".to_owned()).unwrap();
+}
+
+#[test]
+fn server_recovers_partial_append_automatically() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = testing::open(temp_dir.path()).unwrap();
+    store.inject_short_write(short_write(10));
+
+    let log = slog::Logger::root(slog::Discard, slog::o!());
+    let server = KvsServer::new("127.0.0.1:0", store, log).unwrap();
+    let addr = server.local_addr().unwrap();
+    thread::spawn(move || server.listen_forever());
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    assert!(client.set("doomed".to_owned(), "value".to_owned()).is_err());
+    client.set("key".to_owned(), "value".to_owned()).unwrap();
+    assert_eq!(
+        client.get("key".to_owned()).unwrap(),
+        Some("value".to_owned())
+    );
+    assert_eq!(client.get("doomed".to_owned()).unwrap(), None);
+}
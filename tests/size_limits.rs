@@ -0,0 +1,136 @@
+use kvs::testing;
+use kvs::{KvError, KvStore, KvStoreOptions, KvsClient, KvsServer};
+use std::fs;
+use std::path::Path;
+use std::thread;
+use tempfile::TempDir;
+
+const MAX_KEY: u64 = 16;
+const MAX_VALUE: u64 = 1024;
+
+fn limited() -> KvStoreOptions {
+    testing::options()
+        .max_key_size(MAX_KEY)
+        .max_value_size(MAX_VALUE)
+}
+
+fn open(dir: &Path) -> KvStore {
+    KvStore::open_with_options(dir, limited()).unwrap()
+}
+
+fn key(len: u64) -> String {
+    "k".repeat(len as usize)
+}
+
+fn value(len: u64) -> String {
+    "v".repeat(len as usize)
+}
+
+#[test]
+fn sets_at_the_limit_succeed_and_one_byte_over_fails() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = open(temp_dir.path());
+
+    store.set(key(MAX_KEY), value(MAX_VALUE)).unwrap();
+    match store.set(key(MAX_KEY + 1), value(1)) {
+        Err(KvError::KeyTooLarge { size, limit, .. }) => {
+            assert_eq!((size, limit), (MAX_KEY + 1, MAX_KEY))
+        }
+        other => panic!("expected KeyTooLarge, got {:?}", other),
+    }
+    match store.set_bytes("big".to_owned(), &[0xff; MAX_VALUE as usize + 1]) {
+        Err(err @ KvError::ValueTooLarge { .. }) => {
+            assert_eq!(
+                err.to_string(),
+                "Error: the value for big is 1025 bytes, over the store's limit of 1024"
+            );
+        }
+        other => panic!("expected ValueTooLarge, got {:?}", other),
+    }
+    // A batch with one oversized value writes none of its pairs.
+    let batch = vec![
+        ("first".to_owned(), value(1)),
+        ("second".to_owned(), value(MAX_VALUE + 1)),
+    ];
+    assert!(matches!(
+        store.set_batch(batch),
+        Err(KvError::ValueTooLarge { .. })
+    ));
+    assert!(matches!(
+        store.compare_and_swap("first".to_owned(), None, Some(value(MAX_VALUE + 1))),
+        Err(KvError::ValueTooLarge { .. })
+    ));
+    assert_eq!(store.len(), 1);
+    drop(store);
+
+    let store = open(temp_dir.path());
+    assert_eq!(store.get(&key(MAX_KEY)).unwrap(), Some(value(MAX_VALUE)));
+    assert_eq!(store.get("first").unwrap(), None);
+    assert_eq!(store.get("big").unwrap(), None);
+}
+
+#[test]
+fn json_logs_measure_values_after_escaping() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = open(temp_dir.path());
+
+    let quotes = "\"".repeat(MAX_VALUE as usize / 2 + 1);
+    match store.set("quotes".to_owned(), quotes) {
+        Err(KvError::ValueTooLarge { size, limit, .. }) => {
+            assert_eq!((size, limit), (MAX_VALUE + 2, MAX_VALUE))
+        }
+        other => panic!("expected ValueTooLarge, got {:?}", other),
+    }
+    store
+        .set("quotes".to_owned(), "\"".repeat(MAX_VALUE as usize / 2))
+        .unwrap();
+    assert_eq!(store.get("quotes").unwrap().map(|v| v.len()), Some(512));
+}
+
+#[test]
+fn stores_are_unlimited_by_default() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = testing::open(temp_dir.path()).unwrap();
+    store.set(key(4096), value(1 << 20)).unwrap();
+    assert_eq!(store.get(&key(4096)).unwrap(), Some(value(1 << 20)));
+}
+
+#[test]
+fn server_refuses_oversized_sets_and_uploads() {
+    let temp_dir = TempDir::new().unwrap();
+    let log = slog::Logger::root(slog::Discard, slog::o!());
+    let server = KvsServer::new("127.0.0.1:0", open(temp_dir.path()), log).unwrap();
+    let addr = server.local_addr().unwrap();
+    thread::spawn(move || server.listen_forever());
+    let mut client = KvsClient::connect(addr).unwrap();
+
+    client.set(key(MAX_KEY), value(MAX_VALUE)).unwrap();
+    match client.set(key(MAX_KEY + 1), value(1)) {
+        Err(KvError::ServerError(message)) => assert!(message.contains("the key"), "{}", message),
+        other => panic!("expected a server error, got {:?}", other),
+    }
+
+    // The upload is refused from its announced length, so nothing of it is spooled.
+    let big = value(MAX_VALUE + 1);
+    match client.set_reader("big".to_owned(), big.as_bytes(), big.len() as u64) {
+        Err(KvError::ServerError(message)) => {
+            assert!(message.contains("1025 bytes"), "{}", message)
+        }
+        other => panic!("expected a server error, got {:?}", other),
+    }
+    let spools = fs::read_dir(temp_dir.path())
+        .unwrap()
+        .filter(|entry| {
+            let name = entry.as_ref().unwrap().file_name();
+            name.to_string_lossy().starts_with(".upload-")
+        })
+        .count();
+    assert_eq!(spools, 0);
+
+    let at_limit = value(MAX_VALUE);
+    client
+        .set_reader("exact".to_owned(), at_limit.as_bytes(), MAX_VALUE)
+        .unwrap();
+    assert_eq!(client.get("exact".to_owned()).unwrap(), Some(at_limit));
+    assert_eq!(client.get("big".to_owned()).unwrap(), None);
+}
@@ -0,0 +1,90 @@
+use kvs::testing;
+use kvs::{KvStore, MergeOperator};
+use std::thread;
+use tempfile::TempDir;
+
+struct Concat;
+
+impl MergeOperator for Concat {
+    fn merge(&self, _key: &str, existing: Option<&str>, operand: &str) -> String {
+        format!("{}{}", existing.unwrap_or(""), operand)
+    }
+}
+
+fn pairs(items: &[(&str, &str)]) -> Vec<(String, String)> {
+    items
+        .iter()
+        .map(|&(key, value)| (key.to_owned(), value.to_owned()))
+        .collect()
+}
+
+#[test]
+fn snapshot_ignores_later_writes_and_compaction() {
+    let temp_dir = TempDir::new().unwrap();
+    let options = testing::options().merge_operator(Box::new(Concat));
+    let mut store = KvStore::open_with_options(temp_dir.path(), options).unwrap();
+    store.set("a".to_owned(), "1".to_owned()).unwrap();
+    store.set("b".to_owned(), "2".to_owned()).unwrap();
+    store.merge("list".to_owned(), "x".to_owned()).unwrap();
+    let snapshot = store.snapshot().unwrap();
+    assert_eq!(snapshot.last_sequence(), 3);
+
+    store.set("a".to_owned(), "changed".to_owned()).unwrap();
+    store.remove("b".to_owned()).unwrap();
+    store.merge("list".to_owned(), "y".to_owned()).unwrap();
+    store.set_batch(pairs(&[("c", "3"), ("d", "4")])).unwrap();
+    let expected = pairs(&[("a", "1"), ("b", "2"), ("list", "x")]);
+    assert_eq!(snapshot.get("a").unwrap(), Some("1".to_owned()));
+    assert_eq!(snapshot.get("c").unwrap(), None);
+    assert_eq!(
+        snapshot.iter().collect::<kvs::Result<Vec<_>>>().unwrap(),
+        expected
+    );
+    assert_eq!(snapshot.len(), 3);
+
+    // The log the snapshot points into is replaced, and then emptied.
+    store.compact().unwrap();
+    store.clear().unwrap();
+    store.set("a".to_owned(), "after".to_owned()).unwrap();
+    assert_eq!(store.get("list").unwrap(), None);
+    assert_eq!(snapshot.get("list").unwrap(), Some("x".to_owned()));
+    assert_eq!(
+        snapshot.iter().collect::<kvs::Result<Vec<_>>>().unwrap(),
+        expected
+    );
+
+    let later = store.snapshot().unwrap();
+    assert_eq!(
+        later.iter().collect::<kvs::Result<Vec<_>>>().unwrap(),
+        pairs(&[("a", "after")])
+    );
+    assert!(snapshot.last_sequence() < later.last_sequence());
+}
+
+#[test]
+fn snapshot_is_read_from_another_thread_while_the_store_is_written() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = testing::open(temp_dir.path()).unwrap();
+    for i in 0..100 {
+        store
+            .set(format!("key{:03}", i), "before".to_owned())
+            .unwrap();
+    }
+    let snapshot = store.snapshot().unwrap();
+
+    let reader = thread::spawn(move || {
+        for _ in 0..20 {
+            let values: Vec<(String, String)> = snapshot.iter().map(Result::unwrap).collect();
+            assert_eq!(values.len(), 100);
+            assert!(values.iter().all(|(_, value)| value == "before"));
+        }
+    });
+    for round in 0..5 {
+        let batch = (0..100)
+            .map(|i| (format!("key{:03}", i), format!("round {}", round)))
+            .collect();
+        store.set_batch(batch).unwrap();
+        store.compact().unwrap();
+    }
+    reader.join().unwrap();
+}
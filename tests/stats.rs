@@ -0,0 +1,166 @@
+use kvs::testing;
+use kvs::{KvStore, KvStoreOptions, StatCounters};
+use std::fs;
+use std::path::Path;
+use tempfile::TempDir;
+
+fn open(dir: &Path) -> KvStore {
+    KvStore::open_with_options(dir, testing::options().persist_stats(true)).unwrap()
+}
+
+fn workload(store: &mut KvStore) {
+    for i in 0..10 {
+        store.set(format!("key{}", i), "value".to_owned()).unwrap();
+    }
+    store
+        .set("key0".to_owned(), "overwritten".to_owned())
+        .unwrap();
+    store.remove("key1".to_owned()).unwrap();
+    store.get("key0").unwrap();
+    store.get("missing").unwrap();
+    store.compact().unwrap();
+}
+
+#[test]
+fn lifetime_counters_survive_a_restart() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = open(temp_dir.path());
+    let created_at = store.stats().created_at;
+    assert!(created_at.is_some());
+    workload(&mut store);
+
+    let stats = store.stats();
+    // The compaction at open, then the explicit one.
+    let expected = StatCounters {
+        sets: 11,
+        removes: 1,
+        gets: 2,
+        compactions: 2,
+        bytes_reclaimed: stats.since_open.bytes_reclaimed,
+        bytes_rewritten: stats.since_open.bytes_rewritten,
+        transient_retries: 0,
+    };
+    assert!(expected.bytes_reclaimed > 0);
+    assert!(expected.bytes_rewritten > 0);
+    assert_eq!(stats.since_open, expected);
+    assert_eq!(stats.lifetime, expected);
+    assert!(stats.last_compaction_at.is_some());
+    drop(store);
+
+    let mut store = open(temp_dir.path());
+    let stats = store.stats();
+    assert_eq!(stats.created_at, created_at);
+    assert_eq!(stats.since_open.sets, 0);
+    assert_eq!(stats.since_open.compactions, 1);
+    assert_eq!(stats.lifetime.sets, 11);
+    assert_eq!(stats.lifetime.compactions, 3);
+
+    store.set("after".to_owned(), "restart".to_owned()).unwrap();
+    store.reopen().unwrap();
+    assert_eq!(store.stats().lifetime.sets, 12);
+    assert_eq!(store.stats().since_open.sets, 0);
+}
+
+#[test]
+fn corrupt_snapshot_restarts_lifetime_counters() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = open(temp_dir.path());
+    workload(&mut store);
+    drop(store);
+
+    fs::write(temp_dir.path().join("stats.json"), "{\"counters\":").unwrap();
+    let store = open(temp_dir.path());
+    assert_eq!(store.get("key0").unwrap(), Some("overwritten".to_owned()));
+    let stats = store.stats();
+    assert_eq!(stats.lifetime, stats.since_open);
+    assert_eq!(stats.created_at, None);
+    drop(store);
+
+    // The next save replaced the corrupt snapshot.
+    let store = open(temp_dir.path());
+    assert_eq!(store.stats().lifetime.gets, 1);
+}
+
+#[test]
+fn snapshot_missing_newer_fields_reads_them_as_zero() {
+    let temp_dir = TempDir::new().unwrap();
+    fs::write(
+        temp_dir.path().join("stats.json"),
+        "{\"counters\":{\"sets\":40,\"removes\":2}}",
+    )
+    .unwrap();
+
+    let store = open(temp_dir.path());
+    let stats = store.stats();
+    assert_eq!(stats.lifetime.sets, 40);
+    assert_eq!(stats.lifetime.removes, 2);
+    assert_eq!(stats.lifetime.gets, 0);
+    assert_eq!(stats.created_at, None);
+}
+
+#[test]
+fn stats_are_not_persisted_by_default() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = KvStore::open_with_options(temp_dir.path(), KvStoreOptions::new()).unwrap();
+    store.set("key".to_owned(), "value".to_owned()).unwrap();
+    drop(store);
+
+    assert!(!temp_dir.path().join("stats.json").exists());
+    let store = testing::open(temp_dir.path()).unwrap();
+    assert_eq!(store.stats().lifetime, store.stats().since_open);
+    assert_eq!(store.stats().lifetime.sets, 0);
+}
+
+#[test]
+fn dead_bytes_are_what_compaction_reclaims() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = testing::open(temp_dir.path()).unwrap();
+    let stats = store.stats();
+    assert_eq!(
+        (stats.live_keys, stats.log_bytes, stats.dead_bytes),
+        (0, testing::LOG_HEADER_LEN as u64, 0)
+    );
+
+    for i in 0..20 {
+        store.set(format!("key{}", i), "value".to_owned()).unwrap();
+    }
+    let stats = store.stats();
+    assert_eq!(stats.live_keys, 20);
+    assert_eq!(stats.dead_bytes, 0);
+    assert_eq!(
+        stats.log_bytes,
+        fs::metadata(temp_dir.path().join("db.log")).unwrap().len()
+    );
+
+    for i in 0..5 {
+        store
+            .set(format!("key{}", i), "longer value".to_owned())
+            .unwrap();
+        store.remove(format!("key{}", i + 10)).unwrap();
+    }
+    store.set("last".to_owned(), "set".to_owned()).unwrap();
+    let before = store.stats();
+    assert_eq!(before.live_keys, 16);
+    assert_eq!(before.since_open.sets + before.since_open.removes, 31);
+    assert!(before.dead_bytes > 0);
+
+    store.compact().unwrap();
+    let after = store.stats();
+    assert_eq!(after.dead_bytes, 0);
+    assert_eq!(after.log_bytes, before.log_bytes - before.dead_bytes);
+    assert_eq!(after.since_open.bytes_reclaimed, before.dead_bytes);
+    assert_eq!(
+        after.since_open.compactions,
+        before.since_open.compactions + 1
+    );
+    drop(store);
+
+    // Replaying the log on open comes to the same numbers.
+    store = KvStore::open_with_options(temp_dir.path(), testing::options().compact_on_open(false))
+        .unwrap();
+    store.remove("key1".to_owned()).unwrap();
+    store.set("key0".to_owned(), "again".to_owned()).unwrap();
+    let live = store.stats().log_bytes - store.stats().dead_bytes;
+    store.compact().unwrap();
+    assert_eq!(store.stats().log_bytes, live);
+}
@@ -0,0 +1,310 @@
+use assert_cmd::prelude::*;
+use kvs::kv_store::DEFAULT_LOG_FILE_NAME;
+use kvs::testing;
+use kvs::{KvError, KvStore, KvsClient, KvsServer, SwapStats};
+use predicates::str::contains;
+use std::fs;
+use std::process::Command;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use tempfile::TempDir;
+
+const NAMES: [&str; 3] = ["alpha", "beta", "gamma"];
+
+/// `config_v1/` and `config_v2/` with the same names, valued after their version, plus
+/// `config_v2/only` and an unrelated key.
+fn build_versions(store: &mut KvStore) {
+    for name in NAMES {
+        store
+            .set(format!("config_v1/{}", name), "v1".to_owned())
+            .unwrap();
+        store
+            .set(format!("config_v2/{}", name), "v2".to_owned())
+            .unwrap();
+    }
+    store
+        .set("config_v2/only".to_owned(), "v2".to_owned())
+        .unwrap();
+    store.set("other".to_owned(), "kept".to_owned()).unwrap();
+}
+
+fn assert_versions(store: &KvStore, v1: &str, v2: &str) {
+    for name in NAMES {
+        assert_eq!(
+            store
+                .get(&format!("config_v1/{}", name))
+                .unwrap()
+                .as_deref(),
+            Some(v1)
+        );
+        assert_eq!(
+            store
+                .get(&format!("config_v2/{}", name))
+                .unwrap()
+                .as_deref(),
+            Some(v2)
+        );
+    }
+    assert_eq!(store.get("other").unwrap(), Some("kept".to_owned()));
+}
+
+#[test]
+fn swap_exchanges_prefixes_and_persists() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = testing::open(temp_dir.path()).unwrap();
+    build_versions(&mut store);
+
+    let stats = store.swap_prefixes("config_v1/", "config_v2/").unwrap();
+    assert_eq!(
+        stats,
+        SwapStats {
+            moved_from_a: 3,
+            moved_from_b: 4,
+            // Seven sets, and a remove of `config_v2/only`, which nothing moves into.
+            records_written: 8,
+        }
+    );
+    assert_versions(&store, "v2", "v1");
+    assert_eq!(store.get("config_v1/only").unwrap(), Some("v2".to_owned()));
+    assert_eq!(store.get("config_v2/only").unwrap(), None);
+    drop(store);
+
+    let mut store = testing::open(temp_dir.path()).unwrap();
+    assert_versions(&store, "v2", "v1");
+    store.swap_prefixes("config_v1/", "config_v2/").unwrap();
+    assert_versions(&store, "v1", "v2");
+    assert_eq!(store.get("config_v2/only").unwrap(), Some("v2".to_owned()));
+    assert_eq!(store.get("config_v1/only").unwrap(), None);
+}
+
+#[test]
+fn swap_refuses_overlapping_prefixes() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = testing::open(temp_dir.path()).unwrap();
+    build_versions(&mut store);
+    for (a, b) in [("config", "config_v1/"), ("config_v2/", ""), ("x/", "x/")] {
+        match store.swap_prefixes(a, b) {
+            Err(KvError::OverlappingPrefixes(..)) => {}
+            other => panic!("expected OverlappingPrefixes, got {:?}", other),
+        }
+    }
+    assert_versions(&store, "v1", "v2");
+}
+
+/// Cutting the swap's append short anywhere after its group's header and before its last
+/// record leaves both prefixes as they were on reopen.
+#[test]
+fn swap_prefixes_cut_short_applies_none_of_it() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = testing::open(temp_dir.path()).unwrap();
+    build_versions(&mut store);
+    let log = temp_dir.path().join("db.log");
+    let before = fs::metadata(&log).unwrap().len() as usize;
+    store.swap_prefixes("config_v1/", "config_v2/").unwrap();
+    drop(store);
+    let full = fs::read(&log).unwrap();
+    let header = full[before..].iter().position(|&b| b == b'\n').unwrap() + 1;
+
+    for cut in before + header..full.len() - 1 {
+        let crashed = TempDir::new().unwrap();
+        fs::write(crashed.path().join("db.log"), &full[..cut]).unwrap();
+        let store = testing::open(crashed.path()).unwrap();
+        assert_versions(&store, "v1", "v2");
+        assert_eq!(
+            store.get("config_v2/only").unwrap(),
+            Some("v2".to_owned()),
+            "cut at {}",
+            cut
+        );
+        assert_eq!(store.get("config_v1/only").unwrap(), None, "cut at {}", cut);
+    }
+}
+
+/// A reader taking the store between swaps always sees all of one prefix's keys from the
+/// same version.
+#[test]
+fn concurrent_reader_never_sees_a_mixed_state() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = testing::open(temp_dir.path()).unwrap();
+    build_versions(&mut store);
+    let store = Arc::new(Mutex::new(store));
+    let stop = Arc::new(AtomicBool::new(false));
+
+    let reader = {
+        let store = Arc::clone(&store);
+        let stop = Arc::clone(&stop);
+        thread::spawn(move || {
+            let mut flips = 0;
+            let mut last = None;
+            while !stop.load(Ordering::SeqCst) {
+                let store = store.lock().unwrap();
+                let values: Vec<String> = NAMES
+                    .iter()
+                    .map(|name| store.get(&format!("config_v1/{}", name)).unwrap().unwrap())
+                    .collect();
+                assert!(
+                    values.iter().all(|value| *value == values[0]),
+                    "{:?}",
+                    values
+                );
+                if last.as_ref() != Some(&values[0]) {
+                    flips += 1;
+                    last = Some(values[0].clone());
+                }
+            }
+            flips
+        })
+    };
+
+    for _ in 0..50 {
+        store
+            .lock()
+            .unwrap()
+            .swap_prefixes("config_v1/", "config_v2/")
+            .unwrap();
+        thread::yield_now();
+    }
+    stop.store(true, Ordering::SeqCst);
+    assert!(reader.join().unwrap() >= 1);
+    assert_versions(&store.lock().unwrap(), "v1", "v2");
+}
+
+#[test]
+fn swap_over_the_protocol() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = testing::open(temp_dir.path()).unwrap();
+    build_versions(&mut store);
+    let log = slog::Logger::root(slog::Discard, slog::o!());
+    let server = KvsServer::new("127.0.0.1:0", store, log).unwrap();
+    let addr = server.local_addr().unwrap();
+    thread::spawn(move || server.listen_forever());
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    let stats = client
+        .swap_prefixes("config_v1/".to_owned(), "config_v2/".to_owned())
+        .unwrap();
+    assert_eq!(stats.moved_from_b, 4);
+    assert_eq!(
+        client.get("config_v1/alpha".to_owned()).unwrap(),
+        Some("v2".to_owned())
+    );
+    match client.swap_prefixes("a".to_owned(), "ab".to_owned()) {
+        Err(KvError::ServerError(message)) => assert!(message.contains("cannot swap")),
+        other => panic!("expected a server error, got {:?}", other),
+    }
+}
+
+#[test]
+fn cli_swap_prefix() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = KvStore::open(temp_dir.path()).unwrap();
+    build_versions(&mut store);
+    drop(store);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["swap-prefix", "config_v1/", "config_v2/", "--dir"])
+        .arg(temp_dir.path())
+        .assert()
+        .success()
+        .stdout(contains("Moved 3 keys"));
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    assert_versions(&store, "v2", "v1");
+}
+
+fn blue_green(store: &mut KvStore) {
+    store
+        .set("config:active".to_owned(), "blue".to_owned())
+        .unwrap();
+    store
+        .set("config:staging".to_owned(), "green".to_owned())
+        .unwrap();
+}
+
+fn active_and_staging(store: &KvStore) -> (String, String) {
+    (
+        store.get("config:active").unwrap().unwrap(),
+        store.get("config:staging").unwrap().unwrap(),
+    )
+}
+
+#[test]
+fn swap_exchanges_two_values() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = testing::open(temp_dir.path()).unwrap();
+    blue_green(&mut store);
+
+    store.swap("config:active", "config:staging").unwrap();
+    assert_eq!(
+        active_and_staging(&store),
+        ("green".to_owned(), "blue".to_owned())
+    );
+    store.swap("config:active", "config:active").unwrap();
+    drop(store);
+
+    let mut store = testing::open(temp_dir.path()).unwrap();
+    assert_eq!(
+        active_and_staging(&store),
+        ("green".to_owned(), "blue".to_owned())
+    );
+    store.swap("config:staging", "config:active").unwrap();
+    assert_eq!(
+        active_and_staging(&store),
+        ("blue".to_owned(), "green".to_owned())
+    );
+}
+
+#[test]
+fn swap_with_a_missing_key_writes_nothing() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = testing::open(temp_dir.path()).unwrap();
+    blue_green(&mut store);
+    let log = temp_dir.path().join(DEFAULT_LOG_FILE_NAME);
+    let before = fs::metadata(&log).unwrap().len();
+
+    for (a, b) in [("config:active", "nope"), ("nope", "config:staging")] {
+        match store.swap(a, b) {
+            Err(KvError::KeyNotFound(key)) => assert_eq!(key, "nope"),
+            other => panic!("expected KeyNotFound, got {:?}", other),
+        }
+    }
+    assert_eq!(fs::metadata(&log).unwrap().len(), before);
+    assert_eq!(
+        active_and_staging(&store),
+        ("blue".to_owned(), "green".to_owned())
+    );
+}
+
+/// Cutting the swap's append short anywhere leaves neither set applied on reopen, and
+/// the store writable after it.
+#[test]
+fn swap_cut_short_applies_neither_side() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = testing::open(temp_dir.path()).unwrap();
+    blue_green(&mut store);
+    let log = temp_dir.path().join(DEFAULT_LOG_FILE_NAME);
+    let before = fs::metadata(&log).unwrap().len() as usize;
+    store.swap("config:active", "config:staging").unwrap();
+    drop(store);
+    let full = fs::read(&log).unwrap();
+
+    for cut in before..=full.len() {
+        let crashed = TempDir::new().unwrap();
+        fs::write(crashed.path().join(DEFAULT_LOG_FILE_NAME), &full[..cut]).unwrap();
+        let mut store = testing::open(crashed.path()).unwrap();
+        // Only the trailing newline of the second set may be missing for the swap to count.
+        let expected = if cut + 1 >= full.len() {
+            ("green".to_owned(), "blue".to_owned())
+        } else {
+            ("blue".to_owned(), "green".to_owned())
+        };
+        assert_eq!(active_and_staging(&store), expected, "cut at {}", cut);
+
+        store.set("after".to_owned(), "crash".to_owned()).unwrap();
+        drop(store);
+        let store = testing::open(crashed.path()).unwrap();
+        assert_eq!(active_and_staging(&store), expected, "cut at {}", cut);
+        assert_eq!(store.get("after").unwrap(), Some("crash".to_owned()));
+    }
+}
@@ -0,0 +1,100 @@
+use kvs::cli::report;
+use kvs::cli::style::Style;
+use kvs::cli::table::{human_bytes, human_duration, Cell, Table};
+use kvs::{CompactionEstimate, CompactionStats};
+use std::time::Duration;
+
+#[test]
+fn bytes_switch_units_at_1024() {
+    assert_eq!(human_bytes(0), "0 B");
+    assert_eq!(human_bytes(1023), "1023 B");
+    assert_eq!(human_bytes(1024), "1.0 KiB");
+    assert_eq!(human_bytes(1536), "1.5 KiB");
+    // Rounds up into the next unit instead of printing 1024.0 KiB.
+    assert_eq!(human_bytes(1024 * 1024 - 1), "1.0 MiB");
+    assert_eq!(human_bytes(1024 * 1024 - 60), "1023.9 KiB");
+    assert_eq!(human_bytes(1_503_238_554), "1.4 GiB");
+    assert_eq!(human_bytes(u64::MAX), "16.0 EiB");
+}
+
+#[test]
+fn durations_keep_sub_millisecond_precision() {
+    assert_eq!(human_duration(Duration::ZERO), "0 ns");
+    assert_eq!(human_duration(Duration::from_nanos(999)), "999 ns");
+    assert_eq!(human_duration(Duration::from_nanos(1_000)), "1 µs");
+    assert_eq!(human_duration(Duration::from_micros(250)), "250 µs");
+    assert_eq!(human_duration(Duration::from_micros(999_999)), "999 ms");
+    assert_eq!(human_duration(Duration::from_millis(230)), "230 ms");
+    assert_eq!(human_duration(Duration::from_millis(1_500)), "1.5 s");
+    assert_eq!(human_duration(Duration::from_secs(125)), "2m 05s");
+}
+
+#[test]
+fn columns_align_and_lines_have_no_trailing_spaces() {
+    let table = Table::new()
+        .header(["name", "size", "took"])
+        .row(vec![
+            Cell::text("a"),
+            Cell::bytes(1023),
+            Cell::duration(Duration::from_micros(5)),
+        ])
+        .row(vec![
+            Cell::text("longer name"),
+            Cell::bytes(1 << 20),
+            Cell::text(""),
+        ]);
+    assert_eq!(
+        table.render(Style::new(false)),
+        "name            size  took\n\
+         a             1023 B  5 µs\n\
+         longer name  1.0 MiB\n"
+    );
+}
+
+#[test]
+fn styling_does_not_shift_columns() {
+    let table = Table::new()
+        .row(vec![Cell::label("a"), Cell::number(1)])
+        .row(vec![Cell::label("bbb"), Cell::number(22)]);
+    assert_eq!(
+        table.render(Style::new(true)),
+        "\x1b[1ma\x1b[0m     1\n\x1b[1mbbb\x1b[0m  22\n"
+    );
+}
+
+#[test]
+fn compaction_report_snapshot() {
+    let estimate = CompactionEstimate {
+        current_log_bytes: 1_503_238_554,
+        projected_log_bytes: 52_428_800,
+        reclaimable_bytes: 1_450_809_754,
+        stale_records: 1_200_000,
+        tombstone_records: 340,
+    };
+    assert_eq!(
+        report::compaction(&estimate).render(Style::new(false)),
+        "log size            1.4 GiB\n\
+         projected size     50.0 MiB\n\
+         reclaimable         1.4 GiB\n\
+         stale records       1200000\n\
+         tombstone records       340\n"
+    );
+}
+
+#[test]
+fn compacted_report_snapshot() {
+    let stats = CompactionStats {
+        log_bytes_before: 1_503_238_554,
+        log_bytes_after: 52_428_800,
+        records_dropped: 1_200_340,
+        duration: Duration::from_millis(1_500),
+    };
+    assert_eq!(
+        report::compacted(&stats).render(Style::new(false)),
+        "log size before   1.4 GiB\n\
+         log size after   50.0 MiB\n\
+         reclaimed         1.4 GiB\n\
+         records dropped   1200340\n\
+         took                1.5 s\n"
+    );
+}
@@ -0,0 +1,33 @@
+// Kept alone in its own test binary: it predicts the next temp name, which another test
+// running in parallel would consume.
+
+use kvs::fsutil;
+use kvs::testing;
+use kvs::KvError;
+use std::fs;
+use tempfile::TempDir;
+
+#[test]
+fn compaction_refuses_to_adopt_an_existing_temp_file() {
+    let temp_dir = TempDir::new().unwrap();
+    testing::open(temp_dir.path()).unwrap();
+
+    // Names end in a process-wide counter; the next compaction takes the one after this.
+    let peeked = fsutil::temp_path(temp_dir.path(), "compact");
+    let name = peeked.file_name().unwrap().to_string_lossy().into_owned();
+    let (prefix, counter) = name.trim_end_matches(".tmp").rsplit_once('-').unwrap();
+    let counter: u64 = counter.parse().unwrap();
+    let next = temp_dir
+        .path()
+        .join(format!("{}-{}.tmp", prefix, counter + 1));
+    fs::write(&next, "someone else's compaction").unwrap();
+
+    match testing::open(temp_dir.path()) {
+        Err(KvError::TempFileExists(path)) => assert_eq!(path, next),
+        other => panic!("expected TempFileExists, got {:?}", other.err()),
+    }
+    assert_eq!(
+        fs::read_to_string(&next).unwrap(),
+        "someone else's compaction"
+    );
+}
@@ -0,0 +1,121 @@
+use assert_cmd::prelude::*;
+use kvs::protocol::{read_frame, write_frame, Request, Response};
+use kvs::{KvError, KvsClient, KvsClientOptions, ShardedKvsClient};
+use predicates::str::contains;
+use std::io::BufReader;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::process::Command;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const TIMEOUT: Duration = Duration::from_millis(300);
+
+/// A server that accepts its first connection and never answers on it, then answers every
+/// `Get` on later connections with `"value"`.
+fn start_stalling_server() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || {
+        let mut stalled = Vec::new();
+        for (index, stream) in listener.incoming().enumerate() {
+            let stream = stream.unwrap();
+            if index == 0 {
+                stalled.push(stream);
+            } else {
+                thread::spawn(move || answer_gets(stream));
+            }
+        }
+    });
+    addr
+}
+
+fn answer_gets(stream: TcpStream) {
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    let mut writer = stream;
+    while let Ok(Some(request)) = read_frame::<_, Request>(&mut reader) {
+        assert!(matches!(request, Request::Get { .. }));
+        write_frame(&mut writer, &Response::Ok(Some("value".to_owned()))).unwrap();
+    }
+}
+
+fn options() -> KvsClientOptions {
+    KvsClientOptions::new().operation_timeout(TIMEOUT)
+}
+
+#[test]
+fn operation_timeout_fires_and_abandons_the_connection() {
+    let addr = start_stalling_server();
+    let mut stalled = KvsClient::connect_with_options(addr, options()).unwrap();
+
+    let started = Instant::now();
+    match stalled.get("key".to_owned()) {
+        Err(KvError::OperationTimedOut { op, elapsed }) => {
+            assert_eq!(op, "get");
+            assert!(elapsed >= TIMEOUT);
+        }
+        other => panic!("expected OperationTimedOut, got {:?}", other),
+    }
+    let waited = started.elapsed();
+    assert!(waited >= TIMEOUT && waited < TIMEOUT * 5, "{:?}", waited);
+
+    // A late answer could arrive on this connection, so it is not used again.
+    assert!(matches!(
+        stalled.get("key".to_owned()),
+        Err(KvError::ConnectionError(_))
+    ));
+
+    let mut fresh = KvsClient::connect_with_options(addr, options()).unwrap();
+    assert_eq!(
+        fresh.get("key".to_owned()).unwrap(),
+        Some("value".to_owned())
+    );
+}
+
+#[test]
+fn get_with_timeout_overrides_the_client_timeout() {
+    let addr = start_stalling_server();
+    let slow = KvsClientOptions::new().operation_timeout(Duration::from_secs(60));
+    let mut client = KvsClient::connect_with_options(addr, slow).unwrap();
+
+    let started = Instant::now();
+    assert!(matches!(
+        client.get_with_timeout("key".to_owned(), Duration::from_millis(100)),
+        Err(KvError::OperationTimedOut { op: "get", .. })
+    ));
+    assert!(started.elapsed() < Duration::from_secs(5));
+}
+
+#[test]
+fn sharded_client_reports_the_timeout_and_reconnects() {
+    let addr = start_stalling_server();
+    let mut sharded = ShardedKvsClient::with_options(vec![addr], 16, options()).unwrap();
+
+    assert!(matches!(
+        sharded.get("key".to_owned()),
+        Err(KvError::OperationTimedOut { .. })
+    ));
+    assert_eq!(
+        sharded.get("key".to_owned()).unwrap(),
+        Some("value".to_owned())
+    );
+}
+
+#[test]
+fn cli_timeout_flag() {
+    let addr = start_stalling_server().to_string();
+    let started = Instant::now();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["--addr", &addr, "--timeout", "200ms", "get", "key"])
+        .assert()
+        .failure()
+        .stderr(contains("get timed out after"));
+    assert!(started.elapsed() < Duration::from_secs(10));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["--addr", &addr, "--timeout", "soon", "get", "key"])
+        .assert()
+        .failure()
+        .stderr(contains("invalid duration 'soon'"));
+}
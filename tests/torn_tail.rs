@@ -0,0 +1,99 @@
+use kvs::testing;
+use kvs::{KvError, TornTail};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use tempfile::TempDir;
+
+fn log_path(dir: &Path) -> std::path::PathBuf {
+    dir.join("db.log")
+}
+
+/// Sets a few keys and returns the log's length after them.
+fn write_keys(dir: &Path) -> u64 {
+    let mut store = testing::open(dir).unwrap();
+    for i in 0..5 {
+        store
+            .set(format!("key{}", i), format!("value{}", i))
+            .unwrap();
+    }
+    drop(store);
+    fs::metadata(log_path(dir)).unwrap().len()
+}
+
+fn append(dir: &Path, bytes: &[u8]) {
+    let mut log = OpenOptions::new().append(true).open(log_path(dir)).unwrap();
+    log.write_all(bytes).unwrap();
+}
+
+#[test]
+fn half_a_record_at_the_end_is_cut_away() {
+    let temp_dir = TempDir::new().unwrap();
+    let good_len = write_keys(temp_dir.path());
+    let record = b"{\"Set\":{\"key\":\"key5\",\"value\":\"value5\",\"seq\":6}}\n";
+    append(temp_dir.path(), &record[..record.len() / 2]);
+
+    let mut store = testing::open(temp_dir.path()).unwrap();
+    assert_eq!(
+        store.open_report().torn_tail,
+        Some(TornTail {
+            offset: good_len,
+            bytes: record.len() as u64 / 2,
+        })
+    );
+    for i in 0..5 {
+        assert_eq!(
+            store.get(&format!("key{}", i)).unwrap(),
+            Some(format!("value{}", i))
+        );
+    }
+    assert_eq!(store.get("key5").unwrap(), None);
+
+    // The log carries on from the last whole record.
+    store.set("key5".to_owned(), "value5".to_owned()).unwrap();
+    drop(store);
+    let store = testing::open(temp_dir.path()).unwrap();
+    assert_eq!(store.open_report().torn_tail, None);
+    assert_eq!(store.get("key5").unwrap(), Some("value5".to_owned()));
+}
+
+#[test]
+fn garbage_ending_in_a_newline_at_the_end_is_cut_away() {
+    let temp_dir = TempDir::new().unwrap();
+    let good_len = write_keys(temp_dir.path());
+    append(temp_dir.path(), b"{\"Set\":{\"key\":\"ke\0\0\0\n");
+
+    let store = testing::open(temp_dir.path()).unwrap();
+    assert_eq!(
+        store.open_report().torn_tail.map(|tail| tail.offset),
+        Some(good_len)
+    );
+    assert_eq!(store.get("key4").unwrap(), Some("value4".to_owned()));
+}
+
+#[test]
+fn a_clean_log_reports_no_torn_tail() {
+    let temp_dir = TempDir::new().unwrap();
+    write_keys(temp_dir.path());
+    let store = testing::open(temp_dir.path()).unwrap();
+    assert_eq!(store.open_report().torn_tail, None);
+}
+
+#[test]
+fn garbage_before_the_last_record_is_corruption() {
+    let temp_dir = TempDir::new().unwrap();
+    let good_len = write_keys(temp_dir.path());
+    append(temp_dir.path(), b"{\"Set\":{\"key\":\"ke\n");
+    append(
+        temp_dir.path(),
+        b"{\"Set\":{\"key\":\"key5\",\"value\":\"value5\",\"seq\":6}}\n",
+    );
+
+    match testing::open(temp_dir.path()) {
+        Err(KvError::Corruption { offset, .. }) => assert_eq!(offset, good_len),
+        Err(e) => panic!("unexpected error: {:?}", e),
+        Ok(_) => panic!("opened a log with garbage in the middle"),
+    }
+    // Nothing was cut.
+    assert!(fs::metadata(log_path(temp_dir.path())).unwrap().len() > good_len);
+}
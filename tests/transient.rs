@@ -0,0 +1,167 @@
+use kvs::fsutil::{IoSite, RetryPolicy};
+use kvs::testing::{self, TransientFaults};
+use kvs::{KvError, KvStore, KvStoreOptions};
+use std::io::ErrorKind;
+use std::path::Path;
+use std::time::Duration;
+use tempfile::TempDir;
+
+fn policy(max_attempts: u32) -> RetryPolicy {
+    RetryPolicy {
+        max_attempts,
+        base_delay: Duration::from_millis(1),
+        ..RetryPolicy::default()
+    }
+}
+
+fn options(faults: &TransientFaults, max_attempts: u32) -> KvStoreOptions {
+    testing::options()
+        .transient_retry(policy(max_attempts))
+        .transient_faults(faults.clone())
+}
+
+fn populated(dir: &Path) {
+    let mut store = KvStore::open(dir).unwrap();
+    for i in 0..20 {
+        store
+            .set(format!("key{}", i), format!("value{}", i))
+            .unwrap();
+    }
+}
+
+fn retries(store: &KvStore) -> u64 {
+    store.stats().since_open.transient_retries
+}
+
+fn assert_exhausted<T: std::fmt::Debug>(result: kvs::Result<T>, expected_attempts: u32) {
+    match result {
+        Err(KvError::TransientIoExhausted { attempts, error }) => {
+            assert_eq!(attempts, expected_attempts);
+            assert!(error.contains("injected transient failure"), "{}", error);
+        }
+        other => panic!("expected TransientIoExhausted, got {:?}", other),
+    }
+}
+
+#[test]
+fn get_succeeds_after_transient_read_errors() {
+    let temp_dir = TempDir::new().unwrap();
+    populated(temp_dir.path());
+    let faults = TransientFaults::new();
+    let store = KvStore::open_with_options(temp_dir.path(), options(&faults, 5)).unwrap();
+
+    faults.arm(IoSite::Read, ErrorKind::TimedOut, 3);
+    assert_eq!(store.get("key7").unwrap(), Some("value7".to_owned()));
+    assert_eq!(faults.remaining(IoSite::Read), 0);
+    assert_eq!(retries(&store), 3);
+}
+
+#[test]
+fn get_gives_up_after_max_attempts() {
+    let temp_dir = TempDir::new().unwrap();
+    populated(temp_dir.path());
+    let faults = TransientFaults::new();
+    let store = KvStore::open_with_options(temp_dir.path(), options(&faults, 3)).unwrap();
+
+    faults.arm(IoSite::Read, ErrorKind::StaleNetworkFileHandle, 10);
+    assert_exhausted(store.get("key7"), 3);
+    assert_eq!(faults.remaining(IoSite::Read), 7);
+    assert_eq!(retries(&store), 2);
+}
+
+#[test]
+fn errors_outside_the_policy_are_not_retried() {
+    let temp_dir = TempDir::new().unwrap();
+    populated(temp_dir.path());
+    let faults = TransientFaults::new();
+    let store = KvStore::open_with_options(temp_dir.path(), options(&faults, 5)).unwrap();
+
+    faults.arm(IoSite::Read, ErrorKind::PermissionDenied, 2);
+    assert!(matches!(store.get("key7"), Err(KvError::OpenError)));
+    assert_eq!(faults.remaining(IoSite::Read), 1);
+    assert_eq!(retries(&store), 0);
+}
+
+#[test]
+fn retrying_is_off_by_default() {
+    let temp_dir = TempDir::new().unwrap();
+    populated(temp_dir.path());
+    let faults = TransientFaults::new();
+    let options = testing::options().transient_faults(faults.clone());
+    let store = KvStore::open_with_options(temp_dir.path(), options).unwrap();
+
+    faults.arm(IoSite::Read, ErrorKind::TimedOut, 1);
+    assert!(matches!(store.get("key7"), Err(KvError::OpenError)));
+    assert_eq!(store.get("key7").unwrap(), Some("value7".to_owned()));
+}
+
+#[test]
+fn replay_succeeds_after_transient_read_errors() {
+    let temp_dir = TempDir::new().unwrap();
+    populated(temp_dir.path());
+    let faults = TransientFaults::new();
+    faults.arm(IoSite::Replay, ErrorKind::WouldBlock, 4);
+
+    let store = KvStore::open_with_options(temp_dir.path(), options(&faults, 5)).unwrap();
+    assert_eq!(retries(&store), 4);
+    for i in 0..20 {
+        assert_eq!(
+            store.get(&format!("key{}", i)).unwrap(),
+            Some(format!("value{}", i))
+        );
+    }
+    drop(store);
+
+    faults.arm(IoSite::Replay, ErrorKind::WouldBlock, 5);
+    assert_exhausted(
+        KvStore::open_with_options(temp_dir.path(), options(&faults, 5)).map(drop),
+        5,
+    );
+}
+
+#[test]
+fn compaction_rename_succeeds_after_transient_errors() {
+    let temp_dir = TempDir::new().unwrap();
+    populated(temp_dir.path());
+    let faults = TransientFaults::new();
+    let mut store = KvStore::open_with_options(temp_dir.path(), options(&faults, 5)).unwrap();
+    store.set("key0".to_owned(), "new".to_owned()).unwrap();
+
+    faults.arm(IoSite::Rename, ErrorKind::StaleNetworkFileHandle, 2);
+    store.compact().unwrap();
+    assert_eq!(retries(&store), 2);
+    assert_eq!(store.get("key0").unwrap(), Some("new".to_owned()));
+
+    store.set("key2".to_owned(), "new".to_owned()).unwrap();
+    faults.arm(IoSite::Rename, ErrorKind::StaleNetworkFileHandle, 5);
+    assert_exhausted(store.compact(), 5);
+    store.set("key1".to_owned(), "after".to_owned()).unwrap();
+    drop(store);
+
+    let store = testing::open(temp_dir.path()).unwrap();
+    assert_eq!(store.get("key0").unwrap(), Some("new".to_owned()));
+    assert_eq!(store.get("key1").unwrap(), Some("after".to_owned()));
+}
+
+#[test]
+fn append_is_retried_without_poisoning_the_log() {
+    let temp_dir = TempDir::new().unwrap();
+    let faults = TransientFaults::new();
+    let mut store = KvStore::open_with_options(temp_dir.path(), options(&faults, 4)).unwrap();
+
+    faults.arm(IoSite::Append, ErrorKind::TimedOut, 3);
+    store.set("retried".to_owned(), "1".to_owned()).unwrap();
+    assert_eq!(retries(&store), 3);
+
+    faults.arm(IoSite::Append, ErrorKind::TimedOut, 4);
+    assert_exhausted(store.set("lost".to_owned(), "2".to_owned()), 4);
+    // Nothing of the record reached the log, so there is nothing to truncate.
+    assert!(!store.is_append_poisoned());
+    store.set("after".to_owned(), "3".to_owned()).unwrap();
+    drop(store);
+
+    let store = testing::open(temp_dir.path()).unwrap();
+    assert_eq!(store.get("retried").unwrap(), Some("1".to_owned()));
+    assert_eq!(store.get("lost").unwrap(), None);
+    assert_eq!(store.get("after").unwrap(), Some("3".to_owned()));
+}
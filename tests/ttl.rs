@@ -0,0 +1,351 @@
+use assert_cmd::prelude::*;
+use kvs::testing;
+use kvs::{KvError, KvStore, KvsClient, KvsServer};
+use predicates::str::contains;
+use std::fs;
+use std::net::SocketAddr;
+use std::process::Command;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+const SHORT: Duration = Duration::from_millis(100);
+const LONG: Duration = Duration::from_secs(3_600);
+
+fn log_of(dir: &std::path::Path) -> String {
+    String::from_utf8(testing::log_records(dir)).unwrap()
+}
+
+fn start_server(store: KvStore) -> SocketAddr {
+    let log = slog::Logger::root(slog::Discard, slog::o!());
+    let server = KvsServer::new("127.0.0.1:0", store, log).unwrap();
+    let addr = server.local_addr().unwrap();
+    thread::spawn(move || server.listen_forever());
+    addr
+}
+
+#[test]
+fn expired_keys_read_as_missing() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = testing::open(temp_dir.path()).unwrap();
+    store.set("kept".to_owned(), "forever".to_owned()).unwrap();
+    store
+        .set_with_ttl("session".to_owned(), "token".to_owned(), SHORT)
+        .unwrap();
+    assert_eq!(store.get("session").unwrap(), Some("token".to_owned()));
+    assert_eq!(store.len(), 2);
+
+    thread::sleep(SHORT * 2);
+    assert_eq!(store.get("session").unwrap(), None);
+    assert!(!store.contains_key("session").unwrap());
+    assert_eq!(store.len(), 1);
+    assert_eq!(store.keys().unwrap().collect::<Vec<_>>(), ["kept"]);
+    assert_eq!(store.iter().unwrap().count(), 1);
+    assert_eq!(
+        store
+            .multi_get(&["session".to_owned(), "kept".to_owned()])
+            .unwrap(),
+        [None, Some("forever".to_owned())]
+    );
+    assert!(matches!(
+        store.remove("session".to_owned()),
+        Err(KvError::RemoveError(_))
+    ));
+
+    // It can be set again like any missing key.
+    assert!(store
+        .set_if_absent("session".to_owned(), "fresh".to_owned())
+        .unwrap());
+    assert_eq!(store.len(), 2);
+}
+
+#[test]
+fn replay_and_compaction_drop_expired_keys_and_keep_live_expiries() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = testing::open(temp_dir.path()).unwrap();
+    store
+        .set_with_ttl("short".to_owned(), "gone".to_owned(), SHORT)
+        .unwrap();
+    store
+        .set_with_ttl("long".to_owned(), "stays".to_owned(), LONG)
+        .unwrap();
+    drop(store);
+    thread::sleep(SHORT * 2);
+
+    // Opening compacts, which leaves the expired key out and carries the other's expiry.
+    let store = testing::open(temp_dir.path()).unwrap();
+    assert_eq!(store.get("short").unwrap(), None);
+    assert_eq!(store.get("long").unwrap(), Some("stays".to_owned()));
+    assert_eq!(store.len(), 1);
+    let log = log_of(temp_dir.path());
+    assert!(!log.contains("short"), "{}", log);
+    assert!(log.contains("expires_at"), "{}", log);
+    drop(store);
+
+    let store = testing::open(temp_dir.path()).unwrap();
+    assert_eq!(store.get("long").unwrap(), Some("stays".to_owned()));
+}
+
+#[test]
+fn read_only_replay_skips_expired_records() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = testing::open(temp_dir.path()).unwrap();
+    store.set("key".to_owned(), "old".to_owned()).unwrap();
+    store
+        .set_with_ttl("key".to_owned(), "brief".to_owned(), SHORT)
+        .unwrap();
+    drop(store);
+    thread::sleep(SHORT * 2);
+
+    // Without the compaction of a writable open, the expired set hides the older value.
+    let store = kvs::KvStore::open_read_only(temp_dir.path()).unwrap();
+    assert_eq!(store.get("key").unwrap(), None);
+    assert_eq!(store.len(), 0);
+}
+
+#[test]
+fn plain_sets_write_no_expiry_and_clear_one() {
+    let temp_dir = TempDir::new().unwrap();
+    fs::write(
+        temp_dir.path().join("db.log"),
+        "{\"Set\":{\"key\":\"old\",\"value\":\"format\"}}\n",
+    )
+    .unwrap();
+    let options = testing::options().write_time(1_700_000_000_000);
+    let mut store = KvStore::open_with_options(temp_dir.path(), options).unwrap();
+    assert_eq!(store.get("old").unwrap(), Some("format".to_owned()));
+
+    store
+        .set_with_ttl("key".to_owned(), "brief".to_owned(), SHORT)
+        .unwrap();
+    store.set("key".to_owned(), "lasting".to_owned()).unwrap();
+    thread::sleep(SHORT * 2);
+    assert_eq!(store.get("key").unwrap(), Some("lasting".to_owned()));
+
+    // Compacting also seals the old record with a CRC.
+    store.compact().unwrap();
+    assert_eq!(
+        log_of(temp_dir.path()),
+        "{\"Set\":{\"key\":\"key\",\"value\":\"lasting\",\"timestamp\":1700000000000,\"seq\":2,\"crc\":780387318}}\n\
+         {\"Set\":{\"key\":\"old\",\"value\":\"format\",\"crc\":2436466135}}\n"
+    );
+}
+
+#[test]
+fn expire_and_persist_change_the_lifetime_of_existing_keys() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = testing::open(temp_dir.path()).unwrap();
+    store.set("key".to_owned(), "value".to_owned()).unwrap();
+    assert_eq!(store.ttl("key").unwrap(), None);
+
+    assert!(store.expire("key", LONG).unwrap());
+    let left = store.ttl("key").unwrap().unwrap();
+    assert!(
+        left <= LONG && left > LONG - Duration::from_secs(60),
+        "{:?}",
+        left
+    );
+    assert!(store.persist("key").unwrap());
+    assert_eq!(store.ttl("key").unwrap(), None);
+    let log_len = log_of(temp_dir.path()).len();
+    assert!(store.persist("key").unwrap());
+    assert_eq!(log_of(temp_dir.path()).len(), log_len);
+
+    assert!(store.expire("key", SHORT).unwrap());
+    drop(store);
+    let mut store = testing::open(temp_dir.path()).unwrap();
+    assert!(store.ttl("key").unwrap().unwrap() <= SHORT);
+    thread::sleep(SHORT * 2);
+
+    // Expired and not yet collected, it is as missing to all three.
+    assert!(!store.expire("key", LONG).unwrap());
+    assert!(!store.persist("key").unwrap());
+    assert!(matches!(store.ttl("key"), Err(KvError::KeyNotFound(_))));
+    assert_eq!(store.get("key").unwrap(), None);
+    assert!(!store.expire("missing", LONG).unwrap());
+    assert!(matches!(store.ttl("missing"), Err(KvError::KeyNotFound(_))));
+}
+
+#[test]
+fn keys_moved_or_rewritten_keep_their_expiry() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = testing::open(temp_dir.path()).unwrap();
+    store
+        .set_with_ttl("session".to_owned(), "token".to_owned(), SHORT)
+        .unwrap();
+    store
+        .set_with_ttl("counter".to_owned(), "1".to_owned(), LONG)
+        .unwrap();
+    store
+        .set_with_ttl("a/one".to_owned(), "1".to_owned(), LONG)
+        .unwrap();
+    store.set("b/two".to_owned(), "2".to_owned()).unwrap();
+    store
+        .set_with_ttl("left".to_owned(), "l".to_owned(), LONG)
+        .unwrap();
+    store.set("right".to_owned(), "r".to_owned()).unwrap();
+
+    store.rename("session", "renamed".to_owned()).unwrap();
+    assert_eq!(store.increment("counter", 2).unwrap(), 3);
+    store.swap_prefixes("a/", "b/").unwrap();
+    store.swap("left", "right").unwrap();
+
+    let lasting = |store: &KvStore, key: &str| {
+        let ttl = store.ttl(key).unwrap().unwrap();
+        assert!(ttl > LONG / 2, "{}: {:?}", key, ttl);
+    };
+    assert!(store.ttl("renamed").unwrap().unwrap() <= SHORT);
+    lasting(&store, "counter");
+    lasting(&store, "b/one");
+    assert_eq!(store.ttl("a/two").unwrap(), None);
+    lasting(&store, "right");
+    assert_eq!(store.ttl("left").unwrap(), None);
+
+    // As written to the log, not only as held in memory.
+    drop(store);
+    let store = testing::open(temp_dir.path()).unwrap();
+    lasting(&store, "counter");
+    lasting(&store, "b/one");
+    lasting(&store, "right");
+    assert_eq!(store.ttl("a/two").unwrap(), None);
+    assert_eq!(store.ttl("left").unwrap(), None);
+
+    thread::sleep(SHORT * 2);
+    assert_eq!(store.get("renamed").unwrap(), None);
+    assert_eq!(store.get("session").unwrap(), None);
+    assert_eq!(store.get("counter").unwrap(), Some("3".to_owned()));
+}
+
+#[test]
+fn touch_extends_a_key_past_its_deadline_without_writing_its_value() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = testing::open(temp_dir.path()).unwrap();
+    store
+        .set_with_ttl("session".to_owned(), "token".to_owned(), SHORT)
+        .unwrap();
+    assert!(store.touch("session", LONG).unwrap());
+    assert!(store.ttl("session").unwrap().unwrap() > LONG / 2);
+    let log = log_of(temp_dir.path());
+    assert_eq!(log.matches("token").count(), 1, "{}", log);
+    assert!(log.contains("\"Touch\""), "{}", log);
+
+    thread::sleep(SHORT * 2);
+    assert_eq!(store.get("session").unwrap(), Some("token".to_owned()));
+
+    // Missing and expired keys are left alone.
+    assert!(!store.touch("missing", LONG).unwrap());
+    store
+        .set_with_ttl("brief".to_owned(), "gone".to_owned(), SHORT)
+        .unwrap();
+    thread::sleep(SHORT * 2);
+    let log_len = log_of(temp_dir.path()).len();
+    assert!(!store.touch("brief", LONG).unwrap());
+    assert_eq!(log_of(temp_dir.path()).len(), log_len);
+    assert_eq!(store.get("brief").unwrap(), None);
+}
+
+#[test]
+fn touches_survive_reopening_before_and_after_compaction() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = testing::open(temp_dir.path()).unwrap();
+    store
+        .set_with_ttl("touched".to_owned(), "kept".to_owned(), SHORT)
+        .unwrap();
+    store.touch("touched", LONG).unwrap();
+    store.set("plain".to_owned(), "value".to_owned()).unwrap();
+    store.touch("plain", SHORT).unwrap();
+    drop(store);
+    thread::sleep(SHORT * 2);
+
+    // Replayed without compacting, the set's own deadline has passed but the touch's has not,
+    // and the other way round.
+    let store = KvStore::open_read_only(temp_dir.path()).unwrap();
+    assert_eq!(store.get("touched").unwrap(), Some("kept".to_owned()));
+    assert!(store.ttl("touched").unwrap().unwrap() > LONG / 2);
+    assert_eq!(store.get("plain").unwrap(), None);
+    drop(store);
+
+    let mut store = testing::open(temp_dir.path()).unwrap();
+    assert_eq!(store.get("touched").unwrap(), Some("kept".to_owned()));
+    assert_eq!(store.get("plain").unwrap(), None);
+    store.compact().unwrap();
+    let log = log_of(temp_dir.path());
+    assert!(!log.contains("Touch"), "{}", log);
+    drop(store);
+
+    let store = testing::open(temp_dir.path()).unwrap();
+    assert_eq!(store.get("touched").unwrap(), Some("kept".to_owned()));
+    assert!(store.ttl("touched").unwrap().unwrap() > LONG / 2);
+    assert_eq!(store.len(), 1);
+}
+
+#[test]
+fn a_chain_of_touches_compacts_down_to_one_record() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = testing::open(temp_dir.path()).unwrap();
+    store.set("key".to_owned(), "value".to_owned()).unwrap();
+    for _ in 0..100 {
+        assert!(store.touch("key", LONG).unwrap());
+    }
+    assert_eq!(store.compact_dry_run().unwrap().stale_records, 99);
+
+    store.compact().unwrap();
+    let log = log_of(temp_dir.path());
+    assert_eq!(log.lines().count(), 1, "{}", log);
+    assert!(log.contains("expires_at"), "{}", log);
+    assert!(store.ttl("key").unwrap().unwrap() > LONG / 2);
+
+    // A key written again drops its touch with the value it touched.
+    store.touch("key", LONG).unwrap();
+    store.set("key".to_owned(), "lasting".to_owned()).unwrap();
+    assert_eq!(store.ttl("key").unwrap(), None);
+    drop(store);
+    let store = testing::open(temp_dir.path()).unwrap();
+    assert_eq!(store.ttl("key").unwrap(), None);
+    assert_eq!(store.get("key").unwrap(), Some("lasting".to_owned()));
+}
+
+#[test]
+fn cli_touch_extends_a_key_on_the_server() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = testing::open(temp_dir.path()).unwrap();
+    store
+        .set_with_ttl("session".to_owned(), "token".to_owned(), SHORT)
+        .unwrap();
+    let addr = start_server(store);
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args([
+            "--addr",
+            &addr.to_string(),
+            "touch",
+            "session",
+            "--ttl",
+            "30m",
+        ])
+        .assert()
+        .success()
+        .stdout("");
+    thread::sleep(SHORT * 2);
+    let mut client = KvsClient::connect(addr).unwrap();
+    assert_eq!(
+        client.get("session".to_owned()).unwrap(),
+        Some("token".to_owned())
+    );
+
+    assert!(!client.touch("missing".to_owned(), LONG).unwrap());
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args([
+            "--addr",
+            &addr.to_string(),
+            "touch",
+            "missing",
+            "--ttl",
+            "60m",
+        ])
+        .assert()
+        .failure()
+        .stdout(contains("Key not found"));
+}
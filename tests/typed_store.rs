@@ -0,0 +1,116 @@
+use kvs::testing;
+use kvs::{KvError, TypedStore};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use tempfile::TempDir;
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+struct Reading {
+    sensor: String,
+    celsius: f64,
+    tags: Vec<String>,
+}
+
+fn reading(id: u64, sample: u32) -> Reading {
+    Reading {
+        sensor: format!("sensor-{}", id),
+        celsius: sample as f64 / 4.0,
+        tags: vec![format!("sample-{}", sample)],
+    }
+}
+
+fn open<K, V>(dir: &Path) -> TypedStore<K, V>
+where
+    K: Serialize + for<'de> Deserialize<'de>,
+    V: Serialize + for<'de> Deserialize<'de>,
+{
+    TypedStore::new(testing::open(dir).unwrap())
+}
+
+#[test]
+fn tuple_keys_and_struct_values_survive_compaction_and_reopen() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut readings: TypedStore<(u64, u32), Reading> = open(temp_dir.path());
+    for id in 0..20 {
+        for sample in 0..5 {
+            readings.set(&(id, sample), &reading(id, sample)).unwrap();
+        }
+    }
+    readings.set(&(3, 1), &reading(99, 99)).unwrap();
+    assert!(readings.remove(&(4, 0)).unwrap());
+    assert!(!readings.remove(&(4, 0)).unwrap());
+    assert_eq!(readings.len(), 99);
+    assert_eq!(readings.get(&(3, 1)).unwrap(), Some(reading(99, 99)));
+    assert_eq!(readings.get(&(4, 0)).unwrap(), None);
+
+    readings.compact().unwrap();
+    assert_eq!(readings.get(&(3, 1)).unwrap(), Some(reading(99, 99)));
+    drop(readings);
+
+    let readings: TypedStore<(u64, u32), Reading> = open(temp_dir.path());
+    assert_eq!(readings.len(), 99);
+    assert_eq!(readings.get(&(19, 4)).unwrap(), Some(reading(19, 4)));
+    assert_eq!(readings.get(&(4, 0)).unwrap(), None);
+    let mut keys = readings.keys().unwrap();
+    keys.sort();
+    let expected: Vec<(u64, u32)> = (0..20)
+        .flat_map(|id| (0..5).map(move |sample| (id, sample)))
+        .filter(|&key| key != (4, 0))
+        .collect();
+    assert_eq!(keys, expected);
+    for entry in readings.iter() {
+        let ((id, sample), value) = entry.unwrap();
+        if (id, sample) != (3, 1) {
+            assert_eq!(value, reading(id, sample));
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+struct AccountFirst {
+    account: u64,
+    region: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+struct RegionFirst {
+    region: String,
+    account: u64,
+}
+
+#[test]
+fn keys_are_equal_when_their_serialized_forms_are() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store: TypedStore<AccountFirst, u32> = open(temp_dir.path());
+    let key = AccountFirst {
+        account: 7,
+        region: "eu".to_owned(),
+    };
+    store.set(&key, &1).unwrap();
+    drop(store);
+
+    // The fields are declared the other way round, but the canonical JSON is the same.
+    let mut store: TypedStore<RegionFirst, u32> = open(temp_dir.path());
+    let key = RegionFirst {
+        region: "eu".to_owned(),
+        account: 7,
+    };
+    assert_eq!(store.get(&key).unwrap(), Some(1));
+    store.set(&key, &2).unwrap();
+    assert_eq!(store.len(), 1);
+    assert_eq!(store.keys().unwrap(), vec![key]);
+}
+
+#[test]
+fn keys_of_another_type_fail_to_decode() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store: TypedStore<String, u32> = open(temp_dir.path());
+    store.set(&"name".to_owned(), &1).unwrap();
+    let store: TypedStore<(u64, u32), u32> = TypedStore::new(store.into_inner());
+
+    match store.keys() {
+        Err(KvError::ValueSerializationError { key, .. }) => assert_eq!(key, "\"name\""),
+        other => panic!("expected ValueSerializationError, got {:?}", other),
+    }
+    assert_eq!(store.get(&(1, 2)).unwrap(), None);
+}
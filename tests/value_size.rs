@@ -0,0 +1,115 @@
+use kvs::testing;
+use kvs::{KvStore, KvStoreOptions, MergeOperator};
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+struct Concat;
+
+impl MergeOperator for Concat {
+    fn merge(&self, _key: &str, existing: Option<&str>, operand: &str) -> String {
+        format!("{}{}", existing.unwrap_or(""), operand)
+    }
+}
+
+fn options() -> KvStoreOptions {
+    testing::options().merge_operator(Box::new(Concat))
+}
+
+/// Checks every key's reported size against the value `get` returns.
+fn assert_sizes_match(store: &KvStore, keys: &[&str]) {
+    for key in keys {
+        let expected = store
+            .get_bytes(key)
+            .unwrap()
+            .map(|value| value.len() as u64);
+        assert_eq!(store.value_size(key).unwrap(), expected, "{}", key);
+    }
+}
+
+fn fill(dir: &Path) -> KvStore {
+    let mut store = KvStore::open_with_options(dir, options()).unwrap();
+    store.set("ascii".to_owned(), "plain".to_owned()).unwrap();
+    // Two, three and four byte characters, and ones the log escapes.
+    store
+        .set(
+            "multibyte".to_owned(),
+            "h\u{e9}llo \u{20ac} \u{1f600}".to_owned(),
+        )
+        .unwrap();
+    store
+        .set("escaped".to_owned(), "\"quoted\"\n\\\t\u{1}".to_owned())
+        .unwrap();
+    store
+        .set_bytes("bytes".to_owned(), &[0xff, 0x00, b'"'])
+        .unwrap();
+    store
+        .set_from_reader("streamed".to_owned(), &b"streamed \xc3\xa9"[..], 11)
+        .unwrap();
+    store.set("merged".to_owned(), "\u{e9}".to_owned()).unwrap();
+    store
+        .merge("merged".to_owned(), "\u{20ac}".to_owned())
+        .unwrap();
+    store
+        .set_batch(vec![("batch".to_owned(), "\u{e9}\u{e9}".to_owned())])
+        .unwrap();
+    store.set("removed".to_owned(), "gone".to_owned()).unwrap();
+    store.remove("removed".to_owned()).unwrap();
+    store.set("empty".to_owned(), String::new()).unwrap();
+    store
+}
+
+const KEYS: &[&str] = &[
+    "ascii",
+    "multibyte",
+    "escaped",
+    "bytes",
+    "streamed",
+    "merged",
+    "batch",
+    "removed",
+    "empty",
+    "missing",
+];
+
+#[test]
+fn sizes_are_of_values_not_records() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = fill(temp_dir.path());
+    assert_eq!(
+        store.value_size("multibyte").unwrap(),
+        Some("h\u{e9}llo \u{20ac} \u{1f600}".len() as u64)
+    );
+    assert_eq!(store.value_size("merged").unwrap(), Some(5));
+    assert_eq!(store.value_size("removed").unwrap(), None);
+    assert_sizes_match(&store, KEYS);
+}
+
+#[test]
+fn sizes_are_recovered_on_replay_and_kept_by_compaction() {
+    let temp_dir = TempDir::new().unwrap();
+    drop(fill(temp_dir.path()));
+
+    let replayed =
+        KvStore::open_with_options(temp_dir.path(), options().compact_on_open(false)).unwrap();
+    assert_sizes_match(&replayed, KEYS);
+    drop(replayed);
+
+    let mut store = KvStore::open_with_options(temp_dir.path(), options()).unwrap();
+    assert_sizes_match(&store, KEYS);
+    store
+        .set_with_ttl(
+            "ascii".to_owned(),
+            "short".to_owned(),
+            Duration::from_millis(50),
+        )
+        .unwrap();
+    thread::sleep(Duration::from_millis(100));
+    assert_eq!(store.value_size("ascii").unwrap(), None);
+    drop(store);
+
+    // The cold tier of the index keeps sizes in its table too.
+    let store = KvStore::open_with_options(temp_dir.path(), options().max_index_bytes(1)).unwrap();
+    assert_sizes_match(&store, KEYS);
+}
@@ -0,0 +1,102 @@
+use kvs::testing;
+use kvs::{KvStore, KvStoreOptions, KvsClient, KvsServer};
+use std::fs;
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+/// Long enough that the test's requests run before the warm-up finishes.
+const HELD: Duration = Duration::from_millis(500);
+
+fn deferred() -> KvStoreOptions {
+    testing::options().defer_warm_up(true).warm_up_delay(HELD)
+}
+
+/// A log with 100 overwrites of each of ten keys, uncompacted.
+fn overwritten(dir: &Path) -> u64 {
+    let mut store = KvStore::open(dir).unwrap();
+    for round in 0..100 {
+        for key in 0..10 {
+            store
+                .set(format!("key{}", key), format!("round{}", round))
+                .unwrap();
+        }
+    }
+    drop(store);
+    fs::metadata(dir.join("db.log")).unwrap().len()
+}
+
+#[test]
+fn requests_before_warm_up_finishes_are_answered_correctly() {
+    let temp_dir = TempDir::new().unwrap();
+    overwritten(temp_dir.path());
+
+    let mut store = KvStore::open_with_options(temp_dir.path(), deferred()).unwrap();
+    assert!(!store.ready());
+    assert_eq!(store.stats().first_get_latency, None);
+    for key in 0..10 {
+        assert_eq!(
+            store.get(&format!("key{}", key)).unwrap(),
+            Some("round99".to_owned())
+        );
+    }
+    assert!(store.stats().first_get_latency.is_some());
+    store.set("new".to_owned(), "value".to_owned()).unwrap();
+    store.remove("key0".to_owned()).unwrap();
+    assert_eq!(store.get("new").unwrap(), Some("value".to_owned()));
+    assert_eq!(store.get("key0").unwrap(), None);
+    assert!(!store.ready());
+
+    assert!(!store.wait_ready(Duration::from_millis(10)));
+    assert!(store.wait_ready(Duration::from_secs(30)));
+    assert!(store.ready());
+    assert_eq!(store.get("new").unwrap(), Some("value".to_owned()));
+}
+
+#[test]
+fn deferred_open_leaves_compaction_for_later() {
+    let temp_dir = TempDir::new().unwrap();
+    let before = overwritten(temp_dir.path());
+    let log = temp_dir.path().join("db.log");
+
+    let mut store = KvStore::open_with_options(temp_dir.path(), deferred()).unwrap();
+    assert_eq!(fs::metadata(&log).unwrap().len(), before);
+    store.compact().unwrap();
+    assert!(fs::metadata(&log).unwrap().len() < before);
+}
+
+#[test]
+fn store_opened_without_deferral_is_ready_at_once() {
+    let temp_dir = TempDir::new().unwrap();
+    let before = overwritten(temp_dir.path());
+
+    let store = testing::open(temp_dir.path()).unwrap();
+    assert!(store.ready());
+    assert!(store.wait_ready(Duration::ZERO));
+    assert!(fs::metadata(temp_dir.path().join("db.log")).unwrap().len() < before);
+}
+
+#[test]
+fn server_reports_warming_up_until_ready() {
+    let temp_dir = TempDir::new().unwrap();
+    overwritten(temp_dir.path());
+    let store = KvStore::open_with_options(temp_dir.path(), deferred()).unwrap();
+    let log = slog::Logger::root(slog::Discard, slog::o!());
+    let server = KvsServer::new("127.0.0.1:0", store, log).unwrap();
+    let addr = server.local_addr().unwrap();
+    thread::spawn(move || server.listen_forever());
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    assert!(client.ping().unwrap().warming_up);
+    assert_eq!(
+        client.get("key3".to_owned()).unwrap(),
+        Some("round99".to_owned())
+    );
+
+    let deadline = Instant::now() + Duration::from_secs(30);
+    while client.ping().unwrap().warming_up {
+        assert!(Instant::now() < deadline, "never finished warming up");
+        thread::sleep(Duration::from_millis(10));
+    }
+}
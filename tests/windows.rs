@@ -0,0 +1,138 @@
+//! End-to-end checks of the behaviour that differs on Windows: replacing files this process
+//! still has open, truncating through append-only handles, CRLF input and path display.
+//! Import of CRLF files is covered for every platform in `tests/bulk.rs`.
+#![cfg(windows)]
+
+use assert_cmd::prelude::*;
+use kvs::testing::{self, ShortWrite};
+use kvs::{KvError, KvStore};
+use predicates::str::contains;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::process::Command;
+use tempfile::TempDir;
+
+fn churn(store: &mut KvStore, rounds: usize) {
+    for round in 0..rounds {
+        for i in 0..200 {
+            store
+                .set(format!("key{}", i), format!("value{}-{}", i, round))
+                .unwrap();
+        }
+    }
+}
+
+fn assert_churned(dir: &Path, options: kvs::KvStoreOptions, rounds: usize) {
+    let store = KvStore::open_with_options(dir, options).unwrap();
+    for i in (1..200).step_by(17) {
+        assert_eq!(
+            store.get(&format!("key{}", i)).unwrap(),
+            Some(format!("value{}-{}", i, rounds - 1))
+        );
+    }
+}
+
+#[test]
+fn open_set_get_compact_reopen() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = testing::open(temp_dir.path()).unwrap();
+    churn(&mut store, 3);
+    store.remove("key0".to_owned()).unwrap();
+
+    // Twice, so the second rename replaces a log the appender reopened after the first.
+    store.compact().unwrap();
+    store.compact().unwrap();
+    store
+        .set("after".to_owned(), "compaction".to_owned())
+        .unwrap();
+    drop(store);
+
+    let store = testing::open(temp_dir.path()).unwrap();
+    assert_eq!(store.get("key0").unwrap(), None);
+    assert_eq!(store.get("after").unwrap(), Some("compaction".to_owned()));
+    drop(store);
+    assert_churned(temp_dir.path(), testing::options(), 3);
+}
+
+#[test]
+fn compaction_replaces_the_open_cold_table() {
+    let temp_dir = TempDir::new().unwrap();
+    let options = testing::options().max_index_bytes(4 * 1024);
+    let mut store = KvStore::open_with_options(temp_dir.path(), options.clone()).unwrap();
+    // Enough keys to spill, so the table is merged into while open.
+    churn(&mut store, 4);
+    store.compact().unwrap();
+    churn(&mut store, 5);
+    drop(store);
+    assert_churned(temp_dir.path(), options, 5);
+}
+
+#[test]
+fn recover_append_truncates_the_log() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = testing::open(temp_dir.path()).unwrap();
+    store.set("kept".to_owned(), "value".to_owned()).unwrap();
+    let len = fs::metadata(temp_dir.path().join("db.log")).unwrap().len();
+
+    store.inject_short_write(ShortWrite {
+        after_bytes: 5,
+        error: io::ErrorKind::StorageFull,
+    });
+    assert!(store.set("lost".to_owned(), "value".to_owned()).is_err());
+    store.recover_append().unwrap();
+    assert_eq!(
+        fs::metadata(temp_dir.path().join("db.log")).unwrap().len(),
+        len
+    );
+    store.set("next".to_owned(), "value".to_owned()).unwrap();
+}
+
+#[test]
+fn errors_show_native_paths() {
+    let temp_dir = TempDir::new().unwrap();
+    let file = temp_dir.path().join("not-a-dir");
+    fs::write(&file, "").unwrap();
+
+    let err = testing::open(&file).err().unwrap();
+    assert!(matches!(err, KvError::NotADirectory(_)));
+    let message = err.to_string();
+    assert!(message.contains(&file.display().to_string()));
+    assert!(!message.contains('/'));
+}
+
+#[test]
+fn canonical_export_survives_crlf_editing() {
+    let source = TempDir::new().unwrap();
+    let mut store = testing::open(source.path()).unwrap();
+    store.set("a".to_owned(), "1".to_owned()).unwrap();
+    store.set("b".to_owned(), "2\r\n".to_owned()).unwrap();
+    drop(store);
+
+    let work = TempDir::new().unwrap();
+    let file = work.path().join("canonical.txt");
+    let rebuilt = work.path().join("rebuilt");
+    let output = Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["log", "export", "--dir"])
+        .arg(source.path())
+        .output()
+        .unwrap();
+    let exported = String::from_utf8(output.stdout).unwrap();
+    // What an editor that normalises line ends would save.
+    fs::write(&file, exported.replace('\n', "\r\n")).unwrap();
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["log", "build", "--from"])
+        .arg(&file)
+        .arg("--out")
+        .arg(&rebuilt)
+        .arg("--diff-against")
+        .arg(source.path())
+        .assert()
+        .success()
+        .stdout(contains("0 keys differ"));
+    let store = testing::open(&rebuilt).unwrap();
+    assert_eq!(store.get("b").unwrap(), Some("2\r\n".to_owned()));
+}
@@ -0,0 +1,153 @@
+use kvs::testing;
+use kvs::{ChangeEvent, ChangeOp, KvError, KvStore, WriteBatch};
+use std::fs;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use tempfile::TempDir;
+
+fn log_path(dir: &Path) -> std::path::PathBuf {
+    dir.join("db.log")
+}
+
+fn batch(puts: &[(&str, &str)], deletes: &[&str]) -> WriteBatch {
+    let mut batch = WriteBatch::new();
+    for (key, value) in puts {
+        batch.put((*key).to_owned(), (*value).to_owned());
+    }
+    for key in deletes {
+        batch.delete((*key).to_owned());
+    }
+    batch
+}
+
+#[test]
+fn puts_and_deletes_apply_and_survive_reopening() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = testing::open(temp_dir.path()).unwrap();
+    store.set("old".to_owned(), "value".to_owned()).unwrap();
+    let batch = batch(&[("a", "1"), ("b", "2")], &["old"]);
+    assert_eq!(batch.len(), 3);
+    store.apply_batch(batch).unwrap();
+    assert_eq!(store.get("a").unwrap(), Some("1".to_owned()));
+    assert_eq!(store.get("b").unwrap(), Some("2".to_owned()));
+    assert_eq!(store.get("old").unwrap(), None);
+    drop(store);
+
+    let store = testing::open(temp_dir.path()).unwrap();
+    assert_eq!(store.open_report().torn_tail, None);
+    assert_eq!(store.get("a").unwrap(), Some("1".to_owned()));
+    assert_eq!(store.get("b").unwrap(), Some("2".to_owned()));
+    assert_eq!(store.get("old").unwrap(), None);
+}
+
+#[test]
+fn later_writes_of_a_key_win() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = testing::open(temp_dir.path()).unwrap();
+    let mut batch = WriteBatch::new();
+    batch.put("key".to_owned(), "first".to_owned());
+    batch.put("key".to_owned(), "second".to_owned());
+    batch.put("gone".to_owned(), "soon".to_owned());
+    batch.delete("gone".to_owned());
+    store.apply_batch(batch).unwrap();
+    assert_eq!(store.get("key").unwrap(), Some("second".to_owned()));
+    assert_eq!(store.get("gone").unwrap(), None);
+    drop(store);
+
+    let store = testing::open(temp_dir.path()).unwrap();
+    assert_eq!(store.get("key").unwrap(), Some("second".to_owned()));
+    assert_eq!(store.get("gone").unwrap(), None);
+}
+
+#[test]
+fn an_empty_batch_writes_nothing() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = testing::open(temp_dir.path()).unwrap();
+    store.set("key".to_owned(), "value".to_owned()).unwrap();
+    let before = fs::read(log_path(temp_dir.path())).unwrap();
+    assert!(WriteBatch::new().is_empty());
+    store.apply_batch(WriteBatch::new()).unwrap();
+    assert_eq!(fs::read(log_path(temp_dir.path())).unwrap(), before);
+}
+
+#[test]
+fn a_batch_cut_short_by_a_crash_applies_none_of_it() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = testing::open(temp_dir.path()).unwrap();
+    store.set("kept".to_owned(), "value".to_owned()).unwrap();
+    store.set("old".to_owned(), "value".to_owned()).unwrap();
+    let good_len = fs::metadata(log_path(temp_dir.path())).unwrap().len();
+    store
+        .apply_batch(batch(&[("a", "1"), ("b", "2"), ("c", "3")], &["old"]))
+        .unwrap();
+    drop(store);
+
+    let contents = fs::read(log_path(temp_dir.path())).unwrap();
+    let batch_len = contents.len() - good_len as usize;
+    // Cut it after its first records, then part way through one.
+    for cut in [batch_len / 2, batch_len - 3] {
+        fs::write(
+            log_path(temp_dir.path()),
+            &contents[..good_len as usize + cut],
+        )
+        .unwrap();
+        let store = testing::open(temp_dir.path()).unwrap();
+        let tail = store.open_report().torn_tail.unwrap();
+        assert_eq!(tail.offset, good_len);
+        assert_eq!(store.get("kept").unwrap(), Some("value".to_owned()));
+        assert_eq!(store.get("old").unwrap(), Some("value".to_owned()));
+        for key in ["a", "b", "c"] {
+            assert_eq!(store.get(key).unwrap(), None, "{}", key);
+        }
+    }
+    assert_eq!(
+        fs::metadata(log_path(temp_dir.path())).unwrap().len(),
+        good_len
+    );
+}
+
+#[test]
+fn a_batch_with_a_write_too_large_writes_nothing() {
+    let temp_dir = TempDir::new().unwrap();
+    let options = testing::options().max_value_size(16);
+    let mut store = KvStore::open_with_options(temp_dir.path(), options).unwrap();
+    store.set("old".to_owned(), "value".to_owned()).unwrap();
+    let before = fs::read(log_path(temp_dir.path())).unwrap();
+
+    let big = "x".repeat(17);
+    let batch = batch(&[("a", "1"), ("big", &big)], &["old"]);
+    match store.apply_batch(batch) {
+        Err(KvError::ValueTooLarge { .. }) => {}
+        other => panic!("unexpected result: {:?}", other),
+    }
+    assert_eq!(fs::read(log_path(temp_dir.path())).unwrap(), before);
+    assert_eq!(store.get("a").unwrap(), None);
+    assert_eq!(store.get("old").unwrap(), Some("value".to_owned()));
+}
+
+#[test]
+fn subscribers_see_each_write_of_a_batch() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = testing::open(temp_dir.path()).unwrap();
+    store.set("old".to_owned(), "value".to_owned()).unwrap();
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let sink = Arc::clone(&events);
+    store.subscribe(move |event| sink.lock().unwrap().push(event.clone()));
+
+    store.apply_batch(batch(&[("a", "1")], &["old"])).unwrap();
+    assert_eq!(
+        *events.lock().unwrap(),
+        vec![
+            ChangeEvent {
+                key: "a".to_owned(),
+                op: ChangeOp::Set,
+                value: Some(b"1".to_vec()),
+            },
+            ChangeEvent {
+                key: "old".to_owned(),
+                op: ChangeOp::Remove,
+                value: None,
+            },
+        ]
+    );
+}